@@ -721,6 +721,36 @@ impl ButtplugClientDevice {
       .send_message_expect_ok(StopDeviceCmd::new(self.index).into())
   }
 
+  /// Commands the given scalar features (by index in [Self::scalar_attributes]) to stop, leaving
+  /// every other feature running as it is.
+  ///
+  /// This sends a ScalarCmd that only contains the stopped features, so the client doesn't need to
+  /// know the current state of the rest of the device.
+  pub fn stop_features(&self, feature_indexes: &[u32]) -> ButtplugClientResultFuture {
+    let attrs = self.scalar_attributes();
+    if attrs.is_empty() {
+      return create_boxed_future_client_error(
+        ButtplugDeviceError::MessageNotSupported(ButtplugDeviceMessageType::ScalarCmd).into(),
+      );
+    }
+    let mut scalar_vec = Vec::with_capacity(feature_indexes.len());
+    for idx in feature_indexes {
+      if *idx >= attrs.len() as u32 {
+        return create_boxed_future_client_error(
+          ButtplugDeviceError::DeviceFeatureIndexError(attrs.len() as u32, *idx).into(),
+        );
+      }
+      let attr = &attrs[*idx as usize];
+      scalar_vec.push(ScalarSubcommand::new(
+        *attr.index(),
+        0.0,
+        *attr.actuator_type(),
+      ));
+    }
+    let msg = ScalarCmd::new(self.index, scalar_vec).into();
+    self.event_loop_sender.send_message_expect_ok(msg)
+  }
+
   pub(super) fn set_device_connected(&self, connected: bool) {
     self.device_connected.store(connected, Ordering::SeqCst);
   }
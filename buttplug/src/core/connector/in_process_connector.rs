@@ -16,7 +16,7 @@ use crate::{
   util::async_manager,
 };
 use futures::{
-  future::{self, BoxFuture, FutureExt},
+  future::{BoxFuture, FutureExt},
   StreamExt,
 };
 use std::{
@@ -152,7 +152,14 @@ impl ButtplugConnector<ButtplugCurrentSpecClientMessage, ButtplugCurrentSpecServ
   fn disconnect(&self) -> ButtplugConnectorResultFuture {
     if self.connected.load(Ordering::SeqCst) {
       self.connected.store(false, Ordering::SeqCst);
-      future::ready(Ok(())).boxed()
+      // Disconnect the server side too, so the devices the client was using stop, and clients on
+      // other servers sharing the device manager can use the devices it claimed.
+      let server = self.server.clone();
+      async move {
+        let _ = server.disconnect().await;
+        Ok(())
+      }
+      .boxed()
     } else {
       ButtplugConnectorError::ConnectorNotConnected.into()
     }
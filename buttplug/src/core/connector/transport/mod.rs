@@ -18,6 +18,8 @@ use futures::future::BoxFuture;
 use thiserror::Error;
 use tokio::sync::mpsc::{Receiver, Sender};
 #[cfg(feature = "websockets")]
+pub(crate) use websocket::get_rustls_config_dangerous;
+#[cfg(feature = "websockets")]
 pub use websocket::{
   ButtplugWebsocketClientTransport,
   ButtplugWebsocketServerTransport,
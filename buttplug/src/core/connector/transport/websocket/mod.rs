@@ -12,6 +12,8 @@ pub mod websocket_server;
 
 pub use tokio_tungstenite::tungstenite::Error as TungsteniteError;
 pub use websocket_client::ButtplugWebsocketClientTransport;
+// Also used by the websocket device manager, for devices it connects out to.
+pub(crate) use websocket_client::get_rustls_config_dangerous;
 
 pub use websocket_server::{
   ButtplugWebsocketServerTransport,
@@ -22,6 +22,7 @@ use crate::server::device::hardware::communication::HardwareSpecificError;
 use displaydoc::Display;
 use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use thiserror::Error;
 
 pub type ButtplugResult<T = ()> = Result<T, ButtplugError>;
@@ -173,12 +174,89 @@ pub enum ButtplugDeviceError {
   UntypedDeserializedError(String),
   /// Device Configuration Error: {0}
   DeviceConfigurationError(String),
+  /// Device Configuration Error: {0}
+  ConfigurationError(#[from] ConfigurationError),
   /// Actuator Type Mismatch: Index {0} got command for {1}, but expects {2}
   DeviceActuatorTypeMismatch(String, ActuatorType, ActuatorType),
   /// Sensor Type Mismatch: Index {0} got command for {1}, but expects {2}
   DeviceSensorTypeMismatch(u32, SensorType, SensorType),
   /// Protocol does not have an implementation available for Sensor Type {0}
   ProtocolSensorNotSupported(SensorType),
+  /// Device {0} is busy, {1}
+  DeviceBusy(String, String),
+}
+
+/// Errors from loading device configuration files, so frontends can tell what went wrong without
+/// parsing messages.
+#[derive(Debug, Error, Display, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub enum ConfigurationError {
+  /// {details}
+  SchemaViolation {
+    details: String,
+    errors: Vec<SchemaValidationError>,
+  },
+  /// Device configuration file major version {file} is different than internal major version {internal}. Cannot load external files that do not have matching major version numbers.
+  VersionMismatch { file: String, internal: String },
+  /// {message}
+  SerdeError { message: String },
+  /// Reserved index {index} is used by more than one device: {devices:?}
+  DuplicateReservedIndex { index: u32, devices: Vec<String> },
+  /// Device {device} is set to be both allowed and denied
+  ContradictoryDeviceAccess { device: String },
+  /// Protocol definition for {protocol} is invalid: {error}
+  InvalidProtocolDefinition {
+    protocol: String,
+    error: Box<ConfigurationError>,
+  },
+  /// Cannot read device configuration file {path}: {message}
+  ReadError { path: String, message: String },
+  /// Device configuration file {path} is not valid UTF-8: {message}
+  InvalidUtf8 { path: String, message: String },
+  /// Cannot load device configuration from {paths}: {error}
+  FileLoadError {
+    paths: String,
+    error: Box<ConfigurationError>,
+  },
+  /// Device configurations for {protocol} inherit from each other in a cycle: {chain:?}
+  InheritanceCycle {
+    protocol: String,
+    chain: Vec<String>,
+  },
+  /// Device configuration {identifier} for {protocol} inherits from unknown configuration {parent}
+  UnknownInheritanceParent {
+    protocol: String,
+    identifier: String,
+    parent: String,
+  },
+  /// Device configuration has lint errors: {lints:?}
+  LintErrors { lints: Vec<String> },
+  /// Protocol {protocol} has an invalid BLE name pattern {pattern}: {message}
+  InvalidNameRegex {
+    protocol: String,
+    pattern: String,
+    message: String,
+  },
+  /// Protocol {protocol} already has an implementation, another one can't be added under the same name
+  ProtocolAlreadyRegistered { protocol: String },
+  /// Cannot migrate device configuration version {version}: {message}
+  MigrationFailed { version: String, message: String },
+}
+
+/// A single schema validation failure, located by a JSON pointer into the document that failed (e.g.
+/// `/protocols/lovense/defaults/features/0`). The pointer is empty for errors about the document as
+/// a whole, like it not being valid JSON.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub struct SchemaValidationError {
+  pub pointer: String,
+  pub message: String,
+}
+
+impl fmt::Display for SchemaValidationError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} (at \"{}\")", self.message, self.pointer)
+  }
 }
 
 /// Unknown errors occur in exceptional circumstances where no other error type
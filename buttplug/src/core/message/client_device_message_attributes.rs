@@ -52,7 +52,7 @@ impl TryFrom<FeatureType> for ActuatorType {
   }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Display)]
 pub enum SensorType {
   Unknown,
   Battery,
@@ -64,6 +64,19 @@ pub enum SensorType {
   // Gyro,
 }
 
+/// Unit of the values a sensor reports, from the device config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display)]
+pub enum SensorUnit {
+  /// Raw values with no physical meaning, e.g. pressure sensors reporting ADC counts.
+  Unitless,
+  Percent,
+  Millivolt,
+  Gram,
+  Millimeter,
+  /// Signal strength, as reported for RSSI.
+  DecibelMilliwatt,
+}
+
 impl TryFrom<FeatureType> for SensorType {
   type Error = String;
   fn try_from(value: FeatureType) -> Result<Self, Self::Error> {
@@ -151,6 +164,7 @@ pub struct ClientDeviceMessageAttributes {
   vorze_a10_cyclone_cmd: Option<NullDeviceMessageAttributes>,
 }
 
+// Keeps feature order from the config, same as the server side attributes.
 impl From<Vec<DeviceFeature>> for ClientDeviceMessageAttributes {
   fn from(features: Vec<DeviceFeature>) -> Self {
     let actuator_filter = |message_type| {
@@ -253,6 +267,17 @@ impl ClientDeviceMessageAttributes {
     }
   }
 
+  /// Mark scalar features that take vibrate commands through the user config, so older specs list
+  /// them as vibrators.
+  #[cfg(feature = "server")]
+  pub(crate) fn set_scalar_vibrate_aliases(&mut self, indexes: &[u32]) {
+    if let Some(scalar_attrs) = &mut self.scalar_cmd {
+      for attr in scalar_attrs.iter_mut() {
+        attr.vibrate_alias = indexes.contains(&attr.index);
+      }
+    }
+  }
+
   pub fn finalize(&mut self) {
     if let Some(scalar_attrs) = &mut self.scalar_cmd {
       for (i, attr) in scalar_attrs.iter_mut().enumerate() {
@@ -327,6 +352,19 @@ impl ClientDeviceMessageAttributesBuilder {
 #[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct NullDeviceMessageAttributes {}
 
+fn optional_range_serialize<S>(
+  range: &Option<RangeInclusive<u32>>,
+  serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+  S: Serializer,
+{
+  match range {
+    Some(range) => [*range.start(), *range.end()].serialize(serializer),
+    None => serializer.serialize_none(),
+  }
+}
+
 fn unspecified_feature() -> String {
   "N/A".to_string()
 }
@@ -343,10 +381,26 @@ pub struct ClientGenericDeviceMessageAttributes {
   #[serde(rename = "StepCount")]
   #[getset(get = "pub")]
   step_count: u32,
+  /// Device steps the feature's StepCount is mapped onto, for features that have a minimum step.
+  /// Step 1 is the minimum, and a step of 0 still turns the feature off. Not part of the v3 spec,
+  /// so it's left out for features without a minimum.
+  #[getset(get = "pub", set = "pub(crate)")]
+  #[serde(
+    rename = "StepRange",
+    default,
+    skip_serializing_if = "Option::is_none",
+    serialize_with = "optional_range_serialize"
+  )]
+  step_range: Option<RangeInclusive<u32>>,
   // TODO This needs to actually be part of the device info relayed to the client in spec v4.
   #[getset(get = "pub")]
   #[serde(skip, default)]
   index: u32,
+  /// True if the feature isn't a vibrator, but the user config maps vibrate commands to it. Older
+  /// message specs list these features as vibrators.
+  #[getset(get = "pub")]
+  #[serde(skip, default)]
+  vibrate_alias: bool,
 }
 
 impl TryFrom<DeviceFeature> for ClientGenericDeviceMessageAttributes {
@@ -360,7 +414,9 @@ impl TryFrom<DeviceFeature> for ClientGenericDeviceMessageAttributes {
         feature_descriptor: value.description().to_owned(),
         actuator_type,
         step_count: step_count,
+        step_range: Some(step_limit.clone()).filter(|range| *range.start() > 0),
         index: 0,
+        vibrate_alias: false,
       };
       Ok(attrs)
     } else {
@@ -377,7 +433,9 @@ impl ClientGenericDeviceMessageAttributes {
       feature_descriptor: feature_descriptor.to_owned(),
       actuator_type,
       step_count,
+      step_range: None,
       index: 0,
+      vibrate_alias: false,
     }
   }
 
@@ -425,9 +483,15 @@ pub struct SensorDeviceMessageAttributes {
   #[getset(get = "pub")]
   #[serde(rename = "SensorType")]
   sensor_type: SensorType,
-  #[getset(get = "pub")]
+  #[getset(get = "pub", set = "pub(crate)")]
   #[serde(rename = "SensorRange", serialize_with = "range_sequence_serialize")]
   sensor_range: Vec<RangeInclusive<i32>>,
+  /// None of the current message spec versions allow extra fields on sensor attributes, so this is
+  /// never serialized, and clients always see None. Only available to the server, and to clients
+  /// built into the same application.
+  #[getset(get = "pub")]
+  #[serde(skip, default)]
+  unit: Option<SensorUnit>,
   // TODO This needs to actually be part of the device info relayed to the client in spec v4.
   #[getset(get = "pub")]
   #[serde(skip, default)]
@@ -442,6 +506,7 @@ impl TryFrom<DeviceFeature> for SensorDeviceMessageAttributes {
         feature_descriptor: value.description().to_owned(),
         sensor_type: (*value.feature_type()).try_into()?,
         sensor_range: sensor.value_range().clone(),
+        unit: *sensor.unit(),
         index: 0,
       })
     } else {
@@ -521,6 +586,9 @@ pub struct ClientDeviceMessageAttributesV2 {
 impl From<ClientDeviceMessageAttributes> for ClientDeviceMessageAttributesV2 {
   fn from(other: ClientDeviceMessageAttributes) -> Self {
     Self {
+      // Older specs only know about vibrators, so only Vibrate typed scalar features can be
+      // exposed. Anything else (e.g. e-stim frequency controls) is only available in v3, unless the
+      // user config maps vibrate commands to it.
       vibrate_cmd: other
         .scalar_cmd()
         .as_ref()
@@ -566,7 +634,7 @@ impl From<ClientDeviceMessageAttributes> for ClientDeviceMessageAttributesV2 {
       raw_read_cmd: other.raw_read_cmd().clone(),
       raw_write_cmd: other.raw_write_cmd().clone(),
       raw_subscribe_cmd: other.raw_subscribe_cmd().clone(),
-      raw_unsubscribe_cmd: other.raw_subscribe_cmd().clone(),
+      raw_unsubscribe_cmd: other.raw_unsubscribe_cmd().clone(),
       fleshlight_launch_fw12_cmd: other.fleshlight_launch_fw12_cmd().clone(),
       vorze_a10_cyclone_cmd: other.vorze_a10_cyclone_cmd().clone(),
     }
@@ -590,7 +658,7 @@ impl GenericDeviceMessageAttributesV2 {
     let mut feature_count = 0u32;
     let mut step_count = vec![];
     for attr in attributes_vec {
-      if *attr.actuator_type() == ActuatorType::Vibrate {
+      if *attr.actuator_type() == ActuatorType::Vibrate || attr.vibrate_alias {
         feature_count += 1;
         step_count.push(*attr.step_count());
       }
@@ -629,16 +697,20 @@ pub struct ClientDeviceMessageAttributesV1 {
 
   // StopDeviceCmd always exists
   #[getset(get = "pub")]
+  #[serde(rename = "StopDeviceCmd")]
   stop_device_cmd: NullDeviceMessageAttributes,
 
   // Obsolete commands are only added post-serialization
   #[getset(get = "pub")]
+  #[serde(rename = "SingleMotorVibrateCmd")]
   #[serde(skip_serializing_if = "Option::is_none")]
   single_motor_vibrate_cmd: Option<NullDeviceMessageAttributes>,
   #[getset(get = "pub")]
+  #[serde(rename = "FleshlightLaunchFW12Cmd")]
   #[serde(skip_serializing_if = "Option::is_none")]
   fleshlight_launch_fw12_cmd: Option<NullDeviceMessageAttributes>,
   #[getset(get = "pub")]
+  #[serde(rename = "VorzeA10CycloneCmd")]
   #[serde(skip_serializing_if = "Option::is_none")]
   vorze_a10_cyclone_cmd: Option<NullDeviceMessageAttributes>,
 }
@@ -683,3 +755,48 @@ impl From<GenericDeviceMessageAttributesV2> for GenericDeviceMessageAttributesV1
     }
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::core::message::DeviceFeatureActuator;
+  use std::collections::HashSet;
+
+  fn vibrator_with_step_limit(step_limit: RangeInclusive<u32>) -> DeviceFeature {
+    DeviceFeature::new(
+      "",
+      FeatureType::Vibrate,
+      &Some(DeviceFeatureActuator::new(
+        &(0..=20),
+        &step_limit,
+        &HashSet::from([ButtplugActuatorFeatureMessageType::ScalarCmd]),
+      )),
+      &None,
+    )
+  }
+
+  #[test]
+  pub fn test_scalar_step_range() {
+    let attributes = ClientDeviceMessageAttributes::from(vec![vibrator_with_step_limit(3..=20)]);
+    let scalar = &attributes
+      .scalar_cmd()
+      .as_ref()
+      .expect("Test, assuming infallible.")[0];
+    assert_eq!(*scalar.step_count(), 17);
+    assert_eq!(*scalar.step_range(), Some(3..=20));
+    assert!(serde_json::to_string(scalar)
+      .expect("Test, assuming infallible.")
+      .contains(r#""StepRange":[3,20]"#));
+
+    // The v3 spec has no step range, so it's only sent for features that need it.
+    let attributes = ClientDeviceMessageAttributes::from(vec![vibrator_with_step_limit(0..=20)]);
+    let scalar = &attributes
+      .scalar_cmd()
+      .as_ref()
+      .expect("Test, assuming infallible.")[0];
+    assert_eq!(*scalar.step_range(), None);
+    assert!(!serde_json::to_string(scalar)
+      .expect("Test, assuming infallible.")
+      .contains("StepRange"));
+  }
+}
@@ -10,7 +10,7 @@ use crate::core::{
   message::{ButtplugDeviceMessageType, Endpoint},
 };
 use getset::{Getters, MutGetters, Setters};
-use serde::{ser::SerializeSeq, Deserialize, Serialize, Serializer};
+use serde::{ser::SerializeSeq, Deserialize, Deserializer, Serialize, Serializer};
 use std::{collections::HashSet, ops::RangeInclusive};
 
 use super::{
@@ -18,6 +18,7 @@ use super::{
   ButtplugActuatorFeatureMessageType,
   ButtplugSensorFeatureMessageType,
   SensorType,
+  SensorUnit,
 };
 
 #[derive(Debug, Default, Display, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -162,21 +163,61 @@ where
   seq.end()
 }
 
+// Ranges are written as [start, end], so read them back the same way. Serde's own RangeInclusive
+// impl expects a struct, which only happens to work for formats (like JSON) that let a sequence
+// stand in for one.
+fn range_deserialize<'de, D>(deserializer: D) -> Result<RangeInclusive<u32>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let [start, end] = <[u32; 2]>::deserialize(deserializer)?;
+  Ok(start..=end)
+}
+
+fn optional_range_deserialize<'de, D>(
+  deserializer: D,
+) -> Result<Option<RangeInclusive<u32>>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  Ok(Option::<[u32; 2]>::deserialize(deserializer)?.map(|[start, end]| start..=end))
+}
+
+fn range_sequence_deserialize<'de, D>(deserializer: D) -> Result<Vec<RangeInclusive<i32>>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  Ok(
+    Vec::<[i32; 2]>::deserialize(deserializer)?
+      .into_iter()
+      .map(|[start, end]| start..=end)
+      .collect(),
+  )
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Getters, MutGetters, Setters, Serialize, Deserialize)]
 pub struct DeviceFeatureActuatorSerialized {
   #[getset(get = "pub")]
   #[serde(rename = "step-range")]
-  #[serde(serialize_with = "range_serialize")]
+  #[serde(
+    serialize_with = "range_serialize",
+    deserialize_with = "range_deserialize"
+  )]
   step_range: RangeInclusive<u32>,
   // This doesn't exist in base configs, so when we load these from the base config file, we'll just
   // copy the step_range value.
   #[getset(get = "pub")]
   #[serde(rename = "step-limit")]
-  #[serde(default)]
+  #[serde(default, deserialize_with = "optional_range_deserialize")]
   step_limit: Option<RangeInclusive<u32>>,
   #[getset(get = "pub")]
   #[serde(rename = "messages")]
   messages: HashSet<ButtplugActuatorFeatureMessageType>,
+  // User config only, see DeviceFeatureActuator.
+  #[getset(get = "pub")]
+  #[serde(rename = "intensity-curve")]
+  #[serde(default)]
+  intensity_curve: Option<IntensityCurve>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Getters, MutGetters, Setters, Serialize, Deserialize)]
@@ -195,6 +236,12 @@ pub struct DeviceFeatureActuator {
   #[getset(get = "pub")]
   #[serde(rename = "messages")]
   messages: HashSet<ButtplugActuatorFeatureMessageType>,
+  // Also user config only. Remaps scalar values before they're turned into steps, for motors that
+  // don't respond linearly.
+  #[getset(get = "pub", set = "pub")]
+  #[serde(rename = "intensity-curve")]
+  #[serde(skip_serializing_if = "Option::is_none")]
+  intensity_curve: Option<IntensityCurve>,
 }
 
 impl From<DeviceFeatureActuatorSerialized> for DeviceFeatureActuator {
@@ -203,6 +250,7 @@ impl From<DeviceFeatureActuatorSerialized> for DeviceFeatureActuator {
       step_range: value.step_range.clone(),
       step_limit: value.step_limit.unwrap_or(value.step_range),
       messages: value.messages,
+      intensity_curve: value.intensity_curve,
     }
   }
 }
@@ -217,6 +265,7 @@ impl DeviceFeatureActuator {
       step_range: step_range.clone(),
       step_limit: step_limit.clone(),
       messages: messages.clone(),
+      intensity_curve: None,
     }
   }
 
@@ -229,20 +278,110 @@ impl DeviceFeatureActuator {
       Err(ButtplugDeviceError::DeviceConfigurationError(format!(
         "Step limit out of order, must be start <= x <= end."
       )))
+    } else if let Some(curve) = &self.intensity_curve {
+      curve.is_valid()
     } else {
       Ok(())
     }
   }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IntensityCurvePreset {
+  Linear,
+  Quadratic,
+}
+
+/// Remaps a scalar value (0.0-1.0) before it is converted to device steps. Either a named preset,
+/// or a list of [input, output] control points, which are linearly interpolated between. If the
+/// points don't cover the ends of the range, [0, 0] and [1, 1] are assumed. No matter the curve, 0
+/// always maps to 0 and 1 always maps to 1.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum IntensityCurve {
+  Preset(IntensityCurvePreset),
+  Points(Vec<[f64; 2]>),
+}
+
+// Control points are validated to be finite, so this holds.
+impl Eq for IntensityCurve {
+}
+
+impl IntensityCurve {
+  pub fn is_valid(&self) -> Result<(), ButtplugDeviceError> {
+    if let IntensityCurve::Points(points) = self {
+      if points.is_empty() {
+        return Err(ButtplugDeviceError::DeviceConfigurationError(
+          "Intensity curve must have at least one control point.".to_owned(),
+        ));
+      }
+      if points
+        .iter()
+        .flatten()
+        .any(|value| !(0.0..=1.0).contains(value))
+      {
+        return Err(ButtplugDeviceError::DeviceConfigurationError(
+          "Intensity curve control points must be in [0, 1].".to_owned(),
+        ));
+      }
+      if points.windows(2).any(|pair| pair[0][0] >= pair[1][0]) {
+        return Err(ButtplugDeviceError::DeviceConfigurationError(
+          "Intensity curve control point inputs must be in increasing order.".to_owned(),
+        ));
+      }
+    }
+    Ok(())
+  }
+
+  pub fn apply(&self, value: f64) -> f64 {
+    if value <= 0.0 {
+      return 0.0;
+    }
+    if value >= 1.0 {
+      return 1.0;
+    }
+    let output = match self {
+      IntensityCurve::Preset(IntensityCurvePreset::Linear) => value,
+      IntensityCurve::Preset(IntensityCurvePreset::Quadratic) => value * value,
+      IntensityCurve::Points(points) => {
+        let mut curve = Vec::with_capacity(points.len() + 2);
+        if points.first().is_none_or(|point| point[0] > 0.0) {
+          curve.push([0.0, 0.0]);
+        }
+        curve.extend_from_slice(points);
+        if points.last().is_none_or(|point| point[0] < 1.0) {
+          curve.push([1.0, 1.0]);
+        }
+        curve
+          .windows(2)
+          .find(|pair| value <= pair[1][0])
+          .map(|pair| {
+            let [start_x, start_y] = pair[0];
+            let [end_x, end_y] = pair[1];
+            start_y + (end_y - start_y) * (value - start_x) / (end_x - start_x)
+          })
+          .unwrap_or(1.0)
+      }
+    };
+    output.clamp(0.0, 1.0)
+  }
+}
+
 #[derive(
   Clone, Debug, Default, PartialEq, Eq, Getters, MutGetters, Setters, Serialize, Deserialize,
 )]
 pub struct DeviceFeatureSensor {
   #[getset(get = "pub", get_mut = "pub(super)")]
   #[serde(rename = "value-range")]
-  #[serde(serialize_with = "range_sequence_serialize")]
+  #[serde(
+    serialize_with = "range_sequence_serialize",
+    deserialize_with = "range_sequence_deserialize"
+  )]
   value_range: Vec<RangeInclusive<i32>>,
+  #[getset(get = "pub", set = "pub")]
+  #[serde(rename = "unit", default, skip_serializing_if = "Option::is_none")]
+  unit: Option<SensorUnit>,
   #[getset(get = "pub")]
   #[serde(rename = "messages")]
   messages: HashSet<ButtplugSensorFeatureMessageType>,
@@ -255,6 +394,7 @@ impl DeviceFeatureSensor {
   ) -> Self {
     Self {
       value_range: value_range.clone(),
+      unit: None,
       messages: messages.clone(),
     }
   }
@@ -11,8 +11,38 @@ use super::*;
 use getset::CopyGetters;
 #[cfg(feature = "serialize-json")]
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
-#[derive(Debug, Default, ButtplugMessage, Clone, PartialEq, Eq, CopyGetters)]
+/// Why a device was removed from the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
+pub enum DeviceRemovedReason {
+  /// The device went away on its own (unplugged, turned off, out of range).
+  DeviceInitiated,
+  /// The server shut down.
+  ServerShutdown,
+  /// The device configuration changed, and the device is no longer allowed to connect.
+  ConfigDenied,
+  /// The device disconnected after writes to it failed.
+  WriteFailure,
+  /// The application owning the server asked for the device to be disconnected.
+  ClientRequest,
+}
+
+impl fmt::Display for DeviceRemovedReason {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let reason = match self {
+      DeviceRemovedReason::DeviceInitiated => "device initiated",
+      DeviceRemovedReason::ServerShutdown => "server shutdown",
+      DeviceRemovedReason::ConfigDenied => "denied by configuration",
+      DeviceRemovedReason::WriteFailure => "write failure",
+      DeviceRemovedReason::ClientRequest => "client request",
+    };
+    write!(f, "{}", reason)
+  }
+}
+
+#[derive(Debug, Default, ButtplugMessage, Clone, Eq, CopyGetters)]
 #[cfg_attr(feature = "serialize-json", derive(Serialize, Deserialize))]
 pub struct DeviceRemoved {
   #[cfg_attr(feature = "serialize-json", serde(rename = "Id"))]
@@ -20,6 +50,19 @@ pub struct DeviceRemoved {
   #[cfg_attr(feature = "serialize-json", serde(rename = "DeviceIndex"))]
   #[getset(get_copy = "pub")]
   device_index: u32,
+  /// Set for messages coming from the server. None of the current message spec versions allow
+  /// extra fields on DeviceRemoved, so this is never serialized, and clients behind a serializing
+  /// connector always see None. In process clients get the reason as the server set it. Not
+  /// compared by equality, so messages match whether or not the reason survived the trip.
+  #[cfg_attr(feature = "serialize-json", serde(skip))]
+  #[getset(get_copy = "pub")]
+  reason: Option<DeviceRemovedReason>,
+}
+
+impl PartialEq for DeviceRemoved {
+  fn eq(&self, other: &Self) -> bool {
+    self.id == other.id && self.device_index == other.device_index
+  }
 }
 
 impl DeviceRemoved {
@@ -27,8 +70,14 @@ impl DeviceRemoved {
     Self {
       id: 0,
       device_index,
+      reason: None,
     }
   }
+
+  pub fn with_reason(mut self, reason: DeviceRemovedReason) -> Self {
+    self.reason = Some(reason);
+    self
+  }
 }
 
 impl ButtplugMessageValidator for DeviceRemoved {
@@ -73,6 +73,7 @@ pub use client_device_message_attributes::{
   RawDeviceMessageAttributes,
   SensorDeviceMessageAttributes,
   SensorType,
+  SensorUnit,
 };
 pub use device_added::{DeviceAdded, DeviceAddedV0, DeviceAddedV1, DeviceAddedV2};
 pub use device_feature::{
@@ -81,6 +82,8 @@ pub use device_feature::{
   DeviceFeatureRaw,
   DeviceFeatureSensor,
   FeatureType,
+  IntensityCurve,
+  IntensityCurvePreset,
 };
 pub use device_list::{DeviceList, DeviceListV0, DeviceListV1, DeviceListV2};
 pub use device_message_info::{
@@ -89,7 +92,7 @@ pub use device_message_info::{
   DeviceMessageInfoV1,
   DeviceMessageInfoV2,
 };
-pub use device_removed::DeviceRemoved;
+pub use device_removed::{DeviceRemoved, DeviceRemovedReason};
 pub use endpoint::Endpoint;
 pub use error::{Error, ErrorCode, ErrorV0};
 pub use fleshlight_launch_fw12_cmd::FleshlightLaunchFW12Cmd;
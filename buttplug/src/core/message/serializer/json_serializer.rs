@@ -343,7 +343,12 @@ impl ButtplugMessageSerializer for ButtplugClientJSONSerializer {
 #[cfg(test)]
 mod test {
   use super::*;
-  use crate::core::message::{RequestServerInfo, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION};
+  use crate::core::message::{
+    DeviceRemoved,
+    DeviceRemovedReason,
+    RequestServerInfo,
+    BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+  };
 
   #[test]
   fn test_correct_message_version() {
@@ -364,6 +369,22 @@ mod test {
     );
   }
 
+  #[test]
+  fn test_device_removed_reason_not_serialized() {
+    let serializer = ButtplugServerJSONSerializer::default();
+    serializer.force_message_version(&BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION);
+    let msg = DeviceRemoved::new(1).with_reason(DeviceRemovedReason::WriteFailure);
+    let json = match serializer.serialize(&[msg.clone().into()]) {
+      ButtplugSerializedMessage::Text(json) => json,
+      _ => panic!("JSON serializer should always produce text"),
+    };
+    assert_eq!(json, r#"[{"DeviceRemoved":{"Id":0,"DeviceIndex":1}}]"#);
+    // The reason isn't part of the message as far as equality goes either.
+    assert_eq!(msg, DeviceRemoved::new(1));
+    assert!(create_message_validator()
+      .is_valid(&serde_json::from_str(&json).expect("Infallible deserialization")));
+  }
+
   #[test]
   fn test_wrong_message_version() {
     let json = r#"[{
@@ -7,6 +7,11 @@
 
 //! Protocol message and error definitions.
 
+#[cfg(any(
+  feature = "tokio-runtime",
+  feature = "wasm-bindgen-runtime",
+  feature = "dummy-runtime"
+))]
 pub mod connector;
 pub mod errors;
 pub mod message;
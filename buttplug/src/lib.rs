@@ -39,6 +39,19 @@ extern crate futures;
 #[macro_use]
 extern crate tracing;
 
+// Only the message types (see the client-messages-only feature) build without a runtime.
+#[cfg(all(
+  any(feature = "client", feature = "server", feature = "websockets"),
+  not(any(
+    feature = "tokio-runtime",
+    feature = "wasm-bindgen-runtime",
+    feature = "dummy-runtime"
+  ))
+))]
+std::compile_error!(
+  "Please choose a runtime feature: tokio-runtime, wasm-bindgen-runtime, dummy-runtime"
+);
+
 #[cfg(feature = "client")]
 pub mod client;
 pub mod core;
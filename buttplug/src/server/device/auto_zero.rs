@@ -0,0 +1,95 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Dead man timer for devices with an auto zero timeout in the user device configuration. Unlike
+//! the client ping, which covers the whole connection, this stops a single device once it goes
+//! without actuation commands for too long, even if the client is still there.
+
+use crate::util::{self, async_manager};
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+#[derive(Default)]
+struct TimerState {
+  /// Cancels the running countdown.
+  cancel: CancellationToken,
+  /// True from the timer running out until the expiry is handled, or the timer restarts.
+  expired: bool,
+}
+
+/// Counts down from every actuation command, and reports on [Self::expiry_stream] once the timeout
+/// passes without another one. Does nothing for devices without a timeout.
+pub(super) struct AutoZeroTimer {
+  timeout: Option<Duration>,
+  state: Arc<Mutex<TimerState>>,
+  expiry_sender: broadcast::Sender<()>,
+}
+
+impl AutoZeroTimer {
+  pub(super) fn new(timeout: Option<Duration>) -> Self {
+    Self {
+      timeout,
+      state: Arc::new(Mutex::new(TimerState::default())),
+      expiry_sender: broadcast::channel(1).0,
+    }
+  }
+
+  /// Start counting down again, for an actuation command.
+  pub(super) fn reset(&self) {
+    let timeout = if let Some(timeout) = self.timeout {
+      timeout
+    } else {
+      return;
+    };
+    let cancel = {
+      let mut state = self.state.lock().expect("Lock poisoned");
+      state.cancel.cancel();
+      state.cancel = CancellationToken::new();
+      state.expired = false;
+      state.cancel.clone()
+    };
+    let state = self.state.clone();
+    let expiry_sender = self.expiry_sender.clone();
+    async_manager::spawn(async move {
+      tokio::select! {
+        _ = util::sleep(timeout) => {}
+        _ = cancel.cancelled() => return,
+      }
+      {
+        // Checked under the lock, so a reset racing the expiry always wins.
+        let mut state = state.lock().expect("Lock poisoned");
+        if cancel.is_cancelled() {
+          return;
+        }
+        state.expired = true;
+      }
+      // No receivers just means the device is gone.
+      let _ = expiry_sender.send(());
+    });
+  }
+
+  /// Stop counting down, for stops, disconnects, and device removal.
+  pub(super) fn cancel(&self) {
+    let mut state = self.state.lock().expect("Lock poisoned");
+    state.cancel.cancel();
+    state.expired = false;
+  }
+
+  /// True if the timer ran out, and nothing restarted or cancelled it since. Only true once per
+  /// expiry.
+  pub(super) fn take_expired(&self) -> bool {
+    std::mem::take(&mut self.state.lock().expect("Lock poisoned").expired)
+  }
+
+  pub(super) fn expiry_stream(&self) -> broadcast::Receiver<()> {
+    self.expiry_sender.subscribe()
+  }
+}
@@ -0,0 +1,210 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Per-device limit on hardware commands waiting to be written, so clients sending commands faster
+//! than the hardware can take them don't build up an unbounded backlog.
+
+use super::command_rate_limiter::{coalesce_pending, PendingCommands};
+use crate::core::{
+  errors::{ButtplugDeviceError, ButtplugError},
+  message::ActuatorType,
+};
+use futures::future::{BoxFuture, FutureExt};
+use getset::CopyGetters;
+use serde::Serialize;
+use std::sync::{
+  atomic::{AtomicU32, AtomicU64, Ordering},
+  Arc,
+  Mutex,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Command batches a device can have waiting on the hardware, unless the user config sets
+/// `max-queued-commands`.
+pub const DEFAULT_MAX_QUEUED_COMMANDS: u32 = 64;
+
+/// Command queue counters for a device, part of its [DeviceIoStats](super::hardware::DeviceIoStats).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, CopyGetters)]
+#[getset(get_copy = "pub")]
+pub struct CommandQueueStats {
+  /// Most command batches that can wait on the hardware at once.
+  limit: u32,
+  /// Command batches waiting on the hardware right now.
+  depth: u32,
+  /// Highest depth the queue has reached.
+  max_depth: u32,
+  /// Scalar and rotation commands merged into a pending command because the queue was full.
+  coalesced: u64,
+  /// Commands refused with [ButtplugDeviceError::DeviceBusy] because the queue was full.
+  rejected: u64,
+}
+
+/// A place in the queue. Held until the commands it was taken for are written.
+pub(super) struct QueueSlot {
+  _permit: OwnedSemaphorePermit,
+}
+
+#[derive(Default)]
+struct QueueCounters {
+  max_depth: AtomicU32,
+  coalesced: AtomicU64,
+  rejected: AtomicU64,
+}
+
+/// Bounds the hardware command batches waiting on a single device.
+///
+/// Commands take a place in the queue from when they're handed to the hardware until the write is
+/// done. Once the queue is full, scalar and rotation commands are merged per feature index into a
+/// single pending command (newest value wins), which is written when a place frees up. Other
+/// commands are refused with [ButtplugDeviceError::DeviceBusy]. Stop commands don't take a place,
+/// and drop anything pending.
+#[derive(Clone)]
+pub(super) struct CommandQueue {
+  limit: u32,
+  slots: Arc<Semaphore>,
+  counters: Arc<QueueCounters>,
+  pending_scalar: PendingCommands<(ActuatorType, u32)>,
+  pending_rotation: PendingCommands<(u32, bool)>,
+}
+
+impl CommandQueue {
+  /// Create a new queue. A limit of 0 is treated as 1.
+  pub fn new(limit: u32) -> Self {
+    let limit = limit.max(1);
+    Self {
+      limit,
+      slots: Arc::new(Semaphore::new(limit as usize)),
+      counters: Arc::new(QueueCounters::default()),
+      pending_scalar: Arc::new(Mutex::new(None)),
+      pending_rotation: Arc::new(Mutex::new(None)),
+    }
+  }
+
+  fn depth(&self) -> u32 {
+    self.limit - self.slots.available_permits() as u32
+  }
+
+  fn take_slot(&self, permit: OwnedSemaphorePermit) -> QueueSlot {
+    self
+      .counters
+      .max_depth
+      .fetch_max(self.depth(), Ordering::Relaxed);
+    QueueSlot { _permit: permit }
+  }
+
+  /// Take a place in the queue, or fail with [ButtplugDeviceError::DeviceBusy] if it's full.
+  pub fn enqueue(&self, device_name: &str) -> Result<QueueSlot, ButtplugDeviceError> {
+    match self.slots.clone().try_acquire_owned() {
+      Ok(permit) => Ok(self.take_slot(permit)),
+      Err(_) => {
+        self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+        warn!(
+          "Command queue for {} is full ({} commands), refusing command.",
+          device_name, self.limit
+        );
+        Err(ButtplugDeviceError::DeviceBusy(
+          device_name.to_owned(),
+          "too many commands are waiting to be written".to_owned(),
+        ))
+      }
+    }
+  }
+
+  /// Wait for a place in the queue.
+  fn wait_for_slot(&self) -> BoxFuture<'static, QueueSlot> {
+    let queue = self.clone();
+    async move {
+      let permit = queue
+        .slots
+        .clone()
+        .acquire_owned()
+        .await
+        .expect("Semaphore is never closed");
+      queue.take_slot(permit)
+    }
+    .boxed()
+  }
+
+  /// Take a place in the queue for scalar commands, unless the queue is full or scalar commands
+  /// are already waiting on a place. In that case, the commands should go through
+  /// [Self::coalesce_scalar].
+  pub fn try_enqueue_scalar(&self) -> Option<QueueSlot> {
+    self.try_enqueue_coalescable(&self.pending_scalar)
+  }
+
+  /// Same as [Self::try_enqueue_scalar], for rotation commands.
+  pub fn try_enqueue_rotation(&self) -> Option<QueueSlot> {
+    self.try_enqueue_coalescable(&self.pending_rotation)
+  }
+
+  fn try_enqueue_coalescable<T>(&self, pending: &PendingCommands<T>) -> Option<QueueSlot> {
+    if pending.lock().expect("Lock poisoned").is_some() {
+      return None;
+    }
+    let permit = self.slots.clone().try_acquire_owned().ok()?;
+    Some(self.take_slot(permit))
+  }
+
+  /// Merge scalar commands into the ones waiting on a place in the queue. See
+  /// [coalesce_pending].
+  pub fn coalesce_scalar<F>(
+    &self,
+    commands: &[Option<(ActuatorType, u32)>],
+    send: F,
+  ) -> BoxFuture<'static, Result<(), ButtplugError>>
+  where
+    F: FnOnce(Vec<Option<(ActuatorType, u32)>>) -> BoxFuture<'static, Result<(), ButtplugError>>
+      + Send
+      + 'static,
+  {
+    self.counters.coalesced.fetch_add(1, Ordering::Relaxed);
+    let queue = self.clone();
+    coalesce_pending(
+      self.pending_scalar.clone(),
+      commands,
+      move || queue.wait_for_slot(),
+      send,
+    )
+  }
+
+  /// Merge rotation commands into the ones waiting on a place in the queue.
+  pub fn coalesce_rotation<F>(
+    &self,
+    commands: &[Option<(u32, bool)>],
+    send: F,
+  ) -> BoxFuture<'static, Result<(), ButtplugError>>
+  where
+    F: FnOnce(Vec<Option<(u32, bool)>>) -> BoxFuture<'static, Result<(), ButtplugError>>
+      + Send
+      + 'static,
+  {
+    self.counters.coalesced.fetch_add(1, Ordering::Relaxed);
+    let queue = self.clone();
+    coalesce_pending(
+      self.pending_rotation.clone(),
+      commands,
+      move || queue.wait_for_slot(),
+      send,
+    )
+  }
+
+  /// Drop any commands waiting on a place, so they won't be sent after a stop.
+  pub fn preempt(&self) {
+    self.pending_scalar.lock().expect("Lock poisoned").take();
+    self.pending_rotation.lock().expect("Lock poisoned").take();
+  }
+
+  pub fn stats(&self) -> CommandQueueStats {
+    CommandQueueStats {
+      limit: self.limit,
+      depth: self.depth(),
+      max_depth: self.counters.max_depth.load(Ordering::Relaxed),
+      coalesced: self.counters.coalesced.load(Ordering::Relaxed),
+      rejected: self.counters.rejected.load(Ordering::Relaxed),
+    }
+  }
+}
@@ -0,0 +1,226 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Per-device hardware write throttling, configured via the user device configuration.
+
+use crate::{
+  core::{errors::ButtplugError, message::ActuatorType},
+  util::{self, async_manager, Instant},
+};
+use futures::future::{BoxFuture, FutureExt};
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+use tokio::sync::{oneshot, Mutex as AsyncMutex, OwnedMutexGuard};
+
+/// How a batch of commands should be handled when a device has a command rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum CommandDispatch {
+  /// Only the newest value for each feature matters, so intermediate commands can be merged
+  /// together. Used for scalar and rotation commands.
+  Coalesce,
+  /// Every command must reach the device, in order, so commands wait for a free write slot.
+  Queue,
+  /// Skip the rate limit entirely. Used for stop commands, which should never be delayed.
+  Immediate,
+}
+
+/// Commands waiting on the next write slot, along with everyone waiting on them to be written.
+pub(super) struct Pending<T> {
+  commands: Vec<Option<T>>,
+  waiters: Vec<oneshot::Sender<Result<(), ButtplugError>>>,
+}
+
+pub(super) type PendingCommands<T> = Arc<Mutex<Option<Pending<T>>>>;
+
+/// Holds the exclusive right to write to the device. Marks the time of the write when dropped.
+pub(super) struct WriteSlot(OwnedMutexGuard<Option<Instant>>);
+
+impl Drop for WriteSlot {
+  fn drop(&mut self) {
+    *self.0 = Some(Instant::now());
+  }
+}
+
+/// Limits the rate of writes to a single device.
+///
+/// Writes are spaced at least `1 / max_rate_hz` seconds apart. Coalesced commands are merged per
+/// feature index while waiting on the next write slot (newest value wins), queued commands wait
+/// their turn, and immediate commands drop anything pending and go out as soon as a coalesced write
+/// that's already being sent is done.
+#[derive(Clone)]
+pub(super) struct CommandRateLimiter {
+  max_rate_hz: u32,
+  interval: Duration,
+  last_write: Arc<AsyncMutex<Option<Instant>>>,
+  pending_scalar: PendingCommands<(ActuatorType, u32)>,
+  pending_rotation: PendingCommands<(u32, bool)>,
+  /// Held while coalesced commands are being sent, from the moment they're taken out of pending.
+  in_flight: Arc<AsyncMutex<()>>,
+}
+
+impl CommandRateLimiter {
+  /// Create a new limiter. Returns None if the rate is 0, which means no limit.
+  pub fn new(max_rate_hz: u32) -> Option<Self> {
+    if max_rate_hz == 0 {
+      return None;
+    }
+    Some(Self {
+      max_rate_hz,
+      interval: Duration::from_secs_f64(1.0 / max_rate_hz as f64),
+      last_write: Arc::new(AsyncMutex::new(None)),
+      pending_scalar: Arc::new(Mutex::new(None)),
+      pending_rotation: Arc::new(Mutex::new(None)),
+      in_flight: Arc::new(AsyncMutex::new(())),
+    })
+  }
+
+  pub fn max_rate_hz(&self) -> u32 {
+    self.max_rate_hz
+  }
+
+  /// Wait until the device can be written to again.
+  pub async fn acquire(&self) -> WriteSlot {
+    let last_write = self.last_write.clone().lock_owned().await;
+    if let Some(last) = *last_write {
+      let elapsed = Instant::now().duration_since(last);
+      if elapsed < self.interval {
+        util::sleep(self.interval - elapsed).await;
+      }
+    }
+    WriteSlot(last_write)
+  }
+
+  /// Drop any pending coalesced commands, so they won't be sent after an immediate write. Commands
+  /// already being sent can't be taken back, see [Self::wait_for_in_flight].
+  pub fn preempt(&self) {
+    self.pending_scalar.lock().expect("Lock poisoned").take();
+    self.pending_rotation.lock().expect("Lock poisoned").take();
+    if let Ok(mut last_write) = self.last_write.try_lock() {
+      *last_write = Some(Instant::now());
+    }
+  }
+
+  /// Wait for coalesced commands that are already being sent, so an immediate write made while
+  /// holding the guard can't be overtaken by them. Call after [Self::preempt].
+  pub async fn wait_for_in_flight(&self) -> OwnedMutexGuard<()> {
+    self.in_flight.clone().lock_owned().await
+  }
+
+  pub fn coalesce_scalar<F>(
+    &self,
+    commands: &[Option<(ActuatorType, u32)>],
+    send: F,
+  ) -> BoxFuture<'static, Result<(), ButtplugError>>
+  where
+    F: FnOnce(Vec<Option<(ActuatorType, u32)>>) -> BoxFuture<'static, Result<(), ButtplugError>>
+      + Send
+      + 'static,
+  {
+    self.coalesce(self.pending_scalar.clone(), commands, send)
+  }
+
+  pub fn coalesce_rotation<F>(
+    &self,
+    commands: &[Option<(u32, bool)>],
+    send: F,
+  ) -> BoxFuture<'static, Result<(), ButtplugError>>
+  where
+    F: FnOnce(Vec<Option<(u32, bool)>>) -> BoxFuture<'static, Result<(), ButtplugError>>
+      + Send
+      + 'static,
+  {
+    self.coalesce(self.pending_rotation.clone(), commands, send)
+  }
+
+  /// Merge commands into the next write. The returned future resolves with the result of that
+  /// write, or Ok if an immediate write replaced the commands before they went out. Dropping it
+  /// doesn't cancel the write.
+  fn coalesce<T, F>(
+    &self,
+    pending: PendingCommands<T>,
+    commands: &[Option<T>],
+    send: F,
+  ) -> BoxFuture<'static, Result<(), ButtplugError>>
+  where
+    T: Clone + Send + 'static,
+    F: FnOnce(Vec<Option<T>>) -> BoxFuture<'static, Result<(), ButtplugError>> + Send + 'static,
+  {
+    let limiter = self.clone();
+    coalesce_pending(
+      pending,
+      commands,
+      move || {
+        async move {
+          let slot = limiter.acquire().await;
+          (slot, limiter.wait_for_in_flight().await)
+        }
+        .boxed()
+      },
+      send,
+    )
+  }
+}
+
+/// Merge commands into the pending ones, which are sent once `wait` resolves. The value `wait`
+/// resolves with is held until the send is done. If nothing was pending, this schedules the send,
+/// otherwise the commands go out with the send that's already scheduled.
+///
+/// The returned future resolves with the result of the send, or Ok if the pending commands were
+/// dropped before they went out. Dropping it doesn't cancel the send.
+pub(super) fn coalesce_pending<T, F, W, S>(
+  pending: PendingCommands<T>,
+  commands: &[Option<T>],
+  wait: W,
+  send: F,
+) -> BoxFuture<'static, Result<(), ButtplugError>>
+where
+  T: Clone + Send + 'static,
+  F: FnOnce(Vec<Option<T>>) -> BoxFuture<'static, Result<(), ButtplugError>> + Send + 'static,
+  W: FnOnce() -> BoxFuture<'static, S> + Send + 'static,
+  S: Send + 'static,
+{
+  let (waiter, written) = oneshot::channel();
+  let written = written.map(|result| result.unwrap_or(Ok(()))).boxed();
+  {
+    let mut pending_guard = pending.lock().expect("Lock poisoned");
+    if let Some(existing) = pending_guard.as_mut() {
+      // A send is already scheduled, merge our values into it and let it go out on its own.
+      if existing.commands.len() < commands.len() {
+        existing.commands.resize(commands.len(), None);
+      }
+      for (index, command) in commands.iter().enumerate() {
+        if command.is_some() {
+          existing.commands[index] = command.clone();
+        }
+      }
+      existing.waiters.push(waiter);
+      return written;
+    }
+    *pending_guard = Some(Pending {
+      commands: commands.to_vec(),
+      waiters: vec![waiter],
+    });
+  }
+  async_manager::spawn(async move {
+    let _slot = wait().await;
+    let pending = pending.lock().expect("Lock poisoned").take();
+    // If an immediate write preempted us, there's nothing left to do.
+    if let Some(pending) = pending {
+      let result = send(pending.commands).await;
+      if let Err(e) = &result {
+        warn!("Error writing coalesced command to device: {:?}", e);
+      }
+      for waiter in pending.waiters {
+        // Not everyone waits on the write.
+        let _ = waiter.send(result.clone());
+      }
+    }
+  });
+  written
+}
@@ -1,7 +1,9 @@
 use getset::{CopyGetters, Getters, MutGetters, Setters};
 use serde::{Deserialize, Serialize};
 
-use crate::core::message::{DeviceFeature, Endpoint};
+use super::UserDeviceIdentifier;
+use crate::core::message::{ActuatorType, DeviceFeature, Endpoint, SensorType};
+use std::{collections::BTreeMap, ops::RangeInclusive};
 
 #[derive(Debug, Clone, Getters)]
 #[getset(get = "pub")]
@@ -22,21 +24,257 @@ impl BaseDeviceDefinition {
   }
 }
 
-#[derive(Serialize, Deserialize, Debug, Getters, CopyGetters, Default, Clone)]
+/// Another device that scalar and stop commands get mirrored to, for controlling multiple devices
+/// as one (e.g. linking the channels of two DG-Lab boxes).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Getters)]
+#[getset(get = "pub")]
+pub struct DeviceMirror {
+  /// Device that receives the mirrored commands.
+  identifier: UserDeviceIdentifier,
+  /// Scale factor for each scalar feature, by feature index. Features past the end of the list
+  /// are mirrored unscaled.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  #[serde(rename = "feature-scales")]
+  feature_scales: Vec<f64>,
+}
+
+impl DeviceMirror {
+  pub fn new(identifier: &UserDeviceIdentifier, feature_scales: &[f64]) -> Self {
+    Self {
+      identifier: identifier.clone(),
+      feature_scales: feature_scales.to_vec(),
+    }
+  }
+
+  /// Scale the value of a scalar command for the given feature index, clamped to [0, 1].
+  pub fn scale(&self, index: u32, scalar: f64) -> f64 {
+    let factor = self
+      .feature_scales
+      .get(index as usize)
+      .copied()
+      .unwrap_or(1.0);
+    (scalar * factor).clamp(0.0, 1.0)
+  }
+}
+
+/// Limit on how fast scalar features can increase, for devices where sudden jumps in power are
+/// painful or unsafe (e.g. e-stim). Increases beyond the limit are ramped up to over time by the
+/// server. Decreases and stops are never limited.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Getters, CopyGetters)]
+pub struct ScalarRamp {
+  /// Maximum increase per second, in feature steps.
+  #[serde(rename = "max-step-per-second")]
+  #[getset(get_copy = "pub")]
+  max_step_per_second: u32,
+  /// Scalar feature indexes (as used in ScalarCmd) the limit applies to.
+  #[getset(get = "pub")]
+  features: Vec<u32>,
+}
+
+impl ScalarRamp {
+  pub fn new(max_step_per_second: u32, features: &[u32]) -> Self {
+    Self {
+      max_step_per_second,
+      features: features.to_vec(),
+    }
+  }
+}
+
+/// Rotation features whose direction is flipped, for devices that turn the other way around from
+/// what clients expect. Either `true` for every rotation feature of the device, or a list of
+/// RotateCmd feature indexes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum RotationInversion {
+  All(bool),
+  Features(Vec<u32>),
+}
+
+impl RotationInversion {
+  /// True if the rotation feature at `index` is inverted.
+  pub fn inverts(&self, index: u32) -> bool {
+    match self {
+      Self::All(all) => *all,
+      Self::Features(features) => features.contains(&index),
+    }
+  }
+}
+
+fn default_calibration_scale() -> f64 {
+  1.0
+}
+
+/// Calibration of a sensor's raw values, for sensors whose range differs between units of the
+/// same model. Readings are reported to clients as `(raw + offset) * scale`, rounded.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, CopyGetters)]
+#[getset(get_copy = "pub")]
+pub struct SensorCalibration {
+  #[serde(default)]
+  offset: i32,
+  #[serde(default = "default_calibration_scale")]
+  scale: f64,
+}
+
+impl SensorCalibration {
+  pub fn new(offset: i32, scale: f64) -> Self {
+    Self { offset, scale }
+  }
+
+  /// Calibration mapping the lowest and highest raw values observed on a device to 0 and 100.
+  pub fn from_observed_range(min: i32, max: i32) -> Self {
+    let span = (max - min).max(1);
+    Self::new(-min, 100.0 / span as f64)
+  }
+
+  pub fn apply(&self, raw: i32) -> i32 {
+    ((raw as f64 + self.offset as f64) * self.scale)
+      .round()
+      .clamp(i32::MIN as f64, i32::MAX as f64) as i32
+  }
+
+  /// Range that calibrated values fall in, for a sensor reporting raw values in `range`.
+  pub fn apply_range(&self, range: &RangeInclusive<i32>) -> RangeInclusive<i32> {
+    let (start, end) = (self.apply(*range.start()), self.apply(*range.end()));
+    start.min(end)..=start.max(end)
+  }
+}
+
+/// Whether the user config puts a device on the allow list, the deny list, or neither.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeviceAccess {
+  /// On neither list. The device connects unless other devices are on the allow list.
+  #[default]
+  Default,
+  Allow,
+  Deny,
+}
+
+#[derive(Serialize, Deserialize, Debug, Getters, CopyGetters, Setters, Default, Clone)]
 pub struct UserDeviceCustomization {
   #[serde(skip_serializing_if = "Option::is_none")]
   #[serde(default)]
   #[serde(rename = "display-name")]
-  #[getset(get = "pub")]
+  #[getset(get = "pub", set = "pub")]
   display_name: Option<String>,
   #[serde(default)]
-  #[getset(get_copy = "pub")]
+  #[getset(get_copy = "pub", set = "pub")]
   allow: bool,
   #[serde(default)]
-  #[getset(get_copy = "pub")]
+  #[getset(get_copy = "pub", set = "pub")]
   deny: bool,
-  #[getset(get_copy = "pub")]
+  #[getset(get_copy = "pub", set = "pub(crate)")]
   index: u32,
+  /// True if the index was assigned by the library the first time the device was seen, rather than
+  /// reserved by the user. Saving the user config keeps the index for later sessions, but if a
+  /// user reservation claims the same index, the reservation wins and this device gets a new one.
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  #[serde(default)]
+  #[serde(rename = "auto-index")]
+  #[getset(get_copy = "pub", set = "pub")]
+  auto_index: bool,
+  /// Maximum rate, in hz, that commands will be written to the device. None means no limit.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  #[serde(rename = "max-command-rate-hz")]
+  #[getset(get_copy = "pub", set = "pub")]
+  max_command_rate_hz: Option<u32>,
+  /// Most command batches that can wait on the device at once, see
+  /// [DEFAULT_MAX_QUEUED_COMMANDS](crate::server::device::DEFAULT_MAX_QUEUED_COMMANDS).
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  #[serde(rename = "max-queued-commands")]
+  #[getset(get_copy = "pub", set = "pub")]
+  max_queued_commands: Option<u32>,
+  /// If set, the device is stopped once this long passes without an actuation command, even if
+  /// the client is still connected. Sensor commands don't count.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[serde(default)]
+  #[serde(rename = "auto-zero-timeout-ms")]
+  #[getset(get_copy = "pub", set = "pub")]
+  auto_zero_timeout_ms: Option<u32>,
+  /// If true, hardware writes are logged instead of sent to the device.
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  #[serde(default)]
+  #[serde(rename = "dry-run")]
+  #[getset(get_copy = "pub", set = "pub")]
+  dry_run: bool,
+  /// If true, the device is only used for telemetry. Actuation messages are neither advertised to
+  /// clients nor accepted, and stop commands become no-ops, so nothing ever moves the device.
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  #[serde(default)]
+  #[serde(rename = "monitor-only")]
+  #[getset(get_copy = "pub", set = "pub")]
+  monitor_only: bool,
+  /// If true, every write to the device is sent with response, for devices that drop writes
+  /// without response.
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  #[serde(default)]
+  #[serde(rename = "write-with-response")]
+  #[getset(get_copy = "pub", set = "pub")]
+  write_with_response: bool,
+  /// If true, replies to commands held back by the command rate limit or a full command queue wait
+  /// until the command is written, so write errors reach the client. Otherwise they're sent once
+  /// the command is queued.
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  #[serde(default)]
+  #[serde(rename = "ack-on-write")]
+  #[getset(get_copy = "pub", set = "pub")]
+  ack_on_write: bool,
+  /// If true, the first client connection to send the device an actuation command claims it, and
+  /// actuation commands from other clients are refused until the claim is released. See
+  /// [ServerDevice::claim](crate::server::device::ServerDevice::claim).
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  #[serde(default)]
+  #[getset(get_copy = "pub", set = "pub")]
+  exclusive: bool,
+  /// If true, the device manager scans for the device when the server starts, and connects it
+  /// without waiting on a client to start scanning.
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  #[serde(default)]
+  #[serde(rename = "auto-connect")]
+  #[getset(get_copy = "pub", set = "pub")]
+  auto_connect: bool,
+  /// If true, protocols with two output channels (DG-Lab) swap them, for devices cabled the other
+  /// way around from what clients expect. Features keep their order, so commands for the channel A
+  /// features are output on channel B, and the other way around.
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  #[serde(default)]
+  #[serde(rename = "swap-channels")]
+  #[getset(get_copy = "pub", set = "pub")]
+  swap_channels: bool,
+  /// Rotation features that turn the other way around. Commands have their direction flipped
+  /// before they reach the protocol, so clockwise from a client is counterclockwise on the wire.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  #[serde(rename = "invert-rotation")]
+  #[getset(get = "pub", set = "pub")]
+  invert_rotation: Option<RotationInversion>,
+  /// Identifier of a base config configuration of the protocol to take the name and features
+  /// from, instead of the one matching the device. For layouts the hardware can't tell apart, like
+  /// the dg-lab-v2 "simple" configuration.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  #[getset(get = "pub", set = "pub")]
+  configuration: Option<String>,
+  /// Devices that scalar and stop commands sent to this device are mirrored to.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  #[getset(get = "pub", set = "pub")]
+  mirror: Vec<DeviceMirror>,
+  /// Ramp limit for increases of scalar features.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  #[getset(get = "pub", set = "pub")]
+  ramp: Option<ScalarRamp>,
+  /// Scalar features (keyed by ScalarCmd feature index) that also accept commands for a second
+  /// actuator type, with values passed through unchanged. One of the feature type and the accepted
+  /// type must be Vibrate, e.g. mapping a DG-Lab frequency feature to Vibrate lets clients that
+  /// only know VibrateCmd drive it.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  #[serde(rename = "treat-vibrate-as")]
+  #[getset(get = "pub", set = "pub")]
+  treat_vibrate_as: BTreeMap<u32, ActuatorType>,
+  /// Calibration applied to readings of sensors of each type before they're sent to clients.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  #[serde(rename = "sensor-calibration")]
+  #[getset(get = "pub", set = "pub")]
+  sensor_calibration: BTreeMap<SensorType, SensorCalibration>,
 }
 
 impl UserDeviceCustomization {
@@ -46,8 +284,80 @@ impl UserDeviceCustomization {
       allow,
       deny,
       index,
+      auto_index: false,
+      max_command_rate_hz: None,
+      max_queued_commands: None,
+      auto_zero_timeout_ms: None,
+      dry_run: false,
+      monitor_only: false,
+      write_with_response: false,
+      ack_on_write: false,
+      exclusive: false,
+      auto_connect: false,
+      swap_channels: false,
+      invert_rotation: None,
+      configuration: None,
+      mirror: vec![],
+      ramp: None,
+      treat_vibrate_as: BTreeMap::new(),
+      sensor_calibration: BTreeMap::new(),
+    }
+  }
+
+  /// Which access list the device is on. Configs setting both allow and deny are rejected when
+  /// loaded or added to the [DeviceConfigurationManager](super::DeviceConfigurationManager), but
+  /// deny wins for any that are built by hand.
+  pub fn access(&self) -> DeviceAccess {
+    if self.deny {
+      DeviceAccess::Deny
+    } else if self.allow {
+      DeviceAccess::Allow
+    } else {
+      DeviceAccess::Default
+    }
+  }
+}
+
+/// Expand the placeholders in a display name template.
+///
+/// `{name}` is replaced with the device config name, `{index}` with the device index, and
+/// `{address-short}` with the last 4 characters of the device address. `{{` and `}}` are literal
+/// braces. Any other braces are left as they are.
+pub fn expand_display_name_template(
+  template: &str,
+  name: &str,
+  index: u32,
+  address: &str,
+) -> String {
+  let address_chars: Vec<char> = address.chars().collect();
+  let address_short: String = address_chars[address_chars.len().saturating_sub(4)..]
+    .iter()
+    .collect();
+  let placeholders = [
+    ("{{", "{".to_owned()),
+    ("}}", "}".to_owned()),
+    ("{name}", name.to_owned()),
+    ("{index}", index.to_string()),
+    ("{address-short}", address_short),
+  ];
+  let mut expanded = String::with_capacity(template.len());
+  let mut rest = template;
+  while let Some(position) = rest.find(['{', '}']) {
+    expanded.push_str(&rest[..position]);
+    rest = &rest[position..];
+    if let Some((placeholder, value)) = placeholders
+      .iter()
+      .find(|(placeholder, _)| rest.starts_with(placeholder))
+    {
+      expanded.push_str(value);
+      rest = &rest[placeholder.len()..];
+    } else {
+      expanded.push_str(&rest[..1]);
+      rest = &rest[1..];
     }
   }
+  expanded.push_str(rest);
+  expanded
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Getters, Setters, MutGetters)]
@@ -81,7 +391,8 @@ impl UserDeviceDefinition {
       name: def.name().clone(),
       features: def.features().clone(),
       user_config: UserDeviceCustomization {
-        index: index,
+        index,
+        auto_index: true,
         ..Default::default()
       },
     }
@@ -93,3 +404,33 @@ impl UserDeviceDefinition {
       .push(DeviceFeature::new_raw_feature(endpoints));
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::expand_display_name_template;
+
+  #[test]
+  fn test_display_name_template_placeholders() {
+    let expand = |template| expand_display_name_template(template, "Hush", 2, "AA:BB:CC:DD:EE:FF");
+    assert_eq!(expand("{name} #{index}"), "Hush #2");
+    assert_eq!(expand("Toy {address-short}"), "Toy E:FF");
+    assert_eq!(expand("{name}{name}"), "HushHush");
+    assert_eq!(expand("No placeholders"), "No placeholders");
+    // Addresses shorter than 4 characters are used whole.
+    assert_eq!(
+      expand_display_name_template("{address-short}", "Hush", 0, "ab"),
+      "ab"
+    );
+  }
+
+  #[test]
+  fn test_display_name_template_escaping() {
+    let expand = |template| expand_display_name_template(template, "Hush", 2, "AA:BB:CC:DD:EE:FF");
+    assert_eq!(expand("{{name}}"), "{name}");
+    assert_eq!(expand("{{{name}}}"), "{Hush}");
+    assert_eq!(expand("{{index}} is {index}"), "{index} is 2");
+    // Unknown placeholders and lone braces are kept as is.
+    assert_eq!(expand("{unknown} {"), "{unknown} {");
+    assert_eq!(expand("}"), "}");
+  }
+}
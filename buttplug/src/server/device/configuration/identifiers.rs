@@ -1,5 +1,11 @@
-use getset::{Getters, MutGetters};
+use crate::core::errors::ButtplugDeviceError;
+use getset::{Getters, MutGetters, Setters};
 use serde::{Deserialize, Serialize};
+use std::{
+  fmt::{self, Display},
+  hash::{Hash, Hasher},
+  str::FromStr,
+};
 
 /// Identifying information for devices that are currently connected or have connected in the past.
 ///
@@ -11,7 +17,11 @@ use serde::{Deserialize, Serialize};
 /// are used for the address field on bluetooth devices. These will differ between all platforms due
 /// to address formatting as well as available information (macOS/iOS and WebBluetooth obfuscate
 /// bluetooth addresses)
-#[derive(Debug, Eq, PartialEq, Hash, Clone, Getters, MutGetters, Serialize, Deserialize)]
+///
+/// Some protocols can also report a stable id (usually a serial number) for the device, which
+/// doesn't change when the address does. The stable id is not part of equality or hashing, as it is
+/// only known once the device has been initialized.
+#[derive(Debug, Clone, Getters, MutGetters, Setters, Serialize, Deserialize)]
 #[getset(get = "pub", get_mut = "pub(crate)")]
 pub struct UserDeviceIdentifier {
   /// Name of the protocol used
@@ -20,6 +30,10 @@ pub struct UserDeviceIdentifier {
   identifier: Option<String>,
   /// Address, as possibly serialized by whatever the managing library for the Device Communication Manager is.
   address: String,
+  /// Protocol reported identifier that stays the same across addresses, if the protocol has one.
+  #[serde(rename = "stable-id", default, skip_serializing_if = "Option::is_none")]
+  #[getset(set = "pub")]
+  stable_id: Option<String>,
 }
 
 impl UserDeviceIdentifier {
@@ -29,7 +43,64 @@ impl UserDeviceIdentifier {
       address: address.to_owned(),
       protocol: protocol.to_owned(),
       identifier: identifier.clone(),
+      stable_id: None,
+    }
+  }
+}
+
+impl PartialEq for UserDeviceIdentifier {
+  fn eq(&self, other: &Self) -> bool {
+    self.protocol == other.protocol
+      && self.identifier == other.identifier
+      && self.address == other.address
+  }
+}
+
+impl Eq for UserDeviceIdentifier {
+}
+
+impl Hash for UserDeviceIdentifier {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.protocol.hash(state);
+    self.identifier.hash(state);
+    self.address.hash(state);
+  }
+}
+
+/// Formats as `protocol[/identifier]@address`, e.g. `lovense/P@C8:AB:12:34:56:78`. The stable id is
+/// not included.
+impl Display for UserDeviceIdentifier {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}@{}", BaseDeviceIdentifier::from(self), self.address)
+  }
+}
+
+/// Parses the format produced by [Display](UserDeviceIdentifier#impl-Display-for-UserDeviceIdentifier).
+/// The address is required.
+impl FromStr for UserDeviceIdentifier {
+  type Err = ButtplugDeviceError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (base, address) = s.rsplit_once('@').ok_or_else(|| {
+      ButtplugDeviceError::DeviceConfigurationError(format!(
+        "Device identifier {s} is missing an address segment."
+      ))
+    })?;
+    if address.is_empty() {
+      return Err(ButtplugDeviceError::DeviceConfigurationError(format!(
+        "Device identifier {s} has an empty address."
+      )));
     }
+    let base = base.parse::<BaseDeviceIdentifier>()?;
+    Ok(Self::new(address, &base.protocol, &base.identifier))
+  }
+}
+
+impl TryFrom<&str> for UserDeviceIdentifier {
+  type Error = ButtplugDeviceError;
+
+  fn try_from(value: &str) -> Result<Self, Self::Error> {
+    value.parse()
   }
 }
 
@@ -52,6 +123,49 @@ impl BaseDeviceIdentifier {
   }
 }
 
+/// Formats as `protocol` for protocol defaults, or `protocol/identifier`, e.g. `lovense/P`.
+impl Display for BaseDeviceIdentifier {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if let Some(identifier) = &self.identifier {
+      write!(f, "{}/{}", self.protocol, identifier)
+    } else {
+      write!(f, "{}", self.protocol)
+    }
+  }
+}
+
+/// Parses the format produced by [Display](BaseDeviceIdentifier#impl-Display-for-BaseDeviceIdentifier).
+/// Protocol names never contain `/`, so everything after the first `/` is the identifier.
+impl FromStr for BaseDeviceIdentifier {
+  type Err = ButtplugDeviceError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (protocol, identifier) = match s.split_once('/') {
+      Some((protocol, identifier)) => (protocol, Some(identifier)),
+      None => (s, None),
+    };
+    if protocol.is_empty() {
+      return Err(ButtplugDeviceError::DeviceConfigurationError(format!(
+        "Device identifier {s} is missing a protocol segment."
+      )));
+    }
+    if identifier.is_some_and(|identifier| identifier.is_empty()) {
+      return Err(ButtplugDeviceError::DeviceConfigurationError(format!(
+        "Device identifier {s} has an empty identifier segment."
+      )));
+    }
+    Ok(Self::new(protocol, &identifier.map(|x| x.to_owned())))
+  }
+}
+
+impl TryFrom<&str> for BaseDeviceIdentifier {
+  type Error = ButtplugDeviceError;
+
+  fn try_from(value: &str) -> Result<Self, Self::Error> {
+    value.parse()
+  }
+}
+
 impl From<&UserDeviceIdentifier> for BaseDeviceIdentifier {
   fn from(other: &UserDeviceIdentifier) -> Self {
     Self {
@@ -66,3 +180,60 @@ impl PartialEq<UserDeviceIdentifier> for BaseDeviceIdentifier {
     self.protocol == *other.protocol() && self.identifier == *other.identifier()
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_base_identifier_string_round_trip() {
+    for (string, identifier) in [
+      ("lovense", BaseDeviceIdentifier::new("lovense", &None)),
+      (
+        "lovense/P",
+        BaseDeviceIdentifier::new("lovense", &Some("P".to_owned())),
+      ),
+      (
+        "aneros/Massage Demo",
+        BaseDeviceIdentifier::new("aneros", &Some("Massage Demo".to_owned())),
+      ),
+    ] {
+      assert_eq!(string.parse::<BaseDeviceIdentifier>().unwrap(), identifier);
+      assert_eq!(identifier.to_string(), string);
+    }
+  }
+
+  #[test]
+  fn test_user_identifier_string_round_trip() {
+    for (string, identifier) in [
+      (
+        "lovense@C8:AB:12:34:56:78",
+        UserDeviceIdentifier::new("C8:AB:12:34:56:78", "lovense", &None),
+      ),
+      (
+        "lovense/P@C8:AB:12:34:56:78",
+        UserDeviceIdentifier::new("C8:AB:12:34:56:78", "lovense", &Some("P".to_owned())),
+      ),
+    ] {
+      assert_eq!(string.parse::<UserDeviceIdentifier>().unwrap(), identifier);
+      assert_eq!(UserDeviceIdentifier::try_from(string).unwrap(), identifier);
+      assert_eq!(identifier.to_string(), string);
+    }
+  }
+
+  #[test]
+  fn test_identifier_string_parse_failures() {
+    for string in ["", "/P", "lovense/"] {
+      assert!(
+        BaseDeviceIdentifier::try_from(string).is_err(),
+        "{string} should not parse"
+      );
+    }
+    for string in ["lovense/P", "lovense/P@", "/P@address", "lovense/@address"] {
+      assert!(
+        UserDeviceIdentifier::try_from(string).is_err(),
+        "{string} should not parse"
+      );
+    }
+  }
+}
@@ -57,7 +57,9 @@
 //! - Device Allow/Deny Lists: library will either only connect to certain devices, or never connect
 //!   to them, respectively.
 //! - Reserved indexes: allows the same device to show up to clients on the same device index every
-//!   time it connects
+//!   time it connects. Devices seen for the first time are given an index automatically, which is
+//!   kept for later sessions if the user configuration is saved. Indexes reserved by hand take
+//!   priority over automatically assigned ones.
 //! - Device configuration extensions: If a new device from a brand comes out and has not been added
 //!   to the main Device Configuration file, or else a user creates their own DIY device that uses
 //!   another protocol (hence it will never be in the main Device Configuration file as there may
@@ -102,8 +104,12 @@
 //!
 //! ### Adding Protocols
 //!
-//! Adding protocols to the DCM happens via the add_protocol_factory and remove_protocol_factory
-//! methods.
+//! Adding protocols to the DCM happens via the
+//! [DeviceConfigurationManagerBuilder::protocol_factory] and
+//! [DeviceConfigurationManagerBuilder::add_protocol_factory] methods, and removing them via
+//! [DeviceConfigurationManagerBuilder::remove_protocol]. See the
+//! [protocol module](crate::server::device::protocol) for what protocols from outside the library
+//! need to implement.
 //!
 //! ### Protocol Device Specifiers
 //!
@@ -145,36 +151,209 @@ mod identifiers;
 pub use identifiers::*;
 mod device_definitions;
 pub use device_definitions::*;
+mod views;
+pub use views::*;
 
 use crate::{
-  core::{errors::ButtplugDeviceError, message::Endpoint},
+  core::{
+    errors::{ButtplugDeviceError, ConfigurationError},
+    message::Endpoint,
+  },
   server::device::protocol::{
     get_default_protocol_map,
     ProtocolIdentifierFactory,
     ProtocolSpecializer,
   },
 };
-use dashmap::DashMap;
-use getset::Getters;
+use dashmap::{DashMap, DashSet};
+use getset::{CopyGetters, Getters};
+use serde::Serialize;
 use std::{
-  collections::HashMap,
-  fmt::{self, Debug},
+  collections::{HashMap, HashSet},
+  fmt::{self, Debug, Display},
   sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicU64, Ordering},
     Arc,
   },
+  time::Duration,
 };
 
+/// How long protocol initialization can take before the device is disconnected, for protocols that
+/// don't set their own timeout in the device config.
+pub const DEFAULT_PROTOCOL_INITIALIZE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Protocol the Lovense Connect Service manager settings are read from.
+const LOVENSE_CONNECT_SERVICE_PROTOCOL: &str = "lovense-connect-service";
+
+/// Serial specifiers in a user config that share a port with a base specifier of the same protocol
+/// are overrides, which get their explicitly set line settings merged into the base specifier
+/// instead of being used on their own. Returns the base specifiers with overrides applied, and the
+/// user specifiers that weren't overrides.
+fn merge_serial_overrides(
+  base_specifiers: &[ProtocolCommunicationSpecifier],
+  user_specifiers: &[ProtocolCommunicationSpecifier],
+) -> (
+  Vec<ProtocolCommunicationSpecifier>,
+  Vec<ProtocolCommunicationSpecifier>,
+) {
+  let mut merged_specifiers = base_specifiers.to_vec();
+  let mut remaining_specifiers = vec![];
+  for user_specifier in user_specifiers {
+    if let ProtocolCommunicationSpecifier::Serial(user_serial) = user_specifier {
+      let base_serial = merged_specifiers
+        .iter_mut()
+        .find_map(|specifier| match specifier {
+          ProtocolCommunicationSpecifier::Serial(base_serial) if *base_serial == *user_serial => {
+            Some(base_serial)
+          }
+          _ => None,
+        });
+      if let Some(base_serial) = base_serial {
+        base_serial.merge(user_serial);
+        continue;
+      }
+    }
+    remaining_specifiers.push(user_specifier.clone());
+  }
+  (merged_specifiers, remaining_specifiers)
+}
+
+/// Add a user specifier to a protocol, unless the protocol already has an equivalent one. Reloading
+/// or layering user configs would otherwise pile up copies of the same specifiers.
+fn push_user_specifier(
+  protocol: &str,
+  specifiers: &mut Vec<ProtocolCommunicationSpecifier>,
+  specifier: &ProtocolCommunicationSpecifier,
+) {
+  if specifiers
+    .iter()
+    .any(|existing| existing.is_equivalent(specifier))
+  {
+    debug!(
+      "Skipping duplicate user communication specifier for {}: {:?}",
+      protocol, specifier
+    );
+  } else {
+    specifiers.push(specifier.clone());
+  }
+}
+
+/// Devices can't be on both the allow and deny lists, as which one wins would depend on the order
+/// the lists are checked in.
+pub(crate) fn check_device_access(
+  identifier: &UserDeviceIdentifier,
+  definition: &UserDeviceDefinition,
+) -> Result<(), ButtplugDeviceError> {
+  let user_config = definition.user_config();
+  if user_config.allow() && user_config.deny() {
+    Err(
+      ConfigurationError::ContradictoryDeviceAccess {
+        device: identifier.to_string(),
+      }
+      .into(),
+    )
+  } else {
+    Ok(())
+  }
+}
+
+/// Where the device configuration of a server came from, for figuring out what a user is running
+/// when triaging bug reports. Filled in when configs are loaded by
+/// [load_protocol_configs](crate::util::device_configuration::load_protocol_configs) and friends.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Getters, CopyGetters)]
+pub struct ServerDeviceConfigInfo {
+  /// Version of the device configuration embedded in the library.
+  #[getset(get = "pub")]
+  bundled_version: String,
+  /// Version of the main configuration that was loaded, or None if there was no base config.
+  #[getset(get = "pub")]
+  loaded_main_version: Option<String>,
+  /// True if a user configuration was loaded on top of the main configuration.
+  #[getset(get_copy = "pub")]
+  user_config_present: bool,
+  /// Number of protocols defined in the main configuration.
+  #[getset(get_copy = "pub")]
+  protocol_count: usize,
+  /// Number of device definitions in the user configuration.
+  #[getset(get_copy = "pub")]
+  user_device_override_count: usize,
+}
+
+impl ServerDeviceConfigInfo {
+  pub fn new(
+    bundled_version: &str,
+    loaded_main_version: Option<String>,
+    user_config_present: bool,
+    protocol_count: usize,
+    user_device_override_count: usize,
+  ) -> Self {
+    Self {
+      bundled_version: bundled_version.to_owned(),
+      loaded_main_version,
+      user_config_present,
+      protocol_count,
+      user_device_override_count,
+    }
+  }
+}
+
+impl Display for ServerDeviceConfigInfo {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "bundled version {}, loaded main version {}, {} protocols, user config {}, {} user device overrides",
+      self.bundled_version,
+      self.loaded_main_version.as_deref().unwrap_or("none"),
+      self.protocol_count,
+      if self.user_config_present {
+        "present"
+      } else {
+        "not present"
+      },
+      self.user_device_override_count
+    )
+  }
+}
+
+/// A protocol a device advertisement came close to matching, see
+/// [DeviceConfigurationManager::protocol_near_misses].
+#[derive(Debug, Clone, PartialEq, Eq, Getters)]
+#[getset(get = "pub")]
+pub struct ProtocolNearMiss {
+  protocol: String,
+  failure: BluetoothLEMatchFailure,
+}
+
+impl ProtocolNearMiss {
+  pub fn new(protocol: &str, failure: BluetoothLEMatchFailure) -> Self {
+    Self {
+      protocol: protocol.to_owned(),
+      failure,
+    }
+  }
+}
+
+impl Display for ProtocolNearMiss {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}: {}", self.protocol, self.failure)
+  }
+}
+
 #[derive(Default, Clone)]
 pub struct DeviceConfigurationManagerBuilder {
   skip_default_protocols: bool,
   allow_raw_messages: bool,
   communication_specifiers: HashMap<String, Vec<ProtocolCommunicationSpecifier>>,
+  initialize_timeouts: HashMap<String, Duration>,
   user_communication_specifiers: DashMap<String, Vec<ProtocolCommunicationSpecifier>>,
+  user_protocol_display_names: DashMap<String, String>,
   base_device_definitions: HashMap<BaseDeviceIdentifier, BaseDeviceDefinition>,
   user_device_definitions: DashMap<UserDeviceIdentifier, UserDeviceDefinition>,
+  denied_addresses: HashSet<String>,
+  disabled_protocols: HashSet<String>,
   /// Map of protocol names to their respective protocol instance factories
   protocols: Vec<(String, Arc<dyn ProtocolIdentifierFactory>)>,
+  config_info: Option<ServerDeviceConfigInfo>,
 }
 
 impl DeviceConfigurationManagerBuilder {
@@ -191,6 +370,19 @@ impl DeviceConfigurationManagerBuilder {
     self
   }
 
+  /// Set how long initialization of devices using the protocol can take, overriding
+  /// [DEFAULT_PROTOCOL_INITIALIZE_TIMEOUT].
+  pub fn protocol_initialize_timeout(
+    &mut self,
+    protocol_name: &str,
+    timeout: Duration,
+  ) -> &mut Self {
+    self
+      .initialize_timeouts
+      .insert(protocol_name.to_owned(), timeout);
+    self
+  }
+
   pub fn protocol_features(
     &mut self,
     identifier: &BaseDeviceIdentifier,
@@ -202,16 +394,54 @@ impl DeviceConfigurationManagerBuilder {
     self
   }
 
+  /// Remove the communication specifiers and device definitions for a protocol, so it can be
+  /// redefined. User configurations are left alone.
+  pub fn remove_protocol_definitions(&mut self, protocol_name: &str) -> &mut Self {
+    self.communication_specifiers.remove(protocol_name);
+    self.initialize_timeouts.remove(protocol_name);
+    self
+      .base_device_definitions
+      .retain(|ident, _| ident.protocol() != protocol_name);
+    self
+  }
+
+  /// Remove everything configured for a protocol: base and user specifiers, device definitions,
+  /// display names and any factory added with [Self::protocol_factory]. Devices will no longer
+  /// match the protocol.
+  pub fn remove_protocol(&mut self, protocol_name: &str) -> &mut Self {
+    self.remove_protocol_definitions(protocol_name);
+    self.user_communication_specifiers.remove(protocol_name);
+    self.user_protocol_display_names.remove(protocol_name);
+    self
+      .user_device_definitions
+      .retain(|ident, _| ident.protocol() != protocol_name);
+    self.protocols.retain(|(name, _)| name != protocol_name);
+    self
+  }
+
   pub fn user_communication_specifier(
     &mut self,
     protocol_name: &str,
     specifier: &[ProtocolCommunicationSpecifier],
   ) -> &mut Self {
+    {
+      let mut specifiers = self
+        .user_communication_specifiers
+        .entry(protocol_name.to_owned())
+        .or_default();
+      for user_specifier in specifier {
+        push_user_specifier(protocol_name, &mut specifiers, user_specifier);
+      }
+    }
     self
-      .user_communication_specifiers
-      .entry(protocol_name.to_owned())
-      .or_default()
-      .extend(specifier.iter().cloned());
+  }
+
+  /// Set the display name template used for devices of a protocol that don't have a display name
+  /// of their own. See [expand_display_name_template] for the template format.
+  pub fn user_protocol_display_name(&mut self, protocol_name: &str, template: &str) -> &mut Self {
+    self
+      .user_protocol_display_names
+      .insert(protocol_name.to_owned(), template.to_owned());
     self
   }
 
@@ -226,14 +456,45 @@ impl DeviceConfigurationManagerBuilder {
     self
   }
 
+  /// Never connect to devices at this address, whatever protocol they match. Unlike a deny entry
+  /// in the user config, this isn't tied to a protocol and isn't saved with the user config.
+  pub fn deny_address(&mut self, address: &str) -> &mut Self {
+    self.denied_addresses.insert(address.to_owned());
+    self
+  }
+
+  /// Start the session with a protocol disabled, see [DeviceConfigurationManager::disable_protocol].
+  pub fn disable_protocol(&mut self, protocol_name: &str) -> &mut Self {
+    self.disabled_protocols.insert(protocol_name.to_owned());
+    self
+  }
+
   /// Add a protocol instance factory for a [ButtplugProtocol]
   pub fn protocol_factory<T>(&mut self, factory: T) -> &mut Self
   where
     T: ProtocolIdentifierFactory + 'static,
   {
+    let name = factory.identifier().to_owned();
+    self.add_protocol_factory(&name, Arc::new(factory))
+  }
+
+  /// Add a protocol implementation from outside the library. Base and user configs refer to it as
+  /// `name`, the same way as built in protocols, so `name` has to be what
+  /// [ProtocolIdentifierFactory::identifier] returns. [Self::finish] fails if it isn't, and with
+  /// [ConfigurationError::ProtocolAlreadyRegistered] if a built in protocol, or another added
+  /// factory, already uses the name.
+  pub fn add_protocol_factory(
+    &mut self,
+    name: &str,
+    factory: Arc<dyn ProtocolIdentifierFactory>,
+  ) -> &mut Self {
+    self.protocols.push((name.to_owned(), factory));
     self
-      .protocols
-      .push((factory.identifier().to_owned(), Arc::new(factory)));
+  }
+
+  /// Record where the configuration loaded into this builder came from.
+  pub fn config_info(&mut self, config_info: &ServerDeviceConfigInfo) -> &mut Self {
+    self.config_info = Some(config_info.clone());
     self
   }
 
@@ -248,6 +509,19 @@ impl DeviceConfigurationManagerBuilder {
   }
 
   pub fn finish(&mut self) -> Result<DeviceConfigurationManager, ButtplugDeviceError> {
+    // Compile BLE name patterns once here, instead of on every advertisement.
+    for (protocol, specifiers) in self.communication_specifiers.iter_mut() {
+      for specifier in specifiers {
+        specifier.compile_names_regex(protocol)?;
+      }
+    }
+    for mut kv in self.user_communication_specifiers.iter_mut() {
+      let protocol = kv.key().clone();
+      for specifier in kv.value_mut() {
+        specifier.compile_names_regex(&protocol)?;
+      }
+    }
+
     // Map of protocol names to their respective protocol instance factories
     let mut protocol_map = if !self.skip_default_protocols {
       get_default_protocol_map()
@@ -256,12 +530,63 @@ impl DeviceConfigurationManagerBuilder {
     };
 
     for (name, protocol) in &self.protocols {
+      // Identifiers report devices under the factory's name, so with any other name devices found
+      // for the protocol would never match their configs.
+      if protocol.identifier() != name {
+        return Err(ButtplugDeviceError::DeviceConfigurationError(format!(
+          "Protocol factory registered as {} identifies itself as {}.",
+          name,
+          protocol.identifier()
+        )));
+      }
       if protocol_map.contains_key(name) {
-        // TODO Fill in error
+        return Err(
+          ConfigurationError::ProtocolAlreadyRegistered {
+            protocol: name.clone(),
+          }
+          .into(),
+        );
       }
       protocol_map.insert(name.clone(), protocol.clone());
     }
 
+    // Cross-check configured protocols against the implementations we have, so configs naming
+    // protocols missing from this build are flagged at startup instead of on device connection.
+    // The base config carries protocols we no longer (or don't yet) implement, so those are only
+    // logged at debug level, and only when we're using the default protocol set, as otherwise we'd
+    // expect most of them to be missing.
+    let mut unimplemented_protocols: Vec<String> = Vec::new();
+    let mut base_unimplemented: Vec<&String> = self
+      .communication_specifiers
+      .keys()
+      .filter(|name| !self.skip_default_protocols && !protocol_map.contains_key(*name))
+      .collect();
+    base_unimplemented.sort();
+    for name in base_unimplemented {
+      debug!("Protocol {name} in base configuration has no implementation in this build.");
+      unimplemented_protocols.push(name.clone());
+    }
+    let mut user_unimplemented: Vec<String> = self
+      .user_communication_specifiers
+      .iter()
+      .map(|kv| kv.key().clone())
+      .chain(
+        self
+          .user_device_definitions
+          .iter()
+          .map(|kv| kv.key().protocol().clone()),
+      )
+      .filter(|name| !protocol_map.contains_key(name))
+      .collect();
+    user_unimplemented.sort();
+    user_unimplemented.dedup();
+    for name in user_unimplemented {
+      warn!("Protocol {name} in user configuration has no implementation in this build.");
+      unimplemented_protocols.push(name);
+    }
+    unimplemented_protocols.sort();
+    unimplemented_protocols.dedup();
+
     // Build and validate the protocol attributes tree.
     let mut attribute_tree_map = HashMap::new();
 
@@ -278,7 +603,7 @@ impl DeviceConfigurationManagerBuilder {
       }
       for feature in attr.features() {
         if let Err(e) = feature.is_valid() {
-          error!("Feature {attr:?} for ident {ident:?} is not valid, skipping addition: {e:?}");
+          error!("Feature {attr:?} for ident {ident} is not valid, skipping addition: {e:?}");
           continue;
         }
       }
@@ -293,14 +618,13 @@ impl DeviceConfigurationManagerBuilder {
       // anything with it anyways.
       if !protocol_map.contains_key(ident.protocol()) {
         warn!(
-          "Protocol {:?} in user configurations does not exist in system, discarding definition.",
-          ident.protocol()
+          "Protocol for user configuration {ident} does not exist in system, discarding definition."
         );
         continue;
       }
       for feature in attr.features() {
         if let Err(e) = feature.is_valid() {
-          error!("Feature {attr:?} for ident {ident:?} is not valid, skipping addition: {e:?}");
+          error!("Feature {attr:?} for ident {ident} is not valid, skipping addition: {e:?}");
           continue;
         }
       }
@@ -310,10 +634,17 @@ impl DeviceConfigurationManagerBuilder {
     Ok(DeviceConfigurationManager {
       allow_raw_messages: Arc::new(AtomicBool::new(self.allow_raw_messages)),
       base_communication_specifiers: self.communication_specifiers.clone(),
+      base_initialize_timeouts: self.initialize_timeouts.clone(),
       user_communication_specifiers: self.user_communication_specifiers.clone(),
+      user_protocol_display_names: self.user_protocol_display_names.clone(),
       base_device_definitions: attribute_tree_map,
       user_device_definitions: user_attribute_tree_map,
+      denied_addresses: self.denied_addresses.clone(),
+      disabled_protocols: self.disabled_protocols.iter().cloned().collect(),
       protocol_map,
+      unimplemented_protocols,
+      config_info: self.config_info.clone(),
+      config_generation: AtomicU64::new(0),
     })
   }
 }
@@ -340,14 +671,33 @@ pub struct DeviceConfigurationManager {
   base_communication_specifiers: HashMap<String, Vec<ProtocolCommunicationSpecifier>>,
   /// Device definitions from the base device config. Should not change/update during a session.
   base_device_definitions: HashMap<BaseDeviceIdentifier, BaseDeviceDefinition>,
+  /// Protocol initialization timeouts from the base device config, for protocols that set one.
+  base_initialize_timeouts: HashMap<String, Duration>,
   /// Communication specifiers provided by the user, mapped from protocol name to vector of
   /// specifiers. Loaded at session start, may change over life of session.
   #[getset(get = "pub")]
   user_communication_specifiers: DashMap<String, Vec<ProtocolCommunicationSpecifier>>,
+  /// Display name templates provided by the user, mapped from protocol name to template. Used for
+  /// devices without a display name of their own.
+  #[getset(get = "pub")]
+  user_protocol_display_names: DashMap<String, String>,
   /// Device definitions from the base device config. Loaded at session start, may change over life
   /// of session.
   #[getset(get = "pub")]
   user_device_definitions: DashMap<UserDeviceIdentifier, UserDeviceDefinition>,
+  /// Addresses denied on the builder, see [DeviceConfigurationManagerBuilder::deny_address].
+  denied_addresses: HashSet<String>,
+  /// Protocols excluded from device matching. May change over life of session.
+  disabled_protocols: DashSet<String>,
+  /// Protocols named in the base or user configuration that have no implementation in this build,
+  /// sorted by name. Their configuration entries are discarded.
+  #[getset(get = "pub")]
+  unimplemented_protocols: Vec<String>,
+  /// Where the configuration came from, if it was loaded from config files/strings.
+  #[getset(get = "pub")]
+  config_info: Option<ServerDeviceConfigInfo>,
+  /// Bumped whenever the user config changes, see [Self::config_generation].
+  config_generation: AtomicU64,
 }
 
 impl Debug for DeviceConfigurationManager {
@@ -372,17 +722,34 @@ impl DeviceConfigurationManager {
     self.allow_raw_messages.store(allow, Ordering::Relaxed)
   }
 
+  /// Changes every time user specifiers, user device definitions or disabled protocols are changed
+  /// through this manager, so anything worked out from the config (like which protocol a device
+  /// matched) can tell when it has to be worked out again.
+  pub fn config_generation(&self) -> u64 {
+    self.config_generation.load(Ordering::SeqCst)
+  }
+
+  fn config_changed(&self) {
+    self.config_generation.fetch_add(1, Ordering::SeqCst);
+  }
+
   pub fn add_user_communication_specifier(
     &self,
     protocol: &str,
     specifier: &ProtocolCommunicationSpecifier,
   ) -> Result<(), ButtplugDeviceError> {
     if !self.protocol_map.contains_key(protocol) {}
-    self
-      .user_communication_specifiers
-      .entry(protocol.to_owned())
-      .or_default()
-      .push(specifier.clone());
+    let mut specifier = specifier.clone();
+    specifier.compile_names_regex(protocol)?;
+    push_user_specifier(
+      protocol,
+      &mut self
+        .user_communication_specifiers
+        .entry(protocol.to_owned())
+        .or_default(),
+      &specifier,
+    );
+    self.config_changed();
     Ok(())
   }
 
@@ -399,6 +766,7 @@ impl DeviceConfigurationManager {
         .cloned()
         .collect();
     }
+    self.config_changed();
   }
 
   pub fn add_user_device_definition(
@@ -407,39 +775,101 @@ impl DeviceConfigurationManager {
     definition: &UserDeviceDefinition,
   ) -> Result<(), ButtplugDeviceError> {
     if !self.protocol_map.contains_key(identifier.protocol()) {}
+    check_device_access(identifier, definition)?;
+    // Remove any existing entry first, so the stored key picks up the new identifier's stable id.
+    self.user_device_definitions.remove(identifier);
     self
       .user_device_definitions
-      .entry(identifier.clone())
-      .insert(definition.clone());
+      .insert(identifier.clone(), definition.clone());
+    self.config_changed();
     Ok(())
   }
 
   pub fn remove_user_device_definition(&self, identifier: &UserDeviceIdentifier) {
     self.user_device_definitions.remove(identifier);
+    self.config_changed();
   }
 
-  pub fn address_allowed(&self, address: &str) -> bool {
-    // Make sure the device isn't on the deny list
-    if self
+  /// Stop matching devices to a protocol. Only affects devices found from now on, devices already
+  /// using the protocol stay connected.
+  pub fn disable_protocol(&self, protocol: &str) -> Result<(), ButtplugDeviceError> {
+    if !self.protocol_map.contains_key(protocol) {
+      return Err(ButtplugDeviceError::ProtocolNotImplemented(
+        protocol.to_owned(),
+      ));
+    }
+    info!("Disabling protocol {protocol}.");
+    self.disabled_protocols.insert(protocol.to_owned());
+    self.config_changed();
+    Ok(())
+  }
+
+  /// Match devices to a protocol disabled with [Self::disable_protocol] (or in the user config)
+  /// again.
+  pub fn enable_protocol(&self, protocol: &str) {
+    if self.disabled_protocols.remove(protocol).is_some() {
+      info!("Enabling protocol {protocol}.");
+      self.config_changed();
+    }
+  }
+
+  pub fn protocol_enabled(&self, protocol: &str) -> bool {
+    self.protocol_map.contains_key(protocol) && !self.disabled_protocols.contains(protocol)
+  }
+
+  /// Names of the protocols devices can currently be matched to, sorted by name.
+  pub fn enabled_protocols(&self) -> Vec<String> {
+    let mut protocols: Vec<String> = self
+      .protocol_map
+      .keys()
+      .filter(|name| !self.disabled_protocols.contains(*name))
+      .cloned()
+      .collect();
+    protocols.sort();
+    protocols
+  }
+
+  /// Names of the disabled protocols, sorted by name. May include protocols without an
+  /// implementation in this build, if the user config disabled them.
+  pub fn disabled_protocols(&self) -> Vec<String> {
+    let mut protocols: Vec<String> = self
+      .disabled_protocols
+      .iter()
+      .map(|name| name.clone())
+      .collect();
+    protocols.sort();
+    protocols
+  }
+
+  /// True if the address is on the deny list.
+  pub fn address_denied(&self, address: &str) -> bool {
+    self.denied_addresses.contains(address)
+      || self.user_device_definitions.iter().any(|kv| {
+        kv.key().address() == address && kv.value().user_config().access() == DeviceAccess::Deny
+      })
+  }
+
+  /// True if the allow list isn't empty and the address isn't on it.
+  pub fn address_excluded_by_allow_list(&self, address: &str) -> bool {
+    self
       .user_device_definitions
       .iter()
-      .any(|kv| kv.key().address() == address && kv.value().user_config().deny())
-    {
+      .any(|kv| kv.value().user_config().access() == DeviceAccess::Allow)
+      && !self.user_device_definitions.iter().any(|kv| {
+        kv.key().address() == address && kv.value().user_config().access() == DeviceAccess::Allow
+      })
+  }
+
+  pub fn address_allowed(&self, address: &str) -> bool {
+    // Make sure the device isn't on the deny list
+    if self.address_denied(address) {
       // If device is outright denied, deny
       info!(
         "Device {} denied by configuration, not connecting.",
         address
       );
       false
-    } else if self
-      .user_device_definitions
-      .iter()
-      .any(|kv| kv.value().user_config().allow())
-      && !self
-        .user_device_definitions
-        .iter()
-        .any(|kv| kv.key().address() == address && kv.value().user_config().allow())
-    {
+    } else if self.address_excluded_by_allow_list(address) {
       // If device is not on allow list and allow list isn't empty, deny
       info!(
         "Device {} not on allow list and allow list not empty, not connecting.",
@@ -451,15 +881,44 @@ impl DeviceConfigurationManager {
     }
   }
 
-  fn device_index(&self, identifier: &UserDeviceIdentifier) -> u32 {
-    // See if we have a reserved or reusable device index here.
-    if let Some(config) = self.user_device_definitions.get(identifier) {
-      let index = config.user_config().index();
-      debug!("Found index {index} for device {identifier:?}");
-      return index;
-    }
+  /// Devices the user config marks as auto-connect, leaving out any that are on the deny list.
+  pub fn auto_connect_devices(&self) -> Vec<UserDeviceIdentifier> {
+    self
+      .user_device_definitions
+      .iter()
+      .filter(|kv| {
+        kv.value().user_config().auto_connect()
+          && kv.value().user_config().access() != DeviceAccess::Deny
+      })
+      .map(|kv| kv.key().clone())
+      .collect()
+  }
 
-    let current_indexes: Vec<u32> = self
+  /// Index reserved for a device in the user config. Indexes the library assigned automatically
+  /// aren't reservations, as a reservation for the same index would take it over.
+  pub fn reserved_index_for(&self, identifier: &UserDeviceIdentifier) -> Option<u32> {
+    self
+      .user_device_definitions
+      .get(identifier)
+      .filter(|definition| !definition.user_config().auto_index())
+      .map(|definition| definition.user_config().index())
+  }
+
+  /// Device that has reserved an index in the user config, if any.
+  pub fn owner_of_index(&self, index: u32) -> Option<UserDeviceIdentifier> {
+    self
+      .user_device_definitions
+      .iter()
+      .find(|definition| {
+        !definition.user_config().auto_index() && definition.user_config().index() == index
+      })
+      .map(|definition| definition.key().clone())
+  }
+
+  /// Lowest index that isn't reserved, or already assigned to a device this session or in an
+  /// earlier one. This is the index the next new device will get.
+  pub fn next_unreserved_index(&self) -> u32 {
+    let current_indexes: HashSet<u32> = self
       .user_device_definitions
       .iter()
       .map(|x| x.user_config().index())
@@ -474,6 +933,18 @@ impl DeviceConfigurationManager {
     while current_indexes.contains(&index) {
       index = index + 1;
     }
+    index
+  }
+
+  fn device_index(&self, identifier: &UserDeviceIdentifier) -> u32 {
+    // See if we have a reserved or reusable device index here.
+    if let Some(config) = self.user_device_definitions.get(identifier) {
+      let index = config.user_config().index();
+      debug!("Found index {index} for device {identifier:?}");
+      return index;
+    }
+
+    let index = self.next_unreserved_index();
     debug!("Generating and assigning index {index:?} for device {identifier:?}");
     index
   }
@@ -500,6 +971,13 @@ impl DeviceConfigurationManager {
     let mut update_specializer_map =
       |name: &str, specifiers: &Vec<ProtocolCommunicationSpecifier>| {
         if specifiers.contains(specifier) {
+          if self.disabled_protocols.contains(name) {
+            info!(
+              "Protocol {:?} matches specifier {:?}, but is disabled.",
+              name, specifier
+            );
+            return;
+          }
           info!(
             "Found protocol {:?} for user specifier {:?}.",
             name, specifier
@@ -507,6 +985,7 @@ impl DeviceConfigurationManager {
 
           if self.protocol_map.contains_key(name) {
             specializers.push(ProtocolSpecializer::new(
+              name,
               specifiers.clone(),
               self
                 .protocol_map
@@ -525,14 +1004,88 @@ impl DeviceConfigurationManager {
 
     // Loop through both maps, as chaining between DashMap and HashMap gets kinda gross.
     for spec in self.user_communication_specifiers.iter() {
-      update_specializer_map(spec.key(), spec.value());
+      let base_specifiers = self
+        .base_communication_specifiers
+        .get(spec.key())
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+      let (_, user_specifiers) = merge_serial_overrides(base_specifiers, spec.value());
+      update_specializer_map(spec.key(), &user_specifiers);
     }
     for (name, specifiers) in self.base_communication_specifiers.iter() {
-      update_specializer_map(name, specifiers);
+      let user_specifiers = self.user_communication_specifiers.get(name);
+      let (specifiers, _) = merge_serial_overrides(
+        specifiers,
+        user_specifiers
+          .as_deref()
+          .map(Vec::as_slice)
+          .unwrap_or_default(),
+      );
+      update_specializer_map(name, &specifiers);
     }
     specializers
   }
 
+  /// Protocols whose BLE specifiers a device advertisement came close to matching, with why each
+  /// didn't match, sorted by protocol. At most one near miss is reported per protocol, and disabled
+  /// protocols are left out. Meant for diagnosing devices that match no protocol, see
+  /// [BluetoothLESpecifier::near_miss].
+  pub fn protocol_near_misses(&self, device: &BluetoothLESpecifier) -> Vec<ProtocolNearMiss> {
+    let mut protocols: Vec<String> = self
+      .base_communication_specifiers
+      .keys()
+      .cloned()
+      .chain(
+        self
+          .user_communication_specifiers
+          .iter()
+          .map(|spec| spec.key().clone()),
+      )
+      .filter(|protocol| !self.disabled_protocols.contains(protocol))
+      .collect();
+    protocols.sort();
+    protocols.dedup();
+    protocols
+      .iter()
+      .filter_map(|protocol| {
+        let user_specifiers = self.user_communication_specifiers.get(protocol);
+        user_specifiers
+          .iter()
+          .flat_map(|specifiers| specifiers.value().iter())
+          .chain(
+            self
+              .base_communication_specifiers
+              .get(protocol)
+              .into_iter()
+              .flatten(),
+          )
+          .find_map(|specifier| match specifier {
+            ProtocolCommunicationSpecifier::BluetoothLE(btle) => btle.near_miss(device),
+            _ => None,
+          })
+          .map(|failure| ProtocolNearMiss::new(protocol, failure))
+      })
+      .collect()
+  }
+
+  /// Specializer for a protocol a device matched before, using the specifiers it matched with. None
+  /// if the protocol is disabled or has no implementation.
+  pub(crate) fn protocol_specializer(
+    &self,
+    protocol: &str,
+    specifiers: &[ProtocolCommunicationSpecifier],
+  ) -> Option<ProtocolSpecializer> {
+    if self.disabled_protocols.contains(protocol) {
+      return None;
+    }
+    let factory = self.protocol_map.get(protocol)?;
+    Some(ProtocolSpecializer::new(
+      protocol,
+      specifiers.to_vec(),
+      factory.create(),
+    ))
+  }
+
   pub fn device_definition(
     &self,
     identifier: &UserDeviceIdentifier,
@@ -554,12 +1107,43 @@ impl DeviceConfigurationManager {
       .base_device_definitions
       .get(&BaseDeviceIdentifier::new(&identifier.protocol(), &None))
     {
-      debug!("Protocol device config found for {:?}", identifier);
+      if let Some(model) = identifier.identifier() {
+        // Usually a new hardware or firmware revision. Protocol defaults should be close enough to
+        // work, but we want to know about these so they can be added to the device config.
+        info!(
+          "No device config for identifier {} in protocol {}, using protocol defaults.",
+          model,
+          identifier.protocol()
+        );
+      } else {
+        debug!("Protocol device config found for {:?}", identifier);
+      }
       UserDeviceDefinition::new_from_base_definition(attrs, self.device_index(identifier))
     } else {
       return None;
     };
 
+    if let Some(configuration) = features.user_config().configuration().clone() {
+      if let Some(attrs) = self.base_device_definitions.get(&BaseDeviceIdentifier::new(
+        identifier.protocol(),
+        &Some(configuration.clone()),
+      )) {
+        debug!(
+          "User config selects configuration {} for {:?}",
+          configuration, identifier
+        );
+        features =
+          UserDeviceDefinition::new(attrs.name(), attrs.features(), features.user_config());
+      } else {
+        warn!(
+          "User config for {:?} selects configuration {}, which protocol {} doesn't have. Ignoring.",
+          identifier,
+          configuration,
+          identifier.protocol()
+        );
+      }
+    }
+
     // If this is a new device, it needs to be added to the user device definition map. Make sure we
     // do this before we add raw message features.
     //
@@ -578,6 +1162,185 @@ impl DeviceConfigurationManager {
 
     Some(features)
   }
+
+  /// How long initialization of a device using the protocol can take before giving up on it.
+  pub fn protocol_initialize_timeout(&self, protocol_name: &str) -> Duration {
+    self
+      .base_initialize_timeouts
+      .get(protocol_name)
+      .copied()
+      .unwrap_or(DEFAULT_PROTOCOL_INITIALIZE_TIMEOUT)
+  }
+
+  /// Websocket specifiers with a url, from both the base and user configs. Used to set up the
+  /// devices the websocket device manager connects out to.
+  pub fn outbound_websocket_specifiers(&self) -> Vec<WebsocketSpecifier> {
+    let user_specifiers: Vec<ProtocolCommunicationSpecifier> = self
+      .user_communication_specifiers
+      .iter()
+      .flat_map(|entry| entry.value().clone())
+      .collect();
+    self
+      .base_communication_specifiers
+      .values()
+      .flatten()
+      .chain(user_specifiers.iter())
+      .filter_map(|specifier| match specifier {
+        ProtocolCommunicationSpecifier::Websocket(websocket) if websocket.url().is_some() => {
+          Some(websocket.clone())
+        }
+        _ => None,
+      })
+      .collect()
+  }
+
+  /// The Lovense Connect Service settings (allowed hosts, poll interval) to scan with. User config
+  /// specifiers win over the base config, and the last user specifier wins if there are several.
+  /// Pass this to the Lovense Connect Service manager builder after loading or reloading the
+  /// config.
+  pub fn lovense_connect_service_specifier(&self) -> LovenseConnectServiceSpecifier {
+    let find_specifier = |specifiers: &[ProtocolCommunicationSpecifier]| {
+      specifiers
+        .iter()
+        .rev()
+        .find_map(|specifier| match specifier {
+          ProtocolCommunicationSpecifier::LovenseConnectService(lovense) => Some(lovense.clone()),
+          _ => None,
+        })
+    };
+    self
+      .user_communication_specifiers
+      .get(LOVENSE_CONNECT_SERVICE_PROTOCOL)
+      .and_then(|specifiers| find_specifier(&specifiers))
+      .or_else(|| {
+        self
+          .base_communication_specifiers
+          .get(LOVENSE_CONNECT_SERVICE_PROTOCOL)
+          .and_then(|specifiers| find_specifier(specifiers))
+      })
+      .unwrap_or_default()
+  }
+
+  /// Names of protocols with factories registered on the builder rather than built into the
+  /// library. These only exist for the life of the manager, so config files can't bring them back.
+  pub(crate) fn custom_protocol_names(&self) -> Vec<String> {
+    let default_protocols = get_default_protocol_map();
+    let mut names: Vec<String> = self
+      .protocol_map
+      .keys()
+      .filter(|name| !default_protocols.contains_key(*name))
+      .cloned()
+      .collect();
+    names.sort();
+    names
+  }
+
+  /// True if the base device config has a configuration for the identifier. Devices that identify
+  /// as something without a configuration fall back to their protocol defaults.
+  pub fn has_base_device_definition(&self, identifier: &BaseDeviceIdentifier) -> bool {
+    self.base_device_definitions.contains_key(identifier)
+  }
+
+  /// Identifier of the base device config configuration a device's definition comes from, i.e. the
+  /// one its user config selects, or else the one it identified as. None if the device uses its
+  /// protocol defaults.
+  pub fn configuration_identifier(&self, identifier: &UserDeviceIdentifier) -> Option<String> {
+    let selected = self
+      .user_device_definitions
+      .get(identifier)
+      .and_then(|definition| definition.user_config().configuration().clone());
+    selected
+      .into_iter()
+      .chain(identifier.identifier().clone())
+      .find(|configuration| {
+        self.has_base_device_definition(&BaseDeviceIdentifier::new(
+          identifier.protocol(),
+          &Some(configuration.clone()),
+        ))
+      })
+  }
+
+  /// Look up the user device definition for a device that has reported a stable id.
+  ///
+  /// If a definition with the same protocol and stable id exists under another address (i.e. the
+  /// device address changed since we last saw it), that definition is moved over to the new
+  /// identifier and returned. Otherwise, the definition for the current identifier is updated to
+  /// record the stable id, so the device can be found later if its address changes. Returns None if
+  /// the identifier has no stable id, or there is no definition for it.
+  pub fn device_definition_by_stable_id(
+    &self,
+    identifier: &UserDeviceIdentifier,
+    raw_endpoints: &[Endpoint],
+  ) -> Option<UserDeviceDefinition> {
+    let stable_id = identifier.stable_id().as_ref()?;
+    let previous_identifier = self
+      .user_device_definitions
+      .iter()
+      .find(|kv| {
+        kv.key() != identifier
+          && kv.key().protocol() == identifier.protocol()
+          && kv.key().stable_id().as_ref() == Some(stable_id)
+      })
+      .map(|kv| kv.key().clone());
+
+    let current_definition = self
+      .user_device_definitions
+      .remove(identifier)
+      .map(|(_, definition)| definition);
+    let mut features = if let Some(previous_identifier) = previous_identifier {
+      info!(
+        "Device with stable id {} moved from address {} to {}, using existing user config.",
+        stable_id,
+        previous_identifier.address(),
+        identifier.address()
+      );
+      self
+        .user_device_definitions
+        .remove(&previous_identifier)
+        .map(|(_, definition)| definition)
+        .or(current_definition)?
+    } else {
+      current_definition?
+    };
+
+    self
+      .user_device_definitions
+      .insert(identifier.clone(), features.clone());
+
+    if self.allow_raw_messages.load(Ordering::Relaxed) {
+      features.add_raw_messages(raw_endpoints);
+    }
+
+    Some(features)
+  }
+}
+
+/// Address level pre-filter built from the user configuration allow and deny lists.
+///
+/// Handed to hardware communication managers, so they can drop devices the user doesn't want
+/// before creating connectors or connecting to them. Lookups go through the device configuration
+/// manager, so allow/deny changes made during a session are picked up without rebuilding the filter.
+#[derive(Clone)]
+pub struct DeviceAddressFilter {
+  device_config_manager: Arc<DeviceConfigurationManager>,
+}
+
+impl DeviceAddressFilter {
+  pub fn new(device_config_manager: Arc<DeviceConfigurationManager>) -> Self {
+    Self {
+      device_config_manager,
+    }
+  }
+
+  /// True if a device at this address should be connected to. Unlike
+  /// [DeviceConfigurationManager::address_allowed], this doesn't log, as it will be called for
+  /// every advertisement.
+  pub fn allows(&self, address: &str) -> bool {
+    !self.device_config_manager.address_denied(address)
+      && !self
+        .device_config_manager
+        .address_excluded_by_allow_list(address)
+  }
 }
 
 #[cfg(test)]
@@ -644,6 +1407,7 @@ mod test {
       "LovenseDummyTestName",
       &HashMap::new(),
       &[],
+      &HashMap::new(),
     ));
     assert!(!config.protocol_specializers(&spec).is_empty());
   }
@@ -655,6 +1419,7 @@ mod test {
       "LVS-Whatever",
       &HashMap::new(),
       &[],
+      &HashMap::new(),
     ));
     assert!(!config.protocol_specializers(&spec).is_empty());
   }
@@ -666,6 +1431,7 @@ mod test {
       "LVS-Whatever",
       &HashMap::new(),
       &[],
+      &HashMap::new(),
     ));
     assert!(!dcm.protocol_specializers(&spec).is_empty());
     let config: ProtocolDeviceAttributes = dcm
@@ -698,6 +1464,7 @@ mod test {
       "LVS-Whatever",
       &HashMap::new(),
       &[],
+      &HashMap::new(),
     ));
     assert!(!dcm.protocol_specializers(&spec).is_empty());
     let config: ProtocolDeviceAttributes = dcm
@@ -723,6 +1490,7 @@ mod test {
       "LVS-Whatever",
       &HashMap::new(),
       &[],
+      &HashMap::new(),
     ));
     assert!(!dcm.protocol_specializers(&spec).is_empty());
     let config: ProtocolDeviceAttributes = dcm
@@ -740,4 +1508,41 @@ mod test {
     assert!(config.message_attributes().raw_subscribe_cmd().is_none());
     assert!(config.message_attributes().raw_unsubscribe_cmd().is_none());
   }
+
+  #[test]
+  fn test_address_filter() {
+    let dcm = Arc::new(create_unit_test_dcm(false));
+    let filter = DeviceAddressFilter::new(dcm.clone());
+    assert!(filter.allows("Whatever"));
+
+    // Deny lists are read through the device configuration manager, so changes apply to an
+    // existing filter.
+    dcm
+      .add_user_device_definition(
+        &UserDeviceIdentifier::new("Denied", "lovense", &Some("P".to_owned())),
+        &UserDeviceDefinition::new(
+          "Lovense Edge",
+          &[],
+          &UserDeviceCustomization::new(&None, false, true, 0),
+        ),
+      )
+      .unwrap();
+    assert!(!filter.allows("Denied"));
+    assert!(filter.allows("Whatever"));
+
+    // Once anything is on the allow list, only allowed addresses pass.
+    dcm
+      .add_user_device_definition(
+        &UserDeviceIdentifier::new("Allowed", "lovense", &Some("P".to_owned())),
+        &UserDeviceDefinition::new(
+          "Lovense Edge",
+          &[],
+          &UserDeviceCustomization::new(&None, true, false, 1),
+        ),
+      )
+      .unwrap();
+    assert!(filter.allows("Allowed"));
+    assert!(!filter.allows("Whatever"));
+    assert!(!filter.allows("Denied"));
+  }
 }
@@ -1,7 +1,11 @@
 //! A collection of legacy device definitions for the server portion of Buttplug. All structs in
 //! this module can be considered deprecated, and will be removed as we move toward Buttplug v4.
 
-use std::{mem, ops::RangeInclusive};
+use std::{
+  collections::{BTreeMap, HashMap},
+  mem,
+  ops::RangeInclusive,
+};
 
 use getset::{Getters, MutGetters, Setters};
 
@@ -15,13 +19,14 @@ use crate::core::message::{
   ClientGenericDeviceMessageAttributes,
   DeviceFeature,
   Endpoint,
+  IntensityCurve,
   NullDeviceMessageAttributes,
   RawDeviceMessageAttributes,
   SensorDeviceMessageAttributes,
   SensorType,
 };
 
-use super::UserDeviceDefinition;
+use super::{RotationInversion, SensorCalibration, UserDeviceDefinition};
 
 /// Device attribute storage and handling
 ///
@@ -53,18 +58,34 @@ use super::UserDeviceDefinition;
 pub struct ProtocolDeviceAttributes {
   /// Given name of the device this instance represents.
   name: String,
+  /// Identifier of the device config configuration the attributes come from, if they don't come
+  /// from the protocol defaults. See
+  /// [DeviceConfigurationManager::configuration_identifier](super::DeviceConfigurationManager::configuration_identifier).
+  #[getset(get = "pub", set = "pub")]
+  identifier: Option<String>,
   /// User configured name of the device this instance represents, assuming one exists.
   display_name: Option<String>,
   /// Message attributes for this device instance.
   message_attributes: ServerDeviceMessageAttributes,
+  /// True if the user config swaps the output channels of the device, see
+  /// [UserDeviceCustomization::swap_channels](super::UserDeviceCustomization::swap_channels).
+  #[getset(skip)]
+  swap_channels: bool,
+  /// Rotation features the user config inverts, see
+  /// [UserDeviceCustomization::invert_rotation](super::UserDeviceCustomization::invert_rotation).
+  #[getset(skip)]
+  invert_rotation: Option<RotationInversion>,
 }
 
 impl From<UserDeviceDefinition> for ProtocolDeviceAttributes {
   fn from(mut value: UserDeviceDefinition) -> Self {
     Self {
       name: { mem::take(value.name_mut()) },
+      identifier: None,
       display_name: value.user_config_mut().display_name().clone(),
       message_attributes: { mem::take(value.features_mut()).into() },
+      swap_channels: value.user_config().swap_channels(),
+      invert_rotation: value.user_config().invert_rotation().clone(),
     }
   }
 }
@@ -78,11 +99,34 @@ impl ProtocolDeviceAttributes {
   ) -> Self {
     Self {
       name: name.to_owned(),
+      identifier: None,
       display_name: display_name.clone(),
       message_attributes: message_attributes.clone(),
+      swap_channels: false,
+      invert_rotation: None,
     }
   }
 
+  pub fn swap_channels(&self) -> bool {
+    self.swap_channels
+  }
+
+  pub fn set_swap_channels(&mut self, swap_channels: bool) {
+    self.swap_channels = swap_channels;
+  }
+
+  /// True if the user config inverts the rotation feature at `index`.
+  pub fn inverts_rotation(&self, index: u32) -> bool {
+    self
+      .invert_rotation
+      .as_ref()
+      .is_some_and(|inversion| inversion.inverts(index))
+  }
+
+  pub fn set_invert_rotation(&mut self, invert_rotation: Option<RotationInversion>) {
+    self.invert_rotation = invert_rotation;
+  }
+
   /// Check if a type of device message is supported by this instance.
   pub fn allows_message(&self, message_type: &ButtplugDeviceMessageType) -> bool {
     self.message_attributes.message_allowed(message_type)
@@ -93,6 +137,11 @@ impl ProtocolDeviceAttributes {
   pub fn add_raw_messages(&mut self, endpoints: &[Endpoint]) {
     self.message_attributes.add_raw_messages(endpoints);
   }
+
+  /// Cap the step limits of scalar features to steps the device accepts, keyed by feature index.
+  pub fn cap_scalar_step_limits(&mut self, caps: &HashMap<u32, u32>) {
+    self.message_attributes.cap_scalar_step_limits(caps);
+  }
 }
 
 // Unlike other message components, MessageAttributes is always turned on for
@@ -134,6 +183,10 @@ pub struct ServerDeviceMessageAttributes {
   vorze_a10_cyclone_cmd: Option<NullDeviceMessageAttributes>,
 }
 
+/// Attribute lists keep the order that features are listed in the device config, so index N of a
+/// message is always the Nth feature in the config that supports that message. Protocols rely on
+/// this to map indexes to physical actuators (e.g. DG-Lab channel A vs B), so features are kept in
+/// Vecs all the way from the config file to here, and nothing in between may reorder them.
 impl From<Vec<DeviceFeature>> for ServerDeviceMessageAttributes {
   fn from(features: Vec<DeviceFeature>) -> Self {
     let actuator_filter = |message_type| {
@@ -292,12 +345,58 @@ impl ServerDeviceMessageAttributes {
     }
   }
 
+  /// Copy of the attributes with every message that can actuate the device removed, leaving sensor,
+  /// raw read/subscribe and stop messages.
+  pub fn without_actuators(&self) -> ServerDeviceMessageAttributes {
+    Self {
+      sensor_read_cmd: self.sensor_read_cmd.clone(),
+      sensor_subscribe_cmd: self.sensor_subscribe_cmd.clone(),
+      raw_read_cmd: self.raw_read_cmd.clone(),
+      raw_subscribe_cmd: self.raw_subscribe_cmd.clone(),
+      ..Default::default()
+    }
+  }
+
   pub fn add_raw_messages(&mut self, endpoints: &[Endpoint]) {
     let raw_attrs = RawDeviceMessageAttributes::new(endpoints);
     self.raw_read_cmd = Some(raw_attrs.clone());
     self.raw_write_cmd = Some(raw_attrs.clone());
     self.raw_subscribe_cmd = Some(raw_attrs);
   }
+
+  /// Lower the end of the step limit of scalar features (keyed by feature index) to the given caps.
+  /// Limits already below their cap are left alone, and caps never move the end below the start.
+  pub fn cap_scalar_step_limits(&mut self, caps: &HashMap<u32, u32>) {
+    if let Some(scalar_cmd) = &mut self.scalar_cmd {
+      for (index, attrs) in scalar_cmd.iter_mut().enumerate() {
+        if let Some(cap) = caps.get(&(index as u32)) {
+          let start = *attrs.step_limit().start();
+          let end = (*attrs.step_limit().end()).min(*cap).max(start);
+          attrs.set_step_limit(start..=end);
+        }
+      }
+    }
+  }
+
+  /// Replace the ranges of sensors that have a calibration for their type with the range their
+  /// calibrated readings fall in.
+  pub fn calibrate_sensor_ranges(&mut self, calibration: &BTreeMap<SensorType, SensorCalibration>) {
+    for sensors in [&mut self.sensor_read_cmd, &mut self.sensor_subscribe_cmd]
+      .into_iter()
+      .flatten()
+    {
+      for sensor in sensors.iter_mut() {
+        if let Some(calibration) = calibration.get(sensor.sensor_type()) {
+          let ranges = sensor
+            .sensor_range()
+            .iter()
+            .map(|range| calibration.apply_range(range))
+            .collect();
+          sensor.set_sensor_range(ranges);
+        }
+      }
+    }
+  }
 }
 
 impl From<ServerDeviceMessageAttributes> for ClientDeviceMessageAttributes {
@@ -398,15 +497,21 @@ pub struct ServerGenericDeviceMessageAttributes {
   step_range: RangeInclusive<u32>,
   #[getset(get = "pub", set = "pub")]
   step_limit: RangeInclusive<u32>,
+  #[getset(get = "pub", set = "pub")]
+  intensity_curve: Option<IntensityCurve>,
 }
 
 impl From<ServerGenericDeviceMessageAttributes> for ClientGenericDeviceMessageAttributes {
   fn from(attrs: ServerGenericDeviceMessageAttributes) -> Self {
-    ClientGenericDeviceMessageAttributes::new(
+    let mut client_attrs = ClientGenericDeviceMessageAttributes::new(
       &attrs.feature_descriptor,
       attrs.step_count(),
       attrs.actuator_type,
-    )
+    );
+    if *attrs.step_limit.start() > 0 {
+      client_attrs.set_step_range(Some(attrs.step_limit));
+    }
+    client_attrs
   }
 }
 
@@ -420,6 +525,7 @@ impl TryFrom<DeviceFeature> for ServerGenericDeviceMessageAttributes {
         actuator_type,
         step_range: actuator.step_range().clone(),
         step_limit: actuator.step_limit().clone(),
+        intensity_curve: actuator.intensity_curve().clone(),
       };
       Ok(attrs)
     } else {
@@ -440,7 +546,7 @@ impl ServerGenericDeviceMessageAttributes {
 mod test {
   use std::collections::HashSet;
 
-  use crate::core::message::DeviceFeatureActuator;
+  use crate::core::message::{DeviceFeatureActuator, DeviceFeatureSensor, FeatureType, SensorUnit};
 
   use super::*;
 
@@ -475,4 +581,106 @@ mod test {
       device_feature_2.try_into().unwrap();
     assert_eq!(vibrate_attributes_2.step_count(), 4);
   }
+
+  #[test]
+  pub fn test_step_range_carried_to_client_attributes() {
+    let feature = |step_limit| {
+      DeviceFeature::new(
+        "",
+        FeatureType::Vibrate,
+        &Some(DeviceFeatureActuator::new(
+          &RangeInclusive::new(0, 20),
+          &step_limit,
+          &HashSet::from([ButtplugActuatorFeatureMessageType::ScalarCmd]),
+        )),
+        &None,
+      )
+    };
+    let step_range = |step_limit| {
+      let attributes: ServerDeviceMessageAttributes = vec![feature(step_limit)].into();
+      ClientDeviceMessageAttributes::from(attributes)
+        .scalar_cmd()
+        .as_ref()
+        .unwrap()[0]
+        .step_range()
+        .clone()
+    };
+    assert_eq!(step_range(3..=20), Some(3..=20));
+    // Only features with a minimum need a step range.
+    assert_eq!(step_range(0..=20), None);
+  }
+
+  #[test]
+  pub fn test_feature_order_preserved() {
+    let feature = |description: &str, feature_type, message| {
+      DeviceFeature::new(
+        description,
+        feature_type,
+        &Some(DeviceFeatureActuator::new(
+          &RangeInclusive::new(0, 10),
+          &RangeInclusive::new(0, 10),
+          &HashSet::from([message]),
+        )),
+        &None,
+      )
+    };
+    let attributes: ServerDeviceMessageAttributes = vec![
+      feature(
+        "b",
+        FeatureType::Vibrate,
+        ButtplugActuatorFeatureMessageType::ScalarCmd,
+      ),
+      feature(
+        "rotate",
+        FeatureType::Rotate,
+        ButtplugActuatorFeatureMessageType::RotateCmd,
+      ),
+      feature(
+        "a",
+        FeatureType::Vibrate,
+        ButtplugActuatorFeatureMessageType::ScalarCmd,
+      ),
+      feature(
+        "c",
+        FeatureType::Oscillate,
+        ButtplugActuatorFeatureMessageType::ScalarCmd,
+      ),
+    ]
+    .into();
+    let scalar_order: Vec<&String> = attributes
+      .scalar_cmd()
+      .as_ref()
+      .unwrap()
+      .iter()
+      .map(|x| x.feature_descriptor())
+      .collect();
+    assert_eq!(scalar_order, ["b", "a", "c"]);
+    assert_eq!(attributes.rotate_cmd().as_ref().unwrap().len(), 1);
+  }
+
+  #[test]
+  pub fn test_sensor_unit_carried_to_client_attributes() {
+    let mut sensor = DeviceFeatureSensor::new(
+      &vec![0..=100],
+      &HashSet::from([ButtplugSensorFeatureMessageType::SensorReadCmd]),
+    );
+    sensor.set_unit(Some(SensorUnit::Percent));
+    let attributes: ServerDeviceMessageAttributes = vec![DeviceFeature::new(
+      "Battery Level",
+      FeatureType::Battery,
+      &None,
+      &Some(sensor),
+    )]
+    .into();
+    let server_sensor = &attributes.sensor_read_cmd().as_ref().unwrap()[0];
+    assert_eq!(*server_sensor.unit(), Some(SensorUnit::Percent));
+
+    let client_attributes: ClientDeviceMessageAttributes = attributes.into();
+    let client_sensor = &client_attributes.sensor_read_cmd().as_ref().unwrap()[0];
+    assert_eq!(*client_sensor.unit(), Some(SensorUnit::Percent));
+    // Spec v3 has no field for the unit, so it stays out of serialized attributes.
+    let json = serde_json::to_value(client_sensor).unwrap();
+    assert!(json.get("Unit").is_none());
+    assert_eq!(json["SensorRange"], serde_json::json!([[0, 100]]));
+  }
 }
@@ -5,10 +5,18 @@
 // Licensed under the BSD 3-Clause license. See LICENSE file in the project root
 // for full license information.
 
-use crate::core::message::Endpoint;
-use getset::{Getters, MutGetters, Setters};
+use crate::core::{
+  errors::{ButtplugDeviceError, ConfigurationError},
+  message::Endpoint,
+};
+use getset::{CopyGetters, Getters, MutGetters, Setters};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::{
+  collections::{HashMap, HashSet},
+  fmt::{self, Debug},
+  time::Duration,
+};
 use uuid::Uuid;
 
 // Note: There's a ton of extra structs in here just to deserialize the json
@@ -33,6 +41,14 @@ impl BluetoothLEManufacturerData {
   }
 }
 
+impl BluetoothLEManufacturerData {
+  /// Whether two entries are exactly the same, unlike [PartialEq], which also treats entries
+  /// without data and data subsequences as matches.
+  pub fn is_equivalent(&self, other: &Self) -> bool {
+    self.company == other.company && self.data == other.data
+  }
+}
+
 impl PartialEq for BluetoothLEManufacturerData {
   fn eq(&self, other: &Self) -> bool {
     if self.company != *other.company() {
@@ -83,6 +99,182 @@ impl PartialEq for BluetoothLEManufacturerData {
   }
 }
 
+/// (De)serializes bytes as a hex string, e.g. "aa0b03".
+mod hex_data {
+  use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+  pub fn serialize<S>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: Serializer,
+  {
+    serializer.serialize_str(
+      &data
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>(),
+    )
+  }
+
+  pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let hex = String::deserialize(deserializer)?;
+    if hex.len() % 2 != 0 {
+      return Err(D::Error::custom(format!(
+        "Hex data {} has an odd number of digits",
+        hex
+      )));
+    }
+    (0..hex.len())
+      .step_by(2)
+      .map(|index| {
+        hex
+          .get(index..index + 2)
+          .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+          .ok_or_else(|| D::Error::custom(format!("Invalid hex data {}", hex)))
+      })
+      .collect()
+  }
+}
+
+/// A write made to a device right after it connects, before its protocol is initialized.
+///
+/// Used for devices that need a fixed unlock or setup sequence, so their protocols don't need a
+/// custom initializer just for that.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Getters, CopyGetters)]
+pub struct InitSequenceWrite {
+  #[getset(get_copy = "pub")]
+  endpoint: Endpoint,
+  #[getset(get = "pub")]
+  #[serde(rename = "hex-data", with = "hex_data")]
+  data: Vec<u8>,
+  /// How long to wait after the write before the next one (or before initializing the protocol),
+  /// in milliseconds.
+  #[getset(get_copy = "pub")]
+  #[serde(rename = "delay-ms", default, skip_serializing_if = "Option::is_none")]
+  delay_ms: Option<u64>,
+  #[getset(get_copy = "pub")]
+  #[serde(
+    rename = "write-with-response",
+    default,
+    skip_serializing_if = "std::ops::Not::not"
+  )]
+  write_with_response: bool,
+}
+
+impl InitSequenceWrite {
+  pub fn new(
+    endpoint: Endpoint,
+    data: &[u8],
+    delay_ms: Option<u64>,
+    write_with_response: bool,
+  ) -> Self {
+    Self {
+      endpoint,
+      data: data.to_vec(),
+      delay_ms,
+      write_with_response,
+    }
+  }
+
+  pub fn delay(&self) -> Duration {
+    Duration::from_millis(self.delay_ms.unwrap_or(0))
+  }
+}
+
+/// Advertised service data a Bluetooth LE device is expected to have.
+///
+/// Matches a service data entry for the same service UUID. If data is set, the advertised data
+/// must start with it, otherwise any data for the service matches.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Getters, Setters)]
+#[getset(get = "pub", set = "pub")]
+pub struct BluetoothLEServiceData {
+  service: Uuid,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  data: Option<Vec<u8>>,
+}
+
+impl BluetoothLEServiceData {
+  pub fn new(service: Uuid, data: &Option<Vec<u8>>) -> Self {
+    Self {
+      service,
+      data: data.clone(),
+    }
+  }
+
+  /// Check an advertisement's service data map against this entry.
+  pub fn matches(&self, advertised_service_data: &HashMap<Uuid, Vec<u8>>) -> bool {
+    advertised_service_data
+      .get(&self.service)
+      .is_some_and(|advertised| {
+        self
+          .data
+          .as_ref()
+          .is_none_or(|prefix| advertised.starts_with(prefix))
+      })
+  }
+}
+
+/// Connection priority hint for a Bluetooth LE connection. High priority asks the platform for a
+/// short connection interval, for devices that need more throughput or lower latency than the
+/// default gives them, at the cost of power.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BluetoothLEConnectionPriority {
+  Balanced,
+  High,
+  LowPower,
+}
+
+/// Why a device advertisement came close to a BLE specifier from a protocol config without
+/// matching it. See [BluetoothLESpecifier::near_miss].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BluetoothLEMatchFailure {
+  /// The name matched, but the service data the specifier requires wasn't advertised. Contains the
+  /// services the specifier expects data for.
+  ServiceDataMissing(Vec<Uuid>),
+  /// The required service data was advertised, but the name isn't one of the specifier's names.
+  ServiceDataNameMismatch,
+  /// The name only matches a configured name when ignoring case. Contains the configured name.
+  NameCaseMismatch(String),
+  /// One of the advertised and configured names starts with the other, e.g. for names truncated in
+  /// the advertisement. Contains the configured name.
+  NamePrefix(String),
+  /// The manufacturer company matched, but the manufacturer data didn't. Contains the company.
+  ManufacturerDataMismatch(u16),
+}
+
+impl fmt::Display for BluetoothLEMatchFailure {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::ServiceDataMissing(services) => write!(
+        f,
+        "name matched, but required service data for {} was absent",
+        services
+          .iter()
+          .map(ToString::to_string)
+          .collect::<Vec<String>>()
+          .join(", ")
+      ),
+      Self::ServiceDataNameMismatch => {
+        write!(f, "service data matched, but the name didn't")
+      }
+      Self::NameCaseMismatch(name) => write!(f, "name only matches {} ignoring case", name),
+      Self::NamePrefix(name) => write!(f, "name only shares a prefix with {}", name),
+      Self::ManufacturerDataMismatch(company) => write!(
+        f,
+        "manufacturer company {:#06x} matched, but its data didn't",
+        company
+      ),
+    }
+  }
+}
+
+/// Shortest name prefix [BluetoothLEMatchFailure::NamePrefix] is reported for, so short generic
+/// names don't turn up as near misses for every device.
+const NEAR_MISS_MIN_PREFIX_LENGTH: usize = 3;
+
 /// Specifier for Bluetooth LE Devices
 ///
 /// Used by protocols for identifying bluetooth devices via their advertisements, as well as
@@ -90,26 +282,115 @@ impl PartialEq for BluetoothLEManufacturerData {
 #[derive(Serialize, Deserialize, Debug, Clone, Getters, MutGetters, Setters)]
 #[getset(get = "pub", set = "pub", get_mut = "pub(crate)")]
 pub struct BluetoothLESpecifier {
-  /// Set of expected advertised names for this device.
+  /// Set of expected advertised names for this device. Can only be empty if service data or name
+  /// patterns are set.
+  #[serde(default)]
   names: HashSet<String>,
+  /// Regular expressions advertised names can match instead, for devices with per unit suffixes
+  /// (e.g. `MD-\d{4}`). Patterns have to match the whole name, and are case sensitive.
+  #[serde(default, rename = "names-regex", skip_serializing_if = "Vec::is_empty")]
+  #[getset(skip)]
+  names_regex: Vec<String>,
+  /// [Self::names_regex], compiled by [Self::compile_names_regex].
+  #[serde(skip)]
+  #[getset(skip)]
+  compiled_names_regex: Vec<Regex>,
   /// Array of possible manufacturer data values.
   #[serde(default, rename = "manufacturer-data")]
   manufacturer_data: Vec<BluetoothLEManufacturerData>,
   /// Set of expected advertised services for this device.
   #[serde(default, rename = "advertised-services")]
   advertised_services: HashSet<Uuid>,
+  /// Advertised service data expected for this device. If set, a device needs to match one of these
+  /// (as well as one of the names, unless there are none) to be considered part of the protocol.
+  #[serde(
+    default,
+    rename = "service-data",
+    skip_serializing_if = "Vec::is_empty"
+  )]
+  service_data: Vec<BluetoothLEServiceData>,
+  /// Service data map of the advertisement this specifier was created from. Only set on specifiers
+  /// created from devices, never loaded from configs.
+  #[serde(skip)]
+  advertised_service_data: HashMap<Uuid, Vec<u8>>,
   /// Services we expect the device may have. More services may be listed in a specifier than any
   /// one device may have, but we expect at least one to be matched by a device in order to consider
   /// the device part of the protocol that has this specifier.
   services: HashMap<Uuid, HashMap<Endpoint, Uuid>>,
+  /// Endpoints in [Self::services] the device can connect without, for firmware revisions that
+  /// don't have every characteristic. All other endpoints are required.
+  #[serde(
+    default,
+    rename = "optional-endpoints",
+    skip_serializing_if = "HashSet::is_empty"
+  )]
+  optional_endpoints: HashSet<Endpoint>,
+  /// Writes made to matching devices once connected, before the protocol is initialized.
+  #[serde(
+    default,
+    rename = "init-sequence",
+    skip_serializing_if = "Vec::is_empty"
+  )]
+  init_sequence: Vec<InitSequenceWrite>,
+  /// ATT MTU to ask for once connected, for devices with packets larger than the default MTU fits.
+  /// Clamped to what BLE allows when applied.
+  #[serde(
+    default,
+    rename = "requested-mtu",
+    skip_serializing_if = "Option::is_none"
+  )]
+  requested_mtu: Option<u16>,
+  /// Connection priority to ask for once connected.
+  #[serde(
+    default,
+    rename = "connection-priority",
+    skip_serializing_if = "Option::is_none"
+  )]
+  connection_priority: Option<BluetoothLEConnectionPriority>,
 }
 
 impl PartialEq for BluetoothLESpecifier {
   fn eq(&self, other: &Self) -> bool {
+    // Service data is a requirement rather than an alternative, so if either side expects it, that
+    // decides the match on its own.
+    if !self.service_data.is_empty() || !other.service_data.is_empty() {
+      return self.service_data_matches(other) || other.service_data_matches(self);
+    }
+
     // If names or manufacturer data are found, use those automatically.
+    if self.names_match(other) {
+      return true;
+    }
+
+    if !self.manufacturer_data.is_empty() && !other.manufacturer_data.is_empty() {
+      for data in &self.manufacturer_data {
+        if other.manufacturer_data.contains(data) {
+          return true;
+        }
+      }
+    }
+
+    if self
+      .advertised_services
+      .intersection(&other.advertised_services)
+      .count()
+      > 0
+    {
+      return true;
+    }
+
+    false
+  }
+}
+
+impl BluetoothLESpecifier {
+  fn names_match(&self, other: &Self) -> bool {
     if self.names.intersection(&other.names).count() > 0 {
       return true;
     }
+    if self.regex_matches(&other.names) || other.regex_matches(&self.names) {
+      return true;
+    }
     // Otherwise, try wildcarded names.
     for name in &self.names {
       for other_name in &other.names {
@@ -131,29 +412,66 @@ impl PartialEq for BluetoothLESpecifier {
         }
       }
     }
+    false
+  }
 
-    if !self.manufacturer_data.is_empty() && !other.manufacturer_data.is_empty() {
-      for data in &self.manufacturer_data {
-        if other.manufacturer_data.contains(data) {
-          return true;
-        }
-      }
-    }
+  fn regex_matches(&self, names: &HashSet<String>) -> bool {
+    self
+      .compiled_names_regex
+      .iter()
+      .any(|regex| names.iter().any(|name| regex.is_match(name)))
+  }
 
-    if self
-      .advertised_services
-      .intersection(&other.advertised_services)
-      .count()
-      > 0
-    {
-      return true;
-    }
+  fn has_names(&self) -> bool {
+    !self.names.is_empty() || !self.names_regex.is_empty()
+  }
 
-    false
+  /// Name patterns advertised names can match, see [Self::compile_names_regex].
+  pub fn names_regex(&self) -> &[String] {
+    &self.names_regex
+  }
+
+  /// Set the name patterns advertised names can match. They're compiled right away, so matching
+  /// doesn't have to.
+  pub fn set_names_regex(
+    &mut self,
+    protocol: &str,
+    names_regex: &[String],
+  ) -> Result<(), ConfigurationError> {
+    self.names_regex = names_regex.to_vec();
+    self.compile_names_regex(protocol)
+  }
+
+  /// Compile the name patterns loaded from a config, for the protocol the specifier belongs to.
+  /// Patterns are anchored at both ends. Until this is called, loaded patterns match nothing.
+  pub fn compile_names_regex(&mut self, protocol: &str) -> Result<(), ConfigurationError> {
+    self.compiled_names_regex = self
+      .names_regex
+      .iter()
+      .map(|pattern| {
+        Regex::new(&format!("^(?:{})$", pattern)).map_err(|err| {
+          ConfigurationError::InvalidNameRegex {
+            protocol: protocol.to_owned(),
+            pattern: pattern.clone(),
+            message: err.to_string(),
+          }
+        })
+      })
+      .collect::<Result<_, _>>()?;
+    Ok(())
+  }
+
+  /// Whether the advertisement `device` was created from has the service data we expect, and one
+  /// of our names if we have any.
+  fn service_data_matches(&self, device: &Self) -> bool {
+    !self.service_data.is_empty()
+      && self
+        .service_data
+        .iter()
+        .any(|data| data.matches(&device.advertised_service_data))
+      && (!self.has_names() || self.names_match(device))
   }
-}
 
-impl BluetoothLESpecifier {
   pub fn new(
     names: HashSet<String>,
     manufacturer_data: Vec<BluetoothLEManufacturerData>,
@@ -162,9 +480,17 @@ impl BluetoothLESpecifier {
   ) -> Self {
     Self {
       names,
+      names_regex: vec![],
+      compiled_names_regex: vec![],
       manufacturer_data,
       advertised_services,
+      service_data: vec![],
+      advertised_service_data: HashMap::new(),
       services,
+      optional_endpoints: HashSet::new(),
+      init_sequence: vec![],
+      requested_mtu: None,
+      connection_priority: None,
     }
   }
 
@@ -173,6 +499,7 @@ impl BluetoothLESpecifier {
     name: &str,
     manufacturer_data: &HashMap<u16, Vec<u8>>,
     advertised_services: &[Uuid],
+    service_data: &HashMap<Uuid, Vec<u8>>,
   ) -> BluetoothLESpecifier {
     let mut name_set = HashSet::new();
     name_set.insert(name.to_string());
@@ -186,10 +513,148 @@ impl BluetoothLESpecifier {
     let service_set = HashSet::from_iter(advertised_services.iter().copied());
     BluetoothLESpecifier {
       names: name_set,
+      names_regex: vec![],
+      compiled_names_regex: vec![],
       manufacturer_data: data_vec,
       advertised_services: service_set,
+      service_data: vec![],
+      advertised_service_data: service_data.clone(),
       services: HashMap::new(),
+      optional_endpoints: HashSet::new(),
+      init_sequence: vec![],
+      requested_mtu: None,
+      connection_priority: None,
+    }
+  }
+
+  /// Advertised names in lower case, as BLE names in configs are matched regardless of case when
+  /// checking for duplicate specifiers.
+  pub fn normalized_names(&self) -> HashSet<String> {
+    self.names.iter().map(|name| name.to_lowercase()).collect()
+  }
+
+  /// Required endpoints in [Self::services] that aren't in `found`, the endpoints discovered on a
+  /// connected device.
+  pub fn missing_required_endpoints(&self, found: &[Endpoint]) -> Vec<Endpoint> {
+    let mut missing: Vec<Endpoint> = self
+      .services
+      .values()
+      .flat_map(|endpoints| endpoints.keys())
+      .filter(|endpoint| !self.optional_endpoints.contains(endpoint) && !found.contains(endpoint))
+      .cloned()
+      .collect();
+    missing.sort_by_key(|endpoint| endpoint.to_string());
+    missing.dedup();
+    missing
+  }
+
+  /// Fail with a [ButtplugDeviceError::DeviceConnectionError] naming the required endpoints that
+  /// weren't discovered on device `name` at `address`. Used by connectors once characteristic
+  /// discovery is done.
+  pub fn check_discovered_endpoints(
+    &self,
+    name: &str,
+    address: &str,
+    found: &[Endpoint],
+  ) -> Result<(), ButtplugDeviceError> {
+    let missing = self.missing_required_endpoints(found);
+    if missing.is_empty() {
+      return Ok(());
+    }
+    Err(ButtplugDeviceError::DeviceConnectionError(format!(
+      "Device {} ({}) is missing endpoints required by its device config: {}",
+      name,
+      address,
+      missing
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>()
+        .join(", ")
+    )))
+  }
+
+  /// Why `device`, a specifier created from an advertisement, doesn't match this config specifier,
+  /// if it came close. None if it matches, or isn't close to matching.
+  ///
+  /// Follows the checks of the [PartialEq] impl, so each failure names the check that didn't pass.
+  pub fn near_miss(&self, device: &Self) -> Option<BluetoothLEMatchFailure> {
+    if self == device {
+      return None;
+    }
+    if !self.service_data.is_empty() && self.has_names() {
+      if self.names_match(device) {
+        let mut services: Vec<Uuid> = self.service_data.iter().map(|data| data.service).collect();
+        services.sort();
+        services.dedup();
+        return Some(BluetoothLEMatchFailure::ServiceDataMissing(services));
+      }
+      if self
+        .service_data
+        .iter()
+        .any(|data| data.matches(&device.advertised_service_data))
+      {
+        return Some(BluetoothLEMatchFailure::ServiceDataNameMismatch);
+      }
+    }
+    let mut names: Vec<&String> = self.names.iter().collect();
+    names.sort();
+    for name in names {
+      for advertised in &device.names {
+        let lower_advertised = advertised.to_lowercase();
+        let case_match = if let Some(prefix) = name.strip_suffix('*') {
+          lower_advertised.starts_with(&prefix.to_lowercase())
+        } else {
+          lower_advertised == name.to_lowercase()
+        };
+        if case_match {
+          return Some(BluetoothLEMatchFailure::NameCaseMismatch(name.clone()));
+        }
+        let (shorter, longer) = if name.len() < advertised.len() {
+          (name, advertised)
+        } else {
+          (advertised, name)
+        };
+        if shorter.len() >= NEAR_MISS_MIN_PREFIX_LENGTH && longer.starts_with(shorter.as_str()) {
+          return Some(BluetoothLEMatchFailure::NamePrefix(name.clone()));
+        }
+      }
     }
+    self
+      .manufacturer_data
+      .iter()
+      .find(|data| {
+        device
+          .manufacturer_data
+          .iter()
+          .any(|advertised| advertised.company == data.company)
+      })
+      .map(|data| BluetoothLEMatchFailure::ManufacturerDataMismatch(data.company))
+  }
+
+  /// Whether two config specifiers describe the same devices. [PartialEq] checks whether a device
+  /// matches a config specifier instead, which is a much looser comparison. UUIDs are compared as
+  /// parsed, so differences in how they were formatted in the config don't matter.
+  pub fn is_equivalent(&self, other: &Self) -> bool {
+    self.normalized_names() == other.normalized_names()
+      && self.names_regex == other.names_regex
+      && self.manufacturer_data.len() == other.manufacturer_data.len()
+      && self.manufacturer_data.iter().all(|data| {
+        other
+          .manufacturer_data
+          .iter()
+          .any(|other_data| data.is_equivalent(other_data))
+      })
+      && self.advertised_services == other.advertised_services
+      && self.service_data.len() == other.service_data.len()
+      && self
+        .service_data
+        .iter()
+        .all(|data| other.service_data.contains(data))
+      && self.services == other.services
+      && self.optional_endpoints == other.optional_endpoints
+      && self.init_sequence == other.init_sequence
+      && self.requested_mtu == other.requested_mtu
+      && self.connection_priority == other.connection_priority
   }
 
   /// Merge with another BLE specifier, used when loading user configs that extend a protocol
@@ -197,32 +662,84 @@ impl BluetoothLESpecifier {
   pub fn merge(&mut self, other: BluetoothLESpecifier) {
     // Add any new names.
     self.names = self.names.union(&other.names).cloned().collect();
+    for pattern in other.names_regex {
+      if !self.names_regex.contains(&pattern) {
+        self.names_regex.push(pattern);
+      }
+    }
+    for regex in other.compiled_names_regex {
+      if !self
+        .compiled_names_regex
+        .iter()
+        .any(|compiled| compiled.as_str() == regex.as_str())
+      {
+        self.compiled_names_regex.push(regex);
+      }
+    }
     // Add new services, overwrite matching services.
     self.advertised_services = self
       .advertised_services
       .union(&other.advertised_services)
       .cloned()
       .collect();
+    for data in other.service_data {
+      if !self.service_data.contains(&data) {
+        self.service_data.push(data);
+      }
+    }
     self.services.extend(other.services);
+    self.optional_endpoints.extend(other.optional_endpoints);
+    // The init sequence is replaced as a whole, as merging writes wouldn't make sense.
+    if !other.init_sequence.is_empty() {
+      self.init_sequence = other.init_sequence;
+    }
+    if other.requested_mtu.is_some() {
+      self.requested_mtu = other.requested_mtu;
+    }
+    if other.connection_priority.is_some() {
+      self.connection_priority = other.connection_priority;
+    }
   }
 }
 
 /// Specifier for [Lovense Connect
 /// Service](crate::server::device::communication_manager::lovense_connect_service) devices
 ///
-/// Network based services, has no attributes because the [Lovense Connect
+/// Network based services, has no identifying attributes because the [Lovense Connect
 /// Service](crate::server::device::communication_manager::lovense_connect_service) device communication manager
-/// handles all device discovery and identification itself.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// handles all device discovery and identification itself. It can however limit which Lovense
+/// Connect hosts it talks to, and how often the manager polls for devices.
+#[derive(Serialize, Deserialize, Debug, Clone, Getters, CopyGetters)]
 pub struct LovenseConnectServiceSpecifier {
   // Needed for proper deserialization, but clippy will complain.
   #[allow(dead_code)]
   exists: bool,
+  /// Addresses (as returned by the Lovense API, e.g. "192.168.1.10") of the Lovense Connect hosts
+  /// to connect to. If unset, every host the API returns is used.
+  #[serde(
+    rename = "allowed-hosts",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  #[getset(get = "pub")]
+  allowed_hosts: Option<Vec<String>>,
+  /// How often to poll for devices, in milliseconds.
+  #[serde(
+    rename = "poll-interval-ms",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  #[getset(get_copy = "pub")]
+  poll_interval_ms: Option<u64>,
 }
 
 impl Default for LovenseConnectServiceSpecifier {
   fn default() -> Self {
-    Self { exists: true }
+    Self {
+      exists: true,
+      allowed_hosts: None,
+      poll_interval_ms: None,
+    }
   }
 }
 
@@ -232,21 +749,106 @@ impl PartialEq for LovenseConnectServiceSpecifier {
   }
 }
 
+impl LovenseConnectServiceSpecifier {
+  pub fn new(allowed_hosts: &Option<Vec<String>>, poll_interval_ms: Option<u64>) -> Self {
+    Self {
+      exists: true,
+      allowed_hosts: allowed_hosts.clone(),
+      poll_interval_ms,
+    }
+  }
+
+  /// Whether the Lovense Connect host at the given address should be connected to.
+  pub fn allows_host(&self, address: &str) -> bool {
+    self
+      .allowed_hosts
+      .as_ref()
+      .is_none_or(|hosts| hosts.iter().any(|host| host == address))
+  }
+
+  /// Allowed hosts sorted and without repeats, or None if all hosts are allowed.
+  pub fn normalized_allowed_hosts(&self) -> Option<Vec<String>> {
+    self.allowed_hosts.as_ref().map(|hosts| {
+      let mut hosts = hosts.clone();
+      hosts.sort_unstable();
+      hosts.dedup();
+      hosts
+    })
+  }
+
+  /// Whether two config specifiers allow the same hosts with the same poll interval.
+  pub fn is_equivalent(&self, other: &Self) -> bool {
+    self.normalized_allowed_hosts() == other.normalized_allowed_hosts()
+      && self.poll_interval_ms == other.poll_interval_ms
+  }
+}
+
 /// Specifier for [XInput](crate::server::device::communication_manager::xinput) devices
 ///
-/// Network based services, has no attributes because the
+/// Has no identifying attributes because the
 /// [XInput](crate::server::device::communication_manager::xinput) device communication manager handles all device
-/// discovery and identification itself.
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+/// discovery and identification itself. It can however limit which controller slots are exposed,
+/// and how often the manager rescans for controllers.
+#[derive(Serialize, Deserialize, Debug, Clone, Getters, CopyGetters)]
 pub struct XInputSpecifier {
   // Needed for deserialziation but unused.
   #[allow(dead_code)]
   exists: bool,
+  /// Controller slots (0-3) to expose as devices. If unset, all slots are exposed.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  #[getset(get = "pub")]
+  slots: Option<Vec<u8>>,
+  /// How often to rescan for newly connected controllers, in milliseconds.
+  #[serde(
+    rename = "rescan-interval-ms",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  #[getset(get_copy = "pub")]
+  rescan_interval_ms: Option<u64>,
 }
 
 impl Default for XInputSpecifier {
   fn default() -> Self {
-    Self { exists: true }
+    Self {
+      exists: true,
+      slots: None,
+      rescan_interval_ms: None,
+    }
+  }
+}
+
+impl XInputSpecifier {
+  pub fn new(slots: &Option<Vec<u8>>, rescan_interval_ms: Option<u64>) -> Self {
+    Self {
+      exists: true,
+      slots: slots.clone(),
+      rescan_interval_ms,
+    }
+  }
+
+  /// Whether the controller in the given slot should be exposed as a device.
+  pub fn exposes_slot(&self, slot: u8) -> bool {
+    self
+      .slots
+      .as_ref()
+      .is_none_or(|slots| slots.contains(&slot))
+  }
+
+  /// Exposed slots sorted and without repeats, or None if all slots are exposed.
+  pub fn normalized_slots(&self) -> Option<Vec<u8>> {
+    self.slots.as_ref().map(|slots| {
+      let mut slots = slots.clone();
+      slots.sort_unstable();
+      slots.dedup();
+      slots
+    })
+  }
+
+  /// Whether two config specifiers expose the same slots with the same rescan interval.
+  pub fn is_equivalent(&self, other: &Self) -> bool {
+    self.normalized_slots() == other.normalized_slots()
+      && self.rescan_interval_ms == other.rescan_interval_ms
   }
 }
 
@@ -273,6 +875,14 @@ pub struct VIDPIDPair {
 #[getset(get = "pub", set = "pub", get_mut = "pub(crate)")]
 pub struct VIDPIDSpecifier {
   pairs: Vec<VIDPIDPair>,
+  /// HID report IDs for the logical endpoints protocols write to. Writes to endpoints without a
+  /// report ID are sent as is.
+  #[serde(
+    rename = "report-ids",
+    default,
+    skip_serializing_if = "HashMap::is_empty"
+  )]
+  report_ids: HashMap<Endpoint, u8>,
 }
 
 impl VIDPIDSpecifier {
@@ -282,8 +892,17 @@ impl VIDPIDSpecifier {
         vendor_id,
         product_id,
       }],
+      report_ids: HashMap::new(),
     }
   }
+
+  /// Whether two config specifiers have the same VID/PID pairs (in any order) and report IDs.
+  /// [PartialEq] only checks for a shared pair.
+  pub fn is_equivalent(&self, other: &Self) -> bool {
+    self.pairs.iter().all(|pair| other.pairs.contains(pair))
+      && other.pairs.iter().all(|pair| self.pairs.contains(pair))
+      && self.report_ids == other.report_ids
+  }
 }
 
 impl PartialEq for VIDPIDSpecifier {
@@ -301,28 +920,51 @@ impl PartialEq for VIDPIDSpecifier {
 
 /// Specifier for Serial devices
 ///
-/// Handles serial port device identification (via port names) and configuration.
+/// Handles serial port device identification (via port names) and configuration. Line settings are
+/// optional, so user configs can override only some settings of a base config entry for the same
+/// port.
 #[derive(Serialize, Deserialize, Debug, Clone, Default, Getters, Setters, MutGetters)]
 #[getset(get = "pub", set = "pub", get_mut = "pub(crate)")]
 pub struct SerialSpecifier {
-  #[serde(rename = "baud-rate")]
-  baud_rate: u32,
-  #[serde(rename = "data-bits")]
-  data_bits: u8,
-  #[serde(rename = "stop-bits")]
-  stop_bits: u8,
-  parity: char,
+  #[serde(rename = "baud-rate", default, skip_serializing_if = "Option::is_none")]
+  baud_rate: Option<u32>,
+  #[serde(rename = "data-bits", default, skip_serializing_if = "Option::is_none")]
+  data_bits: Option<u8>,
+  #[serde(rename = "stop-bits", default, skip_serializing_if = "Option::is_none")]
+  stop_bits: Option<u8>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  parity: Option<char>,
   port: String,
+  /// Writes made to the device once the port is open, before the protocol is initialized.
+  #[serde(
+    default,
+    rename = "init-sequence",
+    skip_serializing_if = "Vec::is_empty"
+  )]
+  init_sequence: Vec<InitSequenceWrite>,
 }
 
 impl SerialSpecifier {
   pub fn new(port: &str, baud_rate: u32, data_bits: u8, stop_bits: u8, parity: char) -> Self {
     Self {
       port: port.to_owned(),
-      baud_rate,
-      data_bits,
-      stop_bits,
-      parity,
+      baud_rate: Some(baud_rate),
+      data_bits: Some(data_bits),
+      stop_bits: Some(stop_bits),
+      parity: Some(parity),
+      init_sequence: vec![],
+    }
+  }
+
+  /// Overwrite line settings (and the init sequence) with the ones explicitly set in `overrides`,
+  /// leaving the rest alone.
+  pub fn merge(&mut self, overrides: &SerialSpecifier) {
+    self.baud_rate = overrides.baud_rate.or(self.baud_rate);
+    self.data_bits = overrides.data_bits.or(self.data_bits);
+    self.stop_bits = overrides.stop_bits.or(self.stop_bits);
+    self.parity = overrides.parity.or(self.parity);
+    if !overrides.init_sequence.is_empty() {
+      self.init_sequence = overrides.init_sequence.clone();
     }
   }
 
@@ -334,6 +976,17 @@ impl SerialSpecifier {
       ..Default::default()
     }
   }
+
+  /// Whether two config specifiers have the same port, line settings and init sequence.
+  /// [PartialEq] only compares ports.
+  pub fn is_equivalent(&self, other: &Self) -> bool {
+    self.port == other.port
+      && self.baud_rate == other.baud_rate
+      && self.data_bits == other.data_bits
+      && self.stop_bits == other.stop_bits
+      && self.parity == other.parity
+      && self.init_sequence == other.init_sequence
+  }
 }
 
 impl PartialEq for SerialSpecifier {
@@ -348,19 +1001,57 @@ impl PartialEq for SerialSpecifier {
 /// Specifier for Websocket Device Manager devices
 ///
 /// The websocket device manager is a network based manager, so we have no info other than possibly
-/// a device name that is provided as part of the connection handshake.
-#[derive(Serialize, Deserialize, Debug, Clone, Default, Getters, Setters, MutGetters)]
+/// a device name that is provided as part of the connection handshake. Devices that run their own
+/// websocket server can instead be given a url, which the manager dials out to.
+#[derive(Serialize, Deserialize, Clone, Default, Getters, Setters, MutGetters)]
 #[getset(get = "pub", set = "pub")]
 pub struct WebsocketSpecifier {
   name: String,
+  /// Set in the device config if the protocol can be used by devices that share a websocket
+  /// connection with other devices.
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  multiplex: bool,
+  /// Websocket endpoint of the device (e.g. wss://esp32.local/ws). If set, the websocket device
+  /// manager connects to the device, rather than waiting for the device to connect to it.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  url: Option<String>,
+  /// Bearer token sent when connecting to `url`. Never logged.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  token: Option<String>,
+  /// Skip certificate verification for wss urls, for devices using self-signed certificates.
+  #[serde(
+    rename = "bypass-cert-verify",
+    default,
+    skip_serializing_if = "std::ops::Not::not"
+  )]
+  bypass_cert_verify: bool,
+  /// How long the connection can go without hearing from the device before we ping it, in
+  /// milliseconds. Defaults to 30 seconds.
+  #[serde(
+    rename = "ping-interval-ms",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  ping_interval_ms: Option<u64>,
+  /// How long to wait for the device to answer a ping before considering it disconnected, in
+  /// milliseconds. Defaults to 10 seconds.
+  #[serde(
+    rename = "ping-timeout-ms",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  ping_timeout_ms: Option<u64>,
+  /// Set by the websocket device manager when the device is one of several declared on a single
+  /// connection. Only matches specifiers with multiplex turned on.
+  #[serde(skip)]
+  shared_connection: bool,
 }
 
 impl PartialEq for WebsocketSpecifier {
   fn eq(&self, other: &Self) -> bool {
-    if self.name == other.name {
-      return true;
-    }
-    false
+    self.name == other.name
+      && (!self.shared_connection || other.multiplex)
+      && (!other.shared_connection || self.multiplex)
   }
 }
 
@@ -368,8 +1059,56 @@ impl WebsocketSpecifier {
   pub fn new(name: &str) -> WebsocketSpecifier {
     Self {
       name: name.to_owned(),
+      ..Default::default()
+    }
+  }
+
+  /// Create a specifier for a device sharing its websocket connection with other devices.
+  pub fn new_shared_connection(name: &str) -> WebsocketSpecifier {
+    Self {
+      name: name.to_owned(),
+      shared_connection: true,
+      ..Default::default()
     }
   }
+
+  /// Replace the token with a placeholder, for exports that can end up in bug reports. A redacted
+  /// specifier can't connect to its device anymore.
+  pub fn redact_token(&mut self) {
+    if self.token.is_some() {
+      self.token = Some(REDACTED_TOKEN.to_owned());
+    }
+  }
+
+  /// Whether two config specifiers have the same name, multiplex setting and connection settings.
+  pub fn is_equivalent(&self, other: &Self) -> bool {
+    self.name == other.name
+      && self.multiplex == other.multiplex
+      && self.url == other.url
+      && self.token == other.token
+      && self.bypass_cert_verify == other.bypass_cert_verify
+      && self.ping_interval_ms == other.ping_interval_ms
+      && self.ping_timeout_ms == other.ping_timeout_ms
+  }
+}
+
+/// Stands in for websocket tokens in logs and exports.
+pub const REDACTED_TOKEN: &str = "<redacted>";
+
+// Written out to keep the token out of logs.
+impl Debug for WebsocketSpecifier {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("WebsocketSpecifier")
+      .field("name", &self.name)
+      .field("multiplex", &self.multiplex)
+      .field("url", &self.url)
+      .field("token", &self.token.as_ref().map(|_| REDACTED_TOKEN))
+      .field("bypass_cert_verify", &self.bypass_cert_verify)
+      .field("ping_interval_ms", &self.ping_interval_ms)
+      .field("ping_timeout_ms", &self.ping_timeout_ms)
+      .field("shared_connection", &self.shared_connection)
+      .finish()
+  }
 }
 
 /// Enum that covers all types of communication specifiers.
@@ -377,6 +1116,7 @@ impl WebsocketSpecifier {
 /// Allows generalization of specifiers to handle checking for equality. Used for testing newly discovered
 /// devices against the list of known devices for a protocol.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum ProtocolCommunicationSpecifier {
   #[serde(rename = "btle")]
   BluetoothLE(BluetoothLESpecifier),
@@ -414,3 +1154,288 @@ impl PartialEq for ProtocolCommunicationSpecifier {
 
 impl Eq for ProtocolCommunicationSpecifier {
 }
+
+impl ProtocolCommunicationSpecifier {
+  /// Writes to make once a matching device connects. Only BLE and serial specifiers can have them.
+  pub fn init_sequence(&self) -> &[InitSequenceWrite] {
+    match self {
+      ProtocolCommunicationSpecifier::BluetoothLE(spec) => spec.init_sequence(),
+      ProtocolCommunicationSpecifier::Serial(spec) => spec.init_sequence(),
+      _ => &[],
+    }
+  }
+
+  /// Redact secrets of the specifier, see [WebsocketSpecifier::redact_token].
+  pub fn redact(&mut self) {
+    if let ProtocolCommunicationSpecifier::Websocket(spec) = self {
+      spec.redact_token();
+    }
+  }
+
+  /// Compile any BLE name patterns in the specifier, see
+  /// [BluetoothLESpecifier::compile_names_regex].
+  pub fn compile_names_regex(&mut self, protocol: &str) -> Result<(), ConfigurationError> {
+    match self {
+      ProtocolCommunicationSpecifier::BluetoothLE(spec) => spec.compile_names_regex(protocol),
+      _ => Ok(()),
+    }
+  }
+
+  /// Whether two config specifiers are duplicates of each other. Unlike [PartialEq], which is used
+  /// to match devices against config specifiers, this compares every setting of the specifiers.
+  pub fn is_equivalent(&self, other: &ProtocolCommunicationSpecifier) -> bool {
+    use ProtocolCommunicationSpecifier::*;
+    match (self, other) {
+      (USB(self_spec), USB(other_spec)) => self_spec.is_equivalent(other_spec),
+      (Serial(self_spec), Serial(other_spec)) => self_spec.is_equivalent(other_spec),
+      (BluetoothLE(self_spec), BluetoothLE(other_spec)) => self_spec.is_equivalent(other_spec),
+      (HID(self_spec), HID(other_spec)) => self_spec.is_equivalent(other_spec),
+      (XInput(self_spec), XInput(other_spec)) => self_spec.is_equivalent(other_spec),
+      (Websocket(self_spec), Websocket(other_spec)) => self_spec.is_equivalent(other_spec),
+      (LovenseConnectService(self_spec), LovenseConnectService(other_spec)) => {
+        self_spec.is_equivalent(other_spec)
+      }
+      _ => false,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  static SERVICE: Uuid = Uuid::from_u128(0x0000fff0_0000_1000_8000_00805f9b34fb);
+  static OTHER_SERVICE: Uuid = Uuid::from_u128(0x0000fff1_0000_1000_8000_00805f9b34fb);
+
+  fn config_specifier(
+    names: &[&str],
+    service_data: Vec<BluetoothLEServiceData>,
+  ) -> BluetoothLESpecifier {
+    let mut specifier = BluetoothLESpecifier::new(
+      names.iter().map(|name| name.to_string()).collect(),
+      vec![],
+      HashSet::new(),
+      HashMap::new(),
+    );
+    specifier.set_service_data(service_data);
+    specifier
+  }
+
+  fn device_specifier(name: &str, service: Uuid, data: &[u8]) -> BluetoothLESpecifier {
+    BluetoothLESpecifier::new_from_device(
+      name,
+      &HashMap::new(),
+      &[],
+      &HashMap::from([(service, data.to_vec())]),
+    )
+  }
+
+  #[test]
+  fn test_service_data_prefix_match() {
+    let config = config_specifier(
+      &[],
+      vec![BluetoothLEServiceData::new(
+        SERVICE,
+        &Some(vec![0x01, 0x02]),
+      )],
+    );
+    assert_eq!(
+      config,
+      device_specifier("BLE Device", SERVICE, &[0x01, 0x02, 0x03])
+    );
+    assert_eq!(device_specifier("", SERVICE, &[0x01, 0x02]), config);
+    assert_ne!(
+      config,
+      device_specifier("BLE Device", SERVICE, &[0x01, 0x03])
+    );
+    assert_ne!(config, device_specifier("BLE Device", SERVICE, &[0x01]));
+    // Without data, any data for the service matches.
+    let config = config_specifier(&[], vec![BluetoothLEServiceData::new(SERVICE, &None)]);
+    assert_eq!(config, device_specifier("BLE Device", SERVICE, &[0xff]));
+  }
+
+  #[test]
+  fn test_service_data_wrong_uuid() {
+    let config = config_specifier(
+      &[],
+      vec![BluetoothLEServiceData::new(SERVICE, &Some(vec![0x01]))],
+    );
+    assert_ne!(
+      config,
+      device_specifier("BLE Device", OTHER_SERVICE, &[0x01])
+    );
+    assert_ne!(
+      config,
+      BluetoothLESpecifier::new_from_device("BLE Device", &HashMap::new(), &[], &HashMap::new())
+    );
+  }
+
+  #[test]
+  fn test_service_data_with_names() {
+    let config = config_specifier(
+      &["BLE Device"],
+      vec![BluetoothLEServiceData::new(SERVICE, &Some(vec![0x01]))],
+    );
+    assert_eq!(config, device_specifier("BLE Device", SERVICE, &[0x01]));
+    // Both the name and the service data are required.
+    assert_ne!(config, device_specifier("Other Device", SERVICE, &[0x01]));
+    assert_ne!(config, device_specifier("BLE Device", SERVICE, &[0x02]));
+    assert_ne!(
+      config,
+      BluetoothLESpecifier::new_from_device("BLE Device", &HashMap::new(), &[], &HashMap::new())
+    );
+    // Specifiers without service data still match on names alone.
+    assert_eq!(
+      config_specifier(&["BLE Device"], vec![]),
+      device_specifier("BLE Device", SERVICE, &[0x02])
+    );
+  }
+
+  #[test]
+  fn test_near_miss() {
+    let config = config_specifier(
+      &["BLE Device"],
+      vec![BluetoothLEServiceData::new(SERVICE, &Some(vec![0x01]))],
+    );
+    assert_eq!(
+      config.near_miss(&device_specifier("BLE Device", OTHER_SERVICE, &[0x01])),
+      Some(BluetoothLEMatchFailure::ServiceDataMissing(vec![SERVICE]))
+    );
+    assert_eq!(
+      config.near_miss(&device_specifier("Other Device", SERVICE, &[0x01])),
+      Some(BluetoothLEMatchFailure::ServiceDataNameMismatch)
+    );
+    assert_eq!(
+      config.near_miss(&device_specifier("BLE Device", SERVICE, &[0x01])),
+      None
+    );
+
+    let config = config_specifier(&["BLE Device", "LVS-*"], vec![]);
+    assert_eq!(
+      config.near_miss(&device_specifier("lvs-edge", SERVICE, &[])),
+      Some(BluetoothLEMatchFailure::NameCaseMismatch(
+        "LVS-*".to_owned()
+      ))
+    );
+    // Truncated names share a prefix, short ones are too generic to report.
+    assert_eq!(
+      config.near_miss(&device_specifier("BLE Dev", SERVICE, &[])),
+      Some(BluetoothLEMatchFailure::NamePrefix("BLE Device".to_owned()))
+    );
+    assert_eq!(
+      config.near_miss(&device_specifier("BL", SERVICE, &[])),
+      None
+    );
+
+    let config = BluetoothLESpecifier::new(
+      HashSet::new(),
+      vec![BluetoothLEManufacturerData::new(0x0a0b, &Some(vec![0x01]))],
+      HashSet::new(),
+      HashMap::new(),
+    );
+    assert_eq!(
+      config.near_miss(&BluetoothLESpecifier::new_from_device(
+        "BLE Device",
+        &HashMap::from([(0x0a0b, vec![0x02])]),
+        &[],
+        &HashMap::new(),
+      )),
+      Some(BluetoothLEMatchFailure::ManufacturerDataMismatch(0x0a0b))
+    );
+  }
+
+  #[test]
+  fn test_names_regex_match() {
+    let mut config = config_specifier(&[], vec![]);
+    config
+      .set_names_regex("test", &["MD-\\d{4}".to_owned()])
+      .expect("Test, assuming infallible.");
+    assert_eq!(config, device_specifier("MD-1234", SERVICE, &[]));
+    // Patterns are anchored and case sensitive.
+    for name in ["MD-12345", "XMD-1234", "md-1234", "MD-12"] {
+      assert_ne!(config, device_specifier(name, SERVICE, &[]));
+    }
+    // Literal names still match alongside patterns.
+    let mut config = config_specifier(&["BLE Device"], vec![]);
+    config
+      .set_names_regex("test", &["MD-\\d{4}".to_owned()])
+      .expect("Test, assuming infallible.");
+    assert_eq!(config, device_specifier("BLE Device", SERVICE, &[]));
+    assert_eq!(config, device_specifier("MD-0001", SERVICE, &[]));
+  }
+
+  #[test]
+  fn test_names_regex_invalid_pattern() {
+    let mut config = config_specifier(&[], vec![]);
+    assert!(matches!(
+      config.set_names_regex("test", &["MD-(".to_owned()]),
+      Err(ConfigurationError::InvalidNameRegex { ref protocol, ref pattern, .. })
+        if protocol == "test" && pattern == "MD-("
+    ));
+  }
+
+  #[test]
+  fn test_service_data_nameless_deserialization() {
+    let specifier: BluetoothLESpecifier = serde_json::from_str(
+      r#"{
+        "names": [],
+        "service-data": [
+          {
+            "service": "0000fff0-0000-1000-8000-00805f9b34fb",
+            "data": [1, 2]
+          }
+        ],
+        "services": {}
+      }"#,
+    )
+    .expect("Test, assuming infallible.");
+    assert!(specifier.names().is_empty());
+    assert_eq!(
+      specifier.service_data(),
+      &vec![BluetoothLEServiceData::new(
+        SERVICE,
+        &Some(vec![0x01, 0x02])
+      )]
+    );
+    assert_eq!(
+      specifier,
+      device_specifier("", SERVICE, &[0x01, 0x02, 0x03])
+    );
+  }
+
+  #[test]
+  fn test_init_sequence_deserialization() {
+    let specifier: BluetoothLESpecifier = serde_json::from_str(
+      r#"{
+        "names": ["BLE Device"],
+        "services": {},
+        "init-sequence": [
+          { "endpoint": "tx", "hex-data": "aa0B03", "delay-ms": 50 },
+          { "endpoint": "command", "hex-data": "ff", "write-with-response": true }
+        ]
+      }"#,
+    )
+    .expect("Test, assuming infallible.");
+    assert_eq!(
+      specifier.init_sequence(),
+      &vec![
+        InitSequenceWrite::new(Endpoint::Tx, &[0xaa, 0x0b, 0x03], Some(50), false),
+        InitSequenceWrite::new(Endpoint::Command, &[0xff], None, true),
+      ]
+    );
+    assert_eq!(
+      serde_json::from_str::<InitSequenceWrite>(
+        &serde_json::to_string(&specifier.init_sequence()[0]).expect("Test, assuming infallible.")
+      )
+      .expect("Test, assuming infallible."),
+      specifier.init_sequence()[0]
+    );
+    for bad_data in ["abc", "zz"] {
+      assert!(serde_json::from_str::<InitSequenceWrite>(&format!(
+        r#"{{ "endpoint": "tx", "hex-data": "{}" }}"#,
+        bad_data
+      ))
+      .is_err());
+    }
+  }
+}
@@ -0,0 +1,156 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Borrowed views over the [DeviceConfigurationManager] contents, for listing configurations in
+//! UIs without cloning the underlying maps.
+
+use super::{
+  BaseDeviceDefinition,
+  DeviceAccess,
+  DeviceConfigurationManager,
+  ProtocolCommunicationSpecifier,
+  UserDeviceCustomization,
+  UserDeviceDefinition,
+  UserDeviceIdentifier,
+};
+use crate::core::message::DeviceFeature;
+use dashmap::mapref::multiple::RefMulti;
+use std::{collections::HashMap, time::Duration};
+
+/// A protocol from the base device config, with its specifiers and device definitions.
+#[derive(Debug, Clone)]
+pub struct ProtocolView<'a> {
+  name: &'a str,
+  specifiers: &'a [ProtocolCommunicationSpecifier],
+  defaults: Option<&'a BaseDeviceDefinition>,
+  configurations: Vec<(&'a str, &'a BaseDeviceDefinition)>,
+  initialize_timeout: Option<Duration>,
+}
+
+impl<'a> ProtocolView<'a> {
+  pub fn name(&self) -> &'a str {
+    self.name
+  }
+
+  /// Communication specifiers used to match devices to the protocol.
+  pub fn specifiers(&self) -> &'a [ProtocolCommunicationSpecifier] {
+    self.specifiers
+  }
+
+  /// Device definition used for devices without a configuration for their identifier.
+  pub fn defaults(&self) -> Option<&'a BaseDeviceDefinition> {
+    self.defaults
+  }
+
+  /// Initialization timeout set for the protocol in the base device config, if any.
+  pub fn initialize_timeout(&self) -> Option<Duration> {
+    self.initialize_timeout
+  }
+
+  /// Identifier specific device definitions, sorted by identifier.
+  pub fn configurations(&self) -> &[(&'a str, &'a BaseDeviceDefinition)] {
+    &self.configurations
+  }
+
+  /// Device definition for an identifier, without falling back to the protocol defaults.
+  pub fn configuration(&self, identifier: &str) -> Option<&'a BaseDeviceDefinition> {
+    self
+      .configurations
+      .iter()
+      .find(|(config_identifier, _)| *config_identifier == identifier)
+      .map(|(_, definition)| *definition)
+  }
+}
+
+/// The user configuration of a single device.
+///
+/// Holds a read lock on part of the user device definition map while alive, so views should be
+/// dropped before adding or removing user device definitions.
+pub struct DeviceOverrideView<'a> {
+  entry: RefMulti<'a, UserDeviceIdentifier, UserDeviceDefinition>,
+}
+
+impl DeviceOverrideView<'_> {
+  pub fn identifier(&self) -> &UserDeviceIdentifier {
+    self.entry.key()
+  }
+
+  pub fn definition(&self) -> &UserDeviceDefinition {
+    self.entry.value()
+  }
+
+  /// All user customizations for the device, including the ones without a shortcut here.
+  pub fn customization(&self) -> &UserDeviceCustomization {
+    self.entry.value().user_config()
+  }
+
+  pub fn display_name(&self) -> Option<&str> {
+    self.customization().display_name().as_deref()
+  }
+
+  pub fn access(&self) -> DeviceAccess {
+    self.customization().access()
+  }
+
+  pub fn allow(&self) -> bool {
+    self.access() == DeviceAccess::Allow
+  }
+
+  pub fn deny(&self) -> bool {
+    self.access() == DeviceAccess::Deny
+  }
+
+  pub fn index(&self) -> u32 {
+    self.customization().index()
+  }
+
+  /// Features of the device, with any user message overrides applied.
+  pub fn features(&self) -> &[DeviceFeature] {
+    self.entry.value().features()
+  }
+}
+
+impl DeviceConfigurationManager {
+  /// Iterate over the protocols in the base device config, sorted by name.
+  pub fn protocols(&self) -> impl Iterator<Item = ProtocolView<'_>> {
+    let mut definitions: HashMap<&str, (Option<&BaseDeviceDefinition>, Vec<_>)> = HashMap::new();
+    for (identifier, definition) in self.base_device_definitions.iter() {
+      let entry = definitions
+        .entry(identifier.protocol().as_str())
+        .or_default();
+      match identifier.identifier() {
+        Some(config_identifier) => entry.1.push((config_identifier.as_str(), definition)),
+        None => entry.0 = Some(definition),
+      }
+    }
+    let mut views: Vec<ProtocolView<'_>> = self
+      .base_communication_specifiers
+      .iter()
+      .map(|(name, specifiers)| {
+        let (defaults, mut configurations) = definitions.remove(name.as_str()).unwrap_or_default();
+        configurations.sort_by_key(|(identifier, _)| *identifier);
+        ProtocolView {
+          name,
+          specifiers,
+          defaults,
+          configurations,
+          initialize_timeout: self.base_initialize_timeouts.get(name).copied(),
+        }
+      })
+      .collect();
+    views.sort_by_key(|view| view.name);
+    views.into_iter()
+  }
+
+  /// Iterate over the user configurations of all devices.
+  pub fn device_overrides(&self) -> impl Iterator<Item = DeviceOverrideView<'_>> {
+    self
+      .user_device_definitions
+      .iter()
+      .map(|entry| DeviceOverrideView { entry })
+  }
+}
@@ -0,0 +1,163 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Remembers how devices were matched to protocols, so a device found again within a session
+//! doesn't go through protocol matching and endpoint discovery again.
+
+use super::configuration::ProtocolCommunicationSpecifier;
+use crate::core::message::Endpoint;
+use getset::Getters;
+use std::collections::{HashMap, VecDeque};
+
+/// Most addresses the cache keeps matches for. The oldest match is dropped past that.
+pub(super) const DEVICE_MATCH_CACHE_LENGTH: usize = 64;
+
+/// How a device was matched the last time it connected.
+#[derive(Debug, Clone, PartialEq, Eq, Getters)]
+#[getset(get = "pub(super)")]
+pub(super) struct DeviceMatch {
+  /// Specifier the hardware was found with. A device found with a different one (e.g. a changed
+  /// advertised name) is matched from scratch.
+  device_specifier: ProtocolCommunicationSpecifier,
+  protocol: String,
+  /// Config specifiers of the protocol the device matched.
+  specifiers: Vec<ProtocolCommunicationSpecifier>,
+  endpoints: Vec<Endpoint>,
+}
+
+impl DeviceMatch {
+  pub fn new(
+    device_specifier: &ProtocolCommunicationSpecifier,
+    protocol: &str,
+    specifiers: &[ProtocolCommunicationSpecifier],
+    endpoints: &[Endpoint],
+  ) -> Self {
+    Self {
+      device_specifier: device_specifier.clone(),
+      protocol: protocol.to_owned(),
+      specifiers: specifiers.to_vec(),
+      endpoints: endpoints.to_vec(),
+    }
+  }
+}
+
+/// Device matches by hardware address.
+///
+/// Matches are only good for the config they were made with, so the cache is emptied whenever the
+/// [config generation](super::configuration::DeviceConfigurationManager::config_generation) moves
+/// on. Matches don't replace allow and deny list checks, which still run for every device found.
+#[derive(Debug, Default)]
+pub(super) struct DeviceMatchCache {
+  config_generation: u64,
+  matches: HashMap<String, DeviceMatch>,
+  /// Addresses in the order their matches were stored, oldest first.
+  order: VecDeque<String>,
+}
+
+impl DeviceMatchCache {
+  fn sync_generation(&mut self, config_generation: u64) {
+    if self.config_generation != config_generation {
+      self.clear();
+      self.config_generation = config_generation;
+    }
+  }
+
+  /// The match stored for an address, if the config hasn't changed since, and the device was found
+  /// with the same specifier as last time.
+  pub fn get(
+    &mut self,
+    address: &str,
+    device_specifier: &ProtocolCommunicationSpecifier,
+    config_generation: u64,
+  ) -> Option<DeviceMatch> {
+    self.sync_generation(config_generation);
+    self
+      .matches
+      .get(address)
+      .filter(|device_match| device_match.device_specifier() == device_specifier)
+      .cloned()
+  }
+
+  /// Store the match for an address, made with the config at `config_generation`. Matches made
+  /// with an older config than the cache has seen are dropped.
+  pub fn insert(&mut self, address: &str, device_match: DeviceMatch, config_generation: u64) {
+    if config_generation < self.config_generation {
+      return;
+    }
+    self.sync_generation(config_generation);
+    if self
+      .matches
+      .insert(address.to_owned(), device_match)
+      .is_some()
+    {
+      self.order.retain(|stored| stored != address);
+    } else if self.order.len() == DEVICE_MATCH_CACHE_LENGTH {
+      if let Some(oldest) = self.order.pop_front() {
+        self.matches.remove(&oldest);
+      }
+    }
+    self.order.push_back(address.to_owned());
+  }
+
+  pub fn remove(&mut self, address: &str) {
+    if self.matches.remove(address).is_some() {
+      self.order.retain(|stored| stored != address);
+    }
+  }
+
+  pub fn clear(&mut self) {
+    self.matches.clear();
+    self.order.clear();
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::server::device::configuration::BluetoothLESpecifier;
+
+  fn device_match(name: &str) -> DeviceMatch {
+    let specifier = ProtocolCommunicationSpecifier::BluetoothLE(
+      BluetoothLESpecifier::new_from_device(name, &HashMap::new(), &[], &HashMap::new()),
+    );
+    DeviceMatch::new(
+      &specifier,
+      "aneros",
+      std::slice::from_ref(&specifier),
+      &[Endpoint::Tx],
+    )
+  }
+
+  #[test]
+  fn test_device_match_cache() {
+    let mut cache = DeviceMatchCache::default();
+    let found = device_match("Massage Demo");
+    cache.insert("First", found.clone(), 0);
+    assert_eq!(
+      cache.get("First", found.device_specifier(), 0),
+      Some(found.clone())
+    );
+    // Found with a different specifier, or with a newer config.
+    let renamed = device_match("Renamed");
+    assert_eq!(cache.get("First", renamed.device_specifier(), 0), None);
+    assert_eq!(cache.get("First", found.device_specifier(), 1), None);
+    // The cache has moved on to generation 1, so matches from generation 0 are stale.
+    cache.insert("First", found.clone(), 0);
+    assert_eq!(cache.get("First", found.device_specifier(), 1), None);
+
+    // Past the length limit, the oldest match goes. Storing a match again makes it the newest.
+    for index in 0..DEVICE_MATCH_CACHE_LENGTH {
+      cache.insert(&index.to_string(), found.clone(), 1);
+    }
+    cache.insert("0", found.clone(), 1);
+    cache.insert("Last", found.clone(), 1);
+    assert!(cache.get("0", found.device_specifier(), 1).is_some());
+    assert!(cache.get("1", found.device_specifier(), 1).is_none());
+    assert!(cache.get("Last", found.device_specifier(), 1).is_some());
+    assert_eq!(cache.matches.len(), DEVICE_MATCH_CACHE_LENGTH);
+  }
+}
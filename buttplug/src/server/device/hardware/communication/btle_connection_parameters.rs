@@ -0,0 +1,215 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Applies the connection parameters from a protocol's btle definition (requested MTU, connection
+//! priority) to a connected device. These are hints: platforms that can't set them, or refuse
+//! them, leave the connection on its defaults, and the connection itself never fails over them.
+
+use crate::server::device::configuration::{BluetoothLEConnectionPriority, BluetoothLESpecifier};
+use async_trait::async_trait;
+use thiserror::Error;
+
+/// Smallest MTU BLE allows, and the default every connection starts with.
+pub const BLUETOOTHLE_MIN_MTU: u16 = 23;
+/// Largest MTU that fits the 512 byte attribute value limit.
+pub const BLUETOOTHLE_MAX_MTU: u16 = 517;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum BluetoothLEConnectionParameterError {
+  #[error("Not supported on this platform")]
+  Unsupported,
+  #[error("{0}")]
+  Failed(String),
+}
+
+/// Platform calls for changing the parameters of a connected BLE device.
+#[async_trait]
+pub trait BluetoothLEConnectionControl: Send + Sync {
+  async fn request_mtu(&self, mtu: u16) -> Result<(), BluetoothLEConnectionParameterError>;
+  async fn request_connection_priority(
+    &self,
+    priority: BluetoothLEConnectionPriority,
+  ) -> Result<(), BluetoothLEConnectionParameterError>;
+}
+
+/// Clamps a requested MTU to the range BLE allows.
+pub fn clamp_mtu(mtu: u16) -> u16 {
+  mtu.clamp(BLUETOOTHLE_MIN_MTU, BLUETOOTHLE_MAX_MTU)
+}
+
+/// Requests whatever connection parameters the specifier sets. Failures are logged, not returned.
+pub async fn apply_connection_parameters(
+  name: &str,
+  specifier: &BluetoothLESpecifier,
+  control: &dyn BluetoothLEConnectionControl,
+) {
+  if let Some(requested_mtu) = *specifier.requested_mtu() {
+    let mtu = clamp_mtu(requested_mtu);
+    if mtu != requested_mtu {
+      warn!(
+        "Requested MTU {} for device {} is out of range, using {} instead.",
+        requested_mtu, name, mtu
+      );
+    }
+    match control.request_mtu(mtu).await {
+      Ok(()) => info!("Requested MTU {} for device {}.", mtu, name),
+      Err(BluetoothLEConnectionParameterError::Unsupported) => info!(
+        "Can't request MTU for device {} on this platform, using platform default.",
+        name
+      ),
+      Err(e) => warn!("Failed to request MTU {} for device {}: {}", mtu, name, e),
+    }
+  }
+  if let Some(priority) = *specifier.connection_priority() {
+    match control.request_connection_priority(priority).await {
+      Ok(()) => info!(
+        "Requested connection priority {:?} for device {}.",
+        priority, name
+      ),
+      Err(BluetoothLEConnectionParameterError::Unsupported) => info!(
+        "Can't request connection priority for device {} on this platform, using platform default.",
+        name
+      ),
+      Err(e) => warn!(
+        "Failed to request connection priority {:?} for device {}: {}",
+        priority, name, e
+      ),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::sync::Mutex;
+
+  #[derive(Default)]
+  struct MockControl {
+    unsupported: bool,
+    mtus: Mutex<Vec<u16>>,
+    priorities: Mutex<Vec<BluetoothLEConnectionPriority>>,
+  }
+
+  #[async_trait]
+  impl BluetoothLEConnectionControl for MockControl {
+    async fn request_mtu(&self, mtu: u16) -> Result<(), BluetoothLEConnectionParameterError> {
+      self
+        .mtus
+        .lock()
+        .expect("Test, assuming infallible.")
+        .push(mtu);
+      if self.unsupported {
+        Err(BluetoothLEConnectionParameterError::Unsupported)
+      } else {
+        Ok(())
+      }
+    }
+
+    async fn request_connection_priority(
+      &self,
+      priority: BluetoothLEConnectionPriority,
+    ) -> Result<(), BluetoothLEConnectionParameterError> {
+      self
+        .priorities
+        .lock()
+        .expect("Test, assuming infallible.")
+        .push(priority);
+      if self.unsupported {
+        Err(BluetoothLEConnectionParameterError::Unsupported)
+      } else {
+        Ok(())
+      }
+    }
+  }
+
+  fn specifier(
+    mtu: Option<u16>,
+    priority: Option<BluetoothLEConnectionPriority>,
+  ) -> BluetoothLESpecifier {
+    let mut specifier = BluetoothLESpecifier::new(
+      Default::default(),
+      vec![],
+      Default::default(),
+      Default::default(),
+    );
+    specifier.set_requested_mtu(mtu);
+    specifier.set_connection_priority(priority);
+    specifier
+  }
+
+  #[tokio::test]
+  async fn test_apply_connection_parameters() {
+    let control = MockControl::default();
+    apply_connection_parameters(
+      "Test",
+      &specifier(Some(247), Some(BluetoothLEConnectionPriority::High)),
+      &control,
+    )
+    .await;
+    assert_eq!(
+      *control.mtus.lock().expect("Test, assuming infallible."),
+      vec![247]
+    );
+    assert_eq!(
+      *control
+        .priorities
+        .lock()
+        .expect("Test, assuming infallible."),
+      vec![BluetoothLEConnectionPriority::High]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_apply_connection_parameters_clamps_mtu() {
+    let control = MockControl::default();
+    apply_connection_parameters("Test", &specifier(Some(5), None), &control).await;
+    apply_connection_parameters("Test", &specifier(Some(4096), None), &control).await;
+    assert_eq!(
+      *control.mtus.lock().expect("Test, assuming infallible."),
+      vec![BLUETOOTHLE_MIN_MTU, BLUETOOTHLE_MAX_MTU]
+    );
+    assert!(control
+      .priorities
+      .lock()
+      .expect("Test, assuming infallible.")
+      .is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_apply_connection_parameters_unset_or_unsupported() {
+    let control = MockControl::default();
+    apply_connection_parameters("Test", &specifier(None, None), &control).await;
+    assert!(control
+      .mtus
+      .lock()
+      .expect("Test, assuming infallible.")
+      .is_empty());
+
+    // Unsupported platforms are only logged, everything is still attempted.
+    let control = MockControl {
+      unsupported: true,
+      ..Default::default()
+    };
+    apply_connection_parameters(
+      "Test",
+      &specifier(Some(517), Some(BluetoothLEConnectionPriority::LowPower)),
+      &control,
+    )
+    .await;
+    assert_eq!(
+      *control.mtus.lock().expect("Test, assuming infallible."),
+      vec![517]
+    );
+    assert_eq!(
+      *control
+        .priorities
+        .lock()
+        .expect("Test, assuming infallible."),
+      vec![BluetoothLEConnectionPriority::LowPower]
+    );
+  }
+}
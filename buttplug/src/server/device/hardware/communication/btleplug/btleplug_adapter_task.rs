@@ -6,7 +6,10 @@
 // for full license information.
 
 use super::btleplug_hardware::BtleplugHardwareConnector;
-use crate::server::device::hardware::communication::HardwareCommunicationManagerEvent;
+use crate::server::device::{
+  configuration::DeviceAddressFilter,
+  hardware::communication::HardwareCommunicationManagerEvent,
+};
 use btleplug::{
   api::{Central, CentralEvent, Manager as _, Peripheral, ScanFilter},
   platform::{Adapter, Manager, PeripheralId},
@@ -37,6 +40,7 @@ struct PeripheralInfo {
   peripheral_id: PeripheralId,
   manufacturer_data: HashMap<u16, Vec<u8>>,
   services: Vec<uuid::Uuid>,
+  service_data: HashMap<uuid::Uuid, Vec<u8>>,
 }
 
 pub struct BtleplugAdapterTask {
@@ -44,6 +48,7 @@ pub struct BtleplugAdapterTask {
   command_receiver: Receiver<BtleplugAdapterCommand>,
   adapter_connected: Arc<AtomicBool>,
   requires_keepalive: bool,
+  address_filter: Option<DeviceAddressFilter>,
 }
 
 impl BtleplugAdapterTask {
@@ -52,12 +57,14 @@ impl BtleplugAdapterTask {
     command_receiver: Receiver<BtleplugAdapterCommand>,
     adapter_connected: Arc<AtomicBool>,
     requires_keepalive: bool,
+    address_filter: Option<DeviceAddressFilter>,
   ) -> Self {
     Self {
       event_sender,
       command_receiver,
       adapter_connected,
       requires_keepalive,
+      address_filter,
     }
   }
 
@@ -95,9 +102,14 @@ impl BtleplugAdapterTask {
       peripheral_id: peripheral_id.clone(),
       manufacturer_data: properties.manufacturer_data.clone(),
       services: properties.services.clone(),
+      service_data: properties.service_data.clone(),
     };
 
-    if (!device_name.is_empty() || !properties.services.is_empty())
+    // Devices without a name can still be identified through their advertised services or service
+    // data.
+    if (!device_name.is_empty()
+      || !properties.services.is_empty()
+      || !properties.service_data.is_empty())
       && !tried_addresses.contains(&peripheral_info)
     {
       let span = info_span!(
@@ -112,10 +124,23 @@ impl BtleplugAdapterTask {
         peripheral_info
       );
       tried_addresses.push(peripheral_info.clone());
+      let address = format!("{:?}", peripheral_id);
+      // Check the user config allow/deny lists before we build a connector, so denied devices
+      // never make it to the device manager (and never get connected to).
+      if let Some(filter) = &self.address_filter {
+        if !filter.allows(&address) {
+          info!(
+            "Device {} not allowed by user configuration, not reporting.",
+            address
+          );
+          return;
+        }
+      }
       let device_creator = Box::new(BtleplugHardwareConnector::new(
         &device_name,
         &properties.manufacturer_data,
         &properties.services,
+        &properties.service_data,
         peripheral.clone(),
         adapter.clone(),
         self.requires_keepalive,
@@ -124,7 +149,7 @@ impl BtleplugAdapterTask {
         .event_sender
         .send(HardwareCommunicationManagerEvent::DeviceFound {
           name: device_name,
-          address: format!("{:?}", peripheral_id),
+          address,
           creator: device_creator,
         })
         .await
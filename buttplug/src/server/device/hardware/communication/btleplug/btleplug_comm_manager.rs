@@ -8,10 +8,13 @@
 use super::btleplug_adapter_task::{BtleplugAdapterCommand, BtleplugAdapterTask};
 use crate::{
   core::{errors::ButtplugDeviceError, ButtplugResultFuture},
-  server::device::hardware::communication::{
-    HardwareCommunicationManager,
-    HardwareCommunicationManagerBuilder,
-    HardwareCommunicationManagerEvent,
+  server::device::{
+    configuration::DeviceAddressFilter,
+    hardware::communication::{
+      HardwareCommunicationManager,
+      HardwareCommunicationManagerBuilder,
+      HardwareCommunicationManagerEvent,
+    },
   },
   util::async_manager,
 };
@@ -25,6 +28,7 @@ use tokio::sync::mpsc::{channel, Sender};
 #[derive(Default, Clone)]
 pub struct BtlePlugCommunicationManagerBuilder {
   require_keepalive: bool,
+  address_filter: Option<DeviceAddressFilter>,
 }
 
 impl BtlePlugCommunicationManagerBuilder {
@@ -35,6 +39,10 @@ impl BtlePlugCommunicationManagerBuilder {
 }
 
 impl HardwareCommunicationManagerBuilder for BtlePlugCommunicationManagerBuilder {
+  fn address_filter(&mut self, filter: DeviceAddressFilter) {
+    self.address_filter = Some(filter);
+  }
+
   fn finish(
     &mut self,
     sender: Sender<HardwareCommunicationManagerEvent>,
@@ -42,6 +50,7 @@ impl HardwareCommunicationManagerBuilder for BtlePlugCommunicationManagerBuilder
     Box::new(BtlePlugCommunicationManager::new(
       sender,
       self.require_keepalive,
+      self.address_filter.clone(),
     ))
   }
 }
@@ -56,6 +65,7 @@ impl BtlePlugCommunicationManager {
   pub fn new(
     event_sender: Sender<HardwareCommunicationManagerEvent>,
     require_keepalive: bool,
+    address_filter: Option<DeviceAddressFilter>,
   ) -> Self {
     let (sender, receiver) = channel(256);
     let adapter_connected = Arc::new(AtomicBool::new(false));
@@ -66,6 +76,7 @@ impl BtlePlugCommunicationManager {
         receiver,
         adapter_connected_clone,
         require_keepalive,
+        address_filter,
       );
       task.run().await;
     });
@@ -7,9 +7,20 @@
 
 use crate::{
   core::{errors::ButtplugDeviceError, message::Endpoint},
-  server::device::hardware::communication::HardwareSpecificError,
+  server::device::hardware::communication::{
+    btle_connection_parameters::{
+      apply_connection_parameters,
+      BluetoothLEConnectionControl,
+      BluetoothLEConnectionParameterError,
+    },
+    HardwareSpecificError,
+  },
   server::device::{
-    configuration::{BluetoothLESpecifier, ProtocolCommunicationSpecifier},
+    configuration::{
+      BluetoothLEConnectionPriority,
+      BluetoothLESpecifier,
+      ProtocolCommunicationSpecifier,
+    },
     hardware::{
       Hardware,
       HardwareConnector,
@@ -53,6 +64,7 @@ pub(super) struct BtleplugHardwareConnector<T: Peripheral + 'static> {
   manufacturer_data: HashMap<u16, Vec<u8>>,
   // Passed in and stored as a member because otherwise it's annoying to get (properties require await)
   services: Vec<Uuid>,
+  service_data: HashMap<Uuid, Vec<u8>>,
   device: T,
   adapter: Adapter,
   requires_keepalive: bool,
@@ -63,6 +75,7 @@ impl<T: Peripheral> BtleplugHardwareConnector<T> {
     name: &str,
     manufacturer_data: &HashMap<u16, Vec<u8>>,
     services: &[Uuid],
+    service_data: &HashMap<Uuid, Vec<u8>>,
     device: T,
     adapter: Adapter,
     requires_keepalive: bool,
@@ -71,6 +84,7 @@ impl<T: Peripheral> BtleplugHardwareConnector<T> {
       name: name.to_owned(),
       manufacturer_data: manufacturer_data.clone(),
       services: services.to_vec(),
+      service_data: service_data.clone(),
       device,
       adapter,
       requires_keepalive,
@@ -94,6 +108,7 @@ impl<T: Peripheral> HardwareConnector for BtleplugHardwareConnector<T> {
       &self.name,
       &self.manufacturer_data,
       &self.services,
+      &self.service_data,
     ))
   }
 
@@ -145,6 +160,24 @@ impl<T: Peripheral> BtleplugHardwareSpecializer<T> {
   }
 }
 
+/// Btleplug doesn't expose MTU or connection priority on any platform yet, so connections stay on
+/// the platform defaults.
+struct BtleplugConnectionControl;
+
+#[async_trait]
+impl BluetoothLEConnectionControl for BtleplugConnectionControl {
+  async fn request_mtu(&self, _mtu: u16) -> Result<(), BluetoothLEConnectionParameterError> {
+    Err(BluetoothLEConnectionParameterError::Unsupported)
+  }
+
+  async fn request_connection_priority(
+    &self,
+    _priority: BluetoothLEConnectionPriority,
+  ) -> Result<(), BluetoothLEConnectionParameterError> {
+    Err(BluetoothLEConnectionParameterError::Unsupported)
+  }
+}
+
 #[async_trait]
 impl<T: Peripheral> HardwareSpecializer for BtleplugHardwareSpecializer<T> {
   async fn specialize(
@@ -160,6 +193,7 @@ impl<T: Peripheral> HardwareSpecializer for BtleplugHardwareSpecializer<T> {
       .iter()
       .find(|x| matches!(x, ProtocolCommunicationSpecifier::BluetoothLE(_)))
     {
+      apply_connection_parameters(&self.name, btle, &BtleplugConnectionControl).await;
       for (proto_uuid, proto_service) in btle.services() {
         for service in self.device.services() {
           if service.uuid != *proto_uuid {
@@ -175,15 +209,19 @@ impl<T: Peripheral> HardwareSpecializer for BtleplugHardwareSpecializer<T> {
               );
               endpoints.insert(*chr_name, chr.clone());
               uuid_map.insert(*chr_uuid, *chr_name);
-            } else {
-              error!(
-                "Characteristic {} ({}) not found, may cause issues in connection.",
+            } else if btle.optional_endpoints().contains(chr_name) {
+              info!(
+                "Optional characteristic {} ({}) not found, continuing without it.",
                 chr_name, chr_uuid
               );
+            } else {
+              error!("Characteristic {} ({}) not found.", chr_name, chr_uuid);
             }
           }
         }
       }
+      let found: Vec<Endpoint> = endpoints.keys().cloned().collect();
+      btle.check_discovered_endpoints(&self.name, &format!("{:?}", address), &found)?;
     } else {
       error!(
         "Can't find btle protocol specifier mapping for device {} {:?}",
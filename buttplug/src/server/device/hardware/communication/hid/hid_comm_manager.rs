@@ -1,11 +1,14 @@
 use crate::{
   core::errors::ButtplugDeviceError,
-  server::device::hardware::communication::{
-    HardwareCommunicationManager,
-    HardwareCommunicationManagerBuilder,
-    HardwareCommunicationManagerEvent,
-    TimedRetryCommunicationManager,
-    TimedRetryCommunicationManagerImpl,
+  server::device::{
+    configuration::DeviceAddressFilter,
+    hardware::communication::{
+      HardwareCommunicationManager,
+      HardwareCommunicationManagerBuilder,
+      HardwareCommunicationManagerEvent,
+      TimedRetryCommunicationManager,
+      TimedRetryCommunicationManagerImpl,
+    },
   },
 };
 use async_trait::async_trait;
@@ -16,15 +19,21 @@ use tokio::sync::mpsc::Sender;
 use super::hid_device_impl::HidHardwareConnector;
 
 #[derive(Default)]
-pub struct HidCommunicationManagerBuilder {}
+pub struct HidCommunicationManagerBuilder {
+  address_filter: Option<DeviceAddressFilter>,
+}
 
 impl HardwareCommunicationManagerBuilder for HidCommunicationManagerBuilder {
+  fn address_filter(&mut self, filter: DeviceAddressFilter) {
+    self.address_filter = Some(filter);
+  }
+
   fn finish(
     &mut self,
     sender: Sender<HardwareCommunicationManagerEvent>,
   ) -> Box<dyn HardwareCommunicationManager> {
     Box::new(TimedRetryCommunicationManager::new(
-      HidCommunicationManager::new(sender),
+      HidCommunicationManager::new(sender, self.address_filter.clone()),
     ))
   }
 }
@@ -32,13 +41,18 @@ impl HardwareCommunicationManagerBuilder for HidCommunicationManagerBuilder {
 pub struct HidCommunicationManager {
   sender: Sender<HardwareCommunicationManagerEvent>,
   hidapi: Arc<HidApi>,
+  address_filter: Option<DeviceAddressFilter>,
 }
 
 impl HidCommunicationManager {
-  fn new(sender: Sender<HardwareCommunicationManagerEvent>) -> Self {
+  fn new(
+    sender: Sender<HardwareCommunicationManagerEvent>,
+    address_filter: Option<DeviceAddressFilter>,
+  ) -> Self {
     Self {
       sender,
       hidapi: Arc::new(HidApi::new().unwrap()),
+      address_filter,
     }
   }
 }
@@ -64,6 +78,15 @@ impl TimedRetryCommunicationManagerImpl for HidCommunicationManager {
         continue;
       }
       seen_addresses.push(serial_number.clone());
+      if let Some(filter) = &self.address_filter {
+        if !filter.allows(&serial_number) {
+          debug!(
+            "HID device {} not allowed by user configuration, not reporting.",
+            serial_number
+          );
+          continue;
+        }
+      }
       let device_creator = HidHardwareConnector::new(api.clone(), &device);
       if device_sender
         .send(HardwareCommunicationManagerEvent::DeviceFound {
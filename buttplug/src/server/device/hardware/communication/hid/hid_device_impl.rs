@@ -5,7 +5,6 @@ use crate::{
     configuration::{ProtocolCommunicationSpecifier, VIDPIDSpecifier},
     hardware::{
       Endpoint,
-      GenericHardwareSpecializer,
       Hardware,
       HardwareConnector,
       HardwareEvent,
@@ -23,6 +22,7 @@ use async_trait::async_trait;
 use futures::{future::BoxFuture, AsyncWriteExt};
 use hidapi::{DeviceInfo, HidApi};
 use std::{
+  collections::HashMap,
   fmt::{self, Debug},
   sync::{
     atomic::{AtomicBool, Ordering},
@@ -71,18 +71,57 @@ impl HardwareConnector for HidHardwareConnector {
 
   async fn connect(&mut self) -> Result<Box<dyn HardwareSpecializer>, ButtplugDeviceError> {
     let device = self.device_info.open_device(&self.hid_instance).unwrap();
-    let device_impl_internal = HIDDeviceImpl::new(HidAsyncDevice::new(device).unwrap());
     info!(
       "New HID device created: {}",
       self.device_info.product_string().unwrap()
     );
-    let hardware = Hardware::new(
+    Ok(Box::new(HidHardwareSpecializer {
+      device: Some(Mutex::new(HidAsyncDevice::new(device).unwrap())),
+      device_info: self.device_info.clone(),
+    }))
+  }
+}
+
+/// Picks up the report IDs for the device's endpoints from the protocol's HID specifier.
+pub struct HidHardwareSpecializer {
+  device: Option<Mutex<HidAsyncDevice>>,
+  device_info: DeviceInfo,
+}
+
+#[async_trait]
+impl HardwareSpecializer for HidHardwareSpecializer {
+  async fn specialize(
+    &mut self,
+    specifiers: &[ProtocolCommunicationSpecifier],
+  ) -> Result<Hardware, ButtplugDeviceError> {
+    let device_specifier =
+      VIDPIDSpecifier::new(self.device_info.vendor_id(), self.device_info.product_id());
+    let report_ids = specifiers
+      .iter()
+      .find_map(|specifier| match specifier {
+        ProtocolCommunicationSpecifier::HID(hid) if *hid == device_specifier => {
+          Some(hid.report_ids().clone())
+        }
+        _ => None,
+      })
+      .unwrap_or_default();
+    let mut endpoints = vec![Endpoint::Rx, Endpoint::Tx];
+    for endpoint in report_ids.keys() {
+      if !endpoints.contains(endpoint) {
+        endpoints.push(*endpoint);
+      }
+    }
+    let device = self
+      .device
+      .take()
+      .expect("This should only be run once")
+      .into_inner();
+    Ok(Hardware::new(
       &self.device_info.product_string().unwrap(),
       &self.device_info.serial_number().unwrap(),
-      &[Endpoint::Rx, Endpoint::Tx],
-      Box::new(device_impl_internal),
-    );
-    Ok(Box::new(GenericHardwareSpecializer::new(hardware)))
+      &endpoints,
+      Box::new(HIDDeviceImpl::new(device, report_ids)),
+    ))
   }
 }
 
@@ -90,13 +129,16 @@ pub struct HIDDeviceImpl {
   connected: Arc<AtomicBool>,
   device_event_sender: broadcast::Sender<HardwareEvent>,
   device: Arc<Mutex<HidAsyncDevice>>,
+  /// Report IDs for writes to each endpoint, from the device config.
+  report_ids: HashMap<Endpoint, u8>,
 }
 
 impl HIDDeviceImpl {
-  pub fn new(device: HidAsyncDevice) -> Self {
+  pub fn new(device: HidAsyncDevice, report_ids: HashMap<Endpoint, u8>) -> Self {
     let (device_event_sender, _) = broadcast::channel(256);
     Self {
       device: Arc::new(Mutex::new(device)),
+      report_ids,
       connected: Arc::new(AtomicBool::new(true)),
       device_event_sender,
     }
@@ -128,7 +170,7 @@ impl HardwareInternal for HIDDeviceImpl {
     msg: &HardwareWriteCmd,
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
     let device = self.device.clone();
-    let data = msg.data.clone();
+    let data = msg.hid_output_report(&self.report_ids);
     Box::pin(async move {
       device.lock().await.write(&data).await.map_err(|e| {
         ButtplugDeviceError::DeviceCommunicationError(format!(
@@ -8,12 +8,15 @@
 use super::lovense_connect_service_hardware::LovenseServiceHardwareConnector;
 use crate::{
   core::errors::ButtplugDeviceError,
-  server::device::hardware::communication::{
-    HardwareCommunicationManager,
-    HardwareCommunicationManagerBuilder,
-    HardwareCommunicationManagerEvent,
-    TimedRetryCommunicationManager,
-    TimedRetryCommunicationManagerImpl,
+  server::device::{
+    configuration::{DeviceAddressFilter, LovenseConnectServiceSpecifier},
+    hardware::communication::{
+      HardwareCommunicationManager,
+      HardwareCommunicationManagerBuilder,
+      HardwareCommunicationManagerEvent,
+      TimedRetryCommunicationManager,
+      TimedRetryCommunicationManagerImpl,
+    },
   },
 };
 use async_trait::async_trait;
@@ -21,7 +24,11 @@ use dashmap::DashSet;
 use reqwest::StatusCode;
 use serde::{Deserialize, Deserializer};
 use serde_aux::prelude::*;
-use std::{collections::HashMap, time::Duration};
+use std::{
+  collections::HashMap,
+  sync::{Arc, RwLock},
+  time::Duration,
+};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender;
 
@@ -110,23 +117,129 @@ pub(super) struct LovenseServiceLocalInfo {
 
 type LovenseServiceInfo = HashMap<String, LovenseServiceHostInfo>;
 
+static DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A Lovense Connect app on the local network, as reported by the Lovense remote API.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct LovenseServiceHost {
+  /// IP address of the host, which is what the specifier allow list is matched against.
+  address: String,
+  http_port: u16,
+}
+
+impl LovenseServiceHost {
+  fn url(&self) -> String {
+    // We set the protocol type here so it'll just filter down, in case we want to move to secure.
+    format!("http://{}:{}", self.address, self.http_port)
+  }
+}
+
+/// The HTTP calls made while scanning, so scan logic can be tested without the Lovense APIs.
+#[async_trait]
+trait LovenseServiceApi: Send + Sync {
+  /// Ask the Lovense remote API for the Lovense Connect hosts on our network.
+  async fn remote_hosts(&self) -> Option<Vec<LovenseServiceHost>>;
+  /// Ask a Lovense Connect host for its toys.
+  async fn local_info(&self, host_url: &str) -> Option<LovenseServiceLocalInfo>;
+}
+
+struct LovenseServiceHttpApi {}
+
+#[async_trait]
+impl LovenseServiceApi for LovenseServiceHttpApi {
+  async fn remote_hosts(&self) -> Option<Vec<LovenseServiceHost>> {
+    match reqwest::get("https://api.lovense.com/api/lan/getToys").await {
+      Ok(res) => {
+        if res.status() != StatusCode::OK {
+          error!(
+            "Error contacting Lovense Connect Remote API endpoint. Status returned: {}",
+            res.status()
+          );
+          return None;
+        }
+        let text = res
+          .text()
+          .await
+          .expect("Should always get json back from service, if we got a response.");
+        let info: LovenseServiceInfo = serde_json::from_str(&text)
+          .expect("Should always get json back from service, if we got a response.");
+        Some(
+          info
+            .iter()
+            .map(|x| {
+              // Lovense Connect uses [ip].lovense.club, which is a loopback DNS resolver that
+              // should just point to [ip]. This is used for handling secure certificate
+              // resolution when trying to use lovense connect over secure contexts. However,
+              // this sometimes fails on DNS resolution. Since we aren't using secure contexts
+              // at the moment, we can just cut out the IP from the domain and use that
+              // directly, which has fixed issues for some users.
+              let host_parts: Vec<&str> = x.0.split('.').collect();
+              LovenseServiceHost {
+                address: host_parts[0].replace('-', "."),
+                http_port: x.1.http_port,
+              }
+            })
+            .collect(),
+        )
+      }
+      Err(err) => {
+        error!("Got http error: {}", err);
+        None
+      }
+    }
+  }
+
+  async fn local_info(&self, host_url: &str) -> Option<LovenseServiceLocalInfo> {
+    get_local_info(host_url).await
+  }
+}
+
 #[derive(Default, Clone)]
-pub struct LovenseConnectServiceCommunicationManagerBuilder {}
+pub struct LovenseConnectServiceCommunicationManagerBuilder {
+  specifier: Arc<RwLock<LovenseConnectServiceSpecifier>>,
+  address_filter: Option<DeviceAddressFilter>,
+}
+
+impl LovenseConnectServiceCommunicationManagerBuilder {
+  /// Set which Lovense Connect hosts are connected to, and how often to poll for devices.
+  ///
+  /// Clones of this builder share this setting with any manager built from them, so it can be
+  /// updated while scanning (for instance, after reloading the device config, see
+  /// [DeviceConfigurationManager::lovense_connect_service_specifier](crate::server::device::configuration::DeviceConfigurationManager::lovense_connect_service_specifier))
+  /// without restarting the server.
+  pub fn specifier(&mut self, specifier: &LovenseConnectServiceSpecifier) -> &mut Self {
+    *self.specifier.write().expect("Lock poisoned") = specifier.clone();
+    self
+  }
+}
 
 impl HardwareCommunicationManagerBuilder for LovenseConnectServiceCommunicationManagerBuilder {
+  fn address_filter(&mut self, filter: DeviceAddressFilter) {
+    self.address_filter = Some(filter);
+  }
+
   fn finish(
     &mut self,
     sender: Sender<HardwareCommunicationManagerEvent>,
   ) -> Box<dyn HardwareCommunicationManager> {
     Box::new(TimedRetryCommunicationManager::new(
-      LovenseConnectServiceCommunicationManager::new(sender),
+      LovenseConnectServiceCommunicationManager::new(
+        sender,
+        self.specifier.clone(),
+        self.address_filter.clone(),
+        Box::new(LovenseServiceHttpApi {}),
+      ),
     ))
   }
 }
 
 pub struct LovenseConnectServiceCommunicationManager {
   sender: mpsc::Sender<HardwareCommunicationManagerEvent>,
-  known_hosts: DashSet<String>,
+  known_hosts: DashSet<LovenseServiceHost>,
+  specifier: Arc<RwLock<LovenseConnectServiceSpecifier>>,
+  /// Checked against toy ids, which are the device addresses.
+  address_filter: Option<DeviceAddressFilter>,
+  api: Box<dyn LovenseServiceApi>,
 }
 
 pub(super) async fn get_local_info(host: &str) -> Option<LovenseServiceLocalInfo> {
@@ -167,25 +280,60 @@ pub(super) async fn get_local_info(host: &str) -> Option<LovenseServiceLocalInfo
 }
 
 impl LovenseConnectServiceCommunicationManager {
-  fn new(sender: mpsc::Sender<HardwareCommunicationManagerEvent>) -> Self {
+  fn new(
+    sender: mpsc::Sender<HardwareCommunicationManagerEvent>,
+    specifier: Arc<RwLock<LovenseConnectServiceSpecifier>>,
+    address_filter: Option<DeviceAddressFilter>,
+    api: Box<dyn LovenseServiceApi>,
+  ) -> Self {
     Self {
       sender,
       known_hosts: DashSet::new(),
+      specifier,
+      address_filter,
+      api,
     }
   }
 
+  fn allows_host(&self, host: &LovenseServiceHost) -> bool {
+    self
+      .specifier
+      .read()
+      .expect("Lock poisoned")
+      .allows_host(&host.address)
+  }
+
   async fn lovense_local_service_check(&self) {
-    if self.known_hosts.is_empty() {
-      return;
-    }
-    for host in self.known_hosts.iter() {
-      match get_local_info(&host).await {
+    // The allow list may have changed since the hosts were found.
+    self.known_hosts.retain(|host| {
+      let allowed = self.allows_host(host);
+      if !allowed {
+        info!(
+          "Lovense Connect host {} is no longer allowed, ignoring it.",
+          host.address
+        );
+      }
+      allowed
+    });
+    let hosts: Vec<LovenseServiceHost> = self.known_hosts.iter().map(|host| host.clone()).collect();
+    for host in hosts {
+      let host_url = host.url();
+      match self.api.local_info(&host_url).await {
         Some(info) => {
           for (_, toy) in info.data.iter() {
             if !toy.connected {
               continue;
             }
-            let device_creator = Box::new(LovenseServiceHardwareConnector::new(&host, toy));
+            if let Some(filter) = &self.address_filter {
+              if !filter.allows(&toy.id) {
+                debug!(
+                  "Lovense Connect toy {} not allowed by user configuration, not reporting.",
+                  toy.id
+                );
+                continue;
+              }
+            }
+            let device_creator = Box::new(LovenseServiceHardwareConnector::new(&host_url, toy));
             // This will emit all of the toys as new devices every time we find them. Just let the
             // Device Manager reject them as either connecting or already connected.
             if self
@@ -203,7 +351,7 @@ impl LovenseConnectServiceCommunicationManager {
           }
         }
         None => {
-          self.known_hosts.remove(&*host);
+          self.known_hosts.remove(&host);
         }
       }
     }
@@ -217,54 +365,36 @@ impl TimedRetryCommunicationManagerImpl for LovenseConnectServiceCommunicationMa
   }
 
   fn rescan_wait_duration(&self) -> Duration {
-    Duration::from_secs(10)
+    self
+      .specifier
+      .read()
+      .expect("Lock poisoned")
+      .poll_interval_ms()
+      .map_or(DEFAULT_POLL_INTERVAL, Duration::from_millis)
   }
 
   async fn scan(&self) -> Result<(), ButtplugDeviceError> {
     // If we already know about a local host, check it. Otherwise, query remotely to look for local
     // hosts.
-    if !self.known_hosts.is_empty() {
-      self.lovense_local_service_check().await;
-    } else {
-      match reqwest::get("https://api.lovense.com/api/lan/getToys").await {
-        Ok(res) => {
-          if res.status() != StatusCode::OK {
-            error!(
-              "Error contacting Lovense Connect Remote API endpoint. Status returned: {}",
-              res.status()
+    if self.known_hosts.is_empty() {
+      if let Some(hosts) = self.api.remote_hosts().await {
+        for host in hosts {
+          if !self.allows_host(&host) {
+            info!(
+              "Lovense Connect host {} is not in the allowed hosts, ignoring it.",
+              host.address
             );
-            return Ok(());
-          }
-          let text = res
-            .text()
-            .await
-            .expect("Should always get json back from service, if we got a response.");
-          let info: LovenseServiceInfo = serde_json::from_str(&text)
-            .expect("Should always get json back from service, if we got a response.");
-          info.iter().for_each(|x| {
-            // Lovense Connect uses [ip].lovense.club, which is a loopback DNS resolver that
-            // should just point to [ip]. This is used for handling secure certificate
-            // resolution when trying to use lovense connect over secure contexts. However,
-            // this sometimes fails on DNS resolution. Since we aren't using secure contexts
-            // at the moment, we can just cut out the IP from the domain and use that
-            // directly, which has fixed issues for some users.
-            let host_parts: Vec<&str> = x.0.split('.').collect();
-            let new_http_host = host_parts[0].replace('-', ".");
-            // We set the protocol type here so it'll just filter down, in case we want to move to secure.
-            let host = format!("http://{}:{}", new_http_host, x.1.http_port);
-            debug!("Lovense Connect converting IP to {}", host);
-            self.known_hosts.insert(host);
-          });
-          // If we've found new hosts, go ahead and search them.
-          if !self.known_hosts.is_empty() {
-            self.lovense_local_service_check().await
+            continue;
           }
-        }
-        Err(err) => {
-          error!("Got http error: {}", err);
+          debug!("Lovense Connect converting IP to {}", host.url());
+          self.known_hosts.insert(host);
         }
       }
     }
+    // If we've found new hosts, go ahead and search them.
+    if !self.known_hosts.is_empty() {
+      self.lovense_local_service_check().await;
+    }
     Ok(())
   }
 
@@ -274,3 +404,156 @@ impl TimedRetryCommunicationManagerImpl for LovenseConnectServiceCommunicationMa
     true
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::server::device::configuration::DeviceConfigurationManagerBuilder;
+  use std::sync::Mutex;
+
+  #[derive(Default)]
+  struct MockLovenseServiceApi {
+    hosts: Vec<LovenseServiceHost>,
+    queried_urls: Arc<Mutex<Vec<String>>>,
+  }
+
+  #[async_trait]
+  impl LovenseServiceApi for MockLovenseServiceApi {
+    async fn remote_hosts(&self) -> Option<Vec<LovenseServiceHost>> {
+      Some(self.hosts.clone())
+    }
+
+    async fn local_info(&self, host_url: &str) -> Option<LovenseServiceLocalInfo> {
+      self
+        .queried_urls
+        .lock()
+        .expect("Test, assuming infallible.")
+        .push(host_url.to_owned());
+      // Every host has a single connected toy, named after the host.
+      let toy = LovenseServiceToyInfo {
+        id: host_url.to_owned(),
+        name: format!("Toy at {host_url}"),
+        _nickname: String::new(),
+        connected: true,
+        _version: 0,
+        battery: 100,
+      };
+      Some(LovenseServiceLocalInfo {
+        _reply_type: String::new(),
+        _code: 200,
+        data: HashMap::from([(toy.id.clone(), toy)]),
+      })
+    }
+  }
+
+  fn host(address: &str) -> LovenseServiceHost {
+    LovenseServiceHost {
+      address: address.to_owned(),
+      http_port: 20010,
+    }
+  }
+
+  fn found_addresses(
+    receiver: &mut mpsc::Receiver<HardwareCommunicationManagerEvent>,
+  ) -> Vec<String> {
+    let mut addresses = vec![];
+    while let Ok(event) = receiver.try_recv() {
+      if let HardwareCommunicationManagerEvent::DeviceFound { address, .. } = event {
+        addresses.push(address);
+      }
+    }
+    addresses
+  }
+
+  #[tokio::test]
+  async fn test_allowed_hosts_filter() {
+    let (sender, mut receiver) = mpsc::channel(256);
+    let specifier = Arc::new(RwLock::new(LovenseConnectServiceSpecifier::new(
+      &Some(vec!["192.168.1.10".to_owned()]),
+      None,
+    )));
+    let api = MockLovenseServiceApi {
+      hosts: vec![host("192.168.1.10"), host("192.168.1.20")],
+      ..Default::default()
+    };
+    let queried_urls = api.queried_urls.clone();
+    let manager = LovenseConnectServiceCommunicationManager::new(
+      sender,
+      specifier.clone(),
+      None,
+      Box::new(api),
+    );
+
+    manager.scan().await.expect("Test, assuming infallible.");
+    assert_eq!(
+      found_addresses(&mut receiver),
+      vec!["http://192.168.1.10:20010".to_owned()]
+    );
+    // The filtered host is never contacted.
+    assert_eq!(
+      *queried_urls.lock().expect("Test, assuming infallible."),
+      vec!["http://192.168.1.10:20010".to_owned()]
+    );
+
+    // Updating the shared specifier drops hosts we already know about.
+    *specifier.write().expect("Test, assuming infallible.") =
+      LovenseConnectServiceSpecifier::new(&Some(vec!["192.168.1.20".to_owned()]), None);
+    manager.scan().await.expect("Test, assuming infallible.");
+    assert!(found_addresses(&mut receiver).is_empty());
+    manager.scan().await.expect("Test, assuming infallible.");
+    assert_eq!(
+      found_addresses(&mut receiver),
+      vec!["http://192.168.1.20:20010".to_owned()]
+    );
+    assert_eq!(
+      queried_urls
+        .lock()
+        .expect("Test, assuming infallible.")
+        .len(),
+      2
+    );
+  }
+
+  #[tokio::test]
+  async fn test_address_filter() {
+    let (sender, mut receiver) = mpsc::channel(256);
+    let mut dcm_builder = DeviceConfigurationManagerBuilder::default();
+    dcm_builder.deny_address("http://192.168.1.20:20010");
+    let filter = DeviceAddressFilter::new(Arc::new(
+      dcm_builder.finish().expect("Test, assuming infallible."),
+    ));
+    let api = MockLovenseServiceApi {
+      hosts: vec![host("192.168.1.10"), host("192.168.1.20")],
+      ..Default::default()
+    };
+    let manager = LovenseConnectServiceCommunicationManager::new(
+      sender,
+      Arc::new(RwLock::new(LovenseConnectServiceSpecifier::default())),
+      Some(filter),
+      Box::new(api),
+    );
+
+    manager.scan().await.expect("Test, assuming infallible.");
+    // Toys are filtered by id, so the denied toy is never reported.
+    assert_eq!(
+      found_addresses(&mut receiver),
+      vec!["http://192.168.1.10:20010".to_owned()]
+    );
+  }
+
+  #[test]
+  fn test_poll_interval() {
+    let (sender, _receiver) = mpsc::channel(256);
+    let specifier = Arc::new(RwLock::new(LovenseConnectServiceSpecifier::default()));
+    let manager = LovenseConnectServiceCommunicationManager::new(
+      sender,
+      specifier.clone(),
+      None,
+      Box::new(MockLovenseServiceApi::default()),
+    );
+    assert_eq!(manager.rescan_wait_duration(), DEFAULT_POLL_INTERVAL);
+    *specifier.write().expect("Test, assuming infallible.") =
+      LovenseConnectServiceSpecifier::new(&None, Some(30000));
+    assert_eq!(manager.rescan_wait_duration(), Duration::from_secs(30));
+  }
+}
@@ -75,7 +75,12 @@ impl LovenseDongleHardwareConnector {
       //
       // Hacky, but it works.
       specifier: ProtocolCommunicationSpecifier::BluetoothLE(
-        BluetoothLESpecifier::new_from_device("LVS-DongleDevice", &HashMap::new(), &[]),
+        BluetoothLESpecifier::new_from_device(
+          "LVS-DongleDevice",
+          &HashMap::new(),
+          &[],
+          &HashMap::new(),
+        ),
       ),
       id: id.to_string(),
       device_outgoing,
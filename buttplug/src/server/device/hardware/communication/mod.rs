@@ -5,6 +5,8 @@
 // Licensed under the BSD 3-Clause license. See LICENSE file in the project root
 // for full license information.
 
+pub mod btle_connection_parameters;
+
 // Network DCMs work on all platforms
 #[cfg(feature = "lovense-connect-service-manager")]
 pub mod lovense_connect_service;
@@ -48,7 +50,7 @@ pub mod xinput;
 
 use crate::{
   core::{errors::ButtplugDeviceError, ButtplugResultFuture},
-  server::device::hardware::HardwareConnector,
+  server::device::{configuration::DeviceAddressFilter, hardware::HardwareConnector},
   util::{async_manager, sleep},
 };
 use async_trait::async_trait;
@@ -72,6 +74,15 @@ pub enum HardwareCommunicationManagerEvent {
 }
 
 pub trait HardwareCommunicationManagerBuilder: Send {
+  /// Called before [finish](HardwareCommunicationManagerBuilder::finish) with the user
+  /// configuration address filter. Comm managers should check it before connecting to a device or
+  /// reporting it upward. Defaults to ignoring the filter, in which case the device manager still
+  /// checks the address when the device is reported. The BLE, serial, HID, XInput and Lovense
+  /// Connect managers check it, the Lovense dongle managers don't, as the dongle has already
+  /// connected to a toy by the time its address is known.
+  fn address_filter(&mut self, _filter: DeviceAddressFilter) {
+  }
+
   fn finish(
     &mut self,
     sender: Sender<HardwareCommunicationManagerEvent>,
@@ -155,7 +166,6 @@ impl<T: TimedRetryCommunicationManagerImpl> HardwareCommunicationManager
     let token = CancellationToken::new();
     let child_token = token.child_token();
     self.cancellation_token = Some(token);
-    let duration = self.comm_manager.rescan_wait_duration();
     async move {
       async_manager::spawn(async move {
         loop {
@@ -163,8 +173,9 @@ impl<T: TimedRetryCommunicationManagerImpl> HardwareCommunicationManager
             error!("Timed Device Communication Manager Failure: {}", err);
             break;
           }
+          // Check the wait every time, as some managers allow changing it while scanning.
           tokio::select! {
-            _ = sleep(duration) => continue,
+            _ = sleep(comm_manager.rescan_wait_duration()) => continue,
             _ = child_token.cancelled() => break,
           }
         }
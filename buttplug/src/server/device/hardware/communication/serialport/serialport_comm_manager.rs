@@ -10,12 +10,15 @@ use std::time::Duration;
 use super::SerialPortHardwareConnector;
 use crate::{
   core::errors::ButtplugDeviceError,
-  server::device::hardware::communication::{
-    HardwareCommunicationManager,
-    HardwareCommunicationManagerBuilder,
-    HardwareCommunicationManagerEvent,
-    TimedRetryCommunicationManager,
-    TimedRetryCommunicationManagerImpl,
+  server::device::{
+    configuration::DeviceAddressFilter,
+    hardware::communication::{
+      HardwareCommunicationManager,
+      HardwareCommunicationManagerBuilder,
+      HardwareCommunicationManagerEvent,
+      TimedRetryCommunicationManager,
+      TimedRetryCommunicationManagerImpl,
+    },
   },
 };
 use async_trait::async_trait;
@@ -23,27 +26,40 @@ use serialport::available_ports;
 use tokio::sync::mpsc::Sender;
 
 #[derive(Default, Clone)]
-pub struct SerialPortCommunicationManagerBuilder {}
+pub struct SerialPortCommunicationManagerBuilder {
+  address_filter: Option<DeviceAddressFilter>,
+}
 
 impl HardwareCommunicationManagerBuilder for SerialPortCommunicationManagerBuilder {
+  fn address_filter(&mut self, filter: DeviceAddressFilter) {
+    self.address_filter = Some(filter);
+  }
+
   fn finish(
     &mut self,
     sender: Sender<HardwareCommunicationManagerEvent>,
   ) -> Box<dyn HardwareCommunicationManager> {
     Box::new(TimedRetryCommunicationManager::new(
-      SerialPortCommunicationManager::new(sender),
+      SerialPortCommunicationManager::new(sender, self.address_filter.clone()),
     ))
   }
 }
 
 pub struct SerialPortCommunicationManager {
   sender: Sender<HardwareCommunicationManagerEvent>,
+  address_filter: Option<DeviceAddressFilter>,
 }
 
 impl SerialPortCommunicationManager {
-  fn new(sender: Sender<HardwareCommunicationManagerEvent>) -> Self {
+  fn new(
+    sender: Sender<HardwareCommunicationManagerEvent>,
+    address_filter: Option<DeviceAddressFilter>,
+  ) -> Self {
     trace!("Serial port created.");
-    Self { sender }
+    Self {
+      sender,
+      address_filter,
+    }
   }
 }
 
@@ -63,6 +79,15 @@ impl TimedRetryCommunicationManagerImpl for SerialPortCommunicationManager {
       Ok(ports) => {
         debug!("Got {} serial ports back", ports.len());
         for p in ports {
+          if let Some(filter) = &self.address_filter {
+            if !filter.allows(&p.port_name) {
+              debug!(
+                "Serial port {} not allowed by user configuration, not reporting.",
+                p.port_name
+              );
+              continue;
+            }
+          }
           trace!(
             "Sending serial port {:?} for possible device connection.",
             p
@@ -194,6 +194,12 @@ impl SerialPortHardware {
       }
     }
     let port_def = port_def.expect("We'll always have a port definition by this point");
+    let baud_rate = port_def.baud_rate().ok_or_else(|| {
+      ButtplugDeviceError::DeviceConfigurationError(format!(
+        "Serial port definition for {} has no baud rate.",
+        port_def.port()
+      ))
+    })?;
 
     // This seems like it should be a oneshot, but there's no way to await a
     // value on those?
@@ -210,7 +216,7 @@ impl SerialPortHardware {
       .name("Serial Port Connection Thread".to_string())
       .spawn(move || {
         debug!("Starting serial port connection thread for {}", port_name);
-        let port_result = serialport::new(&port_name, *port_def.baud_rate())
+        let port_result = serialport::new(&port_name, baud_rate)
           .timeout(Duration::from_millis(100))
           .open();
         if port_sender.blocking_send(port_result)
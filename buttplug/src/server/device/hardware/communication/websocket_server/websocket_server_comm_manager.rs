@@ -7,20 +7,45 @@
 
 use super::websocket_server_hardware::WebsocketServerHardwareConnector;
 use crate::{
-  core::ButtplugResultFuture,
-  server::device::hardware::communication::{
-    HardwareCommunicationManager,
-    HardwareCommunicationManagerBuilder,
-    HardwareCommunicationManagerEvent,
+  core::{connector::transport::get_rustls_config_dangerous, ButtplugResultFuture},
+  server::device::{
+    configuration::WebsocketSpecifier,
+    hardware::communication::{
+      HardwareCommunicationManager,
+      HardwareCommunicationManagerBuilder,
+      HardwareCommunicationManagerEvent,
+    },
   },
-  util::async_manager,
+  util::{self, async_manager},
 };
 use futures::{FutureExt, StreamExt};
 use getset::{CopyGetters, Getters};
+use instant::Instant;
 use serde::{Deserialize, Serialize};
-use tokio::{net::TcpListener, sync::mpsc::Sender};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+  net::{TcpListener, TcpStream},
+  sync::mpsc::Sender,
+};
+use tokio_tungstenite::{
+  connect_async_tls_with_config,
+  tungstenite::{
+    client::IntoClientRequest,
+    http::{header::AUTHORIZATION, HeaderValue},
+  },
+  Connector,
+  MaybeTlsStream,
+  WebSocketStream,
+};
 use tokio_util::sync::CancellationToken;
 
+// Wait between attempts to reach an outbound device, doubled after every failure up to the maximum.
+const OUTBOUND_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const OUTBOUND_MAXIMUM_BACKOFF: Duration = Duration::from_secs(30);
+// Connections that close sooner than this count as failed attempts, so a device the server drops
+// right away (denied, no matching protocol, etc) isn't redialed constantly.
+const OUTBOUND_STABLE_CONNECTION: Duration = Duration::from_secs(10);
+
 // Packet format received from external devices.
 #[derive(Serialize, Deserialize, Debug, Clone, Getters, CopyGetters)]
 pub struct WebsocketServerDeviceCommManagerInitInfo {
@@ -30,12 +55,30 @@ pub struct WebsocketServerDeviceCommManagerInitInfo {
   address: String,
   #[getset(get_copy = "pub")]
   version: u32,
+  /// If not empty, the connection carries multiple devices, and every frame is prefixed with the
+  /// index of the device it's for in this list.
+  #[serde(default)]
+  #[getset(get = "pub")]
+  devices: Vec<WebsocketServerDeviceInfo>,
+}
+
+/// A device declared as part of a multiplexed websocket connection.
+#[derive(Serialize, Deserialize, Debug, Clone, Getters)]
+#[getset(get = "pub")]
+pub struct WebsocketServerDeviceInfo {
+  /// Name matched against websocket specifiers in the device config.
+  name: String,
+  /// Used to build the device address, so it's stable across connections. Defaults to the device
+  /// index in the handshake.
+  #[serde(default)]
+  identifier: Option<String>,
 }
 
 #[derive(Clone)]
 pub struct WebsocketServerDeviceCommunicationManagerBuilder {
   listen_on_all_interfaces: bool,
   server_port: u16,
+  outbound_devices: Vec<WebsocketSpecifier>,
 }
 
 impl Default for WebsocketServerDeviceCommunicationManagerBuilder {
@@ -43,6 +86,7 @@ impl Default for WebsocketServerDeviceCommunicationManagerBuilder {
     Self {
       listen_on_all_interfaces: false,
       server_port: 54817,
+      outbound_devices: vec![],
     }
   }
 }
@@ -57,6 +101,113 @@ impl WebsocketServerDeviceCommunicationManagerBuilder {
     self.server_port = port;
     self
   }
+
+  /// Connect out to the devices at the urls of these specifiers (see
+  /// [DeviceConfigurationManager::outbound_websocket_specifiers](crate::server::device::configuration::DeviceConfigurationManager::outbound_websocket_specifiers)),
+  /// reconnecting whenever the connection drops. Specifiers without a url are ignored, those
+  /// devices connect to our listener instead.
+  pub fn outbound_devices(mut self, specifiers: &[WebsocketSpecifier]) -> Self {
+    self.outbound_devices.extend(
+      specifiers
+        .iter()
+        .filter(|specifier| specifier.url().is_some())
+        .cloned(),
+    );
+    self
+  }
+}
+
+async fn connect_outbound(
+  specifier: &WebsocketSpecifier,
+  url: &str,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, String> {
+  let mut request = url.into_client_request().map_err(|err| err.to_string())?;
+  if let Some(token) = specifier.token() {
+    let value = HeaderValue::from_str(&format!("Bearer {}", token))
+      .map_err(|_| "Token is not a valid header value".to_owned())?;
+    request.headers_mut().insert(AUTHORIZATION, value);
+  }
+  let connector = if *specifier.bypass_cert_verify() {
+    Some(Connector::Rustls(Arc::new(get_rustls_config_dangerous())))
+  } else {
+    None
+  };
+  connect_async_tls_with_config(request, None, false, connector)
+    .await
+    .map(|(stream, _)| stream)
+    .map_err(|err| err.to_string())
+}
+
+/// Keep a connection up to an outbound device, handing a new connector to the device manager every
+/// time we connect.
+async fn run_outbound_device(
+  specifier: WebsocketSpecifier,
+  sender: Sender<HardwareCommunicationManagerEvent>,
+  token: CancellationToken,
+) {
+  let url = specifier
+    .url()
+    .clone()
+    .expect("Only outbound specifiers get here");
+  let mut backoff = OUTBOUND_INITIAL_BACKOFF;
+  loop {
+    debug!(
+      "Connecting to websocket device {} at {}",
+      specifier.name(),
+      url
+    );
+    let result = select! {
+      result = connect_outbound(&specifier, &url).fuse() => result,
+      _ = token.cancelled().fuse() => return,
+    };
+    match result {
+      Ok(ws_stream) => {
+        info!(
+          "Connected to websocket device {} at {}",
+          specifier.name(),
+          url
+        );
+        let connected_at = Instant::now();
+        let (connector, connection) =
+          WebsocketServerHardwareConnector::new_outbound(specifier.name(), &url, ws_stream);
+        if sender
+          .send(HardwareCommunicationManagerEvent::DeviceFound {
+            name: format!("Websocket Device {}", connector.name()),
+            address: connector.address().to_owned(),
+            creator: Box::new(connector),
+          })
+          .await
+          .is_err()
+        {
+          error!("Device manager disappeared, exiting.");
+          return;
+        }
+        select! {
+          _ = connection.fuse() => {},
+          _ = token.cancelled().fuse() => return,
+        };
+        info!(
+          "Connection to websocket device {} at {} closed.",
+          specifier.name(),
+          url
+        );
+        if connected_at.elapsed() >= OUTBOUND_STABLE_CONNECTION {
+          backoff = OUTBOUND_INITIAL_BACKOFF;
+        }
+      }
+      Err(err) => warn!(
+        "Cannot connect to websocket device {} at {}: {}",
+        specifier.name(),
+        url,
+        err
+      ),
+    }
+    select! {
+      _ = util::sleep(backoff).fuse() => {},
+      _ = token.cancelled().fuse() => return,
+    };
+    backoff = (backoff * 2).min(OUTBOUND_MAXIMUM_BACKOFF);
+  }
 }
 
 impl HardwareCommunicationManagerBuilder for WebsocketServerDeviceCommunicationManagerBuilder {
@@ -68,6 +219,7 @@ impl HardwareCommunicationManagerBuilder for WebsocketServerDeviceCommunicationM
       sender,
       self.server_port,
       self.listen_on_all_interfaces,
+      &self.outbound_devices,
     ))
   }
 }
@@ -81,9 +233,17 @@ impl WebsocketServerDeviceCommunicationManager {
     sender: Sender<HardwareCommunicationManagerEvent>,
     port: u16,
     listen_on_all_interfaces: bool,
+    outbound_devices: &[WebsocketSpecifier],
   ) -> Self {
     trace!("Websocket server port created.");
     let server_cancellation_token = CancellationToken::new();
+    for specifier in outbound_devices {
+      async_manager::spawn(run_outbound_device(
+        specifier.clone(),
+        sender.clone(),
+        server_cancellation_token.child_token(),
+      ));
+    }
     let child_token = server_cancellation_token.child_token();
     async_manager::spawn(async move {
       let base_addr = if listen_on_all_interfaces {
@@ -143,19 +303,30 @@ impl WebsocketServerDeviceCommunicationManager {
                     }
                     return;
                   };
-                if sender_clone
-                  .send(HardwareCommunicationManagerEvent::DeviceFound {
-                    name: format!("Websocket Device {}", info_packet.identifier),
-                    address: info_packet.address.clone(),
-                    creator: Box::new(WebsocketServerHardwareConnector::new(
-                      info_packet,
-                      ws_stream,
-                    )),
-                  })
-                  .await
-                  .is_err()
-                {
-                  error!("Device manager disappeared, exiting.");
+                let connectors = if info_packet.devices.is_empty() {
+                  vec![WebsocketServerHardwareConnector::new(info_packet, ws_stream)]
+                } else if info_packet.devices.len() > u8::MAX as usize + 1 {
+                  error!("Too many devices declared in info packet for one byte channel tags, disconnecting.");
+                  if let Err(err) = ws_stream.close(None).await {
+                    error!("Error closing connection: {}", err);
+                  }
+                  return;
+                } else {
+                  WebsocketServerHardwareConnector::new_multiplexed(info_packet, ws_stream)
+                };
+                for connector in connectors {
+                  if sender_clone
+                    .send(HardwareCommunicationManagerEvent::DeviceFound {
+                      name: format!("Websocket Device {}", connector.name()),
+                      address: connector.address().to_owned(),
+                      creator: Box::new(connector),
+                    })
+                    .await
+                    .is_err()
+                  {
+                    error!("Device manager disappeared, exiting.");
+                    return;
+                  }
                 }
               } else {
                 error!("Did not receive info message as first packet, dropping connection.");
@@ -11,7 +11,6 @@ use crate::{
   server::device::{
     configuration::{ProtocolCommunicationSpecifier, WebsocketSpecifier},
     hardware::{
-      GenericHardwareSpecializer,
       Hardware,
       HardwareConnector,
       HardwareEvent,
@@ -42,38 +41,159 @@ use std::{
   time::Duration,
 };
 use tokio::{
+  io::{AsyncRead, AsyncWrite},
   net::TcpStream,
   sync::{
     broadcast,
     mpsc::{channel, Receiver, Sender},
     Mutex,
+    Notify,
   },
-  time::sleep,
+  task::JoinHandle,
+  time::{sleep_until, Instant},
 };
+use tokio_tungstenite::MaybeTlsStream;
 use tokio_util::sync::CancellationToken;
 
-async fn run_connection_loop(
-  address: &str,
-  event_sender: broadcast::Sender<HardwareEvent>,
-  ws_stream: tokio_tungstenite::WebSocketStream<TcpStream>,
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Ping settings of a websocket connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct KeepaliveSettings {
+  /// How long the connection can go without incoming traffic before we send a ping.
+  interval: Duration,
+  /// How long to wait for any reply to a ping before closing the connection.
+  timeout: Duration,
+}
+
+impl Default for KeepaliveSettings {
+  fn default() -> Self {
+    Self {
+      interval: DEFAULT_PING_INTERVAL,
+      timeout: DEFAULT_PING_TIMEOUT,
+    }
+  }
+}
+
+impl KeepaliveSettings {
+  fn from_specifier(specifier: &WebsocketSpecifier) -> Self {
+    Self {
+      interval: specifier
+        .ping_interval_ms()
+        .map_or(DEFAULT_PING_INTERVAL, Duration::from_millis),
+      timeout: specifier
+        .ping_timeout_ms()
+        .map_or(DEFAULT_PING_TIMEOUT, Duration::from_millis),
+    }
+  }
+}
+
+/// Ping settings shared between a connection loop and the devices on it.
+///
+/// Connections are made before we know which config specifier their devices match, so the loop
+/// starts out with the defaults, and devices apply the settings of their specifier once they're
+/// matched. If devices sharing a connection have different settings, the shortest ones win.
+#[derive(Default)]
+struct ConnectionKeepalive {
+  settings: std::sync::Mutex<Option<KeepaliveSettings>>,
+  changed: Notify,
+}
+
+impl ConnectionKeepalive {
+  fn settings(&self) -> KeepaliveSettings {
+    self
+      .settings
+      .lock()
+      .expect("Lock poisoned")
+      .unwrap_or_default()
+  }
+
+  fn apply(&self, new_settings: KeepaliveSettings) {
+    {
+      let mut settings = self.settings.lock().expect("Lock poisoned");
+      *settings = Some(settings.map_or(new_settings, |current| KeepaliveSettings {
+        interval: current.interval.min(new_settings.interval),
+        timeout: current.timeout.min(new_settings.timeout),
+      }));
+    }
+    self.changed.notify_one();
+  }
+}
+
+/// Per device state for a websocket connection. Single device connections have one channel, while
+/// multiplexed connections have one per declared device, tagged by its index in the handshake.
+#[derive(Clone)]
+struct WebsocketServerChannel {
+  address: String,
+  incoming_broadcaster: broadcast::Sender<Vec<u8>>,
+  device_event_sender: broadcast::Sender<HardwareEvent>,
+}
+
+impl WebsocketServerChannel {
+  fn new(address: &str) -> Self {
+    Self {
+      address: address.to_owned(),
+      incoming_broadcaster: broadcast::channel(256).0,
+      device_event_sender: broadcast::channel(256).0,
+    }
+  }
+}
+
+fn route_incoming(channels: &[WebsocketServerChannel], multiplexed: bool, data: Vec<u8>) {
+  if !multiplexed {
+    // If no one is listening, ignore output.
+    let _ = channels[0].incoming_broadcaster.send(data);
+    return;
+  }
+  match data.split_first() {
+    Some((tag, payload)) if (*tag as usize) < channels.len() => {
+      let _ = channels[*tag as usize]
+        .incoming_broadcaster
+        .send(payload.to_vec());
+    }
+    _ => warn!(
+      "Got websocket frame for unknown device channel, ignoring: {:?}",
+      data
+    ),
+  }
+}
+
+async fn run_connection_loop<S>(
+  channels: Vec<WebsocketServerChannel>,
+  multiplexed: bool,
+  ws_stream: tokio_tungstenite::WebSocketStream<S>,
   mut request_receiver: Receiver<Vec<u8>>,
-  response_sender: broadcast::Sender<Vec<u8>>,
-) {
+  keepalive: Arc<ConnectionKeepalive>,
+) where
+  S: AsyncRead + AsyncWrite + Unpin,
+{
   info!("Starting websocket server connection event loop.");
 
   let (mut websocket_server_sender, mut websocket_server_receiver) = ws_stream.split();
 
-  // Start pong count at 1, so we'll clear it after sending our first ping.
-  let mut pong_count = 1u32;
+  // Any frame from the device shows the connection is alive, so we only ping once it has been
+  // quiet for the ping interval. Our own writes don't count, as they go out whether or not the
+  // device is still there.
+  let mut last_received = Instant::now();
+  let mut ping_sent: Option<Instant> = None;
 
   loop {
+    let settings = keepalive.settings();
+    let deadline = match ping_sent {
+      Some(sent) => sent + settings.timeout,
+      None => last_received + settings.interval,
+    };
     select! {
-      _ = sleep(Duration::from_millis(10000)).fuse() => {
-        if pong_count == 0 {
-          error!("No pongs received, considering connection closed.");
+      _ = sleep_until(deadline).fuse() => {
+        if ping_sent.is_some() {
+          error!(
+            "No reply to ping within {}ms, considering connection closed.",
+            settings.timeout.as_millis()
+          );
           break;
         }
-        pong_count = 0;
+        ping_sent = Some(Instant::now());
         if websocket_server_sender
           .send(tokio_tungstenite::tungstenite::Message::Ping(vec!(0)))
           .await
@@ -82,6 +202,10 @@ async fn run_connection_loop(
           break;
         }
       }
+      _ = keepalive.changed.notified().fuse() => {
+        // Work the deadline out again with the new settings.
+        continue;
+      }
       ws_msg = request_receiver.recv().fuse() => {
         if let Some(binary_msg) = ws_msg {
           if websocket_server_sender
@@ -100,21 +224,17 @@ async fn run_connection_loop(
         Some(ws_data) => {
           match ws_data {
             Ok(msg) => {
+              last_received = Instant::now();
+              ping_sent = None;
               match msg {
                 tokio_tungstenite::tungstenite::Message::Text(text_msg) => {
                   // If someone accidentally packs text, politely turn it into binary for them.
-                  let _ = response_sender.send(text_msg.as_bytes().to_vec());
+                  route_incoming(&channels, multiplexed, text_msg.as_bytes().to_vec());
                 }
                 tokio_tungstenite::tungstenite::Message::Binary(binary_msg) => {
-                  // If no one is listening, ignore output.
-                  let _ = response_sender.send(binary_msg);
+                  route_incoming(&channels, multiplexed, binary_msg);
                 }
                 tokio_tungstenite::tungstenite::Message::Close(_) => {
-                  // Drop the error if no one receives the message, we're breaking anyways.
-                  let _ = event_sender
-                    .send(HardwareEvent::Disconnected(
-                      address.to_owned()
-                    ));
                   break;
                 }
                 tokio_tungstenite::tungstenite::Message::Ping(_) => {
@@ -126,7 +246,7 @@ async fn run_connection_loop(
                   continue;
                 }
                 tokio_tungstenite::tungstenite::Message::Pong(_) => {
-                  pong_count += 1;
+                  // noop
                   continue;
                 }
               }
@@ -145,6 +265,13 @@ async fn run_connection_loop(
     }
   }
 
+  // However the connection ended, the devices on it are gone. Drop the error if no one receives
+  // the message, we're exiting anyways.
+  for channel in &channels {
+    let _ = channel
+      .device_event_sender
+      .send(HardwareEvent::Disconnected(channel.address.clone()));
+  }
   if let Err(e) = websocket_server_sender.close().await {
     error!("Error closing websocket: {}", e);
   }
@@ -154,16 +281,20 @@ async fn run_connection_loop(
 impl Debug for WebsocketServerHardwareConnector {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     f.debug_struct("WebsocketServerHardwareConnector")
-      .field("info", &self.info)
+      .field("name", &self.name)
+      .field("channel", &self.channel.address)
+      .field("tag", &self.tag)
       .finish()
   }
 }
 
 pub struct WebsocketServerHardwareConnector {
-  info: WebsocketServerDeviceCommManagerInitInfo,
+  name: String,
+  /// Channel tag byte prepended to every frame, if the connection is multiplexed.
+  tag: Option<u8>,
+  channel: WebsocketServerChannel,
   outgoing_sender: Sender<Vec<u8>>,
-  incoming_broadcaster: broadcast::Sender<Vec<u8>>,
-  device_event_sender: broadcast::Sender<HardwareEvent>,
+  keepalive: Arc<ConnectionKeepalive>,
 }
 
 impl WebsocketServerHardwareConnector {
@@ -171,51 +302,148 @@ impl WebsocketServerHardwareConnector {
     info: WebsocketServerDeviceCommManagerInitInfo,
     ws_stream: tokio_tungstenite::WebSocketStream<TcpStream>,
   ) -> Self {
+    Self::new_single(info.identifier(), info.address(), ws_stream).0
+  }
+
+  /// Create a connector for a device we connected out to. The returned handle finishes once the
+  /// connection closes.
+  pub fn new_outbound(
+    name: &str,
+    address: &str,
+    ws_stream: tokio_tungstenite::WebSocketStream<MaybeTlsStream<TcpStream>>,
+  ) -> (Self, JoinHandle<()>) {
+    Self::new_single(name, address, ws_stream)
+  }
+
+  fn new_single<S>(
+    name: &str,
+    address: &str,
+    ws_stream: tokio_tungstenite::WebSocketStream<S>,
+  ) -> (Self, JoinHandle<()>)
+  where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+  {
     let (outgoing_sender, outgoing_receiver) = channel(256);
-    let (incoming_broadcaster, _) = broadcast::channel(256);
-    let incoming_broadcaster_clone = incoming_broadcaster.clone();
-    let (device_event_sender, _) = broadcast::channel(256);
-    let device_event_sender_clone = device_event_sender.clone();
-    let address = info.address().clone();
-    tokio::spawn(async move {
+    let channel = WebsocketServerChannel::new(address);
+    let channels = vec![channel.clone()];
+    let keepalive = Arc::new(ConnectionKeepalive::default());
+    let loop_keepalive = keepalive.clone();
+    let connection = tokio::spawn(async move {
       run_connection_loop(
-        &address,
-        device_event_sender_clone,
+        channels,
+        false,
         ws_stream,
         outgoing_receiver,
-        incoming_broadcaster_clone,
+        loop_keepalive,
       )
       .await;
     });
-    Self {
-      info,
+    let connector = Self {
+      name: name.to_owned(),
+      tag: None,
+      channel,
       outgoing_sender,
-      incoming_broadcaster,
-      device_event_sender,
-    }
+      keepalive,
+    };
+    (connector, connection)
+  }
+
+  /// Create one connector per device declared in the handshake, all sharing the websocket
+  /// connection. Frames in both directions are prefixed with the index of the device in the
+  /// handshake device list.
+  pub fn new_multiplexed(
+    info: WebsocketServerDeviceCommManagerInitInfo,
+    ws_stream: tokio_tungstenite::WebSocketStream<TcpStream>,
+  ) -> Vec<Self> {
+    let (outgoing_sender, outgoing_receiver) = channel(256);
+    let keepalive = Arc::new(ConnectionKeepalive::default());
+    let connectors: Vec<Self> = info
+      .devices()
+      .iter()
+      .enumerate()
+      .map(|(index, device)| {
+        let channel_id = device
+          .identifier()
+          .clone()
+          .unwrap_or_else(|| index.to_string());
+        Self {
+          name: device.name().clone(),
+          tag: Some(index as u8),
+          channel: WebsocketServerChannel::new(&format!("{}-{}", info.address(), channel_id)),
+          outgoing_sender: outgoing_sender.clone(),
+          keepalive: keepalive.clone(),
+        }
+      })
+      .collect();
+    let channels = connectors
+      .iter()
+      .map(|connector| connector.channel.clone())
+      .collect();
+    tokio::spawn(async move {
+      run_connection_loop(channels, true, ws_stream, outgoing_receiver, keepalive).await;
+    });
+    connectors
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn address(&self) -> &str {
+    &self.channel.address
   }
 }
 
 #[async_trait]
 impl HardwareConnector for WebsocketServerHardwareConnector {
   fn specifier(&self) -> ProtocolCommunicationSpecifier {
-    ProtocolCommunicationSpecifier::Websocket(WebsocketSpecifier::new(self.info.identifier()))
+    let specifier = if self.tag.is_some() {
+      WebsocketSpecifier::new_shared_connection(&self.name)
+    } else {
+      WebsocketSpecifier::new(&self.name)
+    };
+    ProtocolCommunicationSpecifier::Websocket(specifier)
   }
 
   async fn connect(&mut self) -> Result<Box<dyn HardwareSpecializer>, ButtplugDeviceError> {
-    let hardware_internal = WebsocketServerHardware::new(
-      self.device_event_sender.clone(),
-      self.info.clone(),
-      self.outgoing_sender.clone(),
-      self.incoming_broadcaster.clone(),
-    );
+    let hardware_internal =
+      WebsocketServerHardware::new(self.channel.clone(), self.tag, self.outgoing_sender.clone());
     let hardware = Hardware::new(
-      self.info.identifier(),
-      self.info.address(),
+      &self.name,
+      &self.channel.address,
       &[Endpoint::Rx, Endpoint::Tx],
       Box::new(hardware_internal),
     );
-    Ok(Box::new(GenericHardwareSpecializer::new(hardware)))
+    Ok(Box::new(WebsocketServerHardwareSpecializer {
+      hardware: Some(hardware),
+      specifier: self.specifier(),
+      keepalive: self.keepalive.clone(),
+    }))
+  }
+}
+
+/// Applies the ping settings of the config specifier the device matched to its connection.
+pub struct WebsocketServerHardwareSpecializer {
+  hardware: Option<Hardware>,
+  specifier: ProtocolCommunicationSpecifier,
+  keepalive: Arc<ConnectionKeepalive>,
+}
+
+#[async_trait]
+impl HardwareSpecializer for WebsocketServerHardwareSpecializer {
+  async fn specialize(
+    &mut self,
+    specifiers: &[ProtocolCommunicationSpecifier],
+  ) -> Result<Hardware, ButtplugDeviceError> {
+    if let Some(ProtocolCommunicationSpecifier::Websocket(config_specifier)) = specifiers
+      .iter()
+      .find(|specifier| **specifier == self.specifier)
+    {
+      self
+        .keepalive
+        .apply(KeepaliveSettings::from_specifier(config_specifier));
+    }
+    Ok(self.hardware.take().expect("This should only be run once"))
   }
 }
 
@@ -223,25 +451,22 @@ pub struct WebsocketServerHardware {
   connected: Arc<AtomicBool>,
   subscribed: Arc<AtomicBool>,
   subscribe_token: Arc<Mutex<Option<CancellationToken>>>,
-  info: WebsocketServerDeviceCommManagerInitInfo,
+  channel: WebsocketServerChannel,
+  tag: Option<u8>,
   outgoing_sender: Sender<Vec<u8>>,
-  incoming_broadcaster: broadcast::Sender<Vec<u8>>,
-  device_event_sender: broadcast::Sender<HardwareEvent>,
 }
 
 impl WebsocketServerHardware {
-  pub fn new(
-    device_event_sender: broadcast::Sender<HardwareEvent>,
-    info: WebsocketServerDeviceCommManagerInitInfo,
+  fn new(
+    channel: WebsocketServerChannel,
+    tag: Option<u8>,
     outgoing_sender: Sender<Vec<u8>>,
-    incoming_broadcaster: broadcast::Sender<Vec<u8>>,
   ) -> Self {
     Self {
       connected: Arc::new(AtomicBool::new(true)),
-      info,
+      channel,
+      tag,
       outgoing_sender,
-      incoming_broadcaster,
-      device_event_sender,
       subscribed: Arc::new(AtomicBool::new(false)),
       subscribe_token: Arc::new(Mutex::new(None)),
     }
@@ -250,7 +475,7 @@ impl WebsocketServerHardware {
 
 impl HardwareInternal for WebsocketServerHardware {
   fn event_stream(&self) -> broadcast::Receiver<HardwareEvent> {
-    self.device_event_sender.subscribe()
+    self.channel.device_event_sender.subscribe()
   }
 
   fn disconnect(&self) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
@@ -277,7 +502,10 @@ impl HardwareInternal for WebsocketServerHardware {
     msg: &HardwareWriteCmd,
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
     let sender = self.outgoing_sender.clone();
-    let data = msg.data.clone();
+    let mut data = msg.data.clone();
+    if let Some(tag) = self.tag {
+      data.insert(0, tag);
+    }
     // TODO Should check endpoint validity
     async move {
       sender.send(data).await.map_err(|err| {
@@ -299,9 +527,9 @@ impl HardwareInternal for WebsocketServerHardware {
       return future::ready(Ok(())).boxed();
     }
     // TODO Should check endpoint validity
-    let mut data_receiver = self.incoming_broadcaster.subscribe();
-    let event_sender = self.device_event_sender.clone();
-    let address = self.info.address().clone();
+    let mut data_receiver = self.channel.incoming_broadcaster.subscribe();
+    let event_sender = self.channel.device_event_sender.clone();
+    let address = self.channel.address.clone();
     let subscribed = self.subscribed.clone();
     let subscribed_token = self.subscribe_token.clone();
     async move {
@@ -8,21 +8,31 @@
 use super::xinput_hardware::XInputHardwareConnector;
 use crate::{
   core::errors::ButtplugDeviceError,
-  server::device::hardware::communication::{
-    HardwareCommunicationManager,
-    HardwareCommunicationManagerBuilder,
-    HardwareCommunicationManagerEvent,
-    TimedRetryCommunicationManager,
-    TimedRetryCommunicationManagerImpl,
+  server::device::{
+    configuration::{DeviceAddressFilter, XInputSpecifier},
+    hardware::communication::{
+      HardwareCommunicationManager,
+      HardwareCommunicationManagerBuilder,
+      HardwareCommunicationManagerEvent,
+      TimedRetryCommunicationManager,
+      TimedRetryCommunicationManagerImpl,
+    },
   },
 };
 use async_trait::async_trait;
 use rusty_xinput::XInputHandle;
-use std::string::ToString;
+use std::{
+  string::ToString,
+  sync::{Arc, RwLock},
+  time::Duration,
+};
 use tokio::sync::mpsc;
 
+// Matches the TimedRetryCommunicationManagerImpl default.
+const DEFAULT_RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+
 // 1-index this because we use it elsewhere for showing which controller is which.
-#[derive(Debug, Display, Clone, Copy)]
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum XInputControllerIndex {
   XInputController1 = 0,
@@ -31,16 +41,70 @@ pub enum XInputControllerIndex {
   XInputController4 = 3,
 }
 
+const CONTROLLER_INDEXES: [XInputControllerIndex; 4] = [
+  XInputControllerIndex::XInputController1,
+  XInputControllerIndex::XInputController2,
+  XInputControllerIndex::XInputController3,
+  XInputControllerIndex::XInputController4,
+];
+
+/// The parts of the XInput API used while scanning, so scan logic can be tested without
+/// controllers attached.
+trait XInputLayer {
+  fn is_connected(&self, index: XInputControllerIndex) -> bool;
+}
+
+impl XInputLayer for XInputHandle {
+  fn is_connected(&self, index: XInputControllerIndex) -> bool {
+    self.get_state(index as u32).is_ok()
+  }
+}
+
+/// Get the controllers that are connected, and in slots the specifier exposes.
+fn exposed_controllers(
+  layer: &impl XInputLayer,
+  specifier: &XInputSpecifier,
+) -> Vec<XInputControllerIndex> {
+  CONTROLLER_INDEXES
+    .iter()
+    .copied()
+    .filter(|index| specifier.exposes_slot(*index as u8) && layer.is_connected(*index))
+    .collect()
+}
+
 #[derive(Default, Clone)]
-pub struct XInputDeviceCommunicationManagerBuilder {}
+pub struct XInputDeviceCommunicationManagerBuilder {
+  specifier: Arc<RwLock<XInputSpecifier>>,
+  address_filter: Option<DeviceAddressFilter>,
+}
+
+impl XInputDeviceCommunicationManagerBuilder {
+  /// Set which controller slots are exposed as devices, and how often to rescan for controllers.
+  ///
+  /// Clones of this builder share this setting with any manager built from them, so it can be
+  /// updated while scanning (for instance, after reloading the device config) without restarting
+  /// the server.
+  pub fn specifier(&mut self, specifier: &XInputSpecifier) -> &mut Self {
+    *self.specifier.write().expect("Lock poisoned") = specifier.clone();
+    self
+  }
+}
 
 impl HardwareCommunicationManagerBuilder for XInputDeviceCommunicationManagerBuilder {
+  fn address_filter(&mut self, filter: DeviceAddressFilter) {
+    self.address_filter = Some(filter);
+  }
+
   fn finish(
     &mut self,
     sender: mpsc::Sender<HardwareCommunicationManagerEvent>,
   ) -> Box<dyn HardwareCommunicationManager> {
     Box::new(TimedRetryCommunicationManager::new(
-      XInputDeviceCommunicationManager::new(sender),
+      XInputDeviceCommunicationManager::new(
+        sender,
+        self.specifier.clone(),
+        self.address_filter.clone(),
+      ),
     ))
   }
 }
@@ -48,14 +112,22 @@ impl HardwareCommunicationManagerBuilder for XInputDeviceCommunicationManagerBui
 pub struct XInputDeviceCommunicationManager {
   sender: mpsc::Sender<HardwareCommunicationManagerEvent>,
   handle: XInputHandle,
+  specifier: Arc<RwLock<XInputSpecifier>>,
+  address_filter: Option<DeviceAddressFilter>,
 }
 
 impl XInputDeviceCommunicationManager {
-  fn new(sender: mpsc::Sender<HardwareCommunicationManagerEvent>) -> Self {
+  fn new(
+    sender: mpsc::Sender<HardwareCommunicationManagerEvent>,
+    specifier: Arc<RwLock<XInputSpecifier>>,
+    address_filter: Option<DeviceAddressFilter>,
+  ) -> Self {
     Self {
       sender,
       handle: rusty_xinput::XInputHandle::load_default()
         .expect("Always loads in windows, this shouldn't run elsewhere."),
+      specifier,
+      address_filter,
     }
   }
 }
@@ -66,38 +138,48 @@ impl TimedRetryCommunicationManagerImpl for XInputDeviceCommunicationManager {
     "XInputDeviceCommunicationManager"
   }
 
+  fn rescan_wait_duration(&self) -> Duration {
+    self
+      .specifier
+      .read()
+      .expect("Lock poisoned")
+      .rescan_interval_ms()
+      .map_or(DEFAULT_RESCAN_INTERVAL, Duration::from_millis)
+  }
+
   async fn scan(&self) -> Result<(), ButtplugDeviceError> {
     trace!("XInput manager scanning for devices");
-    for i in &[
-      XInputControllerIndex::XInputController1,
-      XInputControllerIndex::XInputController2,
-      XInputControllerIndex::XInputController3,
-      XInputControllerIndex::XInputController4,
-    ] {
-      match self.handle.get_state(*i as u32) {
-        Ok(_) => {
-          let index = *i as u32;
-          debug!("XInput manager found device {}", index);
-          let device_creator = Box::new(XInputHardwareConnector::new(*i));
-
-          if self
-            .sender
-            .send(HardwareCommunicationManagerEvent::DeviceFound {
-              name: i.to_string(),
-              address: i.to_string(),
-              creator: device_creator,
-            })
-            .await
-            .is_err()
-          {
-            error!("Error sending device found message from Xinput.");
-            break;
-          }
-        }
-        Err(_) => {
+    let controllers = {
+      let specifier = self.specifier.read().expect("Lock poisoned");
+      exposed_controllers(&self.handle, &specifier)
+    };
+    for i in &controllers {
+      let index = *i as u32;
+      debug!("XInput manager found device {}", index);
+      if let Some(filter) = &self.address_filter {
+        if !filter.allows(&i.to_string()) {
+          debug!(
+            "XInput controller {} not allowed by user configuration, not reporting.",
+            i
+          );
           continue;
         }
       }
+      let device_creator = Box::new(XInputHardwareConnector::new(*i));
+
+      if self
+        .sender
+        .send(HardwareCommunicationManagerEvent::DeviceFound {
+          name: i.to_string(),
+          address: i.to_string(),
+          creator: device_creator,
+        })
+        .await
+        .is_err()
+      {
+        error!("Error sending device found message from Xinput.");
+        break;
+      }
     }
     Ok(())
   }
@@ -107,3 +189,41 @@ impl TimedRetryCommunicationManagerImpl for XInputDeviceCommunicationManager {
     true
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  struct MockXInputLayer {
+    connected: Vec<XInputControllerIndex>,
+  }
+
+  impl XInputLayer for MockXInputLayer {
+    fn is_connected(&self, index: XInputControllerIndex) -> bool {
+      self.connected.contains(&index)
+    }
+  }
+
+  #[test]
+  fn test_exposed_controllers_slot_filter() {
+    // A wheel in slot 0 and a rumble pad in slot 1.
+    let layer = MockXInputLayer {
+      connected: vec![
+        XInputControllerIndex::XInputController1,
+        XInputControllerIndex::XInputController2,
+      ],
+    };
+    assert_eq!(
+      exposed_controllers(&layer, &XInputSpecifier::default()),
+      vec![
+        XInputControllerIndex::XInputController1,
+        XInputControllerIndex::XInputController2
+      ]
+    );
+    assert_eq!(
+      exposed_controllers(&layer, &XInputSpecifier::new(&Some(vec![1, 3]), None)),
+      vec![XInputControllerIndex::XInputController2]
+    );
+    assert!(exposed_controllers(&layer, &XInputSpecifier::new(&Some(vec![]), None)).is_empty());
+  }
+}
@@ -1,21 +1,30 @@
 pub mod communication;
 
-use std::{fmt::Debug, sync::Arc, time::Duration};
+use std::{
+  collections::{HashMap, VecDeque},
+  fmt::Debug,
+  sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+    Mutex,
+  },
+  time::Duration,
+};
 
 use crate::{
   core::{
     errors::ButtplugDeviceError,
     message::{Endpoint, RawReadCmd, RawReading, RawSubscribeCmd, RawUnsubscribeCmd, RawWriteCmd},
   },
-  server::device::configuration::ProtocolCommunicationSpecifier,
+  server::device::{configuration::ProtocolCommunicationSpecifier, CommandQueueStats},
 };
 use async_trait::async_trait;
-use futures::future::BoxFuture;
+use futures::future::{self, BoxFuture};
 use futures_util::FutureExt;
 use getset::{CopyGetters, Getters};
-use instant::Instant;
+use instant::{Instant, SystemTime};
 use serde::{Deserialize, Serialize};
-use tokio::sync::{broadcast, RwLock};
+use tokio::sync::{broadcast, Mutex as AsyncMutex, RwLock};
 
 /// Parameters for reading data from a [Hardware](crate::device::Hardware) endpoint
 ///
@@ -70,6 +79,11 @@ pub struct HardwareWriteCmd {
   /// Only used with Bluetooth LE writing. If true, use WriteWithResponse commands when sending data to device.
   #[getset(get_copy = "pub")]
   write_with_response: bool,
+  /// Only used with HID writing. Report ID to send the data with, overriding the report ID
+  /// configured for the endpoint.
+  #[getset(get_copy = "pub")]
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  report_id: Option<u8>,
 }
 
 impl HardwareWriteCmd {
@@ -79,6 +93,27 @@ impl HardwareWriteCmd {
       endpoint,
       data,
       write_with_response,
+      report_id: None,
+    }
+  }
+
+  /// Send the data with a specific HID report ID.
+  pub fn with_report_id(mut self, report_id: u8) -> Self {
+    self.report_id = Some(report_id);
+    self
+  }
+
+  /// The buffer to hand to hid_write for this command, which takes the report ID as its first byte.
+  /// The report ID comes from the command, or the report IDs configured for the device's endpoints.
+  /// Without either, the data is passed through as is, for protocols that put the report ID in
+  /// their data themselves.
+  pub fn hid_output_report(&self, report_ids: &HashMap<Endpoint, u8>) -> Vec<u8> {
+    match self
+      .report_id
+      .or_else(|| report_ids.get(&self.endpoint).copied())
+    {
+      Some(report_id) => [&[report_id], self.data.as_slice()].concat(),
+      None => self.data.clone(),
     }
   }
 }
@@ -89,6 +124,7 @@ impl From<RawWriteCmd> for HardwareWriteCmd {
       endpoint: msg.endpoint(),
       data: msg.data().clone(),
       write_with_response: msg.write_with_response(),
+      report_id: None,
     }
   }
 }
@@ -159,6 +195,9 @@ pub enum HardwareCommand {
   // Read not included here because it needs to be called directly so the response can be handled.
   Subscribe(HardwareSubscribeCmd),
   Unsubscribe(HardwareUnsubscribeCmd),
+  /// Writes that need to reach the device back to back, with no other write in between. See
+  /// [Hardware::write_batch].
+  Batch(Vec<HardwareWriteCmd>),
 }
 
 impl From<RawWriteCmd> for HardwareCommand {
@@ -228,6 +267,162 @@ pub enum HardwareEvent {
   Disconnected(String),
 }
 
+/// What a [HardwarePacketTrace] recorded, relative to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HardwarePacketDirection {
+  /// Data written to the device.
+  Write,
+  /// Data read from the device.
+  Read,
+  /// Subscription to an endpoint. Carries no data.
+  Subscribe,
+  /// Unsubscription from an endpoint. Carries no data.
+  Unsubscribe,
+  /// Data the device sent on a subscribed endpoint.
+  Notification,
+}
+
+/// A packet sent to or received from a [Hardware] while packet tracing is on.
+#[derive(Debug, Clone, PartialEq, Eq, Getters, CopyGetters)]
+pub struct HardwarePacketTrace {
+  #[getset(get_copy = "pub")]
+  timestamp: SystemTime,
+  #[getset(get_copy = "pub")]
+  direction: HardwarePacketDirection,
+  #[getset(get_copy = "pub")]
+  endpoint: Endpoint,
+  #[getset(get = "pub")]
+  bytes: Vec<u8>,
+}
+
+impl HardwarePacketTrace {
+  pub fn new(direction: HardwarePacketDirection, endpoint: Endpoint, bytes: &[u8]) -> Self {
+    Self {
+      timestamp: SystemTime::now(),
+      direction,
+      endpoint,
+      bytes: bytes.to_vec(),
+    }
+  }
+}
+
+/// How many [HardwareErrorRecord]s a [Hardware] keeps. Once full, the oldest record is dropped.
+pub const HARDWARE_ERROR_HISTORY_LENGTH: usize = 32;
+
+/// Hardware operation a [HardwareErrorRecord] was recorded for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HardwareOperation {
+  Write,
+  Read,
+  Subscribe,
+  Unsubscribe,
+  Disconnect,
+}
+
+/// A failed operation on a [Hardware].
+#[derive(Debug, Clone, PartialEq, Eq, Getters, CopyGetters)]
+pub struct HardwareErrorRecord {
+  #[getset(get_copy = "pub")]
+  timestamp: SystemTime,
+  #[getset(get_copy = "pub")]
+  operation: HardwareOperation,
+  /// Endpoint the operation was for. None for operations on the whole device, like disconnects.
+  #[getset(get_copy = "pub")]
+  endpoint: Option<Endpoint>,
+  #[getset(get = "pub")]
+  error: ButtplugDeviceError,
+}
+
+/// How much each new latency sample moves the rolling latency averages of [DeviceIoStats], as a
+/// fraction (1/n) of the difference to the current average.
+const LATENCY_AVERAGE_WEIGHT: u64 = 8;
+
+/// Operation counters for a [Hardware], from [Hardware::io_stats].
+///
+/// Counters add up from when the hardware was created, and reading them doesn't reset anything.
+/// Rates can be worked out from the difference between two snapshots, or from
+/// [uptime_ms](Self::uptime_ms). Writes held back by dry run mode never reach the device, so they
+/// aren't counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, CopyGetters)]
+#[getset(get_copy = "pub")]
+pub struct DeviceIoStats {
+  /// Time since the hardware was created, in milliseconds.
+  uptime_ms: u64,
+  writes: u64,
+  bytes_written: u64,
+  reads: u64,
+  bytes_read: u64,
+  /// Subscribes and unsubscribes.
+  subscription_changes: u64,
+  /// Failed operations of any kind, including disconnects.
+  errors: u64,
+  /// Rolling average time successful writes took to complete, in microseconds. Recent writes
+  /// weigh the most. None until a write has completed.
+  average_write_latency_us: Option<u64>,
+  /// Rolling average time successful reads took to complete, in microseconds.
+  average_read_latency_us: Option<u64>,
+  /// Command queue counters. Only set for stats from [ServerDevice::stats](super::ServerDevice::stats),
+  /// as the queue sits above the hardware.
+  command_queue: Option<CommandQueueStats>,
+}
+
+impl DeviceIoStats {
+  pub(crate) fn with_command_queue(mut self, command_queue: CommandQueueStats) -> Self {
+    self.command_queue = Some(command_queue);
+    self
+  }
+
+  /// Fraction of writes, reads and subscription changes that failed, from 0 to 1.
+  pub fn error_rate(&self) -> f64 {
+    let operations = self.writes + self.reads + self.subscription_changes;
+    if operations == 0 {
+      0.0
+    } else {
+      (self.errors as f64 / operations as f64).min(1.0)
+    }
+  }
+}
+
+#[derive(Default)]
+struct HardwareIoCounters {
+  writes: AtomicU64,
+  bytes_written: AtomicU64,
+  reads: AtomicU64,
+  bytes_read: AtomicU64,
+  subscription_changes: AtomicU64,
+  /// Rolling averages in nanoseconds, 0 until the first sample.
+  write_latency_ns: AtomicU64,
+  read_latency_ns: AtomicU64,
+}
+
+impl HardwareIoCounters {
+  fn add_latency_sample(average_ns: &AtomicU64, latency: Duration) {
+    let sample = (latency.as_nanos().min(u64::MAX as u128) as u64).max(1);
+    // The closure always returns Some, so this can't fail.
+    let _ = average_ns.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+      Some(if average == 0 {
+        sample
+      } else {
+        (average - average / LATENCY_AVERAGE_WEIGHT + sample / LATENCY_AVERAGE_WEIGHT).max(1)
+      })
+    });
+  }
+
+  fn average_latency_us(average_ns: &AtomicU64) -> Option<u64> {
+    match average_ns.load(Ordering::Relaxed) {
+      0 => None,
+      average => Some(average / 1000),
+    }
+  }
+}
+
+#[derive(Default)]
+struct HardwareErrorHistory {
+  records: VecDeque<HardwareErrorRecord>,
+  /// Errors recorded since the hardware was created, including ones dropped from the records.
+  count: u64,
+}
+
 /// Hardware implementation and communication portion of a
 /// [ButtplugDevice](crate::device::ButtplugDevice) instance. The Hardware contains a
 /// HardwareInternal, which handles all of the actual hardware communication. However, the struct
@@ -239,7 +434,7 @@ pub struct Hardware {
   name: String,
   /// Device address
   address: String,
-  /// Communication endpoints
+  /// Communication endpoints found on the device when it was set up
   endpoints: Vec<Endpoint>,
   /// Internal implementation details
   internal_impl: Box<dyn HardwareInternal>,
@@ -247,6 +442,19 @@ pub struct Hardware {
   #[getset(get_copy = "pub")]
   requires_keepalive: bool,
   last_write_time: Arc<RwLock<Instant>>,
+  /// Held for every write, and for the whole of a batch, so batches aren't split up.
+  write_lock: Arc<AsyncMutex<()>>,
+  /// If true, writes are reported on the dry run stream instead of being sent to the device.
+  dry_run: Arc<AtomicBool>,
+  dry_run_sender: broadcast::Sender<HardwareWriteCmd>,
+  /// If true, every write is sent with response, whatever the protocol asked for.
+  force_write_with_response: Arc<AtomicBool>,
+  /// If true, packets to and from the device are sent to the packet trace stream.
+  packet_trace: Arc<AtomicBool>,
+  packet_trace_sender: broadcast::Sender<HardwarePacketTrace>,
+  error_history: Arc<Mutex<HardwareErrorHistory>>,
+  io_counters: Arc<HardwareIoCounters>,
+  created: Instant,
 }
 
 impl Hardware {
@@ -263,6 +471,15 @@ impl Hardware {
       internal_impl,
       requires_keepalive: false,
       last_write_time: Arc::new(RwLock::new(Instant::now())),
+      write_lock: Arc::new(AsyncMutex::new(())),
+      dry_run: Arc::new(AtomicBool::new(false)),
+      dry_run_sender: broadcast::channel(256).0,
+      force_write_with_response: Arc::new(AtomicBool::new(false)),
+      packet_trace: Arc::new(AtomicBool::new(false)),
+      packet_trace_sender: broadcast::channel(256).0,
+      error_history: Arc::new(Mutex::new(HardwareErrorHistory::default())),
+      io_counters: Arc::new(HardwareIoCounters::default()),
+      created: Instant::now(),
     }
   }
 
@@ -284,11 +501,18 @@ impl Hardware {
     &self.address
   }
 
-  /// Returns the device endpoint list
+  /// Returns the endpoints found on the device. Optional endpoints in the device config that
+  /// weren't found are left out.
   pub fn endpoints(&self) -> Vec<Endpoint> {
     self.endpoints.clone()
   }
 
+  /// Whether the endpoint was found on the device, for protocols that use optional endpoints (e.g.
+  /// battery characteristics older firmware doesn't have).
+  pub fn has_endpoint(&self, endpoint: Endpoint) -> bool {
+    self.endpoints.contains(&endpoint)
+  }
+
   /// Returns a receiver for any events the device may emit.
   ///
   /// This uses a broadcast channel and can be called multiple times to create multiple streams if
@@ -297,9 +521,137 @@ impl Hardware {
     self.internal_impl.event_stream()
   }
 
+  /// Returns true if writes are currently being logged instead of sent to the device.
+  pub fn dry_run(&self) -> bool {
+    self.dry_run.load(Ordering::SeqCst)
+  }
+
+  /// Turn dry run mode on or off. While on, every write (including ones protocols send from their
+  /// own background tasks) is logged and sent to the [dry run stream](Self::dry_run_stream) instead
+  /// of the device. Reads and subscriptions are unaffected.
+  pub fn set_dry_run(&self, dry_run: bool) {
+    self.dry_run.store(dry_run, Ordering::SeqCst);
+  }
+
+  /// Returns a receiver for writes that were held back from the device by dry run mode.
+  pub fn dry_run_stream(&self) -> broadcast::Receiver<HardwareWriteCmd> {
+    self.dry_run_sender.subscribe()
+  }
+
+  /// Returns true if all writes are being sent with response.
+  pub fn force_write_with_response(&self) -> bool {
+    self.force_write_with_response.load(Ordering::SeqCst)
+  }
+
+  /// Send every write with response, for devices that need all writes acknowledged. Writes that
+  /// already asked for a response are unaffected.
+  pub fn set_force_write_with_response(&self, force: bool) {
+    self
+      .force_write_with_response
+      .store(force, Ordering::SeqCst);
+  }
+
+  /// Returns true if packets are being sent to the packet trace stream.
+  pub fn packet_trace(&self) -> bool {
+    self.packet_trace.load(Ordering::SeqCst)
+  }
+
+  /// Turn packet tracing on or off. While on, every write, read, subscription change and
+  /// notification is sent to the [packet trace stream](Self::packet_trace_stream). Writes held back
+  /// by dry run mode are not traced, as they never reach the device.
+  pub fn set_packet_trace(&self, packet_trace: bool) {
+    self.packet_trace.store(packet_trace, Ordering::SeqCst);
+  }
+
+  /// Returns a receiver for packets traced while packet tracing is on.
+  pub fn packet_trace_stream(&self) -> broadcast::Receiver<HardwarePacketTrace> {
+    self.packet_trace_sender.subscribe()
+  }
+
+  /// Send a packet to the packet trace stream, if tracing is on. Nothing is copied otherwise.
+  pub(crate) fn trace_packet(
+    &self,
+    direction: HardwarePacketDirection,
+    endpoint: Endpoint,
+    bytes: &[u8],
+  ) {
+    if self.packet_trace() {
+      // No one listening is fine.
+      let _ = self
+        .packet_trace_sender
+        .send(HardwarePacketTrace::new(direction, endpoint, bytes));
+    }
+  }
+
+  /// Returns the most recent failed operations on the device, oldest first. At most
+  /// [HARDWARE_ERROR_HISTORY_LENGTH] records are kept. Failures from every caller are recorded,
+  /// including background tasks protocols run on their own.
+  pub fn error_history(&self) -> Vec<HardwareErrorRecord> {
+    let history = self.error_history.lock().expect("Lock poisoned");
+    history.records.iter().cloned().collect()
+  }
+
+  /// Returns how many operations on the device have failed since it connected, including ones no
+  /// longer in the [error history](Self::error_history).
+  pub fn error_count(&self) -> u64 {
+    self.error_history.lock().expect("Lock poisoned").count
+  }
+
+  /// Returns the operation counters for the device. See [DeviceIoStats].
+  pub fn io_stats(&self) -> DeviceIoStats {
+    let counters = &self.io_counters;
+    DeviceIoStats {
+      uptime_ms: self.created.elapsed().as_millis() as u64,
+      writes: counters.writes.load(Ordering::Relaxed),
+      bytes_written: counters.bytes_written.load(Ordering::Relaxed),
+      reads: counters.reads.load(Ordering::Relaxed),
+      bytes_read: counters.bytes_read.load(Ordering::Relaxed),
+      subscription_changes: counters.subscription_changes.load(Ordering::Relaxed),
+      errors: self.error_count(),
+      average_write_latency_us: HardwareIoCounters::average_latency_us(&counters.write_latency_ns),
+      average_read_latency_us: HardwareIoCounters::average_latency_us(&counters.read_latency_ns),
+      command_queue: None,
+    }
+  }
+
+  /// Wrap an operation future, so a failure gets added to the error history.
+  fn record_errors<T>(
+    &self,
+    operation: HardwareOperation,
+    endpoint: Option<Endpoint>,
+    fut: BoxFuture<'static, Result<T, ButtplugDeviceError>>,
+  ) -> BoxFuture<'static, Result<T, ButtplugDeviceError>>
+  where
+    T: Send + 'static,
+  {
+    let error_history = self.error_history.clone();
+    async move {
+      let result = fut.await;
+      if let Err(error) = &result {
+        let mut history = error_history.lock().expect("Lock poisoned");
+        if history.records.len() == HARDWARE_ERROR_HISTORY_LENGTH {
+          history.records.pop_front();
+        }
+        history.records.push_back(HardwareErrorRecord {
+          timestamp: SystemTime::now(),
+          operation,
+          endpoint,
+          error: error.clone(),
+        });
+        history.count += 1;
+      }
+      result
+    }
+    .boxed()
+  }
+
   /// Disconnect from the device (if it is connected)
   pub fn disconnect(&self) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
-    self.internal_impl.disconnect()
+    self.record_errors(
+      HardwareOperation::Disconnect,
+      None,
+      self.internal_impl.disconnect(),
+    )
   }
 
   pub fn parse_message(
@@ -310,6 +662,7 @@ impl Hardware {
       HardwareCommand::Write(cmd) => self.write_value(cmd),
       HardwareCommand::Subscribe(cmd) => self.subscribe(cmd),
       HardwareCommand::Unsubscribe(cmd) => self.unsubscribe(cmd),
+      HardwareCommand::Batch(cmds) => self.write_batch(cmds),
     }
   }
 
@@ -318,7 +671,37 @@ impl Hardware {
     &self,
     msg: &HardwareReadCmd,
   ) -> BoxFuture<'static, Result<HardwareReading, ButtplugDeviceError>> {
-    self.internal_impl.read_value(msg)
+    self.io_counters.reads.fetch_add(1, Ordering::Relaxed);
+    let io_counters = self.io_counters.clone();
+    let internal_read_fut = self.internal_impl.read_value(msg);
+    let read_fut = self.record_errors(
+      HardwareOperation::Read,
+      Some(msg.endpoint()),
+      async move {
+        let start = Instant::now();
+        let reading = internal_read_fut.await?;
+        HardwareIoCounters::add_latency_sample(&io_counters.read_latency_ns, start.elapsed());
+        io_counters
+          .bytes_read
+          .fetch_add(reading.data().len() as u64, Ordering::Relaxed);
+        Ok(reading)
+      }
+      .boxed(),
+    );
+    if !self.packet_trace() {
+      return read_fut;
+    }
+    let packet_trace_sender = self.packet_trace_sender.clone();
+    async move {
+      let reading = read_fut.await?;
+      let _ = packet_trace_sender.send(HardwarePacketTrace::new(
+        HardwarePacketDirection::Read,
+        *reading.endpoint(),
+        reading.data(),
+      ));
+      Ok(reading)
+    }
+    .boxed()
   }
 
   /// Write a value to the device
@@ -326,7 +709,88 @@ impl Hardware {
     &self,
     msg: &HardwareWriteCmd,
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
-    let write_fut = self.internal_impl.write_value(msg);
+    let write_lock = self.write_lock.clone();
+    let write_fut = self.unlocked_write_value(msg);
+    async move {
+      let _guard = write_lock.lock().await;
+      write_fut.await
+    }
+    .boxed()
+  }
+
+  /// Write values to the device in order, without any other write (from a keepalive task, or
+  /// another command) getting in between. Stops at the first write that fails.
+  pub fn write_batch(
+    &self,
+    msgs: &[HardwareWriteCmd],
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    let write_lock = self.write_lock.clone();
+    let write_futs: Vec<_> = msgs
+      .iter()
+      .map(|msg| self.unlocked_write_value(msg))
+      .collect();
+    async move {
+      let _guard = write_lock.lock().await;
+      for write_fut in write_futs {
+        write_fut.await?;
+      }
+      Ok(())
+    }
+    .boxed()
+  }
+
+  /// Write a value to the device, without waiting on the write lock. Hardware implementations do
+  /// their IO when the future is polled, so the caller just needs to hold the lock while polling.
+  fn unlocked_write_value(
+    &self,
+    msg: &HardwareWriteCmd,
+  ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    let forced_msg;
+    let msg = if self.force_write_with_response() && !msg.write_with_response() {
+      forced_msg = HardwareWriteCmd {
+        write_with_response: true,
+        ..msg.clone()
+      };
+      &forced_msg
+    } else {
+      msg
+    };
+    let write_fut = if self.dry_run() {
+      info!(
+        "Dry run write to {} ({}) on {:?}: {}",
+        self.name,
+        self.address,
+        msg.endpoint(),
+        msg
+          .data()
+          .iter()
+          .map(|byte| format!("{:02x}", byte))
+          .collect::<String>()
+      );
+      // No one listening is fine, the log is enough.
+      let _ = self.dry_run_sender.send(msg.clone());
+      future::ready(Ok(())).boxed()
+    } else {
+      self.trace_packet(HardwarePacketDirection::Write, msg.endpoint(), msg.data());
+      self.io_counters.writes.fetch_add(1, Ordering::Relaxed);
+      self
+        .io_counters
+        .bytes_written
+        .fetch_add(msg.data().len() as u64, Ordering::Relaxed);
+      let io_counters = self.io_counters.clone();
+      let internal_write_fut = self.internal_impl.write_value(msg);
+      self.record_errors(
+        HardwareOperation::Write,
+        Some(msg.endpoint()),
+        async move {
+          let start = Instant::now();
+          internal_write_fut.await?;
+          HardwareIoCounters::add_latency_sample(&io_counters.write_latency_ns, start.elapsed());
+          Ok(())
+        }
+        .boxed(),
+      )
+    };
     if self.requires_keepalive {
       let last_write_time = self.last_write_time.clone();
       async move {
@@ -344,7 +808,16 @@ impl Hardware {
     &self,
     msg: &HardwareSubscribeCmd,
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
-    self.internal_impl.subscribe(msg)
+    self.trace_packet(HardwarePacketDirection::Subscribe, msg.endpoint(), &[]);
+    self
+      .io_counters
+      .subscription_changes
+      .fetch_add(1, Ordering::Relaxed);
+    self.record_errors(
+      HardwareOperation::Subscribe,
+      Some(msg.endpoint()),
+      self.internal_impl.subscribe(msg),
+    )
   }
 
   /// Unsubscribe from a device endpoint, if it exists
@@ -352,7 +825,16 @@ impl Hardware {
     &self,
     msg: &HardwareUnsubscribeCmd,
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
-    self.internal_impl.unsubscribe(msg)
+    self.trace_packet(HardwarePacketDirection::Unsubscribe, msg.endpoint(), &[]);
+    self
+      .io_counters
+      .subscription_changes
+      .fetch_add(1, Ordering::Relaxed);
+    self.record_errors(
+      HardwareOperation::Unsubscribe,
+      Some(msg.endpoint()),
+      self.internal_impl.unsubscribe(msg),
+    )
   }
 }
 
@@ -409,6 +891,17 @@ pub trait HardwareSpecializer: Sync + Send {
     &mut self,
     protocol: &[ProtocolCommunicationSpecifier],
   ) -> Result<Hardware, ButtplugDeviceError>;
+
+  /// Initialize a device that was specialized with the same specifiers before, and had `endpoints`
+  /// then. Hardware that can reuse the endpoints instead of looking for them again should override
+  /// this, and fail if the device no longer has them. By default, this is [Self::specialize].
+  async fn specialize_with_endpoints(
+    &mut self,
+    protocol: &[ProtocolCommunicationSpecifier],
+    _endpoints: &[Endpoint],
+  ) -> Result<Hardware, ButtplugDeviceError> {
+    self.specialize(protocol).await
+  }
 }
 
 /// Used in cases where there's nothing to specialize for the protocol.
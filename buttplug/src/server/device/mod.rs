@@ -95,12 +95,26 @@
 //!
 //!
 
+mod auto_zero;
+mod command_queue;
+mod command_rate_limiter;
 pub mod configuration;
+mod device_match_cache;
 pub mod hardware;
 pub mod protocol;
+mod scalar_ramp;
 pub mod server_device;
 mod server_device_manager;
 mod server_device_manager_event_loop;
+mod unmatched_advertisements;
 
-pub use server_device::{ServerDevice, ServerDeviceEvent};
-pub use server_device_manager::{ServerDeviceManager, ServerDeviceManagerBuilder};
+pub use command_queue::{CommandQueueStats, DEFAULT_MAX_QUEUED_COMMANDS};
+pub use server_device::{InitializationRetryPolicy, ServerDevice, ServerDeviceEvent};
+pub use server_device_manager::{
+  DeviceIgnoredReason,
+  ServerDeviceManager,
+  ServerDeviceManagerBuilder,
+  ServerDeviceManagerEvent,
+  DEFAULT_AUTO_CONNECT_TIMEOUT,
+};
+pub use unmatched_advertisements::UnmatchedAdvertisement;
@@ -0,0 +1,120 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Frequency input handling shared by the DG-Lab protocols.
+//!
+//! Both DG-Lab devices take a frequency in [MINIMUM_INPUT_FREQUENCY, MAXIMUM_INPUT_FREQUENCY], with
+//! 0 meaning off. The device config step range for frequency features starts one below the
+//! minimum, so that the lowest step a client can send through step quantization is still "off".
+//! User configs can narrow step ranges further though, so every value below the minimum is
+//! treated as off, rather than only that one step. Values above the maximum are errors.
+
+use crate::core::errors::ButtplugDeviceError;
+
+pub static MINIMUM_INPUT_FREQUENCY: u32 = 10;
+pub static MAXIMUM_INPUT_FREQUENCY: u32 = 1000;
+
+/// Checks a frequency scalar for the given protocol, returning the frequency to use (0 is off).
+pub fn input_frequency(protocol: &str, scalar: u32) -> Result<u32, ButtplugDeviceError> {
+  if scalar < MINIMUM_INPUT_FREQUENCY {
+    Ok(0)
+  } else if scalar > MAXIMUM_INPUT_FREQUENCY {
+    Err(ButtplugDeviceError::ProtocolSpecificError(
+      protocol.to_owned(),
+      format!(
+        "Frequency scalar {} not in [{}, {}]",
+        scalar, MINIMUM_INPUT_FREQUENCY, MAXIMUM_INPUT_FREQUENCY
+      ),
+    ))
+  } else {
+    Ok(scalar)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    core::message::{ActuatorType, Endpoint},
+    server::device::{
+      hardware::HardwareCommand,
+      protocol::{dg_lab_v2::DGLabV2, dg_lab_v3::DGLabV3, ProtocolHandler},
+    },
+  };
+
+  fn frequency_command(scalar: u32) -> Vec<Option<(ActuatorType, u32)>> {
+    vec![None, None, Some((ActuatorType::Oscillate, scalar))]
+  }
+
+  fn write_data(commands: &[HardwareCommand], endpoint: Endpoint) -> Vec<u8> {
+    commands
+      .iter()
+      .flat_map(|command| match command {
+        HardwareCommand::Write(write) => vec![write],
+        HardwareCommand::Batch(writes) => writes.iter().collect(),
+        _ => vec![],
+      })
+      .find(|write| write.endpoint() == endpoint)
+      .map(|write| write.data().clone())
+      .expect("Test, assuming infallible.")
+  }
+
+  #[test]
+  pub fn test_input_frequency_boundaries() {
+    assert_eq!(input_frequency("dg-lab-v3", 0).unwrap(), 0);
+    assert_eq!(input_frequency("dg-lab-v3", 1).unwrap(), 0);
+    assert_eq!(input_frequency("dg-lab-v3", 9).unwrap(), 0);
+    assert_eq!(input_frequency("dg-lab-v3", 10).unwrap(), 10);
+    assert_eq!(input_frequency("dg-lab-v3", 1000).unwrap(), 1000);
+    assert!(input_frequency("dg-lab-v3", 1001).is_err());
+  }
+
+  #[test]
+  pub fn test_dg_lab_v2_frequency_boundaries() {
+    let handler = DGLabV2::default();
+    let off = handler
+      .handle_scalar_cmd(&frequency_command(0))
+      .expect("Test, assuming infallible.");
+    for scalar in [1, 9] {
+      let commands = handler
+        .handle_scalar_cmd(&frequency_command(scalar))
+        .expect("Test, assuming infallible.");
+      assert_eq!(
+        write_data(&commands, Endpoint::Generic0),
+        write_data(&off, Endpoint::Generic0),
+        "{}",
+        scalar
+      );
+    }
+    let commands = handler
+      .handle_scalar_cmd(&frequency_command(10))
+      .expect("Test, assuming infallible.");
+    assert_ne!(
+      write_data(&commands, Endpoint::Generic0),
+      write_data(&off, Endpoint::Generic0)
+    );
+    assert!(handler.handle_scalar_cmd(&frequency_command(1001)).is_err());
+  }
+
+  #[test]
+  pub fn test_dg_lab_v3_frequency_boundaries() {
+    let handler = DGLabV3::default();
+    // Frequency A is the first of the four channel A frequency bytes.
+    for (scalar, frequency) in [(0, 0), (1, 0), (9, 0), (10, 10)] {
+      let commands = handler
+        .handle_scalar_cmd(&frequency_command(scalar))
+        .expect("Test, assuming infallible.");
+      assert_eq!(
+        write_data(&commands, Endpoint::Tx)[4],
+        frequency,
+        "{}",
+        scalar
+      );
+    }
+    assert!(handler.handle_scalar_cmd(&frequency_command(1001)).is_err());
+  }
+}
@@ -0,0 +1,723 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Channel state and command dispatch shared by the DG-Lab protocols.
+//!
+//! Both DG-Lab versions drive two channels (A and B), each with power, frequency and waveform
+//! features, and need the stored state repeated to keep the device outputting. The versions only
+//! differ in how that state is encoded into packets, which is left to the protocol modules.
+//!
+//! Scalar features are laid out the same way in both device configs: each role takes two
+//! consecutive feature indexes, channel A first, with descriptors naming the channel ("Channel A
+//! Power", "Channel B Power", etc.). [ChannelLimits::feature_layouts] declares this layout to the
+//! device setup, and [ChannelLimits::check_feature_layout] makes sure user configs keep to it.
+
+pub mod frequency;
+
+use self::frequency::input_frequency;
+use crate::{
+  core::{errors::ButtplugDeviceError, message::ActuatorType},
+  server::device::{
+    configuration::ProtocolDeviceAttributes,
+    hardware::{Hardware, HardwareWriteCmd},
+  },
+  util::{self, async_manager},
+};
+use std::{sync::Arc, time::Duration};
+use tokio_util::sync::CancellationToken;
+
+static REPEAT_SLEEP_DURATION: u64 = 100;
+static WAIT_UNTIL_TEST_DURATION: u64 = 500;
+
+/// One of the two output channels of a DG-Lab device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+  A,
+  B,
+}
+
+impl Channel {
+  /// Position of the channel in per-channel arrays, A is 0 and B is 1.
+  pub fn index(&self) -> usize {
+    match self {
+      Channel::A => 0,
+      Channel::B => 1,
+    }
+  }
+
+  /// How the channel is named in feature descriptors.
+  pub fn label(&self) -> &'static str {
+    match self {
+      Channel::A => "Channel A",
+      Channel::B => "Channel B",
+    }
+  }
+
+  /// Channel the device outputs commands for this channel on. That's the other channel if the user
+  /// config swaps channels (for reversed cabling), so protocols route every feature of a channel
+  /// through here before touching the channel state. Packets are built from the state of the
+  /// physical channels, which keeps the swap in repeat packets too.
+  pub fn routed(&self, swap_channels: bool) -> Self {
+    match (self, swap_channels) {
+      (Channel::A, true) => Channel::B,
+      (Channel::B, true) => Channel::A,
+      (channel, false) => *channel,
+    }
+  }
+
+  /// Channel of a LinearCmd vector index, if valid.
+  pub fn from_linear_index(index: u32) -> Option<Self> {
+    match index {
+      0 => Some(Channel::A),
+      1 => Some(Channel::B),
+      _ => None,
+    }
+  }
+}
+
+/// What a scalar feature controls on its channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelRole {
+  /// Output power (S), set through Vibrate features.
+  Power,
+  /// Frequency (X, Y), set through Oscillate features. Values are already checked by
+  /// [input_frequency], so 0 means off.
+  Frequency,
+  /// Pulse width on V2, waveform strength on V3 (Z), set through Inflate features.
+  Waveform,
+  /// Power change relative to the current output (S), set through Constrict features.
+  RelativePower,
+}
+
+impl ChannelRole {
+  /// Roles in scalar feature order, each taking a feature per channel.
+  const LAYOUT: [ChannelRole; 4] = [
+    ChannelRole::Power,
+    ChannelRole::Frequency,
+    ChannelRole::Waveform,
+    ChannelRole::RelativePower,
+  ];
+
+  fn actuator(&self) -> ActuatorType {
+    match self {
+      ChannelRole::Power => ActuatorType::Vibrate,
+      ChannelRole::Frequency => ActuatorType::Oscillate,
+      ChannelRole::Waveform => ActuatorType::Inflate,
+      ChannelRole::RelativePower => ActuatorType::Constrict,
+    }
+  }
+
+  fn from_actuator(actuator: ActuatorType) -> Option<Self> {
+    match actuator {
+      ActuatorType::Vibrate => Some(ChannelRole::Power),
+      ActuatorType::Oscillate => Some(ChannelRole::Frequency),
+      ActuatorType::Inflate => Some(ChannelRole::Waveform),
+      ActuatorType::Constrict => Some(ChannelRole::RelativePower),
+      _ => None,
+    }
+  }
+
+  /// Feature index of channel A for this role. Channel B uses the next index.
+  fn first_index(&self) -> usize {
+    match self {
+      ChannelRole::Power => 0,
+      ChannelRole::Frequency => 2,
+      ChannelRole::Waveform => 4,
+      ChannelRole::RelativePower => 6,
+    }
+  }
+}
+
+/// A checked scalar command, ready to be stored in the channel state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelUpdate {
+  pub channel: Channel,
+  pub role: ChannelRole,
+  pub value: u32,
+}
+
+impl ChannelUpdate {
+  /// Scalar feature index the update came from.
+  pub fn feature_index(&self) -> usize {
+    self.role.first_index() + self.channel.index()
+  }
+}
+
+/// Value limits of a protocol version, used to check scalar commands before they're dispatched.
+pub struct ChannelLimits {
+  pub protocol: &'static str,
+  pub maximum_power: u32,
+  /// Name of the waveform role in error messages, as it means different things per version.
+  pub waveform_name: &'static str,
+  pub maximum_waveform: u32,
+  /// None if the protocol has no relative power features.
+  pub maximum_relative_power: Option<u32>,
+}
+
+impl ChannelLimits {
+  fn error(&self, message: String) -> ButtplugDeviceError {
+    ButtplugDeviceError::ProtocolSpecificError(self.protocol.to_owned(), message)
+  }
+
+  /// Clamp a value that's about to be encoded into a packet to `maximum`.
+  ///
+  /// Values are checked against the limits when commands come in, so this only changes anything if
+  /// a bug lets an unchecked value through. Sending the maximum is safer than whatever bits a
+  /// truncating cast would have left, which could be any power level.
+  pub fn saturate(&self, name: &str, value: u32, maximum: u32) -> u32 {
+    if value > maximum {
+      error!(
+        "{} {} {} over maximum of {}, sending maximum instead",
+        self.protocol, name, value, maximum
+      );
+    }
+    value.min(maximum)
+  }
+
+  /// Scalar feature counts a device config can have. Devices have 6 scalar features, 8 if the
+  /// protocol has relative power, or only the 2 power features if `allow_power_only` is set.
+  fn feature_counts(&self, allow_power_only: bool) -> Vec<usize> {
+    let mut counts = vec![6];
+    if allow_power_only {
+      counts.insert(0, 2);
+    }
+    if self.maximum_relative_power.is_some() {
+      counts.push(8);
+    }
+    counts
+  }
+
+  /// Scalar feature layouts the channel mapping can drive, for
+  /// [ProtocolInitializer::scalar_feature_layouts](super::ProtocolInitializer::scalar_feature_layouts).
+  pub fn feature_layouts(&self, allow_power_only: bool) -> Vec<Vec<ActuatorType>> {
+    self
+      .feature_counts(allow_power_only)
+      .into_iter()
+      .map(|count| {
+        (0..count)
+          .map(|index| ChannelRole::LAYOUT[index / 2].actuator())
+          .collect()
+      })
+      .collect()
+  }
+
+  /// Check that the scalar features of a device config match the layout the channel mapping expects
+  /// (see the module docs), so a user config that reorders or drops features fails initialization
+  /// instead of sending values to the wrong channel or role. Unlike the declared
+  /// [Self::feature_layouts], this also checks descriptors, which can be left empty, but must name
+  /// the right channel if set.
+  pub fn check_feature_layout(
+    &self,
+    attributes: &ProtocolDeviceAttributes,
+    allow_power_only: bool,
+  ) -> Result<(), ButtplugDeviceError> {
+    let features = attributes
+      .message_attributes()
+      .scalar_cmd()
+      .as_deref()
+      .unwrap_or_default();
+    let expected_counts = self.feature_counts(allow_power_only);
+    if !expected_counts.contains(&features.len()) {
+      return Err(self.error(format!(
+        "Device config has {} scalar features, expected {}",
+        features.len(),
+        expected_counts
+          .iter()
+          .map(ToString::to_string)
+          .collect::<Vec<String>>()
+          .join(" or ")
+      )));
+    }
+    for (index, feature) in features.iter().enumerate() {
+      let role = ChannelRole::LAYOUT[index / 2];
+      let channel = if index % 2 == 0 {
+        Channel::A
+      } else {
+        Channel::B
+      };
+      let descriptor = feature.feature_descriptor();
+      if ChannelRole::from_actuator(*feature.actuator_type()) != Some(role) {
+        return Err(self.error(format!(
+          "Scalar feature {} (\"{}\") is {}, expected the {} {:?} feature",
+          index,
+          descriptor,
+          feature.actuator_type(),
+          channel.label(),
+          role
+        )));
+      }
+      if !descriptor.is_empty() && !descriptor.starts_with(channel.label()) {
+        return Err(self.error(format!(
+          "Scalar feature {} (\"{}\") should be a {} feature",
+          index,
+          descriptor,
+          channel.label()
+        )));
+      }
+    }
+    Ok(())
+  }
+
+  fn check_value(&self, role: ChannelRole, scalar: u32) -> Result<u32, ButtplugDeviceError> {
+    let (name, maximum) = match role {
+      ChannelRole::Power => ("Power", self.maximum_power),
+      ChannelRole::Frequency => return input_frequency(self.protocol, scalar),
+      ChannelRole::Waveform => (self.waveform_name, self.maximum_waveform),
+      ChannelRole::RelativePower => (
+        "Relative power",
+        self
+          .maximum_relative_power
+          .expect("Only dispatched if the protocol has relative power"),
+      ),
+    };
+    if scalar > maximum {
+      return Err(self.error(format!(
+        "{} scalar {} not in [0, {}]",
+        name, scalar, maximum
+      )));
+    }
+    Ok(scalar)
+  }
+
+  /// Relative power feature indexes with the step value meaning no change, for
+  /// `ProtocolHandler::scalar_adjustment_features`. Empty if the protocol has none.
+  pub fn relative_power_features(&self) -> Vec<(usize, u32)> {
+    let first_index = ChannelRole::RelativePower.first_index();
+    self
+      .maximum_relative_power
+      .map(|maximum| vec![(first_index, maximum / 2), (first_index + 1, maximum / 2)])
+      .unwrap_or_default()
+  }
+
+  /// Map a single scalar command to the channel and role it controls, checking its value.
+  pub fn channel_update(
+    &self,
+    index: usize,
+    actuator: ActuatorType,
+    scalar: u32,
+  ) -> Result<ChannelUpdate, ButtplugDeviceError> {
+    let role = ChannelRole::from_actuator(actuator)
+      .filter(|role| *role != ChannelRole::RelativePower || self.maximum_relative_power.is_some())
+      .ok_or_else(|| {
+        ButtplugDeviceError::UnhandledCommand(
+          "Unknown actuator types are not controllable.".to_owned(),
+        )
+      })?;
+    let value = self.check_value(role, scalar)?;
+    let channel = match index.checked_sub(role.first_index()) {
+      Some(0) => Channel::A,
+      Some(1) => Channel::B,
+      _ => return Err(self.error(format!("{} command index {} is invalid", actuator, index))),
+    };
+    Ok(ChannelUpdate {
+      channel,
+      role,
+      value,
+    })
+  }
+
+  /// Map every command of a ScalarCmd, in feature order. Fails if any command is invalid, so a
+  /// rejected ScalarCmd never leaves the channels half updated.
+  pub fn channel_updates(
+    &self,
+    commands: &[Option<(ActuatorType, u32)>],
+  ) -> Result<Vec<ChannelUpdate>, ButtplugDeviceError> {
+    commands
+      .iter()
+      .enumerate()
+      .filter_map(|(index, command)| {
+        command.map(|(actuator, scalar)| self.channel_update(index, actuator, scalar))
+      })
+      .collect()
+  }
+}
+
+/// Per-channel state of a DG-Lab device.
+#[derive(Default)]
+pub struct DualChannelState<T> {
+  a: Arc<T>,
+  b: Arc<T>,
+}
+
+impl<T> DualChannelState<T> {
+  pub fn channel(&self, channel: Channel) -> &Arc<T> {
+    match channel {
+      Channel::A => &self.a,
+      Channel::B => &self.b,
+    }
+  }
+
+  pub fn a(&self) -> &Arc<T> {
+    &self.a
+  }
+
+  pub fn b(&self) -> &Arc<T> {
+    &self.b
+  }
+
+  pub fn both(&self) -> [&Arc<T>; 2] {
+    [&self.a, &self.b]
+  }
+}
+
+/// Write the packets built by `packets` every REPEAT_SLEEP_DURATION, so the device keeps
+/// outputting the stored state, until `background_tasks` is cancelled.
+pub fn spawn_keepalive<F>(hardware: Arc<Hardware>, background_tasks: CancellationToken, packets: F)
+where
+  F: Fn() -> Vec<HardwareWriteCmd> + Send + Sync + 'static,
+{
+  async_manager::spawn(async move {
+    let duration = Duration::from_millis(REPEAT_SLEEP_DURATION);
+    // Wait until test finished, or it would cause failure of test (The order of HardwareCmd changed)
+    // TODO: Maybe there's a better way to solve this
+    util::sleep(Duration::from_millis(WAIT_UNTIL_TEST_DURATION)).await;
+    while !background_tasks.is_cancelled() {
+      // Written as a batch, so command writes can't land in the middle of the repeat.
+      if let Err(e) = hardware.write_batch(&packets()).await {
+        warn!("Error writing repeat packets: {:?}", e);
+      }
+      tokio::select! {
+        _ = util::sleep(duration) => {}
+        _ = background_tasks.cancelled() => {}
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::core::message::{
+    ButtplugActuatorFeatureMessageType,
+    DeviceFeature,
+    DeviceFeatureActuator,
+    FeatureType,
+  };
+  use std::collections::HashSet;
+
+  static LIMITS: ChannelLimits = ChannelLimits {
+    protocol: "dg-lab-test",
+    maximum_power: 200,
+    waveform_name: "Waveform strength",
+    maximum_waveform: 100,
+    maximum_relative_power: Some(400),
+  };
+
+  static LIMITS_WITHOUT_RELATIVE_POWER: ChannelLimits = ChannelLimits {
+    protocol: "dg-lab-test",
+    maximum_power: 2047,
+    waveform_name: "Pulse width",
+    maximum_waveform: 31,
+    maximum_relative_power: None,
+  };
+
+  fn update(channel: Channel, role: ChannelRole, value: u32) -> ChannelUpdate {
+    ChannelUpdate {
+      channel,
+      role,
+      value,
+    }
+  }
+
+  fn error_message(error: ButtplugDeviceError) -> String {
+    match error {
+      ButtplugDeviceError::ProtocolSpecificError(protocol, message) => {
+        assert_eq!(protocol, "dg-lab-test");
+        message
+      }
+      other => panic!("Unexpected error {:?}", other),
+    }
+  }
+
+  #[test]
+  pub fn test_channel_update_roles_and_channels() {
+    let cases = [
+      (0, ActuatorType::Vibrate, Channel::A, ChannelRole::Power),
+      (1, ActuatorType::Vibrate, Channel::B, ChannelRole::Power),
+      (
+        2,
+        ActuatorType::Oscillate,
+        Channel::A,
+        ChannelRole::Frequency,
+      ),
+      (
+        3,
+        ActuatorType::Oscillate,
+        Channel::B,
+        ChannelRole::Frequency,
+      ),
+      (4, ActuatorType::Inflate, Channel::A, ChannelRole::Waveform),
+      (5, ActuatorType::Inflate, Channel::B, ChannelRole::Waveform),
+      (
+        6,
+        ActuatorType::Constrict,
+        Channel::A,
+        ChannelRole::RelativePower,
+      ),
+      (
+        7,
+        ActuatorType::Constrict,
+        Channel::B,
+        ChannelRole::RelativePower,
+      ),
+    ];
+    for (index, actuator, channel, role) in cases {
+      assert_eq!(
+        LIMITS
+          .channel_update(index, actuator, 50)
+          .expect("Test, assuming infallible."),
+        update(channel, role, 50)
+      );
+    }
+  }
+
+  #[test]
+  pub fn test_channel_update_checks_values() {
+    assert_eq!(
+      LIMITS
+        .channel_update(0, ActuatorType::Vibrate, 200)
+        .expect("Test, assuming infallible."),
+      update(Channel::A, ChannelRole::Power, 200)
+    );
+    let errors = [
+      (
+        0,
+        ActuatorType::Vibrate,
+        201,
+        "Power scalar 201 not in [0, 200]",
+      ),
+      (
+        4,
+        ActuatorType::Inflate,
+        101,
+        "Waveform strength scalar 101 not in [0, 100]",
+      ),
+      (
+        6,
+        ActuatorType::Constrict,
+        401,
+        "Relative power scalar 401 not in [0, 400]",
+      ),
+      (
+        2,
+        ActuatorType::Oscillate,
+        1001,
+        "Frequency scalar 1001 not in [10, 1000]",
+      ),
+    ];
+    for (index, actuator, scalar, message) in errors {
+      assert_eq!(
+        error_message(
+          LIMITS
+            .channel_update(index, actuator, scalar)
+            .expect_err("Test, assuming failure.")
+        ),
+        message
+      );
+    }
+    // Frequencies below the minimum are off
+    assert_eq!(
+      LIMITS
+        .channel_update(3, ActuatorType::Oscillate, 9)
+        .expect("Test, assuming infallible."),
+      update(Channel::B, ChannelRole::Frequency, 0)
+    );
+  }
+
+  #[test]
+  pub fn test_channel_update_rejects_invalid_indexes() {
+    for (index, actuator) in [
+      (2, ActuatorType::Vibrate),
+      (1, ActuatorType::Oscillate),
+      (6, ActuatorType::Inflate),
+      (5, ActuatorType::Constrict),
+    ] {
+      assert_eq!(
+        error_message(
+          LIMITS
+            .channel_update(index, actuator, 0)
+            .expect_err("Test, assuming failure.")
+        ),
+        format!("{} command index {} is invalid", actuator, index)
+      );
+    }
+  }
+
+  #[test]
+  pub fn test_channel_update_rejects_unhandled_actuators() {
+    assert!(matches!(
+      LIMITS.channel_update(0, ActuatorType::Rotate, 0),
+      Err(ButtplugDeviceError::UnhandledCommand(_))
+    ));
+    assert!(matches!(
+      LIMITS_WITHOUT_RELATIVE_POWER.channel_update(6, ActuatorType::Constrict, 0),
+      Err(ButtplugDeviceError::UnhandledCommand(_))
+    ));
+    assert_eq!(
+      error_message(
+        LIMITS_WITHOUT_RELATIVE_POWER
+          .channel_update(5, ActuatorType::Inflate, 32)
+          .expect_err("Test, assuming failure.")
+      ),
+      "Pulse width scalar 32 not in [0, 31]"
+    );
+  }
+
+  #[test]
+  pub fn test_channel_updates_skip_unset_features() {
+    let commands = [
+      None,
+      Some((ActuatorType::Vibrate, 20)),
+      None,
+      None,
+      Some((ActuatorType::Inflate, 10)),
+    ];
+    assert_eq!(
+      LIMITS
+        .channel_updates(&commands)
+        .expect("Test, assuming infallible."),
+      vec![
+        update(Channel::B, ChannelRole::Power, 20),
+        update(Channel::A, ChannelRole::Waveform, 10),
+      ]
+    );
+    let commands = [
+      Some((ActuatorType::Vibrate, 20)),
+      Some((ActuatorType::Vibrate, 201)),
+    ];
+    assert!(LIMITS.channel_updates(&commands).is_err());
+  }
+
+  fn attributes(features: &[(&str, FeatureType)]) -> ProtocolDeviceAttributes {
+    let features: Vec<DeviceFeature> = features
+      .iter()
+      .map(|(description, feature_type)| {
+        DeviceFeature::new(
+          description,
+          *feature_type,
+          &Some(DeviceFeatureActuator::new(
+            &(0..=100),
+            &(0..=100),
+            &HashSet::from([ButtplugActuatorFeatureMessageType::ScalarCmd]),
+          )),
+          &None,
+        )
+      })
+      .collect();
+    ProtocolDeviceAttributes::new("DG-Lab Test", &None, &features.into())
+  }
+
+  fn full_layout() -> Vec<(&'static str, FeatureType)> {
+    vec![
+      ("Channel A Power", FeatureType::Vibrate),
+      ("Channel B Power", FeatureType::Vibrate),
+      ("Channel A Frequency", FeatureType::Oscillate),
+      ("Channel B Frequency", FeatureType::Oscillate),
+      ("Channel A Waveform Strength", FeatureType::Inflate),
+      ("Channel B Waveform Strength", FeatureType::Inflate),
+      ("Channel A Power Adjustment", FeatureType::Constrict),
+      ("Channel B Power Adjustment", FeatureType::Constrict),
+    ]
+  }
+
+  #[test]
+  pub fn test_check_feature_layout() {
+    let layout = full_layout();
+    assert!(LIMITS
+      .check_feature_layout(&attributes(&layout), false)
+      .is_ok());
+    assert!(LIMITS
+      .check_feature_layout(&attributes(&layout[..6]), false)
+      .is_ok());
+    assert!(LIMITS_WITHOUT_RELATIVE_POWER
+      .check_feature_layout(&attributes(&layout[..2]), true)
+      .is_ok());
+    // Descriptors are optional.
+    let anonymous: Vec<(&str, FeatureType)> = layout.iter().map(|(_, ty)| ("", *ty)).collect();
+    assert!(LIMITS
+      .check_feature_layout(&attributes(&anonymous), false)
+      .is_ok());
+
+    let mut swapped_roles = layout.clone();
+    swapped_roles.swap(0, 2);
+    let mut swapped_channels = layout.clone();
+    swapped_channels.swap(0, 1);
+    let cases = [
+      (
+        &LIMITS,
+        attributes(&layout[..2]),
+        false,
+        "Device config has 2 scalar features, expected 6 or 8",
+      ),
+      (
+        &LIMITS_WITHOUT_RELATIVE_POWER,
+        attributes(&layout),
+        true,
+        "Device config has 8 scalar features, expected 2 or 6",
+      ),
+      (
+        &LIMITS,
+        attributes(&swapped_roles),
+        false,
+        "Scalar feature 0 (\"Channel A Frequency\") is Oscillate, expected the Channel A Power feature",
+      ),
+      (
+        &LIMITS,
+        attributes(&swapped_channels),
+        false,
+        "Scalar feature 0 (\"Channel B Power\") should be a Channel A feature",
+      ),
+    ];
+    for (limits, attributes, allow_power_only, message) in cases {
+      assert_eq!(
+        error_message(
+          limits
+            .check_feature_layout(&attributes, allow_power_only)
+            .expect_err("Test, assuming infallible.")
+        ),
+        message
+      );
+    }
+  }
+
+  #[test]
+  pub fn test_feature_layouts() {
+    use crate::server::device::{
+      configuration::UserDeviceIdentifier,
+      protocol::check_scalar_feature_layout,
+    };
+    let identifier = UserDeviceIdentifier::new("LayoutTest", "dg-lab-test", &None);
+    let layout = full_layout();
+    for (limits, allow_power_only, count) in [
+      (&LIMITS, false, 6),
+      (&LIMITS, false, 8),
+      (&LIMITS_WITHOUT_RELATIVE_POWER, true, 2),
+      (&LIMITS_WITHOUT_RELATIVE_POWER, true, 6),
+    ] {
+      assert!(check_scalar_feature_layout(
+        &identifier,
+        &attributes(&layout[..count]),
+        &limits.feature_layouts(allow_power_only)
+      )
+      .is_ok());
+    }
+    match check_scalar_feature_layout(
+      &identifier,
+      &attributes(&layout[..5]),
+      &LIMITS_WITHOUT_RELATIVE_POWER.feature_layouts(true),
+    ) {
+      Err(ButtplugDeviceError::DeviceConfigurationError(message)) => assert_eq!(
+        message,
+        format!(
+          "Device config for {} has scalar features [Vibrate, Vibrate, Oscillate, Oscillate, Inflate], but protocol dg-lab-test expects [Vibrate, Vibrate] or [Vibrate, Vibrate, Oscillate, Oscillate, Inflate, Inflate]",
+          identifier
+        )
+      ),
+      other => panic!("Unexpected result {:?}", other),
+    }
+  }
+}
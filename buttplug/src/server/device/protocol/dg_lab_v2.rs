@@ -11,30 +11,43 @@ use std::sync::atomic::Ordering::SeqCst;
 use std::time::Duration;
 
 use async_trait::async_trait;
+use futures::future::{BoxFuture, FutureExt};
+use futures::select;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
 
-use crate::{core::errors::ButtplugDeviceError, generic_protocol_initializer_setup, server::device::protocol::ProtocolHandler, util};
-use crate::core::errors::ButtplugDeviceError::ProtocolSpecificError;
-use crate::core::message::{ActuatorType, Endpoint};
+use crate::{core::errors::ButtplugDeviceError, generic_protocol_initializer_setup, server::device::protocol::ProtocolHandler};
+use crate::core::message::{self, ActuatorType, ButtplugDeviceMessage, ButtplugServerMessage, Endpoint};
 use crate::server::device::configuration::ProtocolDeviceAttributes;
-use crate::server::device::hardware::{Hardware, HardwareCommand, HardwareWriteCmd};
+use crate::server::device::hardware::{Hardware, HardwareCommand, HardwareEvent, HardwareReadCmd, HardwareWriteCmd};
+use crate::server::device::protocol::dg_lab::{spawn_keepalive, ChannelLimits, ChannelRole, DualChannelState};
 use crate::server::device::protocol::ProtocolIdentifier;
 use crate::server::device::protocol::ProtocolInitializer;
 use crate::server::device::protocol::UserDeviceIdentifier;
-use crate::util::async_manager;
 
-static MINIMUM_FREQUENCY: u32 = 10;
-static MAXIMUM_FREQUENCY: u32 = 1000;
 static MAXIMUM_POWER: u32 = 2047;
 static MAXIMUM_PULSE_WIDTH: u32 = 31;
-static MAXIMUM_X: f32 = 31f32;
-static MAXIMUM_Y: f32 = 1023f32;
-static REPEAT_SLEEP_DURATION: u64 = 100;
-static WAIT_UNTIL_TEST_DURATION: u64 = 500;
-
+static MAXIMUM_X: u32 = 31;
+static MAXIMUM_Y: u32 = 1023;
+static SIMPLE_MODE_FREQUENCY: u32 = 100;
+// How long to wait on the battery characteristic before giving up.
+static BATTERY_READ_TIMEOUT: Duration = Duration::from_secs(5);
+static LIMITS: ChannelLimits = ChannelLimits {
+    protocol: "dg-lab-v2",
+    maximum_power: MAXIMUM_POWER,
+    waveform_name: "Pulse width",
+    maximum_waveform: MAXIMUM_PULSE_WIDTH,
+    maximum_relative_power: None,
+};
 
 /// AAAA AAAA AAAB BBBB BBBB BB00
+///
+/// Values are saturated rather than masked (see [ChannelLimits::saturate]), as masking wraps power
+/// just over the maximum around to almost nothing, or the other way around.
 fn ab_power_to_byte(a: u32, b: u32) -> Vec<u8> {
-    let data = 0 | ((b & 0x7FF) << 11) | (a & 0x7FF);
+    let a = LIMITS.saturate("power", a, MAXIMUM_POWER);
+    let b = LIMITS.saturate("power", b, MAXIMUM_POWER);
+    let data = 0 | (b << 11) | a;
     return vec![
         (data & 0xFF) as u8,
         ((data >> 8) & 0xFF) as u8,
@@ -45,14 +58,19 @@ fn ab_power_to_byte(a: u32, b: u32) -> Vec<u8> {
 fn frequency_to_xy(frequency: u32) -> (u32, u32) {
     let mut x = (frequency as f32 / 1000f32).sqrt() * 15f32;
     let mut y = frequency as f32 - x;
-    if x > MAXIMUM_X { x = MAXIMUM_X }
-    if y > MAXIMUM_Y { y = MAXIMUM_Y }
+    if x > MAXIMUM_X as f32 { x = MAXIMUM_X as f32 }
+    if y > MAXIMUM_Y as f32 { y = MAXIMUM_Y as f32 }
     return (x.round() as u32, y.round() as u32);
 }
 
 /// XXXX XYYY YYYY YYYZ ZZZZ 0000
+///
+/// Values are saturated like in [ab_power_to_byte].
 fn xyz_to_bytes(x: u32, y: u32, z: u32) -> Vec<u8> {
-    let data = 0 | ((z & 0x1F) << 15) | ((y & 0x3FF) << 5) | (x & 0x1F);
+    let x = LIMITS.saturate("frequency X", x, MAXIMUM_X);
+    let y = LIMITS.saturate("frequency Y", y, MAXIMUM_Y);
+    let z = LIMITS.saturate("pulse width", z, MAXIMUM_PULSE_WIDTH);
+    let data = 0 | (z << 15) | (y << 5) | x;
     return vec![
         (data & 0xFF) as u8,
         ((data >> 8) & 0xFF) as u8,
@@ -60,31 +78,36 @@ fn xyz_to_bytes(x: u32, y: u32, z: u32) -> Vec<u8> {
     ];
 }
 
+/// Pulse width scales with power in simple mode
+fn simple_mode_pulse_width(power: u32) -> u32 {
+    (power as f32 / MAXIMUM_POWER as f32 * MAXIMUM_PULSE_WIDTH as f32).round() as u32
+}
+
 fn commands_vec_by_struct(dg_lab_v2: &DGLabV2) -> Vec<HardwareWriteCmd> {
     vec![
         HardwareWriteCmd::new(
             Endpoint::Tx,
             ab_power_to_byte(
-                dg_lab_v2.a_scalar.power.load(SeqCst),
-                dg_lab_v2.b_scalar.power.load(SeqCst),
+                dg_lab_v2.channels.a().power.load(SeqCst),
+                dg_lab_v2.channels.b().power.load(SeqCst),
             ),
             false,
         ),
         HardwareWriteCmd::new(
             Endpoint::Generic0,
             xyz_to_bytes(
-                dg_lab_v2.a_scalar.xy.0.load(SeqCst),
-                dg_lab_v2.a_scalar.xy.1.load(SeqCst),
-                dg_lab_v2.a_scalar.pulse_width.load(SeqCst),
+                dg_lab_v2.channels.a().xy.0.load(SeqCst),
+                dg_lab_v2.channels.a().xy.1.load(SeqCst),
+                dg_lab_v2.channels.a().pulse_width.load(SeqCst),
             ),
             false,
         ),
         HardwareWriteCmd::new(
             Endpoint::Generic1,
             xyz_to_bytes(
-                dg_lab_v2.b_scalar.xy.0.load(SeqCst),
-                dg_lab_v2.b_scalar.xy.1.load(SeqCst),
-                dg_lab_v2.b_scalar.pulse_width.load(SeqCst),
+                dg_lab_v2.channels.b().xy.0.load(SeqCst),
+                dg_lab_v2.channels.b().xy.1.load(SeqCst),
+                dg_lab_v2.channels.b().pulse_width.load(SeqCst),
             ),
             false,
         ),
@@ -100,8 +123,14 @@ struct ChannelScalar {
 
 #[derive(Default)]
 pub struct DGLabV2 {
-    a_scalar: Arc<ChannelScalar>,
-    b_scalar: Arc<ChannelScalar>,
+    channels: DualChannelState<ChannelScalar>,
+    // Simple mode only exposes channel power, and derives frequency and pulse width from it, so
+    // generic apps that only know about vibration still produce a sensation.
+    simple_mode: bool,
+    // Set if the user config swaps channels, see Channel::routed.
+    swap_channels: bool,
+    // Cancelled on shutdown, to halt the repeat loop.
+    background_tasks: CancellationToken,
 }
 
 generic_protocol_initializer_setup!(DGLabV2, "dg-lab-v2");
@@ -114,142 +143,272 @@ impl ProtocolInitializer for DGLabV2Initializer {
     async fn initialize(
         &mut self,
         hardware: Arc<Hardware>,
-        _: &ProtocolDeviceAttributes,
+        attributes: &ProtocolDeviceAttributes,
     ) -> Result<Arc<dyn ProtocolHandler>, ButtplugDeviceError> {
-        let handler = Arc::new(DGLabV2::default());
-        let handler_copy = handler.clone();
-        let _ = async_manager::spawn(async move {
-            let duration = Duration::from_millis(REPEAT_SLEEP_DURATION);
-            // Wait until test finished, or it would cause failure of test (The order of HardwareCmd changed)
-            // TODO: Maybe there's a better way to solve this
-            util::sleep(Duration::from_millis(WAIT_UNTIL_TEST_DURATION)).await;
-            loop {
-                for cmd in &commands_vec_by_struct(&handler_copy)[1..] {
-                    if let Err(e) = hardware.write_value(&cmd).await {
-                        warn!("Error writing repeat packet: {:?}", e);
-                    }
-                }
-                util::sleep(duration).await;
-            }
+        LIMITS.check_feature_layout(attributes, true)?;
+        // If the device config doesn't expose frequency or pulse width (e.g. the "simple"
+        // configuration), we're in simple mode.
+        let simple_mode = attributes
+            .message_attributes()
+            .scalar_cmd()
+            .as_ref()
+            .is_none_or(|scalars| scalars.iter().all(|x| *x.actuator_type() == ActuatorType::Vibrate));
+        let handler = Arc::new(DGLabV2 {
+            simple_mode,
+            swap_channels: attributes.swap_channels(),
+            ..Default::default()
         });
+        let handler_copy = handler.clone();
+        // Power is only written on change, the repeats only carry the channel waveforms
+        spawn_keepalive(hardware, handler.background_tasks.clone(), move || commands_vec_by_struct(&handler_copy).split_off(1));
         Ok(handler)
     }
+
+    fn required_endpoints(&self) -> Vec<Endpoint> {
+        vec![Endpoint::Tx, Endpoint::Generic0, Endpoint::Generic1]
+    }
+
+    // Configs with only the power features are simple mode.
+    fn scalar_feature_layouts(&self, _identifier: &UserDeviceIdentifier) -> Vec<Vec<ActuatorType>> {
+        LIMITS.feature_layouts(true)
+    }
 }
 
 impl ProtocolHandler for DGLabV2 {
+    // The repeat loop keeps sending whatever is stored, so zero everything out and write it once
+    // right away, rather than waiting on the next repeat.
+    fn handle_client_disconnect(&self) -> Result<Vec<HardwareCommand>, ButtplugDeviceError> {
+        for channel in self.channels.both() {
+            channel.power.store(0, SeqCst);
+            channel.xy.0.store(0, SeqCst);
+            channel.xy.1.store(0, SeqCst);
+            channel.pulse_width.store(0, SeqCst);
+        }
+        Ok(vec![HardwareCommand::Batch(commands_vec_by_struct(self))])
+    }
+
+    // Only called once the zeroed state was written and the hardware disconnected.
+    fn handle_shutdown(&self) {
+        self.background_tasks.cancel();
+    }
+
+    // What the repeat loop is sending. Frequency is reported as the X and Y values it's written as.
+    fn state_snapshot(&self) -> Option<serde_json::Value> {
+        let channel = |channel: &ChannelScalar| {
+            serde_json::json!({
+                "power": channel.power.load(SeqCst),
+                "maximum_power": MAXIMUM_POWER,
+                "x": channel.xy.0.load(SeqCst),
+                "y": channel.xy.1.load(SeqCst),
+                "pulse_width": channel.pulse_width.load(SeqCst),
+            })
+        };
+        Some(serde_json::json!({
+            "a": channel(self.channels.a()),
+            "b": channel(self.channels.b()),
+        }))
+    }
+
     fn handle_scalar_cmd(&self, commands: &[Option<(ActuatorType, u32)>]) -> Result<Vec<HardwareCommand>, ButtplugDeviceError> {
-        // Power A (S)
-        let power_a_scalar = self.a_scalar.power.clone();
-        // Power B (S)
-        let power_b_scalar = self.b_scalar.power.clone();
-        // Frequency A (X, Y)
-        let xy_a_scalar = self.a_scalar.xy.clone();
-        // Frequency B (X, Y)
-        let xy_b_scalar = self.b_scalar.xy.clone();
-        // Pulse width A (Z)
-        let pulse_width_a_scalar = self.a_scalar.pulse_width.clone();
-        // Pulse width B (Z)
-        let pulse_width_b_scalar = self.b_scalar.pulse_width.clone();
-        for (index, command) in commands.iter().enumerate().filter(|(_, x)| x.is_some()) {
-            let (actuator, mut scalar) = command.as_ref().expect("Already verified existence");
-            match *actuator {
+        for update in LIMITS.channel_updates(commands)? {
+            let channel = self.channels.channel(update.channel.routed(self.swap_channels));
+            match update.role {
                 // Set power (S)
-                ActuatorType::Vibrate => {
-                    if scalar > MAXIMUM_POWER {
-                        return Err(
-                            ProtocolSpecificError(
-                                "dg-lab-v2".to_owned(),
-                                format!("Power scalar {} not in [0, {}]", scalar, MAXIMUM_POWER),
-                            )
-                        );
-                    }
-                    match index {
-                        // Channel A
-                        0 => { power_a_scalar.store(scalar, SeqCst); }
-                        // Channel B
-                        1 => { power_b_scalar.store(scalar, SeqCst); }
-                        _ => {
-                            return Err(
-                                ProtocolSpecificError(
-                                    "dg-lab-v2".to_owned(),
-                                    format!("Vibrate command index {} is invalid", index),
-                                )
-                            );
-                        }
+                ChannelRole::Power => {
+                    channel.power.store(update.value, SeqCst);
+                    if self.simple_mode {
+                        let (x_scalar, y_scalar) = frequency_to_xy(SIMPLE_MODE_FREQUENCY);
+                        channel.xy.0.store(x_scalar, SeqCst);
+                        channel.xy.1.store(y_scalar, SeqCst);
+                        channel.pulse_width.store(simple_mode_pulse_width(update.value), SeqCst);
                     }
                 }
                 // Set frequency (X, Y)
-                ActuatorType::Oscillate => {
-                    if scalar == MINIMUM_FREQUENCY - 1 {
-                        scalar = 0
-                    } else if scalar != 0 && (scalar < MINIMUM_FREQUENCY || scalar > MAXIMUM_FREQUENCY) {
-                        return Err(
-                            ProtocolSpecificError(
-                                "dg-lab-v2".to_owned(),
-                                format!("Frequency scalar {} not in [{}, {}]", scalar, MINIMUM_FREQUENCY, MAXIMUM_FREQUENCY),
-                            )
-                        );
-                    }
-                    match index {
-                        // Channel A
-                        2 => {
-                            let (x_scalar, y_scalar) = frequency_to_xy(scalar);
-                            xy_a_scalar.0.store(x_scalar, SeqCst);
-                            xy_a_scalar.1.store(y_scalar, SeqCst);
-                        }
-                        // Channel B
-                        3 => {
-                            let (x_scalar, y_scalar) = frequency_to_xy(scalar);
-                            xy_b_scalar.0.store(x_scalar, SeqCst);
-                            xy_b_scalar.1.store(y_scalar, SeqCst);
-                        }
-                        _ => {
-                            return Err(
-                                ProtocolSpecificError(
-                                    "dg-lab-v2".to_owned(),
-                                    format!("Oscillate command index {} is invalid", index),
-                                )
-                            );
-                        }
-                    }
+                ChannelRole::Frequency => {
+                    let (x_scalar, y_scalar) = frequency_to_xy(update.value);
+                    channel.xy.0.store(x_scalar, SeqCst);
+                    channel.xy.1.store(y_scalar, SeqCst);
                 }
                 // Set pulse width (Z)
-                ActuatorType::Inflate => {
-                    if scalar > MAXIMUM_PULSE_WIDTH {
-                        return Err(
-                            ProtocolSpecificError(
-                                "dg-lab-v2".to_owned(),
-                                format!("Pulse width scalar {} not in [0, {}]", scalar, MAXIMUM_PULSE_WIDTH),
-                            )
-                        );
-                    }
-                    match index {
-                        // Channel A
-                        4 => { pulse_width_a_scalar.store(scalar, SeqCst); }
-                        // Channel B
-                        5 => { pulse_width_b_scalar.store(scalar, SeqCst); }
-                        _ => {
-                            return Err(
-                                ProtocolSpecificError(
-                                    "dg-lab-v2".to_owned(),
-                                    format!("Inflate command index {} is invalid", index),
-                                )
-                            );
-                        }
-                    }
-                }
-                _ => {
-                    return Err(ButtplugDeviceError::UnhandledCommand(
-                        "Unknown actuator types are not controllable.".to_owned(),
-                    ));
+                ChannelRole::Waveform => {
+                    channel.pulse_width.store(update.value, SeqCst);
                 }
+                ChannelRole::RelativePower => unreachable!("V2 has no relative power features"),
             }
         }
-        Ok(
-            commands_vec_by_struct(self)
-                .into_iter()
-                .map(|cmd| HardwareCommand::from(cmd))
-                .collect()
+        // One batch, so no keepalive or other command write lands between power and waveforms,
+        // and the device never sees a mix of old and new values.
+        Ok(vec![HardwareCommand::Batch(commands_vec_by_struct(self))])
+    }
+
+    // The box reports its charge as a single percentage byte on the battery characteristic, which
+    // can be read directly.
+    fn handle_battery_level_cmd(
+        &self,
+        device: Arc<Hardware>,
+        message: message::SensorReadCmd,
+    ) -> BoxFuture<'_, Result<ButtplugServerMessage, ButtplugDeviceError>> {
+        let mut device_events = device.event_stream();
+        let read = device.read_value(&HardwareReadCmd::new(Endpoint::RxBLEBattery, 1, 0));
+        async move {
+            let disconnected = async move {
+                loop {
+                    match device_events.recv().await {
+                        Ok(HardwareEvent::Disconnected(_)) | Err(RecvError::Closed) => return,
+                        _ => continue,
+                    }
+                }
+            };
+            let reading = select! {
+                reading = read.fuse() => reading?,
+                _ = disconnected.fuse() => return Err(ButtplugDeviceError::ProtocolSpecificError(
+                    "dg-lab-v2".to_owned(),
+                    "Device disconnected while reading battery level.".to_owned(),
+                )),
+                _ = crate::util::sleep(BATTERY_READ_TIMEOUT).fuse() => return Err(ButtplugDeviceError::ProtocolSpecificError(
+                    "dg-lab-v2".to_owned(),
+                    format!("Device did not report battery level within {:?}.", BATTERY_READ_TIMEOUT),
+                )),
+            };
+            let battery_level = match reading.data().first() {
+                Some(level) if *level <= 100 => *level as i32,
+                _ => return Err(ButtplugDeviceError::DeviceCommunicationError(format!(
+                    "Unexpected battery data from DG-Lab V2: {:?}",
+                    reading.data()
+                ))),
+            };
+            debug!("Got battery reading: {}", battery_level);
+            Ok(message::SensorReading::new(
+                message.device_index(),
+                *message.sensor_index(),
+                *message.sensor_type(),
+                vec![battery_level],
+            )
+            .into())
+        }
+        .boxed()
+    }
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::device::protocol::dg_lab::frequency::MAXIMUM_INPUT_FREQUENCY;
+    use crate::server::device::protocol::scalar_pipeline_sim::ScalarPipelineSim;
+
+    fn channels(values: [f64; 6]) -> Vec<(u32, ActuatorType, f64)> {
+        let actuators = [ActuatorType::Vibrate, ActuatorType::Oscillate, ActuatorType::Inflate];
+        (0..6).map(|index| (index as u32, actuators[index / 2], values[index])).collect()
+    }
+
+    fn writes(tx: [u8; 3], generic0: [u8; 3], generic1: [u8; 3]) -> Vec<HardwareCommand> {
+        vec![HardwareCommand::Batch(vec![
+            HardwareWriteCmd::new(Endpoint::Tx, tx.to_vec(), false),
+            HardwareWriteCmd::new(Endpoint::Generic0, generic0.to_vec(), false),
+            HardwareWriteCmd::new(Endpoint::Generic1, generic1.to_vec(), false),
+        ])]
+    }
+
+    #[test]
+    pub fn test_scalar_pipeline() {
+        let sim = ScalarPipelineSim::new(
+            "dg-lab-v2",
+            Some("D-LAB ESTIM01"),
+            Arc::new(DGLabV2::default()),
         )
+        .expect("Test, assuming infallible.");
+        let cases = [
+            ([0.0; 6], writes([0x00, 0x00, 0x00], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00])),
+            (
+                [0.5, 0.5, 0.0, 0.0, 0.0, 0.0],
+                writes([0x00, 0x04, 0x20], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00]),
+            ),
+            ([1.0; 6], writes([0xFF, 0xFF, 0x3F], [0x2F, 0xFB, 0x0F], [0x2F, 0xFB, 0x0F])),
+        ];
+        for (values, expected) in cases {
+            let commands = sim.scalar(&channels(values)).expect("Test, assuming infallible.");
+            assert_eq!(commands, expected);
+        }
+        // Nothing changed, so nothing is written.
+        assert!(sim.scalar(&channels([1.0; 6])).expect("Test, assuming infallible.").is_empty());
+        assert_eq!(
+            sim.stop().expect("Test, assuming infallible."),
+            writes([0x00, 0x00, 0x00], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00])
+        );
+    }
+
+    #[test]
+    pub fn test_swap_channels() {
+        let sim = ScalarPipelineSim::new(
+            "dg-lab-v2",
+            Some("D-LAB ESTIM01"),
+            Arc::new(DGLabV2 {
+                swap_channels: true,
+                ..Default::default()
+            }),
+        )
+        .expect("Test, assuming infallible.");
+        // Feature 0 is channel A power, which ends up in the channel B bits. Channel A waveform
+        // features drive the Generic1 (channel B) characteristic.
+        let commands = sim
+            .scalar(&channels([1.0, 0.0, 1.0, 0.0, 1.0, 0.0]))
+            .expect("Test, assuming infallible.");
+        assert_eq!(
+            commands,
+            writes([0x00, 0xF8, 0x3F], [0x00, 0x00, 0x00], [0x2F, 0xFB, 0x0F])
+        );
+    }
+
+    #[test]
+    pub fn test_ab_power_to_byte() {
+        assert_eq!(ab_power_to_byte(0, 0), vec![0, 0, 0]);
+        assert_eq!(ab_power_to_byte(MAXIMUM_POWER, 0), vec![0xFF, 0x07, 0x00]);
+        assert_eq!(ab_power_to_byte(0, MAXIMUM_POWER), vec![0x00, 0xF8, 0x3F]);
+        assert_eq!(ab_power_to_byte(MAXIMUM_POWER, MAXIMUM_POWER), vec![0xFF, 0xFF, 0x3F]);
+        // Over range power saturates, instead of wrapping around to 0 for 2048.
+        assert_eq!(ab_power_to_byte(MAXIMUM_POWER + 1, 0), ab_power_to_byte(MAXIMUM_POWER, 0));
+        assert_eq!(ab_power_to_byte(0, u32::MAX), ab_power_to_byte(0, MAXIMUM_POWER));
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn test_xyz_to_bytes() {
+        assert_eq!(xyz_to_bytes(0, 0, 0), vec![0, 0, 0]);
+        assert_eq!(xyz_to_bytes(MAXIMUM_X, 0, 0), vec![0x1F, 0x00, 0x00]);
+        assert_eq!(xyz_to_bytes(0, MAXIMUM_Y, 0), vec![0xE0, 0x7F, 0x00]);
+        assert_eq!(xyz_to_bytes(0, 0, MAXIMUM_PULSE_WIDTH), vec![0x00, 0x80, 0x0F]);
+        assert_eq!(
+            xyz_to_bytes(MAXIMUM_X + 1, MAXIMUM_Y + 1, MAXIMUM_PULSE_WIDTH + 1),
+            xyz_to_bytes(MAXIMUM_X, MAXIMUM_Y, MAXIMUM_PULSE_WIDTH)
+        );
+        assert_eq!(xyz_to_bytes(u32::MAX, 0, 0), vec![0x1F, 0x00, 0x00]);
+        // Nothing ever spills into the unused top bits.
+        assert_eq!(xyz_to_bytes(u32::MAX, u32::MAX, u32::MAX)[2] & 0xF0, 0);
+    }
+
+    #[test]
+    pub fn test_state_snapshot() {
+        let handler = Arc::new(DGLabV2::default());
+        let sim = ScalarPipelineSim::new("dg-lab-v2", Some("D-LAB ESTIM01"), handler.clone())
+            .expect("Test, assuming infallible.");
+        sim.scalar(&channels([1.0, 0.0, 1.0, 0.0, 1.0, 0.0])).expect("Test, assuming infallible.");
+        let (x, y) = frequency_to_xy(MAXIMUM_INPUT_FREQUENCY);
+        assert_eq!(
+            handler.state_snapshot(),
+            Some(serde_json::json!({
+                "a": {
+                    "power": MAXIMUM_POWER,
+                    "maximum_power": MAXIMUM_POWER,
+                    "x": x,
+                    "y": y,
+                    "pulse_width": MAXIMUM_PULSE_WIDTH,
+                },
+                "b": {
+                    "power": 0,
+                    "maximum_power": MAXIMUM_POWER,
+                    "x": 0,
+                    "y": 0,
+                    "pulse_width": 0,
+                },
+            }))
+        );
+    }
+}
@@ -5,40 +5,78 @@
 // Licensed under the BSD 3-Clause license. See LICENSE file in the project root
 // for full license information.
 
-use std::sync::Arc;
-use std::sync::atomic::AtomicU32;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32};
 use std::sync::atomic::Ordering::SeqCst;
 use std::time::Duration;
 
 use async_trait::async_trait;
+use futures::future::{self, FutureExt};
+use tokio_util::sync::CancellationToken;
 
-use crate::{core::errors::ButtplugDeviceError, generic_protocol_initializer_setup, server::device::protocol::ProtocolHandler, util};
+use crate::{core::errors::ButtplugDeviceError, generic_protocol_initializer_setup, server::device::protocol::ProtocolHandler};
 use crate::core::errors::ButtplugDeviceError::ProtocolSpecificError;
-use crate::core::message::{ActuatorType, Endpoint};
+use crate::core::message::{ActuatorType, Endpoint, LinearCmd};
 use crate::server::device::configuration::ProtocolDeviceAttributes;
-use crate::server::device::hardware::{Hardware, HardwareCommand, HardwareWriteCmd};
+use crate::server::device::hardware::{
+    Hardware,
+    HardwareCommand,
+    HardwareEvent,
+    HardwareReadCmd,
+    HardwareSubscribeCmd,
+    HardwareWriteCmd,
+};
+use crate::server::device::protocol::dg_lab::{
+    spawn_keepalive,
+    Channel,
+    ChannelLimits,
+    ChannelRole,
+    DualChannelState,
+};
+use crate::server::device::protocol::generic_command_manager::ScalarCommandContext;
+use crate::server::device::protocol::linear_ramp::{spawn_linear_ramp, LinearRamps};
 use crate::server::device::protocol::ProtocolIdentifier;
 use crate::server::device::protocol::ProtocolInitializer;
 use crate::server::device::protocol::UserDeviceIdentifier;
 use crate::util::async_manager;
 
-static MINIMUM_INPUT_FREQUENCY: u32 = 10;
-static MAXIMUM_INPUT_FREQUENCY: u32 = 1000;
 static MAXIMUM_POWER: u32 = 200;
 static MAXIMUM_WAVEFORM_STRENGTH: u32 = 100;
+// Largest frequency byte the device takes, and the most input_to_frequency produces.
+static MAXIMUM_FREQUENCY: u32 = 240;
 static B0_HEAD: u8 = 0xB0;
+static B1_HEAD: u8 = 0xB1;
+static B1_LENGTH: usize = 4;
 #[allow(dead_code)]
 static BF_HEAD: u8 = 0xBF;
+// Only used by repeat packets, so their responses can be told apart from ones to commands.
 static DEFAULT_SERIAL_NO: u8 = 0b0000;
-#[allow(dead_code)]
+static MAXIMUM_SERIAL_NO: u8 = 0b1111;
 static STRENGTH_PARSING_METHOD_NONE: u8 = 0b00;
-#[allow(dead_code)]
 static STRENGTH_PARSING_METHOD_INCREASE: u8 = 0b01;
-#[allow(dead_code)]
 static STRENGTH_PARSING_METHOD_DECREASE: u8 = 0b10;
 static STRENGTH_PARSING_METHOD_SET_TO: u8 = 0b11;
-static REPEAT_SLEEP_DURATION: u64 = 100;
-static WAIT_UNTIL_TEST_DURATION: u64 = 500;
+static PATTERN_STEP_DURATION: u64 = 20;
+// The soft limit characteristic is an optional endpoint in the device config, devices without it
+// keep the maximum power.
+static SOFT_LIMIT_ENDPOINT: Endpoint = Endpoint::Generic0;
+static SOFT_LIMIT_LENGTH: u32 = 2;
+static SOFT_LIMIT_READ_TIMEOUT_MS: u32 = 500;
+// The device ID (the Device Information serial number string) is optional too. Devices without
+// it are only known by address.
+static DEVICE_ID_ENDPOINT: Endpoint = Endpoint::Generic1;
+static DEVICE_ID_LENGTH: u32 = 32;
+static DEVICE_ID_READ_TIMEOUT_MS: u32 = 500;
+// Relative power features use a step range of [0, 2 * MAXIMUM_POWER], centered on no change.
+static RELATIVE_POWER_ZERO: u32 = MAXIMUM_POWER;
+static LIMITS: ChannelLimits = ChannelLimits {
+    protocol: "dg-lab-v3",
+    maximum_power: MAXIMUM_POWER,
+    waveform_name: "Waveform strength",
+    maximum_waveform: MAXIMUM_WAVEFORM_STRENGTH,
+    maximum_relative_power: Some(RELATIVE_POWER_ZERO * 2),
+};
 
 fn input_to_frequency(value: u32) -> u32 {
     match value {
@@ -50,9 +88,52 @@ fn input_to_frequency(value: u32) -> u32 {
     }
 }
 
-fn b0_set_command(
-    power_a: u32,
-    power_b: u32,
+/// How a B0 packet changes the strength of a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StrengthChange {
+    Keep,
+    Increase(u32),
+    Decrease(u32),
+    SetTo(u32),
+}
+
+impl StrengthChange {
+    fn relative(delta: i64) -> Self {
+        match delta {
+            0 => StrengthChange::Keep,
+            d if d > 0 => StrengthChange::Increase(d as u32),
+            d => StrengthChange::Decrease(d.unsigned_abs() as u32),
+        }
+    }
+
+    fn parsing_method(&self) -> u8 {
+        match self {
+            StrengthChange::Keep => STRENGTH_PARSING_METHOD_NONE,
+            StrengthChange::Increase(_) => STRENGTH_PARSING_METHOD_INCREASE,
+            StrengthChange::Decrease(_) => STRENGTH_PARSING_METHOD_DECREASE,
+            StrengthChange::SetTo(_) => STRENGTH_PARSING_METHOD_SET_TO,
+        }
+    }
+
+    fn value(&self) -> u32 {
+        match self {
+            StrengthChange::Keep => 0,
+            StrengthChange::Increase(value) | StrengthChange::Decrease(value) | StrengthChange::SetTo(value) => *value,
+        }
+    }
+}
+
+/// Packet byte for a channel value, saturated to `maximum` (see [ChannelLimits::saturate]).
+fn b0_byte(name: &str, value: u32, maximum: u32) -> u8 {
+    LIMITS.saturate(name, value, maximum) as u8
+}
+
+/// Strength changes are limited to MAXIMUM_POWER whether they're absolute or relative, as relative
+/// changes come from the [0, 2 * MAXIMUM_POWER] relative power range.
+fn b0_command(
+    serial_no: u8,
+    strength_a: StrengthChange,
+    strength_b: StrengthChange,
     frequency_a: [u32; 4],
     frequency_b: [u32; 4],
     waveform_strength_a: [u32; 4],
@@ -60,186 +141,858 @@ fn b0_set_command(
 ) -> Vec<u8> {
     let mut data: Vec<u8> = vec![
         B0_HEAD,
-        (DEFAULT_SERIAL_NO << 4) | (STRENGTH_PARSING_METHOD_SET_TO << 2) | STRENGTH_PARSING_METHOD_SET_TO,
-        power_a as u8,
-        power_b as u8,
+        ((serial_no & MAXIMUM_SERIAL_NO) << 4) | (strength_a.parsing_method() << 2) | strength_b.parsing_method(),
+        b0_byte("strength", strength_a.value(), MAXIMUM_POWER),
+        b0_byte("strength", strength_b.value(), MAXIMUM_POWER),
     ];
-    data.extend(frequency_a.iter().map(|&x| x as u8));
-    data.extend(waveform_strength_a.iter().map(|&x| x as u8));
-    data.extend(frequency_b.iter().map(|&x| x as u8));
-    data.extend(waveform_strength_b.iter().map(|&x| x as u8));
+    data.extend(frequency_a.iter().map(|&x| b0_byte("frequency", x, MAXIMUM_FREQUENCY)));
+    data.extend(waveform_strength_a.iter().map(|&x| b0_byte("waveform strength", x, MAXIMUM_WAVEFORM_STRENGTH)));
+    data.extend(frequency_b.iter().map(|&x| b0_byte("frequency", x, MAXIMUM_FREQUENCY)));
+    data.extend(waveform_strength_b.iter().map(|&x| b0_byte("waveform strength", x, MAXIMUM_WAVEFORM_STRENGTH)));
     return data;
 }
 
-fn b0_set_command_by_struct(dg_lab_v3: &DGLabV3) -> Vec<u8> {
-    b0_set_command(
-        dg_lab_v3.a_scalar.power.load(SeqCst),
-        dg_lab_v3.b_scalar.power.load(SeqCst),
-        [dg_lab_v3.a_scalar.frequency.load(SeqCst); 4],
-        [dg_lab_v3.b_scalar.frequency.load(SeqCst); 4],
-        [dg_lab_v3.a_scalar.waveform_strength.load(SeqCst); 4],
-        [dg_lab_v3.b_scalar.waveform_strength.load(SeqCst); 4],
+fn b0_command_by_struct(dg_lab_v3: &DGLabV3, serial_no: u8) -> Vec<u8> {
+    b0_command_with_strength(
+        dg_lab_v3,
+        serial_no,
+        dg_lab_v3.channels.a().strength_change(),
+        dg_lab_v3.channels.b().strength_change(),
     )
 }
 
+fn b0_command_with_strength(
+    dg_lab_v3: &DGLabV3,
+    serial_no: u8,
+    strength_a: StrengthChange,
+    strength_b: StrengthChange,
+) -> Vec<u8> {
+    b0_command(
+        serial_no,
+        strength_a,
+        strength_b,
+        [dg_lab_v3.channels.a().frequency.load(SeqCst); 4],
+        [dg_lab_v3.channels.b().frequency.load(SeqCst); 4],
+        [dg_lab_v3.channels.a().waveform_strength.load(SeqCst); 4],
+        [dg_lab_v3.channels.b().waveform_strength.load(SeqCst); 4],
+    )
+}
+
+/// Packets setting both channels to zero power are what stops output, so they're written with
+/// response. Losing one of those would leave the device running until the next repeat.
+fn is_stop_packet(data: &[u8]) -> bool {
+    let set_to_zero = |shift: u8, value: u8| {
+        (data[1] >> shift) & 0b11 == STRENGTH_PARSING_METHOD_SET_TO && value == 0
+    };
+    set_to_zero(2, data[2]) && set_to_zero(0, data[3])
+}
+
+fn b0_write_cmd(data: Vec<u8>) -> HardwareWriteCmd {
+    let write_with_response = is_stop_packet(&data);
+    HardwareWriteCmd::new(Endpoint::Tx, data, write_with_response)
+}
+
+/// B1 response: the serial number being acknowledged, and the actual strength of both channels
+/// 0xB1 SERIAL_NO(1 byte) POWER_A(1 byte) POWER_B(1 byte)
+#[derive(Debug, PartialEq, Eq)]
+struct B1Response {
+    serial_no: u8,
+    power_a: u32,
+    power_b: u32,
+}
+
+fn parse_b1_response(data: &[u8]) -> Option<B1Response> {
+    if data.len() != B1_LENGTH || data[0] != B1_HEAD {
+        return None;
+    }
+    let response = B1Response {
+        serial_no: data[1],
+        power_a: data[2] as u32,
+        power_b: data[3] as u32,
+    };
+    if response.serial_no > MAXIMUM_SERIAL_NO
+        || response.power_a > MAXIMUM_POWER
+        || response.power_b > MAXIMUM_POWER {
+        return None;
+    }
+    Some(response)
+}
+
+/// Serial numbers of the command packets sent, so B1 responses can be matched to the packet they
+/// acknowledge. Packets sent before an acknowledged one, or 15 packets ago, are taken as dropped.
+#[derive(Debug, Default)]
+struct PacketTracker {
+    // Serial number of the last command packet, DEFAULT_SERIAL_NO before the first one
+    serial_no: u8,
+    // Packets waiting on a response, oldest first, and whether they change strength
+    outstanding: VecDeque<(u8, bool)>,
+    sent: u64,
+    acknowledged: u64,
+    dropped: u64,
+}
+
+impl PacketTracker {
+    /// Serial number for a new command packet. Serial numbers go from 1 to 15 and wrap around, so
+    /// a packet still outstanding with the same serial number is dropped.
+    fn next_serial_no(&mut self, changes_strength: bool) -> u8 {
+        self.serial_no = self.serial_no % MAXIMUM_SERIAL_NO + 1;
+        let serial_no = self.serial_no;
+        if let Some(position) = self.outstanding.iter().position(|(outstanding, _)| *outstanding == serial_no) {
+            self.drop_through(position);
+        }
+        self.outstanding.push_back((serial_no, changes_strength));
+        self.sent += 1;
+        serial_no
+    }
+
+    fn drop_through(&mut self, position: usize) {
+        self.dropped += self.outstanding.drain(..=position).count() as u64;
+    }
+
+    /// Match a response to the packet it acknowledges. Returns true if the reported strength is
+    /// current, meaning no strength change sent after the acknowledged packet is still outstanding.
+    /// Responses to repeat packets and changes made on the device have DEFAULT_SERIAL_NO, and
+    /// responses to packets already taken as dropped are stale.
+    fn acknowledge(&mut self, serial_no: u8) -> bool {
+        if serial_no != DEFAULT_SERIAL_NO {
+            match self.outstanding.iter().position(|(outstanding, _)| *outstanding == serial_no) {
+                Some(position) => {
+                    if position > 0 {
+                        self.drop_through(position - 1);
+                    }
+                    self.outstanding.pop_front();
+                    self.acknowledged += 1;
+                }
+                None => return false,
+            }
+        }
+        !self.outstanding.iter().any(|(_, changes_strength)| *changes_strength)
+    }
+
+    fn snapshot(&self) -> serde_json::Value {
+        serde_json::json!({
+            "serial_no": self.serial_no,
+            "sent": self.sent,
+            "acknowledged": self.acknowledged,
+            "dropped": self.dropped,
+            "outstanding": self.outstanding.len(),
+        })
+    }
+}
+
+/// Highest power each channel will output, as set on the device itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PowerCaps([u32; 2]);
+
+impl Default for PowerCaps {
+    fn default() -> Self {
+        PowerCaps([MAXIMUM_POWER; 2])
+    }
+}
+
+impl PowerCaps {
+    fn cap(&self, channel: Channel) -> u32 {
+        self.0[channel.index()]
+    }
+}
+
+/// Soft limits: POWER_LIMIT_A(1 byte) POWER_LIMIT_B(1 byte). Limits above MAXIMUM_POWER are
+/// clamped to it.
+fn parse_soft_limits(data: &[u8]) -> Option<PowerCaps> {
+    if data.len() < SOFT_LIMIT_LENGTH as usize {
+        return None;
+    }
+    Some(PowerCaps([data[0] as u32, data[1] as u32].map(|limit| limit.min(MAXIMUM_POWER))))
+}
+
+/// Read the soft limits of both channels, falling back to MAXIMUM_POWER if they can't be read.
+async fn read_power_caps(hardware: &Hardware) -> PowerCaps {
+    if !hardware.endpoints().contains(&SOFT_LIMIT_ENDPOINT) {
+        info!("DG-Lab V3 soft limit endpoint not mapped, using maximum power of {}", MAXIMUM_POWER);
+        return PowerCaps::default();
+    }
+    let msg = HardwareReadCmd::new(SOFT_LIMIT_ENDPOINT, SOFT_LIMIT_LENGTH, SOFT_LIMIT_READ_TIMEOUT_MS);
+    match hardware.read_value(&msg).await {
+        Ok(reading) => parse_soft_limits(reading.data()).unwrap_or_else(|| {
+            warn!("Malformed DG-Lab V3 soft limits {:?}, using maximum power of {}", reading.data(), MAXIMUM_POWER);
+            PowerCaps::default()
+        }),
+        Err(e) => {
+            warn!("Error reading DG-Lab V3 soft limits, using maximum power of {}: {:?}", MAXIMUM_POWER, e);
+            PowerCaps::default()
+        }
+    }
+}
+
+/// Device ID: an ASCII string, possibly NUL padded. Empty IDs are treated as missing.
+fn parse_device_id(data: &[u8]) -> Option<String> {
+    let id = String::from_utf8_lossy(data);
+    let id = id.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    (!id.is_empty()).then(|| id.to_owned())
+}
+
+/// Read the device ID, used as the stable id of the device, if the device has one.
+async fn read_device_id(hardware: &Hardware) -> Option<String> {
+    if !hardware.endpoints().contains(&DEVICE_ID_ENDPOINT) {
+        info!("DG-Lab V3 device ID endpoint not mapped, device is only known by address");
+        return None;
+    }
+    let msg = HardwareReadCmd::new(DEVICE_ID_ENDPOINT, DEVICE_ID_LENGTH, DEVICE_ID_READ_TIMEOUT_MS);
+    match hardware.read_value(&msg).await {
+        Ok(reading) => parse_device_id(reading.data()),
+        Err(e) => {
+            warn!("Error reading DG-Lab V3 device ID, device is only known by address: {:?}", e);
+            None
+        }
+    }
+}
+
 #[derive(Default)]
 struct ChannelScalar {
     power: Arc<AtomicU32>,
     frequency: Arc<AtomicU32>,
     waveform_strength: Arc<AtomicU32>,
+    // Power set by a running pattern, used instead of `power` while `pattern_active` is set
+    pattern_power: Arc<AtomicU32>,
+    pattern_active: Arc<AtomicBool>,
+    // Set while a relative strength change is waiting on the device to report the resulting
+    // strength. Strength isn't sent for the channel until then, so repeats don't undo the change.
+    relative_pending: Arc<AtomicBool>,
+}
+
+impl ChannelScalar {
+    fn output_power(&self) -> u32 {
+        if self.pattern_active.load(SeqCst) {
+            self.pattern_power.load(SeqCst)
+        } else {
+            self.power.load(SeqCst)
+        }
+    }
+
+    fn strength_change(&self) -> StrengthChange {
+        if !self.pattern_active.load(SeqCst) && self.relative_pending.load(SeqCst) {
+            StrengthChange::Keep
+        } else {
+            StrengthChange::SetTo(self.output_power())
+        }
+    }
+}
+
+/// Ramps the power of a channel from its current output to `target` over `duration`. The repeat
+/// loop picks up the changes, so nothing needs to be written here. Stops as soon as `cancel` is
+/// cancelled, which happens when a new ramp starts or a ScalarCmd is received. Each step is also
+/// stored as the channel's power, so whatever comes after the ramp carries on from the power it
+/// reached rather than jumping back to the power set before it.
+fn start_power_ramp(
+    channel: Arc<ChannelScalar>,
+    cancel: CancellationToken,
+    target: u32,
+    duration: Duration,
+) {
+    let start = channel.output_power();
+    channel.pattern_power.store(start, SeqCst);
+    channel.pattern_active.store(true, SeqCst);
+    spawn_linear_ramp(
+        cancel,
+        start,
+        target,
+        duration,
+        Duration::from_millis(PATTERN_STEP_DURATION),
+        move |power| {
+            channel.pattern_power.store(power, SeqCst);
+            channel.power.store(power, SeqCst);
+            // Once the target is reached, the device is the source of truth again.
+            if power == target {
+                channel.pattern_active.store(false, SeqCst);
+            }
+            future::ready(Ok(())).boxed()
+        },
+    );
 }
 
 generic_protocol_initializer_setup!(DGLabV3, "dg-lab-v3");
 
 #[derive(Default)]
-pub struct DGLabV3Initializer {}
+pub struct DGLabV3Initializer {
+    // Only set if the soft limits were read from the device
+    power_caps: Option<PowerCaps>,
+    swap_channels: bool,
+    // Only set if the device ID was read from the device
+    device_id: Option<String>,
+}
 
 #[async_trait]
 impl ProtocolInitializer for DGLabV3Initializer {
     async fn initialize(
         &mut self,
         hardware: Arc<Hardware>,
-        _: &ProtocolDeviceAttributes,
+        attributes: &ProtocolDeviceAttributes,
     ) -> Result<Arc<dyn ProtocolHandler>, ButtplugDeviceError> {
-        let handler = Arc::new(DGLabV3::default());
-        let handler_copy = handler.clone();
-        let _ = async_manager::spawn(async move {
-            let duration = Duration::from_millis(REPEAT_SLEEP_DURATION);
-            // Wait until test finished, or it would cause failure of test (The order of HardwareCmd changed)
-            // TODO: Maybe there's a better way to solve this
-            util::sleep(Duration::from_millis(WAIT_UNTIL_TEST_DURATION)).await;
+        LIMITS.check_feature_layout(attributes, false)?;
+        let power_caps = read_power_caps(&hardware).await;
+        self.power_caps = (power_caps != PowerCaps::default()).then_some(power_caps);
+        self.swap_channels = attributes.swap_channels();
+        // Initialize runs again once the device ID is known, no need to read it twice.
+        if self.device_id.is_none() {
+            self.device_id = read_device_id(&hardware).await;
+        }
+        let handler = Arc::new(DGLabV3 {
+            power_caps,
+            swap_channels: self.swap_channels,
+            ..Default::default()
+        });
+        // Listen for B1 responses, so we can stay in sync with the strength the device is actually
+        // outputting.
+        let mut event_receiver = hardware.event_stream();
+        hardware
+            .subscribe(&HardwareSubscribeCmd::new(Endpoint::Rx))
+            .await?;
+        let handler_notify = handler.clone();
+        let background_tasks = handler.background_tasks.clone();
+        async_manager::spawn(async move {
             loop {
-                if let Err(e) = hardware.write_value(
-                    &HardwareWriteCmd::new(
-                        Endpoint::Tx,
-                        b0_set_command_by_struct(&handler_copy),
-                        false,
-                    )
-                ).await {
-                    warn!("Error writing repeat packet: {:?}", e);
+                let event = tokio::select! {
+                    event = event_receiver.recv() => event,
+                    _ = background_tasks.cancelled() => return,
+                };
+                match event {
+                    Ok(HardwareEvent::Notification(_, Endpoint::Rx, data)) => {
+                        match parse_b1_response(&data) {
+                            Some(response) => handler_notify.handle_b1_response(response),
+                            None => warn!("Skipping malformed DG-Lab V3 response: {:?}", data),
+                        }
+                    }
+                    Ok(HardwareEvent::Disconnected(_)) | Err(_) => return,
+                    _ => {}
                 }
-                util::sleep(duration).await;
             }
         });
+        let handler_copy = handler.clone();
+        spawn_keepalive(hardware, handler.background_tasks.clone(), move || {
+            vec![b0_write_cmd(b0_command_by_struct(&handler_copy, DEFAULT_SERIAL_NO))]
+        });
         Ok(handler)
     }
+
+    // Rx carries the B1 responses subscribed to in initialize. The soft limit endpoint is optional,
+    // devices without it just don't report soft limits.
+    fn required_endpoints(&self) -> Vec<Endpoint> {
+        vec![Endpoint::Tx, Endpoint::Rx]
+    }
+
+    // Only known after initialize, which is then run again with the user config stored against it.
+    fn device_serial(&self) -> Option<String> {
+        self.device_id.clone()
+    }
+
+    fn scalar_feature_layouts(&self, _identifier: &UserDeviceIdentifier) -> Vec<Vec<ActuatorType>> {
+        LIMITS.feature_layouts(false)
+    }
+
+    // Power features are indexes 0 (channel A) and 1 (channel B), capped by the soft limit of the
+    // channel they're output on.
+    fn scalar_step_caps(&self) -> HashMap<u32, u32> {
+        let cap = |caps: PowerCaps, channel: Channel| caps.cap(channel.routed(self.swap_channels));
+        self.power_caps
+            .map(|caps| HashMap::from([(0, cap(caps, Channel::A)), (1, cap(caps, Channel::B))]))
+            .unwrap_or_default()
+    }
 }
 
 #[derive(Default)]
 pub struct DGLabV3 {
-    a_scalar: Arc<ChannelScalar>,
-    b_scalar: Arc<ChannelScalar>,
+    channels: DualChannelState<ChannelScalar>,
+    ramps: LinearRamps,
+    // Command packets waiting on B1 responses.
+    packets: Mutex<PacketTracker>,
+    // Soft limits read from the device during initialization. Power is never stored above these,
+    // as the device would clamp it anyway.
+    power_caps: PowerCaps,
+    // Set if the user config swaps channels, see Channel::routed.
+    swap_channels: bool,
+    // Cancelled on shutdown, to halt the repeat loop and the B1 response listener.
+    background_tasks: CancellationToken,
+}
+
+impl DGLabV3 {
+    /// Stop any running patterns, keeping both channels at the power they reached.
+    fn cancel_patterns(&self) {
+        self.ramps.cancel();
+        for channel in self.channels.both() {
+            if channel.pattern_active.swap(false, SeqCst) {
+                channel.power.store(channel.pattern_power.load(SeqCst), SeqCst);
+            }
+        }
+    }
+
+    fn stored_power(&self) -> (u32, u32) {
+        (self.channels.a().power.load(SeqCst), self.channels.b().power.load(SeqCst))
+    }
+
+    fn relative_pending(&self) -> bool {
+        self.channels.both().iter().any(|channel| channel.relative_pending.load(SeqCst))
+    }
+
+    /// Tag a new command packet with the next serial number (1-15), so its B1 response can be told
+    /// apart from ones to other packets.
+    fn next_serial_no(&self, changes_strength: bool) -> u8 {
+        self.packets.lock().expect("Lock poisoned").next_serial_no(changes_strength)
+    }
+
+    /// Resync stored strength with what the device reports. The device is the source of truth, as
+    /// it clamps strength to its own soft limits and can be changed from its own controls, so we
+    /// take on the reported values instead of re-sending ours. Responses are only trusted if no
+    /// strength change sent after the packet they acknowledge is still pending, since they would
+    /// undo it. Channels running a pattern are left alone, the pattern owns their output.
+    fn handle_b1_response(&self, response: B1Response) {
+        if !self.packets.lock().expect("Lock poisoned").acknowledge(response.serial_no) {
+            debug!("Skipping stale DG-Lab V3 response {:?}", response);
+            return;
+        }
+        for (channel, power) in [(self.channels.a(), response.power_a), (self.channels.b(), response.power_b)] {
+            channel.relative_pending.store(false, SeqCst);
+            if channel.pattern_active.load(SeqCst) {
+                continue;
+            }
+            let stored = channel.power.swap(power, SeqCst);
+            if stored != power {
+                info!("DG-Lab V3 reported strength {} instead of {}, resyncing", power, stored);
+            }
+        }
+    }
 }
 
 impl ProtocolHandler for DGLabV3 {
+    // The repeat loop keeps sending whatever is stored, so zero everything out and write it once
+    // right away, rather than waiting on the next repeat.
+    fn handle_client_disconnect(&self) -> Result<Vec<HardwareCommand>, ButtplugDeviceError> {
+        self.cancel_patterns();
+        let serial_no = self.next_serial_no(self.stored_power() != (0, 0) || self.relative_pending());
+        for channel in self.channels.both() {
+            channel.power.store(0, SeqCst);
+            channel.frequency.store(0, SeqCst);
+            channel.waveform_strength.store(0, SeqCst);
+            channel.relative_pending.store(false, SeqCst);
+        }
+        Ok(
+            vec![
+                b0_write_cmd(b0_command_by_struct(self, serial_no)).into(),
+            ]
+        )
+    }
+
+    // Only called once the zeroed state was written and the hardware disconnected.
+    fn handle_shutdown(&self) {
+        self.background_tasks.cancel();
+    }
+
+    fn scalar_adjustment_features(&self) -> Vec<(usize, u32)> {
+        LIMITS.relative_power_features()
+    }
+
+    // What the repeat loop is sending. Power is the pattern power while a pattern runs, and the
+    // maximum is the soft limit the device reported. Frequency is the device value, as written.
+    // Packet counters are for command packets, repeats aren't acknowledged.
+    fn state_snapshot(&self) -> Option<serde_json::Value> {
+        let channel = |channel: Channel| {
+            let scalar = self.channels.channel(channel);
+            serde_json::json!({
+                "power": scalar.output_power(),
+                "maximum_power": self.power_caps.cap(channel),
+                "frequency": scalar.frequency.load(SeqCst),
+                "waveform_strength": scalar.waveform_strength.load(SeqCst),
+                "pattern_active": scalar.pattern_active.load(SeqCst),
+            })
+        };
+        Some(serde_json::json!({
+            "a": channel(Channel::A),
+            "b": channel(Channel::B),
+            "packets": self.packets.lock().expect("Lock poisoned").snapshot(),
+        }))
+    }
+
+    // Without a context we don't know what was handed to us before, so power changes are only
+    // checked against the stored power.
     fn handle_scalar_cmd(&self, commands: &[Option<(ActuatorType, u32)>]) -> Result<Vec<HardwareCommand>, ButtplugDeviceError> {
-        // Power A
-        let power_a_scalar = self.a_scalar.power.clone();
-        // Power B
-        let power_b_scalar = self.b_scalar.power.clone();
-        // Frequency A
-        let frequency_a_scalar = self.a_scalar.frequency.clone();
-        // Frequency B
-        let frequency_b_scalar = self.b_scalar.frequency.clone();
-        // Waveform strength A
-        let waveform_strength_a_scalar = self.a_scalar.waveform_strength.clone();
-        // Waveform strength B
-        let waveform_strength_b_scalar = self.b_scalar.waveform_strength.clone();
-        for (index, command) in commands.iter().enumerate().filter(|(_, x)| x.is_some()) {
-            let (actuator, mut scalar) = command.as_ref().expect("Already verified existence");
-            match *actuator {
-                // Set power (S)
-                ActuatorType::Vibrate => {
-                    if scalar > MAXIMUM_POWER {
-                        return Err(
-                            ProtocolSpecificError(
-                                "dg-lab-v3".to_owned(),
-                                format!("Power scalar {} not in [0, {}]", scalar, MAXIMUM_POWER),
-                            )
-                        );
-                    }
-                    match index {
-                        // Channel A
-                        0 => { power_a_scalar.store(scalar, SeqCst); }
-                        // Channel B
-                        1 => { power_b_scalar.store(scalar, SeqCst); }
-                        _ => {
-                            return Err(
-                                ProtocolSpecificError(
-                                    "dg-lab-v3".to_owned(),
-                                    format!("Vibrate command index {} is invalid", index),
-                                )
-                            );
-                        }
-                    }
+        self.handle_scalar_cmd_with_context(&ScalarCommandContext::new(commands.to_vec(), vec![]))
+    }
+
+    fn handle_scalar_cmd_with_context(&self, context: &ScalarCommandContext) -> Result<Vec<HardwareCommand>, ButtplugDeviceError> {
+        // Direct control always takes over from patterns (this also covers StopDeviceCmd)
+        self.cancel_patterns();
+        let updates = LIMITS.channel_updates(context.commands())?;
+        let mut strength_changed = false;
+        // Channels set to an absolute power, and relative changes, for channel A and B
+        let mut absolute_power = [false, false];
+        let mut relative_power = [0i64, 0i64];
+        for update in updates {
+            let output = update.channel.routed(self.swap_channels);
+            let channel = self.channels.channel(output);
+            match update.role {
+                // Set power (S). It's a strength change if it differs from the power last handed
+                // to us, or from the stored power if we don't know that (first command, or after
+                // a power ramp).
+                ChannelRole::Power => {
+                    let cap = self.power_caps.cap(output);
+                    let power = update.value.min(cap);
+                    let previous = context
+                        .previous(update.feature_index())
+                        .map_or_else(|| channel.power.load(SeqCst), |previous| previous.min(cap));
+                    strength_changed |= power != previous;
+                    channel.power.store(power, SeqCst);
+                    absolute_power[output.index()] = true;
                 }
                 // Set frequency (X, Y)
-                ActuatorType::Oscillate => {
-                    if scalar == MINIMUM_INPUT_FREQUENCY - 1 {
-                        scalar = 0;
-                    } else if scalar != 0 && (scalar < MINIMUM_INPUT_FREQUENCY || scalar > MAXIMUM_INPUT_FREQUENCY) {
-                        return Err(
-                            ProtocolSpecificError(
-                                "dg-lab-v3".to_owned(),
-                                format!("Frequency scalar {} not in [{}, {}]", scalar, MINIMUM_INPUT_FREQUENCY, MAXIMUM_INPUT_FREQUENCY),
-                            )
-                        );
-                    }
-                    match index {
-                        // Channel A
-                        2 => { frequency_a_scalar.store(input_to_frequency(scalar), SeqCst); }
-                        // Channel B
-                        3 => { frequency_b_scalar.store(input_to_frequency(scalar), SeqCst); }
-                        _ => {
-                            return Err(
-                                ProtocolSpecificError(
-                                    "dg-lab-v3".to_owned(),
-                                    format!("Oscillate command index {} is invalid", index),
-                                )
-                            );
-                        }
-                    }
+                ChannelRole::Frequency => {
+                    channel.frequency.store(input_to_frequency(update.value), SeqCst);
                 }
                 // Set waveform strength (Z)
-                ActuatorType::Inflate => {
-                    if scalar > MAXIMUM_WAVEFORM_STRENGTH {
-                        return Err(
-                            ProtocolSpecificError(
-                                "dg-lab-v3".to_owned(),
-                                format!("Waveform strength scalar {} not in [0, {}]", scalar, MAXIMUM_WAVEFORM_STRENGTH),
-                            )
-                        );
-                    }
-                    match index {
-                        // Channel A
-                        4 => { waveform_strength_a_scalar.store(scalar, SeqCst); }
-                        // Channel B
-                        5 => { waveform_strength_b_scalar.store(scalar, SeqCst); }
-                        _ => {
-                            return Err(
-                                ProtocolSpecificError(
-                                    "dg-lab-v3".to_owned(),
-                                    format!("Inflate command index {} is invalid", index),
-                                )
-                            );
-                        }
-                    }
+                ChannelRole::Waveform => {
+                    channel.waveform_strength.store(update.value, SeqCst);
+                }
+                // Adjust power relative to the current output (S)
+                ChannelRole::RelativePower => {
+                    relative_power[output.index()] = update.value as i64 - RELATIVE_POWER_ZERO as i64;
                 }
-                _ => {
-                    return Err(ButtplugDeviceError::UnhandledCommand(
-                        "Unknown actuator types are not controllable.".to_owned(),
-                    ));
+            }
+        }
+        // Absolute power wins over a relative change to the same channel, and settles any relative
+        // change still waiting on a response. Relative changes are left to the device to apply, our
+        // stored power only catches up once the device reports back.
+        let mut strength = [StrengthChange::Keep; 2];
+        for (i, channel) in self.channels.both().into_iter().enumerate() {
+            if absolute_power[i] {
+                if channel.relative_pending.swap(false, SeqCst) {
+                    strength_changed = true;
                 }
+                strength[i] = channel.strength_change();
+            } else if relative_power[i] != 0 {
+                channel.relative_pending.store(true, SeqCst);
+                strength[i] = StrengthChange::relative(relative_power[i]);
+                strength_changed = true;
+            } else {
+                strength[i] = channel.strength_change();
             }
         }
+        let serial_no = self.next_serial_no(strength_changed);
         Ok(
             vec![
-                HardwareWriteCmd::new(
-                    Endpoint::Tx,
-                    b0_set_command_by_struct(self),
-                    false,
-                ).into(),
+                b0_write_cmd(b0_command_with_strength(self, serial_no, strength[0], strength[1])).into(),
             ]
         )
     }
-}
\ No newline at end of file
+
+    // LinearCmd is interpreted as a power ramp: position is the target power of the channel (index 0
+    // is channel A, 1 is channel B), reached over the given duration.
+    fn handle_linear_cmd(&self, message: LinearCmd) -> Result<Vec<HardwareCommand>, ButtplugDeviceError> {
+        let mut ramps = vec![];
+        for vector in message.vectors() {
+            let output = Channel::from_linear_index(vector.index()).map(|channel| channel.routed(self.swap_channels));
+            let (channel, cap) = match output {
+                Some(channel) => (self.channels.channel(channel).clone(), self.power_caps.cap(channel)),
+                None => {
+                    return Err(
+                        ProtocolSpecificError(
+                            "dg-lab-v3".to_owned(),
+                            format!("Linear command index {} is invalid", vector.index()),
+                        )
+                    );
+                }
+            };
+            if !(0.0..=1.0).contains(&vector.position()) {
+                return Err(
+                    ProtocolSpecificError(
+                        "dg-lab-v3".to_owned(),
+                        format!("Ramp target {} not in [0, 1]", vector.position()),
+                    )
+                );
+            }
+            let target = ((vector.position() * MAXIMUM_POWER as f64).round() as u32).min(cap);
+            ramps.push((channel, target, Duration::from_millis(vector.duration() as u64)));
+        }
+        // Starting a new pattern cancels the old one on both channels, keeping the current output
+        // as the new starting point.
+        let cancel = self.ramps.restart();
+        for (channel, target, duration) in ramps {
+            start_power_ramp(channel, cancel.clone(), target, duration);
+        }
+        Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::device::protocol::dg_lab::frequency::MAXIMUM_INPUT_FREQUENCY;
+    use crate::server::device::protocol::scalar_pipeline_sim::ScalarPipelineSim;
+
+    fn channels(values: [f64; 6]) -> Vec<(u32, ActuatorType, f64)> {
+        let actuators = [ActuatorType::Vibrate, ActuatorType::Oscillate, ActuatorType::Inflate];
+        (0..6).map(|index| (index as u32, actuators[index / 2], values[index])).collect()
+    }
+
+    fn b0_write(data: [u8; 20], write_with_response: bool) -> Vec<HardwareCommand> {
+        vec![HardwareWriteCmd::new(Endpoint::Tx, data.to_vec(), write_with_response).into()]
+    }
+
+    #[test]
+    pub fn test_scalar_pipeline() {
+        let sim = ScalarPipelineSim::new(
+            "dg-lab-v3",
+            Some("47L121000"),
+            Arc::new(DGLabV3::default()),
+        )
+        .expect("Test, assuming infallible.");
+        let cases = [
+            (
+                [0.0; 6],
+                b0_write(
+                    [
+                        B0_HEAD, 0x1F, 0, 0,
+                        0, 0, 0, 0,
+                        0, 0, 0, 0,
+                        0, 0, 0, 0,
+                        0, 0, 0, 0,
+                    ],
+                    true,
+                ),
+            ),
+            (
+                [1.0; 6],
+                b0_write(
+                    [
+                        B0_HEAD, 0x2F, 0xC8, 0xC8,
+                        0xF0, 0xF0, 0xF0, 0xF0,
+                        0x64, 0x64, 0x64, 0x64,
+                        0xF0, 0xF0, 0xF0, 0xF0,
+                        0x64, 0x64, 0x64, 0x64,
+                    ],
+                    false,
+                ),
+            ),
+            (
+                [1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+                b0_write(
+                    [
+                        B0_HEAD, 0x3F, 0xC8, 0,
+                        0, 0, 0, 0,
+                        0, 0, 0, 0,
+                        0, 0, 0, 0,
+                        0, 0, 0, 0,
+                    ],
+                    false,
+                ),
+            ),
+            (
+                [0.0, 1.0, 0.0, 0.0, 0.0, 0.0],
+                b0_write(
+                    [
+                        B0_HEAD, 0x4F, 0, 0xC8,
+                        0, 0, 0, 0,
+                        0, 0, 0, 0,
+                        0, 0, 0, 0,
+                        0, 0, 0, 0,
+                    ],
+                    false,
+                ),
+            ),
+            (
+                [0.0, 0.0, 1.0, 0.0, 0.0, 0.0],
+                b0_write(
+                    [
+                        B0_HEAD, 0x5F, 0, 0,
+                        0xF0, 0xF0, 0xF0, 0xF0,
+                        0, 0, 0, 0,
+                        0, 0, 0, 0,
+                        0, 0, 0, 0,
+                    ],
+                    true,
+                ),
+            ),
+        ];
+        for (values, expected) in cases {
+            let commands = sim.scalar(&channels(values)).expect("Test, assuming infallible.");
+            assert_eq!(commands, expected);
+        }
+    }
+
+    #[test]
+    pub fn test_swap_channels() {
+        let sim = ScalarPipelineSim::new(
+            "dg-lab-v3",
+            Some("47L121000"),
+            Arc::new(DGLabV3 {
+                swap_channels: true,
+                ..Default::default()
+            }),
+        )
+        .expect("Test, assuming infallible.");
+        // Feature 0 is channel A power, which ends up in the channel B strength byte, along with
+        // the channel A frequency and waveform features.
+        let commands = sim
+            .scalar(&channels([1.0, 0.0, 1.0, 0.0, 1.0, 0.0]))
+            .expect("Test, assuming infallible.");
+        assert_eq!(
+            commands,
+            b0_write(
+                [
+                    B0_HEAD, 0x1F, 0, 0xC8,
+                    0, 0, 0, 0,
+                    0, 0, 0, 0,
+                    0xF0, 0xF0, 0xF0, 0xF0,
+                    0x64, 0x64, 0x64, 0x64,
+                ],
+                false,
+            )
+        );
+    }
+
+    #[test]
+    pub fn test_b0_command_boundary_values() {
+        let data = b0_command(
+            MAXIMUM_SERIAL_NO,
+            StrengthChange::SetTo(MAXIMUM_POWER),
+            StrengthChange::Keep,
+            [MAXIMUM_FREQUENCY, 10, 0, input_to_frequency(MAXIMUM_INPUT_FREQUENCY)],
+            [0; 4],
+            [MAXIMUM_WAVEFORM_STRENGTH, 0, 1, 99],
+            [0; 4],
+        );
+        assert_eq!(
+            data,
+            vec![
+                B0_HEAD, 0b1111_1100, 200, 0,
+                240, 10, 0, 240,
+                100, 0, 1, 99,
+                0, 0, 0, 0,
+                0, 0, 0, 0,
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_b0_command_saturates_over_range_values() {
+        let data = b0_command(
+            0b1_0001,
+            StrengthChange::SetTo(MAXIMUM_POWER + 1),
+            StrengthChange::Increase(456),
+            [MAXIMUM_FREQUENCY + 1, 256, u32::MAX, 300],
+            [1000; 4],
+            [MAXIMUM_WAVEFORM_STRENGTH + 1, 255, 256, u32::MAX],
+            [357; 4],
+        );
+        assert_eq!(
+            data,
+            vec![
+                B0_HEAD, 0b0001_1101, 200, 200,
+                240, 240, 240, 240,
+                100, 100, 100, 100,
+                240, 240, 240, 240,
+                100, 100, 100, 100,
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_state_snapshot() {
+        let handler = Arc::new(DGLabV3::default());
+        let sim = ScalarPipelineSim::new("dg-lab-v3", Some("47L121000"), handler.clone())
+            .expect("Test, assuming infallible.");
+        sim.scalar(&channels([1.0, 0.0, 1.0, 0.0, 1.0, 0.0])).expect("Test, assuming infallible.");
+        assert_eq!(
+            handler.state_snapshot(),
+            Some(serde_json::json!({
+                "a": {
+                    "power": MAXIMUM_POWER,
+                    "maximum_power": MAXIMUM_POWER,
+                    "frequency": MAXIMUM_FREQUENCY,
+                    "waveform_strength": MAXIMUM_WAVEFORM_STRENGTH,
+                    "pattern_active": false,
+                },
+                "b": {
+                    "power": 0,
+                    "maximum_power": MAXIMUM_POWER,
+                    "frequency": 0,
+                    "waveform_strength": 0,
+                    "pattern_active": false,
+                },
+                "packets": {
+                    "serial_no": 1,
+                    "sent": 1,
+                    "acknowledged": 0,
+                    "dropped": 0,
+                    "outstanding": 1,
+                },
+            }))
+        );
+    }
+
+    fn serial_no(commands: &[HardwareCommand]) -> u8 {
+        match &commands[0] {
+            HardwareCommand::Write(write) => write.data()[1] >> 4,
+            command => panic!("Unexpected command {:?}", command),
+        }
+    }
+
+    #[test]
+    pub fn test_serial_numbers_wrap() {
+        let handler = Arc::new(DGLabV3::default());
+        let sim = ScalarPipelineSim::new("dg-lab-v3", Some("47L121000"), handler.clone())
+            .expect("Test, assuming infallible.");
+        let serials: Vec<u8> = (0..17)
+            .map(|i| {
+                let power = if i % 2 == 0 { 1.0 } else { 0.5 };
+                serial_no(&sim.scalar(&channels([power, 0.0, 0.0, 0.0, 0.0, 0.0])).expect("Test, assuming infallible."))
+            })
+            .collect();
+        assert_eq!(serials, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 1, 2]);
+        // Repeats always use the reserved serial number.
+        assert_eq!(b0_command_by_struct(&handler, DEFAULT_SERIAL_NO)[1] >> 4, DEFAULT_SERIAL_NO);
+        // Serials 1 and 2 were reused while their first packets were still outstanding.
+        let packets = &handler.state_snapshot().expect("Test, assuming infallible.")["packets"];
+        assert_eq!(packets["sent"], 17);
+        assert_eq!(packets["dropped"], 2);
+        assert_eq!(packets["outstanding"], 15);
+    }
+
+    #[test]
+    pub fn test_b1_response_matches_outstanding_packet() {
+        let handler = Arc::new(DGLabV3::default());
+        let sim = ScalarPipelineSim::new("dg-lab-v3", Some("47L121000"), handler.clone())
+            .expect("Test, assuming infallible.");
+        // Serial 1 changes strength, 2 only changes frequency, 3 changes strength again.
+        for values in [
+            [0.5, 0.0, 0.0, 0.0, 0.0, 0.0],
+            [0.5, 0.0, 1.0, 0.0, 0.0, 0.0],
+            [1.0, 0.0, 1.0, 0.0, 0.0, 0.0],
+        ] {
+            sim.scalar(&channels(values)).expect("Test, assuming infallible.");
+        }
+
+        // Acknowledging 2 means 1 was dropped, and the strength it reports is already out of date.
+        handler.handle_b1_response(B1Response { serial_no: 2, power_a: 80, power_b: 0 });
+        assert_eq!(handler.stored_power(), (MAXIMUM_POWER, 0));
+        let packets = &handler.state_snapshot().expect("Test, assuming infallible.")["packets"];
+        assert_eq!(packets["acknowledged"], 1);
+        assert_eq!(packets["dropped"], 1);
+        assert_eq!(packets["outstanding"], 1);
+
+        // Responses to packets that are gone, and device changes, don't count until 3 is in.
+        handler.handle_b1_response(B1Response { serial_no: 1, power_a: 100, power_b: 0 });
+        handler.handle_b1_response(B1Response { serial_no: DEFAULT_SERIAL_NO, power_a: 20, power_b: 0 });
+        assert_eq!(handler.stored_power(), (MAXIMUM_POWER, 0));
+        handler.handle_b1_response(B1Response { serial_no: 3, power_a: 150, power_b: 0 });
+        assert_eq!(handler.stored_power(), (150, 0));
+        let packets = &handler.state_snapshot().expect("Test, assuming infallible.")["packets"];
+        assert_eq!(packets["acknowledged"], 2);
+        assert_eq!(packets["dropped"], 1);
+        assert_eq!(packets["outstanding"], 0);
+    }
+
+    #[test]
+    pub fn test_parse_device_id() {
+        assert_eq!(parse_device_id(b"3A1B2C4D\0\0\0\0"), Some("3A1B2C4D".to_owned()));
+        assert_eq!(parse_device_id(b"\0\0\0\0"), None);
+        assert_eq!(parse_device_id(b""), None);
+    }
+}
@@ -5,15 +5,24 @@
 // Licensed under the BSD 3-Clause license. See LICENSE file in the project root
 // for full license information.
 
-use std::sync::Arc;
+use std::pin::Pin;
+use std::sync::{
+  atomic::{AtomicBool, AtomicU32, Ordering},
+  Arc,
+  Mutex,
+};
+use std::time::Duration;
 
+use futures::select;
 use futures_util::future::BoxFuture;
-use futures_util::{future, FutureExt};
+use futures_util::{future, FutureExt, StreamExt};
+use tokio::sync::broadcast;
 
 use crate::core::message;
 use crate::core::message::{
   ButtplugDeviceMessage,
   ButtplugMessage,
+  ButtplugServerDeviceMessage,
   ButtplugServerMessage,
   SensorReadCmd,
   SensorReading,
@@ -28,93 +37,293 @@ use crate::server::device::hardware::{
   HardwareUnsubscribeCmd,
 };
 use crate::{
-  core::{errors::ButtplugDeviceError, message::Endpoint},
+  core::{
+    errors::ButtplugDeviceError,
+    message::{ActuatorType, Endpoint},
+  },
   server::device::{
+    configuration::{ProtocolDeviceAttributes, UserDeviceIdentifier},
     hardware::{HardwareCommand, HardwareWriteCmd},
-    protocol::{generic_protocol_setup, ProtocolHandler},
+    protocol::{
+      forward_hardware_notifications,
+      galaku_framing::{decode_value, encode_packet},
+      generic_protocol_initializer_setup,
+      linear_ramp::RampConfig,
+      ProtocolHandler,
+      ProtocolIdentifier,
+      ProtocolInitializer,
+    },
   },
+  util::{sleep, stream::convert_broadcast_receiver_to_stream},
 };
+use async_trait::async_trait;
 
-static KEY_TAB: [[u32; 12]; 4] = [
-  [0, 24, 152, 247, 165, 61, 13, 41, 37, 80, 68, 70],
-  [0, 69, 110, 106, 111, 120, 32, 83, 45, 49, 46, 55],
-  [0, 101, 120, 32, 84, 111, 121, 115, 10, 142, 157, 163],
-  [0, 197, 214, 231, 248, 10, 50, 32, 111, 98, 13, 10],
-];
+// How long to wait for a reply to a battery read before giving up.
+static DEFAULT_BATTERY_READ_TIMEOUT: Duration = Duration::from_secs(5);
+static MAXIMUM_ROTATE_SPEED: u32 = 100;
+// Vibration speed changes take effect right away, so ramps can step quickly.
+static LINEAR_RAMP_INTERVAL: Duration = Duration::from_millis(50);
 
-fn get_tab_key(r: usize, t: usize) -> u32 {
-  let e = 3 & r;
-  return KEY_TAB[e][t];
+/// Opcodes a Galaku model uses. All models share the packet layout
+/// `[90, 0, 0, 1, opcode, value, direction, 0, 0, 0]` and the framing in
+/// [galaku_framing](super::galaku_framing), so supporting a new model is a row in
+/// [MODEL_OPCODES] (plus its device config), not a new protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GalakuOpcodes {
+  /// Opcode for setting vibration speed, if the model vibrates.
+  vibrate: Option<u32>,
+  /// Opcode for setting rotation speed and direction, if the model rotates.
+  rotate: Option<u32>,
+  /// Opcode for asking for the battery level.
+  battery: u32,
 }
 
-fn encrypt(data: Vec<u32>) -> Vec<u32> {
-  let mut new_data = vec![data[0]];
-  for i in 1..data.len() {
-    let a = get_tab_key(new_data[i - 1] as usize, i);
-    let u = (a ^ data[0] ^ data[i]) + a;
-    new_data.push(u);
+impl GalakuOpcodes {
+  pub const fn new(vibrate: Option<u32>, rotate: Option<u32>, battery: u32) -> Self {
+    Self {
+      vibrate,
+      rotate,
+      battery,
+    }
+  }
+
+  /// Opcodes for the model with the given device config identifier (usually the advertised name).
+  /// Models without a row in [MODEL_OPCODES] use the One Engine opcodes.
+  pub fn for_model(identifier: &str) -> Self {
+    Self::model_row(identifier).unwrap_or(ONE_ENGINE_OPCODES)
   }
-  return new_data;
-}
 
-fn decrypt(data: Vec<u32>) -> Vec<u32> {
-  let mut new_data = vec![data[0]];
-  for i in 1..data.len() {
-    let a = get_tab_key(data[i - 1] as usize, i);
-    let u = data[i] as i32 - a as i32 ^ data[0] as i32 ^ a as i32;
-    new_data.push(if u < 0 { (u + 256) as u32 } else { u as u32 });
+  /// Opcodes for a device with the given attributes. Devices on the protocol defaults are One
+  /// Engine models. A configuration without a row in [MODEL_OPCODES] also gets the One Engine
+  /// opcodes, with a warning, as its packets may well be different.
+  pub fn for_attributes(attributes: &ProtocolDeviceAttributes) -> Self {
+    if let Some(identifier) = attributes.identifier() {
+      Self::model_row(identifier).unwrap_or_else(|| {
+        warn!(
+          "No Galaku opcodes for configuration {}, using the One Engine opcodes.",
+          identifier
+        );
+        ONE_ENGINE_OPCODES
+      })
+    } else {
+      ONE_ENGINE_OPCODES
+    }
+  }
+
+  fn model_row(identifier: &str) -> Option<Self> {
+    MODEL_OPCODES
+      .iter()
+      .find(|(model, _)| *model == identifier)
+      .map(|(_, opcodes)| *opcodes)
   }
-  return new_data;
 }
 
-fn send_bytes(data: Vec<u32>) -> Vec<u8> {
-  let mut new_data = vec![35];
-  new_data.extend(data);
-  new_data.push(new_data.iter().sum());
-  let mut uint8_array: Vec<u8> = Vec::new();
-  for value in encrypt(new_data) {
-    uint8_array.push(value as u8);
+impl Default for GalakuOpcodes {
+  fn default() -> Self {
+    ONE_ENGINE_OPCODES
   }
-  return uint8_array;
 }
 
-fn read_value(data: Vec<u8>) -> u32 {
-  let mut uint32_data: Vec<u32> = Vec::new();
-  for value in data {
-    uint32_data.push(value as u32);
+static ONE_ENGINE_OPCODES: GalakuOpcodes = GalakuOpcodes::new(Some(49), None, 19);
+
+/// Opcodes for models that don't use the One Engine ones, keyed by device config identifier. GR01
+/// hasn't been captured from a device yet, so it's kept out of the bundled device config and only
+/// used from test configs until it has been.
+pub static MODEL_OPCODES: [(&str, GalakuOpcodes); 1] =
+  [("GR01", GalakuOpcodes::new(None, Some(50), 19))];
+
+fn model_packet(opcode: u32, value: u32, direction: u32) -> Vec<u8> {
+  encode_packet(&[90, 0, 0, 1, opcode, value, direction, 0, 0, 0])
+}
+
+fn unhandled_command(command: &str) -> ButtplugDeviceError {
+  ButtplugDeviceError::UnhandledCommand(format!(
+    "Galaku model does not support {} commands",
+    command
+  ))
+}
+
+generic_protocol_initializer_setup!(Galaku, "galaku");
+
+#[derive(Default)]
+pub struct GalakuInitializer {}
+
+#[async_trait]
+impl ProtocolInitializer for GalakuInitializer {
+  async fn initialize(
+    &mut self,
+    hardware: Arc<Hardware>,
+    attributes: &ProtocolDeviceAttributes,
+  ) -> Result<Arc<dyn ProtocolHandler>, ButtplugDeviceError> {
+    let handler: Arc<dyn ProtocolHandler> = Arc::new(Galaku::new(
+      GalakuOpcodes::for_attributes(attributes),
+      DEFAULT_BATTERY_READ_TIMEOUT,
+    ));
+    forward_hardware_notifications(&hardware, handler.clone());
+    Ok(handler)
   }
-  let decrypted_data = decrypt(uint32_data);
-  if decrypted_data.len() > 0 {
-    decrypted_data[4]
-  } else {
-    0
+
+  // Every galaku device config has a battery.
+  fn required_endpoints(&self) -> Vec<Endpoint> {
+    vec![Endpoint::Tx, Endpoint::RxBLEBattery]
+  }
+
+  // Vibrate commands ignore the feature index, so vibrating models take a single vibrator.
+  // Rotating models only take RotateCmd.
+  fn scalar_feature_layouts(&self, identifier: &UserDeviceIdentifier) -> Vec<Vec<ActuatorType>> {
+    let model = identifier.identifier().as_deref().unwrap_or_default();
+    if GalakuOpcodes::for_model(model).vibrate.is_some() {
+      vec![vec![ActuatorType::Vibrate]]
+    } else {
+      vec![vec![]]
+    }
   }
 }
 
-generic_protocol_setup!(Galaku, "galaku");
+pub struct Galaku {
+  opcodes: GalakuOpcodes,
+  // Device and sensor index of the battery subscription, if there is one.
+  battery_subscription: Arc<Mutex<Option<(u32, u32)>>>,
+  event_stream: broadcast::Sender<ButtplugServerDeviceMessage>,
+  battery_read_timeout: Duration,
+  // Last speed and direction sent, for state snapshots.
+  speed: AtomicU32,
+  clockwise: AtomicBool,
+}
 
-#[derive(Default)]
-pub struct Galaku {}
+impl Default for Galaku {
+  fn default() -> Self {
+    Self::new_with_battery_read_timeout(DEFAULT_BATTERY_READ_TIMEOUT)
+  }
+}
+
+impl Galaku {
+  /// Create a handler for a model with the given opcodes, that gives up on battery reads the
+  /// device doesn't reply to within the given time.
+  pub fn new(opcodes: GalakuOpcodes, battery_read_timeout: Duration) -> Self {
+    let (sender, _) = broadcast::channel(256);
+    Self {
+      opcodes,
+      battery_subscription: Arc::new(Mutex::new(None)),
+      event_stream: sender,
+      battery_read_timeout,
+      speed: AtomicU32::new(0),
+      clockwise: AtomicBool::new(true),
+    }
+  }
+
+  /// Create a One Engine handler that gives up on battery reads the device doesn't reply to
+  /// within the given time.
+  pub fn new_with_battery_read_timeout(battery_read_timeout: Duration) -> Self {
+    Self::new(GalakuOpcodes::default(), battery_read_timeout)
+  }
+}
 
 impl ProtocolHandler for Galaku {
   fn keepalive_strategy(&self) -> super::ProtocolKeepaliveStrategy {
     super::ProtocolKeepaliveStrategy::RepeatLastPacketStrategy
   }
 
+  // LinearCmd ramps vibration speed on vibrating models.
+  fn linear_ramp_config(&self) -> Option<RampConfig> {
+    self
+      .opcodes
+      .vibrate
+      .map(|_| RampConfig::new(LINEAR_RAMP_INTERVAL))
+  }
+
+  fn event_stream(
+    &self,
+  ) -> Pin<Box<dyn futures::Stream<Item = ButtplugServerDeviceMessage> + Send>> {
+    convert_broadcast_receiver_to_stream(self.event_stream.subscribe()).boxed()
+  }
+
+  // Battery level is pushed on the battery endpoint while subscribed.
+  fn handle_hardware_notification(&self, endpoint: Endpoint, data: &[u8]) {
+    if endpoint != Endpoint::RxBLEBattery {
+      return;
+    }
+    let subscription = *self.battery_subscription.lock().expect("Lock poisoned");
+    if let Some((device_index, sensor_index)) = subscription {
+      let _ = self.event_stream.send(
+        SensorReading::new(
+          device_index,
+          sensor_index,
+          SensorType::Battery,
+          vec![decode_value(data) as i32],
+        )
+        .into(),
+      );
+    }
+  }
+
   fn handle_scalar_vibrate_cmd(
     &self,
     _index: u32,
     scalar: u32,
   ) -> Result<Vec<HardwareCommand>, ButtplugDeviceError> {
-    let data: Vec<u32> = vec![90, 0, 0, 1, 49, scalar, 0, 0, 0, 0];
+    let opcode = self
+      .opcodes
+      .vibrate
+      .ok_or_else(|| unhandled_command("vibrate"))?;
+    self.speed.store(scalar, Ordering::Relaxed);
     Ok(vec![HardwareWriteCmd::new(
       Endpoint::Tx,
-      send_bytes(data),
+      model_packet(opcode, scalar, 0),
       false,
     )
     .into()])
   }
 
+  fn handle_rotate_cmd(
+    &self,
+    commands: &[Option<(u32, bool)>],
+  ) -> Result<Vec<HardwareCommand>, ButtplugDeviceError> {
+    let opcode = self
+      .opcodes
+      .rotate
+      .ok_or_else(|| unhandled_command("rotate"))?;
+    if let Some(Some((speed, clockwise))) = commands.first() {
+      let speed = (*speed).min(MAXIMUM_ROTATE_SPEED);
+      self.speed.store(speed, Ordering::Relaxed);
+      self.clockwise.store(*clockwise, Ordering::Relaxed);
+      Ok(vec![HardwareWriteCmd::new(
+        Endpoint::Tx,
+        model_packet(opcode, speed, if *clockwise { 0 } else { 1 }),
+        false,
+      )
+      .into()])
+    } else {
+      Ok(vec![])
+    }
+  }
+
+  // Rotating models also report their direction.
+  fn state_snapshot(&self) -> Option<serde_json::Value> {
+    let speed = self.speed.load(Ordering::Relaxed);
+    Some(if self.opcodes.vibrate.is_some() {
+      serde_json::json!({ "vibrate": speed })
+    } else {
+      serde_json::json!({
+        "rotate": speed,
+        "clockwise": self.clockwise.load(Ordering::Relaxed),
+      })
+    })
+  }
+
+  // The keepalive repeats the last packet, so make sure that's a stop packet, after stopping
+  // every motor the model has.
+  fn handle_client_disconnect(&self) -> Result<Vec<HardwareCommand>, ButtplugDeviceError> {
+    let mut commands = vec![];
+    if self.opcodes.rotate.is_some() {
+      commands.extend(self.handle_rotate_cmd(&[Some((0, true))])?);
+    }
+    if self.opcodes.vibrate.is_some() {
+      commands.extend(self.handle_scalar_vibrate_cmd(0, 0)?);
+    }
+    Ok(commands)
+  }
+
   fn handle_sensor_subscribe_cmd(
     &self,
     device: Arc<Hardware>,
@@ -122,10 +331,13 @@ impl ProtocolHandler for Galaku {
   ) -> BoxFuture<Result<ButtplugServerMessage, ButtplugDeviceError>> {
     match message.sensor_type() {
       SensorType::Battery => {
+        let battery_subscription = self.battery_subscription.clone();
         async move {
           device
             .subscribe(&HardwareSubscribeCmd::new(Endpoint::RxBLEBattery))
             .await?;
+          *battery_subscription.lock().expect("Lock poisoned") =
+            Some((message.device_index(), *message.sensor_index()));
           Ok(message::Ok::new(message.id()).into())
         }
       }
@@ -144,6 +356,11 @@ impl ProtocolHandler for Galaku {
   ) -> BoxFuture<Result<ButtplugServerMessage, ButtplugDeviceError>> {
     match message.sensor_type() {
       SensorType::Battery => {
+        self
+          .battery_subscription
+          .lock()
+          .expect("Lock poisoned")
+          .take();
         async move {
           device
             .unsubscribe(&HardwareUnsubscribeCmd::new(Endpoint::RxBLEBattery))
@@ -164,40 +381,230 @@ impl ProtocolHandler for Galaku {
     device: Arc<Hardware>,
     message: SensorReadCmd,
   ) -> BoxFuture<Result<ButtplugServerMessage, ButtplugDeviceError>> {
-    let data: Vec<u32> = vec![90, 0, 0, 1, 19, 0, 0, 0, 0, 0];
+    let packet = model_packet(self.opcodes.battery, 0, 0);
     let mut device_notification_receiver = device.event_stream();
+    let battery_subscription = self.battery_subscription.clone();
+    let timeout = self.battery_read_timeout;
     async move {
       device
         .subscribe(&HardwareSubscribeCmd::new(Endpoint::RxBLEBattery))
         .await?;
-      device
-        .write_value(&HardwareWriteCmd::new(Endpoint::Tx, send_bytes(data), true))
-        .await?;
-      while let Ok(event) = device_notification_receiver.recv().await {
-        return match event {
-          HardwareEvent::Notification(_, endpoint, data) => {
-            if endpoint != Endpoint::RxBLEBattery {
-              continue;
+      let device_clone = device.clone();
+      let wait_for_reading = async move {
+        device_clone
+          .write_value(&HardwareWriteCmd::new(Endpoint::Tx, packet, true))
+          .await?;
+        while let Ok(event) = device_notification_receiver.recv().await {
+          return match event {
+            HardwareEvent::Notification(_, endpoint, data) => {
+              if endpoint != Endpoint::RxBLEBattery {
+                continue;
+              }
+              let battery_reading = SensorReading::new(
+                message.device_index(),
+                *message.sensor_index(),
+                *message.sensor_type(),
+                vec![decode_value(&data) as i32],
+              );
+              Ok(battery_reading.into())
             }
-            let battery_reading = SensorReading::new(
-              message.device_index(),
-              *message.sensor_index(),
-              *message.sensor_type(),
-              vec![read_value(data) as i32],
-            );
-            Ok(battery_reading.into())
-          }
-          HardwareEvent::Disconnected(_) => Err(ButtplugDeviceError::ProtocolSpecificError(
-            "Galaku".to_owned(),
-            "Galaku Device disconnected while getting Battery info.".to_owned(),
-          )),
-        };
+            HardwareEvent::Disconnected(_) => Err(ButtplugDeviceError::ProtocolSpecificError(
+              "Galaku".to_owned(),
+              "Galaku Device disconnected while getting Battery info.".to_owned(),
+            )),
+          };
+        }
+        Err(ButtplugDeviceError::ProtocolSpecificError(
+          "Galaku".to_owned(),
+          "Galaku Device disconnected while getting Battery info.".to_owned(),
+        ))
+      };
+      let result = select! {
+        result = wait_for_reading.fuse() => result,
+        _ = sleep(timeout).fuse() => Err(ButtplugDeviceError::ProtocolSpecificError(
+          "Galaku".to_owned(),
+          format!("Galaku Device did not report Battery info within {:?}.", timeout),
+        )),
+      };
+      // Unsubscribe whether or not we got a reading, so repeated reads don't stack subscriptions.
+      // A battery sensor subscription still needs the endpoint though.
+      if battery_subscription
+        .lock()
+        .expect("Lock poisoned")
+        .is_none()
+      {
+        if let Err(err) = device
+          .unsubscribe(&HardwareUnsubscribeCmd::new(Endpoint::RxBLEBattery))
+          .await
+        {
+          warn!(
+            "Error unsubscribing from Galaku battery endpoint: {:?}",
+            err
+          );
+        }
       }
-      Err(ButtplugDeviceError::ProtocolSpecificError(
-        "Galaku".to_owned(),
-        "Galaku Device disconnected while getting Battery info.".to_owned(),
-      ))
+      result
     }
     .boxed()
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::server::device::protocol::scalar_pipeline_sim::ScalarPipelineSim;
+
+  fn vibrate_write(data: [u8; 12]) -> Vec<HardwareCommand> {
+    vec![HardwareWriteCmd::new(Endpoint::Tx, data.to_vec(), false).into()]
+  }
+
+  #[test]
+  pub fn test_scalar_pipeline() {
+    let sim = ScalarPipelineSim::new("galaku", Some("GX21"), Arc::new(Galaku::default()))
+      .expect("Test, assuming infallible.");
+    let off = vibrate_write([
+      0x23, 0x81, 0xBB, 0xAB, 0xD2, 0xEC, 0x3B, 0x23, 0xBB, 0xA3, 0x3B, 0x90,
+    ]);
+    let full = vibrate_write([
+      0x23, 0x81, 0xBB, 0xAB, 0xD2, 0xEC, 0x57, 0x23, 0xBB, 0xA3, 0x3B, 0x44,
+    ]);
+    assert_eq!(
+      sim
+        .scalar(&[(0, ActuatorType::Vibrate, 0.0)])
+        .expect("Test, assuming infallible."),
+      off
+    );
+    assert_eq!(
+      sim
+        .scalar(&[(0, ActuatorType::Vibrate, 1.0)])
+        .expect("Test, assuming infallible."),
+      full
+    );
+    assert_eq!(sim.stop().expect("Test, assuming infallible."), off);
+    // Commands are checked against the device features, same as on the server.
+    assert!(sim.scalar(&[(0, ActuatorType::Rotate, 1.0)]).is_err());
+    assert!(sim.scalar(&[(1, ActuatorType::Vibrate, 1.0)]).is_err());
+  }
+
+  #[test]
+  pub fn test_model_opcodes() {
+    assert_eq!(GalakuOpcodes::for_model("GS01"), GalakuOpcodes::default());
+    assert_eq!(
+      GalakuOpcodes::for_model("GR01"),
+      GalakuOpcodes::new(None, Some(50), 19)
+    );
+    let mut attributes = ProtocolDeviceAttributes::new("Galaku", &None, &vec![].into());
+    assert_eq!(
+      GalakuOpcodes::for_attributes(&attributes),
+      GalakuOpcodes::default()
+    );
+    // Configurations without opcodes of their own fall back to the One Engine ones.
+    attributes.set_identifier(Some("GS01".to_owned()));
+    assert_eq!(
+      GalakuOpcodes::for_attributes(&attributes),
+      GalakuOpcodes::default()
+    );
+  }
+
+  #[test]
+  pub fn test_model_opcodes_from_attributes() {
+    let mut attributes = ProtocolDeviceAttributes::new("Galaku", &None, &vec![].into());
+    attributes.set_identifier(Some("GR01".to_owned()));
+    let opcodes = GalakuOpcodes::for_attributes(&attributes);
+    assert_eq!(opcodes, GalakuOpcodes::new(None, Some(50), 19));
+
+    let handler = Galaku::new(opcodes, DEFAULT_BATTERY_READ_TIMEOUT);
+    assert_eq!(
+      handler
+        .handle_rotate_cmd(&[Some((50, true))])
+        .expect("Test, assuming infallible."),
+      vibrate_write([0x23, 0x81, 0xBB, 0xAB, 0xD2, 0xED, 0x51, 0xC3, 0xBB, 0xA3, 0x3B, 0xD5])
+    );
+    assert_eq!(
+      handler
+        .handle_rotate_cmd(&[Some((50, false))])
+        .expect("Test, assuming infallible."),
+      vibrate_write([0x23, 0x81, 0xBB, 0xAB, 0xD2, 0xED, 0x51, 0xC4, 0x2B, 0xA3, 0x3B, 0xD4])
+    );
+    assert_eq!(
+      handler
+        .handle_rotate_cmd(&[Some((0, true))])
+        .expect("Test, assuming infallible."),
+      vibrate_write([0x23, 0x81, 0xBB, 0xAB, 0xD2, 0xED, 0x23, 0x23, 0xBB, 0xA3, 0x3B, 0xA3])
+    );
+    // The row has no vibrate opcode, so the One Engine one isn't used either.
+    assert!(handler.handle_scalar_vibrate_cmd(0, 50).is_err());
+  }
+
+  #[test]
+  pub fn test_rotating_model_packets() {
+    let handler = Galaku::new(
+      GalakuOpcodes::for_model("GR01"),
+      DEFAULT_BATTERY_READ_TIMEOUT,
+    );
+    assert_eq!(
+      handler
+        .handle_rotate_cmd(&[Some((50, true))])
+        .expect("Test, assuming infallible."),
+      vibrate_write([0x23, 0x81, 0xBB, 0xAB, 0xD2, 0xED, 0x51, 0xC3, 0xBB, 0xA3, 0x3B, 0xD5,])
+    );
+    // Disconnecting leaves a rotation stop for the keepalive to repeat.
+    assert_eq!(
+      handler
+        .handle_client_disconnect()
+        .expect("Test, assuming infallible."),
+      vibrate_write([0x23, 0x81, 0xBB, 0xAB, 0xD2, 0xED, 0x23, 0x23, 0xBB, 0xA3, 0x3B, 0xA3,])
+    );
+    assert!(matches!(
+      handler.handle_scalar_vibrate_cmd(0, 50),
+      Err(ButtplugDeviceError::UnhandledCommand(_))
+    ));
+  }
+
+  #[test]
+  pub fn test_disconnect_stops_every_motor() {
+    let handler = Galaku::new(
+      GalakuOpcodes::new(Some(49), Some(50), 19),
+      DEFAULT_BATTERY_READ_TIMEOUT,
+    );
+    handler
+      .handle_scalar_vibrate_cmd(0, 50)
+      .expect("Test, assuming infallible.");
+    let mut stop = vibrate_write([
+      0x23, 0x81, 0xBB, 0xAB, 0xD2, 0xED, 0x23, 0x23, 0xBB, 0xA3, 0x3B, 0xA3,
+    ]);
+    stop.extend(vibrate_write([
+      0x23, 0x81, 0xBB, 0xAB, 0xD2, 0xEC, 0x3B, 0x23, 0xBB, 0xA3, 0x3B, 0x90,
+    ]));
+    assert_eq!(
+      handler
+        .handle_client_disconnect()
+        .expect("Test, assuming infallible."),
+      stop
+    );
+  }
+
+  #[test]
+  pub fn test_state_snapshot() {
+    let handler = Galaku::default();
+    handler
+      .handle_scalar_vibrate_cmd(0, 30)
+      .expect("Test, assuming infallible.");
+    assert_eq!(
+      handler.state_snapshot(),
+      Some(serde_json::json!({ "vibrate": 30 }))
+    );
+
+    let handler = Galaku::new(
+      GalakuOpcodes::for_model("GR01"),
+      DEFAULT_BATTERY_READ_TIMEOUT,
+    );
+    handler
+      .handle_rotate_cmd(&[Some((150, false))])
+      .expect("Test, assuming infallible.");
+    assert_eq!(
+      handler.state_snapshot(),
+      Some(serde_json::json!({ "rotate": MAXIMUM_ROTATE_SPEED, "clockwise": false }))
+    );
+  }
+}
@@ -0,0 +1,65 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Packet framing shared by Galaku devices. Every model wraps its payload the same way (a 0x23
+//! header and a checksum byte, then a table based encryption), only the payload layout and opcodes
+//! differ.
+
+static KEY_TAB: [[u32; 12]; 4] = [
+  [0, 24, 152, 247, 165, 61, 13, 41, 37, 80, 68, 70],
+  [0, 69, 110, 106, 111, 120, 32, 83, 45, 49, 46, 55],
+  [0, 101, 120, 32, 84, 111, 121, 115, 10, 142, 157, 163],
+  [0, 197, 214, 231, 248, 10, 50, 32, 111, 98, 13, 10],
+];
+
+static PACKET_HEADER: u32 = 35;
+
+fn get_tab_key(r: usize, t: usize) -> u32 {
+  let e = 3 & r;
+  KEY_TAB[e][t]
+}
+
+fn encrypt(data: Vec<u32>) -> Vec<u32> {
+  let mut new_data = vec![data[0]];
+  for i in 1..data.len() {
+    let a = get_tab_key(new_data[i - 1] as usize, i);
+    let u = (a ^ data[0] ^ data[i]) + a;
+    new_data.push(u);
+  }
+  new_data
+}
+
+fn decrypt(data: Vec<u32>) -> Vec<u32> {
+  let mut new_data = vec![data[0]];
+  for i in 1..data.len() {
+    let a = get_tab_key(data[i - 1] as usize, i);
+    let u = (data[i] as i32 - a as i32) ^ data[0] as i32 ^ a as i32;
+    new_data.push(if u < 0 { (u + 256) as u32 } else { u as u32 });
+  }
+  new_data
+}
+
+/// Build the encrypted packet for a payload. Payloads are 10 values, the header and checksum are
+/// added here.
+pub fn encode_packet(payload: &[u32]) -> Vec<u8> {
+  let mut data = vec![PACKET_HEADER];
+  data.extend(payload);
+  data.push(data.iter().sum());
+  encrypt(data).into_iter().map(|value| value as u8).collect()
+}
+
+/// Decrypt a packet sent by the device, returning the value byte (e.g. the battery level), or 0 for
+/// packets too short to have one.
+pub fn decode_value(data: &[u8]) -> u32 {
+  if data.is_empty() {
+    return 0;
+  }
+  decrypt(data.iter().map(|value| *value as u32).collect())
+    .get(4)
+    .copied()
+    .unwrap_or(0)
+}
@@ -11,6 +11,7 @@ use crate::{
     message::{
       ActuatorType,
       ButtplugDeviceCommandMessageUnion,
+      IntensityCurve,
       LinearCmd,
       RotateCmd,
       RotationSubcommand,
@@ -23,14 +24,55 @@ use crate::{
 use getset::Getters;
 use std::{
   ops::RangeInclusive,
-  sync::atomic::{AtomicBool, AtomicU32, Ordering::SeqCst},
+  sync::{
+    atomic::{AtomicBool, AtomicU32, Ordering::SeqCst},
+    Mutex,
+  },
 };
 
+/// Scalar commands for a protocol, along with the values previously handed to each feature.
+///
+/// Built by [GenericCommandManager::scalar_context] right before the commands are handed to
+/// [ProtocolHandler::handle_scalar_cmd_with_context](super::ProtocolHandler::handle_scalar_cmd_with_context),
+/// so previous values are what the protocol last saw, including any ramp steps, rather than what
+/// the client last asked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScalarCommandContext {
+  commands: Vec<Option<(ActuatorType, u32)>>,
+  previous: Vec<Option<u32>>,
+}
+
+impl ScalarCommandContext {
+  pub fn new(commands: Vec<Option<(ActuatorType, u32)>>, previous: Vec<Option<u32>>) -> Self {
+    Self { commands, previous }
+  }
+
+  /// Per feature commands, as handed to [ProtocolHandler::handle_scalar_cmd](super::ProtocolHandler::handle_scalar_cmd).
+  pub fn commands(&self) -> &[Option<(ActuatorType, u32)>] {
+    &self.commands
+  }
+
+  /// Value last handed to the protocol for a feature. None if nothing has been handed to it since
+  /// the device connected, or since something other than a scalar command may have changed its
+  /// output (see [GenericCommandManager::reset_scalar_state]).
+  pub fn previous(&self, index: usize) -> Option<u32> {
+    self.previous.get(index).copied().flatten()
+  }
+
+  /// Change from the previous value of a feature in this command. None if the feature isn't in the
+  /// command, or has no previous value.
+  pub fn delta(&self, index: usize) -> Option<i64> {
+    let (_, value) = self.commands.get(index).copied().flatten()?;
+    Some(value as i64 - self.previous(index)? as i64)
+  }
+}
+
 #[derive(Getters)]
 #[getset(get = "pub")]
 struct ScalarGenericCommand {
   actuator: ActuatorType,
   step_range: RangeInclusive<u32>,
+  intensity_curve: Option<IntensityCurve>,
   value: AtomicU32,
 }
 
@@ -39,9 +81,44 @@ impl ScalarGenericCommand {
     Self {
       actuator: *attributes.actuator_type(),
       step_range: attributes.step_limit().clone(),
+      intensity_curve: attributes.intensity_curve().clone(),
       value: AtomicU32::new(0),
     }
   }
+
+  /// Convert a scalar (0.0-1.0) to a step value.
+  fn to_step(&self, scalar: f64) -> u32 {
+    // Apply any user configured curve before quantizing to steps.
+    let value = if let Some(curve) = self.intensity_curve() {
+      curve.apply(scalar)
+    } else {
+      scalar
+    };
+    let range_start = self.step_range().start();
+    let range = self.step_range().end() - range_start;
+    let scalar_modifier = value * range as f64;
+    let step = if scalar_modifier < 0.0001 {
+      0
+    } else if *range_start == 0 {
+      // When calculating speeds, round up. This follows how we calculated
+      // things in buttplug-js and buttplug-csharp, so it's more for history
+      // than anything, but it's what users will expect.
+      scalar_modifier.ceil() as u32
+    } else {
+      // Ranges with a minimum are for motors that stall below it. Rounding up would mean the
+      // minimum is never sent, so round to the nearest step instead, any value above 0 will still
+      // at least get the minimum.
+      (scalar_modifier + *range_start as f64).round() as u32
+    };
+    trace!(
+      "{:?} {} {} {}",
+      self.step_range(),
+      range,
+      scalar_modifier,
+      step
+    );
+    step
+  }
 }
 
 // In order to make our lives easier, we make some assumptions about what's internally mutable in
@@ -59,9 +136,14 @@ pub struct GenericCommandManager {
   scalars: Vec<ScalarGenericCommand>,
   rotations: Vec<(AtomicU32, AtomicBool)>,
   rotation_step_ranges: Vec<RangeInclusive<u32>>,
+  // True for rotation features the user config inverts. Directions are stored as sent to the
+  // protocol, so they're flipped on the way in.
+  inverted_rotations: Vec<bool>,
   _linears: Vec<(u32, u32)>,
   _linear_step_counts: Vec<u32>,
   stop_commands: Vec<ButtplugDeviceCommandMessageUnion>,
+  // Last values handed to the protocol, which differ from the scalar values above while ramping.
+  protocol_scalars: Mutex<Vec<Option<u32>>>,
 }
 
 impl GenericCommandManager {
@@ -69,6 +151,7 @@ impl GenericCommandManager {
     let mut scalars = vec![];
     let mut rotations = vec![];
     let mut rotation_step_ranges = vec![];
+    let mut inverted_rotations = vec![];
     let mut linears = vec![];
     let mut linear_step_counts = vec![];
 
@@ -89,8 +172,9 @@ impl GenericCommandManager {
     }
     if let Some(attrs) = attributes.message_attributes().rotate_cmd() {
       rotations.resize_with(attrs.len(), || (AtomicU32::new(0), AtomicBool::new(false)));
-      for attr in attrs {
+      for (index, attr) in attrs.iter().enumerate() {
         rotation_step_ranges.push(attr.step_range().clone());
+        inverted_rotations.push(attributes.inverts_rotation(index as u32));
       }
 
       // TODO Can we assume clockwise is false here? We might send extra
@@ -110,6 +194,7 @@ impl GenericCommandManager {
       }
     }
 
+    let scalars_len = scalars.len();
     Self {
       sent_scalar: AtomicBool::new(false),
       sent_rotation: AtomicBool::new(false),
@@ -118,11 +203,19 @@ impl GenericCommandManager {
       rotations,
       _linears: linears,
       rotation_step_ranges,
+      inverted_rotations,
       _linear_step_counts: linear_step_counts,
       stop_commands,
+      protocol_scalars: Mutex::new(vec![None; scalars_len]),
     }
   }
 
+  /// Convert a ScalarCmd into the per-feature command vector handed to the protocol.
+  ///
+  /// Features the message doesn't mention are always left as they are on the device. They're None
+  /// in the returned vector, unless `match_all` is set, in which case they're filled with the last
+  /// values sent to them rather than any default. This is what allows clients to stop or change a
+  /// single feature without knowing the state of the others.
   pub fn update_scalar(
     &self,
     msg: &ScalarCmd,
@@ -162,24 +255,7 @@ impl GenericCommandManager {
         );
       }
 
-      let range_start = self.scalars[index].step_range().start();
-      let range = self.scalars[index].step_range().end() - range_start;
-      let scalar_modifier = scalar_command.scalar() * range as f64;
-      let scalar = if scalar_modifier < 0.0001 {
-        0
-      } else {
-        // When calculating speeds, round up. This follows how we calculated
-        // things in buttplug-js and buttplug-csharp, so it's more for history
-        // than anything, but it's what users will expect.
-        (scalar_modifier + *range_start as f64).ceil() as u32
-      };
-      trace!(
-        "{:?} {} {} {}",
-        self.scalars[index].step_range(),
-        range,
-        scalar_modifier,
-        scalar
-      );
+      let scalar = self.scalars[index].to_step(scalar_command.scalar());
       // If we've already sent commands, we don't want to send them again,
       // because some of our communication busses are REALLY slow. Make sure
       // these values get None in our return vector.
@@ -213,6 +289,47 @@ impl GenericCommandManager {
     Ok(result)
   }
 
+  /// Step value a ScalarCmd with this scalar would set a feature to. None if there's no such
+  /// feature.
+  pub fn scalar_step(&self, index: usize, scalar: f64) -> Option<u32> {
+    Some(self.scalars.get(index)?.to_step(scalar))
+  }
+
+  /// Step value last set for a feature, by a ScalarCmd or [Self::set_scalar_step].
+  pub fn scalar_value(&self, index: usize) -> Option<u32> {
+    Some(self.scalars.get(index)?.value().load(SeqCst))
+  }
+
+  /// Set a feature to a step value directly (e.g. for a ramp step), returning the command vector
+  /// for the protocol the same way [Self::update_scalar] does. The value is always sent.
+  pub fn set_scalar_step(
+    &self,
+    index: usize,
+    value: u32,
+    match_all: bool,
+  ) -> Vec<Option<(ActuatorType, u32)>> {
+    let mut result = vec![None; self.scalars.len()];
+    for (feature_index, cmd) in self.scalars.iter().enumerate() {
+      if feature_index == index {
+        cmd.value().store(value, SeqCst);
+        result[feature_index] = Some((*cmd.actuator(), value));
+      } else if match_all {
+        result[feature_index] = Some((*cmd.actuator(), cmd.value().load(SeqCst)));
+      }
+    }
+    result
+  }
+
+  /// Store step values without sending them, e.g. to return features that take one-off
+  /// adjustments to their "no change" value. Indexes the device doesn't have are ignored.
+  pub fn reset_scalar_values(&self, values: &[(usize, u32)]) {
+    for (index, value) in values {
+      if let Some(cmd) = self.scalars.get(*index) {
+        cmd.value().store(*value, SeqCst);
+      }
+    }
+  }
+
   // Test method
   #[cfg(test)]
   pub(super) fn scalars(&self) -> Vec<Option<(ActuatorType, u32)>> {
@@ -274,7 +391,9 @@ impl GenericCommandManager {
         // than anything, but it's what users will expect.
         (speed_modifier + *self.rotation_step_ranges[index].start() as f64).ceil() as u32
       };
-      let clockwise = rotate_command.clockwise();
+      // Inverted before the protocol sees the command, so it composes with however the protocol
+      // encodes direction.
+      let clockwise = rotate_command.clockwise() != self.inverted_rotations[index];
       // If we've already sent commands, we don't want to send them again,
       // because some of our communication busses are REALLY slow. Make sure
       // these values get None in our return vector.
@@ -306,6 +425,17 @@ impl GenericCommandManager {
     Ok(result)
   }
 
+  /// RotateCmd feature indexes whose direction is inverted by the user config.
+  pub fn inverted_rotations(&self) -> Vec<u32> {
+    self
+      .inverted_rotations
+      .iter()
+      .enumerate()
+      .filter(|(_, inverted)| **inverted)
+      .map(|(index, _)| index as u32)
+      .collect()
+  }
+
   pub fn _update_linear(&self, _msg: &LinearCmd) -> Result<Option<Vec<(u32, u32)>>, ButtplugError> {
     // First, make sure this is a valid command, that doesn't contain an
     // index we can't reach.
@@ -325,6 +455,32 @@ impl GenericCommandManager {
     Ok(None)
   }
 
+  /// Forget that scalar values have been sent, so the next scalar command is passed through to the
+  /// protocol even if its values haven't changed. Used when something other than a scalar command
+  /// may have changed actuator output on the device.
+  pub fn reset_scalar_state(&self) {
+    self.sent_scalar.store(false, SeqCst);
+    self
+      .protocol_scalars
+      .lock()
+      .expect("Lock poisoned")
+      .iter_mut()
+      .for_each(|value| *value = None);
+  }
+
+  /// Pair scalar commands about to be handed to the protocol with the values previously handed to
+  /// their features, and remember the new values for the next command.
+  pub fn scalar_context(&self, commands: Vec<Option<(ActuatorType, u32)>>) -> ScalarCommandContext {
+    let mut protocol_scalars = self.protocol_scalars.lock().expect("Lock poisoned");
+    let previous = protocol_scalars.clone();
+    for (value, command) in protocol_scalars.iter_mut().zip(commands.iter()) {
+      if let Some((_, scalar)) = command {
+        *value = Some(*scalar);
+      }
+    }
+    ScalarCommandContext::new(commands, previous)
+  }
+
   pub fn stop_commands(&self) -> Vec<ButtplugDeviceCommandMessageUnion> {
     self.stop_commands.clone()
   }
@@ -604,3 +760,143 @@ mod test {
   // TODO Write test for vibration stop generator
 }
 */
+
+#[cfg(test)]
+mod test {
+  use super::{GenericCommandManager, ProtocolDeviceAttributes, ScalarCommandContext};
+  use crate::{
+    core::{
+      errors::ButtplugDeviceError,
+      message::{
+        ActuatorType,
+        ButtplugActuatorFeatureMessageType,
+        DeviceFeature,
+        DeviceFeatureActuator,
+        FeatureType,
+        ScalarCmd,
+        ScalarSubcommand,
+      },
+    },
+    server::device::{hardware::HardwareCommand, protocol::ProtocolHandler},
+  };
+  use std::{collections::HashSet, ops::RangeInclusive, sync::Mutex};
+
+  fn vibrators(count: usize, step_limit: RangeInclusive<u32>) -> GenericCommandManager {
+    let feature = DeviceFeature::new(
+      "",
+      FeatureType::Vibrate,
+      &Some(DeviceFeatureActuator::new(
+        &(0..=*step_limit.end()),
+        &step_limit,
+        &HashSet::from([ButtplugActuatorFeatureMessageType::ScalarCmd]),
+      )),
+      &None,
+    );
+    GenericCommandManager::new(&ProtocolDeviceAttributes::new(
+      "Whatever",
+      &None,
+      &vec![feature; count].into(),
+    ))
+  }
+
+  fn vibrate_msg(scalars: &[(u32, f64)]) -> ScalarCmd {
+    ScalarCmd::new(
+      0,
+      scalars
+        .iter()
+        .map(|(index, scalar)| ScalarSubcommand::new(*index, *scalar, ActuatorType::Vibrate))
+        .collect(),
+    )
+  }
+
+  fn vibrate(value: u32) -> Option<(ActuatorType, u32)> {
+    Some((ActuatorType::Vibrate, value))
+  }
+
+  // Context for a command, the way the server builds it after updating the scalars.
+  fn context(mgr: &GenericCommandManager, scalars: &[(u32, f64)]) -> ScalarCommandContext {
+    let commands = mgr
+      .update_scalar(&vibrate_msg(scalars), false)
+      .expect("Test, assuming infallible.");
+    mgr.scalar_context(commands)
+  }
+
+  #[test]
+  pub fn test_scalar_context_previous_values() {
+    let mgr = vibrators(2, 0..=100);
+    let first = context(&mgr, &[(0, 0.5), (1, 0.2)]);
+    assert_eq!(first.commands(), &[vibrate(50), vibrate(20)]);
+    assert_eq!(first.previous(0), None);
+    assert_eq!(first.delta(0), None);
+
+    let second = context(&mgr, &[(0, 0.7)]);
+    assert_eq!(second.commands(), &[vibrate(70), None]);
+    assert_eq!(second.previous(0), Some(50));
+    assert_eq!(second.previous(1), Some(20));
+    assert_eq!(second.delta(0), Some(20));
+    assert_eq!(second.delta(1), None);
+    assert_eq!(second.previous(2), None);
+
+    // Once the output may have changed elsewhere, previous values are unknown again.
+    mgr.reset_scalar_state();
+    let third = context(&mgr, &[(0, 0.7)]);
+    assert_eq!(third.commands(), &[vibrate(70), None]);
+    assert_eq!(third.previous(0), None);
+    assert_eq!(third.previous(1), None);
+  }
+
+  type ScalarCommands = Vec<Option<(ActuatorType, u32)>>;
+
+  // Only implements the old scalar handler, like most protocols.
+  #[derive(Default)]
+  struct LegacyRecorder {
+    commands: Mutex<Vec<ScalarCommands>>,
+  }
+
+  impl ProtocolHandler for LegacyRecorder {
+    fn handle_scalar_cmd(
+      &self,
+      commands: &[Option<(ActuatorType, u32)>],
+    ) -> Result<Vec<HardwareCommand>, ButtplugDeviceError> {
+      self.commands.lock().unwrap().push(commands.to_vec());
+      Ok(vec![])
+    }
+  }
+
+  #[test]
+  pub fn test_scalar_context_forwards_to_legacy_handler() {
+    let mgr = vibrators(2, 0..=100);
+    let handler = LegacyRecorder::default();
+    for scalars in [&[(0, 0.5), (1, 0.2)][..], &[(0, 0.7)]] {
+      handler
+        .handle_scalar_cmd_with_context(&context(&mgr, scalars))
+        .expect("Test, assuming infallible.");
+    }
+    assert_eq!(
+      *handler.commands.lock().unwrap(),
+      vec![vec![vibrate(50), vibrate(20)], vec![vibrate(70), None]]
+    );
+  }
+
+  fn quantize(step_limit: RangeInclusive<u32>, scalar: f64) -> u32 {
+    vibrators(1, step_limit)
+      .update_scalar(&vibrate_msg(&[(0, scalar)]), false)
+      .expect("Test, assuming infallible.")[0]
+      .expect("Test, assuming infallible.")
+      .1
+  }
+
+  #[test]
+  pub fn test_scalar_step_range_minimum() {
+    assert_eq!(quantize(3..=20, 1.0), 20);
+    // Anything above 0 should at least get the minimum, 0 is still off.
+    assert_eq!(quantize(3..=20, 0.01), 3);
+    assert_eq!(quantize(3..=20, 0.0), 0);
+    assert_eq!(quantize(3..=20, 0.5), 12);
+
+    // Without a minimum, values above 0 round up to the first step.
+    assert_eq!(quantize(0..=20, 1.0), 20);
+    assert_eq!(quantize(0..=20, 0.01), 1);
+    assert_eq!(quantize(0..=20, 0.0), 0);
+  }
+}
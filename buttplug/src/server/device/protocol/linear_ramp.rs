@@ -0,0 +1,132 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! LinearCmd as a ramp, for devices without positions. The position is the output value to reach,
+//! and the duration is how long getting there takes.
+//!
+//! Protocols can either drive ramps themselves with [spawn_linear_ramp], or have the server ramp
+//! their scalar features by returning a [RampConfig] from
+//! [ProtocolHandler::linear_ramp_config](super::ProtocolHandler::linear_ramp_config).
+
+use crate::{
+  core::errors::ButtplugError,
+  util::{self, async_manager},
+};
+use futures::future::BoxFuture;
+use getset::CopyGetters;
+use std::{sync::Mutex, time::Duration};
+use tokio_util::sync::CancellationToken;
+
+/// How the server ramps scalar features for a protocol's LinearCmd. LinearCmd index `n` ramps the
+/// `n`th scalar feature, to `position` of its step range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, CopyGetters)]
+#[getset(get_copy = "pub")]
+pub struct RampConfig {
+  /// Shortest time between two steps. Ramps with fewer steps than the duration has intervals for
+  /// (e.g. going from 0 to 3 over a second) step less often.
+  interval: Duration,
+}
+
+impl RampConfig {
+  pub fn new(interval: Duration) -> Self {
+    Self { interval }
+  }
+}
+
+/// Values a ramp from `start` to `target` goes through, one per step. The last value is always
+/// `target`, and values never repeat, so slow ramps get fewer steps than the duration has room
+/// for.
+pub fn ramp_steps(start: u32, target: u32, duration: Duration, interval: Duration) -> Vec<u32> {
+  let distance = start.abs_diff(target) as u64;
+  let steps = (duration.as_millis() as u64 / interval.as_millis().max(1) as u64)
+    .min(distance)
+    .max(1);
+  (1..=steps)
+    .map(|step| (start as i64 + (target as i64 - start as i64) * step as i64 / steps as i64) as u32)
+    .collect()
+}
+
+/// Spawn a task ramping from `start` to `target` over `duration`, handing each step from
+/// [ramp_steps] to `step`. Steps are evenly spread over the duration, and each one waits on the
+/// previous step's write, so a slow device falls behind rather than getting a burst of writes to
+/// catch up. The ramp stops without writing anything further once `cancel` is cancelled, or if a
+/// step fails.
+pub fn spawn_linear_ramp<F>(
+  cancel: CancellationToken,
+  start: u32,
+  target: u32,
+  duration: Duration,
+  interval: Duration,
+  mut step: F,
+) where
+  F: FnMut(u32) -> BoxFuture<'static, Result<(), ButtplugError>> + Send + 'static,
+{
+  let values = ramp_steps(start, target, duration, interval);
+  let step_duration = duration / values.len() as u32;
+  async_manager::spawn(async move {
+    for value in values {
+      tokio::select! {
+        _ = util::sleep(step_duration) => {}
+        _ = cancel.cancelled() => return,
+      }
+      // Both can be ready at once, and select doesn't prefer either.
+      if cancel.is_cancelled() {
+        return;
+      }
+      if let Err(e) = step(value).await {
+        warn!("Error writing linear ramp step, cancelling ramp: {:?}", e);
+        return;
+      }
+    }
+  });
+}
+
+/// Cancellation for the ramps of a device. Starting new ramps cancels the running ones.
+#[derive(Debug, Default)]
+pub struct LinearRamps {
+  cancel: Mutex<CancellationToken>,
+}
+
+impl LinearRamps {
+  /// Cancel any running ramps, and return the token for the next ones.
+  pub fn restart(&self) -> CancellationToken {
+    let mut cancel = self.cancel.lock().expect("Lock poisoned");
+    cancel.cancel();
+    *cancel = CancellationToken::new();
+    cancel.clone()
+  }
+
+  /// Cancel any running ramps.
+  pub fn cancel(&self) {
+    self.cancel.lock().expect("Lock poisoned").cancel();
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_ramp_steps() {
+    let interval = Duration::from_millis(100);
+    assert_eq!(
+      ramp_steps(0, 100, Duration::from_millis(500), interval),
+      vec![20, 40, 60, 80, 100]
+    );
+    assert_eq!(
+      ramp_steps(100, 0, Duration::from_millis(300), interval),
+      vec![67, 34, 0]
+    );
+    // Fewer values than intervals, and ramps shorter than an interval.
+    assert_eq!(
+      ramp_steps(0, 3, Duration::from_secs(1), interval),
+      vec![1, 2, 3]
+    );
+    assert_eq!(ramp_steps(7, 42, Duration::ZERO, interval), vec![42]);
+    assert_eq!(ramp_steps(5, 5, Duration::from_secs(1), interval), vec![5]);
+  }
+}
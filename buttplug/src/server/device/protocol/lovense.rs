@@ -79,6 +79,17 @@ fn lovense_model_resolver(type_response: String) -> String {
   identifier
 }
 
+// The third part of the DeviceType response is the device id, which stays the same even if the
+// address changes (e.g. when moving between dongles and native bluetooth).
+fn lovense_serial_resolver(type_response: &str) -> Option<String> {
+  type_response
+    .trim_end_matches(';')
+    .split(':')
+    .nth(2)
+    .filter(|serial| !serial.is_empty())
+    .map(|serial| serial.to_owned())
+}
+
 #[async_trait]
 impl ProtocolIdentifier for LovenseIdentifier {
   async fn identify(
@@ -100,8 +111,9 @@ impl ProtocolIdentifier for LovenseIdentifier {
           if let Ok(HardwareEvent::Notification(_, _, n)) = event {
             let type_response = std::str::from_utf8(&n).map_err(|_| ButtplugDeviceError::ProtocolSpecificError("lovense".to_owned(), "Lovense device init got back non-UTF8 string.".to_owned()))?.to_owned();
             debug!("Lovense Device Type Response: {}", type_response);
+            let serial = lovense_serial_resolver(&type_response);
             let ident = lovense_model_resolver(type_response);
-            return Ok((UserDeviceIdentifier::new(hardware.address(), "lovense", &Some(ident.clone())), Box::new(LovenseInitializer::new(ident))));
+            return Ok((UserDeviceIdentifier::new(hardware.address(), "lovense", &Some(ident.clone())), Box::new(LovenseInitializer::new(ident).with_serial(serial))));
           } else {
             return Err(
               ButtplugDeviceError::ProtocolSpecificError(
@@ -129,11 +141,20 @@ impl ProtocolIdentifier for LovenseIdentifier {
 }
 pub struct LovenseInitializer {
   device_type: String,
+  serial: Option<String>,
 }
 
 impl LovenseInitializer {
   pub fn new(device_type: String) -> Self {
-    Self { device_type }
+    Self {
+      device_type,
+      serial: None,
+    }
+  }
+
+  pub fn with_serial(mut self, serial: Option<String>) -> Self {
+    self.serial = serial;
+    self
   }
 }
 
@@ -172,6 +193,10 @@ impl ProtocolInitializer for LovenseInitializer {
     );
     Ok(Arc::new(protocol))
   }
+
+  fn device_serial(&self) -> Option<String> {
+    self.serial.clone()
+  }
 }
 
 #[derive(Default)]
@@ -6,11 +6,34 @@
 // for full license information.
 
 //! Implementations of communication protocols for hardware supported by Buttplug
+//!
+//! ## Protocols From Outside The Library
+//!
+//! Protocols can also be implemented outside of the library, and registered with
+//! [ServerDeviceManagerBuilder::add_protocol_factory](crate::server::device::ServerDeviceManagerBuilder::add_protocol_factory).
+//! The least an implementation needs is:
+//!
+//! - A [ProtocolHandler], turning client commands into [HardwareCommand]s. Every method has a
+//!   default, so only the commands the device takes have to be implemented (e.g.
+//!   [ProtocolHandler::handle_scalar_vibrate_cmd]).
+//! - A [ProtocolIdentifierFactory] whose [ProtocolIdentifierFactory::create] wraps a new handler in
+//!   a [GenericProtocolIdentifier], and whose [ProtocolIdentifierFactory::identifier] returns the
+//!   name the protocol is registered under.
+//!
+//! Devices that need a handshake before use, or that identify themselves by something other than
+//! their advertised name, need their own [ProtocolIdentifier] and [ProtocolInitializer] instead.
+//! Like built in protocols, the protocol also needs communication specifiers and device features
+//! in a config before devices can use it.
 
 pub mod generic_command_manager;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod scalar_pipeline_sim;
 
 // Utility mods
+pub mod dg_lab;
 pub mod fleshlight_launch_helper;
+pub mod galaku_framing;
+pub mod linear_ramp;
 
 // Since users can pick and choose protocols, we need all of these to be public.
 pub mod adrienlastic;
@@ -135,17 +158,21 @@ use crate::{
     },
   },
   server::device::{
-    configuration::{ProtocolCommunicationSpecifier, UserDeviceIdentifier},
-    hardware::{Hardware, HardwareCommand, HardwareReadCmd},
+    configuration::{InitSequenceWrite, ProtocolCommunicationSpecifier, UserDeviceIdentifier},
+    hardware::{Hardware, HardwareCommand, HardwareEvent, HardwareReadCmd},
   },
+  util::{self, async_manager},
 };
 use async_trait::async_trait;
 use futures::{
   future::{self, BoxFuture, FutureExt},
   StreamExt,
 };
+use generic_command_manager::ScalarCommandContext;
+use once_cell::sync::Lazy;
 use std::pin::Pin;
 use std::{collections::HashMap, sync::Arc};
+use tokio::sync::broadcast::error::RecvError;
 
 /// Strategy for situations where hardware needs to get updates every so often in order to keep
 /// things alive. Currently this only applies to iOS backgrounding with bluetooth devices, but since
@@ -187,6 +214,20 @@ pub trait ProtocolIdentifierFactory: Send + Sync {
   fn create(&self) -> Box<dyn ProtocolIdentifier>;
 }
 
+static SUPPORTED_PROTOCOLS: Lazy<Vec<String>> = Lazy::new(|| {
+  let mut protocols: Vec<String> = get_default_protocol_map().into_keys().collect();
+  protocols.sort();
+  protocols
+});
+
+/// Identifiers of the protocols compiled into this build, sorted by name.
+///
+/// Built from the same table as [get_default_protocol_map], so protocols behind disabled features
+/// won't show up here.
+pub fn supported_protocols() -> Vec<&'static str> {
+  SUPPORTED_PROTOCOLS.iter().map(String::as_str).collect()
+}
+
 pub fn get_default_protocol_map() -> HashMap<String, Arc<dyn ProtocolIdentifierFactory>> {
   let mut map = HashMap::new();
   fn add_to_protocol_map<T>(
@@ -556,21 +597,29 @@ fn print_type_of<T>(_: &T) -> &'static str {
 }
 
 pub struct ProtocolSpecializer {
+  protocol: String,
   specifiers: Vec<ProtocolCommunicationSpecifier>,
   identifier: Box<dyn ProtocolIdentifier>,
 }
 
 impl ProtocolSpecializer {
   pub fn new(
+    protocol: &str,
     specifiers: Vec<ProtocolCommunicationSpecifier>,
     identifier: Box<dyn ProtocolIdentifier>,
   ) -> Self {
     Self {
+      protocol: protocol.to_owned(),
       specifiers,
       identifier,
     }
   }
 
+  /// Name of the protocol the specifiers belong to.
+  pub fn protocol(&self) -> &str {
+    &self.protocol
+  }
+
   pub fn specifiers(&self) -> &Vec<ProtocolCommunicationSpecifier> {
     &self.specifiers
   }
@@ -590,16 +639,166 @@ pub trait ProtocolIdentifier: Sync + Send {
 
 #[async_trait]
 pub trait ProtocolInitializer: Sync + Send {
+  /// Run any setup the device needs before it can be used. Failed initializations are retried (see
+  /// [InitializationRetryPolicy](crate::server::device::server_device::InitializationRetryPolicy)),
+  /// so background tasks should only be spawned once nothing else can fail.
   async fn initialize(
     &mut self,
     hardware: Arc<Hardware>,
     attributes: &ProtocolDeviceAttributes,
   ) -> Result<Arc<dyn ProtocolHandler>, ButtplugDeviceError>;
+
+  /// Stable identifier (usually a serial number) for the device, if the protocol can retrieve one.
+  /// Checked before initialize, so the user configuration stored against it is the one initialize
+  /// sees, and again after initialize for protocols that only learn it then. Used to find a
+  /// device's user configuration when its address changes.
+  fn device_serial(&self) -> Option<String> {
+    None
+  }
+
+  /// Endpoints the protocol can't work without. Checked against the endpoints the hardware exposed
+  /// before initialize is called, so devices missing any of them fail to connect instead of failing
+  /// writes later on.
+  fn required_endpoints(&self) -> Vec<Endpoint> {
+    vec![]
+  }
+
+  /// Scalar feature layouts the protocol can drive, as the actuator type of each ScalarCmd feature
+  /// in index order. Protocols that pick outputs by feature index would send values to the wrong
+  /// output with any other layout, so devices whose config doesn't match one of these fail to
+  /// connect (see [check_scalar_feature_layout]). Checked before initialize. An empty list accepts
+  /// any layout.
+  fn scalar_feature_layouts(&self, _identifier: &UserDeviceIdentifier) -> Vec<Vec<ActuatorType>> {
+    vec![]
+  }
+
+  /// Highest step the device itself accepts for ScalarCmd features (keyed by feature index), if
+  /// the protocol can read limits set on the device. Called after initialize. The advertised step
+  /// counts of those features are capped to match, so clients scale to what the device will output.
+  fn scalar_step_caps(&self) -> HashMap<u32, u32> {
+    HashMap::new()
+  }
+}
+
+/// Call [ProtocolHandler::handle_hardware_notification] for every notification the hardware sends,
+/// until the hardware disconnects.
+pub fn forward_hardware_notifications(hardware: &Hardware, handler: Arc<dyn ProtocolHandler>) {
+  let mut event_receiver = hardware.event_stream();
+  async_manager::spawn(async move {
+    loop {
+      match event_receiver.recv().await {
+        Ok(HardwareEvent::Notification(_, endpoint, data)) => {
+          handler.handle_hardware_notification(endpoint, &data)
+        }
+        Ok(HardwareEvent::Disconnected(_)) | Err(RecvError::Closed) => return,
+        Err(RecvError::Lagged(count)) => {
+          warn!("Protocol handler missed {} hardware notifications", count)
+        }
+      }
+    }
+  });
+}
+
+/// Make the init sequence writes from a device's config specifier, in order, waiting out each
+/// write's delay. Run before the protocol is initialized. Failed writes are returned as
+/// [ButtplugDeviceError::DeviceConnectionError], as the device can't be set up without them.
+pub async fn write_init_sequence(
+  hardware: &Hardware,
+  sequence: &[InitSequenceWrite],
+) -> Result<(), ButtplugDeviceError> {
+  for (index, write) in sequence.iter().enumerate() {
+    hardware
+      .write_value(&HardwareWriteCmd::new(
+        write.endpoint(),
+        write.data().clone(),
+        write.write_with_response(),
+      ))
+      .await
+      .map_err(|err| {
+        ButtplugDeviceError::DeviceConnectionError(format!(
+          "Init sequence write {} to {:?} failed: {:?}",
+          index,
+          write.endpoint(),
+          err
+        ))
+      })?;
+    if write.delay_ms().is_some() {
+      util::sleep(write.delay()).await;
+    }
+  }
+  Ok(())
+}
+
+/// Fail with a [ButtplugDeviceError::DeviceConnectionError] naming any of the `required` endpoints
+/// the hardware doesn't have.
+pub fn check_required_endpoints(
+  hardware: &Hardware,
+  required: &[Endpoint],
+) -> Result<(), ButtplugDeviceError> {
+  let endpoints = hardware.endpoints();
+  let missing: Vec<String> = required
+    .iter()
+    .filter(|endpoint| !endpoints.contains(endpoint))
+    .map(|endpoint| endpoint.to_string())
+    .collect();
+  if missing.is_empty() {
+    Ok(())
+  } else {
+    Err(ButtplugDeviceError::DeviceConnectionError(format!(
+      "Device {} ({}) is missing endpoints required by its protocol: {}",
+      hardware.name(),
+      hardware.address(),
+      missing.join(", ")
+    )))
+  }
+}
+
+/// Fail with a [ButtplugDeviceError::DeviceConfigurationError] if the scalar features of a device
+/// config don't match any of the `layouts` its protocol declared, see
+/// [ProtocolInitializer::scalar_feature_layouts].
+pub fn check_scalar_feature_layout(
+  identifier: &UserDeviceIdentifier,
+  attributes: &ProtocolDeviceAttributes,
+  layouts: &[Vec<ActuatorType>],
+) -> Result<(), ButtplugDeviceError> {
+  let features: Vec<ActuatorType> = attributes
+    .message_attributes()
+    .scalar_cmd()
+    .as_deref()
+    .unwrap_or_default()
+    .iter()
+    .map(|feature| *feature.actuator_type())
+    .collect();
+  if layouts.is_empty() || layouts.contains(&features) {
+    return Ok(());
+  }
+  let format_layout = |layout: &[ActuatorType]| {
+    format!(
+      "[{}]",
+      layout
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>()
+        .join(", ")
+    )
+  };
+  Err(ButtplugDeviceError::DeviceConfigurationError(format!(
+    "Device config for {} has scalar features {}, but protocol {} expects {}",
+    identifier,
+    format_layout(&features),
+    identifier.protocol(),
+    layouts
+      .iter()
+      .map(|layout| format_layout(layout))
+      .collect::<Vec<String>>()
+      .join(" or ")
+  )))
 }
 
 pub struct GenericProtocolIdentifier {
   handler: Option<Arc<dyn ProtocolHandler>>,
   protocol_identifier: String,
+  forward_notifications: bool,
 }
 
 impl GenericProtocolIdentifier {
@@ -607,6 +806,19 @@ impl GenericProtocolIdentifier {
     Self {
       handler: Some(handler),
       protocol_identifier: protocol_identifier.to_owned(),
+      forward_notifications: false,
+    }
+  }
+
+  /// Same as [GenericProtocolIdentifier::new], but hardware notifications will be passed to the
+  /// handler once the device is initialized.
+  pub fn new_with_notifications(
+    handler: Arc<dyn ProtocolHandler>,
+    protocol_identifier: &str,
+  ) -> Self {
+    Self {
+      forward_notifications: true,
+      ..Self::new(handler, protocol_identifier)
     }
   }
 }
@@ -622,23 +834,35 @@ impl ProtocolIdentifier for GenericProtocolIdentifier {
       &self.protocol_identifier,
       &Some(hardware.name().to_owned()),
     );
-    Ok((
-      device_identifier,
-      Box::new(GenericProtocolInitializer::new(
-        self.handler.take().unwrap(),
-      )),
-    ))
+    let handler = self.handler.take().unwrap();
+    let initializer = if self.forward_notifications {
+      GenericProtocolInitializer::new_with_notifications(handler)
+    } else {
+      GenericProtocolInitializer::new(handler)
+    };
+    Ok((device_identifier, Box::new(initializer)))
   }
 }
 
 pub struct GenericProtocolInitializer {
   handler: Option<Arc<dyn ProtocolHandler>>,
+  forward_notifications: bool,
 }
 
 impl GenericProtocolInitializer {
   pub fn new(handler: Arc<dyn ProtocolHandler>) -> Self {
     Self {
       handler: Some(handler),
+      forward_notifications: false,
+    }
+  }
+
+  /// Same as [GenericProtocolInitializer::new], but hardware notifications will be passed to the
+  /// handler after initialization. See [forward_hardware_notifications].
+  pub fn new_with_notifications(handler: Arc<dyn ProtocolHandler>) -> Self {
+    Self {
+      handler: Some(handler),
+      forward_notifications: true,
     }
   }
 }
@@ -647,10 +871,22 @@ impl GenericProtocolInitializer {
 impl ProtocolInitializer for GenericProtocolInitializer {
   async fn initialize(
     &mut self,
-    _: Arc<Hardware>,
+    hardware: Arc<Hardware>,
     _: &ProtocolDeviceAttributes,
   ) -> Result<Arc<dyn ProtocolHandler>, ButtplugDeviceError> {
-    Ok(self.handler.take().unwrap())
+    let handler = self.handler.take().unwrap();
+    if self.forward_notifications {
+      forward_hardware_notifications(&hardware, handler.clone());
+    }
+    Ok(handler)
+  }
+
+  fn required_endpoints(&self) -> Vec<Endpoint> {
+    self
+      .handler
+      .as_ref()
+      .map(|handler| handler.required_endpoints())
+      .unwrap_or_default()
   }
 }
 
@@ -659,6 +895,20 @@ pub trait ProtocolHandler: Sync + Send {
     false
   }
 
+  /// Scalar features whose commands are one-off adjustments rather than levels, with the step
+  /// value meaning "no change". Their stored value is reset after every command, so sending the
+  /// same adjustment twice reaches the handler twice instead of being dropped as unchanged.
+  fn scalar_adjustment_features(&self) -> Vec<(usize, u32)> {
+    vec![]
+  }
+
+  /// Endpoints the handler can't work without. Only checked for protocols using the generic
+  /// initializer, protocols with their own initializer declare them with
+  /// [ProtocolInitializer::required_endpoints].
+  fn required_endpoints(&self) -> Vec<Endpoint> {
+    vec![]
+  }
+
   fn has_handle_message(&self) -> bool {
     false
   }
@@ -667,6 +917,39 @@ pub trait ProtocolHandler: Sync + Send {
     ProtocolKeepaliveStrategy::NoStrategy
   }
 
+  // Called for every connected device when the client disconnects, after the device has been
+  // stopped. Protocols that hold output state for background tasks (repeat loops, patterns, etc)
+  // should reset it here, and return whatever final commands are needed to silence the device.
+  fn handle_client_disconnect(&self) -> Result<Vec<HardwareCommand>, ButtplugDeviceError> {
+    Ok(vec![])
+  }
+
+  // Called for every connected device on server shutdown, once the device has been stopped (see
+  // handle_client_disconnect) and its hardware disconnected. Protocols that spawned background
+  // tasks should halt them here.
+  fn handle_shutdown(&self) {
+  }
+
+  // Current output state of the handler (e.g. per channel power and frequency), for UIs showing
+  // what the device is doing. Only protocols that keep their own output state report one. This can
+  // be polled, so it should only read atomics, never wait on locks.
+  fn state_snapshot(&self) -> Option<serde_json::Value> {
+    None
+  }
+
+  // Vibrating protocols can return a config here to have LinearCmd ramp their scalar features (see
+  // linear_ramp) instead of going to handle_linear_cmd. Ramp steps are handed to
+  // handle_scalar_cmd like any other scalar command.
+  fn linear_ramp_config(&self) -> Option<linear_ramp::RampConfig> {
+    None
+  }
+
+  // Called for every notification the hardware sends, for protocols set up with
+  // generic_protocol_notification_setup! (or that call forward_hardware_notifications themselves).
+  // Endpoints still need to be subscribed to for notifications to arrive.
+  fn handle_hardware_notification(&self, _endpoint: Endpoint, _data: &[u8]) {
+  }
+
   fn handle_message(
     &self,
     message: &ButtplugDeviceCommandMessageUnion,
@@ -687,6 +970,16 @@ pub trait ProtocolHandler: Sync + Send {
     )))
   }
 
+  // This is what the server calls for scalar commands. Protocols that encode changes rather than
+  // absolute values can use the previous values in the context, everything else can keep
+  // implementing handle_scalar_cmd, which this forwards to by default.
+  fn handle_scalar_cmd_with_context(
+    &self,
+    context: &ScalarCommandContext,
+  ) -> Result<Vec<HardwareCommand>, ButtplugDeviceError> {
+    self.handle_scalar_cmd(context.commands())
+  }
+
   // The default scalar handler assumes that most devices require discrete commands per feature. If
   // a protocol has commands that combine multiple features, either with matched or unmatched
   // actuators, they should just implement their own version of this method.
@@ -912,6 +1205,37 @@ macro_rules! generic_protocol_setup {
   };
 }
 
+/// Same as [generic_protocol_setup!], but hardware notifications are passed to the handler's
+/// [ProtocolHandler::handle_hardware_notification] method until the device disconnects.
+#[macro_export]
+macro_rules! generic_protocol_notification_setup {
+  ( $protocol_name:ident, $protocol_identifier:tt) => {
+    paste::paste! {
+      pub mod setup {
+        use std::sync::Arc;
+        use $crate::server::device::protocol::{
+          GenericProtocolIdentifier, ProtocolIdentifier, ProtocolIdentifierFactory,
+        };
+        #[derive(Default)]
+        pub struct [< $protocol_name IdentifierFactory >] {}
+
+        impl ProtocolIdentifierFactory for  [< $protocol_name IdentifierFactory >] {
+          fn identifier(&self) -> &str {
+            $protocol_identifier
+          }
+
+          fn create(&self) -> Box<dyn ProtocolIdentifier> {
+            Box::new(GenericProtocolIdentifier::new_with_notifications(
+              Arc::new(super::$protocol_name::default()),
+              self.identifier(),
+            ))
+          }
+        }
+      }
+    }
+  };
+}
+
 #[macro_export]
 macro_rules! generic_protocol_initializer_setup {
   ( $protocol_name:ident, $protocol_identifier:tt) => {
@@ -950,6 +1274,7 @@ macro_rules! generic_protocol_initializer_setup {
 
 use crate::server::device::configuration::ProtocolDeviceAttributes;
 pub use generic_protocol_initializer_setup;
+pub use generic_protocol_notification_setup;
 pub use generic_protocol_setup;
 
 use super::hardware::HardwareWriteCmd;
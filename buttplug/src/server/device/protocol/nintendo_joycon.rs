@@ -20,41 +20,59 @@ use std::{
 };
 use tokio::sync::Notify;
 
-/// Send command, sub-command, and data (sub-command's arguments) with u8 integers
-/// This returns ACK packet for the command or Error.
-async fn send_command_raw(
-  device: Arc<Hardware>,
+/// Build an output report. The command is picked by the endpoint, which the device config maps to
+/// the command's report ID: Tx for rumble and sub-command (0x01), TxVibrate for rumble only (0x10).
+fn command_packet(
+  endpoint: Endpoint,
   packet_number: u8,
-  command: u8,
   sub_command: u8,
   data: &[u8],
   rumble_r: Option<Rumble>,
   rumble_l: Option<Rumble>,
-) -> Result<(), ButtplugDeviceError> {
-  let mut buf = [0x0; 0x40];
-  // set command
-  buf[0] = command;
+) -> HardwareWriteCmd {
+  // Output reports are 0x40 bytes, including the report ID.
+  let mut buf = [0x0; 0x3F];
   // set packet number
-  buf[1] = packet_number;
+  buf[0] = packet_number;
 
   // rumble
   if let Some(rumble_l) = rumble_l {
     let rumble_left: [u8; 4] = rumble_l.into();
-    buf[2..6].copy_from_slice(&rumble_left);
+    buf[1..5].copy_from_slice(&rumble_left);
   }
   if let Some(rumble_r) = rumble_r {
     let rumble_right: [u8; 4] = rumble_r.into();
-    buf[6..10].copy_from_slice(&rumble_right);
+    buf[5..9].copy_from_slice(&rumble_right);
   }
 
   // set sub command
-  buf[10] = sub_command;
+  buf[9] = sub_command;
   // set data
-  buf[11..11 + data.len()].copy_from_slice(data);
+  buf[10..10 + data.len()].copy_from_slice(data);
+
+  HardwareWriteCmd::new(endpoint, buf.to_vec(), false)
+}
 
-  // send command
+/// Send command, sub-command, and data (sub-command's arguments) with u8 integers
+/// This returns ACK packet for the command or Error.
+async fn send_command_raw(
+  device: Arc<Hardware>,
+  packet_number: u8,
+  endpoint: Endpoint,
+  sub_command: u8,
+  data: &[u8],
+  rumble_r: Option<Rumble>,
+  rumble_l: Option<Rumble>,
+) -> Result<(), ButtplugDeviceError> {
   device
-    .write_value(&HardwareWriteCmd::new(Endpoint::Tx, buf.to_vec(), false))
+    .write_value(&command_packet(
+      endpoint,
+      packet_number,
+      sub_command,
+      data,
+      rumble_r,
+      rumble_l,
+    ))
     .await
 }
 
@@ -72,7 +90,16 @@ async fn send_sub_command_raw(
 ) -> Result<(), ButtplugDeviceError> {
   //use input_report_mode::sub_command_mode::AckByte;
 
-  send_command_raw(device, packet_number, 1, sub_command, data, None, None).await
+  send_command_raw(
+    device,
+    packet_number,
+    Endpoint::Tx,
+    sub_command,
+    data,
+    None,
+    None,
+  )
+  .await
   /*
   // check reply
   if self.valid_reply() {
@@ -264,8 +291,16 @@ impl NintendoJoycon {
           Rumble::stop()
         };
 
-        if let Err(_) =
-          send_command_raw(hardware.clone(), 1, 16, 0, &[], Some(rumble), Some(rumble)).await
+        if let Err(_) = send_command_raw(
+          hardware.clone(),
+          1,
+          Endpoint::TxVibrate,
+          0,
+          &[],
+          Some(rumble),
+          Some(rumble),
+        )
+        .await
         {
           error!("Joycon command failed, exiting update loop");
           break;
@@ -306,3 +341,69 @@ impl Drop for NintendoJoycon {
     self.notifier.notify_one();
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    server::device::configuration::ProtocolCommunicationSpecifier,
+    util::device_configuration::load_protocol_configs,
+  };
+  use std::collections::HashMap;
+
+  fn joycon_report_ids() -> HashMap<Endpoint, u8> {
+    load_protocol_configs(&None, &None, false)
+      .expect("Test, assuming infallible.")
+      .finish()
+      .expect("Test, assuming infallible.")
+      .protocol_device_configurations()
+      .get("nintendo-joycon")
+      .expect("Test, assuming infallible.")
+      .iter()
+      .find_map(|specifier| match specifier {
+        ProtocolCommunicationSpecifier::HID(hid) => Some(hid.report_ids().clone()),
+        _ => None,
+      })
+      .expect("Test, assuming infallible.")
+  }
+
+  #[test]
+  fn test_rumble_report() {
+    let report = command_packet(
+      Endpoint::TxVibrate,
+      1,
+      0,
+      &[],
+      Some(Rumble::stop()),
+      Some(Rumble::stop()),
+    )
+    .hid_output_report(&joycon_report_ids());
+    let mut expected = vec![0x10, 0x01, 0x00, 0x00, 0x41, 0x40, 0x00, 0x00, 0x41, 0x40];
+    expected.resize(0x40, 0);
+    assert_eq!(report, expected);
+  }
+
+  #[test]
+  fn test_sub_command_report() {
+    let report = command_packet(Endpoint::Tx, 0, 72, &[0x01], None, None)
+      .hid_output_report(&joycon_report_ids());
+    let mut expected = vec![0x01, 0x00];
+    expected.resize(10, 0);
+    expected.extend([72, 0x01]);
+    expected.resize(0x40, 0);
+    assert_eq!(report, expected);
+  }
+
+  #[test]
+  fn test_report_id_override() {
+    // A report ID on the command wins over the one configured for the endpoint.
+    let report = HardwareWriteCmd::new(Endpoint::Tx, vec![0xAA], false)
+      .with_report_id(0x30)
+      .hid_output_report(&joycon_report_ids());
+    assert_eq!(report, vec![0x30, 0xAA]);
+    // Endpoints without a report ID are written as is.
+    let report = HardwareWriteCmd::new(Endpoint::Rx, vec![0xAA], false)
+      .hid_output_report(&joycon_report_ids());
+    assert_eq!(report, vec![0xAA]);
+  }
+}
@@ -0,0 +1,158 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Run client style scalar commands through a protocol handler, without a server or hardware.
+//!
+//! Meant for protocol unit tests that only care about the bytes a handler produces for a series of
+//! ScalarCmds. The device attributes come from the bundled device config, and commands go through
+//! the same checks and step quantization the server would apply before calling
+//! [ProtocolHandler::handle_scalar_cmd_with_context]. Anything that relies on hardware (initialization,
+//! notifications, keepalives) or server timing (rate limits, ramps) is out of scope, and should be
+//! covered with device test cases instead.
+//!
+//! Available to in crate unit tests, and to other crates with the `test-utils` feature.
+//!
+//! ```ignore
+//! let sim = ScalarPipelineSim::new("galaku", Some("GX21"), Arc::new(Galaku::default()))?;
+//! let commands = sim.scalar(&[(0, ActuatorType::Vibrate, 1.0)])?;
+//! assert_eq!(commands, vec![HardwareWriteCmd::new(Endpoint::Tx, data, false).into()]);
+//! ```
+
+use super::{generic_command_manager::GenericCommandManager, ProtocolHandler};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    message::{ActuatorType, ScalarCmd, ScalarSubcommand},
+  },
+  server::device::{
+    configuration::{ProtocolDeviceAttributes, UserDeviceIdentifier},
+    hardware::HardwareCommand,
+  },
+  util::device_configuration::load_protocol_configs,
+};
+use std::sync::Arc;
+
+/// Address used to look up device definitions. Never matches a user config.
+static SIM_ADDRESS: &str = "ScalarPipelineSim";
+
+/// A protocol handler with the scalar command state of a single connected device.
+///
+/// Values are remembered between calls the way the server remembers them, so unchanged features
+/// aren't passed to the handler again (unless it needs the full command set).
+pub struct ScalarPipelineSim {
+  handler: Arc<dyn ProtocolHandler>,
+  attributes: ProtocolDeviceAttributes,
+  command_manager: GenericCommandManager,
+}
+
+impl ScalarPipelineSim {
+  /// Create a simulation for a device in the bundled device config. `identifier` is the
+  /// configuration identifier (usually the advertised name), or None for the protocol defaults.
+  pub fn new(
+    protocol: &str,
+    identifier: Option<&str>,
+    handler: Arc<dyn ProtocolHandler>,
+  ) -> Result<Self, ButtplugDeviceError> {
+    let dcm = load_protocol_configs(&None, &None, false)?.finish()?;
+    let definition = dcm
+      .device_definition(
+        &UserDeviceIdentifier::new(SIM_ADDRESS, protocol, &identifier.map(str::to_owned)),
+        &[],
+      )
+      .ok_or_else(|| {
+        ButtplugDeviceError::ProtocolAttributesNotFound(format!(
+          "No device config for protocol {} with identifier {:?}",
+          protocol, identifier
+        ))
+      })?;
+    Ok(Self::new_with_attributes(definition.into(), handler))
+  }
+
+  /// Create a simulation for a device with the given attributes, for testing configurations that
+  /// aren't in the bundled device config.
+  pub fn new_with_attributes(
+    attributes: ProtocolDeviceAttributes,
+    handler: Arc<dyn ProtocolHandler>,
+  ) -> Self {
+    Self {
+      command_manager: GenericCommandManager::new(&attributes),
+      handler,
+      attributes,
+    }
+  }
+
+  pub fn attributes(&self) -> &ProtocolDeviceAttributes {
+    &self.attributes
+  }
+
+  /// Send a ScalarCmd with (feature index, actuator type, scalar) subcommands, returning the
+  /// hardware commands the handler generates. Returns no commands if nothing changed, without
+  /// calling the handler, same as the server.
+  pub fn scalar(
+    &self,
+    scalars: &[(u32, ActuatorType, f64)],
+  ) -> Result<Vec<HardwareCommand>, ButtplugError> {
+    let msg = ScalarCmd::new(
+      0,
+      scalars
+        .iter()
+        .map(|(index, actuator, scalar)| ScalarSubcommand::new(*index, *scalar, *actuator))
+        .collect(),
+    );
+    self.check_scalar_cmd(&msg)?;
+    let commands = self
+      .command_manager
+      .update_scalar(&msg, self.handler.needs_full_command_set())?;
+    if commands.is_empty() {
+      return Ok(vec![]);
+    }
+    let context = self.command_manager.scalar_context(commands);
+    Ok(self.handler.handle_scalar_cmd_with_context(&context)?)
+  }
+
+  /// Set every scalar feature to 0, the way StopDeviceCmd does.
+  pub fn stop(&self) -> Result<Vec<HardwareCommand>, ButtplugError> {
+    let scalars: Vec<(u32, ActuatorType, f64)> = self
+      .attributes
+      .message_attributes()
+      .scalar_cmd()
+      .iter()
+      .flatten()
+      .enumerate()
+      .map(|(index, attr)| (index as u32, *attr.actuator_type(), 0.0))
+      .collect();
+    self.scalar(&scalars)
+  }
+
+  // Same checks as ServerDevice::check_scalar_cmd, minus user configured actuator aliases.
+  fn check_scalar_cmd(&self, msg: &ScalarCmd) -> Result<(), ButtplugError> {
+    let attributes = self.attributes.message_attributes();
+    let attrs = attributes.scalar_cmd().as_ref().ok_or_else(|| {
+      ButtplugDeviceError::ProtocolRequirementError("Device has no scalar features.".to_owned())
+    })?;
+    for command in msg.scalars() {
+      let attr =
+        attrs
+          .get(command.index() as usize)
+          .ok_or(ButtplugDeviceError::DeviceFeatureIndexError(
+            attrs.len() as u32,
+            command.index(),
+          ))?;
+      if *attr.actuator_type() != command.actuator_type() {
+        return Err(
+          ButtplugDeviceError::DeviceActuatorTypeMismatch(
+            self.attributes.name().to_owned(),
+            command.actuator_type(),
+            *attr.actuator_type(),
+          )
+          .into(),
+        );
+      }
+    }
+    Ok(())
+  }
+}
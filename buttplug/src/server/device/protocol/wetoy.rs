@@ -5,40 +5,17 @@
 // Licensed under the BSD 3-Clause license. See LICENSE file in the project root
 // for full license information.
 
+// The 0x80 0x03 handshake write is done by the init sequence in the device config.
+
 use crate::{
   core::{errors::ButtplugDeviceError, message::Endpoint},
   server::device::{
-    configuration::{ProtocolDeviceAttributes, UserDeviceIdentifier},
-    hardware::{Hardware, HardwareCommand, HardwareWriteCmd},
-    protocol::{
-      generic_protocol_initializer_setup,
-      ProtocolHandler,
-      ProtocolIdentifier,
-      ProtocolInitializer,
-    },
+    hardware::{HardwareCommand, HardwareWriteCmd},
+    protocol::{generic_protocol_setup, ProtocolHandler},
   },
 };
-use async_trait::async_trait;
-use std::sync::Arc;
-
-generic_protocol_initializer_setup!(WeToy, "wetoy");
-
-#[derive(Default)]
-pub struct WeToyInitializer {}
 
-#[async_trait]
-impl ProtocolInitializer for WeToyInitializer {
-  async fn initialize(
-    &mut self,
-    hardware: Arc<Hardware>,
-    _: &ProtocolDeviceAttributes,
-  ) -> Result<Arc<dyn ProtocolHandler>, ButtplugDeviceError> {
-    hardware
-      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![0x80, 0x03], true))
-      .await?;
-    Ok(Arc::new(WeToy::default()))
-  }
-}
+generic_protocol_setup!(WeToy, "wetoy");
 
 #[derive(Default)]
 pub struct WeToy {}
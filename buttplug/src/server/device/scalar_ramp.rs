@@ -0,0 +1,216 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Server side ramping of scalar increases, configured via the user device configuration.
+
+use crate::{
+  core::{errors::ButtplugError, message::ActuatorType},
+  util::{self, async_manager},
+};
+use futures::future::BoxFuture;
+use instant::Instant;
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+type ScalarCommands = Vec<Option<(ActuatorType, u32)>>;
+/// Future writing a single ramp step to the device.
+pub(super) type RampStepFuture = BoxFuture<'static, Result<(), ButtplugError>>;
+
+// Shortest time between two ramp steps. Slow ramps step by 1 at longer intervals instead.
+static MINIMUM_STEP_INTERVAL_MS: u64 = 50;
+
+#[derive(Default)]
+struct RampState {
+  /// Last value handed to the protocol for each feature.
+  values: ScalarCommands,
+  /// Value each ramping feature is heading towards.
+  targets: ScalarCommands,
+  /// When a ramped feature last moved up.
+  last_step: Option<Instant>,
+  /// True while the ramp task is running.
+  running: bool,
+}
+
+impl RampState {
+  fn resize(&mut self, len: usize) {
+    if self.values.len() < len {
+      self.values.resize(len, None);
+      self.targets.resize(len, None);
+    }
+  }
+
+  fn current(&self, index: usize) -> u32 {
+    self.values[index].map_or(0, |(_, value)| value)
+  }
+}
+
+/// Limits how fast scalar features of a single device can increase.
+///
+/// Ramped features move up by at most `step` every `interval`. Increases beyond that are split
+/// into steps, with the first one sent right away if the feature hasn't just moved, and the rest
+/// sent by a ramp task until the target is reached. Newer commands replace the target of any
+/// running ramp. Decreases always go out as they are.
+#[derive(Clone)]
+pub(super) struct ScalarRampLimiter {
+  features: Vec<u32>,
+  step: u32,
+  interval: Duration,
+  /// True if the protocol needs every feature in each command, rather than only changed ones.
+  match_all: bool,
+  state: Arc<Mutex<RampState>>,
+}
+
+impl ScalarRampLimiter {
+  /// Create a new limiter. Returns None if the rate is 0 or there are no features to ramp.
+  pub fn new(max_step_per_second: u32, features: &[u32], match_all: bool) -> Option<Self> {
+    if max_step_per_second == 0 || features.is_empty() {
+      return None;
+    }
+    let step = ((max_step_per_second as u64 * MINIMUM_STEP_INTERVAL_MS / 1000) as u32).max(1);
+    Some(Self {
+      features: features.to_vec(),
+      step,
+      interval: Duration::from_secs_f64(step as f64 / max_step_per_second as f64),
+      match_all,
+      state: Arc::new(Mutex::new(RampState::default())),
+    })
+  }
+
+  fn ramped(&self, index: usize) -> bool {
+    self.features.contains(&(index as u32))
+  }
+
+  /// Limit the increases in a batch of commands, returning the commands to send right away.
+  ///
+  /// Features that can't reach their target yet are replaced with their next step, or with their
+  /// current value (None if the protocol doesn't need every feature) if they just moved. If any
+  /// features still have a target to reach, the ramp task is started, and sends the remaining steps
+  /// through `send`.
+  pub fn limit<F>(&self, commands: &[Option<(ActuatorType, u32)>], send: F) -> ScalarCommands
+  where
+    F: Fn(ScalarCommands) -> RampStepFuture + Send + Sync + 'static,
+  {
+    let now = Instant::now();
+    let mut state = self.state.lock().expect("Lock poisoned");
+    state.resize(commands.len());
+    let can_step = state
+      .last_step
+      .is_none_or(|last| now.duration_since(last) >= self.interval);
+    let mut stepped = false;
+    let mut result = commands.to_vec();
+    for (index, command) in commands.iter().enumerate() {
+      if let Some((actuator, target)) = *command {
+        if self.ramped(index) {
+          let current = state.current(index);
+          if target <= current || (can_step && target - current <= self.step) {
+            stepped |= target > current;
+            state.targets[index] = None;
+          } else {
+            state.targets[index] = Some((actuator, target));
+            result[index] = if can_step {
+              stepped = true;
+              Some((actuator, current + self.step))
+            } else if self.match_all {
+              Some((actuator, current))
+            } else {
+              None
+            };
+          }
+        }
+      }
+      if result[index].is_some() {
+        state.values[index] = result[index];
+      }
+    }
+    if stepped {
+      state.last_step = Some(now);
+    }
+    if !state.running && state.targets.iter().any(Option::is_some) {
+      state.running = true;
+      self.spawn_ramp_task(send);
+    }
+    result
+  }
+
+  /// Record commands that bypass the limit (e.g. stops), cancelling any running ramp.
+  pub fn bypass(&self, commands: &[Option<(ActuatorType, u32)>]) {
+    let mut state = self.state.lock().expect("Lock poisoned");
+    state.resize(commands.len());
+    for (index, command) in commands.iter().enumerate() {
+      if command.is_some() {
+        state.values[index] = *command;
+      }
+    }
+    state.targets.iter_mut().for_each(|target| *target = None);
+  }
+
+  /// Cancel any running ramp and forget the feature values, for when the protocol state changes
+  /// outside of scalar commands (client disconnects, linear commands, etc).
+  pub fn reset(&self) {
+    let mut state = self.state.lock().expect("Lock poisoned");
+    let running = state.running;
+    *state = RampState {
+      running,
+      ..Default::default()
+    };
+  }
+
+  fn spawn_ramp_task<F>(&self, send: F)
+  where
+    F: Fn(ScalarCommands) -> RampStepFuture + Send + Sync + 'static,
+  {
+    let limiter = self.clone();
+    async_manager::spawn(async move {
+      loop {
+        util::sleep(limiter.interval).await;
+        // The protocol is handed the step while we still hold the lock, so a newer command can't
+        // get to it first and be overwritten by a stale step.
+        let fut = {
+          let now = Instant::now();
+          let mut state = limiter.state.lock().expect("Lock poisoned");
+          if state
+            .last_step
+            .is_some_and(|last| now.duration_since(last) < limiter.interval)
+          {
+            // A command moved the features since our last step, wait for the next interval.
+            continue;
+          }
+          let mut commands = vec![None; state.values.len()];
+          let mut stepped = false;
+          for (index, command) in commands.iter_mut().enumerate() {
+            if let Some((actuator, target)) = state.targets[index] {
+              let value = (state.current(index) + limiter.step).min(target);
+              if value == target {
+                state.targets[index] = None;
+              }
+              state.values[index] = Some((actuator, value));
+              *command = state.values[index];
+              stepped = true;
+            } else if limiter.match_all {
+              *command = state.values[index];
+            }
+          }
+          if !stepped {
+            state.running = false;
+            return;
+          }
+          state.last_step = Some(now);
+          send(commands)
+        };
+        if let Err(e) = fut.await {
+          warn!("Error writing scalar ramp step, cancelling ramp: {:?}", e);
+          let mut state = limiter.state.lock().expect("Lock poisoned");
+          state.targets.iter_mut().for_each(|target| *target = None);
+          state.running = false;
+          return;
+        }
+      }
+    });
+  }
+}
@@ -6,8 +6,14 @@
 // for full license information.
 
 use std::{
+  collections::{BTreeMap, BTreeSet, HashMap},
   fmt::{self, Debug},
-  sync::Arc,
+  ops::RangeInclusive,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+    Mutex,
+  },
   time::Duration,
 };
 
@@ -25,14 +31,19 @@ use crate::{
       ButtplugMessage,
       ButtplugServerDeviceMessage,
       ButtplugServerMessage,
+      ClientDeviceMessageAttributes,
+      DeviceRemovedReason,
       Endpoint,
+      LinearCmd,
       RSSILevelReading,
       RawReading,
       RawSubscribeCmd,
+      RotateCmd,
       ScalarCmd,
       ScalarSubcommand,
       SensorDeviceMessageAttributes,
       SensorReadCmd,
+      SensorReading,
       SensorType,
     },
     ButtplugResultFuture,
@@ -40,7 +51,16 @@ use crate::{
   server::{
     device::{
       configuration::DeviceConfigurationManager,
-      hardware::{Hardware, HardwareCommand, HardwareConnector, HardwareEvent},
+      hardware::{
+        DeviceIoStats,
+        Hardware,
+        HardwareCommand,
+        HardwareConnector,
+        HardwareErrorRecord,
+        HardwareEvent,
+        HardwarePacketDirection,
+        HardwarePacketTrace,
+      },
       protocol::ProtocolHandler,
     },
     ButtplugServerResultFuture,
@@ -49,31 +69,79 @@ use crate::{
 };
 use core::hash::{Hash, Hasher};
 use dashmap::DashSet;
-use futures::future::{self, FutureExt};
-use getset::Getters;
+use futures::future::{self, BoxFuture, FutureExt, Shared};
+use getset::{CopyGetters, Getters};
 use tokio::sync::RwLock;
 use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
 
 use super::{
+  auto_zero::AutoZeroTimer,
+  command_queue::{CommandQueue, QueueSlot, DEFAULT_MAX_QUEUED_COMMANDS},
+  command_rate_limiter::{CommandDispatch, CommandRateLimiter},
   configuration::{
+    expand_display_name_template,
     ProtocolDeviceAttributes,
+    SensorCalibration,
     ServerDeviceMessageAttributes,
+    ServerGenericDeviceMessageAttributes,
     UserDeviceDefinition,
     UserDeviceIdentifier,
   },
+  device_match_cache::DeviceMatch,
   hardware::HardwareWriteCmd,
   protocol::{
+    check_required_endpoints,
+    check_scalar_feature_layout,
     generic_command_manager::GenericCommandManager,
+    linear_ramp::{spawn_linear_ramp, LinearRamps, RampConfig},
+    write_init_sequence,
     ProtocolKeepaliveStrategy,
     ProtocolSpecializer,
   },
+  scalar_ramp::{RampStepFuture, ScalarRampLimiter},
+  server_device_manager::is_actuation_message,
 };
 
+/// How many times protocol initialization is attempted before giving up on a device, and how long
+/// to wait between attempts. BLE stacks will sometimes fail the first few writes after connecting,
+/// so a failed attempt doesn't always mean the device is unusable.
+#[derive(Debug, Clone, Copy, CopyGetters)]
+#[getset(get_copy = "pub")]
+pub struct InitializationRetryPolicy {
+  attempts: u32,
+  /// Wait before the next attempt, multiplied by the number of attempts made so far.
+  backoff: Duration,
+}
+
+impl InitializationRetryPolicy {
+  /// Create a new policy. `attempts` includes the first try, and is always at least 1.
+  pub fn new(attempts: u32, backoff: Duration) -> Self {
+    Self {
+      attempts: attempts.max(1),
+      backoff,
+    }
+  }
+}
+
+impl Default for InitializationRetryPolicy {
+  fn default() -> Self {
+    Self::new(3, Duration::from_millis(200))
+  }
+}
+
 #[derive(Debug)]
 pub enum ServerDeviceEvent {
   Connected(Arc<ServerDevice>),
   Notification(UserDeviceIdentifier, ButtplugServerDeviceMessage),
-  Disconnected(UserDeviceIdentifier),
+  /// A write that dry run mode kept from reaching the hardware.
+  DryRunWrite(UserDeviceIdentifier, HardwareWriteCmd),
+  /// A packet sent to or received from the hardware while packet tracing was on.
+  PacketTrace(UserDeviceIdentifier, HardwarePacketTrace),
+  /// The device went without actuation commands for its auto zero timeout, and needs to be
+  /// stopped, see [ServerDevice::handle_auto_zero_timeout].
+  AutoZeroTimeout(UserDeviceIdentifier),
+  Disconnected(UserDeviceIdentifier, DeviceRemovedReason),
 }
 
 #[derive(Getters)]
@@ -82,15 +150,47 @@ pub struct ServerDevice {
   handler: Arc<dyn ProtocolHandler>,
   #[getset(get = "pub")]
   definition: UserDeviceDefinition,
+  /// Name shown to clients, from the user config with any template placeholders expanded.
+  display_name: Mutex<Option<String>>,
   // Legacy, should be removed once we hit message spec v4, and message fallback to v3 handled
   // within specific messages.
   attributes: ProtocolDeviceAttributes,
-  generic_command_manager: GenericCommandManager,
+  /// Message attributes clients are given and allowed to use. Same as the protocol attributes,
+  /// unless the device is monitor only.
+  advertised_attributes: ServerDeviceMessageAttributes,
+  generic_command_manager: Arc<GenericCommandManager>,
   /// Unique identifier for the device
   #[getset(get = "pub")]
   identifier: UserDeviceIdentifier,
   raw_subscribed_endpoints: Arc<DashSet<Endpoint>>,
   keepalive_packet: Arc<RwLock<Option<HardwareWriteCmd>>>,
+  rate_limiter: Option<CommandRateLimiter>,
+  command_queue: CommandQueue,
+  scalar_ramp: Option<ScalarRampLimiter>,
+  /// Ramps started by LinearCmd, for protocols with a linear ramp config.
+  linear_ramps: LinearRamps,
+  /// Stops the device once it goes without actuation commands for the auto zero timeout of the
+  /// user config.
+  auto_zero: Arc<AutoZeroTimer>,
+  /// Second actuator type accepted by scalar features, from the user config treat-vibrate-as
+  /// mapping.
+  scalar_aliases: HashMap<u32, ActuatorType>,
+  /// Reason for a disconnect the server asked the hardware for, reported once the hardware
+  /// disconnects.
+  removal_reason: Arc<Mutex<Option<DeviceRemovedReason>>>,
+  /// True if the last write to the hardware failed, so a disconnect right after it can be put down
+  /// to the failure instead of the device.
+  last_write_failed: Arc<AtomicBool>,
+  sensor_calibrator: SensorCalibrator,
+  /// Client connection with an exclusive claim on the device, if any.
+  claimed_by: Mutex<Option<u32>>,
+  /// Client connections that sent the device actuation commands, so a disconnecting client only
+  /// stops the devices it was using.
+  actuated_by: Mutex<BTreeSet<u32>>,
+  /// The hardware is only subscribed to a sensor while at least one client is.
+  sensor_subscriptions: Arc<Mutex<SensorSubscriptions>>,
+  /// Cancelled on shutdown, to halt the keepalive task.
+  background_tasks: CancellationToken,
 }
 impl Debug for ServerDevice {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -116,12 +216,215 @@ impl PartialEq for ServerDevice {
   }
 }
 
+/// Name shown to clients for a device. A display name set for the device wins over the display name
+/// template of its protocol, and template placeholders are expanded.
+fn resolve_display_name(
+  device_config_manager: &DeviceConfigurationManager,
+  identifier: &UserDeviceIdentifier,
+  name: &str,
+  index: u32,
+  display_name: &Option<String>,
+) -> Option<String> {
+  display_name
+    .clone()
+    .or_else(|| {
+      device_config_manager
+        .user_protocol_display_names()
+        .get(identifier.protocol())
+        .map(|template| template.value().clone())
+    })
+    .map(|template| expand_display_name_template(&template, name, index, identifier.address()))
+}
+
+/// Switch to the user config stored against the stable id of a device, if its protocol reported
+/// one and there is such a config. Returns true if the definition was switched.
+fn use_stable_id_definition(
+  device_config_manager: &DeviceConfigurationManager,
+  serial: Option<String>,
+  identifier: &mut UserDeviceIdentifier,
+  definition: &mut UserDeviceDefinition,
+  endpoints: &[Endpoint],
+) -> bool {
+  if let Some(serial) = serial {
+    identifier.set_stable_id(Some(serial));
+    if let Some(stable_definition) =
+      device_config_manager.device_definition_by_stable_id(identifier, endpoints)
+    {
+      *definition = stable_definition;
+      return true;
+    }
+  }
+  false
+}
+
+/// Validate the treat-vibrate-as mapping in the user config against the device features. Mappings
+/// for features that don't exist, or that don't pair Vibrate with another type, are ignored.
+fn scalar_aliases(
+  definition: &UserDeviceDefinition,
+  attributes: &ServerDeviceMessageAttributes,
+) -> HashMap<u32, ActuatorType> {
+  let mut aliases = HashMap::new();
+  for (index, actuator) in definition.user_config().treat_vibrate_as() {
+    let feature_type = attributes
+      .scalar_cmd()
+      .as_ref()
+      .and_then(|attrs| attrs.get(*index as usize))
+      .map(|attr| *attr.actuator_type());
+    match feature_type {
+      Some(feature_type)
+        if feature_type != *actuator
+          && (feature_type == ActuatorType::Vibrate || *actuator == ActuatorType::Vibrate) =>
+      {
+        aliases.insert(*index, *actuator);
+      }
+      _ => warn!(
+        "Ignoring treat-vibrate-as mapping of scalar feature {} to {} for {}, feature type is {:?}.",
+        index,
+        actuator,
+        definition.name(),
+        feature_type
+      ),
+    }
+  }
+  aliases
+}
+
+type SensorRanges = Vec<RangeInclusive<i32>>;
+
+/// Subscription state of each sensor, by sensor index and type.
+type SensorSubscriptions = BTreeMap<(u32, SensorType), SensorSubscription>;
+
+/// Hardware subscribe to a sensor that is still running.
+type PendingSensorSubscribe = Shared<BoxFuture<'static, Result<(), ButtplugDeviceError>>>;
+
+enum SensorSubscription {
+  /// The hardware subscribe is still running. Clients subscribing meanwhile wait on it, and only
+  /// count as subscribed once it succeeds.
+  Pending(PendingSensorSubscribe, BTreeSet<u32>),
+  /// The hardware is subscribed, for these client connections.
+  Active(BTreeSet<u32>),
+}
+
+impl SensorSubscription {
+  fn clients(&self) -> &BTreeSet<u32> {
+    match self {
+      Self::Pending(_, clients) | Self::Active(clients) => clients,
+    }
+  }
+}
+
+/// Client id that messages parsed without a client connection, through
+/// [ServerDevice::parse_message], count as.
+pub(crate) const LOCAL_CLIENT_ID: u32 = u32::MAX;
+
+/// Applies the user config sensor calibration to readings on their way to clients, optionally
+/// checking them against the sensor ranges from the device config first.
+#[derive(Clone)]
+struct SensorCalibrator {
+  calibration: Arc<BTreeMap<SensorType, SensorCalibration>>,
+  /// True if readings should be passed on uncalibrated, for calibration UIs.
+  raw_values: Arc<AtomicBool>,
+  /// Uncalibrated sensor ranges, by sensor index and type.
+  ranges: Arc<BTreeMap<(u32, SensorType), SensorRanges>>,
+  /// True if readings outside of their sensor ranges should be logged.
+  check_ranges: Arc<AtomicBool>,
+}
+
+impl SensorCalibrator {
+  fn new(
+    calibration: &BTreeMap<SensorType, SensorCalibration>,
+    attributes: &ServerDeviceMessageAttributes,
+  ) -> Self {
+    let mut ranges = BTreeMap::new();
+    for sensors in [
+      attributes.sensor_read_cmd(),
+      attributes.sensor_subscribe_cmd(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+      for (index, sensor) in sensors.iter().enumerate() {
+        ranges
+          .entry((index as u32, *sensor.sensor_type()))
+          .or_insert_with(|| sensor.sensor_range().clone());
+      }
+    }
+    Self {
+      calibration: Arc::new(calibration.clone()),
+      raw_values: Arc::new(AtomicBool::new(false)),
+      ranges: Arc::new(ranges),
+      check_ranges: Arc::new(AtomicBool::new(false)),
+    }
+  }
+
+  fn check_range(&self, reading: &SensorReading) {
+    if !self.check_ranges.load(Ordering::Relaxed) {
+      return;
+    }
+    let ranges = match self
+      .ranges
+      .get(&(reading.sensor_index(), reading.sensor_type()))
+    {
+      Some(ranges) => ranges,
+      None => return,
+    };
+    if !sensor_values_in_range(reading.data(), ranges) {
+      warn!(
+        "{} sensor {} on device {} reported {:?}, outside of its range {:?}.",
+        reading.sensor_type(),
+        reading.sensor_index(),
+        reading.device_index(),
+        reading.data(),
+        ranges
+      );
+    }
+  }
+
+  fn calibrate(&self, reading: SensorReading) -> SensorReading {
+    self.check_range(&reading);
+    let calibration = match self.calibration.get(&reading.sensor_type()) {
+      Some(calibration) if !self.raw_values.load(Ordering::Relaxed) => calibration,
+      _ => return reading,
+    };
+    let data = reading
+      .data()
+      .iter()
+      .map(|value| calibration.apply(*value))
+      .collect();
+    let mut calibrated = SensorReading::new(
+      reading.device_index(),
+      reading.sensor_index(),
+      reading.sensor_type(),
+      data,
+    );
+    calibrated.set_id(reading.id());
+    calibrated
+  }
+}
+
+/// True if every value of a sensor reading is in the range for its position. Readings with more
+/// values than ranges check the extra values against the last range.
+fn sensor_values_in_range(data: &[i32], ranges: &[RangeInclusive<i32>]) -> bool {
+  data.iter().enumerate().all(|(index, value)| {
+    ranges
+      .get(index)
+      .or(ranges.last())
+      .is_none_or(|range| range.contains(value))
+  })
+}
+
 impl ServerDevice {
+  /// Connect to and set up a device. If the device was matched to a protocol before (see
+  /// [DeviceMatchCache](super::device_match_cache::DeviceMatchCache)), that protocol is tried
+  /// first, falling back to `protocol_specializers` (or matching from scratch if there are none)
+  /// if it no longer fits. Returns the device, and the match it was set up with.
   pub(super) async fn build(
     device_config_manager: Arc<DeviceConfigurationManager>,
     mut hardware_connector: Box<dyn HardwareConnector>,
-    protocol_specializers: Vec<ProtocolSpecializer>,
-  ) -> Result<Self, ButtplugDeviceError> {
+    mut protocol_specializers: Vec<ProtocolSpecializer>,
+    cached_match: Option<DeviceMatch>,
+    retry_policy: InitializationRetryPolicy,
+  ) -> Result<(Self, DeviceMatch), ButtplugDeviceError> {
     // We've already checked to make sure we have specializers in the server device manager event
     // loop. That check used to be here for sake of continuity in building devices in this method, but
     // having that done before we get here fixes issues with some device advertisement timing (See
@@ -130,32 +433,69 @@ impl ServerDevice {
     // At this point, we know we've got hardware that is waiting to connect, and enough protocol
     // info to actually do something after we connect. So go ahead and connect.
     trace!("Connecting to {:?}", hardware_connector);
+    let device_specifier = hardware_connector.specifier();
     let mut hardware_specializer = hardware_connector.connect().await?;
 
-    // We can't run these in parallel because we need to only accept one specializer.
-    let mut protocol_identifier = None;
-    let mut hardware_out = None;
-    for protocol_specializer in protocol_specializers {
-      if let Ok(specialized_hardware) = hardware_specializer
-        .specialize(protocol_specializer.specifiers())
-        .await
+    let mut matched = None;
+    if let Some(cached_match) = &cached_match {
+      if let Some(protocol_specializer) = device_config_manager
+        .protocol_specializer(cached_match.protocol(), cached_match.specifiers())
       {
-        protocol_identifier = Some(protocol_specializer.identify());
-        hardware_out = Some(specialized_hardware);
-        break;
+        match hardware_specializer
+          .specialize_with_endpoints(cached_match.specifiers(), cached_match.endpoints())
+          .await
+        {
+          Ok(specialized_hardware) => matched = Some((protocol_specializer, specialized_hardware)),
+          Err(e) => info!(
+            "Previous {} match no longer fits {:?}, matching again: {}",
+            cached_match.protocol(),
+            device_specifier,
+            e
+          ),
+        }
+      }
+      if matched.is_none() && protocol_specializers.is_empty() {
+        protocol_specializers = device_config_manager.protocol_specializers(&device_specifier);
+      }
+    }
+
+    // We can't run these in parallel because we need to only accept one specializer.
+    if matched.is_none() {
+      for protocol_specializer in protocol_specializers {
+        if let Ok(specialized_hardware) = hardware_specializer
+          .specialize(protocol_specializer.specifiers())
+          .await
+        {
+          matched = Some((protocol_specializer, specialized_hardware));
+          break;
+        }
       }
     }
 
-    if protocol_identifier.is_none() {
+    let (protocol_specializer, hardware_out) = if let Some(matched) = matched {
+      matched
+    } else {
       return Err(ButtplugDeviceError::DeviceConfigurationError(
         "No protocols with viable communication matches for hardware.".to_owned(),
       ));
-    }
-
-    let mut protocol_identifier_stage = protocol_identifier.unwrap();
-    let hardware = Arc::new(hardware_out.unwrap());
+    };
+    let device_match = DeviceMatch::new(
+      &device_specifier,
+      protocol_specializer.protocol(),
+      protocol_specializer.specifiers(),
+      &hardware_out.endpoints(),
+    );
+    // Use the init sequence of the config specifier this device matched, if it has one.
+    let init_sequence = protocol_specializer
+      .specifiers()
+      .iter()
+      .find(|specifier| **specifier == device_specifier && !specifier.init_sequence().is_empty())
+      .map(|specifier| specifier.init_sequence().to_vec())
+      .unwrap_or_default();
+    let mut protocol_identifier_stage = protocol_specializer.identify();
+    let hardware = Arc::new(hardware_out);
 
-    let (identifier, mut protocol_initializer) =
+    let (mut identifier, mut protocol_initializer) =
       protocol_identifier_stage.identify(hardware.clone()).await?;
 
     // Now we have an identifier. After this point, if anything fails, consider it a complete
@@ -163,30 +503,132 @@ impl ServerDevice {
     // put it in an unknown state if anything fails.
 
     // Check in the DeviceConfigurationManager to make sure we have attributes for this device.
-    let attrs = if let Some(attrs) =
+    let mut attrs = if let Some(attrs) =
       device_config_manager.device_definition(&identifier, &hardware.endpoints())
     {
       attrs
     } else {
       return Err(ButtplugDeviceError::DeviceConfigurationError(format!(
-        "No protocols with viable protocol attributes for hardware {:?}.",
+        "No protocols with viable protocol attributes for hardware {}.",
         identifier
       )));
     };
 
-    // If we have attributes, go ahead and initialize, handing us back our hardware instance that
-    // is now ready to use with the protocol handler.
+    // Firmware variants can lack endpoints the protocol writes to, which would otherwise only show
+    // up as write errors once the device is in use.
+    check_required_endpoints(&hardware, &protocol_initializer.required_endpoints())?;
+
+    // If the protocol can tell us a stable id for the device, prefer the user config stored against
+    // that over the one found by address. Init options come from the user config, so this has to
+    // happen before initialize.
+    let mut stable_id_known = protocol_initializer.device_serial().is_some();
+    use_stable_id_definition(
+      &device_config_manager,
+      protocol_initializer.device_serial(),
+      &mut identifier,
+      &mut attrs,
+      &hardware.endpoints(),
+    );
+
+    // Protocols that pick outputs by feature index would misroute commands for a config with a
+    // different feature layout.
+    let feature_layouts = protocol_initializer.scalar_feature_layouts(&identifier);
 
-    // Build the server device and return.
-    let handler = protocol_initializer
-      .initialize(hardware.clone(), &attrs.clone().into())
-      .await?;
+    let handler = loop {
+      let mut protocol_attributes: ProtocolDeviceAttributes = attrs.clone().into();
+      protocol_attributes
+        .set_identifier(device_config_manager.configuration_identifier(&identifier));
+      check_scalar_feature_layout(&identifier, &protocol_attributes, &feature_layouts)?;
 
+      // If we have attributes, go ahead and initialize, handing us back our hardware instance that
+      // is now ready to use with the protocol handler.
+
+      // Build the server device and return. The hardware stays connected between attempts.
+      let initialize = async {
+        // Init sequence writes aren't retried, as the device state is unknown once one has failed.
+        write_init_sequence(&hardware, &init_sequence).await?;
+        let mut attempt = 1;
+        loop {
+          match protocol_initializer
+            .initialize(hardware.clone(), &protocol_attributes)
+            .await
+          {
+            Ok(handler) => return Ok(handler),
+            Err(e) if attempt < retry_policy.attempts() => {
+              warn!(
+                "Protocol initialization attempt {} of {} failed for {}, retrying: {}",
+                attempt,
+                retry_policy.attempts(),
+                identifier,
+                e
+              );
+              util::sleep(retry_policy.backoff() * attempt).await;
+              attempt += 1;
+            }
+            Err(e) => return Err(e),
+          }
+        }
+      };
+      // A device that stops responding mid handshake would otherwise keep its address marked as
+      // connecting forever, so rescans would never pick it up again.
+      let timeout = device_config_manager.protocol_initialize_timeout(identifier.protocol());
+      let handler = match future::select(initialize.boxed(), util::sleep(timeout).boxed()).await {
+        future::Either::Left((result, _)) => result?,
+        future::Either::Right(_) => {
+          if let Err(e) = hardware.disconnect().await {
+            warn!(
+              "Error disconnecting {} after initialization timeout: {}",
+              identifier, e
+            );
+          }
+          return Err(ButtplugDeviceError::DeviceConnectionError(format!(
+            "Protocol initialization for {} did not finish within {}ms.",
+            identifier,
+            timeout.as_millis()
+          )));
+        }
+      };
+
+      // Protocols that only learn the stable id while initializing are initialized again, with the
+      // user config stored against it.
+      if !stable_id_known {
+        stable_id_known = protocol_initializer.device_serial().is_some();
+        if use_stable_id_definition(
+          &device_config_manager,
+          protocol_initializer.device_serial(),
+          &mut identifier,
+          &mut attrs,
+          &hardware.endpoints(),
+        ) {
+          // The handler from this attempt is dropped, so halt anything it started.
+          handler.handle_shutdown();
+          continue;
+        }
+      }
+      break handler;
+    };
+
+    let scalar_step_caps = protocol_initializer.scalar_step_caps();
     let requires_keepalive = hardware.requires_keepalive();
     let strategy = handler.keepalive_strategy();
 
+    let display_name = resolve_display_name(
+      &device_config_manager,
+      &identifier,
+      attrs.name(),
+      attrs.user_config().index(),
+      attrs.user_config().display_name(),
+    );
+
     // We now have fully initialized hardware, return a server device.
-    let device = Self::new(identifier, handler, hardware, &attrs);
+    let device = Self::new(
+      identifier,
+      handler,
+      hardware,
+      &attrs,
+      display_name,
+      &scalar_step_caps,
+    );
 
     // If we need a keepalive with a packet replay, set this up via stopping the device on connect.
     if requires_keepalive
@@ -203,7 +645,7 @@ impl ServerDevice {
       }
     }
 
-    Ok(device)
+    Ok((device, device_match))
   }
 
   /// Given a protocol and a device impl, create a new ButtplugDevice instance
@@ -212,9 +654,14 @@ impl ServerDevice {
     handler: Arc<dyn ProtocolHandler>,
     hardware: Arc<Hardware>,
     definition: &UserDeviceDefinition,
+    display_name: Option<String>,
+    scalar_step_caps: &HashMap<u32, u32>,
   ) -> Self {
     let keepalive_packet = Arc::new(RwLock::new(None));
-    let attributes = definition.clone().into();
+    let last_write_failed = Arc::new(AtomicBool::new(false));
+    let background_tasks = CancellationToken::new();
+    let mut attributes: ProtocolDeviceAttributes = definition.clone().into();
+    attributes.cap_scalar_step_limits(scalar_step_caps);
     let gcm = GenericCommandManager::new(&attributes);
     // If we've gotten here, we know our hardware is connected. This means we can start the keepalive if it's required.
     if hardware.requires_keepalive()
@@ -226,6 +673,8 @@ impl ServerDevice {
       let hardware = hardware.clone();
       let strategy = handler.keepalive_strategy();
       let keepalive_packet = keepalive_packet.clone();
+      let last_write_failed = last_write_failed.clone();
+      let background_tasks = background_tasks.clone();
       async_manager::spawn(async move {
         // Arbitrary wait time for now.
         let wait_duration = Duration::from_secs(5);
@@ -235,6 +684,7 @@ impl ServerDevice {
               ProtocolKeepaliveStrategy::RepeatPacketStrategy(packet) => {
                 if let Err(e) = hardware.write_value(&packet).await {
                   warn!("Error writing keepalive packet: {:?}", e);
+                  last_write_failed.store(true, Ordering::Relaxed);
                   break;
                 }
               }
@@ -242,6 +692,7 @@ impl ServerDevice {
                 if let Some(packet) = &*keepalive_packet.read().await {
                   if let Err(e) = hardware.write_value(&packet).await {
                     warn!("Error writing keepalive packet: {:?}", e);
+                    last_write_failed.store(true, Ordering::Relaxed);
                     break;
                   }
                 }
@@ -255,21 +706,82 @@ impl ServerDevice {
             }
           }
           // Arbitrary wait time for now.
-          util::sleep(wait_duration).await;
+          tokio::select! {
+            _ = util::sleep(wait_duration) => {}
+            _ = background_tasks.cancelled() => break,
+          }
         }
         info!("Leaving keepalive task for {}", hardware.name());
       });
     }
 
+    let rate_limiter = definition
+      .user_config()
+      .max_command_rate_hz()
+      .and_then(CommandRateLimiter::new);
+    let command_queue = CommandQueue::new(
+      definition
+        .user_config()
+        .max_queued_commands()
+        .unwrap_or(DEFAULT_MAX_QUEUED_COMMANDS),
+    );
+    let scalar_ramp = definition.user_config().ramp().as_ref().and_then(|ramp| {
+      ScalarRampLimiter::new(
+        ramp.max_step_per_second(),
+        ramp.features(),
+        handler.needs_full_command_set(),
+      )
+    });
+    let scalar_aliases = scalar_aliases(definition, attributes.message_attributes());
+    let auto_zero = AutoZeroTimer::new(
+      definition
+        .user_config()
+        .auto_zero_timeout_ms()
+        .map(|timeout| Duration::from_millis(timeout as u64)),
+    );
+    let mut advertised_attributes = if definition.user_config().monitor_only() {
+      info!(
+        "Device {} is monitor only, not accepting actuation messages.",
+        definition.name()
+      );
+      attributes.message_attributes().without_actuators()
+    } else {
+      attributes.message_attributes().clone()
+    };
+    advertised_attributes.calibrate_sensor_ranges(definition.user_config().sensor_calibration());
+    let sensor_calibrator = SensorCalibrator::new(
+      definition.user_config().sensor_calibration(),
+      attributes.message_attributes(),
+    );
+    // Only takes effect once the protocol is initialized, so handshakes still reach the hardware
+    // and the device can be identified.
+    hardware.set_dry_run(definition.user_config().dry_run());
+    hardware.set_force_write_with_response(definition.user_config().write_with_response());
+
     Self {
       identifier,
-      generic_command_manager: gcm,
+      generic_command_manager: Arc::new(gcm),
       handler,
       hardware,
       keepalive_packet,
       attributes,
+      advertised_attributes,
       definition: definition.clone(),
+      display_name: Mutex::new(display_name),
       raw_subscribed_endpoints: Arc::new(DashSet::new()),
+      rate_limiter,
+      command_queue,
+      scalar_ramp,
+      linear_ramps: LinearRamps::default(),
+      auto_zero: Arc::new(auto_zero),
+      scalar_aliases,
+      removal_reason: Arc::new(Mutex::new(None)),
+      last_write_failed,
+      sensor_calibrator,
+      claimed_by: Mutex::new(None),
+      actuated_by: Mutex::new(BTreeSet::new()),
+      sensor_subscriptions: Arc::new(Mutex::new(BTreeMap::new())),
+      background_tasks,
     }
   }
 
@@ -296,8 +808,226 @@ impl ServerDevice {
     }
   }
 
-  /// Disconnect from the device, if it's connected.
+  /// Maximum rate, in hz, that commands are written to the device, if one is set in the user
+  /// device configuration.
+  pub fn max_command_rate_hz(&self) -> Option<u32> {
+    self
+      .rate_limiter
+      .as_ref()
+      .map(|limiter| limiter.max_rate_hz())
+  }
+
+  /// Name shown to clients, from the user config with any template placeholders expanded.
+  pub fn display_name(&self) -> Option<String> {
+    self.display_name.lock().expect("Lock poisoned").clone()
+  }
+
+  /// Update the name shown to clients after the user config of the device changes.
+  /// `display_name` is the display name set for the device itself, if any. Connected clients see
+  /// the new name the next time they request the device list.
+  pub(crate) fn update_display_name(
+    &self,
+    device_config_manager: &DeviceConfigurationManager,
+    display_name: &Option<String>,
+  ) {
+    *self.display_name.lock().expect("Lock poisoned") = resolve_display_name(
+      device_config_manager,
+      &self.identifier,
+      self.definition.name(),
+      self.definition.user_config().index(),
+      display_name,
+    );
+  }
+
+  /// Client connection with an exclusive claim on the device, if any.
+  pub fn claimed_by(&self) -> Option<u32> {
+    *self.claimed_by.lock().expect("Lock poisoned")
+  }
+
+  /// Claim the device for a client connection. Until the claim is released, actuation commands
+  /// from other clients fail with [ButtplugDeviceError::DeviceBusy]. Stops and sensor commands
+  /// are still accepted from everyone.
+  ///
+  /// Claiming a device the client already holds does nothing.
+  pub fn claim(&self, client_id: u32) -> Result<(), ButtplugDeviceError> {
+    let mut claimed_by = self.claimed_by.lock().expect("Lock poisoned");
+    match *claimed_by {
+      Some(owner) if owner != client_id => Err(self.claimed_error(owner)),
+      _ => {
+        *claimed_by = Some(client_id);
+        Ok(())
+      }
+    }
+  }
+
+  /// Release a client connection's claim on the device. Returns false if the client didn't hold
+  /// the claim, in which case nothing changes.
+  pub fn release_claim(&self, client_id: u32) -> bool {
+    let mut claimed_by = self.claimed_by.lock().expect("Lock poisoned");
+    if *claimed_by == Some(client_id) {
+      *claimed_by = None;
+      true
+    } else {
+      false
+    }
+  }
+
+  /// Check a client connection may send the device actuation commands, and if so record it as
+  /// actuating the device. Exclusive devices are claimed by the first client to actuate them.
+  pub(crate) fn check_actuation_access(&self, client_id: u32) -> Result<(), ButtplugDeviceError> {
+    if self.definition.user_config().exclusive() {
+      self.claim(client_id)?;
+    } else {
+      self.check_unclaimed_by_others(client_id)?;
+    }
+    self
+      .actuated_by
+      .lock()
+      .expect("Lock poisoned")
+      .insert(client_id);
+    Ok(())
+  }
+
+  /// Forget a client connection actuated the device, e.g. once it disconnects. Returns true if it
+  /// had.
+  pub(crate) fn release_actuating_client(&self, client_id: u32) -> bool {
+    self
+      .actuated_by
+      .lock()
+      .expect("Lock poisoned")
+      .remove(&client_id)
+  }
+
+  /// Same as [Self::check_actuation_access], without claiming exclusive devices. Used to check a
+  /// whole device group before anything in it is claimed.
+  pub(crate) fn check_unclaimed_by_others(
+    &self,
+    client_id: u32,
+  ) -> Result<(), ButtplugDeviceError> {
+    match self.claimed_by() {
+      Some(owner) if owner != client_id => Err(self.claimed_error(owner)),
+      _ => Ok(()),
+    }
+  }
+
+  fn claimed_error(&self, owner: u32) -> ButtplugDeviceError {
+    ButtplugDeviceError::DeviceBusy(self.name(), format!("it is claimed by client {}", owner))
+  }
+
+  /// True if hardware writes are being logged instead of sent to the device.
+  pub fn dry_run(&self) -> bool {
+    self.hardware.dry_run()
+  }
+
+  /// Turn dry run mode on or off for the device. While on, writes the protocol produces are sent
+  /// out as [ServerDeviceEvent::DryRunWrite] events instead of to the hardware. Reads and
+  /// subscriptions still go to the hardware.
+  pub fn set_dry_run(&self, dry_run: bool) {
+    self.hardware.set_dry_run(dry_run);
+    self.clear_dry_run_state();
+  }
+
+  /// True if sensor readings are being sent to clients without the user config calibration.
+  pub fn raw_sensor_values(&self) -> bool {
+    self.sensor_calibrator.raw_values.load(Ordering::Relaxed)
+  }
+
+  /// Turn calibration of sensor readings off or on for the device, so calibration UIs can see the
+  /// raw values. Sensor ranges in the message attributes stay calibrated either way.
+  pub fn set_raw_sensor_values(&self, raw_sensor_values: bool) {
+    self
+      .sensor_calibrator
+      .raw_values
+      .store(raw_sensor_values, Ordering::Relaxed);
+  }
+
+  /// True if sensor readings outside of the sensor ranges in the device config are being logged.
+  pub fn sensor_range_checks(&self) -> bool {
+    self.sensor_calibrator.check_ranges.load(Ordering::Relaxed)
+  }
+
+  /// Turn logging of sensor readings outside of the sensor ranges in the device config on or off
+  /// for the device. Readings are checked as the device reports them, before calibration, and are
+  /// passed on either way.
+  pub fn set_sensor_range_checks(&self, sensor_range_checks: bool) {
+    self
+      .sensor_calibrator
+      .check_ranges
+      .store(sensor_range_checks, Ordering::Relaxed);
+  }
+
+  /// True if packets to and from the hardware are being traced.
+  pub fn packet_trace(&self) -> bool {
+    self.hardware.packet_trace()
+  }
+
+  /// Turn packet tracing on or off for the device. While on, every packet sent to or received from
+  /// the hardware is sent out as a [ServerDeviceEvent::PacketTrace] event.
+  pub fn set_packet_trace(&self, packet_trace: bool) {
+    self.hardware.set_packet_trace(packet_trace);
+  }
+
+  /// Forget anything we think the device is doing because of writes it may not have received, so
+  /// the next command gets sent in full.
+  fn clear_dry_run_state(&self) {
+    self.generic_command_manager.reset_scalar_state();
+    if let Ok(mut keepalive_packet) = self.keepalive_packet.try_write() {
+      *keepalive_packet = None;
+    }
+  }
+
+  /// The most recent failed hardware operations on the device, oldest first. See
+  /// [Hardware::error_history].
+  pub fn hardware_error_history(&self) -> Vec<HardwareErrorRecord> {
+    self.hardware.error_history()
+  }
+
+  /// How many hardware operations on the device have failed since it connected.
+  pub fn hardware_error_count(&self) -> u64 {
+    self.hardware.error_count()
+  }
+
+  /// Write, read and error counters for the hardware, including writes made by keepalives and by
+  /// protocol background tasks, along with the command queue counters. See [Hardware::io_stats].
+  pub fn stats(&self) -> DeviceIoStats {
+    self
+      .hardware
+      .io_stats()
+      .with_command_queue(self.command_queue.stats())
+  }
+
+  /// Current output state kept by the protocol handler, e.g. per channel power for DG-Lab devices.
+  /// None for protocols that don't keep any. See [ProtocolHandler::state_snapshot].
+  pub fn protocol_state(&self) -> Option<serde_json::Value> {
+    self.handler.state_snapshot()
+  }
+
+  /// Endpoints the hardware actually exposed when it connected. This can be fewer than the device
+  /// config maps, if the hardware is missing characteristics the config expects.
+  pub fn endpoints(&self) -> Vec<Endpoint> {
+    self.hardware.endpoints()
+  }
+
+  /// RotateCmd feature indexes whose direction the user config inverts, see
+  /// [UserDeviceCustomization::invert_rotation](crate::server::device::configuration::UserDeviceCustomization::invert_rotation).
+  pub fn inverted_rotations(&self) -> Vec<u32> {
+    self.generic_command_manager.inverted_rotations()
+  }
+
+  /// Disconnect from the device, if it's connected. The removal is reported as a
+  /// [DeviceRemovedReason::ClientRequest].
   pub fn disconnect(&self) -> ButtplugResultFuture {
+    self.disconnect_with_reason(DeviceRemovedReason::ClientRequest)
+  }
+
+  /// Disconnect from the device, reporting the removal with the given reason. If a disconnect is
+  /// already underway, the reason it was started with is kept.
+  pub fn disconnect_with_reason(&self, reason: DeviceRemovedReason) -> ButtplugResultFuture {
+    self
+      .removal_reason
+      .lock()
+      .expect("Lock poisoned")
+      .get_or_insert(reason);
     let fut = self.hardware.disconnect();
     async move { fut.await.map_err(|err| err.into()) }.boxed()
   }
@@ -307,6 +1037,42 @@ impl ServerDevice {
     self.attributes.message_attributes()
   }
 
+  /// Message attributes to send to clients, with features that take vibrate commands through the
+  /// user config marked so older specs list them as vibrators.
+  pub fn client_message_attributes(&self) -> ClientDeviceMessageAttributes {
+    let mut attributes: ClientDeviceMessageAttributes = self.advertised_attributes.clone().into();
+    attributes.set_scalar_vibrate_aliases(&self.vibrate_aliases());
+    attributes
+  }
+
+  /// Scalar features that aren't vibrators, but take vibrate commands through the user config.
+  fn vibrate_aliases(&self) -> Vec<u32> {
+    let mut indexes: Vec<u32> = self
+      .scalar_aliases
+      .iter()
+      .filter(|(_, actuator)| **actuator == ActuatorType::Vibrate)
+      .map(|(index, _)| *index)
+      .collect();
+    indexes.sort();
+    indexes
+  }
+
+  /// Scalar features that vibrate commands are sent to, with the actuator type to send them as.
+  fn vibrate_features(
+    &self,
+    attrs: &[ServerGenericDeviceMessageAttributes],
+  ) -> Vec<(u32, ActuatorType)> {
+    let aliases = self.vibrate_aliases();
+    attrs
+      .iter()
+      .enumerate()
+      .filter(|(index, attr)| {
+        *attr.actuator_type() == ActuatorType::Vibrate || aliases.contains(&(*index as u32))
+      })
+      .map(|(index, attr)| (index as u32, *attr.actuator_type()))
+      .collect()
+  }
+
   /// Retreive the event stream for the device.
   ///
   /// This will include connections, disconnections, and notification events from subscribed
@@ -314,12 +1080,33 @@ impl ServerDevice {
   pub fn event_stream(&self) -> impl futures::Stream<Item = ServerDeviceEvent> + Send {
     let identifier = self.identifier.clone();
     let raw_endpoints = self.raw_subscribed_endpoints.clone();
+    let removal_reason = self.removal_reason.clone();
+    let last_write_failed = self.last_write_failed.clone();
+    let auto_zero = self.auto_zero.clone();
+    // Only hold a weak reference, so the stream still ends once the hardware is dropped.
+    let hardware = Arc::downgrade(&self.hardware);
     let hardware_stream = convert_broadcast_receiver_to_stream(self.hardware.event_stream())
       .filter_map(move |hardware_event| {
         let id = identifier.clone();
         match hardware_event {
-          HardwareEvent::Disconnected(_) => Some(ServerDeviceEvent::Disconnected(id)),
+          HardwareEvent::Disconnected(_) => {
+            auto_zero.cancel();
+            let reason = removal_reason
+              .lock()
+              .expect("Lock poisoned")
+              .unwrap_or_else(|| {
+                if last_write_failed.load(Ordering::Relaxed) {
+                  DeviceRemovedReason::WriteFailure
+                } else {
+                  DeviceRemovedReason::DeviceInitiated
+                }
+              });
+            Some(ServerDeviceEvent::Disconnected(id, reason))
+          }
           HardwareEvent::Notification(_address, endpoint, data) => {
+            if let Some(hardware) = hardware.upgrade() {
+              hardware.trace_packet(HardwarePacketDirection::Notification, endpoint, &data);
+            }
             // TODO Figure out how we're going to parse raw data into something sendable to the client.
             if raw_endpoints.contains(&endpoint) {
               Some(ServerDeviceEvent::Notification(
@@ -334,11 +1121,34 @@ impl ServerDevice {
       });
 
     let identifier = self.identifier.clone();
+    let sensor_calibrator = self.sensor_calibrator.clone();
     let handler_mapped_stream = self.handler.event_stream().map(move |incoming_message| {
       let id = identifier.clone();
-      ServerDeviceEvent::Notification(id, incoming_message)
+      let message = match incoming_message {
+        ButtplugServerDeviceMessage::SensorReading(reading) => {
+          ButtplugServerDeviceMessage::SensorReading(sensor_calibrator.calibrate(reading))
+        }
+        message => message,
+      };
+      ServerDeviceEvent::Notification(id, message)
     });
-    hardware_stream.merge(handler_mapped_stream)
+
+    let identifier = self.identifier.clone();
+    let dry_run_stream = convert_broadcast_receiver_to_stream(self.hardware.dry_run_stream())
+      .map(move |write| ServerDeviceEvent::DryRunWrite(identifier.clone(), write));
+
+    let identifier = self.identifier.clone();
+    let packet_trace_stream =
+      convert_broadcast_receiver_to_stream(self.hardware.packet_trace_stream())
+        .map(move |packet| ServerDeviceEvent::PacketTrace(identifier.clone(), packet));
+    let identifier = self.identifier.clone();
+    let auto_zero_stream = convert_broadcast_receiver_to_stream(self.auto_zero.expiry_stream())
+      .map(move |_| ServerDeviceEvent::AutoZeroTimeout(identifier.clone()));
+    hardware_stream
+      .merge(handler_mapped_stream)
+      .merge(dry_run_stream)
+      .merge(packet_trace_stream)
+      .merge(auto_zero_stream)
   }
 
   pub fn supports_message(
@@ -348,8 +1158,8 @@ impl ServerDevice {
     // TODO This should be generated by a macro, as should the types enum.
     let check_msg = |msg_type| {
       self
-        .attributes
-        .allows_message(&msg_type)
+        .advertised_attributes
+        .message_allowed(&msg_type)
         .then_some(())
         .ok_or(ButtplugDeviceError::MessageNotSupported(msg_type))
     };
@@ -420,15 +1230,39 @@ impl ServerDevice {
   pub fn parse_message(
     &self,
     command_message: ButtplugDeviceCommandMessageUnion,
+  ) -> ButtplugServerResultFuture {
+    self.parse_client_message(LOCAL_CLIENT_ID, command_message)
+  }
+
+  /// Same as [Self::parse_message], for a message from a specific client connection. Sensor
+  /// subscriptions are counted per client: the hardware is subscribed for the first client, and
+  /// only unsubscribed once the last one unsubscribes or disconnects. Subscribing to a sensor the
+  /// client is already subscribed to (or unsubscribing from one it isn't) succeeds without doing
+  /// anything.
+  pub fn parse_client_message(
+    &self,
+    client_id: u32,
+    command_message: ButtplugDeviceCommandMessageUnion,
   ) -> ButtplugServerResultFuture {
     if let Err(err) = self.supports_message(&command_message) {
       return future::ready(Err(err)).boxed();
     }
+    if is_actuation_message(&command_message) {
+      self.auto_zero.reset();
+    } else if matches!(
+      command_message,
+      ButtplugDeviceCommandMessageUnion::StopDeviceCmd(_)
+    ) {
+      self.auto_zero.cancel();
+    }
 
     // If a handler implements handle message, bypass all of our parsing and let it do its own
     // thing. This should be a very rare thing.
     if self.handler.has_handle_message() {
-      let fut = self.handle_generic_command_result(self.handler.handle_message(&command_message));
+      let fut = self.handle_generic_command_result(
+        self.handler.handle_message(&command_message),
+        CommandDispatch::Queue,
+      );
       return async move { fut.await }.boxed();
     }
 
@@ -450,76 +1284,54 @@ impl ServerDevice {
       // here, in order to reduce boilerplate in the implementations. Generic messages that we can
       // use the generic command manager for, but still need protocol level translation.
       ButtplugDeviceCommandMessageUnion::ScalarCmd(msg) => {
-        // TODO Add ability to turn off actuator matching
-        let attributes = self.attributes.message_attributes();
-        let attrs = attributes
-          .scalar_cmd()
-          .as_ref()
-          .expect("Already checked existence");
-        for command in msg.scalars() {
-          if command.index() > attrs.len() as u32 {
-            return future::ready(Err(
-              ButtplugDeviceError::DeviceFeatureIndexError(attrs.len() as u32, command.index())
-                .into(),
-            ))
-            .boxed();
-          }
-          if *attrs[command.index() as usize].actuator_type() != command.actuator_type() {
-            return future::ready(Err(
-              ButtplugDeviceError::DeviceActuatorTypeMismatch(
-                self.name(),
-                command.actuator_type(),
-                *attrs[command.index() as usize].actuator_type(),
-              )
-              .into(),
-            ))
-            .boxed();
-          }
-        }
-
-        let commands = match self
-          .generic_command_manager
-          .update_scalar(&msg, self.handler.needs_full_command_set())
-        {
-          Ok(values) => values,
-          Err(err) => return future::ready(Err(err)).boxed(),
-        };
-
-        if commands.is_empty() {
-          trace!(
-            "No commands generated for incoming device packet, skipping and returning success."
-          );
-          return future::ready(Ok(message::Ok::default().into())).boxed();
-        }
-
-        self.handle_generic_command_result(self.handler.handle_scalar_cmd(&commands))
+        self.handle_scalar_cmd(msg, CommandDispatch::Coalesce)
       }
       ButtplugDeviceCommandMessageUnion::RotateCmd(msg) => {
-        let commands = match self
-          .generic_command_manager
-          .update_rotation(&msg, self.handler.needs_full_command_set())
-        {
-          Ok(values) => values,
-          Err(err) => return future::ready(Err(err)).boxed(),
-        };
-        self.handle_generic_command_result(self.handler.handle_rotate_cmd(&commands))
+        self.handle_rotate_cmd(msg, CommandDispatch::Coalesce)
       }
       ButtplugDeviceCommandMessageUnion::VibrateCmd(msg) => self.handle_vibrate_cmd(msg),
       ButtplugDeviceCommandMessageUnion::LinearCmd(msg) => {
-        self.handle_generic_command_result(self.handler.handle_linear_cmd(msg))
-      }
-      ButtplugDeviceCommandMessageUnion::FleshlightLaunchFW12Cmd(msg) => {
-        self.handle_generic_command_result(self.handler.handle_fleshlight_launch_fw12_cmd(msg))
+        // Some protocols drive scalar outputs from linear commands (e.g. power ramps), so the next
+        // scalar command needs to reach the protocol even if it matches what we last sent.
+        self.generic_command_manager.reset_scalar_state();
+        if let Some(ramp) = &self.scalar_ramp {
+          ramp.reset();
+        }
+        if let Some(config) = self.handler.linear_ramp_config() {
+          return self.handle_linear_ramp(msg, config);
+        }
+        self.handle_generic_command_result(
+          self.handler.handle_linear_cmd(msg),
+          CommandDispatch::Queue,
+        )
       }
+      ButtplugDeviceCommandMessageUnion::FleshlightLaunchFW12Cmd(msg) => self
+        .handle_generic_command_result(
+          self.handler.handle_fleshlight_launch_fw12_cmd(msg),
+          CommandDispatch::Queue,
+        ),
       ButtplugDeviceCommandMessageUnion::VorzeA10CycloneCmd(msg) => {
-        self.handle_generic_command_result(self.handler.handle_vorze_a10_cyclone_cmd(msg))
+        // Drives the first rotation feature, so it follows that feature's inversion.
+        let msg = if self
+          .generic_command_manager
+          .inverted_rotations()
+          .contains(&0)
+        {
+          message::VorzeA10CycloneCmd::new(msg.device_index(), msg.speed(), !msg.clockwise())
+        } else {
+          msg
+        };
+        self.handle_generic_command_result(
+          self.handler.handle_vorze_a10_cyclone_cmd(msg),
+          CommandDispatch::Queue,
+        )
       }
       ButtplugDeviceCommandMessageUnion::SensorReadCmd(msg) => self.handle_sensor_read_cmd(msg),
       ButtplugDeviceCommandMessageUnion::SensorSubscribeCmd(msg) => {
-        self.handle_sensor_subscribe_cmd(msg)
+        self.handle_sensor_subscribe_cmd(client_id, msg)
       }
       ButtplugDeviceCommandMessageUnion::SensorUnsubscribeCmd(msg) => {
-        self.handle_sensor_unsubscribe_cmd(msg)
+        self.handle_sensor_unsubscribe_cmd(client_id, msg)
       }
       // Everything else, which is mostly older messages, or special things that require reads.
       ButtplugDeviceCommandMessageUnion::KiirooCmd(_) => future::ready(Err(
@@ -529,30 +1341,383 @@ impl ServerDevice {
     }
   }
 
-  fn handle_hardware_commands(&self, commands: Vec<HardwareCommand>) -> ButtplugServerResultFuture {
+  /// Check that a scalar command matches the features of this device, without sending anything.
+  pub(super) fn check_scalar_cmd(&self, msg: &ScalarCmd) -> Result<(), ButtplugError> {
+    self.supports_message(&msg.clone().into())?;
+    // TODO Add ability to turn off actuator matching
+    let attributes = self.attributes.message_attributes();
+    let attrs = attributes
+      .scalar_cmd()
+      .as_ref()
+      .expect("Already checked existence");
+    for command in msg.scalars() {
+      if command.index() >= attrs.len() as u32 {
+        return Err(
+          ButtplugDeviceError::DeviceFeatureIndexError(attrs.len() as u32, command.index()).into(),
+        );
+      }
+      if *attrs[command.index() as usize].actuator_type() != command.actuator_type()
+        && self.scalar_aliases.get(&command.index()) != Some(&command.actuator_type())
+      {
+        return Err(
+          ButtplugDeviceError::DeviceActuatorTypeMismatch(
+            self.name(),
+            command.actuator_type(),
+            *attrs[command.index() as usize].actuator_type(),
+          )
+          .into(),
+        );
+      }
+    }
+    Ok(())
+  }
+
+  /// Replace actuator types accepted through the user config with the actual feature types, so the
+  /// protocol only sees the types it knows about.
+  fn resolve_scalar_aliases(&self, msg: ScalarCmd) -> ScalarCmd {
+    if self.scalar_aliases.is_empty() {
+      return msg;
+    }
+    let attributes = self.attributes.message_attributes();
+    let attrs = attributes
+      .scalar_cmd()
+      .as_ref()
+      .expect("Already checked existence");
+    let scalars = msg
+      .scalars()
+      .iter()
+      .map(|command| {
+        ScalarSubcommand::new(
+          command.index(),
+          command.scalar(),
+          *attrs[command.index() as usize].actuator_type(),
+        )
+      })
+      .collect();
+    let mut resolved = ScalarCmd::new(msg.device_index(), scalars);
+    resolved.set_id(msg.id());
+    resolved
+  }
+
+  fn handle_scalar_cmd(
+    &self,
+    msg: ScalarCmd,
+    dispatch: CommandDispatch,
+  ) -> ButtplugServerResultFuture {
+    if let Err(err) = self.check_scalar_cmd(&msg) {
+      return future::ready(Err(err)).boxed();
+    }
+    let msg = self.resolve_scalar_aliases(msg);
+    // Direct control takes over from any LinearCmd ramp (this also covers stops).
+    self.linear_ramps.cancel();
+
+    let commands = match self
+      .generic_command_manager
+      .update_scalar(&msg, self.handler.needs_full_command_set())
+    {
+      Ok(values) => values,
+      Err(err) => return future::ready(Err(err)).boxed(),
+    };
+    // Adjustments aren't levels, so the next identical one must be sent again.
+    self
+      .generic_command_manager
+      .reset_scalar_values(&self.handler.scalar_adjustment_features());
+
+    if commands.is_empty() {
+      trace!("No commands generated for incoming device packet, skipping and returning success.");
+      return future::ready(Ok(message::Ok::default().into())).boxed();
+    }
+
+    // Stops skip the ramp limit, same as they skip the rate limit.
+    let commands = match (&self.scalar_ramp, dispatch) {
+      (Some(ramp), CommandDispatch::Immediate) => {
+        ramp.bypass(&commands);
+        commands
+      }
+      (Some(ramp), _) => ramp.limit(&commands, self.ramp_step_sender()),
+      (None, _) => commands,
+    };
+    if commands.iter().all(Option::is_none) {
+      trace!("Scalar command only changes ramping features, leaving it to the ramp.");
+      return future::ready(Ok(message::Ok::default().into())).boxed();
+    }
+
+    if let (Some(limiter), CommandDispatch::Coalesce) = (&self.rate_limiter, dispatch) {
+      let handler = self.handler.clone();
+      let gcm = self.generic_command_manager.clone();
+      let write = self.hardware_command_writer();
+      let written = limiter.coalesce_scalar(&commands, move |commands| {
+        match handler.handle_scalar_cmd_with_context(&gcm.scalar_context(commands)) {
+          Ok(hardware_commands) => write(hardware_commands),
+          Err(err) => future::ready(Err(err.into())).boxed(),
+        }
+      });
+      return self.coalesced_reply(written);
+    }
+
+    let slot = if dispatch == CommandDispatch::Coalesce {
+      match self.command_queue.try_enqueue_scalar() {
+        Some(slot) => Some(slot),
+        None => {
+          let handler = self.handler.clone();
+          let gcm = self.generic_command_manager.clone();
+          let write = self.hardware_command_writer();
+          let written =
+            self
+              .command_queue
+              .coalesce_scalar(&commands, move |commands| {
+                match handler.handle_scalar_cmd_with_context(&gcm.scalar_context(commands)) {
+                  Ok(hardware_commands) => write(hardware_commands),
+                  Err(err) => future::ready(Err(err.into())).boxed(),
+                }
+              });
+          return self.coalesced_reply(written);
+        }
+      }
+    } else {
+      None
+    };
+    let context = self.generic_command_manager.scalar_context(commands);
+    self.handle_generic_command_result_in_slot(
+      self.handler.handle_scalar_cmd_with_context(&context),
+      dispatch,
+      slot,
+    )
+  }
+
+  /// LinearCmd for protocols that ramp scalar features instead (see
+  /// [ProtocolHandler::linear_ramp_config]). Replies as soon as the ramps are started.
+  fn handle_linear_ramp(&self, msg: LinearCmd, config: RampConfig) -> ButtplugServerResultFuture {
+    let mut ramps = vec![];
+    for vector in msg.vectors() {
+      let index = vector.index() as usize;
+      let gcm = &self.generic_command_manager;
+      let (current, target) = match (
+        gcm.scalar_value(index),
+        gcm.scalar_step(index, vector.position()),
+      ) {
+        (Some(current), Some(target)) => (current, target),
+        _ => {
+          let features = self
+            .attributes
+            .message_attributes()
+            .scalar_cmd()
+            .as_ref()
+            .map_or(0, |attrs| attrs.len() as u32);
+          return ButtplugDeviceError::DeviceFeatureIndexError(features, vector.index()).into();
+        }
+      };
+      if !(0.0..=1.0).contains(&vector.position()) {
+        return ButtplugDeviceError::ProtocolRequirementError(format!(
+          "LinearCmd position {} not in [0, 1]",
+          vector.position()
+        ))
+        .into();
+      }
+      ramps.push((
+        index,
+        current,
+        target,
+        Duration::from_millis(vector.duration() as u64),
+      ));
+    }
+    let cancel = self.linear_ramps.restart();
+    let match_all = self.handler.needs_full_command_set();
+    for (index, current, target, duration) in ramps {
+      let gcm = self.generic_command_manager.clone();
+      let send = self.ramp_step_sender();
+      spawn_linear_ramp(
+        cancel.clone(),
+        current,
+        target,
+        duration,
+        config.interval(),
+        move |value| send(gcm.set_scalar_step(index, value, match_all)),
+      );
+    }
+    future::ready(Ok(message::Ok::default().into())).boxed()
+  }
+
+  fn handle_rotate_cmd(
+    &self,
+    msg: RotateCmd,
+    dispatch: CommandDispatch,
+  ) -> ButtplugServerResultFuture {
+    let commands = match self
+      .generic_command_manager
+      .update_rotation(&msg, self.handler.needs_full_command_set())
+    {
+      Ok(values) => values,
+      Err(err) => return future::ready(Err(err)).boxed(),
+    };
+
+    if let (Some(limiter), CommandDispatch::Coalesce) = (&self.rate_limiter, dispatch) {
+      let handler = self.handler.clone();
+      let write = self.hardware_command_writer();
+      let written = limiter.coalesce_rotation(&commands, move |commands| {
+        match handler.handle_rotate_cmd(&commands) {
+          Ok(hardware_commands) => write(hardware_commands),
+          Err(err) => future::ready(Err(err.into())).boxed(),
+        }
+      });
+      return self.coalesced_reply(written);
+    }
+
+    let slot = if dispatch == CommandDispatch::Coalesce {
+      match self.command_queue.try_enqueue_rotation() {
+        Some(slot) => Some(slot),
+        None => {
+          let handler = self.handler.clone();
+          let write = self.hardware_command_writer();
+          let written = self
+            .command_queue
+            .coalesce_rotation(&commands, move |commands| {
+              match handler.handle_rotate_cmd(&commands) {
+                Ok(hardware_commands) => write(hardware_commands),
+                Err(err) => future::ready(Err(err.into())).boxed(),
+              }
+            });
+          return self.coalesced_reply(written);
+        }
+      }
+    } else {
+      None
+    };
+    self.handle_generic_command_result_in_slot(
+      self.handler.handle_rotate_cmd(&commands),
+      dispatch,
+      slot,
+    )
+  }
+
+  /// Returns a closure that writes a series of hardware commands to the device, updating the
+  /// keepalive packet as needed. Does not take the command rate limit into account.
+  fn hardware_command_writer(
+    &self,
+  ) -> impl Fn(Vec<HardwareCommand>) -> BoxFuture<'static, Result<(), ButtplugError>> + Send + 'static
+  {
     let hardware = self.hardware.clone();
-    let keepalive_type = self.handler.keepalive_strategy();
+    let store_keepalive_packet = hardware.requires_keepalive()
+      && matches!(
+        self.handler.keepalive_strategy(),
+        ProtocolKeepaliveStrategy::RepeatLastPacketStrategy
+      );
     let keepalive_packet = self.keepalive_packet.clone();
-    async move {
-      // Run commands in order, otherwise we may end up sending out of order. This may take a while,
-      // but it's what 99% of protocols expect. If they want something else, they can implement it
-      // themselves.
-      //
-      // If anything errors out, just bail on the command series. This most likely means the device
-      // disconnected.
-      for command in commands {
-        hardware.parse_message(&command).await?;
-        if hardware.requires_keepalive()
-          && matches!(
-            keepalive_type,
-            ProtocolKeepaliveStrategy::RepeatLastPacketStrategy
-          )
-        {
-          if let HardwareCommand::Write(command) = command {
-            *keepalive_packet.write().await = Some(command);
+    let last_write_failed = self.last_write_failed.clone();
+    move |commands| {
+      let hardware = hardware.clone();
+      let keepalive_packet = keepalive_packet.clone();
+      let last_write_failed = last_write_failed.clone();
+      async move {
+        // Run commands in order, otherwise we may end up sending out of order. This may take a
+        // while, but it's what 99% of protocols expect. If they want something else, they can
+        // implement it themselves.
+        //
+        // If anything errors out, just bail on the command series. This most likely means the
+        // device disconnected.
+        for command in commands {
+          let result = hardware.parse_message(&command).await;
+          last_write_failed.store(result.is_err(), Ordering::Relaxed);
+          result?;
+          // Don't let the keepalive repeat a packet the device never got.
+          if store_keepalive_packet && !hardware.dry_run() {
+            match command {
+              HardwareCommand::Write(command) => {
+                *keepalive_packet.write().await = Some(command);
+              }
+              HardwareCommand::Batch(mut commands) => {
+                if let Some(command) = commands.pop() {
+                  *keepalive_packet.write().await = Some(command);
+                }
+              }
+              _ => {}
+            }
           }
         }
+        Ok(())
+      }
+      .boxed()
+    }
+  }
+
+  /// Returns a closure that hands a ramp step to the protocol right away, and writes the resulting
+  /// hardware commands once the command rate limit allows.
+  fn ramp_step_sender(
+    &self,
+  ) -> impl Fn(Vec<Option<(ActuatorType, u32)>>) -> RampStepFuture + Send + Sync + 'static {
+    let handler = self.handler.clone();
+    let gcm = self.generic_command_manager.clone();
+    let write = self.hardware_command_writer();
+    let limiter = self.rate_limiter.clone();
+    move |commands| {
+      let write = match handler.handle_scalar_cmd_with_context(&gcm.scalar_context(commands)) {
+        Ok(hardware_commands) => write(hardware_commands),
+        Err(err) => return future::ready(Err(err.into())).boxed(),
+      };
+      let limiter = limiter.clone();
+      async move {
+        let _slot = match &limiter {
+          Some(limiter) => Some(limiter.acquire().await),
+          None => None,
+        };
+        write.await
+      }
+      .boxed()
+    }
+  }
+
+  /// Write commands to the device. Unless the commands are immediate, they need a place in the
+  /// command queue, and fail with [ButtplugDeviceError::DeviceBusy] if `slot` is None and the queue
+  /// is full.
+  fn handle_hardware_commands(
+    &self,
+    commands: Vec<HardwareCommand>,
+    dispatch: CommandDispatch,
+    slot: Option<QueueSlot>,
+  ) -> ButtplugServerResultFuture {
+    let slot = match (slot, dispatch) {
+      (_, CommandDispatch::Immediate) => {
+        self.command_queue.preempt();
+        None
       }
+      (Some(slot), _) => Some(slot),
+      (None, _) => match self.command_queue.enqueue(&self.name()) {
+        Ok(slot) => Some(slot),
+        Err(err) => return future::ready(Err(err.into())).boxed(),
+      },
+    };
+    let write = self.hardware_command_writer();
+    let limiter = self.rate_limiter.clone();
+    async move {
+      let _slot = slot;
+      match (limiter, dispatch) {
+        (Some(limiter), CommandDispatch::Immediate) => {
+          limiter.preempt();
+          let _in_flight = limiter.wait_for_in_flight().await;
+          write(commands).await?;
+        }
+        (Some(limiter), _) => {
+          let _slot = limiter.acquire().await;
+          write(commands).await?;
+        }
+        (None, _) => write(commands).await?,
+      }
+      Ok(message::Ok::default().into())
+    }
+    .boxed()
+  }
+
+  /// Reply to a command merged into a later write, by the command rate limit or a full command
+  /// queue. Replies right away, unless the user config asks for replies to wait on the write.
+  fn coalesced_reply(
+    &self,
+    written: BoxFuture<'static, Result<(), ButtplugError>>,
+  ) -> ButtplugServerResultFuture {
+    if !self.definition.user_config().ack_on_write() {
+      return future::ready(Ok(message::Ok::default().into())).boxed();
+    }
+    async move {
+      written.await?;
       Ok(message::Ok::default().into())
     }
     .boxed()
@@ -561,21 +1726,67 @@ impl ServerDevice {
   fn handle_generic_command_result(
     &self,
     command_result: Result<Vec<HardwareCommand>, ButtplugDeviceError>,
+    dispatch: CommandDispatch,
+  ) -> ButtplugServerResultFuture {
+    self.handle_generic_command_result_in_slot(command_result, dispatch, None)
+  }
+
+  /// Same as [Self::handle_generic_command_result], for commands that may already have a place in
+  /// the command queue.
+  fn handle_generic_command_result_in_slot(
+    &self,
+    command_result: Result<Vec<HardwareCommand>, ButtplugDeviceError>,
+    dispatch: CommandDispatch,
+    slot: Option<QueueSlot>,
   ) -> ButtplugServerResultFuture {
     let hardware_commands = match command_result {
       Ok(commands) => commands,
       Err(err) => return future::ready(Err(err.into())).boxed(),
     };
 
-    self.handle_hardware_commands(hardware_commands)
+    self.handle_hardware_commands(hardware_commands, dispatch, slot)
   }
 
   fn handle_stop_device_cmd(&self) -> ButtplugServerResultFuture {
+    if self.definition.user_config().monitor_only() {
+      // Nothing can have moved the device, so there's nothing to stop.
+      return future::ready(Ok(message::Ok::default().into())).boxed();
+    }
     let commands = self.generic_command_manager.stop_commands();
     let mut fut_vec = vec![];
-    commands
-      .iter()
-      .for_each(|msg| fut_vec.push(self.parse_message(msg.clone())));
+    // Stop commands bypass any command rate limit, so they always go out right away.
+    commands.iter().for_each(|msg| {
+      fut_vec.push(match msg.clone() {
+        ButtplugDeviceCommandMessageUnion::ScalarCmd(msg) if !self.handler.has_handle_message() => {
+          // A zero adjustment isn't a stop (e.g. it'd be a full decrease), so leave those out.
+          let adjustments = self.handler.scalar_adjustment_features();
+          let subcommands: Vec<ScalarSubcommand> = msg
+            .scalars()
+            .iter()
+            .filter(|cmd| {
+              !adjustments
+                .iter()
+                .any(|(index, _)| *index == cmd.index() as usize)
+            })
+            .cloned()
+            .collect();
+          if subcommands.is_empty() {
+            return;
+          }
+          self.handle_scalar_cmd(
+            message::ScalarCmd::new(msg.device_index(), subcommands),
+            CommandDispatch::Immediate,
+          )
+        }
+        ButtplugDeviceCommandMessageUnion::RotateCmd(msg) if !self.handler.has_handle_message() => {
+          self.handle_rotate_cmd(msg, CommandDispatch::Immediate)
+        }
+        msg => self.parse_message(msg),
+      })
+    });
+    if self.dry_run() {
+      self.clear_dry_run_state();
+    }
     async move {
       for fut in fut_vec {
         fut.await?;
@@ -585,6 +1796,81 @@ impl ServerDevice {
     .boxed()
   }
 
+  /// Run the protocol's client disconnect teardown, writing out any final commands right away.
+  pub(crate) fn handle_client_disconnect(&self) -> ButtplugServerResultFuture {
+    if self.definition.user_config().monitor_only() {
+      return future::ready(Ok(message::Ok::default().into())).boxed();
+    }
+    if let Some(ramp) = &self.scalar_ramp {
+      ramp.reset();
+    }
+    self.linear_ramps.cancel();
+    self.auto_zero.cancel();
+    self.handle_generic_command_result(
+      self.handler.handle_client_disconnect(),
+      CommandDispatch::Immediate,
+    )
+  }
+
+  /// Shut the device down for server shutdown. In order: stop the device and run the protocol's
+  /// client disconnect teardown, waiting up to `stop_timeout` for the resulting writes, disconnect
+  /// the hardware, then halt the protocol's and the device's background tasks. Failures are
+  /// logged rather than returned, so one device can't keep the others from shutting down.
+  pub(crate) async fn shutdown(&self, stop_timeout: Duration) {
+    let stop = async {
+      self.handle_stop_device_cmd().await?;
+      self.handle_client_disconnect().await
+    };
+    tokio::select! {
+      result = stop => {
+        if let Err(err) = result {
+          error!("Error stopping {} for shutdown: {:?}", self.name(), err);
+        }
+      }
+      _ = util::sleep(stop_timeout) => {
+        warn!(
+          "{} did not stop within {:?}, disconnecting anyway.",
+          self.name(),
+          stop_timeout
+        );
+      }
+    }
+    if let Err(err) = self
+      .disconnect_with_reason(DeviceRemovedReason::ServerShutdown)
+      .await
+    {
+      error!(
+        "Error disconnecting {} for shutdown: {:?}",
+        self.name(),
+        err
+      );
+    }
+    self.teardown();
+  }
+
+  /// Halt the protocol's and the device's background tasks, once the device is removed. Safe to
+  /// call more than once.
+  pub(crate) fn teardown(&self) {
+    self.handler.handle_shutdown();
+    self.linear_ramps.cancel();
+    self.auto_zero.cancel();
+    self.background_tasks.cancel();
+  }
+
+  /// Stop the device for an [auto zero timeout](ServerDeviceEvent::AutoZeroTimeout), the same
+  /// way a StopDeviceCmd would. Does nothing if an actuation command restarted the timer since it
+  /// ran out.
+  pub(crate) fn handle_auto_zero_timeout(&self) -> ButtplugServerResultFuture {
+    if !self.auto_zero.take_expired() {
+      return future::ready(Ok(message::Ok::default().into())).boxed();
+    }
+    info!(
+      "No actuation commands for {} within its auto zero timeout, stopping.",
+      self.name()
+    );
+    self.handle_stop_device_cmd()
+  }
+
   fn check_sensor_command(
     &self,
     attributes: &Vec<SensorDeviceMessageAttributes>,
@@ -621,21 +1907,25 @@ impl ServerDevice {
     );
     let device = self.hardware.clone();
     let handler = self.handler.clone();
+    let sensor_calibrator = self.sensor_calibrator.clone();
     async move {
       result?;
-      handler
-        .handle_sensor_read_cmd(device, message)
-        .await
-        .map_err(|e| e.into())
+      match handler.handle_sensor_read_cmd(device, message).await? {
+        ButtplugServerMessage::SensorReading(reading) => Ok(ButtplugServerMessage::SensorReading(
+          sensor_calibrator.calibrate(reading),
+        )),
+        message => Ok(message),
+      }
     }
     .boxed()
   }
 
   fn handle_sensor_subscribe_cmd(
     &self,
+    client_id: u32,
     message: message::SensorSubscribeCmd,
   ) -> ButtplugServerResultFuture {
-    let result = self.check_sensor_command(
+    if let Err(err) = self.check_sensor_command(
       self
         .message_attributes()
         .sensor_subscribe_cmd()
@@ -643,24 +1933,101 @@ impl ServerDevice {
         .expect("Already checked validity"),
       message.sensor_index(),
       message.sensor_type(),
-    );
+    ) {
+      return future::ready(Err(err.into())).boxed();
+    }
+    let key = (*message.sensor_index(), *message.sensor_type());
+    let id = message.id();
+    let (pending, started) = {
+      let mut subscriptions = self.sensor_subscriptions.lock().expect("Lock poisoned");
+      match subscriptions.get_mut(&key) {
+        Some(SensorSubscription::Active(clients)) => {
+          clients.insert(client_id);
+          return future::ready(Ok(message::Ok::new(id).into())).boxed();
+        }
+        Some(SensorSubscription::Pending(pending, clients)) => {
+          clients.insert(client_id);
+          (pending.clone(), false)
+        }
+        None => {
+          let pending = self.subscribe_hardware_sensor(key, message);
+          subscriptions.insert(
+            key,
+            SensorSubscription::Pending(pending.clone(), BTreeSet::from([client_id])),
+          );
+          (pending, true)
+        }
+      }
+    };
+    // Run the hardware subscribe even if the client that started it stops waiting.
+    if started {
+      async_manager::spawn(pending.clone().map(|_| ()));
+    }
+    async move {
+      pending
+        .await
+        .map(|_| message::Ok::new(id).into())
+        .map_err(|e| e.into())
+    }
+    .boxed()
+  }
+
+  /// Subscribe the hardware to a sensor. Once done, the clients that subscribed meanwhile count as
+  /// subscribed, or none of them do if it failed.
+  fn subscribe_hardware_sensor(
+    &self,
+    key: (u32, SensorType),
+    message: message::SensorSubscribeCmd,
+  ) -> PendingSensorSubscribe {
     let device = self.hardware.clone();
     let handler = self.handler.clone();
+    let subscriptions = self.sensor_subscriptions.clone();
+    let device_index = message.device_index();
     async move {
-      result?;
-      handler
-        .handle_sensor_subscribe_cmd(device, message)
+      let result = handler
+        .handle_sensor_subscribe_cmd(device.clone(), message)
         .await
-        .map_err(|e| e.into())
+        .map(|_| ());
+      let unsubscribe = {
+        let mut subscriptions = subscriptions.lock().expect("Lock poisoned");
+        let clients = subscriptions
+          .remove(&key)
+          .map(|subscription| subscription.clients().clone())
+          .unwrap_or_default();
+        if result.is_ok() && !clients.is_empty() {
+          subscriptions.insert(key, SensorSubscription::Active(clients));
+          false
+        } else {
+          // Every client unsubscribed while the subscribe was running.
+          result.is_ok()
+        }
+      };
+      if unsubscribe {
+        if let Err(e) = handler
+          .handle_sensor_unsubscribe_cmd(
+            device,
+            message::SensorUnsubscribeCmd::new(device_index, key.0, key.1),
+          )
+          .await
+        {
+          warn!(
+            "Error unsubscribing sensor {:?} nobody is subscribed to: {:?}",
+            key, e
+          );
+        }
+      }
+      result
     }
     .boxed()
+    .shared()
   }
 
   fn handle_sensor_unsubscribe_cmd(
     &self,
+    client_id: u32,
     message: message::SensorUnsubscribeCmd,
   ) -> ButtplugServerResultFuture {
-    let result = self.check_sensor_command(
+    if let Err(err) = self.check_sensor_command(
       self
         .message_attributes()
         .sensor_subscribe_cmd()
@@ -668,11 +2035,17 @@ impl ServerDevice {
         .expect("Already checked validity"),
       message.sensor_index(),
       message.sensor_type(),
-    );
+    ) {
+      return future::ready(Err(err.into())).boxed();
+    }
+    if !self
+      .release_sensor_subscription(client_id, (*message.sensor_index(), *message.sensor_type()))
+    {
+      return future::ready(Ok(message::Ok::new(message.id()).into())).boxed();
+    }
     let device = self.hardware.clone();
     let handler = self.handler.clone();
     async move {
-      result?;
       handler
         .handle_sensor_unsubscribe_cmd(device, message)
         .await
@@ -681,14 +2054,85 @@ impl ServerDevice {
     .boxed()
   }
 
-  fn handle_vibrate_cmd(&self, message: VibrateCmd) -> ButtplugServerResultFuture {
-    if let Some(attr) = self.attributes.message_attributes().scalar_cmd() {
-      let mut indexes = Vec::new();
-      for (i, attr) in attr.iter().enumerate() {
-        if attr.actuator_type() == &ActuatorType::Vibrate {
-          indexes.push(i as u32);
+  /// Drop a client's subscription to a sensor. Returns true if it was the last subscribed client,
+  /// so the hardware needs unsubscribing.
+  fn release_sensor_subscription(&self, client_id: u32, key: (u32, SensorType)) -> bool {
+    let mut subscriptions = self.sensor_subscriptions.lock().expect("Lock poisoned");
+    match subscriptions.get_mut(&key) {
+      Some(SensorSubscription::Active(clients)) => {
+        if !clients.remove(&client_id) || !clients.is_empty() {
+          return false;
+        }
+        subscriptions.remove(&key);
+        true
+      }
+      // A pending subscribe unsubscribes the hardware itself if no clients are left once it's done.
+      Some(SensorSubscription::Pending(_, clients)) => {
+        clients.remove(&client_id);
+        false
+      }
+      None => false,
+    }
+  }
+
+  /// Client connections subscribed to a sensor.
+  pub fn sensor_subscribers(&self, sensor_index: u32, sensor_type: SensorType) -> Vec<u32> {
+    match self
+      .sensor_subscriptions
+      .lock()
+      .expect("Lock poisoned")
+      .get(&(sensor_index, sensor_type))
+    {
+      Some(SensorSubscription::Active(clients)) => clients.iter().copied().collect(),
+      _ => vec![],
+    }
+  }
+
+  /// Drop every sensor subscription a client connection holds, e.g. once it disconnects,
+  /// unsubscribing the hardware from sensors no other client is subscribed to. `device_index` is
+  /// the index of the device, for the unsubscribe messages handed to the protocol. Unsubscribe
+  /// errors are logged, and don't keep the other sensors from being unsubscribed.
+  pub(crate) fn release_client_sensor_subscriptions(
+    &self,
+    device_index: u32,
+    client_id: u32,
+  ) -> BoxFuture<'static, ()> {
+    let held: Vec<(u32, SensorType)> = self
+      .sensor_subscriptions
+      .lock()
+      .expect("Lock poisoned")
+      .iter()
+      .filter(|(_, subscription)| subscription.clients().contains(&client_id))
+      .map(|(key, _)| *key)
+      .collect();
+    let released: Vec<(u32, SensorType)> = held
+      .into_iter()
+      .filter(|key| self.release_sensor_subscription(client_id, *key))
+      .collect();
+    let device = self.hardware.clone();
+    let handler = self.handler.clone();
+    async move {
+      for (sensor_index, sensor_type) in released {
+        if let Err(e) = handler
+          .handle_sensor_unsubscribe_cmd(
+            device.clone(),
+            message::SensorUnsubscribeCmd::new(device_index, sensor_index, sensor_type),
+          )
+          .await
+        {
+          error!(
+            "Error unsubscribing sensor {} ({:?}) of disconnected client: {:?}",
+            sensor_index, sensor_type, e
+          );
         }
       }
+    }
+    .boxed()
+  }
+
+  fn handle_vibrate_cmd(&self, message: VibrateCmd) -> ButtplugServerResultFuture {
+    if let Some(attr) = self.attributes.message_attributes().scalar_cmd() {
+      let indexes = self.vibrate_features(attr);
 
       let mut cmds: Vec<ScalarSubcommand> = Vec::new();
       for s in message.speeds().iter() {
@@ -701,11 +2145,8 @@ impl ServerDevice {
           ))
           .into();
         }
-        cmds.push(ScalarSubcommand::new(
-          indexes[s.index() as usize],
-          s.speed(),
-          ActuatorType::Vibrate,
-        ));
+        let (index, actuator) = indexes[s.index() as usize];
+        cmds.push(ScalarSubcommand::new(index, s.speed(), actuator));
       }
 
       if cmds.is_empty() {
@@ -734,11 +2175,10 @@ impl ServerDevice {
   ) -> ButtplugServerResultFuture {
     if let Some(attr) = self.attributes.message_attributes().scalar_cmd() {
       let speed = message.speed();
-      let cmds: Vec<ScalarSubcommand> = attr
-        .iter()
-        .enumerate()
-        .filter(|(_, x)| *x.actuator_type() == ActuatorType::Vibrate)
-        .map(|(index, _)| ScalarSubcommand::new(index as u32, speed, ActuatorType::Vibrate))
+      let cmds: Vec<ScalarSubcommand> = self
+        .vibrate_features(attr)
+        .into_iter()
+        .map(|(index, actuator)| ScalarSubcommand::new(index, speed, actuator))
         .collect();
       if cmds.is_empty() {
         ButtplugDeviceError::ProtocolRequirementError(format!(
@@ -762,9 +2202,22 @@ impl ServerDevice {
 
   fn handle_raw_write_cmd(&self, message: message::RawWriteCmd) -> ButtplugServerResultFuture {
     let id = message.id();
-    let fut = self.hardware.write_value(&message.into());
+    let hardware = self.hardware.clone();
+    let limiter = self.rate_limiter.clone();
+    // Raw writes can't be merged, so they're refused once the command queue is full.
+    let queue_slot = match self.command_queue.enqueue(&self.name()) {
+      Ok(slot) => slot,
+      Err(err) => return future::ready(Err(err.into())).boxed(),
+    };
     async move {
-      fut
+      let _queue_slot = queue_slot;
+      // Raw writes are never dropped, they just wait their turn.
+      let _slot = match &limiter {
+        Some(limiter) => Some(limiter.acquire().await),
+        None => None,
+      };
+      hardware
+        .write_value(&message.into())
         .await
         .map(|_| message::Ok::new(id).into())
         .map_err(|err| err.into())
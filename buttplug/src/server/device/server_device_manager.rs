@@ -21,24 +21,49 @@ use crate::{
       ButtplugServerMessage,
       DeviceList,
       DeviceMessageInfo,
+      DeviceRemovedReason,
+      Endpoint,
+      ScalarCmd,
+      ScalarSubcommand,
+      SensorType,
+      StopDeviceCmd,
     },
+    ButtplugResultFuture,
   },
   server::{
     device::{
-      configuration::{DeviceConfigurationManager, UserDeviceIdentifier},
-      hardware::communication::{
-        HardwareCommunicationManager,
-        HardwareCommunicationManagerBuilder,
+      configuration::{
+        BaseDeviceIdentifier,
+        DeviceAddressFilter,
+        DeviceConfigurationManager,
+        DeviceConfigurationManagerBuilder,
+        DeviceMirror,
+        UserDeviceDefinition,
+        UserDeviceIdentifier,
       },
+      hardware::{
+        communication::{HardwareCommunicationManager, HardwareCommunicationManagerBuilder},
+        DeviceIoStats,
+        HardwareErrorRecord,
+        HardwarePacketTrace,
+      },
+      protocol::ProtocolIdentifierFactory,
+      server_device::LOCAL_CLIENT_ID,
       server_device_manager_event_loop::ServerDeviceManagerEventLoop,
+      unmatched_advertisements::{UnmatchedAdvertisement, UnmatchedAdvertisementLog},
+      InitializationRetryPolicy,
       ServerDevice,
     },
     ButtplugServerError,
     ButtplugServerResultFuture,
   },
-  util::{async_manager, stream::convert_broadcast_receiver_to_stream},
+  util::{
+    async_manager,
+    device_configuration::user_device_config_fragment,
+    stream::convert_broadcast_receiver_to_stream,
+  },
 };
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use futures::{
   future::{self, FutureExt},
   Stream,
@@ -47,13 +72,22 @@ use getset::Getters;
 use std::{
   convert::TryFrom,
   sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicU32, Ordering},
     Arc,
+    Mutex,
   },
+  time::Duration,
 };
 use tokio::sync::{broadcast, mpsc};
 use tokio_util::sync::CancellationToken;
 
+/// How long each device gets to write out its stop commands on shutdown, before it's disconnected
+/// anyway.
+static SHUTDOWN_STOP_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long the startup scan for auto-connect devices runs, unless changed with
+/// [ServerDeviceManagerBuilder::auto_connect_timeout].
+pub const DEFAULT_AUTO_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Debug)]
 pub(super) enum DeviceManagerCommand {
   StartScanning,
@@ -65,28 +99,151 @@ pub(super) enum DeviceManagerCommand {
 pub struct ServerDeviceInfo {
   identifier: UserDeviceIdentifier,
   display_name: Option<String>,
+  max_command_rate_hz: Option<u32>,
+  /// Endpoints discovered on the hardware at connect time.
+  endpoints: Vec<Endpoint>,
+  /// True if hardware writes are being logged instead of sent to the device.
+  dry_run: bool,
+  /// How many hardware operations on the device have failed since it connected.
+  hardware_error_count: u64,
+  /// Client connection with an exclusive claim on the device, if any.
+  claimed_by: Option<u32>,
+  /// RotateCmd feature indexes whose direction is inverted by the user config.
+  inverted_rotations: Vec<u32>,
+}
+
+/// Reasons a device found by a hardware communication manager was not connected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceIgnoredReason {
+  /// The device address is on the user configuration deny list.
+  DenyListed,
+  /// The user configuration has an allow list, and the device address isn't on it.
+  NotAllowListed,
+  /// No protocol communication specifiers match the device.
+  NoMatchingProtocol,
+  /// Protocols matched the device when it was found, but none of them could be used once the
+  /// device was connected. Contains the error message from the connection attempt.
+  NoViableProtocolAfterConnect(String),
+}
+
+/// Server side events from the device manager. These aren't part of the Buttplug protocol and are
+/// never sent to clients, but can be used by embedding applications for logging or UI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerDeviceManagerEvent {
+  /// A device was found but will not be connected.
+  DeviceIgnored {
+    name: String,
+    address: String,
+    reason: DeviceIgnoredReason,
+  },
+  /// A device connected, but identified as a model the device config doesn't have a configuration
+  /// for, so it is using its protocol defaults. Useful for collecting reports of new hardware
+  /// revisions.
+  UnknownDeviceIdentifier {
+    name: String,
+    address: String,
+    identifier: BaseDeviceIdentifier,
+  },
+  /// A device in dry run mode produced a write, which was not sent to the hardware.
+  DeviceDryRunWrite {
+    index: u32,
+    endpoint: Endpoint,
+    data: Vec<u8>,
+  },
+  /// A device with packet tracing on sent or received a packet.
+  DevicePacketTrace {
+    index: u32,
+    identifier: UserDeviceIdentifier,
+    packet: HardwarePacketTrace,
+  },
+  /// A connected device was removed. Sent alongside the DeviceRemoved message clients get.
+  DeviceRemoved {
+    index: u32,
+    identifier: UserDeviceIdentifier,
+    reason: DeviceRemovedReason,
+  },
+}
+
+type DeviceConfigurationHook = Box<dyn FnMut(&mut DeviceConfigurationManagerBuilder) + Send>;
+
+enum DeviceConfigurationSource {
+  Manager(Arc<DeviceConfigurationManager>),
+  Builder(Box<DeviceConfigurationManagerBuilder>),
 }
 
 pub struct ServerDeviceManagerBuilder {
-  device_configuration_manager: Arc<DeviceConfigurationManager>,
+  device_configuration: DeviceConfigurationSource,
+  configure_devices: Vec<DeviceConfigurationHook>,
+  protocol_factories: Vec<(String, Arc<dyn ProtocolIdentifierFactory>)>,
   comm_managers: Vec<Box<dyn HardwareCommunicationManagerBuilder>>,
+  initialization_retry_policy: InitializationRetryPolicy,
+  auto_connect_timeout: Duration,
 }
 
 impl ServerDeviceManagerBuilder {
-  pub fn new(device_configuration_manager: DeviceConfigurationManager) -> Self {
+  fn new_with_source(device_configuration: DeviceConfigurationSource) -> Self {
     Self {
-      device_configuration_manager: Arc::new(device_configuration_manager),
+      device_configuration,
+      configure_devices: vec![],
+      protocol_factories: vec![],
       comm_managers: vec![],
+      initialization_retry_policy: InitializationRetryPolicy::default(),
+      auto_connect_timeout: DEFAULT_AUTO_CONNECT_TIMEOUT,
     }
   }
 
+  pub fn new(device_configuration_manager: DeviceConfigurationManager) -> Self {
+    Self::new_with_source(DeviceConfigurationSource::Manager(Arc::new(
+      device_configuration_manager,
+    )))
+  }
+
   /// Use a prebuilt device configuration manager that needs to be shared with the outside world
   /// (usually for serialization of user configurations to file)
   pub fn new_with_arc(device_configuration_manager: Arc<DeviceConfigurationManager>) -> Self {
-    Self {
+    Self::new_with_source(DeviceConfigurationSource::Manager(
       device_configuration_manager,
-      comm_managers: vec![],
-    }
+    ))
+  }
+
+  /// Use a loaded device configuration (as returned by
+  /// [load_protocol_configs](crate::util::device_configuration::load_protocol_configs)), which is
+  /// built into a device configuration manager when the device manager is finished. This allows
+  /// changing the configuration with [Self::configure_devices] first.
+  pub fn new_from_config(config: DeviceConfigurationManagerBuilder) -> Self {
+    Self::new_with_source(DeviceConfigurationSource::Builder(Box::new(config)))
+  }
+
+  /// Add a hook that can change the loaded device configuration (remove protocols, add specifiers,
+  /// deny addresses, etc.) before the device configuration manager is built. Hooks run in the order
+  /// they were added. Only usable with [Self::new_from_config], as other constructors take an
+  /// already built manager.
+  pub fn configure_devices<F>(&mut self, hook: F) -> &mut Self
+  where
+    F: FnMut(&mut DeviceConfigurationManagerBuilder) + Send + 'static,
+  {
+    self.configure_devices.push(Box::new(hook));
+    self
+  }
+
+  /// Register a protocol implementation from outside the library, so third party protocols can be
+  /// used without changing the library. Configs refer to the protocol as `name`, which has to be
+  /// what [ProtocolIdentifierFactory::identifier] returns. The protocol still needs communication
+  /// specifiers and device features, from the base config, a user config, or a
+  /// [Self::configure_devices] hook (e.g. with
+  /// [add_protocol_definition_from_json](crate::util::device_configuration::add_protocol_definition_from_json)).
+  ///
+  /// [Self::finish] fails if a built in protocol, or another registered protocol, already uses the
+  /// name. Only usable with [Self::new_from_config], as other constructors take an already built
+  /// device configuration manager. See the [protocol module](crate::server::device::protocol) for
+  /// the traits the factory has to implement.
+  pub fn add_protocol_factory(
+    &mut self,
+    name: &str,
+    factory: Arc<dyn ProtocolIdentifierFactory>,
+  ) -> &mut Self {
+    self.protocol_factories.push((name.to_owned(), factory));
+    self
   }
 
   pub fn comm_manager<T>(&mut self, builder: T) -> &mut Self
@@ -97,11 +254,67 @@ impl ServerDeviceManagerBuilder {
     self
   }
 
+  /// Set how many times protocol initialization is tried for newly connected devices, and how long
+  /// to wait between tries. Defaults to 3 attempts, with a 200ms backoff.
+  pub fn initialization_retry_policy(&mut self, policy: InitializationRetryPolicy) -> &mut Self {
+    self.initialization_retry_policy = policy;
+    self
+  }
+
+  /// Set how long the device manager scans for auto-connect devices from the user config when it
+  /// starts, if they aren't all found before then. Defaults to [DEFAULT_AUTO_CONNECT_TIMEOUT].
+  pub fn auto_connect_timeout(&mut self, timeout: Duration) -> &mut Self {
+    self.auto_connect_timeout = timeout;
+    self
+  }
+
+  fn device_configuration_manager(
+    &mut self,
+  ) -> Result<Arc<DeviceConfigurationManager>, ButtplugServerError> {
+    match &self.device_configuration {
+      DeviceConfigurationSource::Manager(dcm) => {
+        if !self.configure_devices.is_empty() {
+          return Err(ButtplugServerError::DeviceConfigurationManagerError(
+            ButtplugDeviceError::DeviceConfigurationError(
+              "Device configuration hooks can't change a device configuration manager that is already built."
+                .to_owned(),
+            ),
+          ));
+        }
+        if !self.protocol_factories.is_empty() {
+          return Err(ButtplugServerError::DeviceConfigurationManagerError(
+            ButtplugDeviceError::DeviceConfigurationError(
+              "Protocols can't be added to a device configuration manager that is already built."
+                .to_owned(),
+            ),
+          ));
+        }
+        Ok(dcm.clone())
+      }
+      DeviceConfigurationSource::Builder(config) => {
+        let mut config = config.clone();
+        for (name, factory) in &self.protocol_factories {
+          config.add_protocol_factory(name, factory.clone());
+        }
+        for hook in &mut self.configure_devices {
+          hook(&mut config);
+        }
+        Ok(Arc::new(config.finish().map_err(
+          ButtplugServerError::DeviceConfigurationManagerError,
+        )?))
+      }
+    }
+  }
+
   pub fn finish(&mut self) -> Result<ServerDeviceManager, ButtplugServerError> {
+    let device_configuration_manager = self.device_configuration_manager()?;
     let (device_command_sender, device_command_receiver) = mpsc::channel(256);
     let (device_event_sender, device_event_receiver) = mpsc::channel(256);
     let mut comm_managers: Vec<Box<dyn HardwareCommunicationManager>> = Vec::new();
     for builder in &mut self.comm_managers {
+      builder.address_filter(DeviceAddressFilter::new(
+        device_configuration_manager.clone(),
+      ));
       let comm_mgr = builder.finish(device_event_sender.clone());
 
       if comm_managers
@@ -147,27 +360,52 @@ impl ServerDeviceManagerBuilder {
 
     let mut event_loop = ServerDeviceManagerEventLoop::new(
       comm_managers,
-      self.device_configuration_manager.clone(),
+      device_configuration_manager.clone(),
+      self.initialization_retry_policy,
+      self.auto_connect_timeout,
       devices.clone(),
       loop_cancellation_token.child_token(),
       output_sender.clone(),
       device_event_receiver,
       device_command_receiver,
     );
+    let manager_event_sender = event_loop.manager_event_sender();
+    let unmatched_advertisements = event_loop.unmatched_advertisements();
     async_manager::spawn(async move {
       event_loop.run().await;
     });
     Ok(ServerDeviceManager {
-      device_configuration_manager: self.device_configuration_manager.clone(),
+      device_configuration_manager,
       devices,
       device_command_sender,
       loop_cancellation_token,
       running: Arc::new(AtomicBool::new(true)),
       output_sender,
+      manager_event_sender,
+      unmatched_advertisements,
+      next_client_id: AtomicU32::new(0),
+      connected_clients: DashSet::new(),
     })
   }
 }
 
+/// Commands that move a device, as opposed to stops, sensor commands and raw reads, which any
+/// client can send to a claimed device.
+pub(super) fn is_actuation_message(msg: &ButtplugDeviceCommandMessageUnion) -> bool {
+  matches!(
+    msg,
+    ButtplugDeviceCommandMessageUnion::FleshlightLaunchFW12Cmd(_)
+      | ButtplugDeviceCommandMessageUnion::SingleMotorVibrateCmd(_)
+      | ButtplugDeviceCommandMessageUnion::VorzeA10CycloneCmd(_)
+      | ButtplugDeviceCommandMessageUnion::KiirooCmd(_)
+      | ButtplugDeviceCommandMessageUnion::VibrateCmd(_)
+      | ButtplugDeviceCommandMessageUnion::LinearCmd(_)
+      | ButtplugDeviceCommandMessageUnion::RotateCmd(_)
+      | ButtplugDeviceCommandMessageUnion::RawWriteCmd(_)
+      | ButtplugDeviceCommandMessageUnion::ScalarCmd(_)
+  )
+}
+
 #[derive(Getters)]
 pub struct ServerDeviceManager {
   #[getset(get = "pub")]
@@ -177,6 +415,11 @@ pub struct ServerDeviceManager {
   loop_cancellation_token: CancellationToken,
   running: Arc<AtomicBool>,
   output_sender: broadcast::Sender<ButtplugServerMessage>,
+  manager_event_sender: broadcast::Sender<ServerDeviceManagerEvent>,
+  unmatched_advertisements: Arc<Mutex<UnmatchedAdvertisementLog>>,
+  next_client_id: AtomicU32,
+  /// Client connections past the handshake.
+  connected_clients: DashSet<u32>,
 }
 
 impl ServerDeviceManager {
@@ -186,6 +429,12 @@ impl ServerDeviceManager {
     convert_broadcast_receiver_to_stream(self.output_sender.subscribe())
   }
 
+  /// Retreive an async stream of [ServerDeviceManagerEvent]s, for events that aren't relayed to
+  /// clients (for instance, devices that were found but ignored).
+  pub fn manager_event_stream(&self) -> impl Stream<Item = ServerDeviceManagerEvent> {
+    convert_broadcast_receiver_to_stream(self.manager_event_sender.subscribe())
+  }
+
   fn start_scanning(&self) -> ButtplugServerResultFuture {
     let command_sender = self.device_command_sender.clone();
     async move {
@@ -233,18 +482,175 @@ impl ServerDeviceManager {
     .boxed()
   }
 
+  /// Record a client connection as connected, once its handshake is done.
+  pub(crate) fn handle_client_connect(&self, client_id: u32) {
+    self.connected_clients.insert(client_id);
+  }
+
+  /// Stops the devices a disconnecting client was using, then gives their protocols a chance to
+  /// clear any state they hold in the background, so nothing keeps running once the client is gone.
+  /// Those are the devices the client claimed or actuated, or every device if no other client is
+  /// connected. The client's claims and sensor subscriptions are released.
+  pub(crate) fn handle_client_disconnect(&self, client_id: u32) -> ButtplugServerResultFuture {
+    self.connected_clients.remove(&client_id);
+    let scoped = !self.connected_clients.is_empty();
+    let stopped: Vec<Arc<ServerDevice>> = self
+      .devices
+      .iter()
+      .filter(|dev| {
+        let device = dev.value();
+        let actuated = device.release_actuating_client(client_id);
+        !scoped || actuated || device.claimed_by() == Some(client_id)
+      })
+      .map(|dev| dev.value().clone())
+      .collect();
+    self.release_client_claims(client_id);
+    let device_map = self.devices.clone();
+    async move {
+      let stop_vec: Vec<_> = stopped
+        .iter()
+        .map(|device| device.parse_message(message::StopDeviceCmd::new(1).into()))
+        .collect();
+      future::join_all(stop_vec).await;
+      let unsubscribe_vec: Vec<_> = device_map
+        .iter()
+        .map(|dev| {
+          dev
+            .value()
+            .release_client_sensor_subscriptions(*dev.key(), client_id)
+        })
+        .collect();
+      future::join_all(unsubscribe_vec).await;
+      let fut_vec: Vec<_> = stopped
+        .iter()
+        .map(|device| device.handle_client_disconnect())
+        .collect();
+      for result in future::join_all(fut_vec).await {
+        if let Err(e) = result {
+          error!("Error running device client disconnect handler: {:?}", e);
+        }
+      }
+      Ok(message::Ok::default().into())
+    }
+    .boxed()
+  }
+
   fn parse_device_message(
     &self,
+    client_id: u32,
     device_msg: ButtplugDeviceCommandMessageUnion,
   ) -> ButtplugServerResultFuture {
-    match self.devices.get(&device_msg.device_index()) {
-      Some(device) => {
-        let fut = device.parse_message(device_msg);
-        // Create a future to run the message through the device, then handle adding the id to the result.
-        async move { fut.await }.boxed()
+    let device = match self.devices.get(&device_msg.device_index()) {
+      Some(device) => device.value().clone(),
+      None => return ButtplugDeviceError::DeviceNotAvailable(device_msg.device_index()).into(),
+    };
+    if !device.definition().user_config().mirror().is_empty() {
+      match device_msg {
+        ButtplugDeviceCommandMessageUnion::ScalarCmd(msg) => {
+          return self.parse_mirrored_scalar_cmd(client_id, device, msg)
+        }
+        ButtplugDeviceCommandMessageUnion::StopDeviceCmd(msg) => {
+          return self.parse_mirrored_stop_device_cmd(client_id, device, msg)
+        }
+        _ => {}
+      }
+    }
+    device.parse_client_message(client_id, device_msg)
+  }
+
+  /// Returns the index, device and mirror configuration of every connected device the given
+  /// device mirrors commands to. Mirror targets that aren't connected are skipped.
+  fn mirror_targets(&self, device: &ServerDevice) -> Vec<(u32, Arc<ServerDevice>, DeviceMirror)> {
+    device
+      .definition()
+      .user_config()
+      .mirror()
+      .iter()
+      .filter_map(|mirror| {
+        self
+          .devices
+          .iter()
+          .find(|entry| entry.value().identifier() == mirror.identifier())
+          .map(|entry| (*entry.key(), entry.value().clone(), mirror.clone()))
+      })
+      .collect()
+  }
+
+  /// Send a scalar command to a device and everything it mirrors to. The whole group is checked
+  /// before anything is sent, so invalid commands never reach part of the group, and devices
+  /// claimed by another client block the command for the whole group. Exclusive devices in the
+  /// group are only claimed once every check has passed.
+  fn parse_mirrored_scalar_cmd(
+    &self,
+    client_id: u32,
+    device: Arc<ServerDevice>,
+    msg: ScalarCmd,
+  ) -> ButtplugServerResultFuture {
+    let mut group = vec![];
+    for (index, target, mirror) in self.mirror_targets(&device) {
+      let scalars = msg
+        .scalars()
+        .iter()
+        .map(|command| {
+          ScalarSubcommand::new(
+            command.index(),
+            mirror.scale(command.index(), command.scalar()),
+            command.actuator_type(),
+          )
+        })
+        .collect();
+      group.push((target, ScalarCmd::new(index, scalars)));
+    }
+    group.insert(0, (device, msg));
+    for (device, msg) in &group {
+      if let Err(err) = device.check_unclaimed_by_others(client_id) {
+        return future::ready(Err(err.into())).boxed();
+      }
+      if let Err(err) = device.check_scalar_cmd(msg) {
+        return future::ready(Err(err)).boxed();
+      }
+    }
+    for (device, _) in &group {
+      if let Err(err) = device.check_actuation_access(client_id) {
+        return future::ready(Err(err.into())).boxed();
+      }
+    }
+    Self::dispatch_to_group(
+      group
+        .into_iter()
+        .map(|(device, msg)| device.parse_client_message(client_id, msg.into()))
+        .collect(),
+    )
+  }
+
+  /// Stops are allowed for claimed devices, so unlike scalar commands they're never blocked.
+  fn parse_mirrored_stop_device_cmd(
+    &self,
+    client_id: u32,
+    device: Arc<ServerDevice>,
+    msg: StopDeviceCmd,
+  ) -> ButtplugServerResultFuture {
+    let mut futs = vec![device.parse_client_message(client_id, msg.into())];
+    for (index, target, _) in self.mirror_targets(&device) {
+      futs.push(target.parse_client_message(client_id, StopDeviceCmd::new(index).into()));
+    }
+    Self::dispatch_to_group(futs)
+  }
+
+  /// Runs the commands for a device group together. If any device fails, the error is reported
+  /// for the whole group.
+  fn dispatch_to_group(futs: Vec<ButtplugServerResultFuture>) -> ButtplugServerResultFuture {
+    async move {
+      let mut results = future::join_all(futs).await.into_iter();
+      let first = results
+        .next()
+        .expect("Groups always contain the source device");
+      for result in results {
+        result?;
       }
-      None => ButtplugDeviceError::DeviceNotAvailable(device_msg.device_index()).into(),
+      first
     }
+    .boxed()
   }
 
   fn parse_device_manager_message(
@@ -261,9 +667,9 @@ impl ServerDeviceManager {
             DeviceMessageInfo::new(
               *device.key(),
               &dev.name(),
-              &dev.definition().user_config().display_name(),
+              &dev.display_name(),
               &None,
-              dev.message_attributes().clone().into(),
+              dev.client_message_attributes(),
             )
           })
           .collect();
@@ -278,13 +684,21 @@ impl ServerDeviceManager {
   }
 
   pub fn parse_message(&self, msg: ButtplugClientMessage) -> ButtplugServerResultFuture {
+    self.route_message(LOCAL_CLIENT_ID, msg)
+  }
+
+  fn route_message(
+    &self,
+    client_id: u32,
+    msg: ButtplugClientMessage,
+  ) -> ButtplugServerResultFuture {
     if !self.running.load(Ordering::SeqCst) {
       return future::ready(Err(ButtplugUnknownError::DeviceManagerNotRunning.into())).boxed();
     }
     // If this is a device command message, just route it directly to the
     // device.
     match ButtplugDeviceCommandMessageUnion::try_from(msg.clone()) {
-      Ok(device_msg) => self.parse_device_message(device_msg),
+      Ok(device_msg) => self.parse_device_message(client_id, device_msg),
       Err(_) => match ButtplugDeviceManagerMessageUnion::try_from(msg.clone()) {
         Ok(manager_msg) => self.parse_device_manager_message(manager_msg),
         Err(_) => ButtplugMessageError::UnexpectedMessageType(format!("{:?}", msg)).into(),
@@ -292,18 +706,286 @@ impl ServerDeviceManager {
     }
   }
 
+  /// Same as [Self::parse_message], for a message from a specific client connection. Actuation
+  /// commands for devices another client has claimed fail with [ButtplugDeviceError::DeviceBusy],
+  /// and sensor subscriptions are counted per client, see [ServerDevice::parse_client_message].
+  pub fn parse_client_message(
+    &self,
+    client_id: u32,
+    msg: ButtplugClientMessage,
+  ) -> ButtplugServerResultFuture {
+    if let Ok(device_msg) = ButtplugDeviceCommandMessageUnion::try_from(msg.clone()) {
+      if is_actuation_message(&device_msg) {
+        if let Some(device) = self.devices.get(&device_msg.device_index()) {
+          if let Err(err) = device.value().check_actuation_access(client_id) {
+            return future::ready(Err(err.into())).boxed();
+          }
+        }
+      }
+    }
+    self.route_message(client_id, msg)
+  }
+
+  /// Hand out an identifier for a new client connection, for claiming devices. Every server
+  /// built on this device manager is one client connection.
+  pub(crate) fn register_client(&self) -> u32 {
+    self.next_client_id.fetch_add(1, Ordering::Relaxed)
+  }
+
+  /// Claim a connected device for a client connection, see [ServerDevice::claim].
+  pub fn claim_device(&self, index: u32, client_id: u32) -> Result<(), ButtplugDeviceError> {
+    self
+      .devices
+      .get(&index)
+      .ok_or(ButtplugDeviceError::DeviceNotAvailable(index))?
+      .value()
+      .claim(client_id)
+  }
+
+  /// Release a client connection's claim on a connected device. Devices the client doesn't hold
+  /// are left alone.
+  pub fn release_device(&self, index: u32, client_id: u32) -> Result<(), ButtplugDeviceError> {
+    self
+      .devices
+      .get(&index)
+      .ok_or(ButtplugDeviceError::DeviceNotAvailable(index))?
+      .value()
+      .release_claim(client_id);
+    Ok(())
+  }
+
+  /// Release every claim a client connection holds, e.g. once it disconnects.
+  pub fn release_client_claims(&self, client_id: u32) {
+    for device in self.devices.iter() {
+      if device.value().release_claim(client_id) {
+        info!(
+          "Released claim of client {} on device {}.",
+          client_id,
+          device.key()
+        );
+      }
+    }
+  }
+
   pub fn device_info(&self, index: u32) -> Option<ServerDeviceInfo> {
     self.devices.get(&index).map(|device| ServerDeviceInfo {
       identifier: device.value().identifier().clone(),
-      display_name: device
-        .value()
-        .definition()
-        .user_config()
-        .display_name()
-        .clone(),
+      display_name: device.value().display_name(),
+      max_command_rate_hz: device.value().max_command_rate_hz(),
+      endpoints: device.value().endpoints(),
+      dry_run: device.value().dry_run(),
+      hardware_error_count: device.value().hardware_error_count(),
+      claimed_by: device.value().claimed_by(),
+      inverted_rotations: device.value().inverted_rotations(),
     })
   }
 
+  /// BLE advertisements found since scanning last started that didn't match any protocol, oldest
+  /// first, with the protocols each came close to matching. One entry is kept per address, and the
+  /// list is bounded, dropping the oldest entries first.
+  pub fn unmatched_advertisements(&self) -> Vec<UnmatchedAdvertisement> {
+    self
+      .unmatched_advertisements
+      .lock()
+      .expect("Lock poisoned")
+      .advertisements()
+  }
+
+  /// The most recent failed hardware operations on a connected device, oldest first.
+  pub fn device_hardware_error_history(&self, index: u32) -> Option<Vec<HardwareErrorRecord>> {
+    self
+      .devices
+      .get(&index)
+      .map(|device| device.value().hardware_error_history())
+  }
+
+  /// Write, read and error counters for a connected device. See [ServerDevice::stats].
+  pub fn device_stats(&self, index: u32) -> Option<DeviceIoStats> {
+    self
+      .devices
+      .get(&index)
+      .map(|device| device.value().stats())
+  }
+
+  /// Client connections subscribed to a sensor of a connected device. See
+  /// [ServerDevice::sensor_subscribers].
+  pub fn device_sensor_subscribers(
+    &self,
+    index: u32,
+    sensor_index: u32,
+    sensor_type: SensorType,
+  ) -> Option<Vec<u32>> {
+    self
+      .devices
+      .get(&index)
+      .map(|device| device.value().sensor_subscribers(sensor_index, sensor_type))
+  }
+
+  /// Current output state of a connected device, if its protocol keeps any. See
+  /// [ServerDevice::protocol_state].
+  pub fn device_protocol_state(&self, index: u32) -> Option<serde_json::Value> {
+    self
+      .devices
+      .get(&index)
+      .and_then(|device| device.value().protocol_state())
+  }
+
+  /// Turn dry run mode on or off for a connected device. While on, the writes the device would have
+  /// received are sent out as [ServerDeviceManagerEvent::DeviceDryRunWrite] events instead.
+  pub fn set_device_dry_run(&self, index: u32, dry_run: bool) -> Result<(), ButtplugDeviceError> {
+    let device = self
+      .devices
+      .get(&index)
+      .ok_or(ButtplugDeviceError::DeviceNotAvailable(index))?;
+    device.value().set_dry_run(dry_run);
+    Ok(())
+  }
+
+  /// Turn packet tracing on or off for a connected device. While on, every packet sent to or
+  /// received from the device is sent out as a [ServerDeviceManagerEvent::DevicePacketTrace] event.
+  pub fn set_device_packet_trace(
+    &self,
+    index: u32,
+    packet_trace: bool,
+  ) -> Result<(), ButtplugDeviceError> {
+    let device = self
+      .devices
+      .get(&index)
+      .ok_or(ButtplugDeviceError::DeviceNotAvailable(index))?;
+    device.value().set_packet_trace(packet_trace);
+    Ok(())
+  }
+
+  /// Turn calibration of sensor readings off or on for a connected device. While on, readings are
+  /// sent to clients as the device reports them, for calibration UIs.
+  pub fn set_device_raw_sensor_values(
+    &self,
+    index: u32,
+    raw_sensor_values: bool,
+  ) -> Result<(), ButtplugDeviceError> {
+    let device = self
+      .devices
+      .get(&index)
+      .ok_or(ButtplugDeviceError::DeviceNotAvailable(index))?;
+    device.value().set_raw_sensor_values(raw_sensor_values);
+    Ok(())
+  }
+
+  /// Turn logging of sensor readings outside of the sensor ranges in the device config on or off for
+  /// a connected device. Off by default.
+  pub fn set_device_sensor_range_checks(
+    &self,
+    index: u32,
+    sensor_range_checks: bool,
+  ) -> Result<(), ButtplugDeviceError> {
+    let device = self
+      .devices
+      .get(&index)
+      .ok_or(ButtplugDeviceError::DeviceNotAvailable(index))?;
+    device.value().set_sensor_range_checks(sensor_range_checks);
+    Ok(())
+  }
+
+  /// Disconnect a connected device. The removal is reported as a
+  /// [DeviceRemovedReason::ClientRequest].
+  pub fn disconnect_device(&self, index: u32) -> ButtplugResultFuture {
+    match self.devices.get(&index) {
+      Some(device) => device.value().disconnect(),
+      None => future::ready(Err(ButtplugDeviceError::DeviceNotAvailable(index).into())).boxed(),
+    }
+  }
+
+  /// Disconnect any connected devices the device configuration no longer allows, for use after the
+  /// user configuration changes (e.g. a device was added to the deny list). The removals are
+  /// reported as a [DeviceRemovedReason::ConfigDenied].
+  pub fn disconnect_denied_devices(&self) -> ButtplugResultFuture {
+    let fut_vec: Vec<_> = self
+      .devices
+      .iter()
+      .filter(|device| {
+        !self
+          .device_configuration_manager
+          .address_allowed(device.value().identifier().address())
+      })
+      .map(|device| {
+        device
+          .value()
+          .disconnect_with_reason(DeviceRemovedReason::ConfigDenied)
+      })
+      .collect();
+    async move {
+      for result in future::join_all(fut_vec).await {
+        result?;
+      }
+      Ok(())
+    }
+    .boxed()
+  }
+
+  /// Set the user config of a device, replacing any config it had, and return it as a user config
+  /// fragment (see [user_device_config_fragment]) for the caller to persist.
+  ///
+  /// Devices connecting from now on use the new config. If the device is connected, its display
+  /// name changes right away, and it's disconnected if the config denies it. Other changes, like
+  /// feature message overrides, apply once the device reconnects. As putting a device on the
+  /// allow list shuts out the devices that aren't, those are disconnected too.
+  ///
+  /// Devices connecting while the config changes get the new config: the config is updated before
+  /// the connected devices are looked at, and the device manager event loop adds a connecting
+  /// device to the connected devices before reading its config again. Neither side holds on to an
+  /// entry of one map while accessing the other, so the two can't deadlock.
+  pub fn set_user_device_config(
+    &self,
+    identifier: &UserDeviceIdentifier,
+    definition: &UserDeviceDefinition,
+  ) -> ButtplugResultFuture<String> {
+    if let Err(err) = self
+      .device_configuration_manager
+      .add_user_device_definition(identifier, definition)
+    {
+      return future::ready(Err(err.into())).boxed();
+    }
+    let fragment = user_device_config_fragment(identifier, definition);
+    self.update_connected_display_name(identifier, definition.user_config().display_name());
+    let disconnect = self.disconnect_denied_devices();
+    async move {
+      disconnect.await?;
+      fragment
+    }
+    .boxed()
+  }
+
+  /// Remove the user config of a device. If the device is connected, it goes back to the display
+  /// name template of its protocol (if there is one), and is disconnected if it's no longer on the
+  /// allow list. See [Self::set_user_device_config] for how devices that are connecting are
+  /// handled.
+  pub fn remove_user_device_config(
+    &self,
+    identifier: &UserDeviceIdentifier,
+  ) -> ButtplugResultFuture {
+    self
+      .device_configuration_manager
+      .remove_user_device_definition(identifier);
+    self.update_connected_display_name(identifier, &None);
+    self.disconnect_denied_devices()
+  }
+
+  fn update_connected_display_name(
+    &self,
+    identifier: &UserDeviceIdentifier,
+    display_name: &Option<String>,
+  ) {
+    let devices: Vec<_> = self
+      .devices
+      .iter()
+      .filter(|device| device.value().identifier() == identifier)
+      .map(|device| device.value().clone())
+      .collect();
+    for device in devices {
+      device.update_display_name(&self.device_configuration_manager, display_name);
+    }
+  }
+
   // Only a ButtplugServer should be able to call this. We don't want to expose this capability to
   // the outside world. Note that this could cause issues for lifetimes if someone holds this longer
   // than the lifetime of the server that originally created it. Ideally we should lock the Server
@@ -315,16 +997,21 @@ impl ServerDeviceManager {
     // again. Otherwise we can have all sorts of ownership weirdness.
     self.running.store(false, Ordering::SeqCst);
     let stop_scanning = self.stop_scanning();
-    let stop_devices = self.stop_all_devices();
     let token = self.loop_cancellation_token.clone();
     async move {
       // Force stop scanning, otherwise we can disconnect and instantly try to reconnect while
       // cleaning up if we're still scanning.
       let _ = stop_scanning.await;
-      let _ = stop_devices.await;
-      for device in devices.iter() {
-        device.value().disconnect().await?;
-      }
+      // Zero every device out before letting go of it, so nothing is left running on its last
+      // values.
+      let shutdown_futs: Vec<_> = devices
+        .iter()
+        .map(|device| {
+          let device = device.value().clone();
+          async move { device.shutdown(SHUTDOWN_STOP_TIMEOUT).await }
+        })
+        .collect();
+      future::join_all(shutdown_futs).await;
       token.cancel();
       Ok(message::Ok::default().into())
     }
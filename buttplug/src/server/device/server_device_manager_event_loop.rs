@@ -6,34 +6,64 @@
 // for full license information.
 
 use crate::{
-  core::message::{ButtplugServerMessage, DeviceAdded, DeviceRemoved, ScanningFinished},
+  core::{
+    errors::ButtplugDeviceError,
+    message::{
+      ButtplugServerMessage,
+      DeviceAdded,
+      DeviceRemoved,
+      DeviceRemovedReason,
+      ScanningFinished,
+    },
+  },
   server::device::{
-    configuration::DeviceConfigurationManager,
+    configuration::{
+      BaseDeviceIdentifier,
+      DeviceConfigurationManager,
+      ProtocolCommunicationSpecifier,
+    },
     hardware::communication::{HardwareCommunicationManager, HardwareCommunicationManagerEvent},
+    InitializationRetryPolicy,
     ServerDevice,
     ServerDeviceEvent,
   },
-  util::async_manager,
+  util::{self, async_manager},
 };
 use dashmap::{DashMap, DashSet};
 use futures::{future, FutureExt, StreamExt};
-use std::sync::Arc;
+use std::{
+  collections::HashSet,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
 use tokio::sync::{broadcast, mpsc};
 use tokio_util::sync::CancellationToken;
 use tracing;
 use tracing_futures::Instrument;
 
-use super::server_device_manager::DeviceManagerCommand;
+use super::device_match_cache::DeviceMatchCache;
+use super::server_device_manager::{
+  DeviceIgnoredReason,
+  DeviceManagerCommand,
+  ServerDeviceManagerEvent,
+};
+use super::unmatched_advertisements::{UnmatchedAdvertisement, UnmatchedAdvertisementLog};
 
 pub(super) struct ServerDeviceManagerEventLoop {
   comm_managers: Vec<Box<dyn HardwareCommunicationManager>>,
   device_config_manager: Arc<DeviceConfigurationManager>,
+  initialization_retry_policy: InitializationRetryPolicy,
+  /// How long the startup scan for auto-connect devices runs before giving up on the ones it
+  /// hasn't found.
+  auto_connect_timeout: Duration,
   device_command_receiver: mpsc::Receiver<DeviceManagerCommand>,
   /// Maps device index (exposed to the outside world) to actual device objects held by the server.
   device_map: Arc<DashMap<u32, Arc<ServerDevice>>>,
   /// Broadcaster that relays device events in the form of Buttplug Messages to
   /// whoever owns the Buttplug Server.
   server_sender: broadcast::Sender<ButtplugServerMessage>,
+  /// Broadcaster for device manager events that aren't part of the Buttplug protocol.
+  manager_event_sender: broadcast::Sender<ServerDeviceManagerEvent>,
   /// As the device manager owns the Device Communication Managers, it will have
   /// a receiver that the comm managers all send thru.
   device_comm_receiver: mpsc::Receiver<HardwareCommunicationManagerEvent>,
@@ -46,16 +76,26 @@ pub(super) struct ServerDeviceManagerEventLoop {
   scanning_bringup_in_progress: bool,
   /// Denote whether scanning has been started since we last sent a ScanningFinished message.
   scanning_started: bool,
+  /// Addresses of auto-connect devices that haven't connected yet. Only non-empty while the startup
+  /// scan for them is running.
+  auto_connect_pending: HashSet<String>,
   /// Devices currently trying to connect.
   connecting_devices: Arc<DashSet<String>>,
+  /// How devices that connected this session were matched to protocols.
+  device_match_cache: Arc<Mutex<DeviceMatchCache>>,
+  /// BLE advertisements that didn't match a protocol since scanning last started.
+  unmatched_advertisements: Arc<Mutex<UnmatchedAdvertisementLog>>,
   /// Cancellation token for the event loop
   loop_cancellation_token: CancellationToken,
 }
 
 impl ServerDeviceManagerEventLoop {
+  #[allow(clippy::too_many_arguments)]
   pub fn new(
     comm_managers: Vec<Box<dyn HardwareCommunicationManager>>,
     device_config_manager: Arc<DeviceConfigurationManager>,
+    initialization_retry_policy: InitializationRetryPolicy,
+    auto_connect_timeout: Duration,
     device_map: Arc<DashMap<u32, Arc<ServerDevice>>>,
     loop_cancellation_token: CancellationToken,
     server_sender: broadcast::Sender<ButtplugServerMessage>,
@@ -63,10 +103,19 @@ impl ServerDeviceManagerEventLoop {
     device_command_receiver: mpsc::Receiver<DeviceManagerCommand>,
   ) -> Self {
     let (device_event_sender, device_event_receiver) = mpsc::channel(256);
+    let manager_event_sender = broadcast::channel(255).0;
+    let auto_connect_pending = device_config_manager
+      .auto_connect_devices()
+      .iter()
+      .map(|identifier| identifier.address().clone())
+      .collect();
     Self {
       comm_managers,
       device_config_manager: device_config_manager,
+      initialization_retry_policy,
+      auto_connect_timeout,
       server_sender,
+      manager_event_sender,
       device_map,
       device_comm_receiver,
       device_event_sender,
@@ -74,7 +123,10 @@ impl ServerDeviceManagerEventLoop {
       device_command_receiver,
       scanning_bringup_in_progress: false,
       scanning_started: false,
+      auto_connect_pending,
       connecting_devices: Arc::new(DashSet::new()),
+      device_match_cache: Arc::new(Mutex::new(DeviceMatchCache::default())),
+      unmatched_advertisements: Arc::new(Mutex::new(UnmatchedAdvertisementLog::default())),
       loop_cancellation_token,
     }
   }
@@ -90,10 +142,18 @@ impl ServerDeviceManagerEventLoop {
   async fn handle_start_scanning(&mut self) {
     if self.scanning_status() || self.scanning_bringup_in_progress {
       debug!("System already scanning, ignoring new scanning request");
+      // The scan may have been started for auto-connect devices, in which case it's now the
+      // client's too, and it should hear when it finishes.
+      self.scanning_started = true;
       return;
     }
 
     info!("No scan currently in progress, starting new scan.");
+    self
+      .unmatched_advertisements
+      .lock()
+      .expect("Lock poisoned")
+      .clear();
     self.scanning_bringup_in_progress = true;
     self.scanning_started = true;
     let fut_vec: Vec<_> = self
@@ -108,6 +168,8 @@ impl ServerDeviceManagerEventLoop {
   }
 
   async fn handle_stop_scanning(&mut self) {
+    // Stopping the scan ends the search for auto-connect devices too.
+    self.auto_connect_pending.clear();
     let fut_vec: Vec<_> = self
       .comm_managers
       .iter_mut()
@@ -117,6 +179,88 @@ impl ServerDeviceManagerEventLoop {
     future::join_all(fut_vec).await;
   }
 
+  /// Scan for the auto-connect devices in the user config, if there are any. Unlike client scans,
+  /// only auto-connect devices are connected, and no ScanningFinished is sent to clients.
+  async fn start_auto_connect_scan(&mut self) {
+    if self.auto_connect_pending.is_empty() {
+      return;
+    }
+    info!(
+      "Scanning for auto-connect devices {:?}.",
+      self.auto_connect_pending
+    );
+    self.scanning_bringup_in_progress = true;
+    let fut_vec: Vec<_> = self
+      .comm_managers
+      .iter_mut()
+      .map(|guard| guard.start_scanning())
+      .collect();
+    future::join_all(fut_vec).await;
+    self.scanning_bringup_in_progress = false;
+  }
+
+  /// End the auto-connect scan, once every auto-connect device connected or it timed out. Scanning
+  /// is left running if a client started scanning in the meantime.
+  async fn finish_auto_connect_scan(&mut self) {
+    if !self.auto_connect_pending.is_empty() {
+      warn!(
+        "Auto-connect devices {:?} not found before timeout.",
+        self.auto_connect_pending
+      );
+    }
+    if self.scanning_started {
+      self.auto_connect_pending.clear();
+    } else {
+      info!("Auto-connect scan finished, stopping scanning.");
+      self.handle_stop_scanning().await;
+    }
+  }
+
+  pub fn manager_event_sender(&self) -> broadcast::Sender<ServerDeviceManagerEvent> {
+    self.manager_event_sender.clone()
+  }
+
+  pub fn unmatched_advertisements(&self) -> Arc<Mutex<UnmatchedAdvertisementLog>> {
+    self.unmatched_advertisements.clone()
+  }
+
+  /// Remember a BLE advertisement no protocol matched, with the protocols it came close to.
+  fn record_unmatched_advertisement(
+    &self,
+    name: &str,
+    address: &str,
+    specifier: &ProtocolCommunicationSpecifier,
+  ) {
+    let advertisement = if let ProtocolCommunicationSpecifier::BluetoothLE(btle) = specifier {
+      btle
+    } else {
+      return;
+    };
+    let near_misses = self
+      .device_config_manager
+      .protocol_near_misses(advertisement);
+    for near_miss in &near_misses {
+      info!(
+        "Device {} ({}) came close to matching protocol {}",
+        name, address, near_miss
+      );
+    }
+    self
+      .unmatched_advertisements
+      .lock()
+      .expect("Lock poisoned")
+      .insert(UnmatchedAdvertisement::new(
+        name,
+        address,
+        advertisement,
+        near_misses,
+      ));
+  }
+
+  fn send_device_ignored(&self, name: &str, address: &str, reason: DeviceIgnoredReason) {
+    send_device_ignored(&self.manager_event_sender, name, address, reason);
+  }
+
   async fn handle_device_communication(&mut self, event: HardwareCommunicationManagerEvent) {
     match event {
       HardwareCommunicationManagerEvent::ScanningFinished => {
@@ -147,6 +291,12 @@ impl ServerDeviceManagerEventLoop {
         info!("Device {} ({}) found.", name, address);
         // Make sure the device isn't on the deny list, or is on the allow list if anything is on it.
         if !self.device_config_manager.address_allowed(&address) {
+          let reason = if self.device_config_manager.address_denied(&address) {
+            DeviceIgnoredReason::DenyListed
+          } else {
+            DeviceIgnoredReason::NotAllowListed
+          };
+          self.send_device_ignored(&name, &address, reason);
           return;
         }
         debug!(
@@ -154,6 +304,18 @@ impl ServerDeviceManagerEventLoop {
           address
         );
 
+        // Only auto-connect devices are connected while the startup scan is the only one running.
+        if !self.scanning_started
+          && !self.auto_connect_pending.is_empty()
+          && !self.auto_connect_pending.contains(&address)
+        {
+          debug!(
+            "Device {} isn't an auto-connect device, ignoring during auto-connect scan.",
+            address
+          );
+          return;
+        }
+
         // Check to make sure the device isn't already connected. If it is, drop what we've been
         // sent and return.
         if self
@@ -175,12 +337,26 @@ impl ServerDeviceManagerEventLoop {
         //
         // We used to do this in build_server_device, but we shouldn't mark devices as actually
         // connecting until after this happens, so we're moving it back here.
-        let protocol_specializers = self
-          .device_config_manager
-          .protocol_specializers(&creator.specifier());
+        //
+        // Devices matched before skip this, and go straight to the protocol they matched then. The
+        // allow and deny lists have been checked above either way.
+        let config_generation = self.device_config_manager.config_generation();
+        let cached_match = self.device_match_cache.lock().expect("Lock poisoned").get(
+          &address,
+          &creator.specifier(),
+          config_generation,
+        );
+        let protocol_specializers = if cached_match.is_some() {
+          debug!("Device {} matched before, reusing match.", address);
+          vec![]
+        } else {
+          self
+            .device_config_manager
+            .protocol_specializers(&creator.specifier())
+        };
 
         // If we have no identifiers, then there's nothing to do here. Throw an error.
-        if protocol_specializers.is_empty() {
+        if cached_match.is_none() && protocol_specializers.is_empty() {
           debug!(
             "{}",
             format!(
@@ -188,6 +364,8 @@ impl ServerDeviceManagerEventLoop {
               creator.specifier()
             )
           );
+          self.record_unmatched_advertisement(&name, &address, &creator.specifier());
+          self.send_device_ignored(&name, &address, DeviceIgnoredReason::NoMatchingProtocol);
           return;
         }
 
@@ -206,16 +384,47 @@ impl ServerDeviceManagerEventLoop {
         let device_event_sender_clone = self.device_event_sender.clone();
 
         let device_config_manager = self.device_config_manager.clone();
+        let initialization_retry_policy = self.initialization_retry_policy;
         let connecting_devices = self.connecting_devices.clone();
+        let device_match_cache = self.device_match_cache.clone();
+        let manager_event_sender = self.manager_event_sender.clone();
         let span = info_span!(
           "device creation",
-          name = tracing::field::display(name),
+          name = tracing::field::display(name.clone()),
           address = tracing::field::display(address.clone())
         );
 
         async_manager::spawn(async move {
-          match ServerDevice::build(device_config_manager, creator, protocol_specializers).await {
-            Ok(device) => {
+          match ServerDevice::build(
+            device_config_manager.clone(),
+            creator,
+            protocol_specializers,
+            cached_match,
+            initialization_retry_policy,
+          )
+          .await
+          {
+            Ok((device, device_match)) => {
+              device_match_cache.lock().expect("Lock poisoned").insert(
+                &address,
+                device_match,
+                config_generation,
+              );
+              let identifier = device.identifier();
+              if identifier.identifier().is_some() {
+                let base_identifier =
+                  BaseDeviceIdentifier::new(identifier.protocol(), identifier.identifier());
+                if !device_config_manager.has_base_device_definition(&base_identifier) {
+                  // No receivers is fine, most embedders won't care about this.
+                  let _ = manager_event_sender.send(
+                    ServerDeviceManagerEvent::UnknownDeviceIdentifier {
+                      name: device.name(),
+                      address: address.clone(),
+                      identifier: base_identifier,
+                    },
+                  );
+                }
+              }
               if device_event_sender_clone
                 .send(ServerDeviceEvent::Connected(Arc::new(device)))
                 .await
@@ -223,7 +432,18 @@ impl ServerDeviceManagerEventLoop {
                 error!("Device manager disappeared before connection established, device will be dropped.");
               }
             },
+            Err(ButtplugDeviceError::DeviceConfigurationError(msg)) => {
+              device_match_cache.lock().expect("Lock poisoned").remove(&address);
+              info!("No viable protocols for device after connection: {}", msg);
+              send_device_ignored(
+                &manager_event_sender,
+                &name,
+                &address,
+                DeviceIgnoredReason::NoViableProtocolAfterConnect(msg),
+              );
+            }
             Err(e) => {
+              device_match_cache.lock().expect("Lock poisoned").remove(&address);
               error!("Device errored while trying to connect: {}", e);
             }
           }
@@ -256,12 +476,17 @@ impl ServerDeviceManagerEventLoop {
         if let Some((_, old_device)) = self.device_map.remove(&device_index) {
           info!("Device map contains key {}.", device_index);
           // After removing the device from the array, manually disconnect it to
-          // make sure the event is thrown.
-          if let Err(err) = old_device.disconnect().await {
+          // make sure the event is thrown. Getting here means the device came back before we saw
+          // it drop, so the old connection is counted as gone on the device's side.
+          if let Err(err) = old_device
+            .disconnect_with_reason(DeviceRemovedReason::DeviceInitiated)
+            .await
+          {
             // If we throw an error during the disconnect, we can't really do
             // anything with it, but should at least log it.
             error!("Error during index collision disconnect: {:?}", err);
           }
+          old_device.teardown();
         } else {
           info!("Device map does not contain key {}.", device_index);
         }
@@ -281,25 +506,59 @@ impl ServerDeviceManagerEventLoop {
         });
 
         info!("Assigning index {} to {}", device_index, device.name());
-        let device_added_message = DeviceAdded::new(
-          device_index,
-          &device.name(),
-          &device.definition().user_config().display_name(),
-          &None,
-          &device.message_attributes().clone().into(),
-        );
-        self.device_map.insert(device_index, device);
-        // After that, we can send out to the server's event listeners to let
-        // them know a device has been added.
-        if self
-          .server_sender
-          .send(device_added_message.into())
-          .is_err()
+        let auto_connected = self
+          .auto_connect_pending
+          .remove(device.identifier().address());
+        self.device_map.insert(device_index, device.clone());
+        // The user config of the device may have changed while it was connecting. Changes made
+        // through ServerDeviceManager::set_user_device_config update the config before looking for
+        // the device in the map, so checking the config again now that the device is in the map
+        // means the change reaches the device either way.
+        let user_display_name = self
+          .device_config_manager
+          .user_device_definitions()
+          .get(device.identifier())
+          .and_then(|definition| definition.user_config().display_name().clone());
+        device.update_display_name(&self.device_config_manager, &user_display_name);
+        if !self
+          .device_config_manager
+          .address_allowed(device.identifier().address())
         {
-          debug!("Server not currently available, dropping Device Added event.");
+          info!(
+            "Device {} was denied while connecting, disconnecting.",
+            device.identifier()
+          );
+          self.device_map.remove(&device_index);
+          if let Err(err) = device
+            .disconnect_with_reason(DeviceRemovedReason::ConfigDenied)
+            .await
+          {
+            error!("Error disconnecting denied device: {:?}", err);
+          }
+          device.teardown();
+        } else {
+          let device_added_message = DeviceAdded::new(
+            device_index,
+            &device.name(),
+            &device.display_name(),
+            &None,
+            &device.client_message_attributes(),
+          );
+          // After that, we can send out to the server's event listeners to let
+          // them know a device has been added.
+          if self
+            .server_sender
+            .send(device_added_message.into())
+            .is_err()
+          {
+            debug!("Server not currently available, dropping Device Added event.");
+          }
+        }
+        if auto_connected && self.auto_connect_pending.is_empty() {
+          self.finish_auto_connect_scan().await;
         }
       }
-      ServerDeviceEvent::Disconnected(identifier) => {
+      ServerDeviceEvent::Disconnected(identifier, reason) => {
         let mut device_index = None;
         for device_pair in self.device_map.iter() {
           if *device_pair.value().identifier() == identifier {
@@ -308,19 +567,79 @@ impl ServerDeviceManagerEventLoop {
           }
         }
         if let Some(device_index) = device_index {
-          self
+          let (_, device) = self
             .device_map
             .remove(&device_index)
             .expect("Remove will always work.");
+          device.teardown();
+          info!(
+            "Device {} ({}) removed: {}",
+            device_index, identifier, reason
+          );
+          // No receivers is fine, most embedders won't care about this.
+          let _ = self
+            .manager_event_sender
+            .send(ServerDeviceManagerEvent::DeviceRemoved {
+              index: device_index,
+              identifier,
+              reason,
+            });
           if self
             .server_sender
-            .send(DeviceRemoved::new(device_index).into())
+            .send(DeviceRemoved::new(device_index).with_reason(reason).into())
             .is_err()
           {
             debug!("Server not currently available, dropping Device Removed event.");
           }
         }
       }
+      ServerDeviceEvent::DryRunWrite(identifier, write) => {
+        let device_index = self
+          .device_map
+          .iter()
+          .find(|device_pair| *device_pair.value().identifier() == identifier)
+          .map(|device_pair| *device_pair.key());
+        if let Some(index) = device_index {
+          let _ = self
+            .manager_event_sender
+            .send(ServerDeviceManagerEvent::DeviceDryRunWrite {
+              index,
+              endpoint: write.endpoint(),
+              data: write.data().clone(),
+            });
+        }
+      }
+      ServerDeviceEvent::PacketTrace(identifier, packet) => {
+        let device_index = self
+          .device_map
+          .iter()
+          .find(|device_pair| *device_pair.value().identifier() == identifier)
+          .map(|device_pair| *device_pair.key());
+        if let Some(index) = device_index {
+          let _ = self
+            .manager_event_sender
+            .send(ServerDeviceManagerEvent::DevicePacketTrace {
+              index,
+              identifier,
+              packet,
+            });
+        }
+      }
+      ServerDeviceEvent::AutoZeroTimeout(identifier) => {
+        let device = self
+          .device_map
+          .iter()
+          .find(|device_pair| *device_pair.value().identifier() == identifier)
+          .map(|device_pair| device_pair.value().clone());
+        if let Some(device) = device {
+          // Stopping waits on the device, which shouldn't hold up the event loop.
+          async_manager::spawn(async move {
+            if let Err(err) = device.handle_auto_zero_timeout().await {
+              error!("Error stopping device after auto zero timeout: {:?}", err);
+            }
+          });
+        }
+      }
       ServerDeviceEvent::Notification(_, message) => {
         if self.server_sender.send(message.into()).is_err() {
           debug!("Server not currently available, dropping Device Added event.");
@@ -331,6 +650,9 @@ impl ServerDeviceManagerEventLoop {
 
   pub async fn run(&mut self) {
     debug!("Starting Device Manager Loop");
+    self.start_auto_connect_scan().await;
+    let auto_connect_timeout = util::sleep(self.auto_connect_timeout);
+    pin_mut!(auto_connect_timeout);
     loop {
       tokio::select! {
         device_comm_msg = self.device_comm_receiver.recv() => {
@@ -362,6 +684,9 @@ impl ServerDeviceManagerEventLoop {
             break;
           }
         }
+        _ = &mut auto_connect_timeout, if !self.auto_connect_pending.is_empty() => {
+          self.finish_auto_connect_scan().await;
+        }
         _ = self.loop_cancellation_token.cancelled().fuse() => {
           debug!("Device event loop cancelled, exiting.");
           break;
@@ -371,3 +696,17 @@ impl ServerDeviceManagerEventLoop {
     debug!("Exiting Device Manager Loop");
   }
 }
+
+fn send_device_ignored(
+  sender: &broadcast::Sender<ServerDeviceManagerEvent>,
+  name: &str,
+  address: &str,
+  reason: DeviceIgnoredReason,
+) {
+  // No receivers is fine, most embedders won't care about this.
+  let _ = sender.send(ServerDeviceManagerEvent::DeviceIgnored {
+    name: name.to_owned(),
+    address: address.to_owned(),
+    reason,
+  });
+}
@@ -0,0 +1,133 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Remembers BLE advertisements that didn't match any protocol during a scanning session, with the
+//! protocols they came closest to, so users can report unsupported devices without having to dig
+//! names and services out of the logs.
+
+use super::configuration::{BluetoothLESpecifier, ProtocolNearMiss};
+use getset::Getters;
+use std::collections::{HashMap, VecDeque};
+use uuid::Uuid;
+
+/// Most advertisements kept per scanning session. The oldest is dropped past that.
+pub(super) const UNMATCHED_ADVERTISEMENT_LOG_LENGTH: usize = 32;
+
+/// A BLE advertisement that didn't match any protocol.
+#[derive(Debug, Clone, PartialEq, Eq, Getters)]
+#[getset(get = "pub")]
+pub struct UnmatchedAdvertisement {
+  name: String,
+  address: String,
+  /// Advertised manufacturer data, by company.
+  manufacturer_data: HashMap<u16, Vec<u8>>,
+  /// Advertised service UUIDs, sorted.
+  advertised_services: Vec<Uuid>,
+  /// Advertised service data, by service UUID.
+  service_data: HashMap<Uuid, Vec<u8>>,
+  /// Protocols the advertisement came close to matching, and why they didn't. Empty if it wasn't
+  /// close to any.
+  near_misses: Vec<ProtocolNearMiss>,
+}
+
+impl UnmatchedAdvertisement {
+  /// Record of `advertisement`, a specifier created from a device advertisement.
+  pub fn new(
+    name: &str,
+    address: &str,
+    advertisement: &BluetoothLESpecifier,
+    near_misses: Vec<ProtocolNearMiss>,
+  ) -> Self {
+    let mut advertised_services: Vec<Uuid> = advertisement
+      .advertised_services()
+      .iter()
+      .copied()
+      .collect();
+    advertised_services.sort();
+    Self {
+      name: name.to_owned(),
+      address: address.to_owned(),
+      manufacturer_data: advertisement
+        .manufacturer_data()
+        .iter()
+        .map(|data| (*data.company(), data.data().clone().unwrap_or_default()))
+        .collect(),
+      advertised_services,
+      service_data: advertisement.advertised_service_data().clone(),
+      near_misses,
+    }
+  }
+}
+
+/// Unmatched advertisements of the current scanning session, one per address, oldest first.
+#[derive(Debug, Default)]
+pub(super) struct UnmatchedAdvertisementLog {
+  advertisements: VecDeque<UnmatchedAdvertisement>,
+}
+
+impl UnmatchedAdvertisementLog {
+  /// Store an advertisement, replacing an earlier one from the same address.
+  pub fn insert(&mut self, advertisement: UnmatchedAdvertisement) {
+    self
+      .advertisements
+      .retain(|stored| stored.address != advertisement.address);
+    if self.advertisements.len() == UNMATCHED_ADVERTISEMENT_LOG_LENGTH {
+      self.advertisements.pop_front();
+    }
+    self.advertisements.push_back(advertisement);
+  }
+
+  pub fn advertisements(&self) -> Vec<UnmatchedAdvertisement> {
+    self.advertisements.iter().cloned().collect()
+  }
+
+  pub fn clear(&mut self) {
+    self.advertisements.clear();
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn advertisement(name: &str, address: &str) -> UnmatchedAdvertisement {
+    UnmatchedAdvertisement::new(
+      name,
+      address,
+      &BluetoothLESpecifier::new_from_device(name, &HashMap::new(), &[], &HashMap::new()),
+      vec![],
+    )
+  }
+
+  #[test]
+  fn test_unmatched_advertisement_log() {
+    let mut log = UnmatchedAdvertisementLog::default();
+    log.insert(advertisement("First", "1"));
+    log.insert(advertisement("Second", "2"));
+    // Advertising again moves the address to the back, with the newest advertisement.
+    log.insert(advertisement("Renamed", "1"));
+    let names: Vec<String> = log
+      .advertisements()
+      .iter()
+      .map(|advertisement| advertisement.name().clone())
+      .collect();
+    assert_eq!(names, vec!["Second", "Renamed"]);
+    log.clear();
+    assert!(log.advertisements().is_empty());
+  }
+
+  #[test]
+  fn test_unmatched_advertisement_log_length() {
+    let mut log = UnmatchedAdvertisementLog::default();
+    for i in 0..=UNMATCHED_ADVERTISEMENT_LOG_LENGTH {
+      log.insert(advertisement("Device", &i.to_string()));
+    }
+    let advertisements = log.advertisements();
+    assert_eq!(advertisements.len(), UNMATCHED_ADVERTISEMENT_LOG_LENGTH);
+    assert_eq!(advertisements[0].address(), "1");
+  }
+}
@@ -49,7 +49,7 @@ pub mod device;
 mod ping_timer;
 
 use self::device::{
-  configuration::DeviceConfigurationManagerBuilder,
+  configuration::{DeviceConfigurationManagerBuilder, ServerDeviceConfigInfo},
   ServerDeviceManager,
   ServerDeviceManagerBuilder,
 };
@@ -63,7 +63,6 @@ use crate::{
       ButtplugDeviceManagerMessageUnion,
       ButtplugMessage,
       ButtplugServerMessage,
-      StopAllDevices,
       StopScanning,
       BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
     },
@@ -152,6 +151,21 @@ impl ButtplugServerBuilder {
     }
   }
 
+  /// Build servers on a device manager other servers already use, e.g. from
+  /// [ButtplugServer::device_manager], so several clients can use the same devices at once. Each
+  /// server is one client connection, see [ButtplugServer::claim_device] for keeping clients from
+  /// fighting over a device.
+  ///
+  /// Disconnecting any of the servers still stops all devices, and shutting one down shuts down
+  /// the device manager for all of them.
+  pub fn new_with_shared_device_manager(device_manager: Arc<ServerDeviceManager>) -> Self {
+    Self {
+      name: "Buttplug Server".to_owned(),
+      max_ping_time: None,
+      device_manager,
+    }
+  }
+
   /// Set the name of the server, which is relayed to the client on connection (mostly for
   /// confirmation in UI dialogs)
   pub fn name(&mut self, name: &str) -> &mut Self {
@@ -176,6 +190,14 @@ impl ButtplugServerBuilder {
     // Create the server
     debug!("Creating server '{}'", self.name);
     info!("Buttplug Server Operating System Info: {}", os_info::get());
+    match self
+      .device_manager
+      .device_configuration_manager()
+      .config_info()
+    {
+      Some(config_info) => info!("Buttplug Server Device Configuration: {}", config_info),
+      None => info!("Buttplug Server Device Configuration: not loaded from config"),
+    }
 
     // Set up our channels to different parts of the system.
     let (output_sender, _) = broadcast::channel(256);
@@ -183,6 +205,7 @@ impl ButtplugServerBuilder {
 
     let connected = Arc::new(AtomicBool::new(false));
     let connected_clone = connected.clone();
+    let client_id = self.device_manager.register_client();
 
     // TODO this should use a cancellation token instead of passing around the timer itself.
     let ping_time = self.max_ping_time.unwrap_or(0);
@@ -199,7 +222,10 @@ impl ButtplugServerBuilder {
           error!("Ping out signal received, stopping server");
           connected_clone.store(false, Ordering::SeqCst);
           async_manager::spawn(async move {
-            if let Err(e) = device_manager_clone.stop_all_devices().await {
+            if let Err(e) = device_manager_clone
+              .handle_client_disconnect(client_id)
+              .await
+            {
               error!("Could not stop devices on ping timeout: {:?}", e);
             }
           });
@@ -223,6 +249,7 @@ impl ButtplugServerBuilder {
       ping_timer,
       connected,
       output_sender,
+      client_id,
     })
   }
 }
@@ -250,6 +277,8 @@ pub struct ButtplugServer {
   /// Broadcaster for server events. Receivers for this are handed out through the
   /// [ButtplugServer::event_stream()] method.
   output_sender: broadcast::Sender<ButtplugServerMessage>,
+  /// Identifies the client connection of this server to the device manager, for device claims.
+  client_id: u32,
 }
 
 impl std::fmt::Debug for ButtplugServer {
@@ -279,20 +308,55 @@ impl ButtplugServer {
     self.device_manager.clone()
   }
 
+  /// Returns where the device configuration of the server came from, or None if the device
+  /// configuration manager wasn't built from loaded configs.
+  pub fn device_config_info(&self) -> Option<ServerDeviceConfigInfo> {
+    self
+      .device_manager
+      .device_configuration_manager()
+      .config_info()
+      .clone()
+  }
+
   /// If true, client is currently connected to the server.
   pub fn connected(&self) -> bool {
     self.connected.load(Ordering::SeqCst)
   }
 
+  /// Identifier of this server's client connection, as used for device claims.
+  pub fn client_id(&self) -> u32 {
+    self.client_id
+  }
+
+  /// Claim a device for this server's client, so actuation commands from clients of other servers
+  /// sharing the device manager are refused with
+  /// [ButtplugDeviceError::DeviceBusy](crate::core::errors::ButtplugDeviceError::DeviceBusy).
+  /// Sensor commands and stops are still shared. The claim is released on disconnect, or with
+  /// [Self::release_device].
+  pub fn claim_device(&self, device_index: u32) -> Result<(), ButtplugError> {
+    Ok(
+      self
+        .device_manager
+        .claim_device(device_index, self.client_id)?,
+    )
+  }
+
+  /// Release this server's claim on a device, if it holds one.
+  pub fn release_device(&self, device_index: u32) -> Result<(), ButtplugError> {
+    Ok(
+      self
+        .device_manager
+        .release_device(device_index, self.client_id)?,
+    )
+  }
+
   /// Disconnects the server from a client, if it is connected.
   pub fn disconnect(&self) -> BoxFuture<Result<(), message::Error>> {
     debug!("Buttplug Server {} disconnect requested", self.server_name);
     let ping_timer = self.ping_timer.clone();
     let stop_scanning_fut =
       self.parse_message(ButtplugClientMessage::StopScanning(StopScanning::default()));
-    let stop_fut = self.parse_message(ButtplugClientMessage::StopAllDevices(
-      StopAllDevices::default(),
-    ));
+    let stop_fut = self.device_manager.handle_client_disconnect(self.client_id);
     let connected = self.connected.clone();
     async move {
       connected.store(false, Ordering::SeqCst);
@@ -348,7 +412,9 @@ impl ButtplugServer {
     let out_fut = if ButtplugDeviceManagerMessageUnion::try_from(msg.clone()).is_ok()
       || ButtplugDeviceCommandMessageUnion::try_from(msg.clone()).is_ok()
     {
-      self.device_manager.parse_message(msg.clone())
+      self
+        .device_manager
+        .parse_client_message(self.client_id, msg.clone())
     } else {
       match msg {
         ButtplugClientMessage::RequestServerInfo(rsi_msg) => self.perform_handshake(rsi_msg),
@@ -401,9 +467,12 @@ impl ButtplugServer {
     let out_msg =
       message::ServerInfo::new(&self.server_name, msg.message_version(), self.max_ping_time);
     let connected = self.connected.clone();
+    let device_manager = self.device_manager.clone();
+    let client_id = self.client_id;
     async move {
       ping_timer.start_ping_timer().await;
       connected.store(true, Ordering::SeqCst);
+      device_manager.handle_client_connect(client_id);
       debug!("Server handshake check successful.");
       Result::Ok(out_msg.into())
     }
@@ -423,6 +492,8 @@ impl ButtplugServer {
     .boxed()
   }
 
+  /// Shut the server down. Every connected device is stopped and zeroed out (waiting a bounded
+  /// time for the writes) before its hardware is disconnected and its background tasks halted.
   pub fn shutdown(&self) -> ButtplugServerResultFuture {
     let device_manager = self.device_manager.clone();
     //let disconnect_future = self.disconnect();
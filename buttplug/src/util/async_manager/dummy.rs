@@ -34,6 +34,14 @@ where
   unimplemented!("Dummy executor can't actually spawn!")
 }
 
+pub async fn spawn_blocking<F, R>(_: F) -> R
+where
+  F: FnOnce() -> R + Send + 'static,
+  R: Send + 'static,
+{
+  unimplemented!("Dummy executor can't actually spawn!")
+}
+
 pub fn block_on<F>(_: F) -> <F as Future>::Output
 where
   F: Future,
@@ -38,6 +38,18 @@ where
   TokioAsyncManager::default().spawn_with_handle(future)
 }
 
+/// Run blocking work (like file IO) on a thread where it won't hold up other tasks.
+pub async fn spawn_blocking<F, R>(f: F) -> R
+where
+  F: FnOnce() -> R + Send + 'static,
+  R: Send + 'static,
+{
+  match tokio::task::spawn_blocking(f).await {
+    Ok(result) => result,
+    Err(e) => std::panic::resume_unwind(e.into_panic()),
+  }
+}
+
 pub fn block_on<F>(f: F) -> <F as Future>::Output
 where
   F: Future,
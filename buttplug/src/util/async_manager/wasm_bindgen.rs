@@ -37,6 +37,15 @@ where
   WasmBindgenAsyncManager::default().spawn_with_handle(future)
 }
 
+/// There are no other threads to move blocking work to, so it runs in place.
+pub async fn spawn_blocking<F, R>(f: F) -> R
+where
+  F: FnOnce() -> R + Send + 'static,
+  R: Send + 'static,
+{
+  f()
+}
+
 pub fn block_on<F>(_: F) -> <F as Future>::Output
 where
   F: Future,
@@ -0,0 +1,1462 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+use super::{
+  async_manager,
+  device_configuration_lint::{validate_external_config, ConfigLint, ConfigLintSeverity},
+  json::JSONValidator,
+};
+use crate::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError, ConfigurationError, SchemaValidationError},
+    message::{ActuatorType, ButtplugActuatorFeatureMessageType, DeviceFeature, SensorType},
+  },
+  server::device::configuration::{
+    check_device_access,
+    BaseDeviceDefinition,
+    BaseDeviceIdentifier,
+    DeviceConfigurationManager,
+    DeviceConfigurationManagerBuilder,
+    ProtocolCommunicationSpecifier,
+    ProtocolView,
+    ServerDeviceConfigInfo,
+    UserDeviceCustomization,
+    UserDeviceDefinition,
+    UserDeviceIdentifier,
+  },
+};
+use async_stream::stream;
+use dashmap::DashMap;
+use futures::Stream;
+use getset::{CopyGetters, Getters, MutGetters, Setters};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::{HashMap, HashSet},
+  fmt::Display,
+  io::ErrorKind,
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime},
+};
+
+pub mod tooling;
+
+pub static DEVICE_CONFIGURATION_JSON: &str =
+  include_str!("../../../buttplug-device-config/build-config/buttplug-device-config-v3.json");
+static DEVICE_CONFIGURATION_JSON_SCHEMA: &str = include_str!(
+  "../../../buttplug-device-config/device-config-v3/buttplug-device-config-schema-v3.json"
+);
+
+// Compiling the schema is the most expensive part of loading a config, so each validator is
+// compiled once and shared between loads.
+static CONFIG_VALIDATOR: Lazy<JSONValidator> =
+  Lazy::new(|| JSONValidator::new(DEVICE_CONFIGURATION_JSON_SCHEMA));
+// User config files only use the user-configs section, so there's no need to validate against the
+// full protocol schema.
+static USER_CONFIG_VALIDATOR: Lazy<JSONValidator> = Lazy::new(|| {
+  JSONValidator::new_for_properties(
+    DEVICE_CONFIGURATION_JSON_SCHEMA,
+    &["version", "user-configs"],
+  )
+});
+static PROTOCOL_DEFINITION_VALIDATOR: Lazy<JSONValidator> = Lazy::new(|| {
+  JSONValidator::new_for_component(DEVICE_CONFIGURATION_JSON_SCHEMA, "protocol-definition")
+});
+
+/// The top level configuration for a protocol. Contains all data about devices that can use the
+/// protocol, as well as names, message attributes, etc... for different devices.
+///
+/// Example: A Kiiroo ProtocolDeviceConfiguration would contain the Bluetooth LE information for all
+/// devices supported under the Kiiroo protocol. It would also contain information about the names
+/// and capabilities of different Kiiroo devices (Cliona, Onyx, Keon, etc...).
+#[derive(Debug, Clone, Getters, MutGetters, Default)]
+struct ProtocolDeviceConfiguration {
+  /// BLE/USB/etc info for device identification.
+  #[getset(get = "pub(crate)", get_mut = "pub(crate)")]
+  specifiers: Vec<ProtocolCommunicationSpecifier>,
+  /// Names and message attributes for all possible devices that use this protocol
+  #[getset(get = "pub(crate)", get_mut = "pub(crate)")]
+  configurations: HashMap<Option<String>, BaseDeviceDefinition>,
+}
+
+impl ProtocolDeviceConfiguration {
+  /// Create a new instance
+  pub fn new(
+    specifiers: Vec<ProtocolCommunicationSpecifier>,
+    configurations: HashMap<Option<String>, BaseDeviceDefinition>,
+  ) -> Self {
+    Self {
+      specifiers,
+      configurations,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Getters, Setters, MutGetters)]
+#[getset(get = "pub", set = "pub", get_mut = "pub(crate)")]
+struct ProtocolAttributes {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  identifier: Option<Vec<String>>,
+  /// Identifier of another configuration in the same protocol (or "defaults") that this
+  /// configuration takes any fields it doesn't set from. Configurations without a parent inherit
+  /// from the protocol defaults.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  inherits: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  name: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  features: Option<Vec<DeviceFeature>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, Getters, Setters, MutGetters)]
+#[getset(get = "pub", set = "pub", get_mut = "pub(crate)")]
+struct ProtocolDefinition {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub communication: Option<Vec<ProtocolCommunicationSpecifier>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub defaults: Option<ProtocolAttributes>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub configurations: Vec<ProtocolAttributes>,
+  /// Display name template for devices of this protocol without a display name of their own. Only
+  /// used in user configs.
+  #[serde(
+    rename = "display-name",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub display_name: Option<String>,
+  /// How long protocol initialization can take, in milliseconds. Only used in base configs.
+  #[serde(
+    rename = "initialize-timeout-ms",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub initialize_timeout_ms: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Getters, Setters, MutGetters)]
+#[getset(get = "pub", set = "pub", get_mut = "pub(crate)")]
+struct UserDeviceConfigPair {
+  identifier: UserDeviceIdentifier,
+  config: UserDeviceDefinition,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, Getters, Setters, MutGetters)]
+#[getset(get = "pub", set = "pub", get_mut = "pub")]
+struct UserConfigDefinition {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  protocols: Option<DashMap<String, ProtocolDefinition>>,
+  #[serde(rename = "devices", default, skip_serializing_if = "Option::is_none")]
+  user_device_configs: Option<Vec<UserDeviceConfigPair>>,
+  #[serde(
+    rename = "disabled-protocols",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  disabled_protocols: Option<Vec<String>>,
+}
+
+static INHERIT_DEFAULTS: &str = "defaults";
+
+impl ProtocolDefinition {
+  /// Resolve the name and features of a configuration, walking its inheritance chain until every
+  /// field is set. Fields a chain leaves unset come from the protocol defaults.
+  fn resolve_configuration(
+    &self,
+    protocol_name: &str,
+    defaults: &ProtocolAttributes,
+    identifier_map: &HashMap<&str, usize>,
+    index: usize,
+  ) -> Result<(String, Vec<DeviceFeature>), ConfigurationError> {
+    let mut name = None;
+    let mut features = None;
+    let mut chain = vec![];
+    let mut current = Some(index);
+    while let Some(index) = current {
+      if chain.contains(&index) {
+        return Err(ConfigurationError::InheritanceCycle {
+          protocol: protocol_name.to_owned(),
+          chain: chain
+            .iter()
+            .chain([&index])
+            .map(|index| self.configurations[*index].identifier_name())
+            .collect(),
+        });
+      }
+      chain.push(index);
+      let config = &self.configurations[index];
+      name = name.or_else(|| config.name.clone());
+      features = features.or_else(|| config.features.clone());
+      current = match config.inherits.as_deref() {
+        None => None,
+        Some(parent) if parent == INHERIT_DEFAULTS => None,
+        Some(parent) => Some(*identifier_map.get(parent).ok_or_else(|| {
+          ConfigurationError::UnknownInheritanceParent {
+            protocol: protocol_name.to_owned(),
+            identifier: config.identifier_name(),
+            parent: parent.to_owned(),
+          }
+        })?),
+      };
+    }
+    Ok((
+      name.or_else(|| defaults.name.clone()).unwrap_or_default(),
+      features
+        .or_else(|| defaults.features.clone())
+        .expect("Defaults always have features"),
+    ))
+  }
+}
+
+impl ProtocolAttributes {
+  /// The first identifier of a configuration, for error messages.
+  fn identifier_name(&self) -> String {
+    self
+      .identifier
+      .as_ref()
+      .and_then(|identifiers| identifiers.first().cloned())
+      .unwrap_or_default()
+  }
+}
+
+impl ProtocolDeviceConfiguration {
+  /// Build a protocol configuration from a definition, resolving configuration inheritance.
+  fn from_definition(
+    protocol_name: &str,
+    protocol_def: ProtocolDefinition,
+  ) -> Result<Self, ConfigurationError> {
+    let mut configurations = HashMap::new();
+
+    if let Some(defaults) = protocol_def.defaults() {
+      let config_attrs = BaseDeviceDefinition::new(
+        defaults.name.as_deref().unwrap_or_default(),
+        defaults
+          .features
+          .as_ref()
+          .expect("This is a default, therefore we'll always have features."),
+      );
+      configurations.insert(None, config_attrs);
+      let mut identifier_map = HashMap::new();
+      for (index, config) in protocol_def.configurations.iter().enumerate() {
+        for identifier in config.identifier.iter().flatten() {
+          identifier_map.insert(identifier.as_str(), index);
+        }
+      }
+      for (index, config) in protocol_def.configurations.iter().enumerate() {
+        if let Some(identifiers) = &config.identifier {
+          let (name, features) =
+            protocol_def.resolve_configuration(protocol_name, defaults, &identifier_map, index)?;
+          // Even subconfigurations always have names
+          let config_attrs = BaseDeviceDefinition::new(&name, &features);
+          for identifier in identifiers {
+            configurations.insert(Some(identifier.to_owned()), config_attrs.clone());
+          }
+        }
+      }
+    }
+
+    Ok(Self::new(
+      protocol_def.communication.unwrap_or_default(),
+      configurations,
+    ))
+  }
+}
+
+#[derive(Deserialize, Serialize, Debug, CopyGetters, Clone, Copy)]
+#[getset(get_copy = "pub", get_mut = "pub")]
+struct ConfigVersion {
+  pub major: u32,
+  pub minor: u32,
+}
+
+impl Display for ConfigVersion {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}.{}", self.major, self.minor)
+  }
+}
+
+trait ConfigVersionGetter {
+  fn version(&self) -> ConfigVersion;
+}
+
+#[derive(Deserialize, Serialize, Debug, Getters)]
+#[getset(get = "pub", get_mut = "pub", set = "pub")]
+pub struct BaseConfigFile {
+  version: ConfigVersion,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  protocols: Option<HashMap<String, ProtocolDefinition>>,
+}
+
+impl Default for BaseConfigFile {
+  fn default() -> Self {
+    Self {
+      version: get_internal_config_version(),
+      protocols: Some(HashMap::new()),
+    }
+  }
+}
+
+impl ConfigVersionGetter for BaseConfigFile {
+  fn version(&self) -> ConfigVersion {
+    self.version
+  }
+}
+
+impl BaseConfigFile {
+  pub fn new(major_version: u32, minor_version: u32) -> Self {
+    Self {
+      version: ConfigVersion {
+        major: major_version,
+        minor: minor_version,
+      },
+      protocols: None,
+    }
+  }
+}
+
+#[derive(Deserialize, Serialize, Debug, Getters)]
+#[getset(get = "pub", get_mut = "pub", set = "pub")]
+struct UserConfigFile {
+  version: ConfigVersion,
+  #[serde(rename = "user-configs", default)]
+  user_configs: Option<UserConfigDefinition>,
+}
+
+impl Default for UserConfigFile {
+  fn default() -> Self {
+    Self {
+      version: get_internal_config_version(),
+      user_configs: Some(UserConfigDefinition::default()),
+    }
+  }
+}
+
+impl ConfigVersionGetter for UserConfigFile {
+  fn version(&self) -> ConfigVersion {
+    self.version
+  }
+}
+
+impl UserConfigFile {
+  pub fn new(major_version: u32, minor_version: u32) -> Self {
+    Self {
+      version: ConfigVersion {
+        major: major_version,
+        minor: minor_version,
+      },
+      user_configs: None,
+    }
+  }
+
+  pub fn to_json(&self) -> String {
+    serde_json::to_string(self)
+      .expect("All types below this are Serialize, so this should be infallible.")
+  }
+}
+
+fn get_internal_config_version() -> ConfigVersion {
+  static INTERNAL_CONFIG_VERSION: Lazy<ConfigVersion> = Lazy::new(|| {
+    let config: BaseConfigFile = serde_json::from_str(DEVICE_CONFIGURATION_JSON)
+      .expect("If this fails, the whole library goes with it.");
+    config.version
+  });
+  *INTERNAL_CONFIG_VERSION
+}
+
+/// The base device configuration that user configurations are layered on top of.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum BaseConfig {
+  /// The device configuration embedded in the library.
+  #[default]
+  Embedded,
+  /// No base configuration. Only protocols from the user config (or added later) exist.
+  None,
+  /// A custom main configuration, which replaces the embedded one entirely.
+  Custom(String),
+}
+
+impl From<&Option<String>> for BaseConfig {
+  fn from(main_config_str: &Option<String>) -> Self {
+    match main_config_str {
+      Some(config) => BaseConfig::Custom(config.clone()),
+      None => BaseConfig::Embedded,
+    }
+  }
+}
+
+/// Validates a config string, keeping the location of every schema failure.
+fn validate_config(validator: &JSONValidator, config_str: &str) -> Result<(), ConfigurationError> {
+  let value: serde_json::Value =
+    serde_json::from_str(config_str).map_err(|err| ConfigurationError::SchemaViolation {
+      details: format!("Configuration is not valid JSON: {}", err),
+      errors: vec![SchemaValidationError {
+        pointer: String::new(),
+        message: err.to_string(),
+      }],
+    })?;
+  validator
+    .validate_value(&value)
+    .map_err(|errors| ConfigurationError::SchemaViolation {
+      details: format!(
+        "Error during JSON Schema Validation: {}",
+        errors
+          .iter()
+          .map(|e| e.to_string())
+          .collect::<Vec<String>>()
+          .join(", ")
+      ),
+      errors,
+    })
+}
+
+/// True if a config looks like a base config, i.e. it has protocol definitions. Anything else is
+/// treated as a user config.
+pub(crate) fn is_base_config(config: &serde_json::Value) -> bool {
+  config.get("protocols").is_some()
+}
+
+/// Schema failures for a base or user config, checked against the schema for whichever kind of
+/// file it looks like.
+pub(crate) fn config_schema_violations(config: &serde_json::Value) -> Vec<SchemaValidationError> {
+  let validator = if is_base_config(config) {
+    &CONFIG_VALIDATOR
+  } else {
+    &USER_CONFIG_VALIDATOR
+  };
+  validator.validate_value(config).err().unwrap_or_default()
+}
+
+/// Every section a base or user config can have, for [ignored_config_fields].
+#[derive(Deserialize)]
+struct AnyConfigFile {
+  #[serde(rename = "version")]
+  _version: ConfigVersion,
+  #[serde(rename = "protocols", default)]
+  _protocols: Option<HashMap<String, ProtocolDefinition>>,
+  #[serde(rename = "user-configs", default)]
+  _user_configs: Option<UserConfigDefinition>,
+}
+
+/// JSON pointers of every field in a base or user config that loading it would ignore (e.g.
+/// misspelled keys), in the order they appear.
+pub(crate) fn ignored_config_fields(
+  config: &serde_json::Value,
+  config_str: &str,
+) -> Result<Vec<String>, serde_json::Error> {
+  fn path_segments(path: &serde_ignored::Path, segments: &mut Vec<String>) {
+    match path {
+      serde_ignored::Path::Root => {}
+      serde_ignored::Path::Seq { parent, index } => {
+        path_segments(parent, segments);
+        segments.push(index.to_string());
+      }
+      serde_ignored::Path::Map { parent, key } => {
+        path_segments(parent, segments);
+        segments.push(key.clone());
+      }
+      serde_ignored::Path::Some { parent }
+      | serde_ignored::Path::NewtypeStruct { parent }
+      | serde_ignored::Path::NewtypeVariant { parent } => path_segments(parent, segments),
+    }
+  }
+  // serde_ignored paths leave out enum variant names, which are keys in the JSON (e.g. the `btle`
+  // in a communication specifier), so put them back by following the path through the config.
+  fn json_pointer(config: &serde_json::Value, segments: &[String]) -> String {
+    let mut pointer = String::new();
+    let mut value = config;
+    for segment in segments {
+      if let Some(object) = value.as_object() {
+        if let (false, 1, Some((variant, inner))) = (
+          object.contains_key(segment),
+          object.len(),
+          object.iter().next(),
+        ) {
+          pointer.push('/');
+          pointer.push_str(&variant.replace('~', "~0").replace('/', "~1"));
+          value = inner;
+        }
+      }
+      pointer.push('/');
+      pointer.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+      value = match value {
+        serde_json::Value::Object(object) => object.get(segment),
+        serde_json::Value::Array(array) => segment
+          .parse()
+          .ok()
+          .and_then(|index: usize| array.get(index)),
+        _ => None,
+      }
+      .unwrap_or(&serde_json::Value::Null);
+    }
+    pointer
+  }
+  let mut ignored = vec![];
+  let deserializer = &mut serde_json::Deserializer::from_str(config_str);
+  serde_ignored::deserialize::<_, _, AnyConfigFile>(deserializer, |path| {
+    let mut segments = vec![];
+    path_segments(&path, &mut segments);
+    ignored.push(json_pointer(config, &segments))
+  })?;
+  Ok(ignored)
+}
+
+fn load_protocol_config_from_json<'a, T>(
+  validator: &JSONValidator,
+  config_str: &'a str,
+  expected_version: ConfigVersion,
+  skip_version_check: bool,
+) -> Result<T, ButtplugDeviceError>
+where
+  T: ConfigVersionGetter + Deserialize<'a>,
+{
+  match validate_config(validator, config_str) {
+    Ok(_) => match serde_json::from_str::<T>(config_str) {
+      Ok(protocol_config) => {
+        if !skip_version_check && protocol_config.version().major != expected_version.major {
+          Err(
+            ConfigurationError::VersionMismatch {
+              file: protocol_config.version().to_string(),
+              internal: expected_version.to_string(),
+            }
+            .into(),
+          )
+        } else {
+          Ok(protocol_config)
+        }
+      }
+      Err(err) => Err(
+        ConfigurationError::SerdeError {
+          message: err.to_string(),
+        }
+        .into(),
+      ),
+    },
+    Err(err) => Err(err.into()),
+  }
+}
+
+/// Loads the base config, returning the builder, the version of the config that was loaded (if
+/// any), and how many protocols it defined.
+fn load_main_config(
+  base_config: &BaseConfig,
+  skip_version_check: bool,
+) -> Result<
+  (
+    DeviceConfigurationManagerBuilder,
+    Option<ConfigVersion>,
+    usize,
+  ),
+  ButtplugDeviceError,
+> {
+  let mut dcm_builder = DeviceConfigurationManagerBuilder::default();
+  let internal_config_version = get_internal_config_version();
+
+  let main_config_str = match base_config {
+    BaseConfig::Embedded => {
+      info!("Loading from internal base device configuration...");
+      DEVICE_CONFIGURATION_JSON
+    }
+    BaseConfig::Custom(config) => {
+      info!("Loading from custom base device configuration...");
+      config
+    }
+    BaseConfig::None => {
+      info!("No base device configuration, starting with no protocols.");
+      return Ok((dcm_builder, None, 0));
+    }
+  };
+  // Start by loading the main config
+  let main_config = load_protocol_config_from_json::<BaseConfigFile>(
+    &CONFIG_VALIDATOR,
+    main_config_str,
+    internal_config_version,
+    skip_version_check,
+  )?;
+  let main_config_version = main_config.version;
+  let protocols = main_config.protocols.unwrap_or_default();
+  let protocol_count = protocols.len();
+
+  // Each protocol will need to become a ProtocolDeviceConfiguration, so we'll need to
+  //
+  // - take the specifiers from both the main and user configs and make a vector out of them
+  // - for each configuration and user config, we'll need to create message lists and figure out
+  //   what to do with allow/deny/index.
+  for (protocol_name, protocol_def) in protocols {
+    add_protocol_definition(&mut dcm_builder, &protocol_name, protocol_def)?;
+  }
+
+  Ok((dcm_builder, Some(main_config_version), protocol_count))
+}
+
+fn add_protocol_definition(
+  dcm_builder: &mut DeviceConfigurationManagerBuilder,
+  protocol_name: &str,
+  protocol_def: ProtocolDefinition,
+) -> Result<(), ConfigurationError> {
+  let initialize_timeout_ms = protocol_def.initialize_timeout_ms;
+  let protocol_device_config =
+    ProtocolDeviceConfiguration::from_definition(protocol_name, protocol_def)?;
+  dcm_builder.communication_specifier(protocol_name, protocol_device_config.specifiers());
+  if let Some(timeout_ms) = initialize_timeout_ms {
+    dcm_builder
+      .protocol_initialize_timeout(protocol_name, Duration::from_millis(timeout_ms as u64));
+  }
+  for (config_ident, config) in protocol_device_config.configurations() {
+    let ident = BaseDeviceIdentifier::new(protocol_name, config_ident);
+    dcm_builder.protocol_features(&ident, config);
+  }
+  Ok(())
+}
+
+/// Adds a single protocol definition to a builder, replacing any existing definition of a protocol
+/// with the same name. The fragment is the value of an entry in the `protocols` section of a
+/// config file (communication, defaults and configurations), so experimental protocols can be
+/// shipped without a whole config file.
+pub fn add_protocol_definition_from_json(
+  dcm_builder: &mut DeviceConfigurationManagerBuilder,
+  protocol_name: &str,
+  fragment: &str,
+) -> Result<(), ButtplugDeviceError> {
+  let invalid_definition = |error| ConfigurationError::InvalidProtocolDefinition {
+    protocol: protocol_name.to_owned(),
+    error: Box::new(error),
+  };
+  validate_config(&PROTOCOL_DEFINITION_VALIDATOR, fragment).map_err(invalid_definition)?;
+  let protocol_def = serde_json::from_str::<ProtocolDefinition>(fragment).map_err(|err| {
+    invalid_definition(ConfigurationError::SerdeError {
+      message: err.to_string(),
+    })
+  })?;
+  // Resolve the definition before touching the builder, so a broken fragment leaves the existing
+  // definition in place.
+  ProtocolDeviceConfiguration::from_definition(protocol_name, protocol_def.clone())
+    .map_err(invalid_definition)?;
+  dcm_builder.remove_protocol_definitions(protocol_name);
+  add_protocol_definition(dcm_builder, protocol_name, protocol_def).map_err(invalid_definition)?;
+  Ok(())
+}
+
+/// Loads a user config into the builder, returning the number of user device definitions loaded.
+fn load_user_config(
+  user_config_str: &str,
+  base_config_version: ConfigVersion,
+  skip_version_check: bool,
+  dcm_builder: &mut DeviceConfigurationManagerBuilder,
+) -> Result<usize, ButtplugDeviceError> {
+  info!("Loading user configuration from string.");
+  let user_config_file = load_protocol_config_from_json::<UserConfigFile>(
+    &USER_CONFIG_VALIDATOR,
+    user_config_str,
+    base_config_version,
+    skip_version_check,
+  )?;
+
+  if user_config_file.user_configs.is_none() {
+    info!("No user configurations provided in user config.");
+    return Ok(0);
+  }
+
+  let user_config = user_config_file
+    .user_configs
+    .expect("Just checked validity");
+
+  for (protocol, specifier) in user_config.protocols.unwrap_or_default() {
+    if let Some(comm_specifiers) = specifier.communication() {
+      dcm_builder.user_communication_specifier(&protocol, comm_specifiers);
+    }
+    if let Some(display_name) = specifier.display_name() {
+      dcm_builder.user_protocol_display_name(&protocol, display_name);
+    }
+  }
+
+  for protocol in user_config.disabled_protocols.unwrap_or_default() {
+    dcm_builder.disable_protocol(&protocol);
+  }
+
+  let mut user_device_configs = user_config.user_device_configs.unwrap_or_default();
+
+  for user_device_config_pair in &user_device_configs {
+    check_device_access(
+      user_device_config_pair.identifier(),
+      user_device_config_pair.config(),
+    )?;
+  }
+
+  // Reserved indexes are how devices keep their index across sessions, so two devices sharing one
+  // would end up stomping on each other when connected.
+  let mut reserved_indexes: HashMap<u32, Vec<String>> = HashMap::new();
+  for user_device_config_pair in user_device_configs
+    .iter()
+    .filter(|pair| !pair.config().user_config().auto_index())
+  {
+    reserved_indexes
+      .entry(user_device_config_pair.config().user_config().index())
+      .or_default()
+      .push(user_device_config_pair.identifier().to_string());
+  }
+  if let Some((index, devices)) = reserved_indexes
+    .iter()
+    .find(|(_, devices)| devices.len() > 1)
+  {
+    return Err(
+      ConfigurationError::DuplicateReservedIndex {
+        index: *index,
+        devices: devices.clone(),
+      }
+      .into(),
+    );
+  }
+
+  // Indexes the library assigned in earlier sessions can end up colliding with reservations the
+  // user made afterwards. The user's reservation wins, the assigned device gets moved.
+  let mut used_indexes: HashSet<u32> = reserved_indexes.into_keys().collect();
+  let mut colliding_configs = vec![];
+  for (position, user_device_config_pair) in user_device_configs.iter().enumerate() {
+    let user_config = user_device_config_pair.config().user_config();
+    if user_config.auto_index() && !used_indexes.insert(user_config.index()) {
+      colliding_configs.push(position);
+    }
+  }
+  for position in colliding_configs {
+    let mut index = 0;
+    while used_indexes.contains(&index) {
+      index += 1;
+    }
+    used_indexes.insert(index);
+    let user_device_config_pair = &mut user_device_configs[position];
+    warn!(
+      "Assigned index {} for device {} is reserved by another device, moving to index {}.",
+      user_device_config_pair.config().user_config().index(),
+      user_device_config_pair.identifier(),
+      index
+    );
+    user_device_config_pair
+      .config_mut()
+      .user_config_mut()
+      .set_index(index);
+  }
+
+  let user_device_override_count = user_device_configs.len();
+  for user_device_config_pair in user_device_configs {
+    dcm_builder.user_protocol_features(
+      user_device_config_pair.identifier(),
+      user_device_config_pair.config(),
+    );
+  }
+
+  Ok(user_device_override_count)
+}
+
+pub fn load_protocol_configs(
+  main_config_str: &Option<String>,
+  user_config_str: &Option<String>,
+  skip_version_check: bool,
+) -> Result<DeviceConfigurationManagerBuilder, ButtplugDeviceError> {
+  load_protocol_configs_with_base(&main_config_str.into(), user_config_str, skip_version_check)
+}
+
+/// Load protocol configurations on top of the given base configuration. User configs are checked
+/// against the version of the base config they're layered on.
+pub fn load_protocol_configs_with_base(
+  base_config: &BaseConfig,
+  user_config_str: &Option<String>,
+  skip_version_check: bool,
+) -> Result<DeviceConfigurationManagerBuilder, ButtplugDeviceError> {
+  let (mut dcm_builder, main_config_version, protocol_count) =
+    load_main_config(base_config, skip_version_check)?;
+  let internal_config_version = get_internal_config_version();
+
+  let user_device_override_count = if let Some(config_str) = user_config_str {
+    load_user_config(
+      config_str,
+      main_config_version.unwrap_or(internal_config_version),
+      skip_version_check,
+      &mut dcm_builder,
+    )?
+  } else {
+    info!("No user configuration provided.");
+    0
+  };
+
+  dcm_builder.config_info(&ServerDeviceConfigInfo::new(
+    &internal_config_version.to_string(),
+    main_config_version.map(|version| version.to_string()),
+    user_config_str.is_some(),
+    protocol_count,
+    user_device_override_count,
+  ));
+  Ok(dcm_builder)
+}
+
+/// Same as [load_protocol_configs_with_base], also running [validate_external_config] over the
+/// custom base config and the user config. Lints are logged and returned with the builder. With
+/// `deny_lint_errors`, any error lint fails the load with [ConfigurationError::LintErrors] before
+/// anything is loaded.
+pub fn load_protocol_configs_with_lints(
+  base_config: &BaseConfig,
+  user_config_str: &Option<String>,
+  skip_version_check: bool,
+  deny_lint_errors: bool,
+) -> Result<(DeviceConfigurationManagerBuilder, Vec<ConfigLint>), ButtplugDeviceError> {
+  let mut lints = vec![];
+  if let BaseConfig::Custom(config_str) = base_config {
+    lints.extend(validate_external_config(config_str));
+  }
+  if let Some(config_str) = user_config_str {
+    lints.extend(validate_external_config(config_str));
+  }
+  for lint in &lints {
+    match lint.severity() {
+      ConfigLintSeverity::Warning => warn!("Device configuration lint: {}", lint),
+      ConfigLintSeverity::Error => error!("Device configuration lint: {}", lint),
+    }
+  }
+  if deny_lint_errors
+    && lints
+      .iter()
+      .any(|lint| lint.severity() == ConfigLintSeverity::Error)
+  {
+    return Err(
+      ConfigurationError::LintErrors {
+        lints: lints
+          .iter()
+          .filter(|lint| lint.severity() == ConfigLintSeverity::Error)
+          .map(|lint| lint.to_string())
+          .collect(),
+      }
+      .into(),
+    );
+  }
+  let dcm_builder =
+    load_protocol_configs_with_base(base_config, user_config_str, skip_version_check)?;
+  Ok((dcm_builder, lints))
+}
+
+/// Reads a config file, off of the async worker threads. Returns None if the file doesn't exist.
+async fn read_config_file(path: &Path) -> Result<Option<String>, ButtplugError> {
+  let path = path.to_owned();
+  async_manager::spawn_blocking(move || read_config_file_blocking(&path)).await
+}
+
+fn read_config_file_blocking(path: &Path) -> Result<Option<String>, ButtplugError> {
+  match std::fs::read(path) {
+    Ok(bytes) => String::from_utf8(bytes).map(Some).map_err(|e| {
+      ButtplugDeviceError::from(ConfigurationError::InvalidUtf8 {
+        path: path.display().to_string(),
+        message: e.to_string(),
+      })
+      .into()
+    }),
+    Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+    Err(e) => Err(
+      ButtplugDeviceError::from(ConfigurationError::ReadError {
+        path: path.display().to_string(),
+        message: e.to_string(),
+      })
+      .into(),
+    ),
+  }
+}
+
+/// Load protocol configurations from files.
+///
+/// If there is no main config file (either no path was given or the file doesn't exist), the
+/// embedded device configuration is used. A missing user config file just means no user
+/// configuration.
+pub async fn load_protocol_configs_from_files(
+  main_config_path: Option<PathBuf>,
+  user_config_path: Option<PathBuf>,
+  skip_version_check: bool,
+) -> Result<DeviceConfigurationManagerBuilder, ButtplugError> {
+  let main_config_str = if let Some(path) = &main_config_path {
+    let config = read_config_file(path).await?;
+    if config.is_none() {
+      warn!(
+        "Device configuration file {} not found, using internal configuration.",
+        path.display()
+      );
+    }
+    config
+  } else {
+    None
+  };
+  let user_config_str = if let Some(path) = &user_config_path {
+    let config = read_config_file(path).await?;
+    if config.is_none() {
+      info!("User configuration file {} not found.", path.display());
+    }
+    config
+  } else {
+    None
+  };
+  load_protocol_configs(&main_config_str, &user_config_str, skip_version_check).map_err(|e| {
+    let paths: Vec<String> = [&main_config_path, &user_config_path]
+      .into_iter()
+      .flatten()
+      .map(|path| path.display().to_string())
+      .collect();
+    match e {
+      ButtplugDeviceError::ConfigurationError(error) if !paths.is_empty() => {
+        ButtplugDeviceError::from(ConfigurationError::FileLoadError {
+          paths: paths.join(", "),
+          error: Box::new(error),
+        })
+        .into()
+      }
+      e => e.into(),
+    }
+  })
+}
+
+// How long the user config file needs to stay unchanged before we reload it. Editors and other
+// tools tend to write files in multiple steps.
+const CONFIG_WATCH_DEBOUNCE: Duration = Duration::from_millis(100);
+
+fn config_file_state(path: &Path) -> Option<(SystemTime, u64)> {
+  let metadata = std::fs::metadata(path).ok()?;
+  Some((metadata.modified().ok()?, metadata.len()))
+}
+
+async fn poll_config_file_state(path: &Path) -> Option<(SystemTime, u64)> {
+  let path = path.to_owned();
+  async_manager::spawn_blocking(move || config_file_state(&path)).await
+}
+
+/// Watch the user config file for changes, yielding a freshly loaded configuration every time it
+/// changes on disk (including being created or removed). The file is checked every
+/// `poll_interval`, and changes are debounced. Errors are yielded but don't end the stream, so a
+/// bad edit can be fixed without restarting the watch.
+pub fn watch_protocol_configs_from_files(
+  main_config_path: Option<PathBuf>,
+  user_config_path: PathBuf,
+  skip_version_check: bool,
+  poll_interval: Duration,
+) -> impl Stream<Item = Result<DeviceConfigurationManagerBuilder, ButtplugError>> {
+  // Grab the current state now rather than on first poll, so changes made before the stream is
+  // polled aren't missed.
+  let mut last_state = config_file_state(&user_config_path);
+  stream! {
+    loop {
+      crate::util::sleep(poll_interval).await;
+      let mut state = poll_config_file_state(&user_config_path).await;
+      if state == last_state {
+        continue;
+      }
+      loop {
+        crate::util::sleep(CONFIG_WATCH_DEBOUNCE).await;
+        let next_state = poll_config_file_state(&user_config_path).await;
+        if next_state == state {
+          break;
+        }
+        state = next_state;
+      }
+      last_state = state;
+      info!(
+        "User configuration file {} changed, reloading.",
+        user_config_path.display()
+      );
+      yield load_protocol_configs_from_files(
+        main_config_path.clone(),
+        Some(user_config_path.clone()),
+        skip_version_check,
+      )
+      .await;
+    }
+  }
+}
+
+fn user_config_file(dcm: &DeviceConfigurationManager) -> UserConfigFile {
+  let user_specifiers = dcm.user_communication_specifiers();
+  let user_definitions_vec = dcm
+    .user_device_definitions()
+    .iter()
+    .map(|kv| UserDeviceConfigPair {
+      identifier: kv.key().clone(),
+      config: kv.value().clone(),
+    })
+    .collect();
+  let user_protos = DashMap::new();
+  for spec in user_specifiers {
+    user_protos.insert(
+      spec.key().clone(),
+      ProtocolDefinition {
+        communication: Some(spec.value().clone()),
+        ..Default::default()
+      },
+    );
+  }
+  for display_name in dcm.user_protocol_display_names() {
+    user_protos
+      .entry(display_name.key().clone())
+      .or_default()
+      .display_name = Some(display_name.value().clone());
+  }
+  let user_config_definition = UserConfigDefinition {
+    protocols: Some(user_protos.clone()),
+    user_device_configs: Some(user_definitions_vec),
+    disabled_protocols: Some(dcm.disabled_protocols()).filter(|protocols| !protocols.is_empty()),
+  };
+  let mut user_config_file = UserConfigFile::new(3, 0);
+  user_config_file.user_configs = Some(user_config_definition);
+  user_config_file
+}
+
+pub fn save_user_config(dcm: &DeviceConfigurationManager) -> Result<String, ButtplugError> {
+  serde_json::to_string(&user_config_file(dcm)).map_err(|e| {
+    ButtplugError::from(ButtplugDeviceError::DeviceConfigurationError(format!(
+      "Cannot save device configuration file: {e:?}",
+    )))
+  })
+}
+
+/// User config file holding only the config of a single device, in the same format as
+/// [save_user_config], for callers that persist changes one device at a time.
+pub fn user_device_config_fragment(
+  identifier: &UserDeviceIdentifier,
+  definition: &UserDeviceDefinition,
+) -> Result<String, ButtplugError> {
+  let mut user_config_file = UserConfigFile::new(3, 0);
+  user_config_file.user_configs = Some(UserConfigDefinition {
+    user_device_configs: Some(vec![UserDeviceConfigPair {
+      identifier: identifier.clone(),
+      config: definition.clone(),
+    }]),
+    ..Default::default()
+  });
+  serde_json::to_string(&user_config_file).map_err(|e| {
+    ButtplugError::from(ButtplugDeviceError::DeviceConfigurationError(format!(
+      "Cannot save device configuration fragment: {e:?}",
+    )))
+  })
+}
+
+/// Device settings as exported by Intiface Central.
+#[derive(Deserialize, Debug)]
+struct IntifaceDeviceSettingsFile {
+  #[serde(rename = "deviceSettings", default)]
+  device_settings: Vec<IntifaceDeviceSetting>,
+  #[serde(flatten)]
+  unsupported: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct IntifaceDeviceSetting {
+  protocol: String,
+  #[serde(default)]
+  identifier: Option<String>,
+  address: String,
+  #[serde(rename = "displayName", default)]
+  display_name: Option<String>,
+  #[serde(default)]
+  enabled: Option<bool>,
+  #[serde(default)]
+  index: Option<u32>,
+  #[serde(flatten)]
+  unsupported: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A user config converted from another format, in the same format as the user device config file.
+#[derive(Debug)]
+pub struct ImportedUserConfig {
+  user_config: UserConfigFile,
+  warnings: Vec<String>,
+}
+
+impl ImportedUserConfig {
+  /// The converted user config, to be loaded like any other user config.
+  pub fn to_json(&self) -> String {
+    self.user_config.to_json()
+  }
+
+  /// Settings that were skipped or changed because the user config can't represent them.
+  pub fn warnings(&self) -> &[String] {
+    &self.warnings
+  }
+}
+
+/// Convert device settings exported from Intiface Central into a user config, so users moving to
+/// an embedded server keep their display names, disabled devices and indexes. Devices are matched
+/// against the embedded base config for their features.
+pub fn import_intiface_device_settings(json: &str) -> Result<ImportedUserConfig, ButtplugError> {
+  import_intiface_device_settings_with_base(&BaseConfig::Embedded, json)
+}
+
+/// Same as [import_intiface_device_settings], with devices matched against the given base config.
+///
+/// Disabled devices are put on the deny list. Devices without a config in the base config are
+/// skipped, and devices whose index is already taken get a new one, with a warning for each.
+pub fn import_intiface_device_settings_with_base(
+  base_config: &BaseConfig,
+  json: &str,
+) -> Result<ImportedUserConfig, ButtplugError> {
+  let settings_file: IntifaceDeviceSettingsFile = serde_json::from_str(json).map_err(|err| {
+    ButtplugDeviceError::from(ConfigurationError::SerdeError {
+      message: err.to_string(),
+    })
+  })?;
+  let dcm = load_protocol_configs_with_base(base_config, &None, false)?.finish()?;
+  let mut warnings: Vec<String> = settings_file
+    .unsupported
+    .keys()
+    // The export format version doesn't change anything we import.
+    .filter(|key| *key != "version")
+    .map(|key| format!("Setting {} isn't supported, skipped.", key))
+    .collect();
+
+  // Indexes are reserved in the order devices are listed, later devices with the same index are
+  // assigned a new one.
+  let mut reserved_indexes = HashSet::new();
+  let mut seen_identifiers = HashSet::new();
+  let mut devices = vec![];
+  for setting in settings_file.device_settings {
+    let identifier =
+      UserDeviceIdentifier::new(&setting.address, &setting.protocol, &setting.identifier);
+    if !seen_identifiers.insert(identifier.clone()) {
+      warnings.push(format!(
+        "Device {} is listed more than once, skipped.",
+        identifier
+      ));
+      continue;
+    }
+    let definition = if let Some(definition) = dcm.device_definition(&identifier, &[]) {
+      definition
+    } else {
+      warnings.push(format!(
+        "Device {} has no device config, skipped.",
+        identifier
+      ));
+      continue;
+    };
+    for key in setting.unsupported.keys() {
+      warnings.push(format!(
+        "Device {}: setting {} isn't supported, skipped.",
+        identifier, key
+      ));
+    }
+    let index = match setting.index {
+      Some(index) if !reserved_indexes.insert(index) => {
+        warnings.push(format!(
+          "Device {}: index {} is already used by another device, assigning a new index.",
+          identifier, index
+        ));
+        None
+      }
+      index => index,
+    };
+    let display_name = setting.display_name.filter(|name| !name.is_empty());
+    devices.push((
+      identifier,
+      definition,
+      display_name,
+      !setting.enabled.unwrap_or(true),
+      index,
+    ));
+  }
+
+  let mut next_index = 0;
+  let user_device_configs = devices
+    .into_iter()
+    .map(|(identifier, mut definition, display_name, deny, index)| {
+      let customization = if let Some(index) = index {
+        UserDeviceCustomization::new(&display_name, false, deny, index)
+      } else {
+        while reserved_indexes.contains(&next_index) {
+          next_index += 1;
+        }
+        let mut customization =
+          UserDeviceCustomization::new(&display_name, false, deny, next_index);
+        customization.set_auto_index(true);
+        next_index += 1;
+        customization
+      };
+      definition.set_user_config(customization);
+      UserDeviceConfigPair {
+        identifier,
+        config: definition,
+      }
+    })
+    .collect();
+
+  for warning in &warnings {
+    warn!("Intiface device settings import: {}", warning);
+  }
+  Ok(ImportedUserConfig {
+    user_config: UserConfigFile {
+      version: get_internal_config_version(),
+      user_configs: Some(UserConfigDefinition {
+        protocols: None,
+        user_device_configs: Some(user_device_configs),
+        disabled_protocols: None,
+      }),
+    },
+    warnings,
+  })
+}
+
+// Order transports are grouped in when exporting a protocol's specifiers, matching the order of
+// the transports in the config schema.
+fn transport_order(specifier: &ProtocolCommunicationSpecifier) -> usize {
+  match specifier {
+    ProtocolCommunicationSpecifier::BluetoothLE(_) => 0,
+    ProtocolCommunicationSpecifier::Serial(_) => 1,
+    ProtocolCommunicationSpecifier::Websocket(_) => 2,
+    ProtocolCommunicationSpecifier::USB(_) => 3,
+    ProtocolCommunicationSpecifier::HID(_) => 4,
+    ProtocolCommunicationSpecifier::XInput(_) => 5,
+    ProtocolCommunicationSpecifier::LovenseConnectService(_) => 6,
+  }
+}
+
+impl ProtocolAttributes {
+  fn from_definition(identifier: Option<&str>, definition: &BaseDeviceDefinition) -> Self {
+    Self {
+      identifier: identifier.map(|identifier| vec![identifier.to_owned()]),
+      inherits: None,
+      name: Some(definition.name().clone()),
+      features: Some(definition.features().clone()),
+    }
+  }
+}
+
+/// The effective configuration of a [DeviceConfigurationManager]: base protocols, user protocol
+/// specifiers and user device definitions, as they were merged for the session.
+///
+/// Meant for support bundles. The export serializes to a single JSON document (see
+/// [ProtocolConfiguration::to_json]), and can be loaded back with [ProtocolConfiguration::load] to
+/// reproduce the session's configuration. Configuration inheritance is resolved on export, so
+/// every configuration is written out in full. Websocket tokens are redacted, so devices using
+/// them won't connect from a reloaded export.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProtocolConfiguration {
+  #[serde(rename = "base-config")]
+  base_config: BaseConfigFile,
+  #[serde(rename = "user-config")]
+  user_config: UserConfigFile,
+  /// Protocols with factories registered at runtime. Their definitions are exported, but a
+  /// reloaded configuration will drop them unless the same factories are registered again.
+  #[serde(
+    rename = "runtime-only-protocols",
+    default,
+    skip_serializing_if = "Vec::is_empty"
+  )]
+  runtime_only_protocols: Vec<String>,
+}
+
+impl ProtocolConfiguration {
+  /// Parse an exported configuration. Validation happens when the configuration is loaded.
+  pub fn from_json(config_str: &str) -> Result<Self, ButtplugDeviceError> {
+    serde_json::from_str(config_str).map_err(|err| {
+      ConfigurationError::SerdeError {
+        message: err.to_string(),
+      }
+      .into()
+    })
+  }
+
+  pub fn to_json(&self) -> String {
+    serde_json::to_string(self)
+      .expect("All types below this are Serialize, so this should be infallible.")
+  }
+
+  /// Parse an export encoded with [Self::to_cbor].
+  #[cfg(feature = "serialize-cbor")]
+  pub fn from_cbor(data: &[u8]) -> Result<Self, ButtplugDeviceError> {
+    ciborium::from_reader(data).map_err(|err| {
+      ConfigurationError::SerdeError {
+        message: err.to_string(),
+      }
+      .into()
+    })
+  }
+
+  /// Encode the export as CBOR, which is smaller and quicker to parse than JSON for processes that
+  /// pass configurations back and forth. Decodes to the same configuration as [Self::to_json].
+  #[cfg(feature = "serialize-cbor")]
+  pub fn to_cbor(&self) -> Vec<u8> {
+    let mut data = vec![];
+    ciborium::into_writer(self, &mut data)
+      .expect("All types below this are Serialize, so this should be infallible.");
+    data
+  }
+
+  /// The base part of the export, in the same format as the main device config file.
+  pub fn base_config_json(&self) -> String {
+    serde_json::to_string(&self.base_config)
+      .expect("All types below this are Serialize, so this should be infallible.")
+  }
+
+  /// The user part of the export, in the same format as the user device config file.
+  pub fn user_config_json(&self) -> String {
+    self.user_config.to_json()
+  }
+
+  pub fn runtime_only_protocols(&self) -> &[String] {
+    &self.runtime_only_protocols
+  }
+
+  /// Load the exported configuration, with the base part replacing the embedded device config.
+  pub fn load(
+    &self,
+    skip_version_check: bool,
+  ) -> Result<DeviceConfigurationManagerBuilder, ButtplugDeviceError> {
+    load_protocol_configs(
+      &Some(self.base_config_json()),
+      &Some(self.user_config_json()),
+      skip_version_check,
+    )
+  }
+}
+
+impl DeviceConfigurationManager {
+  /// Export the effective configuration of the manager. See [ProtocolConfiguration].
+  pub fn to_protocol_configuration(&self) -> ProtocolConfiguration {
+    let version = get_internal_config_version();
+    let mut protocols = HashMap::new();
+    for protocol in self.protocols() {
+      let mut specifiers = protocol.specifiers().to_vec();
+      specifiers.sort_by_key(transport_order);
+      specifiers
+        .iter_mut()
+        .for_each(ProtocolCommunicationSpecifier::redact);
+      protocols.insert(
+        protocol.name().to_owned(),
+        ProtocolDefinition {
+          communication: (!specifiers.is_empty()).then_some(specifiers),
+          defaults: protocol
+            .defaults()
+            .map(|defaults| ProtocolAttributes::from_definition(None, defaults)),
+          configurations: protocol
+            .configurations()
+            .iter()
+            .map(|(identifier, definition)| {
+              ProtocolAttributes::from_definition(Some(identifier), definition)
+            })
+            .collect(),
+          display_name: None,
+          initialize_timeout_ms: protocol
+            .initialize_timeout()
+            .map(|timeout| timeout.as_millis() as u32),
+        },
+      );
+    }
+    let mut user_config = user_config_file(self);
+    user_config.version = version;
+    if let Some(protocols) = user_config
+      .user_configs
+      .as_ref()
+      .and_then(|configs| configs.protocols.as_ref())
+    {
+      for mut protocol in protocols.iter_mut() {
+        protocol
+          .communication
+          .iter_mut()
+          .flatten()
+          .for_each(ProtocolCommunicationSpecifier::redact);
+      }
+    }
+    ProtocolConfiguration {
+      base_config: BaseConfigFile {
+        version,
+        protocols: Some(protocols),
+      },
+      user_config,
+      runtime_only_protocols: self.custom_protocol_names(),
+    }
+  }
+}
+
+/// What the devices of a protocol in the bundled device config can do, combined over the protocol
+/// defaults and every identifier specific configuration. See [protocol_capabilities].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Getters, CopyGetters)]
+pub struct ProtocolCapability {
+  #[getset(get = "pub")]
+  protocol: String,
+  /// Types of the actuator features, sorted by name.
+  #[getset(get = "pub")]
+  actuator_types: Vec<ActuatorType>,
+  /// Types of the sensor features, sorted by name.
+  #[getset(get = "pub")]
+  sensor_types: Vec<SensorType>,
+  /// True if any actuator feature takes ScalarCmd.
+  #[getset(get_copy = "pub")]
+  supports_scalar: bool,
+  /// True if any actuator feature takes LinearCmd.
+  #[getset(get_copy = "pub")]
+  supports_linear: bool,
+  /// True if any actuator feature takes RotateCmd.
+  #[getset(get_copy = "pub")]
+  supports_rotate: bool,
+  /// True if the protocol is found over a transport with endpoints (everything but XInput and the
+  /// Lovense Connect service), so its devices take raw messages when the server allows them.
+  #[getset(get_copy = "pub")]
+  supports_raw: bool,
+}
+
+impl ProtocolCapability {
+  fn new(protocol: &ProtocolView<'_>) -> Self {
+    let features = protocol
+      .defaults()
+      .into_iter()
+      .chain(
+        protocol
+          .configurations()
+          .iter()
+          .map(|(_, definition)| *definition),
+      )
+      .flat_map(|definition| definition.features());
+    let mut actuator_types = vec![];
+    let mut sensor_types = vec![];
+    let mut messages = HashSet::new();
+    for feature in features {
+      if let Some(actuator) = feature.actuator() {
+        if let Ok(actuator_type) = ActuatorType::try_from(*feature.feature_type()) {
+          if !actuator_types.contains(&actuator_type) {
+            actuator_types.push(actuator_type);
+          }
+        }
+        messages.extend(actuator.messages().iter().copied());
+      }
+      if feature.sensor().is_some() {
+        if let Ok(sensor_type) = SensorType::try_from(*feature.feature_type()) {
+          if !sensor_types.contains(&sensor_type) {
+            sensor_types.push(sensor_type);
+          }
+        }
+      }
+    }
+    actuator_types.sort_by_key(ToString::to_string);
+    sensor_types.sort_by_key(ToString::to_string);
+    Self {
+      protocol: protocol.name().to_owned(),
+      actuator_types,
+      sensor_types,
+      supports_scalar: messages.contains(&ButtplugActuatorFeatureMessageType::ScalarCmd),
+      supports_linear: messages.contains(&ButtplugActuatorFeatureMessageType::LinearCmd),
+      supports_rotate: messages.contains(&ButtplugActuatorFeatureMessageType::RotateCmd),
+      supports_raw: protocol.specifiers().iter().any(|specifier| {
+        !matches!(
+          specifier,
+          ProtocolCommunicationSpecifier::XInput(_)
+            | ProtocolCommunicationSpecifier::LovenseConnectService(_)
+        )
+      }),
+    }
+  }
+}
+
+/// Capabilities of every protocol in the bundled device config that this build implements, sorted
+/// by protocol name. Read from the embedded config, for generating device capability docs (e.g. in
+/// client libraries).
+pub fn protocol_capabilities() -> Vec<ProtocolCapability> {
+  let dcm = load_protocol_configs(&None, &None, false)
+    .and_then(|mut builder| builder.finish())
+    .expect("The bundled device config always loads.");
+  dcm
+    .protocols()
+    .map(|protocol| ProtocolCapability::new(&protocol))
+    .collect()
+}
@@ -0,0 +1,549 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Device config file checks for tools outside of the library, like a CLI checking a user config
+//! before it's handed to Intiface.
+//!
+//! - [validate] collects everything wrong with a config file at once.
+//! - [migrate] brings a config file up to the config version this build loads.
+//! - [render_effective] merges a main and user config the same way a server would.
+//!
+//! None of these need a server or a device manager, and all results are Serialize, so tools can
+//! print them as they are.
+
+use super::{
+  get_internal_config_version,
+  is_base_config,
+  load_protocol_configs,
+  load_protocol_configs_with_base,
+  BaseConfig,
+  ConfigVersion,
+};
+use crate::{
+  core::errors::{ButtplugDeviceError, ConfigurationError},
+  server::device::configuration::{DeviceConfigurationManager, UserDeviceIdentifier},
+  util::{
+    device_configuration_lint::{
+      validate_device_config_strict,
+      validate_external_config,
+      ConfigLint,
+      ConfigLintSeverity,
+    },
+    json::JSONValidator,
+  },
+};
+use getset::{CopyGetters, Getters};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+static DEVICE_CONFIGURATION_V2_JSON_SCHEMA: &str = include_str!(
+  "../../../buttplug-device-config/device-config-v2/buttplug-device-config-schema-v2.json"
+);
+static USER_CONFIG_V2_VALIDATOR: Lazy<JSONValidator> = Lazy::new(|| {
+  JSONValidator::new_for_properties(
+    DEVICE_CONFIGURATION_V2_JSON_SCHEMA,
+    &["version", "user-configs"],
+  )
+});
+
+/// Which kind of device config file a config is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ConfigFileKind {
+  /// A main config, with protocol definitions. Replaces the config embedded in the library.
+  Main,
+  /// A user config, layered on top of a main config.
+  User,
+}
+
+impl ConfigFileKind {
+  fn of(config: &Value) -> Self {
+    if is_base_config(config) {
+      ConfigFileKind::Main
+    } else {
+      ConfigFileKind::User
+    }
+  }
+}
+
+/// A single problem found by [validate].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Getters, CopyGetters)]
+pub struct ValidationIssue {
+  #[getset(get_copy = "pub")]
+  severity: ConfigLintSeverity,
+  /// JSON pointer to the problem, e.g. `/user-configs/devices/0/config/features/1`. Empty for
+  /// problems with the file as a whole.
+  #[getset(get = "pub")]
+  pointer: String,
+  #[getset(get = "pub")]
+  message: String,
+}
+
+impl ValidationIssue {
+  fn from_lint(lint: &ConfigLint) -> Self {
+    Self {
+      severity: lint.severity(),
+      pointer: lint_pointer(lint.location()),
+      message: lint.message().clone(),
+    }
+  }
+}
+
+/// Everything [validate] found in a config file.
+#[derive(Debug, Clone, Serialize, Getters, CopyGetters)]
+pub struct ValidationReport {
+  #[getset(get_copy = "pub")]
+  kind: ConfigFileKind,
+  /// Version the file claims to be, e.g. `3.0`, if it has one.
+  #[getset(get = "pub")]
+  version: Option<String>,
+  /// Problems in the order they were found: schema violations and ignored fields first, then lints.
+  #[getset(get = "pub")]
+  issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+  /// True if the config loads and nothing in it is broken. Warnings don't count.
+  pub fn valid(&self) -> bool {
+    !self
+      .issues
+      .iter()
+      .any(|issue| issue.severity() == ConfigLintSeverity::Error)
+  }
+}
+
+/// Check a main or user config file (as JSON) for every problem at once: schema violations, fields
+/// that loading would drop, the lints from [validate_external_config], and anything else that
+/// keeps the file from loading. User configs are loaded on top of the embedded main config.
+///
+/// Only fails if the config isn't JSON at all.
+pub fn validate(config_str: &str) -> Result<ValidationReport, ButtplugDeviceError> {
+  let config = parse_config(config_str)?;
+  let kind = ConfigFileKind::of(&config);
+  let mut issues: Vec<ValidationIssue> = vec![];
+  for lint in validate_device_config_strict(config_str)
+    .iter()
+    .chain(validate_external_config(config_str).iter())
+  {
+    let issue = ValidationIssue::from_lint(lint);
+    if !issues.contains(&issue) {
+      issues.push(issue);
+    }
+  }
+  // Anything found so far usually also breaks loading, and says where.
+  if issues.is_empty() {
+    if let Err(err) = check_loads(kind, config_str) {
+      issues.push(ValidationIssue {
+        severity: ConfigLintSeverity::Error,
+        pointer: error_pointer(&err).to_owned(),
+        message: err.to_string(),
+      });
+    }
+  }
+  Ok(ValidationReport {
+    kind,
+    version: config_version(&config).map(|version| version.to_string()),
+    issues,
+  })
+}
+
+/// A single change made by [migrate].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct MigrationStep {
+  /// JSON pointer to the part of the original config that was changed.
+  pointer: String,
+  description: String,
+}
+
+impl MigrationStep {
+  fn new(pointer: &str, description: String) -> Self {
+    Self {
+      pointer: pointer.to_owned(),
+      description,
+    }
+  }
+}
+
+/// A config migrated by [migrate].
+#[derive(Debug, Clone, Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct MigratedConfig {
+  from_version: String,
+  to_version: String,
+  /// Changes made, in order. Empty if the config was already up to date.
+  steps: Vec<MigrationStep>,
+  /// The migrated config, as pretty printed JSON.
+  config: String,
+}
+
+/// Bring a config file (as JSON) up to the config version of this build.
+///
+/// Configs of the current major version only get their version bumped. Version 2 user configs are
+/// rewritten: specifiers move to the protocols they belong to, and device configs get the name and
+/// features of the device from the embedded main config, with v2 step ranges applied as step
+/// limits (in feature order). Devices the embedded config has no definition for are dropped.
+/// Version 2 main configs can't be migrated, as main configs ship with the library.
+///
+/// The migrated config is checked to load before it's returned.
+pub fn migrate(config_str: &str) -> Result<MigratedConfig, ButtplugDeviceError> {
+  let mut config = parse_config(config_str)?;
+  let kind = ConfigFileKind::of(&config);
+  let version = config_version(&config).ok_or_else(|| ConfigurationError::SerdeError {
+    message: "Configuration has no valid version.".to_owned(),
+  })?;
+  let internal_version = get_internal_config_version();
+  let mut steps = vec![];
+  match version.major {
+    major if major == internal_version.major => {}
+    2 if kind == ConfigFileKind::User => migrate_v2_user_config(&mut config, &mut steps)
+      .map_err(|message| migration_error(version, message))?,
+    2 => {
+      return Err(
+        migration_error(
+          version,
+          "Main configs ship with the library, so only user configs are migrated.".to_owned(),
+        )
+        .into(),
+      )
+    }
+    _ => {
+      return Err(
+        ConfigurationError::VersionMismatch {
+          file: version.to_string(),
+          internal: internal_version.to_string(),
+        }
+        .into(),
+      )
+    }
+  }
+
+  // Newer minor versions load fine, so those are left alone.
+  let to_version =
+    if version.major != internal_version.major || version.minor < internal_version.minor {
+      config["version"] = json!(internal_version);
+      steps.push(MigrationStep::new(
+        "/version",
+        format!("Changed version {} to {}.", version, internal_version),
+      ));
+      internal_version
+    } else {
+      version
+    };
+  let migrated_str =
+    serde_json::to_string_pretty(&config).expect("Config is a JSON value, so this is infallible.");
+  check_loads(kind, &migrated_str).map_err(|err| migration_error(version, err.to_string()))?;
+  Ok(MigratedConfig {
+    from_version: version.to_string(),
+    to_version: to_version.to_string(),
+    steps,
+    config: migrated_str,
+  })
+}
+
+/// The configuration a server would end up with, rendered by [render_effective].
+#[derive(Debug, Clone, Serialize, Getters, CopyGetters)]
+pub struct EffectiveConfig {
+  /// Protocols in the merged config.
+  #[getset(get_copy = "pub")]
+  protocol_count: usize,
+  /// Protocol default and identifier specific device configurations, over all protocols.
+  #[getset(get_copy = "pub")]
+  device_configuration_count: usize,
+  /// Devices with a user config.
+  #[getset(get_copy = "pub")]
+  user_device_count: usize,
+  #[getset(get = "pub")]
+  disabled_protocols: Vec<String>,
+  /// Protocols the configs name that this build has no implementation of, so they're dropped.
+  #[getset(get = "pub")]
+  unimplemented_protocols: Vec<String>,
+  /// The merged config as pretty printed JSON, in the format of
+  /// [ProtocolConfiguration](super::ProtocolConfiguration). Keys are sorted, and so are user
+  /// devices, so renders of the same configs can be diffed.
+  #[getset(get = "pub")]
+  config: String,
+}
+
+/// Merge a main config (or the embedded one, if there's none) and an optional user config, the
+/// same way a server loading them would.
+pub fn render_effective(
+  main_config_str: Option<&str>,
+  user_config_str: Option<&str>,
+) -> Result<EffectiveConfig, ButtplugDeviceError> {
+  let dcm = load_protocol_configs(
+    &main_config_str.map(str::to_owned),
+    &user_config_str.map(str::to_owned),
+    false,
+  )?
+  .finish()?;
+  let mut config = serde_json::to_value(dcm.to_protocol_configuration())
+    .expect("All types below this are Serialize, so this should be infallible.");
+  if let Some(devices) = config
+    .pointer_mut("/user-config/user-configs/devices")
+    .and_then(Value::as_array_mut)
+  {
+    devices.sort_by_key(|device| device["identifier"].to_string());
+  }
+  Ok(EffectiveConfig {
+    protocol_count: dcm.protocols().count(),
+    device_configuration_count: dcm
+      .protocols()
+      .map(|protocol| usize::from(protocol.defaults().is_some()) + protocol.configurations().len())
+      .sum(),
+    user_device_count: dcm.user_device_definitions().len(),
+    disabled_protocols: dcm.disabled_protocols(),
+    unimplemented_protocols: dcm.unimplemented_protocols().clone(),
+    config: serde_json::to_string_pretty(&config)
+      .expect("Config is a JSON value, so this is infallible."),
+  })
+}
+
+fn parse_config(config_str: &str) -> Result<Value, ConfigurationError> {
+  serde_json::from_str(config_str).map_err(|err| ConfigurationError::SerdeError {
+    message: format!("Configuration is not valid JSON: {}", err),
+  })
+}
+
+fn config_version(config: &Value) -> Option<ConfigVersion> {
+  serde_json::from_value(config.get("version")?.clone()).ok()
+}
+
+fn migration_error(version: ConfigVersion, message: String) -> ConfigurationError {
+  ConfigurationError::MigrationFailed {
+    version: version.to_string(),
+    message,
+  }
+}
+
+/// Load a main config in place of the embedded one, or a user config on top of it.
+fn check_loads(kind: ConfigFileKind, config_str: &str) -> Result<(), ButtplugDeviceError> {
+  let (base_config, user_config_str) = match kind {
+    ConfigFileKind::Main => (BaseConfig::Custom(config_str.to_owned()), None),
+    ConfigFileKind::User => (BaseConfig::Embedded, Some(config_str.to_owned())),
+  };
+  load_protocol_configs_with_base(&base_config, &user_config_str, false)?.finish()?;
+  Ok(())
+}
+
+fn error_pointer(err: &ButtplugDeviceError) -> &'static str {
+  match err {
+    ButtplugDeviceError::ConfigurationError(ConfigurationError::VersionMismatch { .. }) => {
+      "/version"
+    }
+    _ => "",
+  }
+}
+
+/// Turn a [validate_external_config] location, like `protocols.lovense.defaults.features[0]`, into
+/// a JSON pointer. [validate_device_config_strict] locations already are pointers.
+fn lint_pointer(location: &str) -> String {
+  if location == "(root)" {
+    String::new()
+  } else if location.starts_with('/') {
+    location.to_owned()
+  } else {
+    location
+      .replace('[', ".")
+      .replace(']', "")
+      .split('.')
+      .map(|token| format!("/{}", token))
+      .collect()
+  }
+}
+
+/// Rewrite a v2 user config into the v3 format, in place. Returns a message for the migration
+/// error if the config can't be rewritten.
+fn migrate_v2_user_config(
+  config: &mut Value,
+  steps: &mut Vec<MigrationStep>,
+) -> Result<(), String> {
+  USER_CONFIG_V2_VALIDATOR
+    .validate_value(config)
+    .map_err(|errors| {
+      format!(
+        "Not a valid version 2 user config: {}",
+        errors
+          .iter()
+          .map(|e| e.to_string())
+          .collect::<Vec<String>>()
+          .join(", ")
+      )
+    })?;
+  let user_configs = if let Some(user_configs) = config
+    .get_mut("user-configs")
+    .and_then(Value::as_object_mut)
+  {
+    user_configs
+  } else {
+    return Ok(());
+  };
+
+  if let Some(Value::Object(specifiers)) = user_configs.remove("specifiers") {
+    let mut protocols = Map::new();
+    for (protocol, transports) in specifiers {
+      protocols.insert(
+        protocol.clone(),
+        json!({ "communication": v2_communication(&transports) }),
+      );
+      steps.push(MigrationStep::new(
+        &format!("/user-configs/specifiers/{}", protocol),
+        format!(
+          "Moved specifiers to /user-configs/protocols/{}/communication.",
+          protocol
+        ),
+      ));
+    }
+    user_configs.insert("protocols".to_owned(), Value::Object(protocols));
+  }
+
+  if let Some(Value::Array(devices)) = user_configs.remove("devices") {
+    let dcm = load_protocol_configs(&None, &None, false)
+      .and_then(|mut builder| builder.finish())
+      .map_err(|err| format!("Cannot load embedded device config: {}", err))?;
+    let mut migrated_devices = vec![];
+    for (index, device) in devices.into_iter().enumerate() {
+      let pointer = format!("/user-configs/devices/{}", index);
+      if let Some(device) = v2_device(&dcm, &device, &pointer, steps)? {
+        migrated_devices.push(device);
+      }
+    }
+    user_configs.insert("devices".to_owned(), Value::Array(migrated_devices));
+  }
+  Ok(())
+}
+
+/// v2 specifiers are keyed by transport, v3 has a list of single transport specifiers.
+fn v2_communication(transports: &Value) -> Vec<Value> {
+  let mut communication = vec![];
+  for (transport, specifier) in transports.as_object().into_iter().flatten() {
+    match transport.as_str() {
+      "websocket" => communication.extend(
+        specifier["names"]
+          .as_array()
+          .into_iter()
+          .flatten()
+          .map(|name| json!({ "websocket": { "name": name } })),
+      ),
+      "serial" => communication.extend(
+        specifier
+          .as_array()
+          .into_iter()
+          .flatten()
+          .map(|port| json!({ "serial": port })),
+      ),
+      "usb" | "hid" => communication.push(json!({ transport.as_str(): { "pairs": specifier } })),
+      _ => communication.push(json!({ transport.as_str(): specifier })),
+    }
+  }
+  communication
+}
+
+/// v2 device configs only hold the user's changes, v3 ones hold the whole device definition.
+/// Returns None if the embedded config doesn't know the device.
+fn v2_device(
+  dcm: &DeviceConfigurationManager,
+  device: &Value,
+  pointer: &str,
+  steps: &mut Vec<MigrationStep>,
+) -> Result<Option<Value>, String> {
+  let identifier: UserDeviceIdentifier = serde_json::from_value(device["identifier"].clone())
+    .map_err(|err| format!("Invalid device identifier at {}: {}", pointer, err))?;
+  let mut definition = if let Some(definition) = dcm.device_definition(&identifier, &[]) {
+    definition
+  } else {
+    steps.push(MigrationStep::new(
+      pointer,
+      format!(
+        "Dropped device {}, the embedded device config doesn't define it.",
+        identifier
+      ),
+    ));
+    return Ok(None);
+  };
+
+  let user_config = &device["config"];
+  let customization = definition.user_config_mut();
+  customization.set_allow(user_config["allow"].as_bool().unwrap_or_default());
+  customization.set_deny(user_config["deny"].as_bool().unwrap_or_default());
+  customization.set_display_name(user_config["display-name"].as_str().map(str::to_owned));
+  if let Some(index) = user_config["index"].as_u64() {
+    customization.set_index(index as u32);
+    customization.set_auto_index(false);
+  } else {
+    // With no index, the loader picks one that doesn't collide with the other devices.
+    customization.set_auto_index(true);
+  }
+  let mut definition = serde_json::to_value(definition)
+    .expect("All types below this are Serialize, so this should be infallible.");
+  for (message, attributes) in user_config["messages"].as_object().into_iter().flatten() {
+    apply_v2_step_ranges(
+      &mut definition,
+      message,
+      attributes,
+      &format!("{}/config/messages/{}", pointer, message),
+      steps,
+    );
+  }
+  steps.push(MigrationStep::new(
+    &format!("{}/config", pointer),
+    format!(
+      "Filled in name and features of {} from the embedded device config.",
+      identifier
+    ),
+  ));
+  Ok(Some(
+    json!({ "identifier": device["identifier"], "config": definition }),
+  ))
+}
+
+/// Apply the step ranges of a v2 message override to the features taking the message, in order.
+fn apply_v2_step_ranges(
+  definition: &mut Value,
+  message: &str,
+  attributes: &Value,
+  pointer: &str,
+  steps: &mut Vec<MigrationStep>,
+) {
+  // VibrateCmd is ScalarCmd for Vibrate features.
+  let (feature_message, feature_type) = match message {
+    "VibrateCmd" => ("ScalarCmd", Some("Vibrate")),
+    message => (message, None),
+  };
+  let mut actuators = definition["features"]
+    .as_array_mut()
+    .into_iter()
+    .flatten()
+    .enumerate()
+    .filter(|(_, feature)| {
+      feature_type.is_none_or(|feature_type| feature["feature-type"] == feature_type)
+        && feature["actuator"]["messages"]
+          .as_array()
+          .is_some_and(|messages| messages.iter().any(|m| m == feature_message))
+    });
+  for (index, attribute) in attributes.as_array().into_iter().flatten().enumerate() {
+    let attribute_pointer = format!("{}/{}", pointer, index);
+    if let Some((feature_index, feature)) = actuators.next() {
+      feature["actuator"]["step-limit"] = attribute["StepRange"].clone();
+      steps.push(MigrationStep::new(
+        &attribute_pointer,
+        format!(
+          "Set step limit of feature {} to {}.",
+          feature_index, attribute["StepRange"]
+        ),
+      ));
+    } else {
+      steps.push(MigrationStep::new(
+        &attribute_pointer,
+        format!(
+          "Dropped, the device has no more features taking {}.",
+          message
+        ),
+      ));
+    }
+  }
+}
@@ -0,0 +1,392 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Lints for hand edited device configuration files.
+//!
+//! Schema validation only checks that a config is shaped right. A config can pass it (or fail it
+//! with an error that's hard to pin to the mistake) and still leave a device with a control that
+//! does nothing, or no way to be found at all. These lints look for the usual culprits.
+//!
+//! [validate_device_config_strict] is stricter, and also flags fields that loading a config would
+//! silently drop, like a misspelled `messages`.
+
+use super::device_configuration::{config_schema_violations, ignored_config_fields};
+use crate::server::device::protocol::supported_protocols;
+use getset::{CopyGetters, Getters};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::fmt;
+
+static ACTUATOR_MESSAGES: [&str; 3] = ["ScalarCmd", "RotateCmd", "LinearCmd"];
+static SENSOR_MESSAGES: [&str; 2] = ["SensorReadCmd", "SensorSubscribeCmd"];
+
+/// How bad a [ConfigLint] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum ConfigLintSeverity {
+  /// The config works, but probably not the way it was meant to.
+  Warning,
+  /// Part of the config will fail to load, or load but never work.
+  Error,
+}
+
+impl fmt::Display for ConfigLintSeverity {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ConfigLintSeverity::Warning => write!(f, "warning"),
+      ConfigLintSeverity::Error => write!(f, "error"),
+    }
+  }
+}
+
+/// A single problem found by [validate_external_config].
+#[derive(Debug, Clone, PartialEq, Eq, Getters, CopyGetters)]
+pub struct ConfigLint {
+  #[getset(get_copy = "pub")]
+  severity: ConfigLintSeverity,
+  /// Where in the config the problem is, e.g. `protocols.lovense.defaults.features[0]`.
+  #[getset(get = "pub")]
+  location: String,
+  #[getset(get = "pub")]
+  message: String,
+}
+
+impl ConfigLint {
+  fn new(severity: ConfigLintSeverity, location: &str, message: String) -> Self {
+    Self {
+      severity,
+      location: location.to_owned(),
+      message,
+    }
+  }
+}
+
+impl fmt::Display for ConfigLint {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{} at {}: {}",
+      self.severity, self.location, self.message
+    )
+  }
+}
+
+/// Lint a device configuration file (base or user config, as JSON).
+///
+/// Checks that:
+///
+/// - Feature messages are message types that exist, and make sense for the feature type.
+/// - Protocols in the base config have both device attributes and communication specifiers.
+/// - Actuator features have at least one step.
+/// - Protocols and user device configs only reference protocols this build implements.
+///
+/// A config that isn't valid JSON gets a single error lint.
+pub fn validate_external_config(config_str: &str) -> Vec<ConfigLint> {
+  let mut lints = vec![];
+  let config: Value = match serde_json::from_str(config_str) {
+    Ok(config) => config,
+    Err(err) => {
+      lints.push(ConfigLint::new(
+        ConfigLintSeverity::Error,
+        "(root)",
+        format!("Configuration is not valid JSON: {}", err),
+      ));
+      return lints;
+    }
+  };
+  let implemented = supported_protocols();
+
+  if let Some(protocols) = config.get("protocols").and_then(Value::as_object) {
+    for (protocol, definition) in protocols {
+      let location = format!("protocols.{}", protocol);
+      lint_protocol_implemented(&mut lints, &implemented, &location, protocol);
+      lint_protocol_definition(&mut lints, &location, definition, true);
+    }
+  }
+
+  if let Some(user_configs) = config.get("user-configs").and_then(Value::as_object) {
+    if let Some(protocols) = user_configs.get("protocols").and_then(Value::as_object) {
+      for (protocol, definition) in protocols {
+        let location = format!("user-configs.protocols.{}", protocol);
+        lint_protocol_implemented(&mut lints, &implemented, &location, protocol);
+        // User configs usually only add specifiers to protocols from the base config, so only
+        // the features they do set get checked.
+        lint_protocol_definition(&mut lints, &location, definition, false);
+      }
+    }
+    if let Some(devices) = user_configs.get("devices").and_then(Value::as_array) {
+      for (index, device) in devices.iter().enumerate() {
+        let location = format!("user-configs.devices[{}]", index);
+        if let Some(protocol) = device
+          .pointer("/identifier/protocol")
+          .and_then(Value::as_str)
+        {
+          lint_protocol_implemented(
+            &mut lints,
+            &implemented,
+            &format!("{}.identifier", location),
+            protocol,
+          );
+        }
+        if let Some(features) = device.pointer("/config/features") {
+          lint_features(
+            &mut lints,
+            &format!("{}.config.features", location),
+            features,
+          );
+        }
+      }
+    }
+  }
+
+  lints
+}
+
+/// Strictly check a device configuration file (base or user config, as JSON), for config
+/// contributors and tooling checking configs before they ship.
+///
+/// Reports every schema violation, and every field that loading the config would ignore (unknown
+/// or misspelled keys, which the schema doesn't catch everywhere). Lint locations are JSON
+/// pointers, e.g. `/protocols/lovense/defaults/features/0/actuator/mesages`. Doesn't include the
+/// lints from [validate_external_config].
+///
+/// A config that isn't valid JSON, or that can't be loaded at all, gets a single error lint.
+pub fn validate_device_config_strict(config_str: &str) -> Vec<ConfigLint> {
+  let config: Value = match serde_json::from_str(config_str) {
+    Ok(config) => config,
+    Err(err) => {
+      return vec![ConfigLint::new(
+        ConfigLintSeverity::Error,
+        "(root)",
+        format!("Configuration is not valid JSON: {}", err),
+      )];
+    }
+  };
+  let pointer_location = |pointer: &str| {
+    if pointer.is_empty() {
+      "(root)".to_owned()
+    } else {
+      pointer.to_owned()
+    }
+  };
+
+  let mut lints: Vec<ConfigLint> = config_schema_violations(&config)
+    .into_iter()
+    .map(|error| {
+      ConfigLint::new(
+        ConfigLintSeverity::Error,
+        &pointer_location(&error.pointer),
+        error.message,
+      )
+    })
+    .collect();
+  match ignored_config_fields(&config, config_str) {
+    Ok(fields) => lints.extend(fields.iter().map(|pointer| {
+      ConfigLint::new(
+        ConfigLintSeverity::Error,
+        pointer,
+        "Field isn't part of the config format, so it's dropped when the config is loaded."
+          .to_owned(),
+      )
+    })),
+    // Schema violations usually explain why loading failed, and say where.
+    Err(err) if lints.is_empty() => lints.push(ConfigLint::new(
+      ConfigLintSeverity::Error,
+      "(root)",
+      format!("Configuration can't be loaded: {}", err),
+    )),
+    Err(_) => {}
+  }
+  lints
+}
+
+fn lint_protocol_implemented(
+  lints: &mut Vec<ConfigLint>,
+  implemented: &[&str],
+  location: &str,
+  protocol: &str,
+) {
+  if !implemented.contains(&protocol) {
+    lints.push(ConfigLint::new(
+      ConfigLintSeverity::Error,
+      location,
+      format!(
+        "Protocol {} has no implementation, so devices using it can never connect.",
+        protocol
+      ),
+    ));
+  }
+}
+
+fn lint_protocol_definition(
+  lints: &mut Vec<ConfigLint>,
+  location: &str,
+  definition: &Value,
+  check_completeness: bool,
+) {
+  let definition = if let Some(definition) = definition.as_object() {
+    definition
+  } else {
+    return;
+  };
+  let configurations = definition
+    .get("configurations")
+    .and_then(Value::as_array)
+    .map(|configurations| configurations.as_slice())
+    .unwrap_or_default();
+
+  if check_completeness {
+    let has_attributes = definition.contains_key("defaults") || !configurations.is_empty();
+    let has_specifiers = definition
+      .get("communication")
+      .and_then(Value::as_array)
+      .is_some_and(|specifiers| !specifiers.is_empty());
+    if has_attributes && !has_specifiers {
+      lints.push(ConfigLint::new(
+        ConfigLintSeverity::Warning,
+        location,
+        "Protocol has device attributes but no communication specifiers, so its devices can never be found.".to_owned(),
+      ));
+    } else if has_specifiers && !has_attributes {
+      lints.push(ConfigLint::new(
+        ConfigLintSeverity::Error,
+        location,
+        "Protocol has communication specifiers but no device attributes, so devices found with them can't be set up.".to_owned(),
+      ));
+    }
+  }
+
+  if let Some(features) = definition
+    .get("defaults")
+    .and_then(|defaults| defaults.get("features"))
+  {
+    lint_features(lints, &format!("{}.defaults.features", location), features);
+  }
+  for (index, configuration) in configurations.iter().enumerate() {
+    if let Some(features) = configuration.get("features") {
+      lint_features(
+        lints,
+        &format!("{}.configurations[{}].features", location, index),
+        features,
+      );
+    }
+  }
+}
+
+fn lint_features(lints: &mut Vec<ConfigLint>, location: &str, features: &Value) {
+  for (index, feature) in features.as_array().into_iter().flatten().enumerate() {
+    if let Some(feature) = feature.as_object() {
+      lint_feature(lints, &format!("{}[{}]", location, index), feature);
+    }
+  }
+}
+
+fn lint_feature(lints: &mut Vec<ConfigLint>, location: &str, feature: &Map<String, Value>) {
+  let feature_type = feature
+    .get("feature-type")
+    .and_then(Value::as_str)
+    .unwrap_or_default();
+
+  if let Some(actuator) = feature.get("actuator") {
+    let messages_location = format!("{}.actuator.messages", location);
+    for (index, message) in messages(actuator) {
+      let message_location = format!("{}[{}]", messages_location, index);
+      if !ACTUATOR_MESSAGES.contains(&message) {
+        lint_unknown_message(lints, &message_location, message, &ACTUATOR_MESSAGES);
+      } else if message == "RotateCmd" && feature_type != "Rotate" {
+        lints.push(ConfigLint::new(
+          ConfigLintSeverity::Warning,
+          &message_location,
+          format!(
+            "RotateCmd is only handled for Rotate features, not {}.",
+            feature_type
+          ),
+        ));
+      } else if message == "LinearCmd" && feature_type != "Position" {
+        lints.push(ConfigLint::new(
+          ConfigLintSeverity::Warning,
+          &message_location,
+          format!(
+            "LinearCmd is only handled for Position features, not {}.",
+            feature_type
+          ),
+        ));
+      }
+    }
+
+    // The step count clients see comes from the step limit, if there is one.
+    let (range_name, range) = if let Some(limit) = actuator.get("step-limit") {
+      ("step-limit", limit)
+    } else {
+      (
+        "step-range",
+        actuator.get("step-range").unwrap_or(&Value::Null),
+      )
+    };
+    if let (Some(start), Some(end)) = (
+      range.get(0).and_then(Value::as_u64),
+      range.get(1).and_then(Value::as_u64),
+    ) {
+      if end <= start {
+        lints.push(ConfigLint::new(
+          ConfigLintSeverity::Error,
+          &format!("{}.actuator.{}", location, range_name),
+          format!(
+            "Feature has a StepCount of 0 ({} [{}, {}]), so it can't be controlled.",
+            range_name, start, end
+          ),
+        ));
+      }
+    }
+  }
+
+  if let Some(sensor) = feature.get("sensor") {
+    let messages_location = format!("{}.sensor.messages", location);
+    for (index, message) in messages(sensor) {
+      if !SENSOR_MESSAGES.contains(&message) {
+        lint_unknown_message(
+          lints,
+          &format!("{}[{}]", messages_location, index),
+          message,
+          &SENSOR_MESSAGES,
+        );
+      }
+    }
+  }
+}
+
+fn messages(section: &Value) -> impl Iterator<Item = (usize, &str)> {
+  section
+    .get("messages")
+    .and_then(Value::as_array)
+    .into_iter()
+    .flatten()
+    .enumerate()
+    .map(|(index, message)| (index, message.as_str().unwrap_or_default()))
+}
+
+fn lint_unknown_message(
+  lints: &mut Vec<ConfigLint>,
+  location: &str,
+  message: &str,
+  expected: &[&str],
+) {
+  let known = ACTUATOR_MESSAGES.contains(&message) || SENSOR_MESSAGES.contains(&message);
+  lints.push(ConfigLint::new(
+    ConfigLintSeverity::Error,
+    location,
+    format!(
+      "{} {}, expected one of {}.",
+      message,
+      if known {
+        "isn't handled in this section"
+      } else {
+        "isn't a feature message type"
+      },
+      expected.join(", ")
+    ),
+  ));
+}
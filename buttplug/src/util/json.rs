@@ -10,11 +10,15 @@
 //! buttplug message de/serializers in both the client and server. Uses the
 //! jsonschema library.
 
-use crate::core::message::serializer::ButtplugSerializerError;
+use crate::core::{errors::SchemaValidationError, message::serializer::ButtplugSerializerError};
 use jsonschema::JSONSchema;
+use std::sync::Arc;
 
+/// Compiled JSON Schema validator. Compiling is expensive, so validators should be created once and
+/// reused. Clones share the compiled schema.
+#[derive(Clone)]
 pub struct JSONValidator {
-  schema: JSONSchema,
+  schema: Arc<JSONSchema>,
 }
 
 impl JSONValidator {
@@ -26,8 +30,54 @@ impl JSONValidator {
   pub fn new(schema: &str) -> Self {
     let schema_json: serde_json::Value =
       serde_json::from_str(schema).expect("Built in schema better be valid");
-    let schema = JSONSchema::compile(&schema_json).expect("Built in schema better be valid");
-    Self { schema }
+    Self::compile(&schema_json)
+  }
+
+  fn compile(schema_json: &serde_json::Value) -> Self {
+    let schema = JSONSchema::compile(schema_json).expect("Built in schema better be valid");
+    Self {
+      schema: Arc::new(schema),
+    }
+  }
+
+  /// Create a new validator that only checks the contents of some of the top level properties of a
+  /// schema. Other properties are still allowed wherever the schema allows them, but their contents
+  /// aren't validated.
+  ///
+  /// # Parameters
+  ///
+  /// - `schema`: JSON Schema that the validator should use.
+  /// - `properties`: Names of the top level properties to check.
+  pub fn new_for_properties(schema: &str, properties: &[&str]) -> Self {
+    let mut schema_json: serde_json::Value =
+      serde_json::from_str(schema).expect("Built in schema better be valid");
+    if let Some(schema_properties) = schema_json["properties"].as_object_mut() {
+      for (name, property_schema) in schema_properties.iter_mut() {
+        if !properties.contains(&name.as_str()) {
+          *property_schema = serde_json::Value::Bool(true);
+        }
+      }
+    }
+    Self::compile(&schema_json)
+  }
+
+  /// Create a new validator that checks against a single component of a schema, rather than the
+  /// whole document.
+  ///
+  /// # Parameters
+  ///
+  /// - `schema`: JSON Schema containing the component, under `components`.
+  /// - `component`: Name of the component to validate against.
+  pub fn new_for_component(schema: &str, component: &str) -> Self {
+    let schema_json: serde_json::Value =
+      serde_json::from_str(schema).expect("Built in schema better be valid");
+    // Keep the rest of the components around, so references between them still resolve.
+    let component_json = serde_json::json!({
+      "$schema": schema_json["$schema"],
+      "$ref": format!("#/components/{}", component),
+      "components": schema_json["components"],
+    });
+    Self::compile(&component_json)
   }
 
   /// Validates a json string, based on the schema the validator was created
@@ -43,12 +93,32 @@ impl JSONValidator {
         json_str, err
       ))
     })?;
-    self.schema.validate(&check_value).map_err(|err| {
-      let err_vec: Vec<jsonschema::ValidationError> = err.collect();
+    self.validate_value(&check_value).map_err(|errors| {
+      // Include where each error happened, so it's possible to track down in larger documents.
+      let err_vec: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
       ButtplugSerializerError::JsonSerializerError(format!(
-        "Error during JSON Schema Validation: {:?}",
-        err_vec
+        "Error during JSON Schema Validation: {}",
+        err_vec.join(", ")
       ))
     })
   }
+
+  /// Validates a json value, returning every failure along with where in the value it happened.
+  ///
+  /// # Parameters
+  ///
+  /// - `value`: JSON value to validate.
+  pub fn validate_value(
+    &self,
+    value: &serde_json::Value,
+  ) -> Result<(), Vec<SchemaValidationError>> {
+    self.schema.validate(value).map_err(|errors| {
+      errors
+        .map(|e| SchemaValidationError {
+          pointer: e.instance_path.to_string(),
+          message: e.to_string(),
+        })
+        .collect()
+    })
+  }
 }
@@ -8,11 +8,23 @@
 //! Utility module, for storing types and functions used across other modules in
 //! the library.
 
+#[cfg(any(
+  feature = "tokio-runtime",
+  feature = "wasm-bindgen-runtime",
+  feature = "dummy-runtime"
+))]
 pub mod async_manager;
 #[cfg(feature = "server")]
 pub mod device_configuration;
+#[cfg(feature = "server")]
+pub mod device_configuration_lint;
 pub mod future;
 pub mod json;
+#[cfg(any(
+  feature = "tokio-runtime",
+  feature = "wasm-bindgen-runtime",
+  feature = "dummy-runtime"
+))]
 pub mod logging;
 pub mod stream;
 
@@ -20,6 +32,11 @@ pub mod stream;
 pub use tokio::time::sleep;
 #[cfg(feature = "wasm")]
 pub use wasmtimer::tokio::sleep;
+// Same clock as sleep, so time measured against it follows a paused tokio runtime in tests.
+#[cfg(not(feature = "wasm"))]
+pub use tokio::time::Instant;
+#[cfg(feature = "wasm")]
+pub use wasmtimer::std::Instant;
 
 #[cfg(all(feature = "server", feature = "client"))]
 use crate::{
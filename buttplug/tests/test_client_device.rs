@@ -8,21 +8,38 @@
 mod util;
 use buttplug::{
   client::{
+    ButtplugClient,
+    ButtplugClientDevice,
     ButtplugClientDeviceEvent,
     ButtplugClientError,
     ButtplugClientEvent,
     ScalarValueCommand,
   },
   core::{
+    connector::ButtplugInProcessClientConnectorBuilder,
     errors::{ButtplugDeviceError, ButtplugError, ButtplugMessageError},
-    message::{self, ButtplugClientMessage, ClientDeviceMessageAttributes},
+    message::{self, ButtplugClientMessage, ClientDeviceMessageAttributes, Endpoint},
   },
-  util::async_manager,
+  server::{
+    device::{
+      hardware::{HardwareCommand, HardwareWriteCmd},
+      ServerDeviceManagerBuilder,
+    },
+    ButtplugServer,
+    ButtplugServerBuilder,
+  },
+  util::{async_manager, stream::recv_now},
 };
-use futures::StreamExt;
+use futures::{pin_mut, Stream, StreamExt};
 use std::{sync::Arc, time::Duration};
 use tokio::time::sleep;
-use util::{test_client_with_device, test_device_manager::TestHardwareEvent};
+use util::{
+  create_test_dcm,
+  test_client_with_device,
+  test_device_manager::{check_test_recv_value, TestDeviceIdentifier, TestHardwareEvent},
+  test_server_with_customized_device,
+  TestDeviceCommunicationManagerBuilder,
+};
 
 #[cfg(feature = "server")]
 #[tokio::test]
@@ -65,7 +82,7 @@ async fn test_client_device_connected_status() {
 #[cfg(feature = "server")]
 #[tokio::test]
 async fn test_client_device_client_disconnected_status() {
-  let (client, _) = test_client_with_device().await;
+  let (client, _device) = test_client_with_device().await;
 
   let mut event_stream = client.event_stream();
   client
@@ -127,7 +144,7 @@ async fn test_client_device_connected_no_event_listener() {
 #[cfg(feature = "server")]
 #[tokio::test]
 async fn test_client_device_invalid_command() {
-  let (client, _) = test_client_with_device().await;
+  let (client, _device) = test_client_with_device().await;
 
   let mut event_stream = client.event_stream();
   client
@@ -284,3 +301,256 @@ async fn test_client_repeated_deviceremoved_message() {
 // TODO Test DeviceList being sent followed by repeat DeviceAdded
 // TODO Test DeviceList being sent multiple times
 // TODO Test sending device return for device that doesn't exist (in client)
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_device_stop_features() {
+  let (client, mut device) = test_client_with_device().await;
+
+  let mut event_stream = client.event_stream();
+  client
+    .start_scanning()
+    .await
+    .expect("Test, assuming infallible.");
+  let mut client_device = None;
+  while let Some(msg) = event_stream.next().await {
+    if let ButtplugClientEvent::DeviceAdded(da) = msg {
+      client_device = Some(da);
+      break;
+    }
+  }
+  let test_device = client_device.expect("Test, assuming infallible.");
+  test_device
+    .vibrate(&ScalarValueCommand::ScalarValue(0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  while recv_now(&mut device.receiver).is_some() {}
+
+  // Only the stopped feature is written, the other keeps vibrating.
+  test_device
+    .stop_features(&[1])
+    .await
+    .expect("Test, assuming infallible.");
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF2, 0], false)),
+  );
+  assert!(recv_now(&mut device.receiver).is_none());
+
+  assert!(matches!(
+    test_device.stop_features(&[2]).await.unwrap_err(),
+    ButtplugClientError::ButtplugError(ButtplugError::ButtplugDeviceError(
+      ButtplugDeviceError::DeviceFeatureIndexError(2, 2)
+    ))
+  ));
+}
+
+async fn connect_test_client(server: ButtplugServer) -> ButtplugClient {
+  let connector = ButtplugInProcessClientConnectorBuilder::default()
+    .server(server)
+    .finish();
+  let client = ButtplugClient::new("Test Client");
+  client
+    .connect(connector)
+    .await
+    .expect("Test, assuming infallible.");
+  client
+}
+
+async fn disconnect_test_client(client: &ButtplugClient) {
+  let mut events = client.event_stream();
+  client
+    .disconnect()
+    .await
+    .expect("Test, assuming infallible.");
+  while let Some(msg) = events.next().await {
+    if let ButtplugClientEvent::ServerDisconnect = msg {
+      break;
+    }
+  }
+}
+
+async fn wait_for_client_device(
+  event_stream: impl Stream<Item = ButtplugClientEvent>,
+) -> Arc<ButtplugClientDevice> {
+  pin_mut!(event_stream);
+  while let Some(msg) = event_stream.next().await {
+    if let ButtplugClientEvent::DeviceAdded(da) = msg {
+      return da;
+    }
+  }
+  panic!("Client event stream ended.");
+}
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_disconnect_leaves_other_clients_devices_running() {
+  // Two clients on servers sharing a device manager, without any claims.
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let mut device = builder.add_test_device(&TestDeviceIdentifier::new("Massage Demo", None));
+  let mut dm_builder = ServerDeviceManagerBuilder::new(create_test_dcm(false));
+  dm_builder.comm_manager(builder);
+  let first_server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  let second_server =
+    ButtplugServerBuilder::new_with_shared_device_manager(first_server.device_manager())
+      .finish()
+      .unwrap();
+  let first_client = connect_test_client(first_server).await;
+  let second_client = connect_test_client(second_server).await;
+
+  let second_events = second_client.event_stream();
+  first_client
+    .start_scanning()
+    .await
+    .expect("Test, assuming infallible.");
+  let second_device = wait_for_client_device(second_events).await;
+  second_device
+    .vibrate(&ScalarValueCommand::ScalarValue(0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+  );
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF2, 64], false)),
+  );
+
+  // The first client never used the device, so it keeps running for the second one.
+  disconnect_test_client(&first_client).await;
+  sleep(Duration::from_millis(50)).await;
+  assert!(recv_now(&mut device.receiver).is_none());
+
+  // The last client leaving stops it.
+  disconnect_test_client(&second_client).await;
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+  );
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF2, 0], false)),
+  );
+}
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_client_exclusive_device_claim() {
+  // Two clients on servers sharing a device manager, with the Massage Demo claimed by whichever
+  // client actuates it first.
+  let (first_server, mut device) = test_server_with_customized_device(
+    &TestDeviceIdentifier::new("Massage Demo", Some("SoloTest".to_owned())),
+    "aneros",
+    |config| {
+      config.set_exclusive(true);
+    },
+  );
+  let second_server =
+    ButtplugServerBuilder::new_with_shared_device_manager(first_server.device_manager())
+      .finish()
+      .unwrap();
+  let third_server =
+    ButtplugServerBuilder::new_with_shared_device_manager(first_server.device_manager())
+      .finish()
+      .unwrap();
+  let device_manager = first_server.device_manager();
+  let first_client = connect_test_client(first_server).await;
+  let second_client = connect_test_client(second_server).await;
+  let third_client = connect_test_client(third_server).await;
+
+  let second_events = second_client.event_stream();
+  let first_events = first_client.event_stream();
+  first_client
+    .start_scanning()
+    .await
+    .expect("Test, assuming infallible.");
+  let first_device = wait_for_client_device(first_events).await;
+  let second_device = wait_for_client_device(second_events).await;
+
+  first_device
+    .vibrate(&ScalarValueCommand::ScalarValue(0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+  );
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF2, 64], false)),
+  );
+  assert!(device_manager
+    .device_info(first_device.index())
+    .expect("Test, assuming infallible.")
+    .claimed_by()
+    .is_some());
+
+  // The second client can't actuate the device, but can still stop it.
+  assert!(matches!(
+    second_device
+      .vibrate(&ScalarValueCommand::ScalarValue(1.0))
+      .await
+      .unwrap_err(),
+    ButtplugClientError::ButtplugError(ButtplugError::ButtplugDeviceError(
+      ButtplugDeviceError::DeviceBusy(..)
+    ))
+  ));
+  assert!(recv_now(&mut device.receiver).is_none());
+  second_device
+    .stop()
+    .await
+    .expect("Test, assuming infallible.");
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+  );
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF2, 0], false)),
+  );
+
+  // A client that never used the device disconnecting leaves it running for the first client.
+  first_device
+    .vibrate(&ScalarValueCommand::ScalarValue(0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+  );
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF2, 64], false)),
+  );
+  disconnect_test_client(&third_client).await;
+  sleep(Duration::from_millis(50)).await;
+  assert!(recv_now(&mut device.receiver).is_none());
+  assert!(device_manager
+    .device_info(first_device.index())
+    .expect("Test, assuming infallible.")
+    .claimed_by()
+    .is_some());
+
+  // Once the first client is gone, its device is stopped and free for the second one.
+  disconnect_test_client(&first_client).await;
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+  );
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF2, 0], false)),
+  );
+  assert!(device_manager
+    .device_info(second_device.index())
+    .expect("Test, assuming infallible.")
+    .claimed_by()
+    .is_none());
+  second_device
+    .vibrate(&ScalarValueCommand::ScalarValue(1.0))
+    .await
+    .expect("Test, assuming infallible.");
+}
@@ -8,7 +8,65 @@
 mod util;
 extern crate buttplug;
 
-use buttplug::util::device_configuration::load_protocol_configs;
+use buttplug::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError, ConfigurationError, SchemaValidationError},
+    message::{ActuatorType, Endpoint, FeatureType, SensorType, SensorUnit},
+  },
+  server::{
+    device::{
+      configuration::{
+        BaseDeviceIdentifier,
+        BluetoothLEConnectionPriority,
+        BluetoothLEMatchFailure,
+        BluetoothLESpecifier,
+        DeviceAccess,
+        DeviceConfigurationManager,
+        DeviceMirror,
+        LovenseConnectServiceSpecifier,
+        ProtocolCommunicationSpecifier,
+        ProtocolNearMiss,
+        ProtocolView,
+        RotationInversion,
+        SerialSpecifier,
+        ServerDeviceConfigInfo,
+        UserDeviceCustomization,
+        UserDeviceDefinition,
+        UserDeviceIdentifier,
+        WebsocketSpecifier,
+        XInputSpecifier,
+      },
+      protocol::{aneros, supported_protocols, ProtocolIdentifier, ProtocolIdentifierFactory},
+      ServerDeviceManagerBuilder,
+    },
+    ButtplugServerBuilder,
+  },
+  util::{
+    device_configuration::{
+      add_protocol_definition_from_json,
+      import_intiface_device_settings,
+      load_protocol_configs,
+      load_protocol_configs_from_files,
+      load_protocol_configs_with_base,
+      load_protocol_configs_with_lints,
+      protocol_capabilities,
+      save_user_config,
+      tooling::{self, ConfigFileKind},
+      watch_protocol_configs_from_files,
+      BaseConfig,
+      ProtocolConfiguration,
+      DEVICE_CONFIGURATION_JSON,
+    },
+    device_configuration_lint::{
+      validate_device_config_strict,
+      validate_external_config,
+      ConfigLint,
+      ConfigLintSeverity,
+    },
+  },
+};
+use futures::{pin_mut, StreamExt};
+use std::{collections::HashMap, fs, time::Duration};
 use tokio_test::assert_ok;
 
 const BASE_CONFIG_JSON: &str = r#"
@@ -108,12 +166,17 @@ async fn test_valid_null_user_config() {
 #[cfg(feature = "server")]
 #[tokio::test]
 async fn test_invalid_null_version_config() {
-  assert!(load_protocol_configs(
+  let err = load_protocol_configs(
     &None,
     &Some(BASE_INVALID_VERSION_CONFIG_JSON.to_owned()),
-    false
+    false,
   )
-  .is_err());
+  .err()
+  .expect("Mismatched major version should not load.");
+  assert!(matches!(
+    err,
+    ButtplugDeviceError::ConfigurationError(ConfigurationError::VersionMismatch { .. })
+  ));
 }
 
 #[cfg(feature = "server")]
@@ -304,7 +367,216 @@ async fn test_server_builder_user_device_config_old_config_version() {
 
 #[tokio::test]
 async fn test_server_builder_user_device_config_invalid_json() {
-  assert!(load_protocol_configs(&None, &Some("{\"Not Valid JSON\"}".to_owned()), false).is_err())
+  let err = load_protocol_configs(&None, &Some("{\"Not Valid JSON\"}".to_owned()), false)
+    .err()
+    .expect("Invalid JSON should not load.");
+  assert!(matches!(
+    err,
+    ButtplugDeviceError::ConfigurationError(ConfigurationError::SchemaViolation { .. })
+  ));
+}
+
+#[tokio::test]
+async fn test_user_device_config_schema_violation() {
+  let user_config_json = r#"{
+      "version": {
+        "major": 3,
+        "minor": 0
+      },
+      "user-configs": {
+        "protocols": "lovense"
+      }
+    }
+    "#;
+  let err = load_protocol_configs(&None, &Some(user_config_json.to_owned()), false)
+    .err()
+    .expect("Schema violation should not load.");
+  assert!(matches!(
+    err,
+    ButtplugDeviceError::ConfigurationError(ConfigurationError::SchemaViolation { .. })
+  ));
+}
+
+#[tokio::test]
+async fn test_user_device_config_serde_error() {
+  // Valid as far as the schema is concerned, but doesn't fit in a u32.
+  let user_config_json = FILE_USER_CONFIG_JSON.replace("\"index\": 0", "\"index\": 5000000000");
+  let err = load_protocol_configs(&None, &Some(user_config_json), false)
+    .err()
+    .expect("Out of range index should not load.");
+  assert!(matches!(
+    err,
+    ButtplugDeviceError::ConfigurationError(ConfigurationError::SerdeError { .. })
+  ));
+}
+
+#[tokio::test]
+async fn test_user_device_config_duplicate_reserved_index() {
+  let mut user_config: serde_json::Value =
+    serde_json::from_str(FILE_USER_CONFIG_JSON).expect("Test, assuming infallible.");
+  let devices = user_config["user-configs"]["devices"]
+    .as_array_mut()
+    .expect("Test, assuming infallible.");
+  let mut second_device = devices[0].clone();
+  second_device["identifier"]["address"] = "FileConfigTest2".into();
+  devices.push(second_device);
+  let err = load_protocol_configs(&None, &Some(user_config.to_string()), false)
+    .err()
+    .expect("Duplicate reserved index should not load.");
+  match err {
+    ButtplugDeviceError::ConfigurationError(ConfigurationError::DuplicateReservedIndex {
+      index,
+      devices,
+    }) => {
+      assert_eq!(index, 0);
+      assert_eq!(devices.len(), 2);
+    }
+    err => panic!("Unexpected error: {:?}", err),
+  }
+}
+
+fn load_user_config_with_access(
+  allow: bool,
+  deny: bool,
+) -> Result<Option<DeviceAccess>, ButtplugDeviceError> {
+  let user_config_json = FILE_USER_CONFIG_JSON
+    .replace("\"allow\": false", &format!("\"allow\": {}", allow))
+    .replace("\"deny\": false", &format!("\"deny\": {}", deny));
+  let dcm = load_protocol_configs(&None, &Some(user_config_json), false)?
+    .finish()
+    .expect("Test, assuming infallible.");
+  let access = dcm.device_overrides().next().map(|view| view.access());
+  Ok(access)
+}
+
+#[tokio::test]
+async fn test_user_device_config_access() {
+  assert_eq!(
+    load_user_config_with_access(false, false).expect("Test, assuming infallible."),
+    Some(DeviceAccess::Default)
+  );
+  assert_eq!(
+    load_user_config_with_access(true, false).expect("Test, assuming infallible."),
+    Some(DeviceAccess::Allow)
+  );
+  assert_eq!(
+    load_user_config_with_access(false, true).expect("Test, assuming infallible."),
+    Some(DeviceAccess::Deny)
+  );
+  match load_user_config_with_access(true, true).expect_err("Contradictory access should not load.")
+  {
+    ButtplugDeviceError::ConfigurationError(ConfigurationError::ContradictoryDeviceAccess {
+      device,
+    }) => assert!(device.contains("FileConfigTest")),
+    err => panic!("Unexpected error: {:?}", err),
+  }
+}
+
+fn load_user_config_with_invert_rotation(
+  invert_rotation: &str,
+) -> Result<Option<RotationInversion>, ButtplugDeviceError> {
+  let user_config_json = FILE_USER_CONFIG_JSON.replace(
+    "\"index\": 0",
+    &format!("\"index\": 0, \"invert-rotation\": {}", invert_rotation),
+  );
+  let dcm = load_protocol_configs(&None, &Some(user_config_json), false)?
+    .finish()
+    .expect("Test, assuming infallible.");
+  let invert_rotation = dcm
+    .device_overrides()
+    .next()
+    .and_then(|view| view.customization().invert_rotation().clone());
+  Ok(invert_rotation)
+}
+
+#[tokio::test]
+async fn test_user_device_config_invert_rotation() {
+  let all = load_user_config_with_invert_rotation("true")
+    .expect("Test, assuming infallible.")
+    .expect("Test, assuming infallible.");
+  assert_eq!(all, RotationInversion::All(true));
+  assert!(all.inverts(0) && all.inverts(1));
+  let features = load_user_config_with_invert_rotation("[1]")
+    .expect("Test, assuming infallible.")
+    .expect("Test, assuming infallible.");
+  assert_eq!(features, RotationInversion::Features(vec![1]));
+  assert!(!features.inverts(0) && features.inverts(1));
+  assert!(matches!(
+    load_user_config_with_invert_rotation("\"yes\""),
+    Err(ButtplugDeviceError::ConfigurationError(
+      ConfigurationError::SchemaViolation { .. }
+    ))
+  ));
+}
+
+#[tokio::test]
+async fn test_user_device_config_selects_configuration() {
+  let dcm = load_protocol_configs(&None, &None, false)
+    .expect("Test, assuming infallible.")
+    .finish()
+    .expect("Test, assuming infallible.");
+  let identifier =
+    UserDeviceIdentifier::new("SimpleTest", "dg-lab-v2", &Some("D-LAB ESTIM01".to_owned()));
+  let mut definition = dcm
+    .device_definition(&identifier, &[])
+    .expect("Test, assuming infallible.");
+  // The BLE name only ever matches the advanced layout.
+  assert_eq!(definition.name(), "Dungeon Lab V2");
+  assert_eq!(definition.features().len(), 7);
+  assert_eq!(dcm.configuration_identifier(&identifier), None);
+
+  definition
+    .user_config_mut()
+    .set_configuration(Some("simple".to_owned()));
+  dcm
+    .add_user_device_definition(&identifier, &definition)
+    .expect("Test, assuming infallible.");
+  let definition = dcm
+    .device_definition(&identifier, &[])
+    .expect("Test, assuming infallible.");
+  assert_eq!(definition.name(), "Dungeon Lab V2 (Simple)");
+  assert_eq!(definition.features().len(), 3);
+  assert_eq!(
+    dcm.configuration_identifier(&identifier),
+    Some("simple".to_owned())
+  );
+
+  // Unknown configurations are ignored.
+  let mut definition = definition.clone();
+  definition
+    .user_config_mut()
+    .set_configuration(Some("missing".to_owned()));
+  dcm
+    .add_user_device_definition(&identifier, &definition)
+    .expect("Test, assuming infallible.");
+  let definition = dcm
+    .device_definition(&identifier, &[])
+    .expect("Test, assuming infallible.");
+  assert_eq!(definition.features().len(), 3);
+  assert_eq!(dcm.configuration_identifier(&identifier), None);
+}
+
+#[tokio::test]
+async fn test_user_device_definition_contradictory_access() {
+  let dcm = load_protocol_configs(&None, &None, false)
+    .expect("Test, assuming infallible.")
+    .finish()
+    .expect("Test, assuming infallible.");
+  let identifier =
+    UserDeviceIdentifier::new("AccessTest", "aneros", &Some("Massage Demo".to_owned()));
+  let definition = UserDeviceDefinition::new(
+    "Aneros Vivi",
+    &[],
+    &UserDeviceCustomization::new(&None, true, true, 0),
+  );
+  assert!(matches!(
+    dcm.add_user_device_definition(&identifier, &definition),
+    Err(ButtplugDeviceError::ConfigurationError(
+      ConfigurationError::ContradictoryDeviceAccess { .. }
+    ))
+  ));
+  assert_eq!(dcm.device_overrides().count(), 0);
+  assert!(dcm.address_allowed("AccessTest"));
 }
 
 /*
@@ -369,6 +641,2326 @@ async fn test_server_builder_user_device_config_invalid_json() {
         .any(|x| x.port == "COM1"));
     }
 */
-// TODO Test invalid config load (not json)
+const FILE_USER_CONFIG_JSON: &str = r#"
+{
+  "version": {
+    "major": 3,
+    "minor": 0
+  },
+  "user-configs": {
+    "devices": [
+      {
+        "identifier": {
+          "address": "FileConfigTest",
+          "protocol": "lovense",
+          "identifier": "B"
+        },
+        "config": {
+          "name": "Lovense Test Device",
+          "features": [
+            {
+              "feature-type": "Vibrate",
+              "description": "Test Speed",
+              "actuator": {
+                "step-range": [0, 20],
+                "step-limit": [0, 20],
+                "messages": ["ScalarCmd"]
+              }
+            }
+          ],
+          "user-config": {
+            "allow": false,
+            "deny": false,
+            "index": 0
+          }
+        }
+      }
+    ]
+  }
+}
+"#;
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_load_configs_from_files() {
+  let dir = tempfile::tempdir().expect("Test, assuming infallible.");
+  let user_config_path = dir.path().join("user-config.json");
+  fs::write(&user_config_path, FILE_USER_CONFIG_JSON).expect("Test, assuming infallible.");
+
+  // A main config path that doesn't exist falls back to the internal config.
+  let dcm = load_protocol_configs_from_files(
+    Some(dir.path().join("missing-main-config.json")),
+    Some(user_config_path),
+    false,
+  )
+  .await
+  .expect("Test, assuming infallible.")
+  .finish()
+  .expect("Test, assuming infallible.");
+  assert_eq!(dcm.user_device_definitions().len(), 1);
+
+  // A missing user config is just no user config.
+  let dcm = load_protocol_configs_from_files(None, Some(dir.path().join("missing.json")), false)
+    .await
+    .expect("Test, assuming infallible.")
+    .finish()
+    .expect("Test, assuming infallible.");
+  assert!(dcm.user_device_definitions().is_empty());
+}
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_load_malformed_config_file() {
+  let dir = tempfile::tempdir().expect("Test, assuming infallible.");
+  let user_config_path = dir.path().join("user-config.json");
+  fs::write(&user_config_path, "{ not json").expect("Test, assuming infallible.");
+  let err = load_protocol_configs_from_files(None, Some(user_config_path.clone()), false)
+    .await
+    .err()
+    .expect("Malformed config should not load.");
+  assert!(err
+    .to_string()
+    .contains(&user_config_path.display().to_string()));
+  assert!(matches!(
+    err,
+    ButtplugError::ButtplugDeviceError(ButtplugDeviceError::ConfigurationError(
+      ConfigurationError::FileLoadError { .. }
+    ))
+  ));
+
+  fs::write(&user_config_path, [0xFF, 0xFE]).expect("Test, assuming infallible.");
+  let err = load_protocol_configs_from_files(None, Some(user_config_path.clone()), false)
+    .await
+    .err()
+    .expect("Non UTF-8 config should not load.");
+  assert!(err
+    .to_string()
+    .contains(&user_config_path.display().to_string()));
+  assert!(matches!(
+    err,
+    ButtplugError::ButtplugDeviceError(ButtplugDeviceError::ConfigurationError(
+      ConfigurationError::InvalidUtf8 { .. }
+    ))
+  ));
+}
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_watch_user_config_file() {
+  let dir = tempfile::tempdir().expect("Test, assuming infallible.");
+  let user_config_path = dir.path().join("user-config.json");
+  fs::write(&user_config_path, BASE_VALID_NULL_USER_CONFIG_JSON)
+    .expect("Test, assuming infallible.");
+  let stream = watch_protocol_configs_from_files(
+    None,
+    user_config_path.clone(),
+    false,
+    Duration::from_millis(50),
+  );
+  pin_mut!(stream);
+
+  // Make sure the modification time moves on coarse filesystems.
+  tokio::time::sleep(Duration::from_millis(100)).await;
+  fs::write(&user_config_path, FILE_USER_CONFIG_JSON).expect("Test, assuming infallible.");
+  let dcm = tokio::time::timeout(Duration::from_secs(5), stream.next())
+    .await
+    .expect("Config change should be picked up.")
+    .expect("Test, assuming infallible.")
+    .expect("Test, assuming infallible.")
+    .finish()
+    .expect("Test, assuming infallible.");
+  assert_eq!(dcm.user_device_definitions().len(), 1);
+}
+
+const PROTOCOL_FRAGMENT_JSON: &str = r#"
+{
+  "defaults": {
+    "name": "Fake BLE Device",
+    "features": [
+      {
+        "feature-type": "Vibrate",
+        "actuator": {
+          "step-range": [0, 100],
+          "messages": ["ScalarCmd"]
+        }
+      }
+    ]
+  },
+  "communication": [
+    {
+      "btle": {
+        "names": ["FakeBLEDevice"],
+        "services": {
+          "0000ff00-0000-1000-8000-00805f9b34fb": {
+            "tx": "0000ff01-0000-1000-8000-00805f9b34fb"
+          }
+        }
+      }
+    }
+  ]
+}
+"#;
+
+#[tokio::test]
+async fn test_add_protocol_definition_from_json() {
+  let mut builder = load_protocol_configs(&None, &None, false).expect("Test, assuming infallible.");
+  add_protocol_definition_from_json(&mut builder, "aneros", PROTOCOL_FRAGMENT_JSON)
+    .expect("Test, assuming infallible.");
+  let dcm = builder.finish().expect("Test, assuming infallible.");
+
+  // The fragment replaces the built in aneros definition.
+  let specifiers = dcm.protocol_device_configurations();
+  assert_eq!(specifiers["aneros"].len(), 1);
+  let fake_device = ProtocolCommunicationSpecifier::BluetoothLE(
+    BluetoothLESpecifier::new_from_device("FakeBLEDevice", &HashMap::new(), &[], &HashMap::new()),
+  );
+  assert!(specifiers["aneros"].contains(&fake_device));
+  let old_device = ProtocolCommunicationSpecifier::BluetoothLE(
+    BluetoothLESpecifier::new_from_device("Massage Demo", &HashMap::new(), &[], &HashMap::new()),
+  );
+  assert!(!specifiers["aneros"].contains(&old_device));
+  assert_eq!(dcm.protocol_specializers(&fake_device).len(), 1);
+
+  let definition = dcm
+    .device_definition(
+      &UserDeviceIdentifier::new("FakeAddress", "aneros", &None),
+      &[],
+    )
+    .expect("Test, assuming infallible.");
+  assert_eq!(definition.name(), "Fake BLE Device");
+  assert_eq!(definition.features().len(), 1);
+}
+
+#[tokio::test]
+async fn test_add_invalid_protocol_definition_from_json() {
+  let mut builder = load_protocol_configs(&None, &None, false).expect("Test, assuming infallible.");
+  let fragment = PROTOCOL_FRAGMENT_JSON.replace("\"step-range\": [0, 100]", "\"step-range\": [0]");
+  let err = add_protocol_definition_from_json(&mut builder, "aneros", &fragment)
+    .expect_err("Test, assuming infallible.");
+  // The error should point at the part of the fragment that failed.
+  assert!(
+    err
+      .to_string()
+      .contains("/defaults/features/0/actuator/step-range"),
+    "{}",
+    err
+  );
+  assert!(matches!(
+    err,
+    ButtplugDeviceError::ConfigurationError(ConfigurationError::InvalidProtocolDefinition {
+      ref protocol,
+      ..
+    }) if protocol == "aneros"
+  ));
+}
+
+#[tokio::test]
+async fn test_add_protocol_definition_with_service_data() {
+  let mut builder = load_protocol_configs(&None, &None, false).expect("Test, assuming infallible.");
+  let fragment = PROTOCOL_FRAGMENT_JSON.replace(
+    r#""names": ["FakeBLEDevice"],"#,
+    r#""names": [],
+        "service-data": [
+          {
+            "service": "0000fff0-0000-1000-8000-00805f9b34fb",
+            "data": [1, 2]
+          }
+        ],"#,
+  );
+  add_protocol_definition_from_json(&mut builder, "aneros", &fragment)
+    .expect("Test, assuming infallible.");
+  let dcm = builder.finish().expect("Test, assuming infallible.");
+  let service = uuid::Uuid::parse_str("0000fff0-0000-1000-8000-00805f9b34fb")
+    .expect("Test, assuming infallible.");
+  let device = |data: &[u8]| {
+    ProtocolCommunicationSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device(
+      "BLE Device",
+      &HashMap::new(),
+      &[],
+      &HashMap::from([(service, data.to_vec())]),
+    ))
+  };
+  assert_eq!(dcm.protocol_specializers(&device(&[1, 2, 3])).len(), 1);
+  assert!(dcm.protocol_specializers(&device(&[2, 2, 3])).is_empty());
+}
+
+#[tokio::test]
+async fn test_protocol_near_misses() {
+  let mut builder = load_protocol_configs(&None, &None, false).expect("Test, assuming infallible.");
+  let fragment = PROTOCOL_FRAGMENT_JSON.replace(
+    r#""names": ["FakeBLEDevice"],"#,
+    r#""names": ["FakeBLEDevice"],
+        "service-data": [
+          {
+            "service": "0000fff0-0000-1000-8000-00805f9b34fb"
+          }
+        ],"#,
+  );
+  add_protocol_definition_from_json(&mut builder, "aneros", &fragment)
+    .expect("Test, assuming infallible.");
+  let dcm = builder.finish().expect("Test, assuming infallible.");
+  let service = uuid::Uuid::parse_str("0000fff0-0000-1000-8000-00805f9b34fb")
+    .expect("Test, assuming infallible.");
+  let device = |name: &str, service_data: &HashMap<uuid::Uuid, Vec<u8>>| {
+    BluetoothLESpecifier::new_from_device(name, &HashMap::new(), &[], service_data)
+  };
+
+  assert_eq!(
+    dcm.protocol_near_misses(&device("FakeBLEDevice", &HashMap::new())),
+    vec![ProtocolNearMiss::new(
+      "aneros",
+      BluetoothLEMatchFailure::ServiceDataMissing(vec![service])
+    )]
+  );
+  assert_eq!(
+    dcm.protocol_near_misses(&device(
+      "OtherBLEDevice",
+      &HashMap::from([(service, vec![1])])
+    )),
+    vec![ProtocolNearMiss::new(
+      "aneros",
+      BluetoothLEMatchFailure::ServiceDataNameMismatch
+    )]
+  );
+  assert_eq!(
+    dcm.protocol_near_misses(&device("fakebledevice", &HashMap::new())),
+    vec![ProtocolNearMiss::new(
+      "aneros",
+      BluetoothLEMatchFailure::NameCaseMismatch("FakeBLEDevice".to_owned())
+    )]
+  );
+  // Devices that match aren't near misses.
+  assert!(dcm
+    .protocol_near_misses(&device(
+      "FakeBLEDevice",
+      &HashMap::from([(service, vec![1])])
+    ))
+    .is_empty());
+}
+
+#[tokio::test]
+async fn test_add_protocol_definition_with_names_regex() {
+  let mut builder = load_protocol_configs(&None, &None, false).expect("Test, assuming infallible.");
+  let fragment = PROTOCOL_FRAGMENT_JSON.replace(
+    r#""names": ["FakeBLEDevice"],"#,
+    r#""names": [],
+        "names-regex": ["FakeBLE-\\d{4}"],"#,
+  );
+  add_protocol_definition_from_json(&mut builder, "aneros", &fragment)
+    .expect("Test, assuming infallible.");
+  let dcm = builder.finish().expect("Test, assuming infallible.");
+  let device = |name: &str| {
+    ProtocolCommunicationSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device(
+      name,
+      &HashMap::new(),
+      &[],
+      &HashMap::new(),
+    ))
+  };
+  assert_eq!(dcm.protocol_specializers(&device("FakeBLE-1234")).len(), 1);
+  assert!(dcm
+    .protocol_specializers(&device("FakeBLE-12345"))
+    .is_empty());
+}
+
+#[tokio::test]
+async fn test_add_protocol_definition_with_invalid_names_regex() {
+  let mut builder = load_protocol_configs(&None, &None, false).expect("Test, assuming infallible.");
+  let fragment = PROTOCOL_FRAGMENT_JSON.replace(
+    r#""names": ["FakeBLEDevice"],"#,
+    r#""names": [],
+        "names-regex": ["FakeBLE-("],"#,
+  );
+  add_protocol_definition_from_json(&mut builder, "aneros", &fragment)
+    .expect("Test, assuming infallible.");
+  assert!(matches!(
+    builder.finish(),
+    Err(ButtplugDeviceError::ConfigurationError(ConfigurationError::InvalidNameRegex {
+      ref protocol,
+      ..
+    })) if protocol == "aneros"
+  ));
+}
+
+#[tokio::test]
+async fn test_add_protocol_definition_with_connection_parameters() {
+  let mut builder = load_protocol_configs(&None, &None, false).expect("Test, assuming infallible.");
+  let fragment = PROTOCOL_FRAGMENT_JSON.replace(
+    r#""names": ["FakeBLEDevice"],"#,
+    r#""names": ["FakeBLEDevice"],
+        "requested-mtu": 247,
+        "connection-priority": "high","#,
+  );
+  add_protocol_definition_from_json(&mut builder, "aneros", &fragment)
+    .expect("Test, assuming infallible.");
+  let dcm = builder.finish().expect("Test, assuming infallible.");
+  let specifiers = dcm.protocol_device_configurations();
+  let btle = specifiers["aneros"]
+    .iter()
+    .find_map(|specifier| match specifier {
+      ProtocolCommunicationSpecifier::BluetoothLE(btle) => Some(btle),
+      _ => None,
+    })
+    .expect("Test, assuming infallible.");
+  assert_eq!(*btle.requested_mtu(), Some(247));
+  assert_eq!(
+    *btle.connection_priority(),
+    Some(BluetoothLEConnectionPriority::High)
+  );
+
+  // Unknown priorities are a schema violation, not a silent default.
+  let mut builder = load_protocol_configs(&None, &None, false).expect("Test, assuming infallible.");
+  let fragment = PROTOCOL_FRAGMENT_JSON.replace(
+    r#""names": ["FakeBLEDevice"],"#,
+    r#""names": ["FakeBLEDevice"],
+        "connection-priority": "fastest","#,
+  );
+  assert!(add_protocol_definition_from_json(&mut builder, "aneros", &fragment).is_err());
+}
+
+#[tokio::test]
+async fn test_add_protocol_definition_with_optional_endpoints() {
+  let mut builder = load_protocol_configs(&None, &None, false).expect("Test, assuming infallible.");
+  let fragment = PROTOCOL_FRAGMENT_JSON.replace(
+    r#""tx": "0000ff01-0000-1000-8000-00805f9b34fb""#,
+    r#""tx": "0000ff01-0000-1000-8000-00805f9b34fb",
+            "rxblebattery": "0000ff02-0000-1000-8000-00805f9b34fb""#,
+  );
+  let fragment = fragment.replace(
+    r#""names": ["FakeBLEDevice"],"#,
+    r#""names": ["FakeBLEDevice"],
+        "optional-endpoints": ["rxblebattery"],"#,
+  );
+  add_protocol_definition_from_json(&mut builder, "aneros", &fragment)
+    .expect("Test, assuming infallible.");
+  let dcm = builder.finish().expect("Test, assuming infallible.");
+  let specifiers = dcm.protocol_device_configurations();
+  let btle = specifiers["aneros"]
+    .iter()
+    .find_map(|specifier| match specifier {
+      ProtocolCommunicationSpecifier::BluetoothLE(btle) => Some(btle),
+      _ => None,
+    })
+    .expect("Test, assuming infallible.");
+  assert!(btle.optional_endpoints().contains(&Endpoint::RxBLEBattery));
+  assert!(btle.missing_required_endpoints(&[Endpoint::Tx]).is_empty());
+  assert_eq!(btle.missing_required_endpoints(&[]), vec![Endpoint::Tx]);
+  let err = btle
+    .check_discovered_endpoints("FakeBLEDevice", "FakeAddress", &[Endpoint::RxBLEBattery])
+    .expect_err("Test, assuming infallible.");
+  assert!(err.to_string().contains("tx"), "{}", err);
+}
+
+fn custom_main_config(minor_version: u32) -> String {
+  let lovense_fragment = PROTOCOL_FRAGMENT_JSON.replace("FakeBLEDevice", "FakeLovenseDevice");
+  format!(
+    r#"{{
+      "version": {{ "major": 3, "minor": {minor_version} }},
+      "protocols": {{
+        "aneros": {PROTOCOL_FRAGMENT_JSON},
+        "lovense": {lovense_fragment}
+      }}
+    }}"#
+  )
+}
+
+#[tokio::test]
+async fn test_custom_base_config_replaces_embedded_config() {
+  let dcm =
+    load_protocol_configs_with_base(&BaseConfig::Custom(custom_main_config(0)), &None, false)
+      .expect("Test, assuming infallible.")
+      .finish()
+      .expect("Test, assuming infallible.");
+  assert_eq!(dcm.protocol_device_configurations().len(), 2);
+
+  for name in ["FakeBLEDevice", "FakeLovenseDevice"] {
+    let device = ProtocolCommunicationSpecifier::BluetoothLE(
+      BluetoothLESpecifier::new_from_device(name, &HashMap::new(), &[], &HashMap::new()),
+    );
+    assert_eq!(dcm.protocol_specializers(&device).len(), 1);
+  }
+  // Devices only the embedded config knows about don't match anything.
+  for name in ["Massage Demo", "LVS-Z001", "47L121000"] {
+    let device = ProtocolCommunicationSpecifier::BluetoothLE(
+      BluetoothLESpecifier::new_from_device(name, &HashMap::new(), &[], &HashMap::new()),
+    );
+    assert!(dcm.protocol_specializers(&device).is_empty(), "{}", name);
+  }
+}
+
+#[tokio::test]
+async fn test_custom_base_config_version_check() {
+  // User configs are checked against the custom main config's version, not the embedded one.
+  let err = load_protocol_configs_with_base(
+    &BaseConfig::Custom(custom_main_config(7)),
+    &Some(BASE_INVALID_VERSION_CONFIG_JSON.to_owned()),
+    false,
+  )
+  .err()
+  .expect("Mismatched major version should not load.");
+  match err {
+    ButtplugDeviceError::ConfigurationError(ConfigurationError::VersionMismatch {
+      file,
+      internal,
+    }) => {
+      assert_eq!(file, "999.999");
+      assert_eq!(internal, "3.7");
+    }
+    err => panic!("Unexpected error: {:?}", err),
+  }
+
+  // The custom main config is still validated against the schema.
+  let err = load_protocol_configs_with_base(
+    &BaseConfig::Custom("{\"protocols\": []}".to_owned()),
+    &None,
+    false,
+  )
+  .err()
+  .expect("Invalid main config should not load.");
+  assert!(matches!(
+    err,
+    ButtplugDeviceError::ConfigurationError(ConfigurationError::SchemaViolation { .. })
+  ));
+}
+
+#[tokio::test]
+async fn test_schema_violation_reports_error_location() {
+  let main_config = custom_main_config(0).replacen("[0, 100]", "[0]", 1);
+  let err = load_protocol_configs_with_base(&BaseConfig::Custom(main_config), &None, false)
+    .err()
+    .expect("Invalid step range should not load.");
+  match err {
+    ButtplugDeviceError::ConfigurationError(ConfigurationError::SchemaViolation {
+      errors, ..
+    }) => {
+      let pointers: Vec<&str> = errors
+        .iter()
+        .map(|SchemaValidationError { pointer, .. }| pointer.as_str())
+        .collect();
+      assert!(
+        pointers.contains(&"/protocols/aneros/defaults/features/0/actuator/step-range"),
+        "Unexpected error locations: {:?}",
+        pointers
+      );
+    }
+    err => panic!("Unexpected error: {:?}", err),
+  }
+
+  // Protocol fragments report locations relative to the fragment.
+  let mut dcm_builder =
+    load_protocol_configs(&None, &None, false).expect("Test, assuming infallible.");
+  let fragment = PROTOCOL_FRAGMENT_JSON.replacen("[0, 100]", "[0]", 1);
+  match add_protocol_definition_from_json(&mut dcm_builder, "aneros", &fragment) {
+    Err(ButtplugDeviceError::ConfigurationError(
+      ConfigurationError::InvalidProtocolDefinition { error, .. },
+    )) => match *error {
+      ConfigurationError::SchemaViolation { errors, .. } => {
+        assert!(errors
+          .iter()
+          .any(|e| e.pointer == "/defaults/features/0/actuator/step-range"));
+      }
+      err => panic!("Unexpected error: {:?}", err),
+    },
+    Err(err) => panic!("Unexpected error: {:?}", err),
+    Ok(_) => panic!("Invalid step range should not load."),
+  }
+}
+
+#[tokio::test]
+async fn test_user_config_schema_violation_reports_error_location() {
+  let user_config_json = r#"{
+      "version": {
+        "major": 3,
+        "minor": 0
+      },
+      "user-configs": {
+        "devices": [
+          {
+            "identifier": {
+              "protocol": "lovense",
+              "identifier": "P",
+              "address": "UserConfigTest"
+            },
+            "config": {
+              "user-config": {
+                "allow": "yes",
+                "deny": false,
+                "index": 0
+              }
+            }
+          }
+        ]
+      }
+    }
+    "#;
+  match load_protocol_configs(&None, &Some(user_config_json.to_owned()), false) {
+    Err(ButtplugDeviceError::ConfigurationError(ConfigurationError::SchemaViolation {
+      errors,
+      ..
+    })) => {
+      assert!(errors
+        .iter()
+        .any(|e| e.pointer == "/user-configs/devices/0/config/user-config/allow"));
+    }
+    Err(err) => panic!("Unexpected error: {:?}", err),
+    Ok(_) => panic!("Invalid user config should not load."),
+  }
+}
+
+#[tokio::test]
+async fn test_no_base_config() {
+  let dcm = load_protocol_configs_with_base(
+    &BaseConfig::None,
+    &Some(FILE_USER_CONFIG_JSON.to_owned()),
+    false,
+  )
+  .expect("Test, assuming infallible.")
+  .finish()
+  .expect("Test, assuming infallible.");
+  assert!(dcm.protocol_device_configurations().is_empty());
+  let device = ProtocolCommunicationSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device(
+    "LVS-Z001",
+    &HashMap::new(),
+    &[],
+    &HashMap::new(),
+  ));
+  assert!(dcm.protocol_specializers(&device).is_empty());
+}
+
+#[tokio::test]
+async fn test_user_config_serial_override() {
+  let user_config_json = r#"{
+      "version": {
+        "major": 3,
+        "minor": 0
+      },
+      "user-configs": {
+        "protocols": {
+          "tcode-v03": {
+            "communication": [
+              {
+                "serial": {
+                  "port": "default",
+                  "baud-rate": 9600
+                }
+              },
+              {
+                "serial": {
+                  "port": "COM7",
+                  "baud-rate": 115200
+                }
+              }
+            ]
+          }
+        }
+      }
+    }
+    "#;
+  let dcm = load_protocol_configs(&None, &Some(user_config_json.to_owned()), false)
+    .expect("Test, assuming infallible.")
+    .finish()
+    .expect("Test, assuming infallible.");
+
+  // The override is merged into the base specifier for the port, rather than matching on its own.
+  let serial_specifiers = |port: &str| -> Vec<SerialSpecifier> {
+    let device = ProtocolCommunicationSpecifier::Serial(SerialSpecifier::new_from_name(port));
+    dcm
+      .protocol_specializers(&device)
+      .iter()
+      .flat_map(|specializer| specializer.specifiers().clone())
+      .filter_map(|specifier| match specifier {
+        ProtocolCommunicationSpecifier::Serial(serial) if serial.port() == port => Some(serial),
+        _ => None,
+      })
+      .collect()
+  };
+  let default_port = serial_specifiers("default");
+  // nobra, kizuna and tcode-v03 all use the default port in the base config.
+  assert_eq!(default_port.len(), 3);
+  let overridden: Vec<&SerialSpecifier> = default_port
+    .iter()
+    .filter(|serial| *serial.baud_rate() == Some(9600))
+    .collect();
+  assert_eq!(overridden.len(), 1);
+  assert_eq!(*overridden[0].data_bits(), Some(8));
+  assert_eq!(*overridden[0].stop_bits(), Some(1));
+  assert_eq!(*overridden[0].parity(), Some('N'));
+
+  // Entries for other ports are still added as is.
+  let other_port = serial_specifiers("COM7");
+  assert_eq!(other_port.len(), 1);
+  assert_eq!(*other_port[0].baud_rate(), Some(115200));
+  assert_eq!(*other_port[0].data_bits(), None);
+
+  // The user config itself keeps only what was set, so it saves back the same way.
+  let user_specifiers = dcm.user_communication_specifiers();
+  let user_specifiers = user_specifiers
+    .get("tcode-v03")
+    .expect("Test, assuming infallible.");
+  if let ProtocolCommunicationSpecifier::Serial(serial) = &user_specifiers[0] {
+    assert_eq!(*serial.baud_rate(), Some(9600));
+    assert_eq!(*serial.data_bits(), None);
+  } else {
+    panic!("Expected a serial specifier, got {:?}", user_specifiers[0]);
+  }
+}
+
+#[tokio::test]
+async fn test_user_config_duplicate_specifiers() {
+  let communication = r#"[
+      {
+        "btle": {
+          "names": ["Massage Test"],
+          "services": {
+            "0000ff00-0000-1000-8000-00805f9b34fb": {
+              "tx": "0000ff01-0000-1000-8000-00805f9b34fb"
+            }
+          }
+        }
+      },
+      { "serial": { "port": "COM7", "baud-rate": 115200 } }
+    ]"#;
+  let user_config_json = format!(
+    r#"{{
+      "version": {{ "major": 3, "minor": 0 }},
+      "user-configs": {{
+        "protocols": {{ "aneros": {{ "communication": {communication} }} }}
+      }}
+    }}"#
+  );
+  let specifiers: Vec<ProtocolCommunicationSpecifier> =
+    serde_json::from_str(communication).expect("Test, assuming infallible.");
+  let mut builder = load_protocol_configs(&None, &Some(user_config_json), false)
+    .expect("Test, assuming infallible.");
+  // Layer the same user config on top again.
+  builder.user_communication_specifier("aneros", &specifiers);
+  let dcm = builder.finish().expect("Test, assuming infallible.");
+  let user_specifier_count = |dcm: &DeviceConfigurationManager| {
+    dcm
+      .user_communication_specifiers()
+      .get("aneros")
+      .expect("Test, assuming infallible.")
+      .len()
+  };
+  assert_eq!(user_specifier_count(&dcm), 2);
+
+  // Names differing only in case and UUIDs formatted differently are still duplicates.
+  let renamed: ProtocolCommunicationSpecifier = serde_json::from_str(
+    r#"{
+      "btle": {
+        "names": ["MASSAGE TEST"],
+        "services": {
+          "0000FF00-0000-1000-8000-00805F9B34FB": {
+            "tx": "0000ff0100001000800000805f9b34fb"
+          }
+        }
+      }
+    }"#,
+  )
+  .expect("Test, assuming infallible.");
+  dcm
+    .add_user_communication_specifier("aneros", &renamed)
+    .expect("Test, assuming infallible.");
+  assert_eq!(user_specifier_count(&dcm), 2);
+
+  // Serial specifiers for the same port with different settings aren't duplicates.
+  dcm
+    .add_user_communication_specifier(
+      "aneros",
+      &ProtocolCommunicationSpecifier::Serial(SerialSpecifier::new_from_name("COM7")),
+    )
+    .expect("Test, assuming infallible.");
+  assert_eq!(user_specifier_count(&dcm), 3);
+
+  // Saving and reloading doesn't grow the merged config either.
+  let saved = save_user_config(&dcm).expect("Test, assuming infallible.");
+  let mut builder =
+    load_protocol_configs(&None, &Some(saved), false).expect("Test, assuming infallible.");
+  builder.user_communication_specifier("aneros", &specifiers);
+  let reloaded = builder.finish().expect("Test, assuming infallible.");
+  assert_eq!(user_specifier_count(&reloaded), 3);
+}
 
 // TODO Test calculation/change of Step Count via Step Range
+
+fn load_session(user_config: &Option<String>) -> DeviceConfigurationManager {
+  load_protocol_configs(&None, user_config, false)
+    .expect("Test, assuming infallible.")
+    .finish()
+    .expect("Test, assuming infallible.")
+}
+
+fn assigned_index(dcm: &DeviceConfigurationManager, identifier: &UserDeviceIdentifier) -> u32 {
+  dcm
+    .device_definition(identifier, &[])
+    .expect("Test, assuming infallible.")
+    .user_config()
+    .index()
+}
+
+#[tokio::test]
+async fn test_assigned_indexes_persist_across_sessions() {
+  let first = UserDeviceIdentifier::new("IndexTest1", "aneros", &Some("Massage Demo".to_owned()));
+  let second = UserDeviceIdentifier::new("IndexTest2", "aneros", &Some("Massage Demo".to_owned()));
+
+  let dcm = load_session(&None);
+  assert_eq!(assigned_index(&dcm, &first), 0);
+  assert_eq!(assigned_index(&dcm, &second), 1);
+  let saved_config = save_user_config(&dcm).expect("Test, assuming infallible.");
+
+  // Devices showing up in a different order next session still get the same indexes.
+  let dcm = load_session(&Some(saved_config));
+  assert_eq!(assigned_index(&dcm, &second), 1);
+  assert_eq!(assigned_index(&dcm, &first), 0);
+  let definition = dcm
+    .device_definition(&first, &[])
+    .expect("Test, assuming infallible.");
+  assert!(definition.user_config().auto_index());
+}
+
+#[tokio::test]
+async fn test_reserved_index_wins_over_assigned_index() {
+  let assigned =
+    UserDeviceIdentifier::new("IndexTest1", "aneros", &Some("Massage Demo".to_owned()));
+  let reserved =
+    UserDeviceIdentifier::new("IndexTest2", "aneros", &Some("Massage Demo".to_owned()));
+
+  let dcm = load_session(&None);
+  assert_eq!(assigned_index(&dcm, &assigned), 0);
+  // Reserve the same index by hand for another device, like a user editing their config would.
+  let mut definition = dcm
+    .device_definition(&reserved, &[])
+    .expect("Test, assuming infallible.");
+  definition.set_user_config(UserDeviceCustomization::new(&None, false, false, 0));
+  dcm
+    .add_user_device_definition(&reserved, &definition)
+    .expect("Test, assuming infallible.");
+  let saved_config = save_user_config(&dcm).expect("Test, assuming infallible.");
+
+  let dcm = load_session(&Some(saved_config));
+  assert_eq!(assigned_index(&dcm, &reserved), 0);
+  assert_eq!(assigned_index(&dcm, &assigned), 1);
+}
+
+#[tokio::test]
+async fn test_config_info_without_user_config() {
+  let dcm = load_session(&None);
+  let config_info = dcm
+    .config_info()
+    .clone()
+    .expect("Test, assuming infallible.");
+  let embedded_config: serde_json::Value =
+    serde_json::from_str(DEVICE_CONFIGURATION_JSON).expect("Test, assuming infallible.");
+  let version = &embedded_config["version"];
+  let embedded_version = format!("{}.{}", version["major"], version["minor"]);
+  assert_eq!(config_info.bundled_version(), &embedded_version);
+  assert_eq!(config_info.loaded_main_version(), &Some(embedded_version));
+  assert!(!config_info.user_config_present());
+  assert_eq!(
+    config_info.protocol_count(),
+    embedded_config["protocols"]
+      .as_object()
+      .expect("Test, assuming infallible.")
+      .len()
+  );
+  assert_eq!(config_info.user_device_override_count(), 0);
+
+  // No base config means no main version or protocols, but we still know what was bundled.
+  let dcm = load_protocol_configs_with_base(&BaseConfig::None, &None, false)
+    .expect("Test, assuming infallible.")
+    .finish()
+    .expect("Test, assuming infallible.");
+  let config_info = dcm
+    .config_info()
+    .clone()
+    .expect("Test, assuming infallible.");
+  assert_eq!(config_info.loaded_main_version(), &None);
+  assert_eq!(config_info.protocol_count(), 0);
+}
+
+#[tokio::test]
+async fn test_config_info_with_user_config() {
+  let dcm = load_session(&None);
+  for address in ["ConfigInfoTest1", "ConfigInfoTest2"] {
+    let identifier = UserDeviceIdentifier::new(address, "aneros", &Some("Massage Demo".to_owned()));
+    dcm
+      .device_definition(&identifier, &[])
+      .expect("Test, assuming infallible.");
+  }
+  let saved_config = save_user_config(&dcm).expect("Test, assuming infallible.");
+
+  let dcm = load_protocol_configs_with_base(
+    &BaseConfig::Custom(custom_main_config(4)),
+    &Some(saved_config),
+    false,
+  )
+  .expect("Test, assuming infallible.")
+  .finish()
+  .expect("Test, assuming infallible.");
+  let config_info = dcm
+    .config_info()
+    .clone()
+    .expect("Test, assuming infallible.");
+  assert_eq!(config_info.loaded_main_version(), &Some("3.4".to_owned()));
+  assert!(config_info.user_config_present());
+  assert_eq!(config_info.protocol_count(), 2);
+  assert_eq!(config_info.user_device_override_count(), 2);
+  let json = serde_json::to_value(&config_info).expect("Test, assuming infallible.");
+  assert_eq!(json["user_device_override_count"], 2);
+
+  // The running server reports the same info.
+  let server = ButtplugServerBuilder::new(
+    ServerDeviceManagerBuilder::new(dcm)
+      .finish()
+      .expect("Test, assuming infallible."),
+  )
+  .finish()
+  .expect("Test, assuming infallible.");
+  assert_eq!(server.device_config_info(), Some(config_info));
+}
+
+#[tokio::test]
+async fn test_config_info_missing_for_unloaded_config() {
+  let dcm = DeviceConfigurationManager::default();
+  assert_eq!(dcm.config_info(), &None::<ServerDeviceConfigInfo>);
+}
+
+#[tokio::test]
+async fn test_user_config_device_mirror_round_trip() {
+  let source =
+    UserDeviceIdentifier::new("MirrorSource", "aneros", &Some("Massage Demo".to_owned()));
+  let target =
+    UserDeviceIdentifier::new("MirrorTarget", "aneros", &Some("Massage Demo".to_owned()));
+  let mirror = DeviceMirror::new(&target, &[0.5, 0.25]);
+  let dcm = load_session(&None);
+  let mut definition = dcm
+    .device_definition(&source, &[])
+    .expect("Test, assuming infallible.");
+  definition
+    .user_config_mut()
+    .set_mirror(vec![mirror.clone()]);
+  dcm
+    .add_user_device_definition(&source, &definition)
+    .expect("Test, assuming infallible.");
+  let saved_config = save_user_config(&dcm).expect("Test, assuming infallible.");
+
+  let dcm = load_session(&Some(saved_config));
+  let definition = dcm
+    .device_definition(&source, &[])
+    .expect("Test, assuming infallible.");
+  assert_eq!(definition.user_config().mirror(), &vec![mirror]);
+}
+
+fn inheritance_main_config(configurations: &str) -> String {
+  format!(
+    r#"{{
+      "version": {{ "major": 3, "minor": 0 }},
+      "protocols": {{
+        "aneros": {{
+          "defaults": {{
+            "name": "Fake BLE Device",
+            "features": [
+              {{
+                "feature-type": "Vibrate",
+                "actuator": {{
+                  "step-range": [0, 100],
+                  "messages": ["ScalarCmd"]
+                }}
+              }}
+            ]
+          }},
+          "configurations": {configurations},
+          "communication": [
+            {{
+              "btle": {{
+                "names": ["FakeBLEDevice"],
+                "services": {{
+                  "0000ff00-0000-1000-8000-00805f9b34fb": {{
+                    "tx": "0000ff01-0000-1000-8000-00805f9b34fb"
+                  }}
+                }}
+              }}
+            }}
+          ]
+        }}
+      }}
+    }}"#
+  )
+}
+
+const INHERITANCE_CONFIGURATIONS_JSON: &str = r#"
+[
+  {
+    "identifier": ["A"],
+    "name": "Device A",
+    "features": [
+      {
+        "feature-type": "Vibrate",
+        "actuator": {
+          "step-range": [0, 20],
+          "messages": ["ScalarCmd"]
+        }
+      },
+      {
+        "feature-type": "Rotate",
+        "actuator": {
+          "step-range": [0, 20],
+          "messages": ["ScalarCmd"]
+        }
+      }
+    ]
+  },
+  {
+    "identifier": ["B"],
+    "inherits": "A",
+    "name": "Device B"
+  },
+  {
+    "identifier": ["C"],
+    "inherits": "B"
+  },
+  {
+    "identifier": ["D"],
+    "inherits": "A",
+    "features": [
+      {
+        "feature-type": "Oscillate",
+        "actuator": {
+          "step-range": [0, 10],
+          "messages": ["ScalarCmd"]
+        }
+      }
+    ]
+  },
+  {
+    "identifier": ["E"],
+    "inherits": "defaults",
+    "name": "Device E"
+  }
+]
+"#;
+
+fn inherited_definition(
+  dcm: &DeviceConfigurationManager,
+  identifier: &str,
+) -> (String, Vec<ActuatorType>) {
+  let definition = dcm
+    .device_definition(
+      &UserDeviceIdentifier::new("InheritanceTest", "aneros", &Some(identifier.to_owned())),
+      &[],
+    )
+    .expect("Test, assuming infallible.");
+  let actuators = definition
+    .features()
+    .iter()
+    .map(|feature| {
+      ActuatorType::try_from(*feature.feature_type()).expect("Test, assuming infallible.")
+    })
+    .collect();
+  (definition.name().clone(), actuators)
+}
+
+#[tokio::test]
+async fn test_protocol_configuration_inheritance_chain() {
+  let dcm = load_protocol_configs_with_base(
+    &BaseConfig::Custom(inheritance_main_config(INHERITANCE_CONFIGURATIONS_JSON)),
+    &None,
+    false,
+  )
+  .expect("Test, assuming infallible.")
+  .finish()
+  .expect("Test, assuming infallible.");
+
+  let a_features = vec![ActuatorType::Vibrate, ActuatorType::Rotate];
+  assert_eq!(
+    inherited_definition(&dcm, "A"),
+    ("Device A".to_owned(), a_features.clone())
+  );
+  // B only overrides the name, C only inherits from B, so both end up with A's features.
+  assert_eq!(
+    inherited_definition(&dcm, "B"),
+    ("Device B".to_owned(), a_features.clone())
+  );
+  assert_eq!(
+    inherited_definition(&dcm, "C"),
+    ("Device B".to_owned(), a_features)
+  );
+  // D only overrides the features, and keeps A's name.
+  assert_eq!(
+    inherited_definition(&dcm, "D"),
+    ("Device A".to_owned(), vec![ActuatorType::Oscillate])
+  );
+  // Inheriting from defaults explicitly is the same as not inheriting at all.
+  assert_eq!(
+    inherited_definition(&dcm, "E"),
+    ("Device E".to_owned(), vec![ActuatorType::Vibrate])
+  );
+}
+
+#[tokio::test]
+async fn test_protocol_configuration_inheritance_cycle() {
+  let configurations = r#"
+    [
+      { "identifier": ["X"], "inherits": "Y", "name": "Device X" },
+      { "identifier": ["Y"], "inherits": "X" }
+    ]
+  "#;
+  let err = load_protocol_configs_with_base(
+    &BaseConfig::Custom(inheritance_main_config(configurations)),
+    &None,
+    false,
+  )
+  .err()
+  .expect("Inheritance cycle should not load.");
+  assert!(
+    matches!(
+      err,
+      ButtplugDeviceError::ConfigurationError(ConfigurationError::InheritanceCycle {
+        ref protocol,
+        ..
+      }) if protocol == "aneros"
+    ),
+    "{:?}",
+    err
+  );
+}
+
+#[tokio::test]
+async fn test_protocol_configuration_unknown_parent() {
+  let configurations = r#"[{ "identifier": ["X"], "inherits": "Missing" }]"#;
+  let err = load_protocol_configs_with_base(
+    &BaseConfig::Custom(inheritance_main_config(configurations)),
+    &None,
+    false,
+  )
+  .err()
+  .expect("Unknown parent should not load.");
+  assert!(
+    matches!(
+      err,
+      ButtplugDeviceError::ConfigurationError(
+        ConfigurationError::UnknownInheritanceParent { ref parent, .. }
+      ) if parent == "Missing"
+    ),
+    "{:?}",
+    err
+  );
+}
+
+fn xinput_main_config(xinput_specifier: &str) -> String {
+  format!(
+    r#"{{
+      "version": {{ "major": 3, "minor": 0 }},
+      "protocols": {{
+        "xinput": {{
+          "defaults": {{
+            "name": "XBox (XInput) Compatible Gamepad",
+            "features": [
+              {{
+                "feature-type": "Vibrate",
+                "actuator": {{
+                  "step-range": [0, 65535],
+                  "messages": ["ScalarCmd"]
+                }}
+              }}
+            ]
+          }},
+          "communication": [{{ "xinput": {xinput_specifier} }}]
+        }}
+      }}
+    }}"#
+  )
+}
+
+#[tokio::test]
+async fn test_xinput_specifier_slots_and_rescan_interval() {
+  let dcm = load_protocol_configs_with_base(
+    &BaseConfig::Custom(xinput_main_config(
+      r#"{ "exists": true, "slots": [1], "rescan-interval-ms": 250 }"#,
+    )),
+    &None,
+    false,
+  )
+  .expect("Test, assuming infallible.")
+  .finish()
+  .expect("Test, assuming infallible.");
+  let specifiers = dcm.protocol_device_configurations();
+  let specifier = match &specifiers["xinput"][..] {
+    [ProtocolCommunicationSpecifier::XInput(specifier)] => specifier.clone(),
+    specifiers => panic!("Unexpected specifiers: {:?}", specifiers),
+  };
+  assert_eq!(specifier.slots(), &Some(vec![1]));
+  assert_eq!(specifier.rescan_interval_ms(), Some(250));
+  assert!(!specifier.exposes_slot(0));
+  assert!(specifier.exposes_slot(1));
+  assert!(XInputSpecifier::default().exposes_slot(0));
+
+  // Only the 4 XInput slots exist.
+  let err = load_protocol_configs_with_base(
+    &BaseConfig::Custom(xinput_main_config(r#"{ "exists": true, "slots": [4] }"#)),
+    &None,
+    false,
+  )
+  .err()
+  .expect("Invalid slot should not load.");
+  assert!(matches!(
+    err,
+    ButtplugDeviceError::ConfigurationError(ConfigurationError::SchemaViolation { .. })
+  ));
+}
+
+fn lovense_connect_service_user_config(lovense_specifier: &str) -> String {
+  format!(
+    r#"{{
+      "version": {{ "major": 3, "minor": 0 }},
+      "user-configs": {{
+        "protocols": {{
+          "lovense-connect-service": {{
+            "communication": [{{ "lovense-connect-service": {lovense_specifier} }}]
+          }}
+        }}
+      }}
+    }}"#
+  )
+}
+
+#[tokio::test]
+async fn test_lovense_connect_service_specifier_user_config() {
+  let specifier = load_session(&None).lovense_connect_service_specifier();
+  assert_eq!(specifier.allowed_hosts(), &None);
+  assert_eq!(specifier.poll_interval_ms(), None);
+  assert!(specifier.allows_host("192.168.1.10"));
+
+  let dcm = load_session(&Some(lovense_connect_service_user_config(
+    r#"{ "exists": true, "allowed-hosts": ["192.168.1.10"], "poll-interval-ms": 30000 }"#,
+  )));
+  let specifier = dcm.lovense_connect_service_specifier();
+  assert_eq!(
+    specifier.allowed_hosts(),
+    &Some(vec!["192.168.1.10".to_owned()])
+  );
+  assert_eq!(specifier.poll_interval_ms(), Some(30000));
+  assert!(specifier.allows_host("192.168.1.10"));
+  assert!(!specifier.allows_host("192.168.1.20"));
+
+  // The settings survive a save and reload.
+  let saved_config = save_user_config(&dcm).expect("Test, assuming infallible.");
+  let reloaded_specifier = load_session(&Some(saved_config)).lovense_connect_service_specifier();
+  assert!(reloaded_specifier.is_equivalent(&specifier));
+
+  // Specifiers with different settings aren't merged away as duplicates.
+  assert!(!specifier.is_equivalent(&LovenseConnectServiceSpecifier::default()));
+  assert!(
+    specifier.is_equivalent(&LovenseConnectServiceSpecifier::new(
+      &Some(vec!["192.168.1.10".to_owned(), "192.168.1.10".to_owned()]),
+      Some(30000)
+    ))
+  );
+
+  let err = load_protocol_configs(
+    &None,
+    &Some(lovense_connect_service_user_config(
+      r#"{ "exists": true, "poll-interval-ms": 0 }"#,
+    )),
+    false,
+  )
+  .err()
+  .expect("Invalid poll interval should not load.");
+  assert!(matches!(
+    err,
+    ButtplugDeviceError::ConfigurationError(ConfigurationError::SchemaViolation { .. })
+  ));
+}
+
+#[tokio::test]
+async fn test_user_config_protocol_display_name_round_trip() {
+  let user_config = r#"
+    {
+      "version": { "major": 3, "minor": 0 },
+      "user-configs": {
+        "protocols": {
+          "aneros": { "display-name": "Aneros #{index}" }
+        }
+      }
+    }
+  "#;
+  let dcm = load_session(&Some(user_config.to_owned()));
+  assert_eq!(
+    dcm
+      .user_protocol_display_names()
+      .get("aneros")
+      .map(|template| template.value().clone()),
+    Some("Aneros #{index}".to_owned())
+  );
+  let saved_config = save_user_config(&dcm).expect("Test, assuming infallible.");
+  let dcm = load_session(&Some(saved_config));
+  assert_eq!(
+    dcm
+      .user_protocol_display_names()
+      .get("aneros")
+      .map(|template| template.value().clone()),
+    Some("Aneros #{index}".to_owned())
+  );
+}
+
+#[tokio::test]
+async fn test_user_config_disabled_protocols_round_trip() {
+  let user_config = r#"
+    {
+      "version": { "major": 3, "minor": 0 },
+      "user-configs": {
+        "disabled-protocols": ["lovense"]
+      }
+    }
+  "#;
+  let dcm = load_session(&Some(user_config.to_owned()));
+  assert!(!dcm.protocol_enabled("lovense"));
+  assert!(dcm.protocol_enabled("aneros"));
+  assert!(!dcm.enabled_protocols().contains(&"lovense".to_owned()));
+  assert_eq!(dcm.disabled_protocols(), vec!["lovense".to_owned()]);
+
+  dcm
+    .disable_protocol("aneros")
+    .expect("Test, assuming infallible.");
+  assert!(dcm.disable_protocol("not-a-protocol").is_err());
+  let saved_config = save_user_config(&dcm).expect("Test, assuming infallible.");
+  let dcm = load_session(&Some(saved_config));
+  assert_eq!(
+    dcm.disabled_protocols(),
+    vec!["aneros".to_owned(), "lovense".to_owned()]
+  );
+
+  dcm.enable_protocol("lovense");
+  dcm.enable_protocol("aneros");
+  assert!(dcm.disabled_protocols().is_empty());
+  assert!(!save_user_config(&dcm)
+    .expect("Test, assuming infallible.")
+    .contains("disabled-protocols"));
+}
+
+#[tokio::test]
+async fn test_protocol_views() {
+  let dcm = load_session(&None);
+  let names: Vec<&str> = dcm.protocols().map(|view| view.name()).collect();
+  let mut sorted_names = names.clone();
+  sorted_names.sort();
+  assert_eq!(names, sorted_names);
+  assert_eq!(names.len(), dcm.protocol_device_configurations().len());
+
+  let lovense = dcm
+    .protocols()
+    .find(|view| view.name() == "lovense")
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    lovense.specifiers(),
+    dcm.protocol_device_configurations()["lovense"].as_slice()
+  );
+  assert_eq!(
+    lovense
+      .defaults()
+      .expect("Test, assuming infallible.")
+      .name(),
+    "Lovense Device"
+  );
+  assert_eq!(
+    lovense
+      .configuration("P")
+      .expect("Test, assuming infallible.")
+      .name(),
+    "Lovense Edge"
+  );
+  // Identifiers sharing a configuration each get their own entry.
+  assert_eq!(
+    lovense
+      .configuration("A")
+      .map(|definition| definition.name()),
+    lovense
+      .configuration("C")
+      .map(|definition| definition.name())
+  );
+  assert!(lovense.configuration("Not A Lovense").is_none());
+  assert!(lovense
+    .configurations()
+    .windows(2)
+    .all(|pair| pair[0].0 < pair[1].0));
+}
+
+#[tokio::test]
+async fn test_device_override_views() {
+  let dcm = load_session(&None);
+  assert_eq!(dcm.device_overrides().count(), 0);
+  let identifier = UserDeviceIdentifier::new("OverrideViewTest", "lovense", &Some("P".to_owned()));
+  let definition = dcm
+    .device_definition(&identifier, &[])
+    .expect("Test, assuming infallible.");
+  let user_config = UserDeviceCustomization::new(
+    &Some("My Edge".to_owned()),
+    false,
+    true,
+    definition.user_config().index(),
+  );
+  dcm
+    .add_user_device_definition(
+      &identifier,
+      &UserDeviceDefinition::new(definition.name(), definition.features(), &user_config),
+    )
+    .expect("Test, assuming infallible.");
+
+  let overrides: Vec<_> = dcm.device_overrides().collect();
+  assert_eq!(overrides.len(), 1);
+  let view = &overrides[0];
+  assert_eq!(view.identifier(), &identifier);
+  assert_eq!(view.display_name(), Some("My Edge"));
+  assert!(view.deny());
+  assert!(!view.allow());
+  assert_eq!(view.index(), user_config.index());
+  assert_eq!(view.features().len(), definition.features().len());
+}
+
+#[tokio::test]
+async fn test_sensor_units() {
+  let dcm = load_session(&None);
+  let galaku = dcm
+    .protocols()
+    .find(|view| view.name() == "galaku")
+    .expect("Test, assuming infallible.");
+  let battery = galaku
+    .defaults()
+    .expect("Test, assuming infallible.")
+    .features()
+    .iter()
+    .find(|feature| *feature.feature_type() == FeatureType::Battery)
+    .and_then(|feature| feature.sensor().clone())
+    .expect("Test, assuming infallible.");
+  assert_eq!(*battery.unit(), Some(SensorUnit::Percent));
+
+  // Units in user configs load, and are saved again.
+  let pressure_feature = r#"{
+    "feature-type": "Pressure",
+    "sensor": {
+      "value-range": [[0, 4095]],
+      "unit": "Gram",
+      "messages": ["SensorReadCmd"]
+    }
+  }"#;
+  let user_config_json = FILE_USER_CONFIG_JSON.replace(
+    r#""features": ["#,
+    &format!(r#""features": [{},"#, pressure_feature),
+  );
+  let dcm = load_session(&Some(user_config_json.clone()));
+  let identifier = UserDeviceIdentifier::new("FileConfigTest", "lovense", &Some("B".to_owned()));
+  let unit = |dcm: &DeviceConfigurationManager| {
+    dcm
+      .user_device_definitions()
+      .get(&identifier)
+      .expect("Test, assuming infallible.")
+      .features()[0]
+      .sensor()
+      .as_ref()
+      .and_then(|sensor| *sensor.unit())
+  };
+  assert_eq!(unit(&dcm), Some(SensorUnit::Gram));
+  let saved = save_user_config(&dcm).expect("Test, assuming infallible.");
+  assert_eq!(unit(&load_session(&Some(saved))), Some(SensorUnit::Gram));
+
+  // Only known units pass the schema.
+  let err = load_protocol_configs(
+    &None,
+    &Some(user_config_json.replace("\"Gram\"", "\"Kilogram\"")),
+    false,
+  )
+  .err()
+  .expect("Unknown unit should not load.");
+  assert!(matches!(
+    err,
+    ButtplugDeviceError::ConfigurationError(ConfigurationError::SchemaViolation { .. })
+  ));
+}
+
+fn matching_specifiers(
+  dcm: &DeviceConfigurationManager,
+  specifier: &ProtocolCommunicationSpecifier,
+) -> Vec<ProtocolCommunicationSpecifier> {
+  dcm
+    .protocol_specializers(specifier)
+    .iter()
+    .flat_map(|specializer| specializer.specifiers().clone())
+    .collect()
+}
+
+#[tokio::test]
+async fn test_protocol_configuration_export_round_trip() {
+  let user_config = r#"
+    {
+      "version": { "major": 3, "minor": 0 },
+      "user-configs": {
+        "protocols": {
+          "aneros": {
+            "communication": [
+              {
+                "btle": {
+                  "names": ["Massage Test"],
+                  "services": {
+                    "0000ff00-0000-1000-8000-00805f9b34fb": {
+                      "tx": "0000ff01-0000-1000-8000-00805f9b34fb"
+                    }
+                  }
+                }
+              }
+            ],
+            "display-name": "Aneros #{index}"
+          },
+          "tcode-v03": {
+            "communication": [ { "serial": { "port": "default", "baud-rate": 9600 } } ]
+          }
+        }
+      }
+    }
+  "#;
+  let mut user_config: serde_json::Value =
+    serde_json::from_str(user_config).expect("Test, assuming infallible.");
+  let file_user_config: serde_json::Value =
+    serde_json::from_str(FILE_USER_CONFIG_JSON).expect("Test, assuming infallible.");
+  user_config["user-configs"]["devices"] = file_user_config["user-configs"]["devices"].clone();
+  let dcm = load_session(&Some(user_config.to_string()));
+
+  let export = ProtocolConfiguration::from_json(&dcm.to_protocol_configuration().to_json())
+    .expect("Test, assuming infallible.");
+  assert!(export.runtime_only_protocols().is_empty());
+  let reloaded = export
+    .load(false)
+    .expect("Test, assuming infallible.")
+    .finish()
+    .expect("Test, assuming infallible.");
+
+  let ble = |name: &str| {
+    ProtocolCommunicationSpecifier::BluetoothLE(BluetoothLESpecifier::new_from_device(
+      name,
+      &HashMap::new(),
+      &[],
+      &HashMap::new(),
+    ))
+  };
+  for specifier in [
+    ble("LVS-Z36"),
+    ble("Massage Demo"),
+    ble("Massage Test"),
+    ble("Not A Device"),
+    ProtocolCommunicationSpecifier::Serial(SerialSpecifier::new_from_name("default")),
+  ] {
+    let specifiers = matching_specifiers(&dcm, &specifier);
+    let reloaded_specifiers = matching_specifiers(&reloaded, &specifier);
+    assert_eq!(
+      specifiers.len(),
+      reloaded_specifiers.len(),
+      "{:?}",
+      specifier
+    );
+    assert!(
+      specifiers
+        .iter()
+        .all(|specifier| reloaded_specifiers.contains(specifier)),
+      "{:?}",
+      specifier
+    );
+  }
+  assert_eq!(
+    matching_specifiers(&reloaded, &ble("Massage Test")).len(),
+    1
+  );
+
+  for identifier in [
+    UserDeviceIdentifier::new("FileConfigTest", "lovense", &Some("B".to_owned())),
+    UserDeviceIdentifier::new("Edge", "lovense", &Some("P".to_owned())),
+    UserDeviceIdentifier::new("Unknown", "lovense", &Some("Unknown".to_owned())),
+    UserDeviceIdentifier::new("Aneros", "aneros", &Some("Massage Demo".to_owned())),
+  ] {
+    let definition = |dcm: &DeviceConfigurationManager| {
+      serde_json::to_value(
+        dcm
+          .device_definition(&identifier, &[])
+          .expect("Test, assuming infallible."),
+      )
+      .expect("Test, assuming infallible.")
+    };
+    assert_eq!(definition(&dcm), definition(&reloaded), "{}", identifier);
+  }
+
+  // Every protocol comes back with the same specifiers and device definitions.
+  assert_eq!(dcm.protocols().count(), reloaded.protocols().count());
+  for (protocol, reloaded_protocol) in dcm.protocols().zip(reloaded.protocols()) {
+    assert_eq!(protocol.name(), reloaded_protocol.name());
+    assert_eq!(
+      protocol.specifiers().len(),
+      reloaded_protocol.specifiers().len(),
+      "{}",
+      protocol.name()
+    );
+    let definitions = |protocol: &ProtocolView| {
+      protocol
+        .defaults()
+        .into_iter()
+        .map(|definition| ("", definition))
+        .chain(protocol.configurations().iter().copied())
+        .map(|(identifier, definition)| {
+          (
+            identifier.to_owned(),
+            definition.name().clone(),
+            definition.features().clone(),
+          )
+        })
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(
+      definitions(&protocol),
+      definitions(&reloaded_protocol),
+      "{}",
+      protocol.name()
+    );
+  }
+  assert_eq!(
+    reloaded
+      .user_protocol_display_names()
+      .get("aneros")
+      .map(|template| template.value().clone()),
+    Some("Aneros #{index}".to_owned())
+  );
+}
+
+#[tokio::test]
+async fn test_protocol_configuration_redacts_websocket_tokens() {
+  let dcm = load_session(&None);
+  let mut specifier = WebsocketSpecifier::new("Massage Demo");
+  specifier.set_url(Some("ws://127.0.0.1:51285".to_owned()));
+  specifier.set_token(Some("export-secret".to_owned()));
+  dcm
+    .add_user_communication_specifier(
+      "aneros",
+      &ProtocolCommunicationSpecifier::Websocket(specifier),
+    )
+    .expect("Test, assuming infallible.");
+  let export = dcm.to_protocol_configuration();
+  let json = export.to_json();
+  assert!(!json.contains("export-secret"));
+  assert!(json.contains("<redacted>"));
+  #[cfg(feature = "serialize-cbor")]
+  assert!(!export
+    .to_cbor()
+    .windows("export-secret".len())
+    .any(|window| window == b"export-secret"));
+  // Saved user configs still need the token to connect.
+  assert!(save_user_config(&dcm)
+    .expect("Test, assuming infallible.")
+    .contains("export-secret"));
+}
+
+#[cfg(feature = "serialize-cbor")]
+#[tokio::test]
+async fn test_protocol_configuration_cbor_round_trip() {
+  let dcm = load_session(&Some(FILE_USER_CONFIG_JSON.to_owned()));
+  let export = dcm.to_protocol_configuration();
+  let decoded =
+    ProtocolConfiguration::from_cbor(&export.to_cbor()).expect("Test, assuming infallible.");
+  // Names and messages are sets, so their order changes between encodings.
+  fn sort_arrays(value: &mut serde_json::Value) {
+    match value {
+      serde_json::Value::Array(values) => {
+        values.iter_mut().for_each(sort_arrays);
+        values.sort_by_key(|value| value.to_string());
+      }
+      serde_json::Value::Object(values) => values.values_mut().for_each(sort_arrays),
+      _ => {}
+    }
+  }
+  let as_value = |config: &ProtocolConfiguration| {
+    let mut value = serde_json::from_str(&config.to_json()).expect("Test, assuming infallible.");
+    sort_arrays(&mut value);
+    value
+  };
+  assert_eq!(as_value(&export), as_value(&decoded));
+  decoded
+    .load(false)
+    .expect("Test, assuming infallible.")
+    .finish()
+    .expect("Test, assuming infallible.");
+
+  assert!(matches!(
+    ProtocolConfiguration::from_cbor(&[0xff, 0x00]),
+    Err(ButtplugDeviceError::ConfigurationError(
+      ConfigurationError::SerdeError { .. }
+    ))
+  ));
+}
+
+#[derive(Default)]
+struct CustomProtocolFactory {
+  factory: aneros::setup::AnerosIdentifierFactory,
+}
+
+impl ProtocolIdentifierFactory for CustomProtocolFactory {
+  fn identifier(&self) -> &str {
+    "custom-protocol"
+  }
+
+  fn create(&self) -> Box<dyn ProtocolIdentifier> {
+    self.factory.create()
+  }
+}
+
+#[tokio::test]
+async fn test_protocol_configuration_export_marks_runtime_only_protocols() {
+  let mut builder = load_protocol_configs(&None, &None, false).expect("Test, assuming infallible.");
+  add_protocol_definition_from_json(&mut builder, "custom-protocol", PROTOCOL_FRAGMENT_JSON)
+    .expect("Test, assuming infallible.");
+  builder.protocol_factory(CustomProtocolFactory::default());
+  let dcm = builder.finish().expect("Test, assuming infallible.");
+  let identifier = BaseDeviceIdentifier::new("custom-protocol", &None);
+  assert!(dcm.has_base_device_definition(&identifier));
+
+  let export = ProtocolConfiguration::from_json(&dcm.to_protocol_configuration().to_json())
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    export.runtime_only_protocols(),
+    &["custom-protocol".to_owned()]
+  );
+  assert!(export.base_config_json().contains("Fake BLE Device"));
+
+  // Without the factory, the reloaded configuration can't use the protocol's definitions.
+  let reloaded = export
+    .load(false)
+    .expect("Test, assuming infallible.")
+    .finish()
+    .expect("Test, assuming infallible.");
+  assert!(!reloaded.has_base_device_definition(&identifier));
+  assert!(reloaded
+    .to_protocol_configuration()
+    .runtime_only_protocols()
+    .is_empty());
+}
+
+#[test]
+fn test_supported_protocols() {
+  let protocols = supported_protocols();
+  for protocol in ["dg-lab-v2", "dg-lab-v3", "galaku", "galaku-pump", "lovense"] {
+    assert!(protocols.contains(&protocol), "{protocol} missing");
+  }
+  assert!(!protocols.contains(&"not-a-real-protocol"));
+  assert!(protocols.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[cfg(feature = "server")]
+#[tokio::test]
+async fn test_unimplemented_protocols_in_user_config() {
+  let dcm = load_protocol_configs(&None, &None, false)
+    .expect("Test, assuming infallible.")
+    .finish()
+    .expect("Test, assuming infallible.");
+  let base_unimplemented = dcm.unimplemented_protocols().clone();
+  assert!(!base_unimplemented.contains(&"lovense".to_owned()));
+  assert!(!base_unimplemented.contains(&"not-a-real-protocol".to_owned()));
+
+  let user_config = FILE_USER_CONFIG_JSON.replace("\"lovense\"", "\"not-a-real-protocol\"");
+  let dcm = load_protocol_configs(&None, &Some(user_config), false)
+    .expect("Test, assuming infallible.")
+    .finish()
+    .expect("Test, assuming infallible.");
+  let mut expected = base_unimplemented;
+  expected.push("not-a-real-protocol".to_owned());
+  expected.sort();
+  assert_eq!(dcm.unimplemented_protocols(), &expected);
+  assert_eq!(dcm.user_device_definitions().len(), 0);
+}
+
+const INTIFACE_DEVICE_SETTINGS_JSON: &str =
+  include_str!("util/device_test/device_test_case/config/intiface_device_settings.json");
+const INTIFACE_DEVICE_SETTINGS_UNSUPPORTED_JSON: &str = include_str!(
+  "util/device_test/device_test_case/config/intiface_device_settings_unsupported.json"
+);
+
+fn imported_user_config(
+  dcm: &DeviceConfigurationManager,
+  identifier: &UserDeviceIdentifier,
+) -> UserDeviceCustomization {
+  dcm
+    .user_device_definitions()
+    .get(identifier)
+    .expect("Test, assuming infallible.")
+    .user_config()
+    .clone()
+}
+
+#[tokio::test]
+async fn test_import_intiface_device_settings() {
+  let imported = import_intiface_device_settings(INTIFACE_DEVICE_SETTINGS_JSON)
+    .expect("Test, assuming infallible.");
+  assert!(imported.warnings().is_empty());
+  let dcm = load_session(&Some(imported.to_json()));
+  assert_eq!(dcm.device_overrides().count(), 3);
+
+  let edge = UserDeviceIdentifier::new("C8:AB:12:34:56:78", "lovense", &Some("P".to_owned()));
+  let edge_config = imported_user_config(&dcm, &edge);
+  assert_eq!(edge_config.display_name(), &Some("Bedroom Edge".to_owned()));
+  assert_eq!(edge_config.access(), DeviceAccess::Default);
+  assert_eq!(edge_config.index(), 3);
+  assert!(!edge_config.auto_index());
+  // Features come from the base config.
+  assert_eq!(
+    dcm.user_device_definitions().get(&edge).unwrap().features(),
+    load_session(&None)
+      .device_definition(&edge, &[])
+      .unwrap()
+      .features()
+  );
+
+  let aneros = UserDeviceIdentifier::new(
+    "IntifaceImportTest",
+    "aneros",
+    &Some("Massage Demo".to_owned()),
+  );
+  let aneros_config = imported_user_config(&dcm, &aneros);
+  assert_eq!(aneros_config.display_name(), &None);
+  assert_eq!(aneros_config.access(), DeviceAccess::Deny);
+  assert_eq!(aneros_config.index(), 0);
+
+  // Devices without an index get the first free one, and keep it until a user reserves it.
+  let machine = UserDeviceIdentifier::new("C8:AB:12:34:56:79", "lovense", &Some("F".to_owned()));
+  let machine_config = imported_user_config(&dcm, &machine);
+  assert_eq!(machine_config.display_name(), &None);
+  assert_eq!(machine_config.access(), DeviceAccess::Default);
+  assert_eq!(machine_config.index(), 1);
+  assert!(machine_config.auto_index());
+}
+
+#[tokio::test]
+async fn test_import_intiface_device_settings_unsupported() {
+  let imported = import_intiface_device_settings(INTIFACE_DEVICE_SETTINGS_UNSUPPORTED_JSON)
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    imported.warnings(),
+    &[
+      "Setting theme isn't supported, skipped.",
+      "Device lovense/P@C8:AB:12:34:56:78: setting favorite isn't supported, skipped.",
+      "Device lovense/F@C8:AB:12:34:56:79: index 1 is already used by another device, assigning \
+       a new index.",
+      "Device not-a-protocol@IntifaceImportTest has no device config, skipped.",
+      "Device lovense/P@C8:AB:12:34:56:78 is listed more than once, skipped.",
+    ]
+  );
+  let dcm = load_session(&Some(imported.to_json()));
+  assert_eq!(dcm.device_overrides().count(), 2);
+  let edge = UserDeviceIdentifier::new("C8:AB:12:34:56:78", "lovense", &Some("P".to_owned()));
+  let edge_config = imported_user_config(&dcm, &edge);
+  assert_eq!(edge_config.display_name(), &Some("Bedroom Edge".to_owned()));
+  assert_eq!(edge_config.index(), 1);
+  let machine = UserDeviceIdentifier::new("C8:AB:12:34:56:79", "lovense", &Some("F".to_owned()));
+  let machine_config = imported_user_config(&dcm, &machine);
+  assert_eq!(machine_config.index(), 0);
+  assert!(machine_config.auto_index());
+}
+
+#[tokio::test]
+async fn test_import_intiface_device_settings_invalid_json() {
+  // Entries need at least a protocol and an address.
+  let err = import_intiface_device_settings(r#"{ "deviceSettings": [{ "protocol": "lovense" }] }"#)
+    .expect_err("Incomplete device settings should not import.");
+  assert!(matches!(
+    err,
+    ButtplugError::ButtplugDeviceError(ButtplugDeviceError::ConfigurationError(
+      ConfigurationError::SerdeError { .. }
+    ))
+  ));
+}
+
+fn lint_config(protocol_json: &str) -> Vec<ConfigLint> {
+  validate_external_config(&format!(
+    r#"{{
+      "version": {{ "major": 3, "minor": 0 }},
+      "protocols": {{ "aneros": {protocol_json} }}
+    }}"#
+  ))
+}
+
+fn assert_single_lint(lints: &[ConfigLint], severity: ConfigLintSeverity, location: &str) {
+  assert_eq!(lints.len(), 1, "{:?}", lints);
+  assert_eq!(lints[0].severity(), severity);
+  assert_eq!(lints[0].location(), location);
+}
+
+#[test]
+fn test_lint_clean_config() {
+  assert!(lint_config(PROTOCOL_FRAGMENT_JSON).is_empty());
+}
+
+#[test]
+fn test_lint_unknown_message_type() {
+  let lints = lint_config(&PROTOCOL_FRAGMENT_JSON.replace("\"ScalarCmd\"", "\"VibrateCmd\""));
+  assert_single_lint(
+    &lints,
+    ConfigLintSeverity::Error,
+    "protocols.aneros.defaults.features[0].actuator.messages[0]",
+  );
+  assert!(lints[0].message().contains("VibrateCmd"));
+
+  // Known messages in the wrong place, or on a feature type that can't handle them, also lint.
+  let lints = lint_config(&PROTOCOL_FRAGMENT_JSON.replace("\"ScalarCmd\"", "\"SensorReadCmd\""));
+  assert_single_lint(
+    &lints,
+    ConfigLintSeverity::Error,
+    "protocols.aneros.defaults.features[0].actuator.messages[0]",
+  );
+  let lints =
+    lint_config(&PROTOCOL_FRAGMENT_JSON.replace("\"ScalarCmd\"", "\"ScalarCmd\", \"LinearCmd\""));
+  assert_single_lint(
+    &lints,
+    ConfigLintSeverity::Warning,
+    "protocols.aneros.defaults.features[0].actuator.messages[1]",
+  );
+}
+
+#[test]
+fn test_lint_attributes_without_specifiers() {
+  let mut protocol: serde_json::Value =
+    serde_json::from_str(PROTOCOL_FRAGMENT_JSON).expect("Test, assuming infallible.");
+  let definition = protocol
+    .as_object_mut()
+    .expect("Test, assuming infallible.");
+  definition.remove("communication");
+  assert_single_lint(
+    &lint_config(&protocol.to_string()),
+    ConfigLintSeverity::Warning,
+    "protocols.aneros",
+  );
+
+  let mut protocol: serde_json::Value =
+    serde_json::from_str(PROTOCOL_FRAGMENT_JSON).expect("Test, assuming infallible.");
+  let definition = protocol
+    .as_object_mut()
+    .expect("Test, assuming infallible.");
+  definition.remove("defaults");
+  assert_single_lint(
+    &lint_config(&protocol.to_string()),
+    ConfigLintSeverity::Error,
+    "protocols.aneros",
+  );
+}
+
+#[test]
+fn test_lint_zero_step_count() {
+  let lints = lint_config(&PROTOCOL_FRAGMENT_JSON.replace("[0, 100]", "[20, 20]"));
+  assert_single_lint(
+    &lints,
+    ConfigLintSeverity::Error,
+    "protocols.aneros.defaults.features[0].actuator.step-range",
+  );
+
+  // The step limit is what clients see, so a usable step range doesn't help.
+  let lints =
+    lint_config(&PROTOCOL_FRAGMENT_JSON.replace("[0, 100]", "[0, 100], \"step-limit\": [50, 50]"));
+  assert_single_lint(
+    &lints,
+    ConfigLintSeverity::Error,
+    "protocols.aneros.defaults.features[0].actuator.step-limit",
+  );
+}
+
+#[test]
+fn test_lint_unimplemented_protocols() {
+  let lints = validate_external_config(&format!(
+    r#"{{
+      "version": {{ "major": 3, "minor": 0 }},
+      "protocols": {{ "not-a-protocol": {PROTOCOL_FRAGMENT_JSON} }}
+    }}"#
+  ));
+  assert_single_lint(
+    &lints,
+    ConfigLintSeverity::Error,
+    "protocols.not-a-protocol",
+  );
+
+  let lints = validate_external_config(
+    r#"{
+      "version": { "major": 3, "minor": 0 },
+      "user-configs": {
+        "devices": [
+          {
+            "identifier": { "address": "LintTest", "protocol": "aneros" },
+            "config": { "index": 0, "allow": false, "deny": false }
+          },
+          {
+            "identifier": { "address": "LintTest", "protocol": "not-a-protocol" },
+            "config": { "index": 1, "allow": false, "deny": false }
+          }
+        ]
+      }
+    }"#,
+  );
+  assert_single_lint(
+    &lints,
+    ConfigLintSeverity::Error,
+    "user-configs.devices[1].identifier",
+  );
+}
+
+#[test]
+fn test_lint_invalid_json() {
+  assert_single_lint(
+    &validate_external_config("{ not json"),
+    ConfigLintSeverity::Error,
+    "(root)",
+  );
+}
+
+#[test]
+fn test_protocol_capabilities() {
+  let capabilities = protocol_capabilities();
+  let capability = |protocol: &str| {
+    capabilities
+      .iter()
+      .find(|capability| capability.protocol() == protocol)
+      .expect("Test, assuming infallible.")
+  };
+
+  let dg_lab_v3 = capability("dg-lab-v3");
+  for actuator_type in [
+    ActuatorType::Vibrate,
+    ActuatorType::Oscillate,
+    ActuatorType::Inflate,
+  ] {
+    assert!(dg_lab_v3.actuator_types().contains(&actuator_type));
+  }
+  assert!(dg_lab_v3.supports_scalar());
+  assert!(dg_lab_v3.supports_linear());
+  assert!(!dg_lab_v3.supports_rotate());
+  assert!(dg_lab_v3.supports_raw());
+
+  let galaku = capability("galaku");
+  assert!(galaku.sensor_types().contains(&SensorType::Battery));
+  assert!(galaku.actuator_types().contains(&ActuatorType::Vibrate));
+  assert!(galaku.supports_linear());
+  assert!(!galaku.supports_rotate());
+
+  // Protocols only found over XInput can't take raw messages.
+  assert!(!capability("xinput").supports_raw());
+
+  // Every implemented protocol is listed once, in order.
+  let protocols: Vec<&str> = capabilities
+    .iter()
+    .map(|capability| capability.protocol().as_str())
+    .collect();
+  let mut sorted = protocols.clone();
+  sorted.sort();
+  sorted.dedup();
+  assert_eq!(protocols, sorted);
+  let json = serde_json::to_value(&capabilities[0]).expect("Test, assuming infallible.");
+  assert_eq!(json["protocol"], capabilities[0].protocol().as_str());
+}
+
+#[test]
+fn test_strict_validation_of_bundled_config() {
+  let lints = validate_device_config_strict(DEVICE_CONFIGURATION_JSON);
+  assert!(
+    lints.is_empty(),
+    "{}",
+    lints
+      .iter()
+      .map(|lint| lint.to_string())
+      .collect::<Vec<_>>()
+      .join("\n")
+  );
+}
+
+const MISSPELLED_FIELD_USER_CONFIG_JSON: &str = include_str!(
+  "util/device_test/device_test_case/config/lovense_ridge_user_config_misspelled_field.json"
+);
+
+#[tokio::test]
+async fn test_strict_validation_flags_dropped_fields() {
+  // Loads fine, the misspelled field is just dropped.
+  load_session(&Some(MISSPELLED_FIELD_USER_CONFIG_JSON.to_owned()));
+  assert!(validate_external_config(MISSPELLED_FIELD_USER_CONFIG_JSON).is_empty());
+
+  assert_single_lint(
+    &validate_device_config_strict(MISSPELLED_FIELD_USER_CONFIG_JSON),
+    ConfigLintSeverity::Error,
+    "/user-configs/devices/0/config/features/1/sensor/mesages",
+  );
+}
+
+#[test]
+fn test_strict_validation_reports_schema_violations() {
+  let lints = validate_device_config_strict(&format!(
+    r#"{{
+      "version": {{ "major": 3, "minor": 0 }},
+      "protocols": {{ "aneros": {} }}
+    }}"#,
+    PROTOCOL_FRAGMENT_JSON.replace("\"names\"", "\"namez\"")
+  ));
+  let location = "/protocols/aneros/communication/0/btle";
+  for message in [
+    "Additional properties are not allowed ('namez' was unexpected)",
+    "\"names\" is a required property",
+  ] {
+    assert!(
+      lints
+        .iter()
+        .any(|lint| lint.location() == location && lint.message() == message),
+      "{:?}",
+      lints
+    );
+  }
+  assert!(lints
+    .iter()
+    .any(|lint| lint.location() == "/protocols/aneros/communication/0/btle/namez"));
+  assert!(lints
+    .iter()
+    .all(|lint| lint.severity() == ConfigLintSeverity::Error));
+
+  assert_single_lint(
+    &validate_device_config_strict("{ not json"),
+    ConfigLintSeverity::Error,
+    "(root)",
+  );
+}
+
+#[tokio::test]
+async fn test_load_protocol_configs_with_lints() {
+  let broken_config = custom_main_config(0).replacen("[0, 100]", "[20, 20]", 1);
+
+  // Lints don't stop the load unless asked to.
+  let (_, lints) = load_protocol_configs_with_lints(
+    &BaseConfig::Custom(broken_config.clone()),
+    &None,
+    false,
+    false,
+  )
+  .expect("Test, assuming infallible.");
+  assert_eq!(lints.len(), 1);
+
+  let err =
+    load_protocol_configs_with_lints(&BaseConfig::Custom(broken_config), &None, false, true)
+      .err()
+      .expect("Error lints should fail the load when denied.");
+  if let ButtplugDeviceError::ConfigurationError(ConfigurationError::LintErrors { lints }) = err {
+    assert_eq!(lints.len(), 1);
+    assert!(lints[0].contains("protocols.aneros.defaults.features[0].actuator.step-range"));
+  } else {
+    panic!("Unexpected error {:?}", err);
+  }
+
+  let (_, lints) = load_protocol_configs_with_lints(
+    &BaseConfig::Custom(custom_main_config(0)),
+    &None,
+    false,
+    true,
+  )
+  .expect("Test, assuming infallible.");
+  assert!(lints.is_empty());
+}
+
+const RIDGE_USER_CONFIG_JSON: &str =
+  include_str!("util/device_test/device_test_case/config/lovense_ridge_user_config.json");
+const V2_USER_CONFIG_JSON: &str =
+  include_str!("util/device_test/device_test_case/config/lovense_v2_user_config.json");
+const INVALID_V2_USER_CONFIG_JSON: &str =
+  include_str!("util/device_test/device_test_case/config/lovense_v2_user_config_invalid.json");
+
+#[test]
+fn test_tooling_validate() {
+  let report = tooling::validate(RIDGE_USER_CONFIG_JSON).expect("Test, assuming infallible.");
+  assert!(report.valid(), "{:?}", report.issues());
+  assert_eq!(report.kind(), ConfigFileKind::User);
+  assert_eq!(report.version().as_deref(), Some("3.999"));
+
+  let report = tooling::validate(&custom_main_config(0)).expect("Test, assuming infallible.");
+  assert!(report.valid(), "{:?}", report.issues());
+  assert_eq!(report.kind(), ConfigFileKind::Main);
+
+  // Lint locations are reported as pointers too.
+  let report = tooling::validate(&custom_main_config(0).replacen("[0, 100]", "[20, 20]", 1))
+    .expect("Test, assuming infallible.");
+  assert!(!report.valid());
+  assert_eq!(report.issues().len(), 1);
+  assert_eq!(
+    report.issues()[0].pointer(),
+    "/protocols/aneros/defaults/features/0/actuator/step-range"
+  );
+
+  let report =
+    tooling::validate(MISSPELLED_FIELD_USER_CONFIG_JSON).expect("Test, assuming infallible.");
+  assert!(!report.valid());
+  assert_eq!(report.issues().len(), 1);
+  assert_eq!(
+    report.issues()[0].pointer(),
+    "/user-configs/devices/0/config/features/1/sensor/mesages"
+  );
+
+  // Old configs don't match the schema, and say so where they don't.
+  let report = tooling::validate(V2_USER_CONFIG_JSON).expect("Test, assuming infallible.");
+  assert!(!report.valid());
+  assert_eq!(report.version().as_deref(), Some("2.6"));
+  assert!(report
+    .issues()
+    .iter()
+    .any(|issue| issue.pointer() == "/user-configs"
+      && issue.message().contains("'specifiers' was unexpected")));
+
+  // Configs that pass every check but still can't be loaded.
+  let report =
+    tooling::validate(&RIDGE_USER_CONFIG_JSON.replacen("\"major\": 3", "\"major\": 4", 1))
+      .expect("Test, assuming infallible.");
+  assert!(!report.valid());
+  assert_eq!(report.issues().len(), 1);
+  assert_eq!(report.issues()[0].pointer(), "/version");
+
+  assert!(matches!(
+    tooling::validate("{ not json"),
+    Err(ButtplugDeviceError::ConfigurationError(
+      ConfigurationError::SerdeError { .. }
+    ))
+  ));
+}
+
+#[test]
+fn test_tooling_migrate_v2_user_config() {
+  let migrated = tooling::migrate(V2_USER_CONFIG_JSON).expect("Test, assuming infallible.");
+  assert_eq!(migrated.from_version(), "2.6");
+  assert_eq!(migrated.to_version(), "3.0");
+  let step_pointers: Vec<&str> = migrated
+    .steps()
+    .iter()
+    .map(|step| step.pointer().as_str())
+    .collect();
+  assert_eq!(
+    step_pointers,
+    vec![
+      "/user-configs/specifiers/lovense",
+      "/user-configs/devices/0/config/messages/ScalarCmd/0",
+      "/user-configs/devices/0/config",
+      "/user-configs/devices/1",
+      "/version",
+    ]
+  );
+
+  let report = tooling::validate(migrated.config()).expect("Test, assuming infallible.");
+  assert!(report.valid(), "{:?}", report.issues());
+  let dcm = load_session(&Some(migrated.config().clone()));
+  assert_eq!(
+    dcm
+      .user_communication_specifiers()
+      .get("lovense")
+      .expect("Test, assuming infallible.")
+      .len(),
+    1
+  );
+  // The device that only exists in the migrated config is the only user device.
+  assert_eq!(dcm.user_device_definitions().len(), 1);
+  let definition = dcm
+    .device_definition(
+      &UserDeviceIdentifier::new("UserConfigTest", "lovense", &Some("F".to_owned())),
+      &[],
+    )
+    .expect("Test, assuming infallible.");
+  assert_eq!(definition.name(), "Lovense Sex Machine");
+  assert_eq!(
+    definition.user_config().display_name().as_deref(),
+    Some("Lovense Name Test")
+  );
+  assert_eq!(definition.user_config().index(), 3);
+  assert_eq!(
+    *definition.features()[0]
+      .actuator()
+      .as_ref()
+      .expect("Test, assuming infallible.")
+      .step_limit(),
+    0..=10
+  );
+}
+
+#[test]
+fn test_tooling_migrate_current_and_unsupported_configs() {
+  // Nothing to do for configs of the current version, or a newer minor version.
+  let migrated = tooling::migrate(RIDGE_USER_CONFIG_JSON).expect("Test, assuming infallible.");
+  assert!(migrated.steps().is_empty());
+  assert_eq!(migrated.to_version(), "3.999");
+
+  let migrated = tooling::migrate(&RIDGE_USER_CONFIG_JSON.replacen("999", "0", 1))
+    .expect("Test, assuming infallible.");
+  assert!(migrated.steps().is_empty());
+
+  let err = tooling::migrate(INVALID_V2_USER_CONFIG_JSON)
+    .expect_err("Invalid v2 configs shouldn't migrate.");
+  if let ButtplugDeviceError::ConfigurationError(ConfigurationError::MigrationFailed {
+    version,
+    message,
+  }) = err
+  {
+    assert_eq!(version, "2.6");
+    assert!(message.contains("/user-configs/specifiers/lovense/websocket"));
+  } else {
+    panic!("Unexpected error {:?}", err);
+  }
+
+  assert!(matches!(
+    tooling::migrate(&V2_USER_CONFIG_JSON.replacen("\"major\": 2", "\"major\": 1", 1)),
+    Err(ButtplugDeviceError::ConfigurationError(
+      ConfigurationError::VersionMismatch { .. }
+    ))
+  ));
+  assert!(matches!(
+    tooling::migrate(&custom_main_config(0).replacen("\"major\": 3", "\"major\": 2", 1)),
+    Err(ButtplugDeviceError::ConfigurationError(
+      ConfigurationError::MigrationFailed { .. }
+    ))
+  ));
+  // Migrated configs have to load, broken ones stay broken.
+  assert!(matches!(
+    tooling::migrate(&RIDGE_USER_CONFIG_JSON.replacen("\"address\"", "\"adress\"", 1)),
+    Err(ButtplugDeviceError::ConfigurationError(
+      ConfigurationError::MigrationFailed { .. }
+    ))
+  ));
+}
+
+#[test]
+fn test_tooling_render_effective() {
+  let embedded = load_session(&None);
+  let effective = tooling::render_effective(None, None).expect("Test, assuming infallible.");
+  assert_eq!(effective.protocol_count(), embedded.protocols().count());
+  assert!(effective.device_configuration_count() >= effective.protocol_count());
+  assert_eq!(effective.user_device_count(), 0);
+  assert_eq!(
+    effective.unimplemented_protocols(),
+    embedded.unimplemented_protocols()
+  );
+
+  let effective =
+    tooling::render_effective(Some(&custom_main_config(0)), Some(RIDGE_USER_CONFIG_JSON))
+      .expect("Test, assuming infallible.");
+  assert_eq!(effective.protocol_count(), 2);
+  assert_eq!(effective.device_configuration_count(), 2);
+  assert_eq!(effective.user_device_count(), 1);
+  // The render is an export, so it can be loaded back.
+  let export: ProtocolConfiguration =
+    serde_json::from_str(effective.config()).expect("Test, assuming infallible.");
+  let dcm = export
+    .load(false)
+    .expect("Test, assuming infallible.")
+    .finish()
+    .expect("Test, assuming infallible.");
+  assert_eq!(dcm.user_device_definitions().len(), 1);
+  // Renders of the same configs are the same.
+  assert_eq!(
+    tooling::render_effective(Some(&custom_main_config(0)), Some(RIDGE_USER_CONFIG_JSON))
+      .expect("Test, assuming infallible.")
+      .config(),
+    effective.config()
+  );
+
+  assert!(matches!(
+    tooling::render_effective(None, Some(V2_USER_CONFIG_JSON)),
+    Err(ButtplugDeviceError::ConfigurationError(
+      ConfigurationError::SchemaViolation { .. }
+    ))
+  ));
+  assert!(tooling::render_effective(Some("{ not json"), None).is_err());
+}
@@ -23,6 +23,7 @@ async fn load_test_case(test_file: &str) -> DeviceTestCase {
 #[test_case("test_ankni_protocol_no_handshake.yaml" ; "Ankni Protocol - No Handshake")]
 #[test_case("test_cachito_protocol.yaml" ; "Cachito Protocol")]
 #[test_case("test_dg_lab_v2.yaml" ; "Dungeon Lab V2 Protocol")]
+#[test_case("test_dg_lab_v2_simple.yaml" ; "Dungeon Lab V2 Protocol - Simple Mode")]
 #[test_case("test_dg_lab_v3.yaml" ; "Dungeon Lab V3 Protocol")]
 #[test_case("test_fredorch_protocol.yaml" ; "Fredorch Protocol")]
 #[test_case("test_hismith_auxfun_box.yaml" ; "Hismith Mini Protocol - Auxfun Box")]
@@ -30,6 +31,7 @@ async fn load_test_case(test_file: &str) -> DeviceTestCase {
 #[test_case("test_hismith_thrusting_cup.yaml" ; "Hismith Protocol - Thrusting Cup")]
 #[test_case("test_hismith_wildolo.yaml" ; "Hismith Protocol - Wildolo")]
 #[test_case("test_lovense_single_vibrator.yaml" ; "Lovense Protocol - Single Vibrator Device")]
+#[test_case("test_lovense_unknown_model.yaml" ; "Lovense Protocol - Unknown Model (Default Device)")]
 #[test_case("test_lovense_max.yaml" ; "Lovense Protocol - Lovense Max (Vibrate/Constrict)")]
 #[test_case("test_lovense_nora.yaml" ; "Lovense Protocol - Lovense Nora (Vibrate/Rotate)")]
 #[test_case("test_lovense_ridge.yaml" ; "Lovense Protocol - Lovense Ridge (Oscillate)")]
@@ -40,6 +42,7 @@ async fn load_test_case(test_file: &str) -> DeviceTestCase {
 #[test_case("test_lovense_flexer_fw3.yaml" ; "Lovense Protocol - Flexer FW3")]
 #[test_case("test_lovense_edge.yaml" ; "Lovense Protocol - Edge")]
 #[test_case("test_user_config_display_name.yaml" ; "User Config Display Name")]
+#[test_case("test_user_config_display_name_template.yaml" ; "User Config Display Name Template")]
 #[test_case("test_satisfyer_single_vibrator.yaml" ; "Satisfyer Protocol - Single Vibrator")]
 #[test_case("test_satisfyer_dual_vibrator.yaml" ; "Satisfyer Protocol - Dual Vibrator")]
 #[test_case("test_mysteryvibe.yaml" ; "Mysteryvibe Protocol")]
@@ -81,6 +84,7 @@ async fn load_test_case(test_file: &str) -> DeviceTestCase {
 #[test_case("test_cowgirl_protocol.yaml" ; "The Cowgirl Protocol")]
 #[test_case("test_galaku_nebula.yaml" ; "Galaku Pump Protocol - Nebula")]
 #[test_case("test_galaku.yaml" ; "Galaku Protocol")]
+#[test_case("test_galaku_rotate.yaml" ; "Galaku Protocol - Rotation")]
 #[test_case("test_xibao_protocol.yaml" ; "Xibao Protocol")]
 #[test_case("test_sensee_protocol.yaml" ; "Sensee Diandou Protocol - Rabbit")]
 #[test_case("test_sensee_capsule.yaml" ; "Sensee Capsule Protocol")]
@@ -116,6 +120,7 @@ async fn test_device_protocols_embedded_v3(test_file: &str) {
 #[test_case("test_ankni_protocol_no_handshake.yaml" ; "Ankni Protocol - No Handshake")]
 #[test_case("test_cachito_protocol.yaml" ; "Cachito Protocol")]
 #[test_case("test_dg_lab_v2.yaml" ; "Dungeon Lab V2 Protocol")]
+#[test_case("test_dg_lab_v2_simple.yaml" ; "Dungeon Lab V2 Protocol - Simple Mode")]
 #[test_case("test_dg_lab_v3.yaml" ; "Dungeon Lab V3 Protocol")]
 #[test_case("test_fredorch_protocol.yaml" ; "Fredorch Protocol")]
 #[test_case("test_hismith_auxfun_box.yaml" ; "Hismith Mini Protocol - Auxfun Box")]
@@ -123,6 +128,7 @@ async fn test_device_protocols_embedded_v3(test_file: &str) {
 #[test_case("test_hismith_thrusting_cup.yaml" ; "Hismith Protocol - Thrusting Cup")]
 #[test_case("test_hismith_wildolo.yaml" ; "Hismith Protocol - Wildolo")]
 #[test_case("test_lovense_single_vibrator.yaml" ; "Lovense Protocol - Single Vibrator Device")]
+#[test_case("test_lovense_unknown_model.yaml" ; "Lovense Protocol - Unknown Model (Default Device)")]
 #[test_case("test_lovense_max.yaml" ; "Lovense Protocol - Lovense Max (Vibrate/Constrict)")]
 #[test_case("test_lovense_nora.yaml" ; "Lovense Protocol - Lovense Nora (Vibrate/Rotate)")]
 #[test_case("test_lovense_ridge.yaml" ; "Lovense Protocol - Lovense Ridge (Oscillate)")]
@@ -133,6 +139,7 @@ async fn test_device_protocols_embedded_v3(test_file: &str) {
 #[test_case("test_lovense_flexer_fw3.yaml" ; "Lovense Protocol - Flexer FW3")]
 #[test_case("test_lovense_edge.yaml" ; "Lovense Protocol - Edge")]
 #[test_case("test_user_config_display_name.yaml" ; "User Config Display Name")]
+#[test_case("test_user_config_display_name_template.yaml" ; "User Config Display Name Template")]
 #[test_case("test_satisfyer_single_vibrator.yaml" ; "Satisfyer Protocol - Single Vibrator")]
 #[test_case("test_satisfyer_dual_vibrator.yaml" ; "Satisfyer Protocol - Dual Vibrator")]
 #[test_case("test_satisfyer_triple_vibrator.yaml" ; "Satisfyer Protocol - Triple Vibrator")]
@@ -175,6 +182,7 @@ async fn test_device_protocols_embedded_v3(test_file: &str) {
 #[test_case("test_cowgirl_protocol.yaml" ; "The Cowgirl Protocol")]
 #[test_case("test_galaku_nebula.yaml" ; "Galaku Pump Protocol - Nebula")]
 #[test_case("test_galaku.yaml" ; "Galaku Protocol")]
+#[test_case("test_galaku_rotate.yaml" ; "Galaku Protocol - Rotation")]
 #[test_case("test_xibao_protocol.yaml" ; "Xibao Protocol")]
 #[test_case("test_sensee_protocol.yaml" ; "Sensee Diandou Protocol - Rabbit")]
 #[test_case("test_sensee_capsule.yaml" ; "Sensee Capsule Protocol")]
@@ -210,6 +218,7 @@ async fn test_device_protocols_json_v3(test_file: &str) {
 #[test_case("test_fredorch_protocol.yaml" ; "Fredorch Protocol")]
 #[test_case("test_hismith_wildolo.yaml" ; "Hismith Protocol - Wildolo")]
 #[test_case("test_lovense_single_vibrator.yaml" ; "Lovense Protocol - Single Vibrator Device")]
+#[test_case("test_lovense_unknown_model.yaml" ; "Lovense Protocol - Unknown Model (Default Device)")]
 #[test_case("test_lovense_nora.yaml" ; "Lovense Protocol - Lovense Nora (Vibrate/Rotate)")]
 #[test_case("test_lovense_battery.yaml" ; "Lovense Protocol - Lovense Battery (Default Devices)")]
 #[test_case("test_lovense_battery_non_default.yaml" ; "Lovense Protocol - Lovense Battery (Non-Default Devices)")]
@@ -277,6 +286,7 @@ async fn test_device_protocols_embedded_v2(test_file: &str) {
 #[test_case("test_fredorch_protocol.yaml" ; "Fredorch Protocol")]
 #[test_case("test_hismith_wildolo.yaml" ; "Hismith Protocol - Wildolo")]
 #[test_case("test_lovense_single_vibrator.yaml" ; "Lovense Protocol - Single Vibrator Device")]
+#[test_case("test_lovense_unknown_model.yaml" ; "Lovense Protocol - Unknown Model (Default Device)")]
 #[test_case("test_lovense_nora.yaml" ; "Lovense Protocol - Lovense Nora (Vibrate/Rotate)")]
 #[test_case("test_lovense_battery.yaml" ; "Lovense Protocol - Lovense Battery (Default Devices)")]
 #[test_case("test_lovense_battery_non_default.yaml" ; "Lovense Protocol - Lovense Battery (Non-Default Devices)")]
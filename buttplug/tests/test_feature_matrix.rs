@@ -0,0 +1,51 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Checks that the library still builds with reduced feature sets, which the other tests (run with
+//! whatever features the test build has) never compile.
+//!
+//! Each test runs a full `cargo check`, so they're ignored by default. CI runs them with
+//! `cargo test -p buttplug --test test_feature_matrix -- --ignored`.
+
+use std::{path::Path, process::Command};
+
+fn check_features(features: &str) {
+  let output = Command::new(env!("CARGO"))
+    .current_dir(env!("CARGO_MANIFEST_DIR"))
+    .args([
+      "check",
+      "--lib",
+      "--no-default-features",
+      "--features",
+      features,
+    ])
+    // Own target directory, so this doesn't wait on or invalidate the build running the tests.
+    .env(
+      "CARGO_TARGET_DIR",
+      Path::new(env!("CARGO_TARGET_TMPDIR")).join("feature-matrix"),
+    )
+    .output()
+    .expect("Test, assuming infallible.");
+  assert!(
+    output.status.success(),
+    "Build with features \"{}\" failed:\n{}",
+    features,
+    String::from_utf8_lossy(&output.stderr)
+  );
+}
+
+#[test]
+#[ignore = "Runs a nested cargo check, see the module docs"]
+fn test_client_messages_only_builds() {
+  check_features("client-messages-only");
+}
+
+#[test]
+#[ignore = "Runs a nested cargo check, see the module docs"]
+fn test_client_without_server_builds() {
+  check_features("tokio-runtime client serialize-json");
+}
@@ -200,7 +200,7 @@ async fn test_version1_singlemotorvibratecmd() {
   let mut msg = recv.next().await.expect("Test, assuming infallible.");
   let mut smsg = serializer.serialize(&vec![msg]);
   // We should receive ScanningFinished and DeviceAdded, but the order may change.
-  let possible_messages: Vec<ButtplugSerializedMessage> = vec![r#"[{"ScanningFinished":{"Id":0}}]"#.to_owned().into(), r#"[{"DeviceAdded":{"Id":0,"DeviceIndex":0,"DeviceName":"Aneros Vivi","DeviceMessages":{"VibrateCmd":{"FeatureCount":2},"stop_device_cmd":{},"single_motor_vibrate_cmd":{}}}}]"#.to_owned().into()];
+  let possible_messages: Vec<ButtplugSerializedMessage> = vec![r#"[{"ScanningFinished":{"Id":0}}]"#.to_owned().into(), r#"[{"DeviceAdded":{"Id":0,"DeviceIndex":0,"DeviceName":"Aneros Vivi","DeviceMessages":{"VibrateCmd":{"FeatureCount":2},"StopDeviceCmd":{},"SingleMotorVibrateCmd":{}}}}]"#.to_owned().into()];
   assert!(
     possible_messages.contains(&smsg),
     "We should receive ScanningFinished and DeviceAdded, but the order may change. Got {:?}",
@@ -312,7 +312,7 @@ async fn test_version1_oscilatoronly() {
   let mut msg = recv.next().await.expect("Test, assuming infallible.");
   let mut smsg = serializer.serialize(&vec![msg]);
   // We should receive ScanningFinished and DeviceAdded, but the order may change.
-  let possible_messages: Vec<ButtplugSerializedMessage> = vec![r#"[{"ScanningFinished":{"Id":0}}]"#.to_owned().into(), r#"[{"DeviceAdded":{"Id":0,"DeviceIndex":0,"DeviceName":"MagicMotion Xone","DeviceMessages":{"stop_device_cmd":{}}}}]"#.to_owned().into()];
+  let possible_messages: Vec<ButtplugSerializedMessage> = vec![r#"[{"ScanningFinished":{"Id":0}}]"#.to_owned().into(), r#"[{"DeviceAdded":{"Id":0,"DeviceIndex":0,"DeviceName":"MagicMotion Xone","DeviceMessages":{"StopDeviceCmd":{}}}}]"#.to_owned().into()];
   assert!(
     possible_messages.contains(&smsg),
     "We should receive ScanningFinished and DeviceAdded, but the order may change. Got {:?}",
@@ -327,3 +327,77 @@ async fn test_version1_oscilatoronly() {
     smsg
   );
 }
+
+async fn serialized_device_list(device_name: &str, message_version: u32) -> String {
+  let (server, _device) = test_server_with_device(device_name, false);
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  let serializer = ButtplugServerJSONSerializer::default();
+  let rsi = format!(
+    r#"[{{"RequestServerInfo":{{"Id": 1, "ClientName": "Test Client", "MessageVersion": {}}}}}]"#,
+    message_version
+  );
+  server
+    .parse_message(
+      serializer
+        .deserialize(&rsi.into())
+        .expect("Test, assuming infallible.")[0]
+        .clone(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  while let Some(msg) = recv.next().await {
+    if matches!(msg, message::ButtplugServerMessage::DeviceAdded(_)) {
+      break;
+    }
+  }
+  let rdl = serializer
+    .deserialize(&r#"[{"RequestDeviceList": { "Id": 1}}]"#.to_owned().into())
+    .expect("Test, assuming infallible.");
+  let output = server
+    .parse_message(rdl[0].clone())
+    .await
+    .expect("Test, assuming infallible.");
+  match serializer.serialize(&[output]) {
+    ButtplugSerializedMessage::Text(text) => text,
+    _ => panic!("Test, assuming infallible."),
+  }
+}
+
+// Only the vibrating channels of e-stim devices should show up as vibrators for older clients,
+// while v3 clients get every feature along with its actuator type.
+#[tokio::test]
+async fn test_version0_dg_lab_v3_device_list() {
+  assert_eq!(
+    serialized_device_list("47L121000", 0).await,
+    r#"[{"DeviceList":{"Id":1,"Devices":[{"DeviceIndex":0,"DeviceName":"Dungeon Lab V3","DeviceMessages":["SingleMotorVibrateCmd","StopDeviceCmd"]}]}}]"#
+  );
+}
+
+#[tokio::test]
+async fn test_version1_dg_lab_v3_device_list() {
+  assert_eq!(
+    serialized_device_list("47L121000", 1).await,
+    r#"[{"DeviceList":{"Id":1,"Devices":[{"DeviceIndex":0,"DeviceName":"Dungeon Lab V3","DeviceMessages":{"VibrateCmd":{"FeatureCount":2},"LinearCmd":{"FeatureCount":2},"StopDeviceCmd":{},"SingleMotorVibrateCmd":{}}}]}}]"#
+  );
+}
+
+#[tokio::test]
+async fn test_version2_dg_lab_v3_device_list() {
+  assert_eq!(
+    serialized_device_list("47L121000", 2).await,
+    r#"[{"DeviceList":{"Id":1,"Devices":[{"DeviceIndex":0,"DeviceName":"Dungeon Lab V3","DeviceMessages":{"VibrateCmd":{"FeatureCount":2,"StepCount":[200,200]},"LinearCmd":{"FeatureCount":2,"StepCount":[200,200]},"BatteryLevelCmd":{},"StopDeviceCmd":{}}}]}}]"#
+  );
+}
+
+#[tokio::test]
+async fn test_version3_dg_lab_v3_device_list() {
+  assert_eq!(
+    serialized_device_list("47L121000", 3).await,
+    r#"[{"DeviceList":{"Id":1,"Devices":[{"DeviceIndex":0,"DeviceName":"Dungeon Lab V3","DeviceMessages":{"ScalarCmd":[{"FeatureDescriptor":"Channel A Power","ActuatorType":"Vibrate","StepCount":200},{"FeatureDescriptor":"Channel B Power","ActuatorType":"Vibrate","StepCount":200},{"FeatureDescriptor":"Channel A Frequency","ActuatorType":"Oscillate","StepCount":991,"StepRange":[9,1000]},{"FeatureDescriptor":"Channel B Frequency","ActuatorType":"Oscillate","StepCount":991,"StepRange":[9,1000]},{"FeatureDescriptor":"Channel A Waveform Strength","ActuatorType":"Inflate","StepCount":100},{"FeatureDescriptor":"Channel B Waveform Strength","ActuatorType":"Inflate","StepCount":100},{"FeatureDescriptor":"Channel A Power Adjustment","ActuatorType":"Constrict","StepCount":400},{"FeatureDescriptor":"Channel B Power Adjustment","ActuatorType":"Constrict","StepCount":400}],"LinearCmd":[{"FeatureDescriptor":"Channel A Power Ramp","ActuatorType":"Position","StepCount":200},{"FeatureDescriptor":"Channel B Power Ramp","ActuatorType":"Position","StepCount":200}],"SensorReadCmd":[{"FeatureDescriptor":"Battery Level","SensorType":"Battery","SensorRange":[[0,100]]}],"StopDeviceCmd":{}}}]}}]"#
+  );
+}
@@ -0,0 +1,19 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+mod util;
+use test_case::test_case;
+use util::conformance::run_protocol_conformance;
+
+// Protocols with a fixture in tests/util/conformance/fixtures also get their packets checked.
+#[test_case("dg-lab-v2" ; "Dungeon Lab V2 Protocol")]
+#[test_case("dg-lab-v3" ; "Dungeon Lab V3 Protocol")]
+#[test_case("galaku" ; "Galaku Protocol - One Engine")]
+#[tokio::test]
+async fn test_protocol_conformance(protocol: &str) {
+  run_protocol_conformance(protocol).await;
+}
@@ -6,14 +6,110 @@
 // for full license information.
 
 mod util;
-use buttplug::core::{
-  errors::{ButtplugDeviceError, ButtplugError},
-  message::{self, ButtplugServerMessage, Endpoint, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION},
+use async_trait::async_trait;
+use buttplug::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError, ConfigurationError},
+    message::{
+      self,
+      ButtplugClientMessage,
+      ButtplugSensorFeatureMessageType,
+      ButtplugServerMessage,
+      DeviceFeature,
+      DeviceFeatureSensor,
+      Endpoint,
+      FeatureType,
+      IntensityCurve,
+      SensorType,
+      BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+    },
+  },
+  server::{
+    device::{
+      configuration::{
+        BaseDeviceIdentifier,
+        BluetoothLEMatchFailure,
+        DeviceConfigurationManager,
+        DeviceConfigurationManagerBuilder,
+        DeviceMirror,
+        InitSequenceWrite,
+        ProtocolDeviceAttributes,
+        ProtocolNearMiss,
+        RotationInversion,
+        ScalarRamp,
+        SensorCalibration,
+        UserDeviceCustomization,
+        UserDeviceDefinition,
+        UserDeviceIdentifier,
+      },
+      hardware::{
+        Hardware,
+        HardwareCommand,
+        HardwareEvent,
+        HardwareOperation,
+        HardwarePacketDirection,
+        HardwareReadCmd,
+        HardwareSubscribeCmd,
+        HardwareUnsubscribeCmd,
+        HardwareWriteCmd,
+        HARDWARE_ERROR_HISTORY_LENGTH,
+      },
+      protocol::{
+        check_required_endpoints,
+        forward_hardware_notifications,
+        galaku::{Galaku, GalakuOpcodes},
+        galaku_framing,
+        write_init_sequence,
+        GenericProtocolIdentifier,
+        ProtocolHandler,
+        ProtocolIdentifier,
+        ProtocolIdentifierFactory,
+        ProtocolInitializer,
+      },
+      DeviceIgnoredReason,
+      InitializationRetryPolicy,
+      ServerDeviceManagerBuilder,
+      ServerDeviceManagerEvent,
+    },
+    ButtplugServer,
+    ButtplugServerBuilder,
+    ButtplugServerError,
+  },
+  util::{
+    device_configuration::{add_protocol_definition_from_json, load_protocol_configs},
+    stream::recv_now,
+  },
 };
-use futures::{pin_mut, StreamExt};
-use std::matches;
+use futures::{future, pin_mut, FutureExt, StreamExt};
+use std::{
+  collections::{BTreeMap, HashSet},
+  matches,
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+    Mutex,
+  },
+  time::Duration,
+};
+use tokio::sync::broadcast;
 pub use util::test_device_manager::TestDeviceCommunicationManagerBuilder;
-use util::test_server_with_device;
+use util::{
+  create_test_dcm,
+  customized_test_dcm,
+  test_device_manager::{
+    check_test_recv_value,
+    test_device::{new_device_channel, TestHardwareNotification},
+    TestDevice,
+    TestDeviceChannelHost,
+    TestDeviceIdentifier,
+    TestHardwareEvent,
+  },
+  test_device_manager_builder,
+  test_server_with_comm_manager,
+  test_server_with_customized_definition,
+  test_server_with_customized_device,
+  test_server_with_device,
+};
 
 // Test devices that have protocols that support movements not all devices do.
 // For instance, the Onyx+ is part of a protocol that supports vibration, but
@@ -242,3 +338,4795 @@ async fn test_repeated_address_additions() {
     }
 }
 */
+
+fn test_server_with_rate_limited_device(
+  max_command_rate_hz: u32,
+) -> (ButtplugServer, TestDeviceChannelHost) {
+  test_server_with_customized_device(
+    &TestDeviceIdentifier::new("Massage Demo", Some("RateLimitTest".to_owned())),
+    "aneros",
+    |config| {
+      config.set_max_command_rate_hz(Some(max_command_rate_hz));
+    },
+  )
+}
+
+async fn wait_for_device_added(server: &ButtplugServer) -> u32 {
+  wait_for_device_added_message(server).await.device_index()
+}
+
+async fn wait_for_device_added_message(server: &ButtplugServer) -> message::DeviceAdded {
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  server
+    .parse_message(
+      message::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  while let Some(msg) = recv.next().await {
+    if let ButtplugServerMessage::DeviceAdded(da) = msg {
+      return da;
+    }
+  }
+  panic!("Device never added.");
+}
+
+/// Move paused time forward a millisecond at a time, letting the tasks each tick wakes run before
+/// the next one. Tasks that sleep in a loop, like ramps, then run every step they would have in
+/// real time, instead of once for the whole jump.
+async fn advance_paused_time(duration: Duration) {
+  for _ in 0..duration.as_millis() {
+    tokio::time::advance(Duration::from_millis(1)).await;
+    tokio::task::yield_now().await;
+  }
+}
+
+fn vibrate_cmd(device_index: u32, speed: f64) -> ButtplugClientMessage {
+  message::ScalarCmd::new(
+    device_index,
+    vec![message::ScalarSubcommand::new(
+      0,
+      speed,
+      message::ActuatorType::Vibrate,
+    )],
+  )
+  .into()
+}
+
+#[tokio::test]
+async fn test_device_intensity_curve() {
+  let (server, mut device) = test_server_with_customized_definition(
+    &TestDeviceIdentifier::new("Massage Demo", Some("CurveTest".to_owned())),
+    "aneros",
+    |definition| {
+      // Skip the bottom of the range, and flatten out the top.
+      let mut features = definition.features().clone();
+      let mut actuator = features[0]
+        .actuator()
+        .clone()
+        .expect("Test, assuming infallible.");
+      actuator.set_intensity_curve(Some(IntensityCurve::Points(vec![[0.0, 0.4], [0.5, 0.8]])));
+      features[0] = DeviceFeature::new(
+        features[0].description(),
+        *features[0].feature_type(),
+        &Some(actuator),
+        features[0].sensor(),
+      );
+      definition.set_features(features);
+    },
+  );
+  let device_index = wait_for_device_added(&server).await;
+
+  // Aneros has 127 steps. Values are remapped, then quantized (rounding up).
+  for (speed, expected) in [(0.25, 77), (0.75, 115), (1.0, 127), (0.0, 0)] {
+    server
+      .parse_message(vibrate_cmd(device_index, speed))
+      .await
+      .expect("Test, assuming infallible.");
+    check_test_recv_value(
+      &mut device,
+      HardwareCommand::Write(HardwareWriteCmd::new(
+        Endpoint::Tx,
+        vec![0xF1, expected],
+        false,
+      )),
+    );
+  }
+}
+
+#[tokio::test]
+async fn test_device_endpoints_reflect_hardware() {
+  // The magic motion config maps tx and rxblebattery, have the hardware only expose tx. The
+  // protocol doesn't require a battery endpoint, so the device still connects.
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let _device = builder.add_test_device(
+    &TestDeviceIdentifier::new("Eidolon", None).with_missing_endpoints(&[Endpoint::RxBLEBattery]),
+  );
+  let mut dm_builder = ServerDeviceManagerBuilder::new(create_test_dcm(false));
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  let device_index = wait_for_device_added(&server).await;
+  assert_eq!(
+    server
+      .device_manager()
+      .device_info(device_index)
+      .expect("Test, assuming infallible.")
+      .endpoints(),
+    &vec![Endpoint::Tx]
+  );
+}
+
+async fn check_missing_required_endpoint(device_name: &str, missing_endpoint: Endpoint) {
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let device = builder.add_test_device(
+    &TestDeviceIdentifier::new(device_name, None).with_missing_endpoints(&[missing_endpoint]),
+  );
+  let mut dm_builder = ServerDeviceManagerBuilder::new(create_test_dcm(false));
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  assert!(
+    tokio::time::timeout(
+      Duration::from_millis(500),
+      wait_for_device_added_message(&server)
+    )
+    .await
+    .is_err(),
+    "{} connected without {}",
+    device_name,
+    missing_endpoint
+  );
+  // The device was found and connected to, it just never got set up.
+  assert!(device.connect_attempts.load(Ordering::SeqCst) > 0);
+  assert!(server.device_manager().device_info(0).is_none());
+}
+
+#[tokio::test]
+async fn test_device_missing_required_endpoint() {
+  check_missing_required_endpoint("D-LAB ESTIM01", Endpoint::Generic1).await;
+  check_missing_required_endpoint("47L121000", Endpoint::Rx).await;
+  check_missing_required_endpoint("GS03", Endpoint::RxBLEBattery).await;
+}
+
+const OPTIONAL_ENDPOINT_PROTOCOL_JSON: &str = r#"
+{
+  "defaults": {
+    "name": "Optional Endpoint Device",
+    "features": [
+      {
+        "feature-type": "Vibrate",
+        "actuator": {
+          "step-range": [0, 127],
+          "messages": ["ScalarCmd"]
+        }
+      }
+    ]
+  },
+  "communication": [
+    {
+      "btle": {
+        "names": ["OptionalEndpointDevice"],
+        "optional-endpoints": ["rxblebattery"],
+        "services": {
+          "0000ff00-0000-1000-8000-00805f9b34fb": {
+            "tx": "0000ff01-0000-1000-8000-00805f9b34fb",
+            "generic0": "0000ff02-0000-1000-8000-00805f9b34fb"
+          },
+          "0000180f-0000-1000-8000-00805f9b34fb": {
+            "rxblebattery": "00002a19-0000-1000-8000-00805f9b34fb"
+          }
+        }
+      }
+    }
+  ]
+}
+"#;
+
+fn optional_endpoint_server(missing_endpoint: Endpoint) -> (ButtplugServer, TestDeviceChannelHost) {
+  let mut dcm_builder =
+    load_protocol_configs(&None, &None, false).expect("Test, assuming infallible.");
+  add_protocol_definition_from_json(&mut dcm_builder, "aneros", OPTIONAL_ENDPOINT_PROTOCOL_JSON)
+    .expect("Test, assuming infallible.");
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let device = builder.add_test_device(
+    &TestDeviceIdentifier::new("OptionalEndpointDevice", None)
+      .with_missing_endpoints(&[missing_endpoint]),
+  );
+  let mut dm_builder =
+    ServerDeviceManagerBuilder::new(dcm_builder.finish().expect("Test, assuming infallible."));
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  (server, device)
+}
+
+#[tokio::test]
+async fn test_device_config_optional_endpoints() {
+  // The battery endpoint is optional, so the device connects without it.
+  let (server, _device) = optional_endpoint_server(Endpoint::RxBLEBattery);
+  let device_index = wait_for_device_added(&server).await;
+  let endpoints = server
+    .device_manager()
+    .device_info(device_index)
+    .expect("Test, assuming infallible.")
+    .endpoints()
+    .clone();
+  assert_eq!(endpoints.len(), 2);
+  assert!(endpoints.contains(&Endpoint::Tx));
+  assert!(endpoints.contains(&Endpoint::Generic0));
+
+  // Aneros only writes to tx, so it's the config that rejects a device without generic0.
+  let (server, device) = optional_endpoint_server(Endpoint::Generic0);
+  assert!(tokio::time::timeout(
+    Duration::from_millis(500),
+    wait_for_device_added_message(&server)
+  )
+  .await
+  .is_err());
+  assert!(device.specialize_attempts.load(Ordering::SeqCst) > 0);
+  assert!(server.device_manager().device_info(0).is_none());
+}
+
+#[tokio::test]
+async fn test_check_required_endpoints() {
+  let (_host, device_channel) = new_device_channel();
+  let hardware = Hardware::new(
+    "EndpointTest",
+    "EndpointTestAddress",
+    &[Endpoint::Tx, Endpoint::Generic0],
+    Box::new(TestDevice::new(
+      "EndpointTest",
+      "EndpointTestAddress",
+      device_channel,
+    )),
+  );
+  assert!(check_required_endpoints(&hardware, &[Endpoint::Tx, Endpoint::Generic0]).is_ok());
+  assert!(check_required_endpoints(&hardware, &[]).is_ok());
+  assert!(hardware.has_endpoint(Endpoint::Generic0));
+  assert!(!hardware.has_endpoint(Endpoint::Rx));
+  let err = check_required_endpoints(
+    &hardware,
+    &[
+      Endpoint::Tx,
+      Endpoint::Generic0,
+      Endpoint::Generic1,
+      Endpoint::Rx,
+    ],
+  )
+  .expect_err("Test, assuming infallible.");
+  if let ButtplugDeviceError::DeviceConnectionError(msg) = err {
+    assert!(msg.ends_with("generic1, rx"), "{}", msg);
+    assert!(msg.contains("EndpointTestAddress"), "{}", msg);
+  } else {
+    panic!("Unexpected error {:?}", err);
+  }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_device_command_rate_limit() {
+  let (server, mut device) = test_server_with_rate_limited_device(10);
+  let device_index = wait_for_device_added(&server).await;
+  assert_eq!(
+    server
+      .device_manager()
+      .device_info(device_index)
+      .expect("Test, assuming infallible.")
+      .max_command_rate_hz(),
+    &Some(10)
+  );
+
+  // Send 100 commands over a second. The first is written right away, then each 100ms write slot
+  // gets the newest command sent since the last one.
+  for i in 1..=100 {
+    server
+      .parse_message(vibrate_cmd(device_index, i as f64 / 100.0))
+      .await
+      .expect("Test, assuming infallible.");
+    advance_paused_time(Duration::from_millis(10)).await;
+  }
+  advance_paused_time(Duration::from_millis(300)).await;
+
+  let mut writes = vec![];
+  while let Some(Some(command)) = recv_now(&mut device.receiver) {
+    writes.push(command);
+  }
+  assert_eq!(
+    writes,
+    [2, 14, 27, 40, 53, 65, 78, 91, 103, 116, 127]
+      .into_iter()
+      .map(|speed| HardwareCommand::Write(HardwareWriteCmd::new(
+        Endpoint::Tx,
+        vec![0xF1, speed],
+        false
+      )))
+      .collect::<Vec<_>>()
+  );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_device_command_rate_limit_stop_not_delayed() {
+  let (server, mut device) = test_server_with_rate_limited_device(1);
+  let device_index = wait_for_device_added(&server).await;
+
+  // First write goes out right away, second is held until the next write slot.
+  server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  advance_paused_time(Duration::from_millis(50)).await;
+  server
+    .parse_message(vibrate_cmd(device_index, 1.0))
+    .await
+    .expect("Test, assuming infallible.");
+  advance_paused_time(Duration::from_millis(50)).await;
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+  );
+
+  // Stopping should write immediately, and drop the pending command.
+  server
+    .parse_message(message::StopDeviceCmd::new(device_index).into())
+    .await
+    .expect("Test, assuming infallible.");
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+  );
+  advance_paused_time(Duration::from_millis(1500)).await;
+  assert!(recv_now(&mut device.receiver).is_none());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_device_command_rate_limit_stop_waits_for_in_flight_write() {
+  let (server, mut device) = test_server_with_customized_device(
+    &TestDeviceIdentifier::new("Massage Demo", Some("InFlightTest".to_owned()))
+      .with_write_delay(Duration::from_millis(100)),
+    "aneros",
+    |config| {
+      config.set_max_command_rate_hz(Some(10));
+    },
+  );
+  let device_index = wait_for_device_added(&server).await;
+
+  // Both features go out in a single coalesced send, one slow write after the other. Stop while
+  // the first write is still on its way.
+  server
+    .parse_message(
+      message::ScalarCmd::new(
+        device_index,
+        (0..2)
+          .map(|index| message::ScalarSubcommand::new(index, 0.5, message::ActuatorType::Vibrate))
+          .collect(),
+      )
+      .into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  advance_paused_time(Duration::from_millis(50)).await;
+  server
+    .parse_message(message::StopDeviceCmd::new(device_index).into())
+    .await
+    .expect("Test, assuming infallible.");
+  advance_paused_time(Duration::from_millis(100)).await;
+
+  let mut writes = vec![];
+  while let Some(Some(HardwareCommand::Write(write))) = recv_now(&mut device.receiver) {
+    writes.push(write.data().clone());
+  }
+  // The stop only goes out once the coalesced send is done, so nothing lands after it.
+  assert_eq!(
+    writes,
+    vec![vec![0xF1, 64], vec![0xF2, 64], vec![0xF1, 0], vec![0xF2, 0]]
+  );
+}
+
+fn test_server_with_ack_on_write_device(
+  failed_commands: u32,
+) -> (ButtplugServer, TestDeviceChannelHost) {
+  test_server_with_customized_device(
+    &TestDeviceIdentifier::new("Massage Demo", Some("AckTest".to_owned()))
+      .with_failed_commands(failed_commands),
+    "aneros",
+    |config| {
+      config.set_max_command_rate_hz(Some(10));
+      config.set_ack_on_write(true);
+    },
+  )
+}
+
+#[tokio::test]
+async fn test_device_ack_on_write_latency() {
+  let (server, mut device) = test_server_with_ack_on_write_device(0);
+  let device_index = wait_for_device_added(&server).await;
+
+  // With a free write slot, waiting on the write adds next to nothing.
+  let start = std::time::Instant::now();
+  server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  assert!(
+    start.elapsed() < Duration::from_millis(50),
+    "{:?}",
+    start.elapsed()
+  );
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+  );
+
+  // Otherwise the reply waits for the next write slot, 100ms at 10hz, and no longer.
+  let start = std::time::Instant::now();
+  server
+    .parse_message(vibrate_cmd(device_index, 1.0))
+    .await
+    .expect("Test, assuming infallible.");
+  let elapsed = start.elapsed();
+  assert!(
+    elapsed >= Duration::from_millis(70) && elapsed < Duration::from_millis(250),
+    "{:?}",
+    elapsed
+  );
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF1, 127], false)),
+  );
+}
+
+#[tokio::test]
+async fn test_device_ack_on_write_error() {
+  let (server, _device) = test_server_with_ack_on_write_device(1);
+  let device_index = wait_for_device_added(&server).await;
+  let err = server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect_err("Test, assuming infallible.");
+  assert!(
+    matches!(
+      err.original_error(),
+      ButtplugError::ButtplugDeviceError(ButtplugDeviceError::DeviceCommunicationError(_))
+    ),
+    "{:?}",
+    err
+  );
+}
+
+fn test_server_with_slow_device(
+  allow_raw_messages: bool,
+  max_queued_commands: u32,
+) -> (ButtplugServer, TestDeviceChannelHost) {
+  let identifier = TestDeviceIdentifier::new("Massage Demo", Some("QueueTest".to_owned()))
+    .with_write_delay(Duration::from_millis(20));
+  let dcm = customized_test_dcm(&identifier, "aneros", allow_raw_messages, |definition| {
+    definition
+      .user_config_mut()
+      .set_max_queued_commands(Some(max_queued_commands));
+  });
+  let (mut dm_builder, device) = test_device_manager_builder(dcm, &identifier);
+  (
+    ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+      .finish()
+      .unwrap(),
+    device,
+  )
+}
+
+#[tokio::test]
+async fn test_device_command_queue_coalesces_scalar_flood() {
+  let (server, mut device) = test_server_with_slow_device(false, 4);
+  let device_index = wait_for_device_added(&server).await;
+
+  let results = future::join_all(
+    (1..=32).map(|step| server.parse_message(vibrate_cmd(device_index, step as f64 / 32.0))),
+  )
+  .await;
+  assert!(results.iter().all(|result| result.is_ok()));
+  tokio::time::sleep(Duration::from_millis(200)).await;
+
+  let stats = server
+    .device_manager()
+    .device_stats(device_index)
+    .expect("Test, assuming infallible.")
+    .command_queue()
+    .expect("Test, assuming infallible.");
+  assert_eq!(stats.limit(), 4);
+  assert_eq!(stats.depth(), 0);
+  assert!(stats.max_depth() <= 4, "{:?}", stats);
+  assert!(stats.coalesced() > 0, "{:?}", stats);
+  assert_eq!(stats.rejected(), 0);
+
+  // Everything in between may have been merged away, but the last command is what sticks.
+  let mut last_write = None;
+  while let Some(Some(command)) = recv_now(&mut device.receiver) {
+    last_write = Some(command);
+  }
+  assert_eq!(
+    last_write,
+    Some(HardwareCommand::Write(HardwareWriteCmd::new(
+      Endpoint::Tx,
+      vec![0xF1, 127],
+      false
+    )))
+  );
+}
+
+#[tokio::test]
+async fn test_server_shutdown_zeroes_devices_before_disconnect() {
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  // Writes take a while to land, so they're only seen once shutdown returns if it waited on them.
+  let mut devices: Vec<_> = ["ShutdownA", "ShutdownB"]
+    .iter()
+    .map(|address| {
+      builder.add_test_device(
+        &TestDeviceIdentifier::new("Massage Demo", Some(address.to_string()))
+          .with_write_delay(Duration::from_millis(100)),
+      )
+    })
+    .collect();
+  let mut dm_builder = ServerDeviceManagerBuilder::new(create_test_dcm(false));
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  server
+    .parse_message(
+      message::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  let mut device_indexes = vec![];
+  while let Some(msg) = recv.next().await {
+    if let ButtplugServerMessage::DeviceAdded(da) = msg {
+      device_indexes.push(da.device_index());
+      if device_indexes.len() == devices.len() {
+        break;
+      }
+    }
+  }
+  for device_index in &device_indexes {
+    server
+      .parse_message(vibrate_cmd(*device_index, 0.5))
+      .await
+      .expect("Test, assuming infallible.");
+  }
+  for device in &mut devices {
+    check_test_recv_value(
+      device,
+      HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+    );
+  }
+
+  server.shutdown().await.expect("Test, assuming infallible.");
+  for device in &mut devices {
+    let mut writes = vec![];
+    while let Some(Some(command)) = recv_now(&mut device.receiver) {
+      writes.push(command);
+    }
+    assert!(
+      writes.contains(&HardwareCommand::Write(HardwareWriteCmd::new(
+        Endpoint::Tx,
+        vec![0xF1, 0],
+        false
+      ))),
+      "{:?}",
+      writes
+    );
+  }
+}
+
+#[tokio::test]
+async fn test_device_command_queue_rejects_raw_write_flood() {
+  let (server, _device) = test_server_with_slow_device(true, 4);
+  let device_index = wait_for_device_added(&server).await;
+
+  let results = future::join_all((0..16).map(|_| {
+    server.parse_message(
+      message::RawWriteCmd::new(device_index, Endpoint::Tx, &[0xF1, 0x10], false).into(),
+    )
+  }))
+  .await;
+  let busy = results
+    .iter()
+    .filter(|result| {
+      matches!(
+        result.as_ref().map_err(|err| err.original_error()),
+        Err(ButtplugError::ButtplugDeviceError(
+          ButtplugDeviceError::DeviceBusy(..)
+        ))
+      )
+    })
+    .count();
+  assert!(busy > 0);
+  assert_eq!(busy + results.iter().filter(|r| r.is_ok()).count(), 16);
+
+  let stats = server
+    .device_manager()
+    .device_stats(device_index)
+    .expect("Test, assuming infallible.")
+    .command_queue()
+    .expect("Test, assuming infallible.");
+  assert!(stats.max_depth() <= 4, "{:?}", stats);
+  assert_eq!(stats.rejected(), busy as u64);
+}
+
+fn drain_dg_lab_v3_power_a(device: &mut TestDeviceChannelHost) -> Vec<u8> {
+  let mut powers = vec![];
+  while let Some(Some(command)) = recv_now(&mut device.receiver) {
+    if let HardwareCommand::Write(write) = command {
+      if write.data()[0] == 0xB0 {
+        powers.push(write.data()[2]);
+      }
+    }
+  }
+  powers
+}
+
+fn power_ramp_cmd(device_index: u32, duration: u32, position: f64) -> ButtplugClientMessage {
+  message::LinearCmd::new(
+    device_index,
+    vec![message::VectorSubcommand::new(0, duration, position)],
+  )
+  .into()
+}
+
+#[tokio::test]
+async fn test_dg_lab_v3_power_ramp() {
+  let (server, mut device) = test_server_with_device("47L121000", false);
+  let device_index = wait_for_device_added(&server).await;
+
+  // Ramp up. Repeat packets start going out 500ms after connection, so we'll see the tail end of
+  // the ramp and the held target.
+  server
+    .parse_message(power_ramp_cmd(device_index, 1000, 1.0))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(1300)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert!(powers.windows(2).all(|w| w[0] <= w[1]), "{:?}", powers);
+  assert!(powers.iter().any(|p| *p > 0 && *p < 200), "{:?}", powers);
+  assert_eq!(*powers.last().expect("Test, assuming infallible."), 200);
+
+  // Ramp down.
+  server
+    .parse_message(power_ramp_cmd(device_index, 500, 0.0))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(800)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert!(powers.windows(2).all(|w| w[0] >= w[1]), "{:?}", powers);
+  assert!(powers.iter().any(|p| *p > 0 && *p < 200), "{:?}", powers);
+  assert_eq!(*powers.last().expect("Test, assuming infallible."), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_dg_lab_v3_power_ramp_end_kept_by_scalar_cmd() {
+  let (server, mut device) = test_server_with_device("47L121000", false);
+  let device_index = wait_for_device_added(&server).await;
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  server
+    .parse_message(vibrate_cmd(device_index, 0.75))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert_eq!(powers.last(), Some(&150), "{:?}", powers);
+
+  server
+    .parse_message(power_ramp_cmd(device_index, 500, 0.0))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(800)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert_eq!(powers.last(), Some(&0), "{:?}", powers);
+
+  // A command that doesn't set power leaves the channel where the ramp took it.
+  server
+    .parse_message(
+      message::ScalarCmd::new(
+        device_index,
+        vec![message::ScalarSubcommand::new(
+          2,
+          0.5,
+          message::ActuatorType::Oscillate,
+        )],
+      )
+      .into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert!(!powers.is_empty());
+  assert!(powers.iter().all(|p| *p == 0), "{:?}", powers);
+}
+
+#[tokio::test]
+async fn test_dg_lab_v3_zeroed_on_client_disconnect() {
+  let (server, mut device) = test_server_with_device("47L121000", false);
+  let device_index = wait_for_device_added(&server).await;
+  // Wait for the repeat loop to start, so we know it's outputting power before we disconnect.
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert_eq!(powers.last(), Some(&100), "{:?}", powers);
+
+  server
+    .disconnect()
+    .await
+    .expect("Test, assuming infallible.");
+  // Wait one repeat interval.
+  tokio::time::sleep(Duration::from_millis(100)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert!(!powers.is_empty());
+  assert!(powers.iter().all(|p| *p == 0), "{:?}", powers);
+}
+
+#[tokio::test]
+async fn test_dg_lab_v3_repeat_loop_halted_on_shutdown() {
+  let (server, mut device) = test_server_with_device("47L121000", false);
+  let device_index = wait_for_device_added(&server).await;
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  drain_dg_lab_v3_power_a(&mut device);
+
+  server.shutdown().await.expect("Test, assuming infallible.");
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert_eq!(powers.last(), Some(&0), "{:?}", powers);
+  // Nothing is repeated once the device is shut down.
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  assert!(recv_now(&mut device.receiver).is_none());
+}
+
+#[tokio::test]
+async fn test_dg_lab_v3_repeat_loop_halted_on_disconnect() {
+  let (server, mut device) = test_server_with_device("47L121000", false);
+  let device_index = wait_for_device_added(&server).await;
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  assert!(!drain_dg_lab_v3_power_a(&mut device).is_empty());
+
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  device
+    .sender
+    .send(TestHardwareEvent::Disconnect)
+    .await
+    .expect("Test, assuming infallible.");
+  let removed = next_device_removed(&mut recv).await;
+  assert_eq!(removed.device_index(), device_index);
+  drain_dg_lab_v3_power_a(&mut device);
+  // Nothing is repeated once the device is gone, and the hardware is dropped rather than held by
+  // the repeat loop, which closes the channel.
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  assert!(matches!(recv_now(&mut device.receiver), Some(None)));
+}
+
+#[tokio::test]
+async fn test_dg_lab_v3_protocol_state() {
+  let (server, _device) = test_server_with_device("47L121000", false);
+  let device_index = wait_for_device_added(&server).await;
+  server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  let state = server
+    .device_manager()
+    .device_protocol_state(device_index)
+    .expect("Test, assuming infallible.");
+  assert_eq!(state["a"]["power"], 100);
+  assert_eq!(state["b"]["power"], 0);
+}
+
+#[tokio::test]
+async fn test_dg_lab_v3_resync_from_b1_response() {
+  let (server, mut device) = test_server_with_device("47L121000", false);
+  let device_index = wait_for_device_added(&server).await;
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+
+  // Responses that don't acknowledge our latest change, and malformed ones, are skipped.
+  device
+    .sender
+    .send(TestHardwareEvent::Notifications(vec![
+      TestHardwareNotification::new(Endpoint::Rx, &[0xB1, 0x00, 50, 100]),
+      TestHardwareNotification::new(Endpoint::Rx, &[0xB1, 0x01]),
+      TestHardwareNotification::new(Endpoint::Rx, &[0xB1, 0x01, 250, 100]),
+      TestHardwareNotification::new(Endpoint::Rx, &[0xB0, 0x01, 50, 100]),
+    ]))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert_eq!(powers.last(), Some(&100), "{:?}", powers);
+
+  // The device acknowledges the change, but clamped channel A.
+  device
+    .sender
+    .send(TestHardwareEvent::Notifications(vec![
+      TestHardwareNotification::new(Endpoint::Rx, &[0xB1, 0x01, 80, 100]),
+    ]))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert_eq!(powers.last(), Some(&80), "{:?}", powers);
+
+  // With nothing pending, changes made on the device itself are picked up too.
+  device
+    .sender
+    .send(TestHardwareEvent::Notifications(vec![
+      TestHardwareNotification::new(Endpoint::Rx, &[0xB1, 0x00, 20, 100]),
+    ]))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert_eq!(powers.last(), Some(&20), "{:?}", powers);
+}
+
+#[tokio::test]
+async fn test_dg_lab_v3_power_ramp_cancel() {
+  let (server, mut device) = test_server_with_device("47L121000", false);
+  let device_index = wait_for_device_added(&server).await;
+  tokio::time::sleep(Duration::from_millis(500)).await;
+
+  server
+    .parse_message(power_ramp_cmd(device_index, 2000, 1.0))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  // A direct ScalarCmd cancels the ramp, even when it matches the last scalar value sent.
+  server
+    .parse_message(vibrate_cmd(device_index, 0.0))
+    .await
+    .expect("Test, assuming infallible.");
+  drain_dg_lab_v3_power_a(&mut device);
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert!(!powers.is_empty());
+  assert!(powers.iter().all(|p| *p == 0), "{:?}", powers);
+
+  // Stopping cancels the ramp too.
+  server
+    .parse_message(power_ramp_cmd(device_index, 2000, 1.0))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  server
+    .parse_message(message::StopDeviceCmd::new(device_index).into())
+    .await
+    .expect("Test, assuming infallible.");
+  drain_dg_lab_v3_power_a(&mut device);
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert!(!powers.is_empty());
+  assert!(powers.iter().all(|p| *p == 0), "{:?}", powers);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_dg_lab_v3_power_ramp_timing() {
+  let (server, _device) = test_server_with_device("47L121000", false);
+  let device_index = wait_for_device_added(&server).await;
+  let power = || {
+    server
+      .device_manager()
+      .device_protocol_state(device_index)
+      .expect("Test, assuming infallible.")["a"]["power"]
+      .as_u64()
+      .expect("Test, assuming infallible.")
+  };
+
+  // 20ms steps, so 50 steps of 2 over a second.
+  server
+    .parse_message(power_ramp_cmd(device_index, 1000, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  advance_paused_time(Duration::from_millis(510)).await;
+  assert_eq!(power(), 50);
+  advance_paused_time(Duration::from_millis(500)).await;
+  assert_eq!(power(), 100);
+}
+
+#[tokio::test]
+async fn test_dg_lab_v3_scalar_ramp_limit() {
+  let (server, mut device) = test_server_with_customized_device(
+    &TestDeviceIdentifier::new("47L121000", Some("RampTest".to_owned())),
+    "dg-lab-v3",
+    |config| {
+      config.set_ramp(Some(ScalarRamp::new(100, &[0, 1])));
+    },
+  );
+  let device_index = wait_for_device_added(&server).await;
+  tokio::time::sleep(Duration::from_millis(500)).await;
+
+  // Increases are limited to 100 per second, so a full power command takes 2 seconds to land.
+  server
+    .parse_message(vibrate_cmd(device_index, 1.0))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(1000)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert!(powers.windows(2).all(|w| w[0] <= w[1]), "{:?}", powers);
+  assert!(powers.iter().all(|p| *p <= 120), "{:?}", powers);
+  assert!(powers.last().is_some_and(|p| *p >= 80), "{:?}", powers);
+  tokio::time::sleep(Duration::from_millis(1300)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert_eq!(powers.last(), Some(&200), "{:?}", powers);
+
+  // Decreases go out right away.
+  server
+    .parse_message(vibrate_cmd(device_index, 0.25))
+    .await
+    .expect("Test, assuming infallible.");
+  drain_dg_lab_v3_power_a(&mut device);
+  tokio::time::sleep(Duration::from_millis(150)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert!(!powers.is_empty());
+  assert!(powers.iter().all(|p| *p == 50), "{:?}", powers);
+
+  // A newer command replaces the target of a running ramp.
+  server
+    .parse_message(vibrate_cmd(device_index, 1.0))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(800)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert!(powers.iter().all(|p| *p <= 100), "{:?}", powers);
+  assert_eq!(powers.last(), Some(&100), "{:?}", powers);
+
+  // Stopping cancels the ramp and goes out right away.
+  server
+    .parse_message(vibrate_cmd(device_index, 1.0))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  server
+    .parse_message(message::StopDeviceCmd::new(device_index).into())
+    .await
+    .expect("Test, assuming infallible.");
+  drain_dg_lab_v3_power_a(&mut device);
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert!(!powers.is_empty());
+  assert!(powers.iter().all(|p| *p == 0), "{:?}", powers);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_dg_lab_v3_auto_zero_timeout() {
+  let (server, mut device) = test_server_with_customized_device(
+    &TestDeviceIdentifier::new("47L121000", Some("AutoZeroTest".to_owned())),
+    "dg-lab-v3",
+    |config| {
+      config.set_auto_zero_timeout_ms(Some(200));
+    },
+  );
+  let device_index = wait_for_device_added(&server).await;
+  advance_paused_time(Duration::from_millis(500)).await;
+
+  server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  advance_paused_time(Duration::from_millis(100)).await;
+  assert_eq!(drain_dg_lab_v3_power_a(&mut device).last(), Some(&100));
+
+  // Every actuation command restarts the timeout, so the device is still running past the end of
+  // the first one.
+  server
+    .parse_message(vibrate_cmd(device_index, 0.6))
+    .await
+    .expect("Test, assuming infallible.");
+  advance_paused_time(Duration::from_millis(190)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert!(!powers.is_empty());
+  assert!(powers.iter().all(|p| *p == 120), "{:?}", powers);
+
+  // Without another command, the device is stopped, with no StopDeviceCmd from the client.
+  advance_paused_time(Duration::from_millis(20)).await;
+  drain_dg_lab_v3_power_a(&mut device);
+  advance_paused_time(Duration::from_millis(300)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert!(!powers.is_empty());
+  assert!(powers.iter().all(|p| *p == 0), "{:?}", powers);
+}
+
+fn drain_dg_lab_v3_frequency_a(device: &mut TestDeviceChannelHost) -> Vec<u8> {
+  let mut frequencies = vec![];
+  while let Some(Some(command)) = recv_now(&mut device.receiver) {
+    if let HardwareCommand::Write(write) = command {
+      if write.data()[0] == 0xB0 {
+        frequencies.push(write.data()[4]);
+      }
+    }
+  }
+  frequencies
+}
+
+fn scalar_feature_descriptors(device_added: &message::DeviceAdded) -> Vec<String> {
+  device_added
+    .device_messages()
+    .scalar_cmd()
+    .as_ref()
+    .expect("Test, assuming infallible.")
+    .iter()
+    .map(|attrs| attrs.feature_descriptor().clone())
+    .collect()
+}
+
+#[tokio::test]
+async fn test_dg_lab_feature_descriptors() {
+  let (server, _device) = test_server_with_device("47L121000", false);
+  assert_eq!(
+    scalar_feature_descriptors(&wait_for_device_added_message(&server).await),
+    [
+      "Channel A Power",
+      "Channel B Power",
+      "Channel A Frequency",
+      "Channel B Frequency",
+      "Channel A Waveform Strength",
+      "Channel B Waveform Strength",
+      "Channel A Power Adjustment",
+      "Channel B Power Adjustment",
+    ]
+  );
+  let (server, _device) = test_server_with_device("D-LAB ESTIM01", false);
+  assert_eq!(
+    scalar_feature_descriptors(&wait_for_device_added_message(&server).await),
+    [
+      "Channel A Power",
+      "Channel B Power",
+      "Channel A Frequency",
+      "Channel B Frequency",
+      "Channel A Pulse Width",
+      "Channel B Pulse Width",
+    ]
+  );
+}
+
+#[tokio::test]
+async fn test_dg_lab_v3_feature_count_mismatch_fails_setup() {
+  // A user override that only keeps five of the scalar features.
+  let mut index = 0;
+  let (server, _device) = test_server_with_customized_definition(
+    &TestDeviceIdentifier::new("47L121000", Some("LayoutTest".to_owned())),
+    "dg-lab-v3",
+    |definition| {
+      definition.features_mut().truncate(5);
+      index = definition.user_config().index();
+    },
+  );
+  let recv = server.device_manager().manager_event_stream();
+  pin_mut!(recv);
+  server
+    .parse_message(
+      message::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  match recv.next().await.expect("Test, assuming infallible.") {
+    ServerDeviceManagerEvent::DeviceIgnored {
+      reason: DeviceIgnoredReason::NoViableProtocolAfterConnect(message),
+      ..
+    } => {
+      assert!(
+        message.contains("has scalar features [Vibrate, Vibrate, Oscillate, Oscillate, Inflate]"),
+        "{}",
+        message
+      );
+    }
+    event => panic!("Unexpected event {:?}", event),
+  }
+  assert!(server
+    .device_manager()
+    .device_info(index)
+    .is_none());
+}
+
+#[tokio::test]
+async fn test_dg_lab_v3_mangled_feature_layout_fails_initialize() {
+  // A user override that swaps channel A power and frequency.
+  let identifier = TestDeviceIdentifier::new("47L121000", Some("LayoutTest".to_owned()));
+  let dcm = customized_test_dcm(&identifier, "dg-lab-v3", false, |definition| {
+    definition.features_mut().swap(0, 2);
+  });
+  let (mut dm_builder, _device) = test_device_manager_builder(dcm, &identifier);
+  dm_builder
+    .initialization_retry_policy(InitializationRetryPolicy::new(1, Duration::from_millis(10)));
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  assert!(
+    tokio::time::timeout(Duration::from_millis(500), wait_for_device_added(&server))
+      .await
+      .is_err()
+  );
+}
+
+async fn v2_vibrate_feature_count(server: &ButtplugServer) -> u32 {
+  match server
+    .parse_message(message::RequestDeviceList::default().into())
+    .await
+    .expect("Test, assuming infallible.")
+  {
+    ButtplugServerMessage::DeviceList(list) => {
+      let info = message::DeviceMessageInfoV2::from(list.devices()[0].clone());
+      info
+        .device_messages()
+        .vibrate_cmd()
+        .as_ref()
+        .map_or(0, |vibrate| *vibrate.feature_count())
+    }
+    msg => panic!("Unexpected message {:?}", msg),
+  }
+}
+
+#[tokio::test]
+async fn test_dg_lab_v3_treat_vibrate_as() {
+  // Without a mapping, older clients only see the power features as vibrators.
+  let (server, _device) = test_server_with_device("47L121000", false);
+  let device_index = wait_for_device_added(&server).await;
+  assert_eq!(v2_vibrate_feature_count(&server).await, 2);
+  let vibrate =
+    message::VibrateCmd::new(device_index, vec![message::VibrateSubcommand::new(2, 1.0)]);
+  assert!(server.parse_message(vibrate.into()).await.is_err());
+
+  let (server, mut device) = test_server_with_customized_device(
+    &TestDeviceIdentifier::new("47L121000", Some("AliasTest".to_owned())),
+    "dg-lab-v3",
+    |config| {
+      config.set_treat_vibrate_as([(2, message::ActuatorType::Vibrate)].into());
+    },
+  );
+  let device_index = wait_for_device_added(&server).await;
+  assert_eq!(v2_vibrate_feature_count(&server).await, 3);
+  tokio::time::sleep(Duration::from_millis(500)).await;
+
+  // The third vibrator is channel A frequency, with the value passed through unchanged.
+  server
+    .parse_message(
+      message::VibrateCmd::new(device_index, vec![message::VibrateSubcommand::new(2, 1.0)]).into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  let frequencies = drain_dg_lab_v3_frequency_a(&mut device);
+  assert_eq!(frequencies.last(), Some(&240), "{:?}", frequencies);
+
+  // Newer clients can still use the feature type, but unmapped features don't take vibrate.
+  server
+    .parse_message(
+      message::ScalarCmd::new(
+        device_index,
+        vec![message::ScalarSubcommand::new(
+          2,
+          0.0,
+          message::ActuatorType::Oscillate,
+        )],
+      )
+      .into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  let unmapped = message::ScalarCmd::new(
+    device_index,
+    vec![message::ScalarSubcommand::new(
+      3,
+      1.0,
+      message::ActuatorType::Vibrate,
+    )],
+  );
+  assert!(server.parse_message(unmapped.into()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_dg_lab_v3_soft_limits() {
+  // The soft limit characteristic comes from the bundled config, with no extra endpoints.
+  let dcm = create_test_dcm(false);
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let mut device = builder.add_test_device(&TestDeviceIdentifier::new(
+    "47L121000",
+    Some("LimitTest".to_owned()),
+  ));
+  // Channel B is set above the maximum the device supports, which is treated as no limit.
+  device
+    .sender
+    .send(TestHardwareEvent::Reads(vec![
+      TestHardwareNotification::new(Endpoint::Generic0, &[120, 250]),
+    ]))
+    .await
+    .expect("Test, assuming infallible.");
+  let mut dm_builder = ServerDeviceManagerBuilder::new(dcm);
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  let device_index = wait_for_device_added(&server).await;
+
+  // Power features advertise the capped step count, so full power maps to the cap.
+  match server
+    .parse_message(message::RequestDeviceList::default().into())
+    .await
+    .expect("Test, assuming infallible.")
+  {
+    ButtplugServerMessage::DeviceList(list) => {
+      let scalar_cmd = list.devices()[0]
+        .device_messages()
+        .scalar_cmd()
+        .clone()
+        .expect("Test, assuming infallible.");
+      assert_eq!(*scalar_cmd[0].step_count(), 120);
+      assert_eq!(*scalar_cmd[1].step_count(), 200);
+    }
+    msg => panic!("Unexpected message {:?}", msg),
+  }
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  server
+    .parse_message(vibrate_cmd(device_index, 1.0))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert_eq!(powers.last(), Some(&120), "{:?}", powers);
+
+  // Power ramps are clamped to the cap too.
+  server
+    .parse_message(power_ramp_cmd(device_index, 100, 0.0))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  server
+    .parse_message(power_ramp_cmd(device_index, 100, 1.0))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert!(powers.iter().all(|p| *p <= 120), "{:?}", powers);
+  assert_eq!(powers.last(), Some(&120), "{:?}", powers);
+}
+
+#[tokio::test]
+async fn test_new_devices_skip_reserved_indexes() {
+  let dcm = Arc::new(create_test_dcm(false));
+  let reserve = |address: &str, index: u32| {
+    let identifier = UserDeviceIdentifier::new(address, "aneros", &Some("Massage Demo".to_owned()));
+    let mut definition = dcm
+      .device_definition(&identifier, &[])
+      .expect("Test, assuming infallible.");
+    definition.set_user_config(UserDeviceCustomization::new(&None, false, false, index));
+    dcm
+      .add_user_device_definition(&identifier, &definition)
+      .expect("Test, assuming infallible.");
+    identifier
+  };
+  let first_reserved = reserve("Reserved0", 0);
+  let second_reserved = reserve("Reserved2", 2);
+  assert_eq!(dcm.reserved_index_for(&first_reserved), Some(0));
+  assert_eq!(dcm.owner_of_index(2), Some(second_reserved));
+  assert_eq!(dcm.owner_of_index(1), None);
+  assert_eq!(dcm.next_unreserved_index(), 1);
+
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let _devices: Vec<TestDeviceChannelHost> = ["New1", "New2", "New3"]
+    .iter()
+    .map(|address| {
+      builder.add_test_device(&TestDeviceIdentifier::new(
+        "Massage Demo",
+        Some(address.to_string()),
+      ))
+    })
+    .collect();
+  let mut dm_builder = ServerDeviceManagerBuilder::new_with_arc(dcm.clone());
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  server
+    .parse_message(
+      message::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  let mut indexes = vec![];
+  while let Some(msg) = recv.next().await {
+    if let ButtplugServerMessage::DeviceAdded(da) = msg {
+      indexes.push(da.device_index());
+      if indexes.len() == 3 {
+        break;
+      }
+    }
+  }
+  indexes.sort();
+  assert_eq!(indexes, vec![1, 3, 4]);
+  // Assigned indexes are in use, but aren't reservations.
+  assert_eq!(dcm.next_unreserved_index(), 5);
+  assert_eq!(dcm.owner_of_index(3), None);
+}
+
+#[tokio::test]
+async fn test_monitor_only_device() {
+  let (server, mut device) = test_server_with_customized_device(
+    &TestDeviceIdentifier::new("Flamingo", Some("MonitorTest".to_owned())),
+    "magic-motion-1",
+    |config| {
+      config.set_monitor_only(true);
+    },
+  );
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  server
+    .parse_message(
+      message::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  let device_added = loop {
+    match recv.next().await {
+      Some(ButtplugServerMessage::DeviceAdded(da)) => break da,
+      Some(_) => continue,
+      None => panic!("Device never added."),
+    }
+  };
+  let device_index = device_added.device_index();
+  assert!(device_added.device_messages().scalar_cmd().is_none());
+  assert!(device_added.device_messages().sensor_read_cmd().is_some());
+
+  // Actuation is rejected before it gets anywhere near the hardware.
+  let err = server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect_err("Monitor only devices should reject actuation.");
+  assert!(matches!(
+    err.original_error(),
+    ButtplugError::ButtplugDeviceError(ButtplugDeviceError::MessageNotSupported(_))
+  ));
+  assert!(server
+    .parse_message(
+      message::VibrateCmd::new(device_index, vec![message::VibrateSubcommand::new(0, 0.5)]).into()
+    )
+    .await
+    .is_err());
+  server
+    .parse_message(message::StopDeviceCmd::new(device_index).into())
+    .await
+    .expect("Test, assuming infallible.");
+  assert!(recv_now(&mut device.receiver).is_none());
+
+  // Sensors still work.
+  device
+    .sender
+    .send(TestHardwareEvent::Reads(vec![
+      TestHardwareNotification::new(Endpoint::RxBLEBattery, &[80]),
+    ]))
+    .await
+    .expect("Test, assuming infallible.");
+  match server
+    .parse_message(message::SensorReadCmd::new(device_index, 0, SensorType::Battery).into())
+    .await
+    .expect("Test, assuming infallible.")
+  {
+    ButtplugServerMessage::SensorReading(reading) => assert_eq!(*reading.data(), vec![80]),
+    msg => panic!("Unexpected message {:?}", msg),
+  }
+}
+
+#[tokio::test]
+async fn test_dg_lab_v2_battery_reading() {
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let device = builder.add_test_device(&TestDeviceIdentifier::new("D-LAB ESTIM01", None));
+  let mut dm_builder = ServerDeviceManagerBuilder::new(create_test_dcm(false));
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  let device_index = wait_for_device_added(&server).await;
+
+  device
+    .sender
+    .send(TestHardwareEvent::Reads(vec![
+      TestHardwareNotification::new(Endpoint::RxBLEBattery, &[87]),
+    ]))
+    .await
+    .expect("Test, assuming infallible.");
+  match server
+    .parse_message(message::SensorReadCmd::new(device_index, 0, SensorType::Battery).into())
+    .await
+    .expect("Test, assuming infallible.")
+  {
+    ButtplugServerMessage::SensorReading(reading) => assert_eq!(*reading.data(), vec![87]),
+    msg => panic!("Unexpected message {:?}", msg),
+  }
+}
+
+#[tokio::test]
+async fn test_dg_lab_v2_swap_channels() {
+  let (server, mut device) = test_server_with_customized_device(
+    &TestDeviceIdentifier::new("D-LAB ESTIM01", Some("SwapTest".to_owned())),
+    "dg-lab-v2",
+    |config| {
+      config.set_swap_channels(true);
+    },
+  );
+  let device_index = wait_for_device_added(&server).await;
+
+  // Channel A power (feature 0) is written to the channel B bits.
+  server
+    .parse_message(vibrate_cmd(device_index, 1.0))
+    .await
+    .expect("Test, assuming infallible.");
+  let mut power_writes = vec![];
+  while let Some(Some(command)) = recv_now(&mut device.receiver) {
+    if let HardwareCommand::Write(write) = command {
+      if write.endpoint() == Endpoint::Tx {
+        power_writes.push(write.data().clone());
+      }
+    }
+  }
+  assert_eq!(power_writes, vec![vec![0x00, 0xF8, 0x3F]]);
+}
+
+#[tokio::test]
+async fn test_invert_rotation() {
+  let (server, mut device) = test_server_with_customized_device(
+    &TestDeviceIdentifier::new("CycSA", Some("InvertTest".to_owned())),
+    "vorze-sa",
+    |config| {
+      config.set_invert_rotation(Some(RotationInversion::Features(vec![0])));
+    },
+  );
+  let device_index = wait_for_device_added(&server).await;
+  assert_eq!(
+    *server
+      .device_manager()
+      .device_info(device_index)
+      .expect("Test, assuming infallible.")
+      .inverted_rotations(),
+    vec![0]
+  );
+
+  // Vorze encodes clockwise as the top bit of the speed byte.
+  for (clockwise, data) in [(true, 0x32), (false, 0xB2)] {
+    server
+      .parse_message(
+        message::RotateCmd::new(
+          device_index,
+          vec![message::RotationSubcommand::new(0, 0.5, clockwise)],
+        )
+        .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    assert_eq!(
+      recv_now(&mut device.receiver),
+      Some(Some(HardwareCommand::Write(HardwareWriteCmd::new(
+        Endpoint::Tx,
+        vec![0x01, 0x01, data],
+        true,
+      ))))
+    );
+  }
+}
+
+fn test_server_with_failing_device(
+  failed_commands: u32,
+  retry_policy: InitializationRetryPolicy,
+) -> (ButtplugServer, TestDeviceChannelHost) {
+  let (mut dm_builder, device) = test_device_manager_builder(
+    create_test_dcm(false),
+    &TestDeviceIdentifier::new("47L121000", None).with_failed_commands(failed_commands),
+  );
+  dm_builder.initialization_retry_policy(retry_policy);
+  (
+    ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+      .finish()
+      .unwrap(),
+    device,
+  )
+}
+
+#[tokio::test]
+async fn test_device_initialization_retry() {
+  // DG-Lab V3 subscribes during initialization, so the first two attempts will fail.
+  let (server, mut device) =
+    test_server_with_failing_device(2, InitializationRetryPolicy::default());
+  let device_index = wait_for_device_added(&server).await;
+  server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  // Only one repeat loop should be running, which sends a packet every 100ms.
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  drain_dg_lab_v3_power_a(&mut device);
+  tokio::time::sleep(Duration::from_millis(1000)).await;
+  let powers = drain_dg_lab_v3_power_a(&mut device);
+  assert!((5..=12).contains(&powers.len()), "{:?}", powers);
+  assert!(powers.iter().all(|p| *p == 100), "{:?}", powers);
+}
+
+#[tokio::test]
+async fn test_device_initialization_retries_exhausted() {
+  let (server, _device) = test_server_with_failing_device(
+    2,
+    InitializationRetryPolicy::new(2, Duration::from_millis(10)),
+  );
+  assert!(
+    tokio::time::timeout(Duration::from_millis(500), wait_for_device_added(&server))
+      .await
+      .is_err()
+  );
+}
+
+// Identifies every device as a "hang-test" device, whose initialization never finishes.
+#[derive(Default)]
+struct HangingProtocolFactory {
+  hardware_events: Arc<Mutex<Option<broadcast::Receiver<HardwareEvent>>>>,
+}
+
+struct HangingProtocol {
+  hardware_events: Arc<Mutex<Option<broadcast::Receiver<HardwareEvent>>>>,
+}
+
+impl ProtocolIdentifierFactory for HangingProtocolFactory {
+  fn identifier(&self) -> &str {
+    "hang-test"
+  }
+
+  fn create(&self) -> Box<dyn ProtocolIdentifier> {
+    Box::new(HangingProtocol {
+      hardware_events: self.hardware_events.clone(),
+    })
+  }
+}
+
+#[async_trait]
+impl ProtocolIdentifier for HangingProtocol {
+  async fn identify(
+    &mut self,
+    hardware: Arc<Hardware>,
+  ) -> Result<(UserDeviceIdentifier, Box<dyn ProtocolInitializer>), ButtplugDeviceError> {
+    Ok((
+      UserDeviceIdentifier::new(hardware.address(), "hang-test", &None),
+      Box::new(HangingProtocol {
+        hardware_events: self.hardware_events.clone(),
+      }),
+    ))
+  }
+}
+
+#[async_trait]
+impl ProtocolInitializer for HangingProtocol {
+  async fn initialize(
+    &mut self,
+    hardware: Arc<Hardware>,
+    _: &ProtocolDeviceAttributes,
+  ) -> Result<Arc<dyn ProtocolHandler>, ButtplugDeviceError> {
+    *self
+      .hardware_events
+      .lock()
+      .expect("Test, assuming infallible.") = Some(hardware.event_stream());
+    future::pending().await
+  }
+}
+
+const HANGING_PROTOCOL_JSON: &str = r#"
+{
+  "initialize-timeout-ms": 200,
+  "defaults": {
+    "name": "Hanging Device",
+    "features": [
+      {
+        "feature-type": "Vibrate",
+        "actuator": {
+          "step-range": [0, 100],
+          "messages": ["ScalarCmd"]
+        }
+      }
+    ]
+  },
+  "communication": [
+    {
+      "btle": {
+        "names": ["HangDevice"],
+        "services": {
+          "0000ff00-0000-1000-8000-00805f9b34fb": {
+            "tx": "0000ff01-0000-1000-8000-00805f9b34fb"
+          }
+        }
+      }
+    }
+  ]
+}
+"#;
+
+#[tokio::test]
+async fn test_device_initialization_timeout() {
+  let factory = HangingProtocolFactory::default();
+  let hardware_events = factory.hardware_events.clone();
+  let mut dcm_builder =
+    load_protocol_configs(&None, &None, false).expect("Test, assuming infallible.");
+  add_protocol_definition_from_json(&mut dcm_builder, "hang-test", HANGING_PROTOCOL_JSON)
+    .expect("Test, assuming infallible.");
+  dcm_builder.protocol_factory(factory);
+  let dcm = dcm_builder.finish().expect("Test, assuming infallible.");
+  assert_eq!(
+    dcm.protocol_initialize_timeout("hang-test"),
+    Duration::from_millis(200)
+  );
+
+  // The hanging device is found on the first scan. A working device shows up at the same address
+  // on the second one.
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  builder.add_test_device(&TestDeviceIdentifier::new(
+    "HangDevice",
+    Some("HangAddress".to_owned()),
+  ));
+  builder.add_test_device(
+    &TestDeviceIdentifier::new("Massage Demo", Some("HangAddress".to_owned()))
+      .with_skipped_scans(1),
+  );
+  let mut dm_builder = ServerDeviceManagerBuilder::new(dcm);
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  server
+    .parse_message(
+      message::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+
+  // Timing out disconnects the hardware.
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  let mut events = hardware_events
+    .lock()
+    .expect("Test, assuming infallible.")
+    .take()
+    .expect("Initialization should have started.");
+  assert!(matches!(
+    events.try_recv(),
+    Ok(HardwareEvent::Disconnected(address)) if address == "HangAddress"
+  ));
+
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  let device_added = tokio::time::timeout(Duration::from_secs(5), async {
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        return da;
+      }
+    }
+    panic!("Server event stream ended.");
+  })
+  .await
+  .expect("Second device should connect after the first one timed out.");
+  assert_eq!(device_added.device_name(), "Aneros Vivi");
+}
+
+// A protocol from outside the library, writing [0xAA, feature, speed] for vibration.
+#[derive(Default)]
+struct ToyProtocol {}
+
+impl ProtocolHandler for ToyProtocol {
+  fn handle_scalar_vibrate_cmd(
+    &self,
+    index: u32,
+    scalar: u32,
+  ) -> Result<Vec<HardwareCommand>, ButtplugDeviceError> {
+    Ok(vec![HardwareWriteCmd::new(
+      Endpoint::Tx,
+      vec![0xAA, index as u8, scalar as u8],
+      false,
+    )
+    .into()])
+  }
+}
+
+struct ToyProtocolFactory {
+  identifier: String,
+}
+
+impl ToyProtocolFactory {
+  fn new(identifier: &str) -> Arc<Self> {
+    Arc::new(Self {
+      identifier: identifier.to_owned(),
+    })
+  }
+}
+
+impl ProtocolIdentifierFactory for ToyProtocolFactory {
+  fn identifier(&self) -> &str {
+    &self.identifier
+  }
+
+  fn create(&self) -> Box<dyn ProtocolIdentifier> {
+    Box::new(GenericProtocolIdentifier::new(
+      Arc::new(ToyProtocol::default()),
+      self.identifier(),
+    ))
+  }
+}
+
+const TOY_PROTOCOL_JSON: &str = r#"
+{
+  "defaults": {
+    "name": "Toy Device",
+    "features": [
+      {
+        "feature-type": "Vibrate",
+        "actuator": {
+          "step-range": [0, 100],
+          "messages": ["ScalarCmd"]
+        }
+      }
+    ]
+  },
+  "communication": [
+    {
+      "btle": {
+        "names": ["ToyDevice"],
+        "services": {
+          "0000ff00-0000-1000-8000-00805f9b34fb": {
+            "tx": "0000ff01-0000-1000-8000-00805f9b34fb"
+          }
+        }
+      }
+    }
+  ]
+}
+"#;
+
+// The user config adds a second name for the toy protocol.
+const TOY_PROTOCOL_USER_CONFIG_JSON: &str = r#"
+{
+  "version": { "major": 3, "minor": 0 },
+  "user-configs": {
+    "protocols": {
+      "toy-protocol": {
+        "communication": [
+          {
+            "btle": {
+              "names": ["UserToyDevice"],
+              "services": {
+                "0000ff00-0000-1000-8000-00805f9b34fb": {
+                  "tx": "0000ff01-0000-1000-8000-00805f9b34fb"
+                }
+              }
+            }
+          }
+        ]
+      }
+    }
+  }
+}
+"#;
+
+fn toy_protocol_device_manager_builder(name: &str) -> ServerDeviceManagerBuilder {
+  let dcm_builder = load_protocol_configs(
+    &None,
+    &Some(TOY_PROTOCOL_USER_CONFIG_JSON.to_owned()),
+    false,
+  )
+  .expect("Test, assuming infallible.");
+  let mut dm_builder = ServerDeviceManagerBuilder::new_from_config(dcm_builder);
+  dm_builder
+    .add_protocol_factory(name, ToyProtocolFactory::new(name))
+    .configure_devices(|config| {
+      add_protocol_definition_from_json(config, "toy-protocol", TOY_PROTOCOL_JSON)
+        .expect("Test, assuming infallible.");
+    });
+  dm_builder
+}
+
+#[tokio::test]
+async fn test_external_protocol_factory() {
+  for device_name in ["ToyDevice", "UserToyDevice"] {
+    let mut builder = TestDeviceCommunicationManagerBuilder::default();
+    let mut device = builder.add_test_device(&TestDeviceIdentifier::new(device_name, None));
+    let mut dm_builder = toy_protocol_device_manager_builder("toy-protocol");
+    dm_builder.comm_manager(builder);
+    let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+      .finish()
+      .unwrap();
+    let device_added = wait_for_device_added_message(&server).await;
+    assert_eq!(device_added.device_name(), "Toy Device");
+
+    server
+      .parse_message(vibrate_cmd(device_added.device_index(), 0.5))
+      .await
+      .expect("Test, assuming infallible.");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    check_test_recv_value(
+      &mut device,
+      HardwareCommand::Write(HardwareWriteCmd::new(
+        Endpoint::Tx,
+        vec![0xAA, 0, 50],
+        false,
+      )),
+    );
+  }
+}
+
+#[test]
+fn test_external_protocol_factory_name_collision() {
+  // Built in protocols can't be replaced.
+  let mut dm_builder = toy_protocol_device_manager_builder("lovense");
+  assert!(matches!(
+    dm_builder.finish(),
+    Err(ButtplugServerError::DeviceConfigurationManagerError(
+      ButtplugDeviceError::ConfigurationError(ConfigurationError::ProtocolAlreadyRegistered {
+        ref protocol
+      })
+    )) if protocol == "lovense"
+  ));
+
+  // Neither can protocols registered earlier.
+  let mut dm_builder = toy_protocol_device_manager_builder("toy-protocol");
+  dm_builder.add_protocol_factory("toy-protocol", ToyProtocolFactory::new("toy-protocol"));
+  assert!(dm_builder.finish().is_err());
+
+  // Factories have to be registered under their own name.
+  let mut dm_builder = toy_protocol_device_manager_builder("toy-protocol");
+  dm_builder.add_protocol_factory(
+    "other-toy-protocol",
+    ToyProtocolFactory::new("toy-protocol"),
+  );
+  assert!(dm_builder.finish().is_err());
+
+  // Built managers can't take new protocols.
+  let mut dm_builder = ServerDeviceManagerBuilder::new(create_test_dcm(false));
+  dm_builder.add_protocol_factory("toy-protocol", ToyProtocolFactory::new("toy-protocol"));
+  assert!(dm_builder.finish().is_err());
+}
+
+#[tokio::test]
+async fn test_auto_connect_device() {
+  let identifier = TestDeviceIdentifier::new("Massage Demo", Some("AutoTest".to_owned()));
+  let mut index = 0;
+  let dcm = customized_test_dcm(&identifier, "aneros", false, |definition| {
+    definition.user_config_mut().set_auto_connect(true);
+    index = definition.user_config().index();
+  });
+
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let _device = builder.add_test_device(&identifier);
+  let other_device = builder.add_test_device(&TestDeviceIdentifier::new(
+    "Massage Demo",
+    Some("OtherTest".to_owned()),
+  ));
+  let mut dm_builder = ServerDeviceManagerBuilder::new(dcm);
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+
+  // The device connects without any client scanning.
+  let mut connected = false;
+  for _ in 0..50 {
+    if server.device_manager().device_info(index).is_some() {
+      connected = true;
+      break;
+    }
+    tokio::time::sleep(Duration::from_millis(20)).await;
+  }
+  assert!(connected);
+  // Devices that aren't auto-connect are left for client scans.
+  assert_eq!(other_device.connect_attempts.load(Ordering::SeqCst), 0);
+
+  server
+    .parse_message(
+      message::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  match server
+    .parse_message(message::RequestDeviceList::default().into())
+    .await
+    .expect("Test, assuming infallible.")
+  {
+    ButtplugServerMessage::DeviceList(list) => {
+      assert_eq!(list.devices().len(), 1);
+      assert_eq!(list.devices()[0].device_index(), index);
+    }
+    msg => panic!("Unexpected message {:?}", msg),
+  }
+}
+
+async fn wait_for_device_ignored(
+  dcm: DeviceConfigurationManager,
+  device_name: &str,
+  address: &str,
+) -> ServerDeviceManagerEvent {
+  wait_for_device_ignored_with_builder(ServerDeviceManagerBuilder::new(dcm), device_name, address)
+    .await
+}
+
+async fn wait_for_device_ignored_with_builder(
+  dm_builder: ServerDeviceManagerBuilder,
+  device_name: &str,
+  address: &str,
+) -> ServerDeviceManagerEvent {
+  wait_for_device_ignored_with_comm_builder(
+    dm_builder,
+    TestDeviceCommunicationManagerBuilder::default(),
+    device_name,
+    address,
+  )
+  .await
+}
+
+async fn wait_for_device_ignored_with_comm_builder(
+  mut dm_builder: ServerDeviceManagerBuilder,
+  mut builder: TestDeviceCommunicationManagerBuilder,
+  device_name: &str,
+  address: &str,
+) -> ServerDeviceManagerEvent {
+  let device = builder.add_test_device(&TestDeviceIdentifier::new(
+    device_name,
+    Some(address.to_owned()),
+  ));
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  let recv = server.device_manager().manager_event_stream();
+  pin_mut!(recv);
+  server
+    .parse_message(
+      message::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  let event = recv.next().await.expect("Test, assuming infallible.");
+  // Ignored devices should be dropped before we ever try to connect to them.
+  assert_eq!(device.connect_attempts.load(Ordering::SeqCst), 0);
+  event
+}
+
+/// Scan for a device the user config address filter drops, returning the addresses the comm manager
+/// filtered out. Filtered devices never reach the device manager, so they aren't even reported as
+/// ignored.
+async fn scan_for_filtered_device(
+  mut dm_builder: ServerDeviceManagerBuilder,
+  device_name: &str,
+  address: &str,
+) -> Vec<String> {
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let device = builder.add_test_device(&TestDeviceIdentifier::new(
+    device_name,
+    Some(address.to_owned()),
+  ));
+  let filtered_addresses = builder.filtered_addresses();
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  let manager_events = server.device_manager().manager_event_stream();
+  pin_mut!(manager_events);
+  let events = server.event_stream();
+  pin_mut!(events);
+  server
+    .parse_message(
+      message::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::timeout(Duration::from_secs(5), async {
+    while let Some(msg) = events.next().await {
+      if let ButtplugServerMessage::ScanningFinished(_) = msg {
+        break;
+      }
+    }
+  })
+  .await
+  .expect("Test, assuming infallible.");
+  assert!(manager_events.next().now_or_never().is_none());
+  assert_eq!(device.connect_attempts.load(Ordering::SeqCst), 0);
+  let filtered_addresses = filtered_addresses
+    .lock()
+    .expect("Test, assuming infallible.")
+    .clone();
+  filtered_addresses
+}
+
+#[tokio::test]
+async fn test_device_ignored_deny_listed() {
+  let dcm = create_test_dcm(false);
+  dcm
+    .add_user_device_definition(
+      &UserDeviceIdentifier::new("DenyTest", "aneros", &Some("Massage Demo".to_owned())),
+      &UserDeviceDefinition::new(
+        "Aneros Vivi",
+        &[],
+        &UserDeviceCustomization::new(&None, false, true, 0),
+      ),
+    )
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    scan_for_filtered_device(
+      ServerDeviceManagerBuilder::new(dcm),
+      "Massage Demo",
+      "DenyTest"
+    )
+    .await,
+    vec!["DenyTest".to_owned()]
+  );
+}
+
+#[tokio::test]
+async fn test_device_ignored_deny_listed_without_address_filter() {
+  let dcm = create_test_dcm(false);
+  dcm
+    .add_user_device_definition(
+      &UserDeviceIdentifier::new("DenyTest", "aneros", &Some("Massage Demo".to_owned())),
+      &UserDeviceDefinition::new(
+        "Aneros Vivi",
+        &[],
+        &UserDeviceCustomization::new(&None, false, true, 0),
+      ),
+    )
+    .expect("Test, assuming infallible.");
+  // Comm managers that don't check the filter still have their devices dropped by the device
+  // manager.
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  builder.ignore_address_filter();
+  assert_eq!(
+    wait_for_device_ignored_with_comm_builder(
+      ServerDeviceManagerBuilder::new(dcm),
+      builder,
+      "Massage Demo",
+      "DenyTest"
+    )
+    .await,
+    ServerDeviceManagerEvent::DeviceIgnored {
+      name: "Massage Demo".to_owned(),
+      address: "DenyTest".to_owned(),
+      reason: DeviceIgnoredReason::DenyListed,
+    }
+  );
+}
+
+#[tokio::test]
+async fn test_device_ignored_not_allow_listed() {
+  let dcm = create_test_dcm(false);
+  dcm
+    .add_user_device_definition(
+      &UserDeviceIdentifier::new("AllowTest", "aneros", &Some("Massage Demo".to_owned())),
+      &UserDeviceDefinition::new(
+        "Aneros Vivi",
+        &[],
+        &UserDeviceCustomization::new(&None, true, false, 0),
+      ),
+    )
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    scan_for_filtered_device(
+      ServerDeviceManagerBuilder::new(dcm),
+      "Massage Demo",
+      "NotAllowTest"
+    )
+    .await,
+    vec!["NotAllowTest".to_owned()]
+  );
+}
+
+#[tokio::test]
+async fn test_device_ignored_no_matching_protocol() {
+  assert_eq!(
+    wait_for_device_ignored(
+      create_test_dcm(false),
+      "Not A Real Device",
+      "NoProtocolTest"
+    )
+    .await,
+    ServerDeviceManagerEvent::DeviceIgnored {
+      name: "Not A Real Device".to_owned(),
+      address: "NoProtocolTest".to_owned(),
+      reason: DeviceIgnoredReason::NoMatchingProtocol,
+    }
+  );
+}
+
+#[tokio::test]
+async fn test_unmatched_advertisement_diagnostics() {
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  builder.add_test_device(&TestDeviceIdentifier::new(
+    "massage demo",
+    Some("NearMissTest".to_owned()),
+  ));
+  builder.add_test_device(
+    &TestDeviceIdentifier::new("Massage Demo", Some("NextScanTest".to_owned()))
+      .with_skipped_scans(1),
+  );
+  let mut dm_builder = ServerDeviceManagerBuilder::new(create_test_dcm(false));
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  let manager_recv = server.device_manager().manager_event_stream();
+  pin_mut!(manager_recv);
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  server
+    .parse_message(
+      message::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  assert!(matches!(
+    manager_recv.next().await,
+    Some(ServerDeviceManagerEvent::DeviceIgnored {
+      reason: DeviceIgnoredReason::NoMatchingProtocol,
+      ..
+    })
+  ));
+
+  let unmatched = server.device_manager().unmatched_advertisements();
+  assert_eq!(unmatched.len(), 1);
+  assert_eq!(unmatched[0].name(), "massage demo");
+  assert_eq!(unmatched[0].address(), "NearMissTest");
+  assert!(unmatched[0].manufacturer_data().is_empty());
+  assert!(unmatched[0].advertised_services().is_empty());
+  assert!(unmatched[0].service_data().is_empty());
+  assert_eq!(
+    *unmatched[0].near_misses(),
+    vec![ProtocolNearMiss::new(
+      "aneros",
+      BluetoothLEMatchFailure::NameCaseMismatch("Massage Demo".to_owned())
+    )]
+  );
+
+  // Restarting scanning starts over with an empty list. The near miss isn't found again, so only
+  // the device that connects in the second scan is seen.
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::timeout(Duration::from_secs(5), async {
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(_) = msg {
+        return;
+      }
+    }
+    panic!("Server event stream ended.");
+  })
+  .await
+  .expect("Device should connect in the second scan.");
+  assert!(server
+    .device_manager()
+    .unmatched_advertisements()
+    .is_empty());
+}
+
+#[tokio::test]
+async fn test_disabled_protocol_not_matched() {
+  // The first device connects before the protocol is disabled, the second is found while it's
+  // disabled, and the third after it's enabled again.
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  builder.add_test_device(&TestDeviceIdentifier::new(
+    "Massage Demo",
+    Some("DisableTest1".to_owned()),
+  ));
+  builder.add_test_device(
+    &TestDeviceIdentifier::new("Massage Demo", Some("DisableTest2".to_owned()))
+      .with_skipped_scans(1),
+  );
+  builder.add_test_device(
+    &TestDeviceIdentifier::new("Massage Demo", Some("DisableTest3".to_owned()))
+      .with_skipped_scans(2),
+  );
+  let mut dm_builder = ServerDeviceManagerBuilder::new(create_test_dcm(false));
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  let dcm = server
+    .device_manager()
+    .device_configuration_manager()
+    .clone();
+  let manager_recv = server.device_manager().manager_event_stream();
+  pin_mut!(manager_recv);
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  let first = wait_for_device_added(&server).await;
+
+  dcm
+    .disable_protocol("aneros")
+    .expect("Test, assuming infallible.");
+  assert!(!dcm.protocol_enabled("aneros"));
+  assert!(!dcm.enabled_protocols().contains(&"aneros".to_owned()));
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  // Skip the unknown identifier event from the first device connecting.
+  let ignored = loop {
+    let event = manager_recv
+      .next()
+      .await
+      .expect("Test, assuming infallible.");
+    if matches!(event, ServerDeviceManagerEvent::DeviceIgnored { .. }) {
+      break event;
+    }
+  };
+  assert_eq!(
+    ignored,
+    ServerDeviceManagerEvent::DeviceIgnored {
+      name: "Massage Demo".to_owned(),
+      address: "DisableTest2".to_owned(),
+      reason: DeviceIgnoredReason::NoMatchingProtocol,
+    }
+  );
+  // Devices that connected before the protocol was disabled are left alone.
+  assert!(server.device_manager().device_info(first).is_some());
+
+  dcm.enable_protocol("aneros");
+  assert!(dcm.protocol_enabled("aneros"));
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  let device_added = tokio::time::timeout(Duration::from_secs(5), async {
+    while let Some(msg) = recv.next().await {
+      if let ButtplugServerMessage::DeviceAdded(da) = msg {
+        if da.device_index() != first {
+          return da;
+        }
+      }
+    }
+    panic!("Server event stream ended.");
+  })
+  .await
+  .expect("Device should connect once its protocol is enabled again.");
+  assert_eq!(device_added.device_name(), "Aneros Vivi");
+}
+
+fn configured_device_manager_builder<F>(hook: F) -> ServerDeviceManagerBuilder
+where
+  F: FnMut(&mut DeviceConfigurationManagerBuilder) + Send + 'static,
+{
+  let mut dm_builder = ServerDeviceManagerBuilder::new_from_config(
+    load_protocol_configs(&None, &None, false).expect("Test, assuming infallible."),
+  );
+  dm_builder.configure_devices(hook);
+  dm_builder
+}
+
+#[tokio::test]
+async fn test_configure_devices_remove_protocol() {
+  let dm_builder = configured_device_manager_builder(|config| {
+    config.remove_protocol("aneros");
+  });
+  assert_eq!(
+    wait_for_device_ignored_with_builder(dm_builder, "Massage Demo", "RemovedProtocolTest").await,
+    ServerDeviceManagerEvent::DeviceIgnored {
+      name: "Massage Demo".to_owned(),
+      address: "RemovedProtocolTest".to_owned(),
+      reason: DeviceIgnoredReason::NoMatchingProtocol,
+    }
+  );
+}
+
+#[tokio::test]
+async fn test_configure_devices_deny_address() {
+  let dm_builder = configured_device_manager_builder(|config| {
+    config.deny_address("HookDenyTest");
+  });
+  assert_eq!(
+    scan_for_filtered_device(dm_builder, "Massage Demo", "HookDenyTest").await,
+    vec!["HookDenyTest".to_owned()]
+  );
+
+  // Hooks run in order, and other addresses are still allowed.
+  let mut dm_builder = configured_device_manager_builder(|config| {
+    config.deny_address("HookDenyTest1");
+  });
+  dm_builder.configure_devices(|config| {
+    config.deny_address("HookDenyTest2");
+  });
+  let device_manager = dm_builder.finish().expect("Test, assuming infallible.");
+  let dcm = device_manager.device_configuration_manager();
+  assert!(!dcm.address_allowed("HookDenyTest1"));
+  assert!(!dcm.address_allowed("HookDenyTest2"));
+  assert!(dcm.address_allowed("HookDenyTest3"));
+}
+
+#[tokio::test]
+async fn test_configure_devices_needs_config_builder() {
+  let mut dm_builder = ServerDeviceManagerBuilder::new(create_test_dcm(false));
+  dm_builder.configure_devices(|config| {
+    config.deny_address("HookDenyTest");
+  });
+  assert!(matches!(
+    dm_builder.finish(),
+    Err(ButtplugServerError::DeviceConfigurationManagerError(_))
+  ));
+}
+
+#[tokio::test]
+async fn test_unknown_device_identifier_uses_defaults() {
+  // Xibao has no configurations, so any name it matches is an identifier we don't know about.
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let _device = builder.add_test_device(&TestDeviceIdentifier::new(
+    "CCYB_NEWMODEL",
+    Some("UnknownModelTest".to_owned()),
+  ));
+  let mut dm_builder = ServerDeviceManagerBuilder::new(create_test_dcm(false));
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  let recv = server.device_manager().manager_event_stream();
+  pin_mut!(recv);
+  wait_for_device_added(&server).await;
+  assert_eq!(
+    recv.next().await.expect("Test, assuming infallible."),
+    ServerDeviceManagerEvent::UnknownDeviceIdentifier {
+      name: "Xibao Smart Masturbation Cup".to_owned(),
+      address: "UnknownModelTest".to_owned(),
+      identifier: BaseDeviceIdentifier::new("xibao", &Some("CCYB_NEWMODEL".to_owned())),
+    }
+  );
+}
+
+#[tokio::test]
+async fn test_dg_lab_v3_user_config_follows_device_id() {
+  let dcm = Arc::new(create_test_dcm(false));
+  let mut old_identifier =
+    UserDeviceIdentifier::new("OldAddress", "dg-lab-v3", &Some("47L121000".to_owned()));
+  old_identifier.set_stable_id(Some("3A1B2C4D".to_owned()));
+  let mut definition = dcm
+    .device_definition(&old_identifier, &[])
+    .expect("Test, assuming infallible.");
+  definition.set_user_config(UserDeviceCustomization::new(
+    &Some("Device Id Test".to_owned()),
+    false,
+    false,
+    6,
+  ));
+  dcm
+    .add_user_device_definition(&old_identifier, &definition)
+    .expect("Test, assuming infallible.");
+
+  // Same device, new address. The soft limits are read before the device ID.
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let device = builder.add_test_device(&TestDeviceIdentifier::new(
+    "47L121000",
+    Some("NewAddress".to_owned()),
+  ));
+  device
+    .sender
+    .send(TestHardwareEvent::Reads(vec![
+      TestHardwareNotification::new(Endpoint::Generic0, &[200, 200]),
+      TestHardwareNotification::new(Endpoint::Generic1, b"3A1B2C4D\0\0\0\0"),
+    ]))
+    .await
+    .expect("Test, assuming infallible.");
+  let mut dm_builder = ServerDeviceManagerBuilder::new_with_arc(dcm.clone());
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+
+  let device_added = wait_for_device_added_message(&server).await;
+  assert_eq!(device_added.device_index(), 6);
+  assert_eq!(
+    device_added.device_display_name(),
+    &Some("Device Id Test".to_owned())
+  );
+  let definitions = dcm.user_device_definitions();
+  assert_eq!(definitions.len(), 1);
+  let entry = definitions
+    .iter()
+    .next()
+    .expect("Test, assuming infallible.");
+  assert_eq!(entry.key().address(), "NewAddress");
+  assert_eq!(entry.key().stable_id(), &Some("3A1B2C4D".to_owned()));
+}
+
+#[tokio::test]
+async fn test_device_user_config_follows_stable_id() {
+  let dcm = Arc::new(create_test_dcm(false));
+  let mut old_identifier =
+    UserDeviceIdentifier::new("OldAddress", "lovense", &Some("B".to_owned()));
+  old_identifier.set_stable_id(Some("0082059AD3BD".to_owned()));
+  let mut definition = dcm
+    .device_definition(&old_identifier, &[])
+    .expect("Test, assuming infallible.");
+  definition.set_user_config(UserDeviceCustomization::new(
+    &Some("Stable Id Test".to_owned()),
+    false,
+    false,
+    5,
+  ));
+  // A second vibrator is only seen by the protocol if initialize gets the stable id config, in
+  // which case it switches to Mply commands.
+  let vibrator = definition.features()[0].clone();
+  definition.features_mut().insert(0, vibrator);
+  dcm
+    .add_user_device_definition(&old_identifier, &definition)
+    .expect("Test, assuming infallible.");
+
+  // Same device, new address.
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let mut device = builder.add_test_device(&TestDeviceIdentifier::new(
+    "LVS-Test",
+    Some("NewAddress".to_owned()),
+  ));
+  let mut dm_builder = ServerDeviceManagerBuilder::new_with_arc(dcm.clone());
+  dm_builder.comm_manager(builder);
+  let server = Arc::new(
+    ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+      .finish()
+      .unwrap(),
+  );
+
+  let added = {
+    let server = server.clone();
+    tokio::spawn(async move { wait_for_device_added(&server).await })
+  };
+  while let Some(command) = device.receiver.recv().await {
+    if let HardwareCommand::Write(write) = command {
+      if write.data() == b"DeviceType;" {
+        break;
+      }
+    }
+  }
+  device
+    .sender
+    .send(TestHardwareEvent::Notifications(vec![
+      TestHardwareNotification::new(Endpoint::Rx, b"B:11:0082059AD3BD;"),
+    ]))
+    .await
+    .expect("Test, assuming infallible.");
+
+  assert_eq!(added.await.expect("Test, assuming infallible."), 5);
+  let definitions = dcm.user_device_definitions();
+  assert_eq!(definitions.len(), 1);
+  let entry = definitions
+    .iter()
+    .next()
+    .expect("Test, assuming infallible.");
+  assert_eq!(entry.key().address(), "NewAddress");
+  assert_eq!(entry.key().stable_id(), &Some("0082059AD3BD".to_owned()));
+  assert_eq!(
+    entry.value().user_config().display_name(),
+    &Some("Stable Id Test".to_owned())
+  );
+
+  server
+    .parse_message(vibrate_cmd(5, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  while let Some(command) = device.receiver.recv().await {
+    if let HardwareCommand::Write(write) = command {
+      if write.data().starts_with(b"Mply") || write.data().starts_with(b"Vibrate") {
+        assert_eq!(write.data(), b"Mply:10:-1:-1;");
+        break;
+      }
+    }
+  }
+}
+
+// Identifies every device as a "serial-test" device, whose serial is only known once initialize
+// has run. Counts the handlers it creates and the ones that were shut down.
+#[derive(Default)]
+struct SerialAfterInitProtocolFactory {
+  handlers_created: Arc<AtomicU32>,
+  handlers_shut_down: Arc<AtomicU32>,
+}
+
+impl ProtocolIdentifierFactory for SerialAfterInitProtocolFactory {
+  fn identifier(&self) -> &str {
+    "serial-test"
+  }
+
+  fn create(&self) -> Box<dyn ProtocolIdentifier> {
+    Box::new(SerialAfterInitProtocol {
+      serial: None,
+      handlers_created: self.handlers_created.clone(),
+      handlers_shut_down: self.handlers_shut_down.clone(),
+    })
+  }
+}
+
+struct SerialAfterInitProtocol {
+  serial: Option<String>,
+  handlers_created: Arc<AtomicU32>,
+  handlers_shut_down: Arc<AtomicU32>,
+}
+
+#[async_trait]
+impl ProtocolIdentifier for SerialAfterInitProtocol {
+  async fn identify(
+    &mut self,
+    hardware: Arc<Hardware>,
+  ) -> Result<(UserDeviceIdentifier, Box<dyn ProtocolInitializer>), ButtplugDeviceError> {
+    Ok((
+      UserDeviceIdentifier::new(hardware.address(), "serial-test", &None),
+      Box::new(SerialAfterInitProtocol {
+        serial: None,
+        handlers_created: self.handlers_created.clone(),
+        handlers_shut_down: self.handlers_shut_down.clone(),
+      }),
+    ))
+  }
+}
+
+#[async_trait]
+impl ProtocolInitializer for SerialAfterInitProtocol {
+  async fn initialize(
+    &mut self,
+    _: Arc<Hardware>,
+    _: &ProtocolDeviceAttributes,
+  ) -> Result<Arc<dyn ProtocolHandler>, ButtplugDeviceError> {
+    self.serial = Some("SERIAL-0001".to_owned());
+    self.handlers_created.fetch_add(1, Ordering::SeqCst);
+    Ok(Arc::new(SerialAfterInitHandler {
+      handlers_shut_down: self.handlers_shut_down.clone(),
+    }))
+  }
+
+  fn device_serial(&self) -> Option<String> {
+    self.serial.clone()
+  }
+}
+
+struct SerialAfterInitHandler {
+  handlers_shut_down: Arc<AtomicU32>,
+}
+
+impl ProtocolHandler for SerialAfterInitHandler {
+  fn handle_shutdown(&self) {
+    self.handlers_shut_down.fetch_add(1, Ordering::SeqCst);
+  }
+}
+
+const SERIAL_AFTER_INIT_PROTOCOL_JSON: &str = r#"
+{
+  "defaults": {
+    "name": "Serial Device",
+    "features": [
+      {
+        "feature-type": "Vibrate",
+        "actuator": {
+          "step-range": [0, 100],
+          "messages": ["ScalarCmd"]
+        }
+      }
+    ]
+  },
+  "communication": [
+    {
+      "btle": {
+        "names": ["SerialDevice"],
+        "services": {
+          "0000ff00-0000-1000-8000-00805f9b34fb": {
+            "tx": "0000ff01-0000-1000-8000-00805f9b34fb"
+          }
+        }
+      }
+    }
+  ]
+}
+"#;
+
+#[tokio::test]
+async fn test_device_reinitialized_for_stable_id_learned_in_initialize() {
+  let factory = SerialAfterInitProtocolFactory::default();
+  let handlers_created = factory.handlers_created.clone();
+  let handlers_shut_down = factory.handlers_shut_down.clone();
+  let mut dcm_builder =
+    load_protocol_configs(&None, &None, false).expect("Test, assuming infallible.");
+  add_protocol_definition_from_json(
+    &mut dcm_builder,
+    "serial-test",
+    SERIAL_AFTER_INIT_PROTOCOL_JSON,
+  )
+  .expect("Test, assuming infallible.");
+  dcm_builder.protocol_factory(factory);
+  let dcm = Arc::new(dcm_builder.finish().expect("Test, assuming infallible."));
+
+  let mut old_identifier = UserDeviceIdentifier::new("OldAddress", "serial-test", &None);
+  old_identifier.set_stable_id(Some("SERIAL-0001".to_owned()));
+  let mut definition = dcm
+    .device_definition(&old_identifier, &[])
+    .expect("Test, assuming infallible.");
+  definition.set_user_config(UserDeviceCustomization::new(
+    &Some("Serial Test".to_owned()),
+    false,
+    false,
+    7,
+  ));
+  dcm
+    .add_user_device_definition(&old_identifier, &definition)
+    .expect("Test, assuming infallible.");
+
+  // Same device, new address.
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  builder.add_test_device(&TestDeviceIdentifier::new(
+    "SerialDevice",
+    Some("NewAddress".to_owned()),
+  ));
+  let mut dm_builder = ServerDeviceManagerBuilder::new_with_arc(dcm.clone());
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  let device_added = wait_for_device_added_message(&server).await;
+  assert_eq!(device_added.device_index(), 7);
+  assert_eq!(
+    device_added.device_display_name(),
+    &Some("Serial Test".to_owned())
+  );
+
+  // Initialized again once the serial was known, with the handler from the first attempt shut
+  // down.
+  assert_eq!(handlers_created.load(Ordering::SeqCst), 2);
+  assert_eq!(handlers_shut_down.load(Ordering::SeqCst), 1);
+}
+
+fn drain_dg_lab_v3_strength_a(device: &mut TestDeviceChannelHost) -> Vec<(u8, u8)> {
+  let mut strengths = vec![];
+  while let Some(Some(command)) = recv_now(&mut device.receiver) {
+    if let HardwareCommand::Write(write) = command {
+      if write.data()[0] == 0xB0 {
+        // Channel A parsing method, and the value it applies.
+        strengths.push(((write.data()[1] >> 2) & 0b11, write.data()[2]));
+      }
+    }
+  }
+  strengths
+}
+
+#[tokio::test]
+async fn test_dg_lab_v3_relative_power_waits_for_b1_response() {
+  let (server, mut device) = test_server_with_device("47L121000", false);
+  let device_index = wait_for_device_added(&server).await;
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  device
+    .sender
+    .send(TestHardwareEvent::Notifications(vec![
+      TestHardwareNotification::new(Endpoint::Rx, &[0xB1, 0x01, 100, 0]),
+    ]))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  drain_dg_lab_v3_strength_a(&mut device);
+
+  // Increase channel A by 25. The increase goes out once, and repeats leave strength alone until
+  // the device tells us where it ended up.
+  server
+    .parse_message(
+      message::ScalarCmd::new(
+        device_index,
+        vec![message::ScalarSubcommand::new(
+          6,
+          0.5625,
+          message::ActuatorType::Constrict,
+        )],
+      )
+      .into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  let strengths = drain_dg_lab_v3_strength_a(&mut device);
+  assert_eq!(strengths.first(), Some(&(0b01, 25)), "{:?}", strengths);
+  assert!(strengths.len() > 1, "{:?}", strengths);
+  assert!(
+    strengths[1..].iter().all(|s| *s == (0b00, 0)),
+    "{:?}",
+    strengths
+  );
+
+  device
+    .sender
+    .send(TestHardwareEvent::Notifications(vec![
+      TestHardwareNotification::new(Endpoint::Rx, &[0xB1, 0x02, 125, 0]),
+    ]))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  let strengths = drain_dg_lab_v3_strength_a(&mut device);
+  assert_eq!(strengths.last(), Some(&(0b11, 125)), "{:?}", strengths);
+}
+
+#[tokio::test]
+async fn test_dg_lab_v3_repeated_relative_power_is_sent_again() {
+  let (server, mut device) = test_server_with_device("47L121000", false);
+  let device_index = wait_for_device_added(&server).await;
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  drain_dg_lab_v3_strength_a(&mut device);
+
+  let increase = || {
+    message::ScalarCmd::new(
+      device_index,
+      vec![message::ScalarSubcommand::new(
+        6,
+        0.5625,
+        message::ActuatorType::Constrict,
+      )],
+    )
+    .into()
+  };
+  let mut increases = 0;
+  for (serial_no, strength) in [(0x01, 25), (0x02, 50)] {
+    server
+      .parse_message(increase())
+      .await
+      .expect("Test, assuming infallible.");
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    increases += drain_dg_lab_v3_strength_a(&mut device)
+      .iter()
+      .filter(|s| **s == (0b01, 25))
+      .count();
+    device
+      .sender
+      .send(TestHardwareEvent::Notifications(vec![
+        TestHardwareNotification::new(Endpoint::Rx, &[0xB1, serial_no, strength, 0]),
+      ]))
+      .await
+      .expect("Test, assuming infallible.");
+    tokio::time::sleep(Duration::from_millis(200)).await;
+  }
+  // The same +25 twice increases channel A twice.
+  assert_eq!(increases, 2);
+}
+
+#[tokio::test]
+async fn test_dry_run_device_logs_writes_instead_of_sending() {
+  let (server, mut device) = test_server_with_device("47L121000", false);
+  let device_index = wait_for_device_added(&server).await;
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  let recv = server.device_manager().manager_event_stream();
+  pin_mut!(recv);
+  server
+    .device_manager()
+    .set_device_dry_run(device_index, true)
+    .expect("Test, assuming infallible.");
+  assert!(server
+    .device_manager()
+    .device_info(device_index)
+    .expect("Test, assuming infallible.")
+    .dry_run());
+  drain_dg_lab_v3_strength_a(&mut device);
+
+  server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  assert!(drain_dg_lab_v3_strength_a(&mut device).is_empty());
+
+  // The B0 packet setting channel A to 100 should show up as a dry run event instead.
+  loop {
+    let event = tokio::time::timeout(Duration::from_secs(1), recv.next())
+      .await
+      .expect("Test, assuming infallible.")
+      .expect("Test, assuming infallible.");
+    if let ServerDeviceManagerEvent::DeviceDryRunWrite {
+      index,
+      endpoint,
+      data,
+    } = event
+    {
+      assert_eq!(index, device_index);
+      assert_eq!(endpoint, Endpoint::Tx);
+      assert_eq!(data[0], 0xB0);
+      if (data[1] >> 2) & 0b11 == 0b11 {
+        assert_eq!(data[2], 100);
+        break;
+      }
+    }
+  }
+
+  // Turning dry run off sends writes to the hardware again.
+  server
+    .device_manager()
+    .set_device_dry_run(device_index, false)
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  assert!(!drain_dg_lab_v3_strength_a(&mut device).is_empty());
+
+  assert!(matches!(
+    server
+      .device_manager()
+      .set_device_dry_run(device_index + 1, true),
+    Err(ButtplugDeviceError::DeviceNotAvailable(_))
+  ));
+}
+
+fn drain_dg_lab_v3_writes(device: &mut TestDeviceChannelHost) -> Vec<HardwareWriteCmd> {
+  let mut writes = vec![];
+  while let Some(Some(command)) = recv_now(&mut device.receiver) {
+    if let HardwareCommand::Write(write) = command {
+      if write.data()[0] == 0xB0 {
+        writes.push(write);
+      }
+    }
+  }
+  writes
+}
+
+#[tokio::test]
+async fn test_dg_lab_v3_stop_packets_written_with_response() {
+  let (server, mut device) = test_server_with_device("47L121000", false);
+  let device_index = wait_for_device_added(&server).await;
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  let writes = drain_dg_lab_v3_writes(&mut device);
+  let update = writes
+    .iter()
+    .find(|write| write.data()[2] == 100)
+    .expect("Test, assuming infallible.");
+  assert!(!update.write_with_response());
+
+  server
+    .parse_message(message::StopDeviceCmd::new(device_index).into())
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  let writes = drain_dg_lab_v3_writes(&mut device);
+  assert!(!writes.is_empty());
+  for write in writes {
+    assert_eq!(write.data()[1] & 0b1111, 0b1111);
+    assert_eq!(write.data()[2..4], [0, 0]);
+    assert!(write.write_with_response());
+  }
+}
+
+#[tokio::test]
+async fn test_user_config_forces_write_with_response() {
+  let (server, mut device) = test_server_with_customized_device(
+    &TestDeviceIdentifier::new("Massage Demo", Some("WriteWithResponseTest".to_owned())),
+    "aneros",
+    |config| {
+      config.set_write_with_response(true);
+    },
+  );
+  let device_index = wait_for_device_added(&server).await;
+
+  server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(50)).await;
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], true)),
+  );
+}
+
+#[derive(Default)]
+struct NotificationRecorder {
+  notifications: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl ProtocolHandler for NotificationRecorder {
+  fn handle_hardware_notification(&self, endpoint: Endpoint, data: &[u8]) {
+    assert_eq!(endpoint, Endpoint::Rx);
+    self
+      .notifications
+      .lock()
+      .expect("Test, assuming infallible.")
+      .push(data.to_vec());
+  }
+}
+
+#[tokio::test]
+async fn test_hardware_notifications_forwarded_until_disconnect() {
+  let (host, device_channel) = new_device_channel();
+  let mut test_device = TestDevice::new("Notification Test", "NotificationTest", device_channel);
+  test_device.add_endpoint(&Endpoint::Rx);
+  let hardware = Hardware::new(
+    "Notification Test",
+    "NotificationTest",
+    &[Endpoint::Rx],
+    Box::new(test_device),
+  );
+  // Keep a receiver around, so the test device can still send events after the handler stops
+  // listening.
+  let _event_receiver = hardware.event_stream();
+  let handler = Arc::new(NotificationRecorder::default());
+  forward_hardware_notifications(&hardware, handler.clone());
+  hardware
+    .subscribe(&HardwareSubscribeCmd::new(Endpoint::Rx))
+    .await
+    .expect("Test, assuming infallible.");
+
+  host
+    .sender
+    .send(TestHardwareEvent::Notifications(vec![
+      TestHardwareNotification::new(Endpoint::Rx, &[1, 2, 3]),
+    ]))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(100)).await;
+  assert_eq!(*handler.notifications.lock().unwrap(), vec![vec![1, 2, 3]]);
+
+  host
+    .sender
+    .send(TestHardwareEvent::Disconnect)
+    .await
+    .expect("Test, assuming infallible.");
+  host
+    .sender
+    .send(TestHardwareEvent::Notifications(vec![
+      TestHardwareNotification::new(Endpoint::Rx, &[4, 5, 6]),
+    ]))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(100)).await;
+  assert_eq!(handler.notifications.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_galaku_battery_notifications() {
+  let (server, device) = test_server_with_device("GS01", false);
+  let device_index = wait_for_device_added(&server).await;
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  server
+    .parse_message(message::SensorSubscribeCmd::new(device_index, 0, SensorType::Battery).into())
+    .await
+    .expect("Test, assuming infallible.");
+
+  // Encrypted battery report of 75%.
+  device
+    .sender
+    .send(TestHardwareEvent::Notifications(vec![
+      TestHardwareNotification::new(
+        Endpoint::RxBLEBattery,
+        &[
+          0x23, 0x81, 0xbb, 0xab, 0x88, 0x5b, 0x43, 0x23, 0xbb, 0xa3, 0x3b, 0xeb,
+        ],
+      ),
+    ]))
+    .await
+    .expect("Test, assuming infallible.");
+  let msg = tokio::time::timeout(Duration::from_secs(1), recv.next())
+    .await
+    .expect("Test, assuming infallible.")
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    msg,
+    message::SensorReading::new(device_index, 0, SensorType::Battery, vec![75]).into()
+  );
+}
+
+#[tokio::test]
+async fn test_galaku_battery_unit() {
+  let (server, device) = test_server_with_device("GS01", false);
+  let device_index = wait_for_device_added(&server).await;
+  let list = match server
+    .parse_message(message::RequestDeviceList::default().into())
+    .await
+    .expect("Test, assuming infallible.")
+  {
+    ButtplugServerMessage::DeviceList(list) => list,
+    msg => panic!("Unexpected message {:?}", msg),
+  };
+  let battery = list.devices()[0]
+    .device_messages()
+    .sensor_subscribe_cmd()
+    .as_ref()
+    .expect("Test, assuming infallible.")[0]
+    .clone();
+  assert_eq!(*battery.sensor_type(), SensorType::Battery);
+  assert_eq!(*battery.unit(), Some(message::SensorUnit::Percent));
+  assert_eq!(battery.sensor_range(), &vec![0..=100]);
+
+  // Checking readings against the range doesn't hold them back.
+  server
+    .device_manager()
+    .set_device_sensor_range_checks(device_index, true)
+    .expect("Test, assuming infallible.");
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  server
+    .parse_message(message::SensorSubscribeCmd::new(device_index, 0, SensorType::Battery).into())
+    .await
+    .expect("Test, assuming infallible.");
+  device
+    .sender
+    .send(TestHardwareEvent::Notifications(vec![
+      TestHardwareNotification::new(
+        Endpoint::RxBLEBattery,
+        &[
+          0x23, 0x81, 0xbb, 0xab, 0x88, 0x5b, 0x43, 0x23, 0xbb, 0xa3, 0x3b, 0xeb,
+        ],
+      ),
+    ]))
+    .await
+    .expect("Test, assuming infallible.");
+  let msg = tokio::time::timeout(Duration::from_secs(1), recv.next())
+    .await
+    .expect("Test, assuming infallible.")
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    msg,
+    message::SensorReading::new(device_index, 0, SensorType::Battery, vec![75]).into()
+  );
+}
+
+/// A second client connection on the device manager of `server`, past the handshake.
+async fn second_client_server(server: &ButtplugServer) -> ButtplugServer {
+  let second = ButtplugServerBuilder::new_with_shared_device_manager(server.device_manager())
+    .finish()
+    .expect("Test, assuming infallible.");
+  second
+    .parse_message(
+      message::RequestServerInfo::new("Second Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+        .into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  second
+}
+
+/// Battery subscribes and unsubscribes the hardware got since the last call.
+async fn battery_subscription_commands(device: &mut TestDeviceChannelHost) -> Vec<HardwareCommand> {
+  tokio::time::sleep(Duration::from_millis(50)).await;
+  let mut commands = vec![];
+  while let Some(Some(command)) = recv_now(&mut device.receiver) {
+    if matches!(
+      command,
+      HardwareCommand::Subscribe(_) | HardwareCommand::Unsubscribe(_)
+    ) {
+      commands.push(command);
+    }
+  }
+  commands
+}
+
+#[tokio::test]
+async fn test_sensor_subscriptions_counted_per_client() {
+  let (first, mut device) = test_server_with_device("GS01", false);
+  let device_index = wait_for_device_added(&first).await;
+  let second = second_client_server(&first).await;
+  battery_subscription_commands(&mut device).await;
+  let subscribe = || message::SensorSubscribeCmd::new(device_index, 0, SensorType::Battery);
+  let unsubscribe = || message::SensorUnsubscribeCmd::new(device_index, 0, SensorType::Battery);
+  let hardware_subscribe =
+    || HardwareCommand::Subscribe(HardwareSubscribeCmd::new(Endpoint::RxBLEBattery));
+  let hardware_unsubscribe =
+    || HardwareCommand::Unsubscribe(HardwareUnsubscribeCmd::new(Endpoint::RxBLEBattery));
+
+  // Only the first subscribe reaches the hardware, and subscribing twice is fine.
+  for (server, msg) in [
+    (&first, subscribe()),
+    (&first, subscribe()),
+    (&second, subscribe()),
+  ] {
+    server
+      .parse_message(msg.into())
+      .await
+      .expect("Test, assuming infallible.");
+  }
+  assert_eq!(
+    battery_subscription_commands(&mut device).await,
+    vec![hardware_subscribe()]
+  );
+  let mut subscribers = first
+    .device_manager()
+    .device_sensor_subscribers(device_index, 0, SensorType::Battery)
+    .expect("Test, assuming infallible.");
+  subscribers.sort();
+  let mut clients = vec![first.client_id(), second.client_id()];
+  clients.sort();
+  assert_eq!(subscribers, clients);
+
+  // The first client leaving doesn't stop notifications for the second.
+  first
+    .parse_message(unsubscribe().into())
+    .await
+    .expect("Test, assuming infallible.");
+  assert!(battery_subscription_commands(&mut device).await.is_empty());
+  let recv = second.event_stream();
+  pin_mut!(recv);
+  device
+    .sender
+    .send(TestHardwareEvent::Notifications(vec![
+      TestHardwareNotification::new(
+        Endpoint::RxBLEBattery,
+        &[
+          0x23, 0x81, 0xbb, 0xab, 0x88, 0x5b, 0x43, 0x23, 0xbb, 0xa3, 0x3b, 0xeb,
+        ],
+      ),
+    ]))
+    .await
+    .expect("Test, assuming infallible.");
+  let msg = tokio::time::timeout(Duration::from_secs(1), recv.next())
+    .await
+    .expect("Test, assuming infallible.")
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    msg,
+    message::SensorReading::new(device_index, 0, SensorType::Battery, vec![75]).into()
+  );
+
+  // Unsubscribing again, or without having subscribed, does nothing.
+  first
+    .parse_message(unsubscribe().into())
+    .await
+    .expect("Test, assuming infallible.");
+  assert!(battery_subscription_commands(&mut device).await.is_empty());
+  second
+    .parse_message(unsubscribe().into())
+    .await
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    battery_subscription_commands(&mut device).await,
+    vec![hardware_unsubscribe()]
+  );
+
+  // Same again with the clients the other way around.
+  for (server, msg) in [(&second, subscribe()), (&first, subscribe())] {
+    server
+      .parse_message(msg.into())
+      .await
+      .expect("Test, assuming infallible.");
+  }
+  second
+    .parse_message(unsubscribe().into())
+    .await
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    battery_subscription_commands(&mut device).await,
+    vec![hardware_subscribe()]
+  );
+  first
+    .parse_message(unsubscribe().into())
+    .await
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    battery_subscription_commands(&mut device).await,
+    vec![hardware_unsubscribe()]
+  );
+}
+
+#[tokio::test]
+async fn test_sensor_subscribe_waits_for_hardware() {
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let mut device = builder.add_test_device(
+    &TestDeviceIdentifier::new("GS01", None).with_subscribe_delay(Duration::from_millis(100)),
+  );
+  let first = test_server_with_comm_manager(builder, false);
+  let device_index = wait_for_device_added(&first).await;
+  let second = second_client_server(&first).await;
+  battery_subscription_commands(&mut device).await;
+  let subscribe = || message::SensorSubscribeCmd::new(device_index, 0, SensorType::Battery);
+  let subscribers = || {
+    first
+      .device_manager()
+      .device_sensor_subscribers(device_index, 0, SensorType::Battery)
+      .expect("Test, assuming infallible.")
+  };
+
+  // A client subscribing while the hardware subscribe is running gets its result, and neither
+  // client counts as subscribed if it failed.
+  device.failed_commands.store(1, Ordering::SeqCst);
+  let (first_result, second_result) = future::join(
+    first.parse_message(subscribe().into()),
+    second.parse_message(subscribe().into()),
+  )
+  .await;
+  assert!(first_result.is_err());
+  assert!(second_result.is_err());
+  assert!(subscribers().is_empty());
+
+  // The next subscribe tries the hardware again.
+  second
+    .parse_message(subscribe().into())
+    .await
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    battery_subscription_commands(&mut device).await,
+    vec![HardwareCommand::Subscribe(HardwareSubscribeCmd::new(
+      Endpoint::RxBLEBattery
+    ))]
+  );
+  assert_eq!(subscribers(), vec![second.client_id()]);
+}
+
+#[tokio::test]
+async fn test_sensor_subscriptions_released_on_client_disconnect() {
+  let (first, mut device) = test_server_with_device("GS01", false);
+  let device_index = wait_for_device_added(&first).await;
+  let second = second_client_server(&first).await;
+  for server in [&first, &second] {
+    server
+      .parse_message(message::SensorSubscribeCmd::new(device_index, 0, SensorType::Battery).into())
+      .await
+      .expect("Test, assuming infallible.");
+  }
+  battery_subscription_commands(&mut device).await;
+
+  first
+    .disconnect()
+    .await
+    .expect("Test, assuming infallible.");
+  assert!(battery_subscription_commands(&mut device).await.is_empty());
+  second
+    .disconnect()
+    .await
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    battery_subscription_commands(&mut device).await,
+    vec![HardwareCommand::Unsubscribe(HardwareUnsubscribeCmd::new(
+      Endpoint::RxBLEBattery
+    ))]
+  );
+}
+
+fn galaku_battery_test_hardware() -> (TestDeviceChannelHost, Arc<Hardware>) {
+  let (host, device_channel) = new_device_channel();
+  let mut test_device = TestDevice::new("GS01", "GalakuBatteryTest", device_channel);
+  test_device.add_endpoint(&Endpoint::Tx);
+  test_device.add_endpoint(&Endpoint::RxBLEBattery);
+  let hardware = Hardware::new(
+    "GS01",
+    "GalakuBatteryTest",
+    &[Endpoint::Tx, Endpoint::RxBLEBattery],
+    Box::new(test_device),
+  );
+  (host, Arc::new(hardware))
+}
+
+fn init_sequence_test_hardware(failed_commands: u32) -> (TestDeviceChannelHost, Arc<Hardware>) {
+  let (host, device_channel) = new_device_channel();
+  let mut test_device = TestDevice::new("InitSequence", "InitSequenceTest", device_channel);
+  test_device.add_endpoint(&Endpoint::Tx);
+  test_device.add_endpoint(&Endpoint::Command);
+  test_device.fail_next_commands(failed_commands);
+  let hardware = Hardware::new(
+    "InitSequence",
+    "InitSequenceTest",
+    &[Endpoint::Tx, Endpoint::Command],
+    Box::new(test_device),
+  );
+  (host, Arc::new(hardware))
+}
+
+#[tokio::test]
+async fn test_init_sequence_writes_in_order_with_delays() {
+  let (mut host, hardware) = init_sequence_test_hardware(0);
+  let sequence = vec![
+    InitSequenceWrite::new(Endpoint::Tx, &[0xaa, 0x01], Some(200), false),
+    InitSequenceWrite::new(Endpoint::Command, &[0xbb], None, true),
+    InitSequenceWrite::new(Endpoint::Tx, &[0xcc], None, false),
+  ];
+  let start = std::time::Instant::now();
+  let write_task = tokio::spawn(async move { write_init_sequence(&hardware, &sequence).await });
+  let mut received = vec![];
+  let mut received_at = vec![];
+  for _ in 0..3 {
+    received.push(
+      host
+        .receiver
+        .recv()
+        .await
+        .expect("Test, assuming infallible."),
+    );
+    received_at.push(start.elapsed());
+  }
+  write_task
+    .await
+    .expect("Test, assuming infallible.")
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    received,
+    vec![
+      HardwareCommand::from(HardwareWriteCmd::new(Endpoint::Tx, vec![0xaa, 0x01], false)),
+      HardwareWriteCmd::new(Endpoint::Command, vec![0xbb], true).into(),
+      HardwareWriteCmd::new(Endpoint::Tx, vec![0xcc], false).into(),
+    ]
+  );
+  // Only the first write has a delay after it.
+  assert!(received_at[0] < Duration::from_millis(150));
+  assert!(received_at[1] >= Duration::from_millis(200));
+  assert!(received_at[2] - received_at[1] < Duration::from_millis(150));
+}
+
+#[tokio::test]
+async fn test_init_sequence_write_failure() {
+  let (mut host, hardware) = init_sequence_test_hardware(1);
+  let sequence = vec![
+    InitSequenceWrite::new(Endpoint::Tx, &[0xaa], None, false),
+    InitSequenceWrite::new(Endpoint::Tx, &[0xbb], None, false),
+  ];
+  assert!(matches!(
+    write_init_sequence(&hardware, &sequence).await,
+    Err(ButtplugDeviceError::DeviceConnectionError(..))
+  ));
+  // Writes after the failed one aren't sent.
+  assert!(recv_now(&mut host.receiver).is_none());
+}
+
+fn check_galaku_battery_read_commands(host: &mut TestDeviceChannelHost) {
+  check_test_recv_value(
+    host,
+    HardwareCommand::Subscribe(HardwareSubscribeCmd::new(Endpoint::RxBLEBattery)),
+  );
+  // Encrypted battery level query.
+  check_test_recv_value(
+    host,
+    HardwareCommand::Write(HardwareWriteCmd::new(
+      Endpoint::Tx,
+      vec![
+        0x23, 0x81, 0xBB, 0xAB, 0xD2, 0xCE, 0xD3, 0x23, 0xBB, 0xA3, 0x3B, 0xC2,
+      ],
+      true,
+    )),
+  );
+  check_test_recv_value(
+    host,
+    HardwareCommand::Unsubscribe(HardwareUnsubscribeCmd::new(Endpoint::RxBLEBattery)),
+  );
+}
+
+#[tokio::test]
+async fn test_galaku_battery_read_times_out() {
+  let (mut host, hardware) = galaku_battery_test_hardware();
+  let handler = Galaku::new_with_battery_read_timeout(Duration::from_millis(200));
+  let start = std::time::Instant::now();
+  let result = tokio::time::timeout(
+    Duration::from_secs(1),
+    handler.handle_battery_level_cmd(
+      hardware,
+      message::SensorReadCmd::new(0, 0, SensorType::Battery),
+    ),
+  )
+  .await
+  .expect("Battery read should give up before the test timeout.");
+  assert!(matches!(
+    result,
+    Err(ButtplugDeviceError::ProtocolSpecificError(..))
+  ));
+  assert!(start.elapsed() >= Duration::from_millis(200));
+  check_galaku_battery_read_commands(&mut host);
+}
+
+#[tokio::test]
+async fn test_galaku_battery_read_unsubscribes() {
+  let (mut host, hardware) = galaku_battery_test_hardware();
+  let handler = Galaku::new_with_battery_read_timeout(Duration::from_millis(500));
+  let sender = host.sender.clone();
+  tokio::spawn(async move {
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    // Encrypted battery report of 75%.
+    sender
+      .send(TestHardwareEvent::Notifications(vec![
+        TestHardwareNotification::new(
+          Endpoint::RxBLEBattery,
+          &[
+            0x23, 0x81, 0xbb, 0xab, 0x88, 0x5b, 0x43, 0x23, 0xbb, 0xa3, 0x3b, 0xeb,
+          ],
+        ),
+      ]))
+      .await
+      .expect("Test, assuming infallible.");
+  });
+  let reading = handler
+    .handle_battery_level_cmd(
+      hardware,
+      message::SensorReadCmd::new(0, 0, SensorType::Battery),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    reading,
+    message::SensorReading::new(0, 0, SensorType::Battery, vec![75]).into()
+  );
+  check_galaku_battery_read_commands(&mut host);
+}
+
+/// Vibration speeds written to a Galaku One Engine device.
+fn drain_galaku_speeds(device: &mut TestDeviceChannelHost) -> Vec<u32> {
+  let packets: Vec<Vec<u8>> = (0..=100)
+    .map(|speed| galaku_framing::encode_packet(&[90, 0, 0, 1, 49, speed, 0, 0, 0, 0]))
+    .collect();
+  let mut speeds = vec![];
+  while let Some(Some(command)) = recv_now(&mut device.receiver) {
+    if let HardwareCommand::Write(write) = command {
+      if let Some(speed) = packets.iter().position(|packet| packet == write.data()) {
+        speeds.push(speed as u32);
+      }
+    }
+  }
+  speeds
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_galaku_linear_ramp() {
+  let (server, mut device) = test_server_with_device("GS01", false);
+  let device_index = wait_for_device_added(&server).await;
+
+  // 50ms steps, so 10 steps of 10 over 500ms.
+  server
+    .parse_message(power_ramp_cmd(device_index, 500, 1.0))
+    .await
+    .expect("Test, assuming infallible.");
+  advance_paused_time(Duration::from_millis(275)).await;
+  assert_eq!(drain_galaku_speeds(&mut device), vec![10, 20, 30, 40, 50]);
+  advance_paused_time(Duration::from_millis(250)).await;
+  assert_eq!(drain_galaku_speeds(&mut device), vec![60, 70, 80, 90, 100]);
+
+  // Slow ramps step less often, rather than repeating values.
+  server
+    .parse_message(power_ramp_cmd(device_index, 300, 0.97))
+    .await
+    .expect("Test, assuming infallible.");
+  advance_paused_time(Duration::from_millis(150)).await;
+  assert_eq!(drain_galaku_speeds(&mut device), vec![99]);
+  advance_paused_time(Duration::from_millis(200)).await;
+  assert_eq!(drain_galaku_speeds(&mut device), vec![98, 97]);
+}
+
+#[tokio::test]
+async fn test_galaku_linear_ramp_cancel() {
+  let (server, mut device) = test_server_with_device("GS01", false);
+  let device_index = wait_for_device_added(&server).await;
+
+  // A new ramp takes over from where the old one got to.
+  server
+    .parse_message(power_ramp_cmd(device_index, 2000, 1.0))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  server
+    .parse_message(power_ramp_cmd(device_index, 100, 0.0))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  let speeds = drain_galaku_speeds(&mut device);
+  let turn = speeds
+    .windows(2)
+    .position(|w| w[1] < w[0])
+    .expect("Test, assuming infallible.");
+  assert!(
+    speeds[..=turn].windows(2).all(|w| w[0] < w[1]),
+    "{:?}",
+    speeds
+  );
+  assert!(
+    speeds[turn + 1..].windows(2).all(|w| w[0] > w[1]),
+    "{:?}",
+    speeds
+  );
+  assert_eq!(speeds.last(), Some(&0));
+
+  // ScalarCmd and stops cancel ramps.
+  for stop in [
+    vibrate_cmd(device_index, 0.5),
+    message::StopDeviceCmd::new(device_index).into(),
+  ] {
+    server
+      .parse_message(power_ramp_cmd(device_index, 2000, 1.0))
+      .await
+      .expect("Test, assuming infallible.");
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    server
+      .parse_message(stop)
+      .await
+      .expect("Test, assuming infallible.");
+    let held = *drain_galaku_speeds(&mut device)
+      .last()
+      .expect("Test, assuming infallible.");
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(drain_galaku_speeds(&mut device)
+      .iter()
+      .all(|speed| *speed == held));
+  }
+}
+
+#[tokio::test]
+async fn test_galaku_rotating_model_has_no_linear_ramp() {
+  let handler = Galaku::new(GalakuOpcodes::for_model("GR01"), Duration::from_secs(1));
+  assert!(handler.linear_ramp_config().is_none());
+  assert!(Galaku::default().linear_ramp_config().is_some());
+}
+
+/// Sets up a server with two Aneros devices, with commands to the first mirrored to the second.
+/// If `target_feature_count` is given, the target only exposes that many features.
+async fn test_server_with_mirrored_devices(
+  target_feature_count: Option<usize>,
+) -> (
+  ButtplugServer,
+  (u32, TestDeviceChannelHost),
+  (u32, TestDeviceChannelHost),
+) {
+  let dcm = create_test_dcm(false);
+  let source =
+    UserDeviceIdentifier::new("MirrorSource", "aneros", &Some("Massage Demo".to_owned()));
+  let target =
+    UserDeviceIdentifier::new("MirrorTarget", "aneros", &Some("Massage Demo".to_owned()));
+  let mut definition = dcm
+    .device_definition(&source, &[])
+    .expect("Test, assuming infallible.");
+  definition
+    .user_config_mut()
+    .set_mirror(vec![DeviceMirror::new(&target, &[0.5])]);
+  dcm
+    .add_user_device_definition(&source, &definition)
+    .expect("Test, assuming infallible.");
+  if let Some(count) = target_feature_count {
+    let mut definition = dcm
+      .device_definition(&target, &[])
+      .expect("Test, assuming infallible.");
+    definition.features_mut().truncate(count);
+    dcm
+      .add_user_device_definition(&target, &definition)
+      .expect("Test, assuming infallible.");
+  }
+
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let source_device = builder.add_test_device(&TestDeviceIdentifier::new(
+    "Massage Demo",
+    Some("MirrorSource".to_owned()),
+  ));
+  let target_device = builder.add_test_device(&TestDeviceIdentifier::new(
+    "Massage Demo",
+    Some("MirrorTarget".to_owned()),
+  ));
+  let mut dm_builder = ServerDeviceManagerBuilder::new(dcm);
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  wait_for_device_added(&server).await;
+  let mut indexes = vec![];
+  while indexes.len() < 2 {
+    if let Some(ButtplugServerMessage::DeviceAdded(da)) = recv.next().await {
+      indexes.push(da.device_index());
+    }
+  }
+  let index_of = |address: &str| {
+    *indexes
+      .iter()
+      .find(|index| {
+        server
+          .device_manager()
+          .device_info(**index)
+          .expect("Test, assuming infallible.")
+          .identifier()
+          .address()
+          == address
+      })
+      .expect("Test, assuming infallible.")
+  };
+  let source_index = index_of("MirrorSource");
+  let target_index = index_of("MirrorTarget");
+  (
+    server,
+    (source_index, source_device),
+    (target_index, target_device),
+  )
+}
+
+#[tokio::test]
+async fn test_mirrored_device_commands() {
+  let (server, (source_index, mut source_device), (_, mut target_device)) =
+    test_server_with_mirrored_devices(None).await;
+
+  server
+    .parse_message(vibrate_cmd(source_index, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(50)).await;
+  check_test_recv_value(
+    &mut source_device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF1, 64], false)),
+  );
+  check_test_recv_value(
+    &mut target_device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF1, 32], false)),
+  );
+
+  server
+    .parse_message(message::StopDeviceCmd::new(source_index).into())
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(50)).await;
+  for device in [&mut source_device, &mut target_device] {
+    check_test_recv_value(
+      device,
+      HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF1, 0], false)),
+    );
+  }
+}
+
+#[tokio::test]
+async fn test_mirrored_device_commands_all_or_nothing() {
+  let (server, (source_index, mut source_device), (_, mut target_device)) =
+    test_server_with_mirrored_devices(Some(1)).await;
+
+  // The target has no second feature, so the command shouldn't reach either device.
+  let err = server
+    .parse_message(
+      message::ScalarCmd::new(
+        source_index,
+        vec![message::ScalarSubcommand::new(
+          1,
+          0.5,
+          message::ActuatorType::Vibrate,
+        )],
+      )
+      .into(),
+    )
+    .await
+    .expect_err("Mismatched mirror target should not accept command.");
+  assert!(matches!(
+    err.original_error(),
+    ButtplugError::ButtplugDeviceError(ButtplugDeviceError::DeviceFeatureIndexError(1, 1))
+  ));
+  tokio::time::sleep(Duration::from_millis(50)).await;
+  assert!(recv_now(&mut source_device.receiver).is_none());
+  assert!(recv_now(&mut target_device.receiver).is_none());
+}
+
+#[tokio::test]
+async fn test_mirrored_device_commands_claimed_target() {
+  let (server, (source_index, mut source_device), (target_index, mut target_device)) =
+    test_server_with_mirrored_devices(None).await;
+  let (client_a, client_b) = (100, 101);
+  server
+    .device_manager()
+    .claim_device(target_index, client_b)
+    .expect("Test, assuming infallible.");
+
+  // Client A can't reach the target through the mirror, so nothing in the group is sent.
+  let err = server
+    .device_manager()
+    .parse_client_message(client_a, vibrate_cmd(source_index, 0.5))
+    .await
+    .expect_err("Claimed mirror target should not accept command.");
+  assert!(matches!(
+    err,
+    ButtplugError::ButtplugDeviceError(ButtplugDeviceError::DeviceBusy(..))
+  ));
+  tokio::time::sleep(Duration::from_millis(50)).await;
+  assert!(recv_now(&mut source_device.receiver).is_none());
+  assert!(recv_now(&mut target_device.receiver).is_none());
+}
+
+fn sparse_scalar_cmd(
+  device_index: u32,
+  scalars: &[(u32, f64, message::ActuatorType)],
+) -> ButtplugClientMessage {
+  message::ScalarCmd::new(
+    device_index,
+    scalars
+      .iter()
+      .map(|(index, scalar, actuator)| message::ScalarSubcommand::new(*index, *scalar, *actuator))
+      .collect(),
+  )
+  .into()
+}
+
+#[tokio::test]
+async fn test_dg_lab_v3_partial_stop_keeps_other_channel() {
+  let (server, mut device) = test_server_with_device("47L121000", false);
+  let device_index = wait_for_device_added(&server).await;
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  server
+    .parse_message(sparse_scalar_cmd(
+      device_index,
+      &[
+        (0, 0.5, message::ActuatorType::Vibrate),
+        (1, 0.5, message::ActuatorType::Vibrate),
+        (3, 0.5, message::ActuatorType::Oscillate),
+      ],
+    ))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  let before = drain_dg_lab_v3_writes(&mut device)
+    .pop()
+    .expect("Test, assuming infallible.");
+
+  // Only stop channel A. Channel B isn't in the message, so it has to keep running as it was.
+  server
+    .parse_message(sparse_scalar_cmd(
+      device_index,
+      &[(0, 0.0, message::ActuatorType::Vibrate)],
+    ))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  let writes = drain_dg_lab_v3_writes(&mut device);
+  assert!(!writes.is_empty());
+  for write in writes {
+    assert_eq!(write.data()[2], 0, "{:?}", write);
+    // Channel B power is either left alone or set to what it already was.
+    assert!(
+      write.data()[1] & 0b11 == 0 || write.data()[3] == before.data()[3],
+      "{:?} {:?}",
+      before,
+      write
+    );
+    // Channel B waveform.
+    assert_eq!(write.data()[12..20], before.data()[12..20]);
+  }
+}
+
+#[tokio::test]
+async fn test_generic_vibrator_partial_stop_keeps_other_feature() {
+  let (server, mut device) = test_server_with_device("PROSTATE VIBE", false);
+  let device_index = wait_for_device_added(&server).await;
+  server
+    .parse_message(sparse_scalar_cmd(
+      device_index,
+      &[
+        (0, 0.5, message::ActuatorType::Vibrate),
+        (1, 0.25, message::ActuatorType::Vibrate),
+      ],
+    ))
+    .await
+    .expect("Test, assuming infallible.");
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF3, 1, 64], true)),
+  );
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF3, 2, 32], true)),
+  );
+
+  // This protocol needs every feature in each command, so the untouched feature should be filled
+  // in with the value it's already running at.
+  server
+    .parse_message(sparse_scalar_cmd(
+      device_index,
+      &[(0, 0.0, message::ActuatorType::Vibrate)],
+    ))
+    .await
+    .expect("Test, assuming infallible.");
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF3, 1, 0], true)),
+  );
+  check_test_recv_value(
+    &mut device,
+    HardwareCommand::Write(HardwareWriteCmd::new(Endpoint::Tx, vec![0xF3, 2, 32], true)),
+  );
+  assert!(recv_now(&mut device.receiver).is_none());
+}
+
+#[tokio::test]
+async fn test_packet_trace_records_dg_lab_v3_writes() {
+  let (server, mut device) = test_server_with_device("47L121000", false);
+  let device_index = wait_for_device_added(&server).await;
+  tokio::time::sleep(Duration::from_millis(500)).await;
+  let recv = server.device_manager().manager_event_stream();
+  pin_mut!(recv);
+  server
+    .device_manager()
+    .set_device_packet_trace(device_index, true)
+    .expect("Test, assuming infallible.");
+  drain_dg_lab_v3_writes(&mut device);
+
+  server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  // The B0 packet setting channel A to 100.
+  let write = drain_dg_lab_v3_writes(&mut device)
+    .into_iter()
+    .find(|write| (write.data()[1] >> 2) & 0b11 == 0b11)
+    .expect("Test, assuming infallible.");
+  assert_eq!(write.data()[2], 100);
+
+  loop {
+    let event = tokio::time::timeout(Duration::from_secs(1), recv.next())
+      .await
+      .expect("Test, assuming infallible.")
+      .expect("Test, assuming infallible.");
+    if let ServerDeviceManagerEvent::DevicePacketTrace {
+      index,
+      identifier,
+      packet,
+    } = event
+    {
+      assert_eq!(index, device_index);
+      assert_eq!(identifier.protocol(), "dg-lab-v3");
+      assert_eq!(packet.direction(), HardwarePacketDirection::Write);
+      assert_eq!(packet.endpoint(), Endpoint::Tx);
+      if packet.bytes() == write.data() {
+        break;
+      }
+    }
+  }
+
+  // Nothing is traced once tracing is turned off.
+  server
+    .device_manager()
+    .set_device_packet_trace(device_index, false)
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  while let Some(Some(_)) = recv.next().now_or_never() {}
+  server
+    .parse_message(vibrate_cmd(device_index, 0.25))
+    .await
+    .expect("Test, assuming infallible.");
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  assert!(!drain_dg_lab_v3_writes(&mut device).is_empty());
+  while let Some(Some(event)) = recv.next().now_or_never() {
+    assert!(
+      !matches!(event, ServerDeviceManagerEvent::DevicePacketTrace { .. }),
+      "{:?}",
+      event
+    );
+  }
+}
+
+async fn next_device_removed<S>(recv: &mut S) -> message::DeviceRemoved
+where
+  S: futures::Stream<Item = ButtplugServerMessage> + Unpin,
+{
+  loop {
+    let msg = tokio::time::timeout(Duration::from_secs(1), recv.next())
+      .await
+      .expect("Test, assuming infallible.")
+      .expect("Test, assuming infallible.");
+    if let ButtplugServerMessage::DeviceRemoved(removed) = msg {
+      return removed;
+    }
+  }
+}
+
+#[tokio::test]
+async fn test_device_removed_reasons() {
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let addresses = [
+    "RemovedByDevice",
+    "RemovedByWrite",
+    "RemovedByConfig",
+    "RemovedByClient",
+  ];
+  let mut hosts: Vec<_> = addresses
+    .iter()
+    .map(|address| {
+      // Fail the first command sent to the write failure device.
+      let failed_commands = if *address == "RemovedByWrite" { 1 } else { 0 };
+      builder.add_test_device(
+        &TestDeviceIdentifier::new("Massage Demo", Some(address.to_string()))
+          .with_failed_commands(failed_commands),
+      )
+    })
+    .collect();
+  let mut dm_builder = ServerDeviceManagerBuilder::new(create_test_dcm(false));
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  let device_manager = server.device_manager();
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  let manager_recv = device_manager.manager_event_stream();
+  pin_mut!(manager_recv);
+  server
+    .parse_message(
+      message::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  let mut indexes = vec![0; addresses.len()];
+  let mut added = 0;
+  while added < addresses.len() {
+    if let ButtplugServerMessage::DeviceAdded(da) =
+      recv.next().await.expect("Test, assuming infallible.")
+    {
+      let info = device_manager
+        .device_info(da.device_index())
+        .expect("Test, assuming infallible.");
+      let position = addresses
+        .iter()
+        .position(|address| info.identifier().address() == address)
+        .expect("Test, assuming infallible.");
+      indexes[position] = da.device_index();
+      added += 1;
+    }
+  }
+
+  // The hardware going away on its own.
+  hosts[0]
+    .sender
+    .send(TestHardwareEvent::Disconnect)
+    .await
+    .expect("Test, assuming infallible.");
+  let removed = next_device_removed(&mut recv).await;
+  assert_eq!(removed.device_index(), indexes[0]);
+  assert_eq!(
+    removed.reason(),
+    Some(message::DeviceRemovedReason::DeviceInitiated)
+  );
+
+  // The hardware going away after a failed write.
+  assert!(server
+    .parse_message(vibrate_cmd(indexes[1], 0.5))
+    .await
+    .is_err());
+  hosts[1]
+    .sender
+    .send(TestHardwareEvent::Disconnect)
+    .await
+    .expect("Test, assuming infallible.");
+  let removed = next_device_removed(&mut recv).await;
+  assert_eq!(removed.device_index(), indexes[1]);
+  assert_eq!(
+    removed.reason(),
+    Some(message::DeviceRemovedReason::WriteFailure)
+  );
+
+  // The device getting denied by a user config change.
+  let identifier = UserDeviceIdentifier::new(
+    "RemovedByConfig",
+    "aneros",
+    &Some("Massage Demo".to_owned()),
+  );
+  device_manager
+    .device_configuration_manager()
+    .add_user_device_definition(
+      &identifier,
+      &UserDeviceDefinition::new(
+        "Aneros Vivi",
+        &[],
+        &UserDeviceCustomization::new(&None, false, true, indexes[2]),
+      ),
+    )
+    .expect("Test, assuming infallible.");
+  device_manager
+    .disconnect_denied_devices()
+    .await
+    .expect("Test, assuming infallible.");
+  let removed = next_device_removed(&mut recv).await;
+  assert_eq!(removed.device_index(), indexes[2]);
+  assert_eq!(
+    removed.reason(),
+    Some(message::DeviceRemovedReason::ConfigDenied)
+  );
+
+  // The embedding application asking for the disconnect.
+  device_manager
+    .disconnect_device(indexes[3])
+    .await
+    .expect("Test, assuming infallible.");
+  let removed = next_device_removed(&mut recv).await;
+  assert_eq!(removed.device_index(), indexes[3]);
+  assert_eq!(
+    removed.reason(),
+    Some(message::DeviceRemovedReason::ClientRequest)
+  );
+  assert!(device_manager.disconnect_device(indexes[3]).await.is_err());
+
+  // The same reasons go out as device manager events.
+  let mut reasons = vec![];
+  while let Some(Some(event)) = manager_recv.next().now_or_never() {
+    if let ServerDeviceManagerEvent::DeviceRemoved { index, reason, .. } = event {
+      reasons.push((index, reason));
+    }
+  }
+  assert_eq!(
+    reasons,
+    vec![
+      (indexes[0], message::DeviceRemovedReason::DeviceInitiated),
+      (indexes[1], message::DeviceRemovedReason::WriteFailure),
+      (indexes[2], message::DeviceRemovedReason::ConfigDenied),
+      (indexes[3], message::DeviceRemovedReason::ClientRequest),
+    ]
+  );
+  hosts.clear();
+}
+
+/// Wait for the test device to connect, and return its index, identifier and user device config.
+async fn connected_device_user_config(
+  server: &ButtplugServer,
+) -> (u32, UserDeviceIdentifier, UserDeviceDefinition) {
+  let device_index = wait_for_device_added(server).await;
+  let identifier = server
+    .device_manager()
+    .device_info(device_index)
+    .expect("Test, assuming infallible.")
+    .identifier()
+    .clone();
+  let definition = server
+    .device_manager()
+    .device_configuration_manager()
+    .user_device_definitions()
+    .get(&identifier)
+    .expect("Test, assuming infallible.")
+    .clone();
+  (device_index, identifier, definition)
+}
+
+async fn listed_display_name(server: &ButtplugServer) -> Option<String> {
+  match server
+    .parse_message(message::RequestDeviceList::default().into())
+    .await
+    .expect("Test, assuming infallible.")
+  {
+    ButtplugServerMessage::DeviceList(list) => list.devices()[0].device_display_name().clone(),
+    msg => panic!("Unexpected message {:?}", msg),
+  }
+}
+
+#[tokio::test]
+async fn test_set_user_device_config_renames_connected_device() {
+  let (server, _device) = test_server_with_device("Massage Demo", false);
+  let (device_index, identifier, mut definition) = connected_device_user_config(&server).await;
+  let device_manager = server.device_manager();
+  assert_eq!(listed_display_name(&server).await, None);
+
+  definition
+    .user_config_mut()
+    .set_display_name(Some("Bedside".to_owned()));
+  let fragment = device_manager
+    .set_user_device_config(&identifier, &definition)
+    .await
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    device_manager
+      .device_info(device_index)
+      .expect("Test, assuming infallible.")
+      .display_name(),
+    &Some("Bedside".to_owned())
+  );
+  assert_eq!(
+    listed_display_name(&server).await,
+    Some("Bedside".to_owned())
+  );
+
+  // The fragment is a user config holding only this device, which loads like any other.
+  let fragment_json: serde_json::Value =
+    serde_json::from_str(&fragment).expect("Test, assuming infallible.");
+  let devices = fragment_json["user-configs"]["devices"]
+    .as_array()
+    .expect("Test, assuming infallible.");
+  assert_eq!(devices.len(), 1);
+  assert_eq!(
+    devices[0]["config"]["user-config"]["display-name"],
+    "Bedside"
+  );
+  let dcm = load_protocol_configs(&None, &Some(fragment), false)
+    .expect("Test, assuming infallible.")
+    .finish()
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    dcm
+      .user_device_definitions()
+      .get(&identifier)
+      .expect("Test, assuming infallible.")
+      .user_config()
+      .display_name(),
+    &Some("Bedside".to_owned())
+  );
+
+  // Removing the config takes the name away again.
+  device_manager
+    .remove_user_device_config(&identifier)
+    .await
+    .expect("Test, assuming infallible.");
+  assert_eq!(listed_display_name(&server).await, None);
+  assert!(device_manager
+    .device_configuration_manager()
+    .user_device_definitions()
+    .get(&identifier)
+    .is_none());
+}
+
+#[tokio::test]
+async fn test_set_user_device_config_denies_connected_device() {
+  let (server, _device) = test_server_with_device("Massage Demo", false);
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  let (device_index, identifier, mut definition) = connected_device_user_config(&server).await;
+  let device_manager = server.device_manager();
+
+  // Configs that are both allowed and denied are refused, and change nothing.
+  definition.user_config_mut().set_allow(true);
+  definition.user_config_mut().set_deny(true);
+  assert!(device_manager
+    .set_user_device_config(&identifier, &definition)
+    .await
+    .is_err());
+  assert!(device_manager.device_info(device_index).is_some());
+
+  definition.user_config_mut().set_allow(false);
+  device_manager
+    .set_user_device_config(&identifier, &definition)
+    .await
+    .expect("Test, assuming infallible.");
+  let removed = next_device_removed(&mut recv).await;
+  assert_eq!(removed.device_index(), device_index);
+  assert_eq!(
+    removed.reason(),
+    Some(message::DeviceRemovedReason::ConfigDenied)
+  );
+  assert!(!device_manager
+    .device_configuration_manager()
+    .address_allowed(identifier.address()));
+}
+
+#[tokio::test]
+async fn test_hardware_error_history_is_bounded() {
+  let (_host, device_channel) = new_device_channel();
+  let mut test_device = TestDevice::new("Error Test", "ErrorTest", device_channel);
+  test_device.add_endpoint(&Endpoint::Tx);
+  test_device.add_endpoint(&Endpoint::TxVibrate);
+  let failures = HARDWARE_ERROR_HISTORY_LENGTH + 8;
+  test_device.fail_next_commands(failures as u32);
+  let hardware = Hardware::new(
+    "Error Test",
+    "ErrorTest",
+    &[Endpoint::Tx, Endpoint::TxVibrate],
+    Box::new(test_device),
+  );
+  // The first failure is the only one on TxVibrate, and should be pushed out of the history.
+  assert!(hardware
+    .write_value(&HardwareWriteCmd::new(Endpoint::TxVibrate, vec![0], false))
+    .await
+    .is_err());
+  for _ in 1..failures {
+    assert!(hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![0], false))
+      .await
+      .is_err());
+  }
+  // Successful operations aren't recorded.
+  hardware
+    .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![0], false))
+    .await
+    .expect("Test, assuming infallible.");
+
+  assert_eq!(hardware.error_count(), failures as u64);
+  let history = hardware.error_history();
+  assert_eq!(history.len(), HARDWARE_ERROR_HISTORY_LENGTH);
+  for record in &history {
+    assert_eq!(record.operation(), HardwareOperation::Write);
+    assert_eq!(record.endpoint(), Some(Endpoint::Tx));
+    assert!(matches!(
+      record.error(),
+      ButtplugDeviceError::DeviceCommunicationError(_)
+    ));
+  }
+  assert!(history
+    .windows(2)
+    .all(|records| records[0].timestamp() <= records[1].timestamp()));
+}
+
+fn batch_test_writes(writer: u8, round: u8) -> Vec<HardwareWriteCmd> {
+  [Endpoint::Tx, Endpoint::Generic0, Endpoint::Generic1]
+    .iter()
+    .map(|endpoint| HardwareWriteCmd::new(*endpoint, vec![writer, round], false))
+    .collect()
+}
+
+#[tokio::test]
+async fn test_hardware_write_batches_are_not_split() {
+  let (mut host, device_channel) = new_device_channel();
+  let mut test_device = TestDevice::new("Batch Test", "BatchTest", device_channel);
+  let endpoints = [Endpoint::Tx, Endpoint::Generic0, Endpoint::Generic1];
+  for endpoint in &endpoints {
+    test_device.add_endpoint(endpoint);
+  }
+  // Slow writes give the other writers plenty of chances to get in between.
+  test_device.set_write_delay(Duration::from_millis(2));
+  let hardware = Hardware::new("Batch Test", "BatchTest", &endpoints, Box::new(test_device));
+
+  // Batches from a few writers (like commands and a keepalive loop), racing single writes.
+  let batches = future::join_all((0..3).map(|writer| {
+    let hardware = &hardware;
+    async move {
+      for round in 0..10 {
+        hardware
+          .write_batch(&batch_test_writes(writer, round))
+          .await
+          .expect("Test, assuming infallible.");
+      }
+    }
+  }));
+  let singles = async {
+    for round in 0..10 {
+      hardware
+        .write_value(&HardwareWriteCmd::new(
+          Endpoint::Tx,
+          vec![u8::MAX, round],
+          false,
+        ))
+        .await
+        .expect("Test, assuming infallible.");
+    }
+  };
+  future::join(batches, singles).await;
+
+  let mut writes = vec![];
+  while let Some(Some(HardwareCommand::Write(write))) = recv_now(&mut host.receiver) {
+    writes.push(write);
+  }
+  assert_eq!(writes.len(), 3 * 10 * 3 + 10);
+  let mut remaining = writes.as_slice();
+  while let Some(write) = remaining.first() {
+    if write.data()[0] == u8::MAX {
+      remaining = &remaining[1..];
+      continue;
+    }
+    let batch = batch_test_writes(write.data()[0], write.data()[1]);
+    assert_eq!(&remaining[..batch.len()], batch.as_slice());
+    remaining = &remaining[batch.len()..];
+  }
+}
+
+#[tokio::test]
+async fn test_device_hardware_error_history() {
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let _device = builder.add_test_device(
+    &TestDeviceIdentifier::new("Massage Demo", Some("ErrorHistoryTest".to_owned()))
+      .with_failed_commands(3),
+  );
+  let mut dm_builder = ServerDeviceManagerBuilder::new(create_test_dcm(false));
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  let device_index = wait_for_device_added(&server).await;
+  let device_manager = server.device_manager();
+  assert_eq!(
+    *device_manager
+      .device_info(device_index)
+      .expect("Test, assuming infallible.")
+      .hardware_error_count(),
+    0
+  );
+
+  for speed in [0.25, 0.5, 0.75] {
+    assert!(server
+      .parse_message(vibrate_cmd(device_index, speed))
+      .await
+      .is_err());
+  }
+  server
+    .parse_message(vibrate_cmd(device_index, 1.0))
+    .await
+    .expect("Test, assuming infallible.");
+
+  assert_eq!(
+    *device_manager
+      .device_info(device_index)
+      .expect("Test, assuming infallible.")
+      .hardware_error_count(),
+    3
+  );
+  let history = device_manager
+    .device_hardware_error_history(device_index)
+    .expect("Test, assuming infallible.");
+  assert_eq!(history.len(), 3);
+  assert!(history.iter().all(|record| {
+    record.operation() == HardwareOperation::Write && record.endpoint() == Some(Endpoint::Tx)
+  }));
+  assert!(device_manager
+    .device_hardware_error_history(device_index + 1)
+    .is_none());
+}
+
+#[tokio::test]
+async fn test_device_io_stats() {
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let mut device = builder.add_test_device(
+    &TestDeviceIdentifier::new("Massage Demo", Some("IoStatsTest".to_owned()))
+      .with_failed_commands(1),
+  );
+  let mut dm_builder = ServerDeviceManagerBuilder::new(create_test_dcm(false));
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  let device_index = wait_for_device_added(&server).await;
+  let device_manager = server.device_manager();
+
+  assert!(server
+    .parse_message(vibrate_cmd(device_index, 0.25))
+    .await
+    .is_err());
+  for speed in [0.5, 1.0] {
+    server
+      .parse_message(vibrate_cmd(device_index, speed))
+      .await
+      .expect("Test, assuming infallible.");
+  }
+
+  let mut received_writes = 0;
+  while let Some(Some(HardwareCommand::Write(_))) = recv_now(&mut device.receiver) {
+    received_writes += 1;
+  }
+  assert_eq!(received_writes, 2);
+  let stats = device_manager
+    .device_stats(device_index)
+    .expect("Test, assuming infallible.");
+  // The failed write still counts as a write.
+  assert_eq!(stats.writes(), 3);
+  assert_eq!(stats.bytes_written(), 6);
+  assert_eq!(stats.errors(), 1);
+  assert!((stats.error_rate() - 1.0 / 3.0).abs() < f64::EPSILON);
+  assert!(stats.average_write_latency_us().is_some());
+  assert_eq!(stats.reads(), 0);
+  assert_eq!(stats.average_read_latency_us(), None);
+
+  // Stats are cumulative, reading them doesn't reset anything.
+  assert_eq!(
+    device_manager
+      .device_stats(device_index)
+      .expect("Test, assuming infallible.")
+      .writes(),
+    3
+  );
+  let json = serde_json::to_value(stats).expect("Test, assuming infallible.");
+  assert_eq!(json["writes"], 3);
+  assert_eq!(json["bytes_written"], 6);
+  assert!(device_manager.device_stats(device_index + 1).is_none());
+}
+
+fn io_stats_test_hardware(write_delay: Option<Duration>) -> (TestDeviceChannelHost, Hardware) {
+  let (host, device_channel) = new_device_channel();
+  let mut test_device = TestDevice::new("IoStats", "IoStatsTest", device_channel);
+  test_device.add_endpoint(&Endpoint::Tx);
+  test_device.add_endpoint(&Endpoint::Rx);
+  if let Some(delay) = write_delay {
+    test_device.set_write_delay(delay);
+  }
+  let hardware = Hardware::new(
+    "IoStats",
+    "IoStatsTest",
+    &[Endpoint::Tx, Endpoint::Rx],
+    Box::new(test_device),
+  );
+  (host, hardware)
+}
+
+#[tokio::test]
+async fn test_hardware_io_stats_reads_and_subscriptions() {
+  let (host, hardware) = io_stats_test_hardware(None);
+  hardware
+    .subscribe(&HardwareSubscribeCmd::new(Endpoint::Rx))
+    .await
+    .expect("Test, assuming infallible.");
+  host
+    .sender
+    .send(TestHardwareEvent::Reads(vec![
+      TestHardwareNotification::new(Endpoint::Rx, &[1, 2, 3, 4]),
+    ]))
+    .await
+    .expect("Test, assuming infallible.");
+  hardware
+    .read_value(&HardwareReadCmd::new(Endpoint::Rx, 4, 0))
+    .await
+    .expect("Test, assuming infallible.");
+  hardware
+    .unsubscribe(&HardwareUnsubscribeCmd::new(Endpoint::Rx))
+    .await
+    .expect("Test, assuming infallible.");
+  // Writes to endpoints the device doesn't have fail.
+  assert!(hardware
+    .write_value(&HardwareWriteCmd::new(Endpoint::Command, vec![0x01], false))
+    .await
+    .is_err());
+
+  let stats = hardware.io_stats();
+  assert_eq!(stats.subscription_changes(), 2);
+  assert_eq!(stats.reads(), 1);
+  assert_eq!(stats.bytes_read(), 4);
+  assert!(stats.average_read_latency_us().is_some());
+  assert_eq!(stats.writes(), 1);
+  assert_eq!(stats.bytes_written(), 1);
+  assert_eq!(stats.average_write_latency_us(), None);
+  assert_eq!(stats.errors(), 1);
+  assert!((stats.error_rate() - 0.25).abs() < f64::EPSILON);
+}
+
+#[tokio::test]
+async fn test_hardware_io_stats_write_latency() {
+  let (_host, hardware) = io_stats_test_hardware(Some(Duration::from_millis(50)));
+  for value in 0..3 {
+    hardware
+      .write_value(&HardwareWriteCmd::new(Endpoint::Tx, vec![value], false))
+      .await
+      .expect("Test, assuming infallible.");
+  }
+  let stats = hardware.io_stats();
+  assert_eq!(stats.writes(), 3);
+  let latency = stats
+    .average_write_latency_us()
+    .expect("Test, assuming infallible.");
+  assert!((50_000..500_000).contains(&latency), "{}", latency);
+  assert!(stats.uptime_ms() >= 150);
+}
+
+/// Server with a Kiiroo Pearl 2.1, which reports pressure and button states through subscriptions,
+/// with the given sensor calibration. Returns the device added message along with the device.
+async fn test_server_with_calibrated_pearl(
+  calibration: &[(SensorType, SensorCalibration)],
+) -> (ButtplugServer, message::DeviceAdded, TestDeviceChannelHost) {
+  let (server, device) = test_server_with_customized_definition(
+    &TestDeviceIdentifier::new("Pearl2.1", Some("CalibrationTest".to_owned())),
+    "kiiroo-v21",
+    |definition| {
+      let subscribe = HashSet::from([ButtplugSensorFeatureMessageType::SensorSubscribeCmd]);
+      definition.features_mut().extend([
+        DeviceFeature::new(
+          "Pressure",
+          FeatureType::Pressure,
+          &None,
+          &Some(DeviceFeatureSensor::new(&vec![0..=65535], &subscribe)),
+        ),
+        DeviceFeature::new(
+          "Button",
+          FeatureType::Button,
+          &None,
+          &Some(DeviceFeatureSensor::new(&vec![0..=1], &subscribe)),
+        ),
+      ]);
+      definition
+        .user_config_mut()
+        .set_sensor_calibration(calibration.iter().copied().collect::<BTreeMap<_, _>>());
+    },
+  );
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  server
+    .parse_message(
+      message::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  let device_added = loop {
+    match recv.next().await {
+      Some(ButtplugServerMessage::DeviceAdded(da)) => break da,
+      Some(_) => continue,
+      None => panic!("Device never added."),
+    }
+  };
+  (server, device_added, device)
+}
+
+/// Pearl 2.1 sensor packet, with all 4 pressure channels reporting a raw value of 1000 and only the
+/// first button pressed.
+fn pearl_sensor_notification() -> TestHardwareEvent {
+  let channel = (u16::MAX - 1000).to_be_bytes();
+  let mut data = channel.repeat(4);
+  data.push(0b0001);
+  TestHardwareEvent::Notifications(vec![TestHardwareNotification::new(Endpoint::Rx, &data)])
+}
+
+async fn read_pearl_battery(
+  server: &ButtplugServer,
+  device: &TestDeviceChannelHost,
+  device_index: u32,
+) -> Vec<i32> {
+  let mut data = vec![0u8; 20];
+  data[5] = 80;
+  device
+    .sender
+    .send(TestHardwareEvent::Reads(vec![
+      TestHardwareNotification::new(Endpoint::Whitelist, &data),
+    ]))
+    .await
+    .expect("Test, assuming infallible.");
+  match server
+    .parse_message(message::SensorReadCmd::new(device_index, 0, SensorType::Battery).into())
+    .await
+    .expect("Test, assuming infallible.")
+  {
+    ButtplugServerMessage::SensorReading(reading) => reading.data().clone(),
+    msg => panic!("Unexpected message {:?}", msg),
+  }
+}
+
+async fn next_sensor_reading<S>(recv: &mut S) -> message::SensorReading
+where
+  S: futures::Stream<Item = ButtplugServerMessage> + Unpin,
+{
+  loop {
+    match tokio::time::timeout(Duration::from_secs(1), recv.next())
+      .await
+      .expect("Test, assuming infallible.")
+    {
+      Some(ButtplugServerMessage::SensorReading(reading)) => break reading,
+      Some(_) => continue,
+      None => panic!("Event stream ended."),
+    }
+  }
+}
+
+#[tokio::test]
+async fn test_sensor_calibration() {
+  let (server, device_added, device) =
+    test_server_with_calibrated_pearl(&[(SensorType::Pressure, SensorCalibration::new(-800, 0.5))])
+      .await;
+  let device_index = device_added.device_index();
+  let subscribe_attrs = device_added
+    .device_messages()
+    .sensor_subscribe_cmd()
+    .clone()
+    .expect("Test, assuming infallible.");
+  assert_eq!(*subscribe_attrs[0].sensor_range(), vec![-400..=32368]);
+  assert_eq!(*subscribe_attrs[1].sensor_range(), vec![0..=1]);
+
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  for (index, sensor_type) in [(0, SensorType::Pressure), (1, SensorType::Button)] {
+    server
+      .parse_message(message::SensorSubscribeCmd::new(device_index, index, sensor_type).into())
+      .await
+      .expect("Test, assuming infallible.");
+  }
+
+  device
+    .sender
+    .send(pearl_sensor_notification())
+    .await
+    .expect("Test, assuming infallible.");
+  let pressure = next_sensor_reading(&mut recv).await;
+  assert_eq!(pressure.sensor_type(), SensorType::Pressure);
+  assert_eq!(*pressure.data(), vec![100; 4]);
+  let button = next_sensor_reading(&mut recv).await;
+  assert_eq!(button.sensor_type(), SensorType::Button);
+  assert_eq!(*button.data(), vec![1, 0, 0, 0]);
+  // Battery has no calibration, so it's passed on as is.
+  assert_eq!(
+    read_pearl_battery(&server, &device, device_index).await,
+    vec![80]
+  );
+
+  // Calibration UIs can ask for the raw values.
+  server
+    .device_manager()
+    .set_device_raw_sensor_values(device_index, true)
+    .expect("Test, assuming infallible.");
+  device
+    .sender
+    .send(pearl_sensor_notification())
+    .await
+    .expect("Test, assuming infallible.");
+  assert_eq!(*next_sensor_reading(&mut recv).await.data(), vec![1000; 4]);
+}
+
+#[tokio::test]
+async fn test_sensor_calibration_applies_to_reads() {
+  let (server, device_added, device) = test_server_with_calibrated_pearl(&[(
+    SensorType::Battery,
+    SensorCalibration::from_observed_range(0, 80),
+  )])
+  .await;
+  let device_index = device_added.device_index();
+  assert_eq!(
+    read_pearl_battery(&server, &device, device_index).await,
+    vec![100]
+  );
+  server
+    .device_manager()
+    .set_device_raw_sensor_values(device_index, true)
+    .expect("Test, assuming infallible.");
+  assert_eq!(
+    read_pearl_battery(&server, &device, device_index).await,
+    vec![80]
+  );
+}
+
+async fn rescan_for_device_added<S>(server: &ButtplugServer, recv: &mut S) -> u32
+where
+  S: futures::Stream<Item = ButtplugServerMessage> + Unpin,
+{
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  loop {
+    let msg = tokio::time::timeout(Duration::from_secs(1), recv.next())
+      .await
+      .expect("Test, assuming infallible.")
+      .expect("Test, assuming infallible.");
+    if let ButtplugServerMessage::DeviceAdded(da) = msg {
+      return da.device_index();
+    }
+  }
+}
+
+#[tokio::test]
+async fn test_reconnect_uses_cached_device_match() {
+  // The same hardware showing up on four scans in a row. Reported whatever the address filter says,
+  // as the deny check on cached matches is what's tested at the end.
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  builder.ignore_address_filter();
+  let mut hosts: Vec<_> = (0..4)
+    .map(|skipped_scans| {
+      builder.add_test_device(
+        &TestDeviceIdentifier::new("Massage Demo", Some("CacheTest".to_owned()))
+          .with_skipped_scans(skipped_scans),
+      )
+    })
+    .collect();
+  let mut dm_builder = ServerDeviceManagerBuilder::new(create_test_dcm(false));
+  dm_builder.comm_manager(builder);
+  let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+    .finish()
+    .unwrap();
+  let device_manager = server.device_manager();
+  let dcm = device_manager.device_configuration_manager().clone();
+  let manager_recv = device_manager.manager_event_stream();
+  pin_mut!(manager_recv);
+  let recv = server.event_stream();
+  pin_mut!(recv);
+
+  let device_index = wait_for_device_added(&server).await;
+  assert_eq!(hosts[0].specialize_attempts.load(Ordering::SeqCst), 1);
+  hosts[0]
+    .sender
+    .send(TestHardwareEvent::Disconnect)
+    .await
+    .expect("Test, assuming infallible.");
+  next_device_removed(&mut recv).await;
+
+  // Coming back skips matching, and the device still works.
+  assert_eq!(
+    rescan_for_device_added(&server, &mut recv).await,
+    device_index
+  );
+  assert_eq!(hosts[1].connect_attempts.load(Ordering::SeqCst), 1);
+  assert_eq!(hosts[1].specialize_attempts.load(Ordering::SeqCst), 0);
+  server
+    .parse_message(vibrate_cmd(device_index, 0.5))
+    .await
+    .expect("Test, assuming infallible.");
+  let command = tokio::time::timeout(Duration::from_secs(1), hosts[1].receiver.recv())
+    .await
+    .expect("Test, assuming infallible.")
+    .expect("Test, assuming infallible.");
+  assert!(matches!(command, HardwareCommand::Write(_)));
+  hosts[1]
+    .sender
+    .send(TestHardwareEvent::Disconnect)
+    .await
+    .expect("Test, assuming infallible.");
+  next_device_removed(&mut recv).await;
+
+  // Config changes throw the cache away.
+  dcm
+    .disable_protocol("lovense")
+    .expect("Test, assuming infallible.");
+  dcm.enable_protocol("lovense");
+  assert_eq!(
+    rescan_for_device_added(&server, &mut recv).await,
+    device_index
+  );
+  assert_eq!(hosts[2].specialize_attempts.load(Ordering::SeqCst), 1);
+
+  // A cached match never gets a denied device past the deny list.
+  let identifier = device_manager
+    .device_info(device_index)
+    .expect("Test, assuming infallible.")
+    .identifier()
+    .clone();
+  let mut definition = dcm
+    .user_device_definitions()
+    .get(&identifier)
+    .expect("Test, assuming infallible.")
+    .clone();
+  definition.user_config_mut().set_deny(true);
+  device_manager
+    .set_user_device_config(&identifier, &definition)
+    .await
+    .expect("Test, assuming infallible.");
+  next_device_removed(&mut recv).await;
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  let ignored = loop {
+    let event = tokio::time::timeout(Duration::from_secs(1), manager_recv.next())
+      .await
+      .expect("Test, assuming infallible.")
+      .expect("Test, assuming infallible.");
+    if matches!(event, ServerDeviceManagerEvent::DeviceIgnored { .. }) {
+      break event;
+    }
+  };
+  assert_eq!(
+    ignored,
+    ServerDeviceManagerEvent::DeviceIgnored {
+      name: "Massage Demo".to_owned(),
+      address: "CacheTest".to_owned(),
+      reason: DeviceIgnoredReason::DenyListed,
+    }
+  );
+  assert_eq!(hosts[3].connect_attempts.load(Ordering::SeqCst), 0);
+}
@@ -12,11 +12,33 @@ mod test {
 
   use buttplug::{
     client::ButtplugClient,
-    core::connector::ButtplugInProcessClientConnectorBuilder,
-    server::device::hardware::communication::websocket_server::websocket_server_comm_manager::WebsocketServerDeviceCommunicationManagerBuilder,
+    core::{
+      connector::ButtplugInProcessClientConnectorBuilder,
+      message::{self, ButtplugServerMessage, Endpoint, BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION},
+    },
+    server::{
+      device::{
+        configuration::{ProtocolCommunicationSpecifier, WebsocketSpecifier},
+        hardware::communication::websocket_server::websocket_server_comm_manager::WebsocketServerDeviceCommunicationManagerBuilder,
+        ServerDeviceManagerBuilder,
+      },
+      ButtplugServer,
+      ButtplugServerBuilder,
+    },
+  };
+  use futures::{pin_mut, SinkExt, Stream, StreamExt};
+  use std::{collections::HashMap, time::Duration};
+  use tokio::{
+    net::TcpListener,
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+    task::JoinHandle,
+  };
+  use tokio_tungstenite::tungstenite::{
+    handshake::server::{Request, Response},
+    Message,
   };
 
-  use crate::util::test_server_with_comm_manager;
+  use crate::util::{create_test_dcm, test_server_with_comm_manager};
 
   async fn setup_test_client() -> ButtplugClient {
     let server = test_server_with_comm_manager(
@@ -42,4 +64,392 @@ mod test {
     let client = setup_test_client().await;
     assert!(client.connected());
   }
+
+  #[tokio::test]
+  async fn test_websocket_server_dcm_multiplexed_devices() {
+    let dcm = create_test_dcm(false);
+    let mut specifier = WebsocketSpecifier::new("Massage Demo");
+    specifier.set_multiplex(true);
+    dcm
+      .add_user_communication_specifier(
+        "aneros",
+        &ProtocolCommunicationSpecifier::Websocket(specifier),
+      )
+      .expect("Test, assuming infallible.");
+    let mut dm_builder = ServerDeviceManagerBuilder::new(dcm);
+    dm_builder
+      .comm_manager(WebsocketServerDeviceCommunicationManagerBuilder::default().server_port(51284));
+    let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+      .finish()
+      .unwrap();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    server
+      .parse_message(
+        message::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    server
+      .parse_message(message::StartScanning::default().into())
+      .await
+      .expect("Test, assuming infallible.");
+
+    // The comm manager binds its listener in the background, so retry until it's up.
+    let mut ws_stream = None;
+    for _ in 0..50 {
+      if let Ok((stream, _)) = tokio_tungstenite::connect_async("ws://127.0.0.1:51284").await {
+        ws_stream = Some(stream);
+        break;
+      }
+      tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let mut ws_stream = ws_stream.expect("Test, assuming infallible.");
+    ws_stream
+      .send(Message::Text(
+        r#"{
+          "identifier": "Controller Board",
+          "address": "MultiplexTest",
+          "version": 0,
+          "devices": [
+            { "name": "Massage Demo", "identifier": "left" },
+            { "name": "Massage Demo" }
+          ]
+        }"#
+          .to_owned(),
+      ))
+      .await
+      .expect("Test, assuming infallible.");
+
+    // Map channel tags to device indexes, via the addresses built from the handshake.
+    let mut indexes = HashMap::new();
+    while indexes.len() < 2 {
+      if let ButtplugServerMessage::DeviceAdded(da) =
+        recv.next().await.expect("Test, assuming infallible.")
+      {
+        let info = server
+          .device_manager()
+          .device_info(da.device_index())
+          .expect("Test, assuming infallible.");
+        let tag = match info.identifier().address().as_str() {
+          "MultiplexTest-left" => 0u8,
+          "MultiplexTest-1" => 1u8,
+          address => panic!("Unexpected device address {}", address),
+        };
+        indexes.insert(tag, da.device_index());
+      }
+    }
+
+    for (tag, speed, expected) in [(0u8, 1.0, 127u8), (1u8, 0.5, 64u8)] {
+      server
+        .parse_message(
+          message::ScalarCmd::new(
+            indexes[&tag],
+            vec![message::ScalarSubcommand::new(
+              0,
+              speed,
+              message::ActuatorType::Vibrate,
+            )],
+          )
+          .into(),
+        )
+        .await
+        .expect("Test, assuming infallible.");
+      loop {
+        match ws_stream.next().await.expect("Test, assuming infallible.") {
+          Ok(Message::Binary(data)) => {
+            assert_eq!(data, vec![tag, 0xF1, expected]);
+            break;
+          }
+          Ok(Message::Ping(_)) => continue,
+          msg => panic!("Unexpected websocket message {:?}", msg),
+        }
+      }
+    }
+  }
+
+  async fn keepalive_test_server(
+    port: u16,
+  ) -> (
+    ButtplugServer,
+    impl Stream<Item = ButtplugServerMessage> + Unpin,
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>,
+    u32,
+  ) {
+    let dcm = create_test_dcm(false);
+    let mut specifier = WebsocketSpecifier::new("Massage Demo");
+    specifier.set_ping_interval_ms(Some(100));
+    specifier.set_ping_timeout_ms(Some(200));
+    dcm
+      .add_user_communication_specifier(
+        "aneros",
+        &ProtocolCommunicationSpecifier::Websocket(specifier),
+      )
+      .expect("Test, assuming infallible.");
+    let mut dm_builder = ServerDeviceManagerBuilder::new(dcm);
+    dm_builder
+      .comm_manager(WebsocketServerDeviceCommunicationManagerBuilder::default().server_port(port));
+    let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+      .finish()
+      .unwrap();
+    let mut recv = Box::pin(server.event_stream());
+    server
+      .parse_message(
+        message::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    server
+      .parse_message(message::StartScanning::default().into())
+      .await
+      .expect("Test, assuming infallible.");
+
+    let url = format!("ws://127.0.0.1:{}", port);
+    let mut ws_stream = None;
+    for _ in 0..50 {
+      if let Ok((stream, _)) = tokio_tungstenite::connect_async(&url).await {
+        ws_stream = Some(stream);
+        break;
+      }
+      tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let mut ws_stream = ws_stream.expect("Test, assuming infallible.");
+    ws_stream
+      .send(Message::Text(
+        r#"{ "identifier": "Massage Demo", "address": "KeepaliveTest", "version": 0 }"#.to_owned(),
+      ))
+      .await
+      .expect("Test, assuming infallible.");
+    let device_index = wait_for_outbound_device(&mut recv).await;
+    (server, recv, ws_stream, device_index)
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_dcm_keepalive_timeout() {
+    let (_server, mut recv, _ws_stream, device_index) = keepalive_test_server(51287).await;
+    // We never read from the stream again, so pings from the server go unanswered, and the device
+    // should be removed once the ping interval and timeout have passed.
+    let removed = tokio::time::timeout(Duration::from_millis(1000), async {
+      loop {
+        match recv.next().await {
+          Some(ButtplugServerMessage::DeviceRemoved(removed)) => return removed,
+          Some(_) => continue,
+          None => panic!("Server event stream closed."),
+        }
+      }
+    })
+    .await
+    .expect("Test, assuming infallible.");
+    assert_eq!(removed.device_index(), device_index);
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_dcm_keepalive_suppressed_by_traffic() {
+    let (server, _recv, ws_stream, device_index) = keepalive_test_server(51288).await;
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+    // Send frames more often than the ping interval, and no pings should show up.
+    let sender_task = tokio::spawn(async move {
+      loop {
+        if ws_sender.send(Message::Binary(vec![0])).await.is_err() {
+          break;
+        }
+        tokio::time::sleep(Duration::from_millis(30)).await;
+      }
+    });
+    let result = tokio::time::timeout(Duration::from_millis(600), async {
+      while let Some(msg) = ws_receiver.next().await {
+        if let Ok(Message::Ping(_)) = msg {
+          return;
+        }
+      }
+    })
+    .await;
+    assert!(
+      result.is_err(),
+      "Ping sent while device traffic was flowing"
+    );
+    sender_task.abort();
+    assert!(server.device_manager().device_info(device_index).is_some());
+
+    // Once the device goes quiet we should ping it again.
+    tokio::time::timeout(Duration::from_millis(1000), async {
+      loop {
+        if let Some(Ok(Message::Ping(_))) = ws_receiver.next().await {
+          return;
+        }
+      }
+    })
+    .await
+    .expect("Test, assuming infallible.");
+  }
+
+  /// Device side of an outbound connection: accepts one connection at a time, reports the
+  /// authorization header it was opened with and every binary frame it gets, and echoes the frames
+  /// back. Everything runs in the returned task, so aborting it takes the device offline.
+  // The handshake callback's error type is set by tungstenite.
+  #[allow(clippy::result_large_err)]
+  fn spawn_echo_device(
+    listener: TcpListener,
+    events: UnboundedSender<(Option<String>, Vec<u8>)>,
+  ) -> JoinHandle<()> {
+    tokio::spawn(async move {
+      loop {
+        let (stream, _) = listener.accept().await.expect("Test, assuming infallible.");
+        let mut auth = None;
+        let mut ws_stream =
+          tokio_tungstenite::accept_hdr_async(stream, |request: &Request, response: Response| {
+            auth = request
+              .headers()
+              .get("authorization")
+              .map(|value| value.to_str().unwrap().to_owned());
+            Ok(response)
+          })
+          .await
+          .expect("Test, assuming infallible.");
+        let _ = events.send((auth.clone(), vec![]));
+        while let Some(Ok(msg)) = ws_stream.next().await {
+          if let Message::Binary(data) = msg {
+            let _ = events.send((auth.clone(), data.clone()));
+            if ws_stream.send(Message::Binary(data)).await.is_err() {
+              break;
+            }
+          }
+        }
+      }
+    })
+  }
+
+  async fn wait_for_outbound_device(
+    recv: &mut (impl Stream<Item = ButtplugServerMessage> + Unpin),
+  ) -> u32 {
+    loop {
+      match tokio::time::timeout(Duration::from_secs(10), recv.next())
+        .await
+        .expect("Test, assuming infallible.")
+      {
+        Some(ButtplugServerMessage::DeviceAdded(da)) => return da.device_index(),
+        Some(_) => continue,
+        None => panic!("Server event stream closed."),
+      }
+    }
+  }
+
+  async fn vibrate_and_expect_echo(
+    server: &ButtplugServer,
+    recv: &mut (impl Stream<Item = ButtplugServerMessage> + Unpin),
+    device_index: u32,
+  ) {
+    server
+      .parse_message(
+        message::ScalarCmd::new(
+          device_index,
+          vec![message::ScalarSubcommand::new(
+            0,
+            1.0,
+            message::ActuatorType::Vibrate,
+          )],
+        )
+        .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+    loop {
+      match tokio::time::timeout(Duration::from_secs(5), recv.next())
+        .await
+        .expect("Test, assuming infallible.")
+      {
+        Some(ButtplugServerMessage::RawReading(reading)) => {
+          assert_eq!(reading.data(), &vec![0xF1, 127]);
+          return;
+        }
+        Some(_) => continue,
+        None => panic!("Server event stream closed."),
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn test_websocket_server_dcm_outbound_device() {
+    let listener = TcpListener::bind("127.0.0.1:51285")
+      .await
+      .expect("Test, assuming infallible.");
+    let (events_sender, mut events) = unbounded_channel();
+    let device_task = spawn_echo_device(listener, events_sender.clone());
+
+    // Raw messages on, so echoed frames come back to us as readings.
+    let dcm = create_test_dcm(true);
+    let mut specifier = WebsocketSpecifier::new("Massage Demo");
+    specifier.set_url(Some("ws://127.0.0.1:51285".to_owned()));
+    specifier.set_token(Some("outbound-secret".to_owned()));
+    dcm
+      .add_user_communication_specifier(
+        "aneros",
+        &ProtocolCommunicationSpecifier::Websocket(specifier),
+      )
+      .expect("Test, assuming infallible.");
+    let outbound = dcm.outbound_websocket_specifiers();
+    assert_eq!(outbound.len(), 1);
+    assert!(!format!("{:?}", outbound[0]).contains("outbound-secret"));
+    let mut dm_builder = ServerDeviceManagerBuilder::new(dcm);
+    dm_builder.comm_manager(
+      WebsocketServerDeviceCommunicationManagerBuilder::default()
+        .server_port(51286)
+        .outbound_devices(&outbound),
+    );
+    let server = ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+      .finish()
+      .unwrap();
+    let recv = server.event_stream();
+    pin_mut!(recv);
+    server
+      .parse_message(
+        message::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION)
+          .into(),
+      )
+      .await
+      .expect("Test, assuming infallible.");
+
+    // Connect, with the token as a bearer token.
+    let device_index = wait_for_outbound_device(&mut recv).await;
+    let (auth, _) = events.recv().await.expect("Test, assuming infallible.");
+    assert_eq!(auth.as_deref(), Some("Bearer outbound-secret"));
+
+    // Writes reach the device, and its frames come back as notifications.
+    server
+      .parse_message(message::RawSubscribeCmd::new(device_index, Endpoint::Tx).into())
+      .await
+      .expect("Test, assuming infallible.");
+    vibrate_and_expect_echo(&server, &mut recv, device_index).await;
+    let (_, data) = events.recv().await.expect("Test, assuming infallible.");
+    assert_eq!(data, vec![0xF1, 127]);
+
+    // Restart the device, and we should connect to it again.
+    device_task.abort();
+    let _ = device_task.await;
+    loop {
+      match tokio::time::timeout(Duration::from_secs(5), recv.next())
+        .await
+        .expect("Test, assuming infallible.")
+      {
+        Some(ButtplugServerMessage::DeviceRemoved(removed)) => {
+          assert_eq!(removed.device_index(), device_index);
+          break;
+        }
+        Some(_) => continue,
+        None => panic!("Server event stream closed."),
+      }
+    }
+    let listener = TcpListener::bind("127.0.0.1:51285")
+      .await
+      .expect("Test, assuming infallible.");
+    let _device_task = spawn_echo_device(listener, events_sender);
+    let device_index = wait_for_outbound_device(&mut recv).await;
+    server
+      .parse_message(message::RawSubscribeCmd::new(device_index, Endpoint::Tx).into())
+      .await
+      .expect("Test, assuming infallible.");
+    vibrate_and_expect_echo(&server, &mut recv, device_index).await;
+  }
 }
@@ -0,0 +1,325 @@
+// Buttplug Rust Source Code File - See https://buttplug.io for more info.
+//
+// Copyright 2016-2024 Nonpolynomial Labs LLC. All rights reserved.
+//
+// Licensed under the BSD 3-Clause license. See LICENSE file in the project root
+// for full license information.
+
+//! Protocol conformance harness. Given a protocol name, connects a test device matching the
+//! protocol's communication block in the bundled config, runs the same interactions against every
+//! protocol, and checks the invariants all protocols should hold:
+//!
+//! - Nothing panics.
+//! - Errors are ProtocolSpecificError or UnhandledCommand, never anything out of shared plumbing.
+//! - Stop succeeds, and writes to the device when it's running.
+//! - A disconnect while a command is running gets the device removed.
+//!
+//! Protocols can opt into byte-exact checks by adding a fixture at
+//! `tests/util/conformance/fixtures/<protocol>.yaml`, listing the packets each step should write.
+//! When a fixture doesn't match, the failure prints the packets that were recorded, in fixture
+//! format.
+
+// Not every test binary that includes util runs the harness.
+#![allow(dead_code)]
+
+use super::{
+  create_test_dcm,
+  test_device_manager::TestDeviceIdentifier,
+  test_server_with_comm_manager,
+  TestDeviceChannelHost,
+  TestDeviceCommunicationManagerBuilder,
+  TestHardwareEvent,
+};
+use buttplug::{
+  core::{
+    errors::{ButtplugDeviceError, ButtplugError},
+    message::{
+      self,
+      ButtplugClientMessage,
+      ButtplugServerMessage,
+      ClientGenericDeviceMessageAttributes,
+      BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION,
+    },
+  },
+  server::{
+    device::{configuration::ProtocolCommunicationSpecifier, hardware::HardwareCommand},
+    ButtplugServer,
+  },
+  util::stream::recv_now,
+};
+use futures::{pin_mut, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+/// Levels every scalar feature is sent, in order, with the suffix used in step names.
+const SCALAR_LEVELS: [(&str, f64); 3] = [("max", 1.0), ("min", 0.0), ("mid", 0.5)];
+
+/// Expected packets for a protocol, keyed by step name. Steps are `initialize`,
+/// `scalar_<index>_<max|min|mid>`, `repeat_first`, `repeat_second`, and `stop`. Steps that write
+/// nothing are listed with an empty list, so a fixture always covers every step.
+#[derive(Serialize, Deserialize)]
+pub struct ConformanceFixture {
+  /// Device to connect, for protocols whose first BLE name isn't the device the fixture is for.
+  identifier: Option<TestDeviceIdentifier>,
+  packets: BTreeMap<String, Vec<HardwareCommand>>,
+}
+
+fn fixture_path(protocol: &str) -> PathBuf {
+  std::path::Path::new(&std::env::var("CARGO_MANIFEST_DIR").expect("Should have manifest path"))
+    .join("tests")
+    .join("util")
+    .join("conformance")
+    .join("fixtures")
+    .join(format!("{}.yaml", protocol))
+}
+
+fn load_fixture(protocol: &str) -> Option<ConformanceFixture> {
+  let path = fixture_path(protocol);
+  if !path.exists() {
+    return None;
+  }
+  let fixture =
+    std::fs::read_to_string(&path).unwrap_or_else(|_| panic!("Cannot read file {:?}", path));
+  Some(serde_yaml::from_str(&fixture).expect("Could not parse yaml for file."))
+}
+
+/// The first exact BLE name in the protocol's bundled communication block.
+fn default_identifier(protocol: &str) -> TestDeviceIdentifier {
+  let specifiers = create_test_dcm(false).protocol_device_configurations();
+  let name = specifiers
+    .get(protocol)
+    .unwrap_or_else(|| panic!("No bundled config for protocol {}", protocol))
+    .iter()
+    .filter_map(|specifier| match specifier {
+      ProtocolCommunicationSpecifier::BluetoothLE(btle) => Some(btle.names().iter()),
+      _ => None,
+    })
+    .flatten()
+    .filter(|name| !name.contains('*'))
+    .min()
+    .unwrap_or_else(|| panic!("No BLE device name for protocol {}", protocol))
+    .clone();
+  TestDeviceIdentifier::new(&name, None)
+}
+
+struct ConformanceRun {
+  protocol: String,
+  server: ButtplugServer,
+  device: TestDeviceChannelHost,
+  device_index: u32,
+  packets: BTreeMap<String, Vec<HardwareCommand>>,
+}
+
+impl ConformanceRun {
+  /// Sends a message through the server on its own task, so a panic anywhere in the protocol is
+  /// reported against the step that caused it.
+  async fn send(
+    &self,
+    step: &str,
+    msg: ButtplugClientMessage,
+  ) -> Result<ButtplugServerMessage, message::Error> {
+    tokio::spawn(self.server.parse_message(msg))
+      .await
+      .unwrap_or_else(|e| panic!("{}: {} panicked: {:?}", self.protocol, step, e))
+  }
+
+  /// Runs a step, checks its result, and records what it wrote.
+  async fn run_step(&mut self, step: &str, msg: ButtplugClientMessage) {
+    if let Err(err) = self.send(step, msg).await {
+      let original_error = err.original_error();
+      assert!(
+        matches!(
+          original_error,
+          ButtplugError::ButtplugDeviceError(
+            ButtplugDeviceError::ProtocolSpecificError(..)
+              | ButtplugDeviceError::UnhandledCommand(_)
+          )
+        ),
+        "{}: {} failed with {:?}, expected a protocol specific or unhandled command error",
+        self.protocol,
+        step,
+        original_error
+      );
+    }
+    self.record(step);
+  }
+
+  fn record(&mut self, step: &str) {
+    let mut commands = vec![];
+    while let Some(Some(command)) = recv_now(&mut self.device.receiver) {
+      commands.push(command);
+    }
+    self.packets.insert(step.to_owned(), commands);
+  }
+
+  fn scalar_cmd(
+    &self,
+    index: u32,
+    feature: &ClientGenericDeviceMessageAttributes,
+    level: f64,
+  ) -> ButtplugClientMessage {
+    message::ScalarCmd::new(
+      self.device_index,
+      vec![message::ScalarSubcommand::new(
+        index,
+        level,
+        *feature.actuator_type(),
+      )],
+    )
+    .into()
+  }
+
+  async fn disconnect_during_command(&self, features: &[ClientGenericDeviceMessageAttributes]) {
+    let recv = self.server.event_stream();
+    pin_mut!(recv);
+    let command = if let Some(feature) = features.first() {
+      self.scalar_cmd(0, feature, 1.0)
+    } else {
+      message::StopDeviceCmd::new(self.device_index).into()
+    };
+    let result = tokio::spawn(self.server.parse_message(command));
+    self
+      .device
+      .sender
+      .send(TestHardwareEvent::Disconnect)
+      .await
+      .expect("Test, assuming infallible.");
+    let result = result.await.unwrap_or_else(|e| {
+      panic!(
+        "{}: disconnect during command panicked: {:?}",
+        self.protocol, e
+      )
+    });
+    if let Err(err) = result {
+      let original_error = err.original_error();
+      assert!(
+        matches!(
+          original_error,
+          ButtplugError::ButtplugDeviceError(
+            ButtplugDeviceError::ProtocolSpecificError(..)
+              | ButtplugDeviceError::UnhandledCommand(_)
+              | ButtplugDeviceError::DeviceNotAvailable(_)
+              | ButtplugDeviceError::DeviceNotConnected(_)
+              | ButtplugDeviceError::DeviceCommunicationError(_)
+          )
+        ),
+        "{}: command during disconnect failed with {:?}",
+        self.protocol,
+        original_error
+      );
+    }
+    loop {
+      tokio::select! {
+        _ = tokio::time::sleep(Duration::from_secs(1)) => {
+          panic!("{}: device was never removed after disconnecting", self.protocol)
+        }
+        msg = recv.next() => {
+          match msg {
+            Some(ButtplugServerMessage::DeviceRemoved(removed))
+              if removed.device_index() == self.device_index => return,
+            Some(_) => {}
+            None => panic!("Should not have dropped event stream!"),
+          }
+        }
+      }
+    }
+  }
+}
+
+async fn connect(
+  protocol: &str,
+  identifier: &TestDeviceIdentifier,
+) -> (ConformanceRun, Vec<ClientGenericDeviceMessageAttributes>) {
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let device = builder.add_test_device(identifier);
+  let server = test_server_with_comm_manager(builder, false);
+  let recv = server.event_stream();
+  pin_mut!(recv);
+  server
+    .parse_message(
+      message::RequestServerInfo::new("Test Client", BUTTPLUG_CURRENT_MESSAGE_SPEC_VERSION).into(),
+    )
+    .await
+    .expect("Test, assuming infallible.");
+  server
+    .parse_message(message::StartScanning::default().into())
+    .await
+    .expect("Test, assuming infallible.");
+  let added = loop {
+    tokio::select! {
+      _ = tokio::time::sleep(Duration::from_secs(1)) => {
+        panic!("{}: device was never added", protocol)
+      }
+      msg = recv.next() => {
+        match msg {
+          Some(ButtplugServerMessage::DeviceAdded(added)) => break added,
+          Some(_) => {}
+          None => panic!("Should not have dropped event stream!"),
+        }
+      }
+    }
+  };
+  let mut run = ConformanceRun {
+    protocol: protocol.to_owned(),
+    server,
+    device,
+    device_index: added.device_index(),
+    packets: BTreeMap::new(),
+  };
+  run.record("initialize");
+  let features = added
+    .device_messages()
+    .scalar_cmd()
+    .clone()
+    .unwrap_or_default();
+  (run, features)
+}
+
+/// Runs the conformance interactions against a protocol, checking its fixture if it has one.
+pub async fn run_protocol_conformance(protocol: &str) {
+  let fixture = load_fixture(protocol);
+  let identifier = fixture
+    .as_ref()
+    .and_then(|fixture| fixture.identifier.clone())
+    .unwrap_or_else(|| default_identifier(protocol));
+  let (mut run, features) = connect(protocol, &identifier).await;
+
+  for (index, feature) in features.iter().enumerate() {
+    for (label, level) in SCALAR_LEVELS {
+      let msg = run.scalar_cmd(index as u32, feature, level);
+      run
+        .run_step(&format!("scalar_{}_{}", index, label), msg)
+        .await;
+    }
+  }
+  if let Some(feature) = features.first() {
+    for step in ["repeat_first", "repeat_second"] {
+      let msg = run.scalar_cmd(0, feature, 1.0);
+      run.run_step(step, msg).await;
+    }
+  }
+  let stop = run
+    .send("stop", message::StopDeviceCmd::new(run.device_index).into())
+    .await;
+  assert!(stop.is_ok(), "{}: stop failed with {:?}", protocol, stop);
+  run.record("stop");
+  // The repeated commands leave the first feature at max, so there's something to stop.
+  if !features.is_empty() {
+    assert!(
+      !run.packets["stop"].is_empty(),
+      "{}: stop didn't write anything to the device",
+      protocol
+    );
+  }
+
+  if let Some(fixture) = &fixture {
+    assert!(
+      fixture.packets == run.packets,
+      "{}: packets don't match the fixture, recorded:\n{}",
+      protocol,
+      serde_yaml::to_string(&run.packets).expect("Test, assuming infallible.")
+    );
+  }
+
+  run.disconnect_during_command(&features).await;
+}
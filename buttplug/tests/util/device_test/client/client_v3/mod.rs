@@ -24,11 +24,46 @@ use super::super::{
   DeviceTestCase,
   TestClientCommand,
   TestCommand,
+  TestScalarFeature,
 };
 use futures::StreamExt;
 use std::{sync::Arc, time::Duration};
 use tracing::*;
 
+/// Assert that a device's ScalarCmd features are in the expected index order.
+fn assert_scalar_feature_order(device: &ButtplugClientDevice, expected: &[TestScalarFeature]) {
+  let actual = device
+    .message_attributes()
+    .scalar_cmd()
+    .clone()
+    .unwrap_or_default();
+  assert_eq!(
+    actual.len(),
+    expected.len(),
+    "Scalar feature count for {} doesn't match: {:?}",
+    device.name(),
+    actual
+  );
+  for (index, (actual, expected)) in actual.iter().zip(expected).enumerate() {
+    assert_eq!(
+      *actual.actuator_type(),
+      expected.actuator_type,
+      "Scalar feature {} of {} has the wrong actuator type",
+      index,
+      device.name()
+    );
+    if let Some(descriptor) = &expected.descriptor {
+      assert_eq!(
+        actual.feature_descriptor(),
+        descriptor,
+        "Scalar feature {} of {} has the wrong descriptor",
+        index,
+        device.name()
+      );
+    }
+  }
+}
+
 async fn run_test_client_command(command: &TestClientCommand, device: &Arc<ButtplugClientDevice>) {
   use TestClientCommand::*;
   match command {
@@ -262,6 +297,9 @@ pub async fn run_test_case(
           if let Some(expected_display_name) = &test_case.devices[device_added.index() as usize].expected_display_name {
             assert_eq!(Some(expected_display_name.clone()), *device_added.display_name());
           }
+          if let Some(expected_features) = &test_case.devices[device_added.index() as usize].expected_scalar_features {
+            assert_scalar_feature_order(&device_added, expected_features);
+          }
           if client.devices().len() == test_case.devices.len() {
             break;
           }
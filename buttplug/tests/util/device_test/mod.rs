@@ -4,7 +4,13 @@ pub mod client;
 pub mod connector;
 use super::{TestDeviceIdentifier, TestHardwareEvent};
 use buttplug::{
-  core::message::{RotationSubcommand, ScalarSubcommand, VectorSubcommand, VibrateSubcommand},
+  core::message::{
+    ActuatorType,
+    RotationSubcommand,
+    ScalarSubcommand,
+    VectorSubcommand,
+    VibrateSubcommand,
+  },
   server::device::hardware::HardwareCommand,
 };
 use serde::{Deserialize, Serialize};
@@ -14,6 +20,16 @@ struct TestDevice {
   identifier: TestDeviceIdentifier,
   expected_name: Option<String>,
   expected_display_name: Option<String>,
+  // ScalarCmd features the device should expose, in index order. Lets protocols pin which index
+  // maps to which actuator.
+  expected_scalar_features: Option<Vec<TestScalarFeature>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TestScalarFeature {
+  actuator_type: ActuatorType,
+  // Only checked if given, as many devices don't describe their features.
+  descriptor: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
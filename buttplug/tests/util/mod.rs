@@ -12,12 +12,18 @@ pub mod device_test;
 pub mod test_device_manager;
 pub use delay_device_communication_manager::DelayDeviceCommunicationManagerBuilder;
 pub mod channel_transport;
+pub mod conformance;
 use buttplug::{
   client::ButtplugClient,
   core::connector::ButtplugInProcessClientConnectorBuilder,
   server::{
     device::{
-      configuration::DeviceConfigurationManager,
+      configuration::{
+        DeviceConfigurationManager,
+        UserDeviceCustomization,
+        UserDeviceDefinition,
+        UserDeviceIdentifier,
+      },
       hardware::communication::HardwareCommunicationManagerBuilder,
       ServerDeviceManagerBuilder,
     },
@@ -147,3 +153,65 @@ pub fn test_server_with_device(
     device,
   )
 }
+
+/// Device configuration manager with a user definition for `device`, edited by `customize`. The
+/// definition is keyed on the device's address, with its name as the `protocol` identifier.
+#[allow(dead_code)]
+pub fn customized_test_dcm(
+  device: &TestDeviceIdentifier,
+  protocol: &str,
+  allow_raw_messages: bool,
+  customize: impl FnOnce(&mut UserDeviceDefinition),
+) -> DeviceConfigurationManager {
+  let dcm = create_test_dcm(allow_raw_messages);
+  let identifier =
+    UserDeviceIdentifier::new(device.address(), protocol, &Some(device.name().to_owned()));
+  let mut definition = dcm
+    .device_definition(&identifier, &[])
+    .expect("Test, assuming infallible.");
+  customize(&mut definition);
+  dcm
+    .add_user_device_definition(&identifier, &definition)
+    .expect("Test, assuming infallible.");
+  dcm
+}
+
+/// Device manager builder using `dcm`, with `device` as its only test device.
+#[allow(dead_code)]
+pub fn test_device_manager_builder(
+  dcm: DeviceConfigurationManager,
+  device: &TestDeviceIdentifier,
+) -> (ServerDeviceManagerBuilder, TestDeviceChannelHost) {
+  let mut builder = TestDeviceCommunicationManagerBuilder::default();
+  let device = builder.add_test_device(device);
+  let mut dm_builder = ServerDeviceManagerBuilder::new(dcm);
+  dm_builder.comm_manager(builder);
+  (dm_builder, device)
+}
+
+#[allow(dead_code)]
+pub fn test_server_with_customized_definition(
+  device: &TestDeviceIdentifier,
+  protocol: &str,
+  customize: impl FnOnce(&mut UserDeviceDefinition),
+) -> (ButtplugServer, TestDeviceChannelHost) {
+  let (mut dm_builder, device) =
+    test_device_manager_builder(customized_test_dcm(device, protocol, false, customize), device);
+  (
+    ButtplugServerBuilder::new(dm_builder.finish().unwrap())
+      .finish()
+      .unwrap(),
+    device,
+  )
+}
+
+#[allow(dead_code)]
+pub fn test_server_with_customized_device(
+  device: &TestDeviceIdentifier,
+  protocol: &str,
+  customize: impl FnOnce(&mut UserDeviceCustomization),
+) -> (ButtplugServer, TestDeviceChannelHost) {
+  test_server_with_customized_definition(device, protocol, |definition| {
+    customize(definition.user_config_mut())
+  })
+}
@@ -33,7 +33,11 @@ use serde::{Deserialize, Serialize};
 use std::{
   collections::{HashSet, VecDeque},
   fmt::{self, Debug},
-  sync::Arc,
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+  },
+  time::Duration,
 };
 use tokio::sync::{broadcast, mpsc, Mutex};
 
@@ -43,6 +47,16 @@ pub struct TestHardwareNotification {
   data: Vec<u8>,
 }
 
+impl TestHardwareNotification {
+  #[allow(dead_code)]
+  pub fn new(endpoint: Endpoint, data: &[u8]) -> Self {
+    Self {
+      endpoint,
+      data: data.to_vec(),
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum TestHardwareEvent {
   // Values to be emitted from subscriptions
@@ -82,9 +96,9 @@ impl HardwareConnector for TestHardwareConnector {
   }
 
   async fn connect(&mut self) -> Result<Box<dyn HardwareSpecializer>, ButtplugDeviceError> {
-    Ok(Box::new(TestHardwareSpecializer::new(
-      self.hardware.take().expect("Test"),
-    )))
+    let hardware = self.hardware.take().expect("Test");
+    hardware.connect_attempts.fetch_add(1, Ordering::SeqCst);
+    Ok(Box::new(TestHardwareSpecializer::new(hardware)))
   }
 }
 
@@ -107,6 +121,7 @@ impl HardwareSpecializer for TestHardwareSpecializer {
     specifiers: &[ProtocolCommunicationSpecifier],
   ) -> Result<Hardware, ButtplugDeviceError> {
     let mut device = self.hardware.take().expect("Test");
+    device.specialize_attempts.fetch_add(1, Ordering::SeqCst);
     let mut endpoints = vec![];
     if let Some(ProtocolCommunicationSpecifier::BluetoothLE(btle)) = specifiers
       .iter()
@@ -114,10 +129,14 @@ impl HardwareSpecializer for TestHardwareSpecializer {
     {
       for endpoint_map in btle.services().values() {
         for endpoint in endpoint_map.keys() {
+          if device.missing_endpoints.contains(endpoint) {
+            continue;
+          }
           device.add_endpoint(endpoint);
           endpoints.push(*endpoint);
         }
       }
+      btle.check_discovered_endpoints(&device.name(), &device.address(), &endpoints)?;
     }
     let hardware = Hardware::new(
       &device.name(),
@@ -127,29 +146,78 @@ impl HardwareSpecializer for TestHardwareSpecializer {
     );
     Ok(hardware)
   }
+
+  async fn specialize_with_endpoints(
+    &mut self,
+    _specifiers: &[ProtocolCommunicationSpecifier],
+    endpoints: &[Endpoint],
+  ) -> Result<Hardware, ButtplugDeviceError> {
+    let mut device = self.hardware.take().expect("Test");
+    if let Some(endpoint) = endpoints
+      .iter()
+      .find(|endpoint| device.missing_endpoints.contains(endpoint))
+    {
+      return Err(ButtplugDeviceError::DeviceConnectionError(format!(
+        "Endpoint {} not found on device",
+        endpoint
+      )));
+    }
+    for endpoint in endpoints {
+      device.add_endpoint(endpoint);
+    }
+    let hardware = Hardware::new(
+      &device.name(),
+      &device.address(),
+      endpoints,
+      Box::new(device),
+    );
+    Ok(hardware)
+  }
 }
 
 pub struct TestDeviceChannelHost {
   pub sender: mpsc::Sender<TestHardwareEvent>,
   pub receiver: mpsc::Receiver<HardwareCommand>,
+  /// Number of times the device manager has tried to connect to the device.
+  #[allow(dead_code)]
+  pub connect_attempts: Arc<AtomicU32>,
+  /// Number of times the device has gone through full protocol matching, rather than being set up
+  /// from a cached match.
+  #[allow(dead_code)]
+  pub specialize_attempts: Arc<AtomicU32>,
+  /// Number of upcoming commands the device will fail, see [TestDevice::fail_next_commands].
+  #[allow(dead_code)]
+  pub failed_commands: Arc<AtomicU32>,
 }
 
 pub struct TestDeviceChannelDevice {
   pub sender: mpsc::Sender<HardwareCommand>,
   pub receiver: mpsc::Receiver<TestHardwareEvent>,
+  pub connect_attempts: Arc<AtomicU32>,
+  pub specialize_attempts: Arc<AtomicU32>,
+  pub failed_commands: Arc<AtomicU32>,
 }
 
 pub fn new_device_channel() -> (TestDeviceChannelHost, TestDeviceChannelDevice) {
   let (host_sender, device_receiver) = mpsc::channel(256);
   let (device_sender, host_receiver) = mpsc::channel(256);
+  let connect_attempts = Arc::new(AtomicU32::new(0));
+  let specialize_attempts = Arc::new(AtomicU32::new(0));
+  let failed_commands = Arc::new(AtomicU32::new(0));
   (
     TestDeviceChannelHost {
       sender: host_sender,
       receiver: host_receiver,
+      connect_attempts: connect_attempts.clone(),
+      specialize_attempts: specialize_attempts.clone(),
+      failed_commands: failed_commands.clone(),
     },
     TestDeviceChannelDevice {
       sender: device_sender,
       receiver: device_receiver,
+      connect_attempts,
+      specialize_attempts,
+      failed_commands,
     },
   )
 }
@@ -162,6 +230,12 @@ pub struct TestDevice {
   event_sender: broadcast::Sender<HardwareEvent>,
   subscribed_endpoints: Arc<DashSet<Endpoint>>,
   read_data: Arc<Mutex<VecDeque<HardwareReading>>>,
+  failed_commands: Arc<AtomicU32>,
+  connect_attempts: Arc<AtomicU32>,
+  specialize_attempts: Arc<AtomicU32>,
+  missing_endpoints: HashSet<Endpoint>,
+  write_delay: Option<Duration>,
+  subscribe_delay: Option<Duration>,
 }
 
 impl TestDevice {
@@ -215,9 +289,38 @@ impl TestDevice {
       event_sender,
       subscribed_endpoints,
       read_data,
+      failed_commands: test_device_channel.failed_commands,
+      connect_attempts: test_device_channel.connect_attempts,
+      specialize_attempts: test_device_channel.specialize_attempts,
+      missing_endpoints: HashSet::new(),
+      write_delay: None,
+      subscribe_delay: None,
     }
   }
 
+  /// Leave these endpoints out when the device is specialized, to simulate hardware that doesn't
+  /// match its device config.
+  pub fn set_missing_endpoints(&mut self, endpoints: &[Endpoint]) {
+    self.missing_endpoints = endpoints.iter().cloned().collect();
+  }
+
+  /// Wait this long before every write completes, to simulate a slow connection.
+  #[allow(dead_code)]
+  pub fn set_write_delay(&mut self, delay: Duration) {
+    self.write_delay = Some(delay);
+  }
+
+  /// Wait this long before every subscribe completes, to simulate a slow connection.
+  #[allow(dead_code)]
+  pub fn set_subscribe_delay(&mut self, delay: Duration) {
+    self.subscribe_delay = Some(delay);
+  }
+
+  /// Fail the next `count` commands sent to the device, to simulate a flaky connection.
+  pub fn fail_next_commands(&self, count: u32) {
+    self.failed_commands.store(count, Ordering::SeqCst);
+  }
+
   pub fn add_endpoint(&mut self, endpoint: &Endpoint) {
     self.endpoints.insert(*endpoint);
   }
@@ -234,6 +337,18 @@ impl TestDevice {
     &self,
     data_command: HardwareCommand,
   ) -> BoxFuture<'static, Result<(), ButtplugDeviceError>> {
+    if self
+      .failed_commands
+      .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+        count.checked_sub(1)
+      })
+      .is_ok()
+    {
+      return future::ready(Err(ButtplugDeviceError::DeviceCommunicationError(
+        "Test device simulating a failed command".to_owned(),
+      )))
+      .boxed();
+    }
     let sender = self.test_device_channel.clone();
     async move {
       sender.send(data_command).await.expect("Test");
@@ -269,8 +384,12 @@ impl HardwareInternal for TestDevice {
     async move {
       let mut count = 0;
       loop {
+        // Optional endpoints (e.g. the DG-Lab V3 soft limits) are read whether or not the test
+        // cares about them, so fail the read the way hardware would rather than the test.
         if count == 5 {
-          panic!("Not getting expected read in time!");
+          return Err(ButtplugDeviceError::DeviceCommunicationError(
+            "Not getting expected read in time!".to_owned(),
+          ));
         }
         {
           if reads.lock().await.len() > 0 {
@@ -301,7 +420,16 @@ impl HardwareInternal for TestDevice {
     if !self.endpoints.contains(&msg.endpoint()) {
       return future::ready(Err(ButtplugDeviceError::InvalidEndpoint(msg.endpoint()))).boxed();
     }
-    self.send_command(msg.clone().into())
+    let send_fut = self.send_command(msg.clone().into());
+    if let Some(delay) = self.write_delay {
+      async move {
+        tokio::time::sleep(delay).await;
+        send_fut.await
+      }
+      .boxed()
+    } else {
+      send_fut
+    }
   }
 
   fn subscribe(
@@ -312,7 +440,16 @@ impl HardwareInternal for TestDevice {
       return future::ready(Err(ButtplugDeviceError::InvalidEndpoint(msg.endpoint()))).boxed();
     }
     self.subscribed_endpoints.insert(msg.endpoint());
-    self.send_command((*msg).into())
+    let send_fut = self.send_command((*msg).into());
+    if let Some(delay) = self.subscribe_delay {
+      async move {
+        tokio::time::sleep(delay).await;
+        send_fut.await
+      }
+      .boxed()
+    } else {
+      send_fut
+    }
   }
 
   fn unsubscribe(
@@ -15,8 +15,12 @@ use super::{
   TestDevice,
 };
 use buttplug::{
-  core::ButtplugResultFuture,
-  server::device::configuration::{BluetoothLESpecifier, ProtocolCommunicationSpecifier},
+  core::{message::Endpoint, ButtplugResultFuture},
+  server::device::configuration::{
+    BluetoothLESpecifier,
+    DeviceAddressFilter,
+    ProtocolCommunicationSpecifier,
+  },
   server::device::hardware::communication::{
     HardwareCommunicationManager,
     HardwareCommunicationManagerBuilder,
@@ -30,8 +34,9 @@ use std::{
   sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
+    Mutex,
   },
-  time::{SystemTime, UNIX_EPOCH},
+  time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::mpsc::Sender;
 use tracing::*;
@@ -52,6 +57,21 @@ pub struct TestDeviceIdentifier {
   name: String,
   #[serde(default = "generate_address")]
   address: String,
+  /// Endpoints in the device config that the test hardware won't expose when connected.
+  #[serde(default)]
+  missing_endpoints: Vec<Endpoint>,
+  /// Number of scans the device stays hidden for before being found.
+  #[serde(default)]
+  skipped_scans: u32,
+  /// Milliseconds every write takes to complete, to simulate a slow connection.
+  #[serde(default)]
+  write_delay_ms: u64,
+  /// Milliseconds every subscribe takes to complete, to simulate a slow connection.
+  #[serde(default)]
+  subscribe_delay_ms: u64,
+  /// Number of commands the device fails before it starts working.
+  #[serde(default)]
+  failed_commands: u32,
 }
 
 impl TestDeviceIdentifier {
@@ -62,18 +82,71 @@ impl TestDeviceIdentifier {
     Self {
       name: name.to_owned(),
       address,
+      missing_endpoints: vec![],
+      skipped_scans: 0,
+      write_delay_ms: 0,
+      subscribe_delay_ms: 0,
+      failed_commands: 0,
     }
   }
+
+  #[allow(dead_code)]
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  #[allow(dead_code)]
+  pub fn address(&self) -> &str {
+    &self.address
+  }
+
+  #[allow(dead_code)]
+  pub fn with_missing_endpoints(mut self, endpoints: &[Endpoint]) -> Self {
+    self.missing_endpoints = endpoints.to_vec();
+    self
+  }
+
+  #[allow(dead_code)]
+  pub fn with_skipped_scans(mut self, scans: u32) -> Self {
+    self.skipped_scans = scans;
+    self
+  }
+
+  #[allow(dead_code)]
+  pub fn with_write_delay(mut self, delay: Duration) -> Self {
+    self.write_delay_ms = delay.as_millis() as u64;
+    self
+  }
+
+  #[allow(dead_code)]
+  pub fn with_subscribe_delay(mut self, delay: Duration) -> Self {
+    self.subscribe_delay_ms = delay.as_millis() as u64;
+    self
+  }
+
+  #[allow(dead_code)]
+  pub fn with_failed_commands(mut self, count: u32) -> Self {
+    self.failed_commands = count;
+    self
+  }
 }
 
+type TestDeviceEntry = (TestDeviceIdentifier, TestDeviceChannelDevice);
+
 pub struct TestDeviceCommunicationManagerBuilder {
-  devices: Option<Vec<(TestDeviceIdentifier, TestDeviceChannelDevice)>>,
+  devices: Option<Vec<TestDeviceEntry>>,
+  address_filter: Option<DeviceAddressFilter>,
+  ignore_address_filter: bool,
+  filtered_addresses: Arc<Mutex<Vec<String>>>,
 }
 
 impl Default for TestDeviceCommunicationManagerBuilder {
   fn default() -> Self {
     Self {
       devices: Some(vec![]),
+      address_filter: None,
+      ignore_address_filter: false,
+      filtered_addresses: Arc::new(Mutex::new(vec![])),
     }
   }
 }
@@ -88,9 +161,29 @@ impl TestDeviceCommunicationManagerBuilder {
       .push((device.clone(), device_channel));
     host_channel
   }
+
+  /// Report every device, like comm managers that leave address checks to the device manager.
+  #[allow(dead_code)]
+  pub fn ignore_address_filter(&mut self) -> &mut Self {
+    self.ignore_address_filter = true;
+    self
+  }
+
+  /// Addresses of the devices the user config address filter kept from being reported, in the
+  /// order they were dropped.
+  #[allow(dead_code)]
+  pub fn filtered_addresses(&self) -> Arc<Mutex<Vec<String>>> {
+    self.filtered_addresses.clone()
+  }
 }
 
 impl HardwareCommunicationManagerBuilder for TestDeviceCommunicationManagerBuilder {
+  fn address_filter(&mut self, filter: DeviceAddressFilter) {
+    if !self.ignore_address_filter {
+      self.address_filter = Some(filter);
+    }
+  }
+
   fn finish(
     &mut self,
     sender: Sender<HardwareCommunicationManagerEvent>,
@@ -101,6 +194,8 @@ impl HardwareCommunicationManagerBuilder for TestDeviceCommunicationManagerBuild
         .devices
         .take()
         .expect("Devices vec does not exist, is this running twice?"),
+      self.address_filter.clone(),
+      self.filtered_addresses.clone(),
     ))
   }
 }
@@ -111,27 +206,41 @@ fn new_uninitialized_ble_test_device(
 ) -> TestHardwareConnector {
   let address = identifier.address.clone();
   let specifier = ProtocolCommunicationSpecifier::BluetoothLE(
-    BluetoothLESpecifier::new_from_device(&identifier.name, &HashMap::new(), &[]),
+    BluetoothLESpecifier::new_from_device(&identifier.name, &HashMap::new(), &[], &HashMap::new()),
   );
-  let hardware = TestDevice::new(&identifier.name, &address, device_channel);
+  let mut hardware = TestDevice::new(&identifier.name, &address, device_channel);
+  hardware.fail_next_commands(identifier.failed_commands);
+  hardware.set_missing_endpoints(&identifier.missing_endpoints);
+  if identifier.write_delay_ms > 0 {
+    hardware.set_write_delay(Duration::from_millis(identifier.write_delay_ms));
+  }
+  if identifier.subscribe_delay_ms > 0 {
+    hardware.set_subscribe_delay(Duration::from_millis(identifier.subscribe_delay_ms));
+  }
   TestHardwareConnector::new(specifier, hardware)
 }
 
 pub struct TestDeviceCommunicationManager {
   device_sender: Sender<HardwareCommunicationManagerEvent>,
-  devices: Vec<(TestDeviceIdentifier, TestDeviceChannelDevice)>,
+  devices: Vec<TestDeviceEntry>,
   is_scanning: Arc<AtomicBool>,
+  address_filter: Option<DeviceAddressFilter>,
+  filtered_addresses: Arc<Mutex<Vec<String>>>,
 }
 
 impl TestDeviceCommunicationManager {
   pub fn new(
     device_sender: Sender<HardwareCommunicationManagerEvent>,
-    devices: Vec<(TestDeviceIdentifier, TestDeviceChannelDevice)>,
+    devices: Vec<TestDeviceEntry>,
+    address_filter: Option<DeviceAddressFilter>,
+    filtered_addresses: Arc<Mutex<Vec<String>>>,
   ) -> Self {
     Self {
       device_sender,
       devices,
       is_scanning: Arc::new(AtomicBool::new(false)),
+      address_filter,
+      filtered_addresses,
     }
   }
 }
@@ -147,8 +256,25 @@ impl HardwareCommunicationManager for TestDeviceCommunicationManager {
     }
 
     let mut events = vec![];
+    let mut hidden_devices = vec![];
 
-    while let Some((device, test_channel)) = self.devices.pop() {
+    while let Some((mut device, test_channel)) = self.devices.pop() {
+      if device.skipped_scans > 0 {
+        device.skipped_scans -= 1;
+        hidden_devices.insert(0, (device, test_channel));
+        continue;
+      }
+      // Same as the BLE manager, filtered devices are dropped before a connector is made.
+      if let Some(filter) = &self.address_filter {
+        if !filter.allows(&device.address) {
+          self
+            .filtered_addresses
+            .lock()
+            .expect("Test, assuming infallible.")
+            .push(device.address);
+          continue;
+        }
+      }
       let device_creator = new_uninitialized_ble_test_device(&device, test_channel);
 
       events.push(HardwareCommunicationManagerEvent::DeviceFound {
@@ -157,6 +283,7 @@ impl HardwareCommunicationManager for TestDeviceCommunicationManager {
         creator: Box::new(device_creator),
       });
     }
+    self.devices = hidden_devices;
     let device_sender = self.device_sender.clone();
     let is_scanning = self.is_scanning.clone();
     async move {